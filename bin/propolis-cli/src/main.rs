@@ -27,9 +27,10 @@ use uuid::Uuid;
 use propolis_client::{
     support::{InstanceSerialConsoleHelper, WSClientOffset},
     types::{
-        DiskRequest, InstanceEnsureRequest, InstanceMigrateInitiateRequest,
-        InstanceProperties, InstanceStateRequested, InstanceVcrReplace,
-        MigrationState,
+        DiskRequest, HangAction, InstanceEnsureRequest,
+        InstanceMigrateInitiateRequest, InstanceProperties,
+        InstanceShutdownRequest, InstanceStateRequested, InstanceVcrReplace,
+        MigrationState, SerialPortNumber, SupervisionPolicy,
     },
     Client,
 };
@@ -89,6 +90,27 @@ enum Command {
         /// A UUID to use for the instance's project, attached to instance metrics.
         #[clap(long)]
         project_id: Option<TypedUuid<ProjectKind>>,
+
+        /// Hold the instance's vCPUs when it's started, leaving it paused
+        /// until it is resumed with the `resume` command.
+        #[clap(long, action)]
+        start_paused: bool,
+
+        /// Have the instance's bootrom show its boot menu when the guest
+        /// boots, from which its setup UI can be entered.
+        #[clap(long, action)]
+        boot_to_firmware_setup: bool,
+
+        /// Consider the guest hung if it shows no sign of life, through its
+        /// serial ports or guest agent, for this many seconds.
+        #[clap(long)]
+        hang_timeout: Option<u64>,
+
+        /// Reset the instance when its guest hangs, rather than only recording
+        /// the hang in its history.  The server must have a guest agent
+        /// configured for the instance.
+        #[clap(long, action, requires = "hang_timeout")]
+        reset_on_hang: bool,
     },
 
     /// Get the properties of a propolis instance
@@ -101,6 +123,14 @@ enum Command {
         state: InstanceStateRequested,
     },
 
+    /// Shut the instance down by pressing its ACPI power button
+    Shutdown {
+        /// Seconds to wait for the guest to power off before stopping the
+        /// instance forcibly
+        #[clap(long, default_value = "60", action)]
+        grace_period_secs: u64,
+    },
+
     /// Drop to a Serial console connected to the instance
     Serial {
         /// The offset since boot (or if negative, the current end of the
@@ -108,6 +138,16 @@ enum Command {
         /// Defaults to the most recent 16 KiB of console output (-16384).
         #[clap(long, short)]
         byte_offset: Option<i64>,
+
+        /// The COM port (1-4) to connect to.
+        #[clap(long, default_value = "1", value_parser = parse_serial_port)]
+        port: SerialPortNumber,
+
+        /// Observe the console without sending input to it. Unlike the
+        /// default read-write mode, this may be used while another client is
+        /// attached to the console.
+        #[clap(long, action)]
+        read_only: bool,
     },
 
     /// Migrate instance to new propolis-server
@@ -135,6 +175,12 @@ enum Command {
     /// Inject an NMI into the instance
     InjectNmi,
 
+    /// Pause the instance's vCPUs and devices
+    Pause,
+
+    /// Resume a paused instance
+    Resume,
+
     /// Call the VolumeConstructionRequest replace endpoint
     Vcr {
         /// Uuid for the disk
@@ -158,6 +204,16 @@ fn parse_state(state: &str) -> anyhow::Result<InstanceStateRequested> {
     }
 }
 
+fn parse_serial_port(port: &str) -> anyhow::Result<SerialPortNumber> {
+    match port {
+        "1" => Ok(SerialPortNumber::Com1),
+        "2" => Ok(SerialPortNumber::Com2),
+        "3" => Ok(SerialPortNumber::Com3),
+        "4" => Ok(SerialPortNumber::Com4),
+        _ => Err(anyhow!("invalid serial port, must be one of: 1, 2, 3, 4")),
+    }
+}
+
 fn parse_json_file<T: serde::de::DeserializeOwned>(
     path: &Path,
 ) -> anyhow::Result<T> {
@@ -219,6 +275,9 @@ async fn new_instance(
     cloud_init_bytes: Option<String>,
     silo_id: TypedUuid<SiloKind>,
     project_id: TypedUuid<ProjectKind>,
+    start_paused: bool,
+    boot_to_firmware_setup: bool,
+    supervision: Option<SupervisionPolicy>,
 ) -> anyhow::Result<()> {
     let properties = InstanceProperties {
         id,
@@ -243,6 +302,9 @@ async fn new_instance(
         disks,
         migrate: None,
         cloud_init_bytes,
+        start_paused,
+        boot_to_firmware_setup,
+        supervision,
     };
 
     // Try to create the instance
@@ -361,11 +423,14 @@ async fn stdin_to_websockets_task(
 }
 
 async fn serial(
-    addr: SocketAddr,
+    client: &Client,
     byte_offset: Option<i64>,
+    port: SerialPortNumber,
+    read_only: bool,
     log: Logger,
 ) -> anyhow::Result<()> {
-    let mut ws_console = serial_connect(addr, byte_offset, log).await?;
+    let mut ws_console =
+        serial_connect(client, byte_offset, port, read_only, log).await?;
 
     let _raw_guard = RawTermiosGuard::stdio_guard()
         .with_context(|| anyhow!("failed to set raw mode"))?;
@@ -456,8 +521,10 @@ async fn serial(
 }
 
 async fn serial_connect(
-    addr: SocketAddr,
+    client: &Client,
     byte_offset: Option<i64>,
+    port: SerialPortNumber,
+    read_only: bool,
     log: Logger,
 ) -> anyhow::Result<InstanceSerialConsoleHelper> {
     let offset = match byte_offset {
@@ -466,7 +533,7 @@ async fn serial_connect(
         None => WSClientOffset::MostRecent(16384),
     };
 
-    Ok(InstanceSerialConsoleHelper::new(addr, offset, Some(log)).await?)
+    Ok(client.serial_console(port, offset, read_only, Some(log)).await?)
 }
 
 async fn migrate_instance(
@@ -496,8 +563,16 @@ async fn migrate_instance(
             migration_id: Uuid::new_v4(),
             src_addr: src_addr.to_string(),
             src_uuid,
+            auto_converge: None,
+            max_downtime_ms: None,
+            tls: None,
+            retry: None,
+            dirty_tracking: None,
         }),
         cloud_init_bytes: None,
+        start_paused: false,
+        boot_to_firmware_setup: false,
+        supervision: None,
     };
 
     // Initiate the migration via the destination instance
@@ -518,19 +593,29 @@ async fn migrate_instance(
     .map(|(role, client, id)| {
         tokio::spawn(async move {
             loop {
-                let state = client
+                let status = client
                     .instance_migrate_status()
                     .migration_id(migration_id)
                     .send()
                     .await?
-                    .state;
+                    .into_inner();
+                let state = status.state;
                 println!("{}({}) migration state={:?}", role, id, state);
                 if state == MigrationState::Finish {
                     return Ok::<_, anyhow::Error>(());
                 } else if state == MigrationState::Error {
-                    return Err(anyhow::anyhow!(
-                        "{role} instance ran into error during migration"
-                    ));
+                    return Err(match status.failure {
+                        Some(f) => anyhow::anyhow!(
+                            "{role} instance ran into error during migration \
+                             ({:?} in phase {:?}): {}",
+                            f.cause,
+                            f.phase,
+                            f.message
+                        ),
+                        None => anyhow::anyhow!(
+                            "{role} instance ran into error during migration"
+                        ),
+                    });
                 }
                 tokio::time::sleep(Duration::from_secs(1)).await;
             }
@@ -580,6 +665,37 @@ async fn monitor(addr: SocketAddr) -> anyhow::Result<()> {
     }
 }
 
+async fn shutdown_instance(
+    client: &Client,
+    grace_period_secs: u64,
+) -> anyhow::Result<()> {
+    client
+        .instance_shutdown()
+        .body(InstanceShutdownRequest { grace_period_secs })
+        .send()
+        .await
+        .with_context(|| anyhow!("failed to shut down instance"))?;
+    Ok(())
+}
+
+async fn pause_instance(client: &Client) -> anyhow::Result<()> {
+    client
+        .instance_pause()
+        .send()
+        .await
+        .with_context(|| anyhow!("failed to pause instance"))?;
+    Ok(())
+}
+
+async fn resume_instance(client: &Client) -> anyhow::Result<()> {
+    client
+        .instance_resume()
+        .send()
+        .await
+        .with_context(|| anyhow!("failed to resume instance"))?;
+    Ok(())
+}
+
 async fn inject_nmi(client: &Client) -> anyhow::Result<()> {
     client
         .instance_issue_nmi()
@@ -607,6 +723,10 @@ async fn main() -> anyhow::Result<()> {
             cloud_init,
             silo_id,
             project_id,
+            start_paused,
+            boot_to_firmware_setup,
+            hang_timeout,
+            reset_on_hang,
         } => {
             let disks = if let Some(crucible_disks) = crucible_disks {
                 parse_json_file(&crucible_disks)?
@@ -621,6 +741,15 @@ async fn main() -> anyhow::Result<()> {
             } else {
                 None
             };
+            let supervision =
+                hang_timeout.map(|hang_timeout_secs| SupervisionPolicy {
+                    hang_timeout_secs,
+                    action: if reset_on_hang {
+                        HangAction::Reset
+                    } else {
+                        HangAction::Report
+                    },
+                });
             new_instance(
                 &client,
                 name.to_string(),
@@ -631,13 +760,19 @@ async fn main() -> anyhow::Result<()> {
                 cloud_init_bytes,
                 silo_id.unwrap_or_else(TypedUuid::new_v4),
                 project_id.unwrap_or_else(TypedUuid::new_v4),
+                start_paused,
+                boot_to_firmware_setup,
+                supervision,
             )
             .await?
         }
         Command::Get => get_instance(&client).await?,
         Command::State { state } => put_instance(&client, state).await?,
-        Command::Serial { byte_offset } => {
-            serial(addr, byte_offset, log).await?
+        Command::Shutdown { grace_period_secs } => {
+            shutdown_instance(&client, grace_period_secs).await?
+        }
+        Command::Serial { byte_offset, port, read_only } => {
+            serial(&client, byte_offset, port, read_only, log).await?
         }
         Command::Migrate { dst_server, dst_port, dst_uuid, crucible_disks } => {
             let dst_addr = SocketAddr::new(dst_server, dst_port);
@@ -652,6 +787,8 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Monitor => monitor(addr).await?,
         Command::InjectNmi => inject_nmi(&client).await?,
+        Command::Pause => pause_instance(&client).await?,
+        Command::Resume => resume_instance(&client).await?,
         Command::Vcr { uuid, vcr_replace } => {
             let replace: InstanceVcrReplace = parse_json_file(&vcr_replace)?;
             replace_vcr(&client, uuid, replace).await?
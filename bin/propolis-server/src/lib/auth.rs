@@ -0,0 +1,191 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authorization of requests to the server's API with bearer tokens.
+//!
+//! Dropshot has no hook through which every request passes, so each endpoint
+//! checks its request itself, naming the scope it requires: `console` for the
+//! serial consoles and display, `lifecycle` for changes to the instance, its
+//! devices and its disks, and `migration` for migrations into and out of it.
+//! Endpoints which only report on the instance accept a token of any scope,
+//! and the health probes, `/healthz` and `/readyz`, need no token at all.
+//! If the server's configuration has no `[auth]` section, all requests are
+//! allowed.  WebSocket connections are upgraded before their endpoints run, so
+//! those refused are simply closed.
+//!
+//! Tokens are kept only as SHA-256 digests, which are compared in place of
+//! the tokens themselves so that the time taken to reject a token reveals
+//! nothing about the tokens accepted.
+
+use std::io;
+use std::path::Path;
+
+use dropshot::HttpError;
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, StatusCode};
+use propolis_api_types::ErrorCode;
+use propolis_server_config::{Auth, AuthScope};
+
+type TokenDigest = [u8; 32];
+
+fn digest(token: &str) -> TokenDigest {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are 32 bytes")
+}
+
+fn read_token(path: &Path) -> io::Result<String> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| {
+            io::Error::new(e.kind(), format!("{}: {e}", path.display()))
+        })?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: token is empty", path.display()),
+        ));
+    }
+    Ok(token)
+}
+
+fn unauthorized(message: &str) -> HttpError {
+    HttpError::for_client_error(
+        Some(ErrorCode::Unauthorized.to_string()),
+        StatusCode::UNAUTHORIZED,
+        message.to_string(),
+    )
+}
+
+/// Decides whether requests may be made of the server's endpoints.
+pub struct Authorizer {
+    /// The digests of the tokens accepted, and the scopes each grants, or
+    /// `None` if requests need no token.
+    tokens: Option<Vec<(TokenDigest, Vec<AuthScope>)>>,
+
+    /// The token presented to the source of a migration into the instance.
+    migration_token: Option<String>,
+}
+
+impl Authorizer {
+    /// Reads the tokens named in the server's `[auth]` configuration.
+    pub fn new(config: Option<&Auth>) -> io::Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self { tokens: None, migration_token: None });
+        };
+
+        let tokens = config
+            .tokens
+            .iter()
+            .map(|t| Ok((digest(&read_token(&t.file)?), t.scopes.clone())))
+            .collect::<io::Result<_>>()?;
+        let migration_token = config
+            .migration_token_file
+            .as_deref()
+            .map(read_token)
+            .transpose()?;
+        Ok(Self { tokens: Some(tokens), migration_token })
+    }
+
+    /// Returns the token to present to the source of a migration into the
+    /// instance, if any.
+    pub(crate) fn migration_token(&self) -> Option<&str> {
+        self.migration_token.as_deref()
+    }
+
+    /// Checks that a request with the given headers carries a token granting
+    /// `scope`, or any accepted token if `scope` is `None`.
+    pub(crate) fn check(
+        &self,
+        headers: &HeaderMap,
+        scope: Option<AuthScope>,
+    ) -> Result<(), HttpError> {
+        let Some(tokens) = &self.tokens else {
+            return Ok(());
+        };
+
+        let presented = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("a bearer token is required"))?;
+        let presented = digest(presented.trim());
+        let scopes = tokens
+            .iter()
+            .find(|(digest, _)| *digest == presented)
+            .map(|(_, scopes)| scopes)
+            .ok_or_else(|| unauthorized("the bearer token is not valid"))?;
+
+        match scope {
+            Some(scope) if !scopes.contains(&scope) => {
+                let message =
+                    format!("the bearer token does not grant {scope:?} access");
+                Err(HttpError::for_client_error(
+                    Some(ErrorCode::Forbidden.to_string()),
+                    StatusCode::FORBIDDEN,
+                    message,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(token: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            let value = HeaderValue::from_str(&format!("Bearer {token}"));
+            headers.insert(AUTHORIZATION, value.unwrap());
+        }
+        headers
+    }
+
+    fn status(result: Result<(), HttpError>) -> Option<StatusCode> {
+        result.err().map(|e| e.status_code)
+    }
+
+    #[test]
+    fn requests_are_checked_against_token_scopes() {
+        let auth = Authorizer {
+            tokens: Some(vec![
+                (digest("operator"), vec![AuthScope::Lifecycle]),
+                (digest("viewer"), vec![AuthScope::Console]),
+            ]),
+            migration_token: None,
+        };
+
+        let lifecycle = Some(AuthScope::Lifecycle);
+        assert_eq!(
+            status(auth.check(&headers(Some("operator")), lifecycle)),
+            None
+        );
+        assert_eq!(status(auth.check(&headers(Some("viewer")), None)), None);
+        assert_eq!(
+            status(auth.check(&headers(Some("viewer")), lifecycle)),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            status(auth.check(&headers(Some("intruder")), None)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            status(auth.check(&headers(None), None)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn requests_are_allowed_without_auth_config() {
+        let auth = Authorizer::new(None).unwrap();
+        let migration = Some(AuthScope::Migration);
+        assert_eq!(status(auth.check(&headers(None), migration)), None);
+    }
+}
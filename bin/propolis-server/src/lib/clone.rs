@@ -0,0 +1,166 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Clones of an instance, made by copying its disks.
+//!
+//! An instance is cloned while it's paused, so that its disks aren't written
+//! while they're copied.  Each writable file-backed disk is copied alongside
+//! its backing file, as disks are for snapshots, and the clone's spec is the
+//! instance's, with those disks backed by the copies instead.  A disk's
+//! integrity sidecar, if it has one, is copied alongside it too, so that the
+//! clone and the instance don't record checksums in the same file.  Read-only
+//! disks are shared with the clone.  Writable disks backed by Crucible or held
+//! in memory can't be copied this way, so instances with them can't be cloned.
+//!
+//! The clone's spec is otherwise the instance's: its network backends, for
+//! one, must be replaced before the clone can run on the same host as the
+//! instance.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use dropshot::HttpError;
+use http::StatusCode;
+use propolis_api_types::instance_spec::v0::{InstanceSpecV0, StorageBackendV0};
+use propolis_api_types::instance_spec::VersionedInstanceSpec;
+use propolis_api_types::{ErrorCode, InstanceCloneResponse, InstanceState};
+use slog::{info, Logger};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::snapshot::copy_file;
+use crate::vm::VmController;
+
+#[derive(Debug, Error)]
+pub(crate) enum CloneError {
+    #[error("The instance must be paused while it's cloned")]
+    NotPaused,
+
+    #[error("Storage backend {0:?} is writable and can't be copied")]
+    Uncopyable(String),
+
+    #[error("Failed to copy storage backend {0:?}: {1}")]
+    Copy(String, io::Error),
+}
+
+impl From<CloneError> for HttpError {
+    fn from(e: CloneError) -> Self {
+        let msg = e.to_string();
+        match e {
+            CloneError::NotPaused => HttpError::for_client_error(
+                Some(ErrorCode::InvalidRequest.to_string()),
+                StatusCode::CONFLICT,
+                msg,
+            ),
+            CloneError::Uncopyable(_) => HttpError::for_bad_request(
+                Some(ErrorCode::InvalidRequest.to_string()),
+                msg,
+            ),
+            CloneError::Copy(..) => {
+                let mut error = HttpError::for_internal_error(msg);
+                error.error_code = Some(ErrorCode::OperationFailed.to_string());
+                error
+            }
+        }
+    }
+}
+
+fn paused(vm: &VmController) -> bool {
+    vm.external_instance_state() == InstanceState::Paused
+}
+
+/// Copies the file at `source` to `{source}.clone-{clone_id}`, returning the
+/// path of the copy.
+async fn copy_for_clone(source: &str, clone_id: Uuid) -> io::Result<String> {
+    let source = source.to_string();
+    let dest = format!("{source}.clone-{clone_id}");
+    let path = dest.clone();
+    tokio::task::spawn_blocking(move || copy_file(&source, &dest))
+        .await
+        .expect("disk copy should not panic")?;
+    Ok(path)
+}
+
+/// Copies the writable file-backed disks in `spec`, and their integrity
+/// sidecars, pointing their backends at the copies.  The paths of the disks'
+/// copies are recorded in `copies`, and those of the sidecars' in `sidecars`.
+async fn copy_backends(
+    spec: &mut InstanceSpecV0,
+    clone_id: Uuid,
+    copies: &mut BTreeMap<String, String>,
+    sidecars: &mut Vec<String>,
+) -> Result<(), CloneError> {
+    // Check every backend first, so that nothing is copied for an instance
+    // which can't be cloned.
+    for (name, backend) in spec.backends.storage_backends.iter() {
+        let copyable = match backend {
+            StorageBackendV0::Crucible(crucible) => crucible.readonly,
+            StorageBackendV0::File(_) => true,
+            StorageBackendV0::Blob(blob) => blob.readonly,
+        };
+        if !copyable {
+            return Err(CloneError::Uncopyable(name.clone()));
+        }
+    }
+
+    for (name, backend) in spec.backends.storage_backends.iter_mut() {
+        let StorageBackendV0::File(file) = backend else {
+            continue;
+        };
+        if file.readonly {
+            continue;
+        }
+        let path = copy_for_clone(&file.path, clone_id)
+            .await
+            .map_err(|e| CloneError::Copy(name.clone(), e))?;
+        copies.insert(name.clone(), path.clone());
+        file.path = path;
+
+        if let Some(sidecar) = &file.integrity_sidecar {
+            let path = copy_for_clone(sidecar, clone_id)
+                .await
+                .map_err(|e| CloneError::Copy(name.clone(), e))?;
+            sidecars.push(path.clone());
+            file.integrity_sidecar = Some(path);
+        }
+    }
+    Ok(())
+}
+
+/// Clones the instance, which must be paused, returning the clone's spec.
+pub(crate) async fn clone_instance(
+    vm: &VmController,
+    log: &Logger,
+) -> Result<InstanceCloneResponse, CloneError> {
+    if !paused(vm) {
+        return Err(CloneError::NotPaused);
+    }
+
+    let clone_id = Uuid::new_v4();
+    let VersionedInstanceSpec::V0(mut spec) = vm.instance_spec().await.clone();
+    let mut copies = BTreeMap::new();
+    let mut sidecars = Vec::new();
+    let mut result =
+        copy_backends(&mut spec, clone_id, &mut copies, &mut sidecars).await;
+
+    // If the instance was resumed while its disks were copied, the guest may
+    // have written to them midway through, leaving the copies inconsistent.
+    if result.is_ok() && !paused(vm) {
+        result = Err(CloneError::NotPaused);
+    }
+    if let Err(e) = result {
+        for path in copies.values().chain(sidecars.iter()) {
+            let _ = std::fs::remove_file(path);
+        }
+        return Err(e);
+    }
+
+    info!(log, "cloned instance";
+          "clone_id" => %clone_id, "copies" => copies.len());
+    Ok(InstanceCloneResponse {
+        clone_id,
+        instance_spec: VersionedInstanceSpec::V0(spec),
+        copies,
+    })
+}
@@ -0,0 +1,86 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Exports of ranges of an instance's disks.
+//!
+//! An export is served from a copy of the requested range, staged in an
+//! unlinked file in the server's temporary directory (`TMPDIR`, if it's set).
+//! The disk's device is held (see [`VmController::hold_storage_device`]) only
+//! while that copy is made, so guest I/O to the disk stalls for as long as it
+//! takes to read the range from the disk's backend, but not for as long as
+//! the client takes to download it.
+//!
+//! [`VmController::hold_storage_device`]:
+//!     crate::vm::VmController::hold_storage_device
+
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+
+use propolis::block::Backend;
+
+/// The maximum number of bytes read from a disk's backend, or from a staged
+/// copy of it, at a time.
+pub(crate) const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Copies the `length` bytes at `offset` in `backend` into a new temporary
+/// file, returning the file positioned at its start.
+///
+/// The backend's device should be paused, and its in-flight I/O drained,
+/// beforehand.  This blocks while the range is read, so it must not be called
+/// from an async context.
+pub(crate) fn stage(
+    backend: &dyn Backend,
+    offset: u64,
+    length: u64,
+) -> io::Result<File> {
+    let mut file = tempfile::tempfile()?;
+    let end = offset + length;
+    let mut pos = offset;
+    let mut buf = vec![0u8; length.min(CHUNK_SIZE) as usize];
+    while pos < end {
+        let len = (end - pos).min(CHUNK_SIZE) as usize;
+        backend.read_direct(pos as usize, &mut buf[..len])?;
+        file.write_all(&buf[..len])?;
+        pos += len as u64;
+    }
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Reads the next chunk of a staged copy, returning `None` at its end.
+pub(crate) fn next_chunk(file: &mut File) -> Option<io::Result<Vec<u8>>> {
+    let mut buf = Vec::with_capacity(CHUNK_SIZE as usize);
+    match file.by_ref().take(CHUNK_SIZE).read_to_end(&mut buf) {
+        Ok(0) => None,
+        Ok(_) => Some(Ok(buf)),
+        Err(e) => Some(Err(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use propolis::block::{BackendOpts, InMemoryBackend};
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn staged_copy_holds_range() {
+        let bytes: Vec<u8> = (0..4 * 512).map(|i| (i / 512) as u8).collect();
+        let backend = InMemoryBackend::create(
+            bytes,
+            BackendOpts { block_size: Some(512), ..Default::default() },
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap();
+
+        let mut file = stage(&*backend, 512, 2 * 512).unwrap();
+        let mut staged = vec![];
+        while let Some(chunk) = next_chunk(&mut file) {
+            staged.extend(chunk.unwrap());
+        }
+        let mut expected = vec![1u8; 512];
+        expected.extend([2u8; 512]);
+        assert_eq!(staged, expected);
+    }
+}
@@ -0,0 +1,249 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodic capture of the guest's framebuffer to PNG files on the host.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use propolis::common::GuestAddr;
+use propolis::hw::qemu::ramfb::{FramebufferSpec, Resolution};
+use rfb::pixel_formats::fourcc::FOURCC_XR24;
+use slog::{info, warn, Logger};
+use tokio::task::JoinHandle;
+
+use crate::vm::VmController;
+
+const PNG_SIGNATURE: [u8; 8] =
+    [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Size of an XRGB8888 pixel
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Where the lines of a frame lie in guest memory
+#[derive(Debug, PartialEq)]
+struct FrameLayout {
+    width: usize,
+    height: usize,
+    /// Bytes from the start of one line to the start of the next
+    line_size: usize,
+    /// Bytes from the start of the first line to the end of the last
+    span: usize,
+}
+impl FrameLayout {
+    /// Returns the layout of the frames in `fb`, or `None` if its dimensions
+    /// (or the distance between its lines) are outside those a display may
+    /// have.
+    fn of(fb: &FramebufferSpec) -> Option<Self> {
+        let res = Resolution { width: fb.width, height: fb.height };
+        let stride = if fb.stride == 0 { fb.width } else { fb.stride };
+        if !res.is_valid()
+            || !(fb.width..=Resolution::MAX.width).contains(&stride)
+        {
+            return None;
+        }
+        let width = fb.width as usize;
+        let height = fb.height as usize;
+        let line_size = (stride as usize).checked_mul(BYTES_PER_PIXEL)?;
+        let span = (height - 1)
+            .checked_mul(line_size)?
+            .checked_add(width.checked_mul(BYTES_PER_PIXEL)?)?;
+        Some(Self { width, height, line_size, span })
+    }
+
+    /// Gathers the pixels of each line from `span`, the bytes of a frame as
+    /// laid out in guest memory.
+    fn pixels(&self, span: &[u8]) -> Vec<u8> {
+        span.chunks(self.line_size)
+            .take(self.height)
+            .flat_map(|line| &line[..self.width * BYTES_PER_PIXEL])
+            .copied()
+            .collect()
+    }
+}
+
+/// Encodes a frame of XRGB8888 pixels (stored little-endian, i.e. as B, G, R,
+/// X bytes) as an RGB PNG image.
+pub fn encode_png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width as usize * height as usize * 4);
+
+    // Each scanline is preceded by its filter type, here always "None".
+    let mut raw =
+        Vec::with_capacity(height as usize * (1 + width as usize * 3));
+    for row in pixels.chunks_exact(width as usize * 4) {
+        raw.push(0);
+        for px in row.chunks_exact(4) {
+            raw.extend_from_slice(&[px[2], px[1], px[0]]);
+        }
+    }
+    let mut zlib = flate2::write::ZlibEncoder::new(
+        Vec::new(),
+        flate2::Compression::fast(),
+    );
+    zlib.write_all(&raw).unwrap();
+    let idat = zlib.finish().unwrap();
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, color type 2 (RGB), and the default compression, filter
+    // and interlace methods.
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    for (kind, data) in [(b"IHDR", &ihdr), (b"IDAT", &idat), (b"IEND", &vec![])]
+    {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        png.extend_from_slice(&crc.finalize().to_be_bytes());
+    }
+    png
+}
+
+/// Spawns a task which captures the guest's framebuffer to
+/// `<directory>/<instance-id>-fb-<unix-time-ms>.png` every `interval`, skipping
+/// frames identical to the last one captured.
+///
+/// Nothing is captured while the guest's configuration of the framebuffer is
+/// incomplete or invalid, or describes a frame larger than a display may be.
+/// If a capture cannot be written, the error is logged and recording stops.
+pub fn spawn_recorder(
+    vm: Arc<VmController>,
+    directory: PathBuf,
+    interval: Duration,
+    log: Logger,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let Some(ramfb) = vm.framebuffer().cloned() else {
+            return;
+        };
+        let id = vm.properties().id;
+        info!(log, "Recording framebuffer";
+              "directory" => %directory.display(),
+              "interval" => ?interval);
+
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut last: Option<(u32, u32, Vec<u8>)> = None;
+        let mut warned_format = false;
+        let mut warned_size = false;
+        loop {
+            ticker.tick().await;
+
+            let Some(fb) = ramfb.valid_framebuffer_spec() else {
+                continue;
+            };
+            if fb.fourcc != FOURCC_XR24 {
+                if !warned_format {
+                    warn!(log, "Cannot record framebuffer format";
+                          "fourcc" => format!("{:#x}", fb.fourcc));
+                    warned_format = true;
+                }
+                continue;
+            }
+            let Some(layout) = FrameLayout::of(&fb) else {
+                if !warned_size {
+                    warn!(log, "Cannot record framebuffer size";
+                          "width" => fb.width,
+                          "height" => fb.height,
+                          "stride" => fb.stride);
+                    warned_size = true;
+                }
+                continue;
+            };
+
+            let mut span = vec![0u8; layout.span];
+            let read = tokio::task::block_in_place(|| {
+                let memctx = vm.machine().acc_mem.access()?;
+                memctx.read_into(GuestAddr(fb.addr), &mut span, layout.span)
+            });
+            if read != Some(layout.span) {
+                continue;
+            }
+            let buf = layout.pixels(&span);
+            if let Some((w, h, pixels)) = &last {
+                if (*w, *h) == (fb.width, fb.height) && *pixels == buf {
+                    continue;
+                }
+            }
+
+            let png = encode_png(fb.width, fb.height, &buf);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let path =
+                directory.join(format!("{}-fb-{}.png", id, now.as_millis()));
+            if let Err(e) = tokio::fs::write(&path, png).await {
+                warn!(log, "Failed to write framebuffer recording, disabling it";
+                      "path" => %path.display(),
+                      "error" => %e);
+                return;
+            }
+            last = Some((fb.width, fb.height, buf));
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_png() {
+        // A 2x1 frame of one red and one blue pixel
+        let pixels = [0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00];
+        let png = encode_png(2, 1, &pixels);
+
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap());
+        assert_eq!(&png[37..41], b"IDAT");
+        let idat = &png[41..41 + idat_len as usize];
+        let mut raw = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::ZlibDecoder::new(idat),
+            &mut raw,
+        )
+        .unwrap();
+        assert_eq!(raw, [0, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff]);
+    }
+
+    fn spec(width: u32, height: u32, stride: u32) -> FramebufferSpec {
+        FramebufferSpec { addr: 0x1000, width, height, fourcc: 0, stride }
+    }
+
+    #[test]
+    fn frame_layout_bounded() {
+        assert_eq!(FrameLayout::of(&spec(u32::MAX, u32::MAX, 0)), None);
+        assert_eq!(FrameLayout::of(&spec(1024, 0, 0)), None);
+        assert_eq!(FrameLayout::of(&spec(1024, 768, 1000)), None);
+        assert_eq!(FrameLayout::of(&spec(1024, 768, u32::MAX)), None);
+
+        let layout = FrameLayout::of(&spec(1024, 768, 0)).unwrap();
+        assert_eq!(layout.line_size, 1024 * 4);
+        assert_eq!(layout.span, 1024 * 768 * 4);
+    }
+
+    #[test]
+    fn frame_layout_honors_stride() {
+        let layout = FrameLayout::of(&spec(640, 480, 648)).unwrap();
+        assert_eq!(layout.line_size, 648 * 4);
+        assert_eq!(layout.span, 479 * 648 * 4 + 640 * 4);
+
+        // Each line is followed by 8 pixels of padding, which is dropped.
+        let span: Vec<u8> = (0..layout.span)
+            .map(|i| u8::from(i % layout.line_size < 640 * 4))
+            .collect();
+        let pixels = layout.pixels(&span);
+        assert_eq!(pixels.len(), 640 * 480 * 4);
+        assert!(pixels.iter().all(|&b| b == 1));
+    }
+}
@@ -0,0 +1,412 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Requests made through the API of an agent running in the guest.
+//!
+//! The agent listens on a port of the instance's virtio-console device and
+//! speaks the QEMU guest agent protocol, so the agents packaged for most
+//! guests (such as `qemu-ga`) can serve these requests unchanged.  Each request
+//! is a JSON object naming the command to `execute` and its `arguments`, sent
+//! on a line of its own; the agent answers each with an object carrying either
+//! the command's `return` value or an `error`.
+//!
+//! The port has a single host-side connection, and the agent may still be
+//! answering a request whose client has gone away, so each session with the
+//! agent begins by resynchronizing with it: a `guest-sync-delimited` request
+//! makes the agent write a 0xFF byte ahead of its answer, before which anything
+//! left over from an earlier session is discarded.  Sessions are made one at a
+//! time.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use dropshot::HttpError;
+use propolis_api_types as api;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::UnixStream;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Time allowed for the agent to answer the request beginning a session.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time allowed for the agent to answer each request in a session.  Freezing
+/// the guest's file systems flushes their dirty data, which can take a while.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a command run in the guest to exit, if the request
+/// doesn't say.
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to ask the agent whether a command run in the guest has exited.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of bytes read from a file in the guest, if the request doesn't
+/// say, and the most that are read in one request.
+const DEFAULT_FILE_READ_BYTES: u64 = 64 * 1024;
+const MAX_FILE_READ_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub(crate) enum GuestAgentError {
+    #[error("No guest agent is configured")]
+    NotConfigured,
+
+    #[error("Failed to reach guest agent: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Guest agent did not respond")]
+    TimedOut,
+
+    #[error("Guest agent sent an unexpected reply: {0}")]
+    Protocol(String),
+
+    #[error("Guest agent reported {class}: {desc}")]
+    Agent { class: String, desc: String },
+
+    #[error("Process {0} did not exit in the time allowed")]
+    ExecTimedOut(i64),
+}
+
+impl From<GuestAgentError> for HttpError {
+    fn from(e: GuestAgentError) -> Self {
+        let msg = e.to_string();
+        match e {
+            GuestAgentError::NotConfigured
+            | GuestAgentError::Io(_)
+            | GuestAgentError::TimedOut => HttpError::for_unavail(
+                Some(api::ErrorCode::GuestAgentUnavailable.to_string()),
+                msg,
+            ),
+            GuestAgentError::Protocol(_) => HttpError::for_internal_error(msg),
+            GuestAgentError::Agent { .. } => HttpError::for_bad_request(
+                Some(api::ErrorCode::GuestAgentFailed.to_string()),
+                msg,
+            ),
+            GuestAgentError::ExecTimedOut(_) => HttpError::for_unavail(
+                Some(api::ErrorCode::GuestAgentFailed.to_string()),
+                msg,
+            ),
+        }
+    }
+}
+
+/// The agent listening on the virtio-console port whose host socket is
+/// `socket`.
+pub(crate) struct GuestAgent {
+    socket: PathBuf,
+    session: Mutex<()>,
+    next_sync_id: AtomicI64,
+}
+
+impl GuestAgent {
+    pub fn new(socket: PathBuf) -> Self {
+        // Sync IDs are drawn from the clock so that answers to a previous
+        // server's requests aren't mistaken for answers to this one's.
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        Self { socket, session: Mutex::new(()), next_sync_id: seed.into() }
+    }
+
+    async fn session(
+        &self,
+    ) -> Result<Session<'_, UnixStream>, GuestAgentError> {
+        let guard = self.session.lock().await;
+        let conn = UnixStream::connect(&self.socket).await?;
+        let sync_id = self.next_sync_id.fetch_add(1, Ordering::Relaxed);
+        Session::begin(guard, conn, sync_id).await
+    }
+
+    /// Runs a command in the guest and waits for it to exit.
+    pub async fn exec(
+        &self,
+        request: api::GuestExecRequest,
+    ) -> Result<api::GuestExecResponse, GuestAgentError> {
+        let timeout = request
+            .timeout_secs
+            .map_or(DEFAULT_EXEC_TIMEOUT, Duration::from_secs);
+        let mut session = self.session().await?;
+        let mut arguments = json!({
+            "path": request.path,
+            "arg": request.args,
+            "env": request.env,
+            "capture-output": true,
+        });
+        if let Some(input) = request.input {
+            arguments["input-data"] = input.into();
+        }
+        let pid = session.execute("guest-exec", arguments).await?["pid"]
+            .as_i64()
+            .ok_or_else(|| GuestAgentError::Protocol("no pid".to_string()))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = session
+                .execute("guest-exec-status", json!({ "pid": pid }))
+                .await?;
+            if status["exited"].as_bool() == Some(true) {
+                let output = |key: &str| {
+                    status[key].as_str().unwrap_or_default().to_string()
+                };
+                let truncated = |key: &str| status[key].as_bool() == Some(true);
+                return Ok(api::GuestExecResponse {
+                    exit_code: status["exitcode"].as_i64().map(|c| c as i32),
+                    signal: status["signal"].as_i64().map(|s| s as i32),
+                    stdout: output("out-data"),
+                    stderr: output("err-data"),
+                    output_truncated: truncated("out-truncated")
+                        || truncated("err-truncated"),
+                });
+            }
+            if Instant::now() >= deadline {
+                return Err(GuestAgentError::ExecTimedOut(pid));
+            }
+            tokio::time::sleep(EXEC_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Reads part of a file in the guest.
+    pub async fn read_file(
+        &self,
+        request: api::GuestFileReadRequest,
+    ) -> Result<api::GuestFileReadResponse, GuestAgentError> {
+        let count = request
+            .max_bytes
+            .unwrap_or(DEFAULT_FILE_READ_BYTES)
+            .min(MAX_FILE_READ_BYTES);
+        let mut session = self.session().await?;
+        let handle = session.open_file(&request.path, "r").await?;
+        let result = async {
+            if let Some(offset) = request.offset {
+                let seek = json!({
+                    "handle": handle,
+                    "offset": offset,
+                    "whence": "set",
+                });
+                session.execute("guest-file-seek", seek).await?;
+            }
+            let read = json!({ "handle": handle, "count": count });
+            session.execute("guest-file-read", read).await
+        }
+        .await;
+        session.close_file(handle).await;
+
+        let result = result?;
+        Ok(api::GuestFileReadResponse {
+            data: result["buf-b64"].as_str().unwrap_or_default().to_string(),
+            eof: result["eof"].as_bool().unwrap_or_default(),
+        })
+    }
+
+    /// Writes a file in the guest, replacing its contents unless the request
+    /// asks that they be appended to.
+    pub async fn write_file(
+        &self,
+        request: api::GuestFileWriteRequest,
+    ) -> Result<api::GuestFileWriteResponse, GuestAgentError> {
+        let mode = if request.append { "a" } else { "w" };
+        let mut session = self.session().await?;
+        let handle = session.open_file(&request.path, mode).await?;
+        let write = json!({ "handle": handle, "buf-b64": request.data });
+        let result = session.execute("guest-file-write", write).await;
+        session.close_file(handle).await;
+
+        let count = result?["count"].as_u64().ok_or_else(|| {
+            GuestAgentError::Protocol("no count of bytes written".to_string())
+        })?;
+        Ok(api::GuestFileWriteResponse { bytes_written: count })
+    }
+
+    /// Checks that the agent is answering requests.
+    pub async fn ping(&self) -> Result<(), GuestAgentError> {
+        let mut session = self.session().await?;
+        session.execute("guest-ping", json!({})).await.map(|_| ())
+    }
+
+    /// Freezes the guest's file systems, returning the number frozen.
+    pub async fn freeze(&self) -> Result<u64, GuestAgentError> {
+        let mut session = self.session().await?;
+        let frozen =
+            session.execute("guest-fsfreeze-freeze", json!({})).await?;
+        frozen.as_u64().ok_or_else(|| {
+            GuestAgentError::Protocol(format!("bad freeze count {frozen}"))
+        })
+    }
+
+    /// Thaws the guest's file systems, returning the number thawed.
+    pub async fn thaw(&self) -> Result<u64, GuestAgentError> {
+        let mut session = self.session().await?;
+        let thawed = session.execute("guest-fsfreeze-thaw", json!({})).await?;
+        thawed.as_u64().ok_or_else(|| {
+            GuestAgentError::Protocol(format!("bad thaw count {thawed}"))
+        })
+    }
+}
+
+/// A connection to the agent, resynchronized with it and held until dropped.
+struct Session<'a, S> {
+    _guard: MutexGuard<'a, ()>,
+    conn: BufReader<S>,
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Session<'a, S> {
+    async fn begin(
+        guard: MutexGuard<'a, ()>,
+        conn: S,
+        sync_id: i64,
+    ) -> Result<Self, GuestAgentError> {
+        let mut session = Self { _guard: guard, conn: BufReader::new(conn) };
+        tokio::time::timeout(SYNC_TIMEOUT, session.sync(sync_id))
+            .await
+            .map_err(|_| GuestAgentError::TimedOut)??;
+        Ok(session)
+    }
+
+    async fn sync(&mut self, sync_id: i64) -> Result<(), GuestAgentError> {
+        // A 0xFF byte makes the agent discard any partial request it has
+        // read, so that the one which follows is read from its start.
+        self.conn.write_all(&[0xff]).await?;
+        let request = json!({
+            "execute": "guest-sync-delimited",
+            "arguments": { "id": sync_id },
+        });
+        self.conn.write_all(format!("{request}\n").as_bytes()).await?;
+
+        loop {
+            let mut discarded = Vec::new();
+            if self.conn.read_until(0xff, &mut discarded).await? == 0 {
+                return Err(std::io::Error::from(
+                    std::io::ErrorKind::UnexpectedEof,
+                )
+                .into());
+            }
+            if discarded.last() != Some(&0xff) {
+                continue;
+            }
+            if self.read_reply().await? == json!(sync_id) {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn read_reply(&mut self) -> Result<Value, GuestAgentError> {
+        let mut line = String::new();
+        if self.conn.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )
+            .into());
+        }
+        let mut reply: Value = serde_json::from_str(&line)
+            .map_err(|e| GuestAgentError::Protocol(e.to_string()))?;
+        if let Some(error) = reply.get("error") {
+            let field =
+                |key: &str| error[key].as_str().unwrap_or_default().to_string();
+            return Err(GuestAgentError::Agent {
+                class: field("class"),
+                desc: field("desc"),
+            });
+        }
+        reply.get_mut("return").map(Value::take).ok_or_else(|| {
+            GuestAgentError::Protocol(format!("reply without return: {line}"))
+        })
+    }
+
+    /// Asks the agent to execute `command`, returning the command's return
+    /// value.
+    async fn execute(
+        &mut self,
+        command: &str,
+        arguments: Value,
+    ) -> Result<Value, GuestAgentError> {
+        let request = json!({ "execute": command, "arguments": arguments });
+        let exchange = async {
+            self.conn.write_all(format!("{request}\n").as_bytes()).await?;
+            self.read_reply().await
+        };
+        tokio::time::timeout(REPLY_TIMEOUT, exchange)
+            .await
+            .map_err(|_| GuestAgentError::TimedOut)?
+    }
+
+    async fn open_file(
+        &mut self,
+        path: &str,
+        mode: &str,
+    ) -> Result<i64, GuestAgentError> {
+        let open = json!({ "path": path, "mode": mode });
+        let handle = self.execute("guest-file-open", open).await?;
+        handle.as_i64().ok_or_else(|| {
+            GuestAgentError::Protocol(format!("bad file handle {handle}"))
+        })
+    }
+
+    /// Closes a file opened in the guest.  Failures are ignored: the request
+    /// for which the file was opened has already succeeded or failed.
+    async fn close_file(&mut self, handle: i64) {
+        let close = json!({ "handle": handle });
+        let _ = self.execute("guest-file-close", close).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Answers the requests read from `conn` with `replies`, in turn, after
+    /// writing some output left over from an earlier session.
+    async fn fake_agent(conn: tokio::io::DuplexStream, replies: Vec<Value>) {
+        let mut conn = BufReader::new(conn);
+        conn.write_all(b"{\"return\": 41}\n").await.unwrap();
+
+        let mut line = Vec::new();
+        conn.read_until(b'\n', &mut line).await.unwrap();
+        let sync: Value =
+            serde_json::from_slice(line.strip_prefix(&[0xff]).unwrap())
+                .unwrap();
+        assert_eq!(sync["execute"], "guest-sync-delimited");
+        let reply = json!({ "return": sync["arguments"]["id"] });
+        conn.write_all(&[0xff]).await.unwrap();
+        conn.write_all(format!("{reply}\n").as_bytes()).await.unwrap();
+
+        for reply in replies {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            conn.write_all(format!("{reply}\n").as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_skip_stale_output_and_report_errors() {
+        let lock = Mutex::new(());
+        let (host, guest) = tokio::io::duplex(4096);
+        let agent = tokio::spawn(fake_agent(
+            guest,
+            vec![
+                json!({ "return": 3 }),
+                json!({ "error": {
+                    "class": "GenericError",
+                    "desc": "no such file",
+                }}),
+            ],
+        ));
+
+        let mut session =
+            Session::begin(lock.lock().await, host, 42).await.unwrap();
+        assert_eq!(
+            session.execute("guest-fsfreeze-freeze", json!({})).await.unwrap(),
+            json!(3)
+        );
+        let open = json!({ "path": "/nope", "mode": "r" });
+        assert!(matches!(
+            session.execute("guest-file-open", open).await,
+            Err(GuestAgentError::Agent { class, .. }) if class == "GenericError"
+        ));
+        agent.await.unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A bounded history of what has happened to the server's instance: its state
+//! transitions, device errors, and the requests made of it through the API.
+//!
+//! The history belongs to the server rather than to the instance, so it
+//! remains available after the instance is destroyed.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use propolis_api_types::{
+    InstanceEvent, InstanceEventKind, InstanceHistoryResponse,
+};
+
+/// The number of events kept in the history.
+const HISTORY_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct Inner {
+    events: VecDeque<InstanceEvent>,
+
+    /// The sequence number to give the next event recorded.
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventHistory {
+    inner: Mutex<Inner>,
+    capacity: usize,
+}
+
+impl Default for EventHistory {
+    fn default() -> Self {
+        Self::with_capacity(HISTORY_CAPACITY)
+    }
+}
+
+impl EventHistory {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                events: VecDeque::with_capacity(capacity),
+                next_seq: 0,
+            }),
+            capacity,
+        }
+    }
+
+    /// Records that `kind` of event happened just now, discarding the oldest
+    /// event if the history is full.
+    pub fn record(&self, kind: InstanceEventKind) {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.events.len() == self.capacity {
+            inner.events.pop_front();
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.events.push_back(InstanceEvent { seq, time_ms, kind });
+    }
+
+    pub fn snapshot(&self) -> InstanceHistoryResponse {
+        let inner = self.inner.lock().unwrap();
+        InstanceHistoryResponse {
+            events: inner.events.iter().cloned().collect(),
+            dropped: inner.next_seq - inner.events.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn oldest_events_are_dropped() {
+        let history = EventHistory::with_capacity(2);
+        for event in ["halt", "reset", "triple fault"] {
+            history.record(InstanceEventKind::Guest { event: event.into() });
+        }
+
+        let snapshot = history.snapshot();
+        assert_eq!(snapshot.dropped, 1);
+        let seqs: Vec<_> = snapshot.events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [1, 2]);
+        assert_eq!(
+            snapshot.events[1].kind,
+            InstanceEventKind::Guest { event: "triple fault".into() }
+        );
+    }
+}
@@ -2,16 +2,24 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{Error, ErrorKind};
 use std::num::NonZeroUsize;
 use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::serial::history_buffer::TTY_BUFFER_SIZE;
+use crate::serial::log_file::RotatingLog;
+use crate::serial::recording::TtyRecorder;
 use crate::serial::Serial;
-use crate::server::{BlockBackendMap, CrucibleBackendMap, DeviceMap};
+use crate::server::{
+    BlockBackendMap, CrucibleBackendMap, DeviceMap, NicLinkMap,
+    NicRateLimiterMap, StorageDevice, StorageDeviceMap, VirtioDeviceMap,
+};
 use crate::stats::virtual_machine::VirtualMachine;
 use anyhow::{Context, Result};
 use crucible_client_types::VolumeConstructionRequest;
@@ -27,19 +35,28 @@ use propolis::hw::chipset::{i440fx, Chipset};
 use propolis::hw::ibmpc;
 use propolis::hw::pci;
 use propolis::hw::ps2::ctrl::PS2Ctrl;
+use propolis::hw::ps2::Keymap;
 use propolis::hw::qemu::pvpanic::QemuPvpanic;
 use propolis::hw::qemu::{debug::QemuDebugPort, fwcfg, ramfb};
 use propolis::hw::uart::LpcUart;
-use propolis::hw::{nvme, virtio};
+use propolis::hw::{ahci, e1000, ide, nvme, ppt, virtio};
 use propolis::intr_pins;
 use propolis::vmm::{self, Builder, Machine};
-use propolis_api_types::instance_spec::{self, v0::InstanceSpecV0};
+use propolis_api_types::instance_spec::{
+    self,
+    components::devices::SerialPortNumber,
+    v0::{InstanceSpecV0, NetworkBackendV0, NetworkDeviceV0},
+};
 use propolis_api_types::InstanceProperties;
 use slog::info;
 
 // Arbitrary ROM limit for now
 const MAX_ROM_SIZE: usize = 0x20_0000;
 
+/// Number of workers contributed to the file backend worker pool by a backend
+/// which does not specify its own count
+const DEFAULT_FILE_WORKERS: u32 = 8;
+
 fn get_spec_guest_ram_limits(spec: &InstanceSpecV0) -> (usize, usize) {
     let memsize = spec.devices.board.memory_mb as usize * MB;
     let lowmem = memsize.min(3 * GB);
@@ -81,14 +98,44 @@ pub fn build_instance(
     Ok(builder.finalize()?)
 }
 
+/// Bridges with hotplug slots, keyed by their logical downstream bus numbers.
+pub(crate) type HotplugBridgeMap = BTreeMap<u8, Arc<pci::bridge::Bridge>>;
+
 pub struct RegisteredChipset {
     chipset: Arc<dyn Chipset>,
     isa: Arc<i440fx::Piix3Lpc>,
+    pm: Arc<i440fx::Piix3PM>,
+    hotplug_bridges: HotplugBridgeMap,
 }
 impl RegisteredChipset {
     pub fn pci_attach(&self, bdf: pci::Bdf, dev: Arc<dyn pci::Endpoint>) {
+        // Devices beneath a hotplug bridge are placed in its slot, so that the
+        // guest sees them as present (and can later release them).
+        if let Some(bridge) = self.hotplug_bridge(bdf) {
+            assert_eq!(bdf.location, pci::hotplug::SLOT_LOCATION);
+            bridge.hotplug_insert(dev).unwrap_or_else(|e| {
+                panic!(
+                    "failed to insert device at {bdf} into hotplug slot: {e}"
+                )
+            });
+            return;
+        }
         self.chipset.pci_attach(bdf, dev, self.isa.route_lintr(bdf));
     }
+    /// Returns the hotplug bridge whose downstream bus `bdf` is on, if any.
+    pub(crate) fn hotplug_bridge(
+        &self,
+        bdf: pci::Bdf,
+    ) -> Option<&Arc<pci::bridge::Bridge>> {
+        self.hotplug_bridges.get(&bdf.bus.get())
+    }
+    pub(crate) fn hotplug_bridges(&self) -> &HotplugBridgeMap {
+        &self.hotplug_bridges
+    }
+    /// Returns the chipset's power management device.
+    pub(crate) fn pm(&self) -> &Arc<i440fx::Piix3PM> {
+        &self.pm
+    }
     pub fn irq_pin(&self, irq: u8) -> Option<Box<dyn intr_pins::IntrPin>> {
         self.isa.irq_pin(irq)
     }
@@ -97,9 +144,9 @@ impl RegisteredChipset {
     }
 }
 
-struct StorageBackendInstance {
-    be: Arc<dyn block::Backend>,
-    crucible: Option<(uuid::Uuid, Arc<block::CrucibleBackend>)>,
+pub(crate) struct StorageBackendInstance {
+    pub be: Arc<dyn block::Backend>,
+    pub crucible: Option<(uuid::Uuid, Arc<block::CrucibleBackend>)>,
 }
 
 #[derive(Default)]
@@ -113,6 +160,10 @@ pub struct MachineInitializer<'a> {
     pub(crate) devices: DeviceMap,
     pub(crate) block_backends: BlockBackendMap,
     pub(crate) crucible_backends: CrucibleBackendMap,
+    pub(crate) storage_devices: StorageDeviceMap,
+    pub(crate) virtio_devices: VirtioDeviceMap,
+    pub(crate) nic_rate_limiters: NicRateLimiterMap,
+    pub(crate) nic_links: NicLinkMap,
     pub(crate) spec: &'a InstanceSpecV0,
     pub(crate) properties: &'a InstanceProperties,
     pub(crate) toml_config: &'a crate::server::VmTomlConfig,
@@ -187,9 +238,14 @@ impl<'a> MachineInitializer<'a> {
         &mut self,
         event_handler: &Arc<dyn super::vm::ChipsetEventHandler>,
     ) -> Result<RegisteredChipset, Error> {
+        let enable_pcie = match self.spec.devices.board.chipset {
+            instance_spec::components::board::Chipset::I440Fx(i440fx) => {
+                i440fx.enable_pcie
+            }
+        };
         let mut pci_builder = pci::topology::Builder::new();
         for (name, bridge) in &self.spec.devices.pci_pci_bridges {
-            let desc = pci::topology::BridgeDescription::new(
+            let mut desc = pci::topology::BridgeDescription::new(
                 pci::topology::LogicalBusId(bridge.downstream_bus),
                 bridge.pci_path.try_into().map_err(|e| {
                     Error::new(
@@ -201,6 +257,31 @@ impl<'a> MachineInitializer<'a> {
                     )
                 })?,
             );
+            if let Some(slot) = bridge.hotplug_slot {
+                if !enable_pcie {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Bridge {} has a hotplug slot, which requires \
+                            PCIe to be enabled",
+                            name
+                        ),
+                    ));
+                }
+                if slot > pci::hotplug::MAX_PHYSICAL_SLOT {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Bridge {} has hotplug slot number {}, which \
+                            exceeds the maximum of {}",
+                            name,
+                            slot,
+                            pci::hotplug::MAX_PHYSICAL_SLOT
+                        ),
+                    ));
+                }
+                desc = desc.with_hotplug_slot(slot);
+            }
             pci_builder.add_bridge(desc)?;
         }
         let pci::topology::FinishedTopology { topology: pci_topology, bridges } =
@@ -270,17 +351,28 @@ impl<'a> MachineInitializer<'a> {
                     chipset_lpc.type_name().into(),
                     chipset_lpc.clone(),
                 );
-                self.devices.insert(chipset_pm.type_name().into(), chipset_pm);
+                self.devices
+                    .insert(chipset_pm.type_name().into(), chipset_pm.clone());
 
                 // Record attachment for any bridges in PCI topology too
+                let mut hotplug_bridges = HotplugBridgeMap::new();
                 for (bdf, bridge) in bridges {
+                    if bridge.hotplug_slot().is_some() {
+                        hotplug_bridges
+                            .insert(bridge.downstream_bus().0, bridge.clone());
+                    }
                     self.devices.insert(
                         format!("{}-{bdf}", bridge.type_name()),
                         bridge,
                     );
                 }
 
-                Ok(RegisteredChipset { chipset: chipset_hb, isa: chipset_lpc })
+                Ok(RegisteredChipset {
+                    chipset: chipset_hb,
+                    isa: chipset_lpc,
+                    pm: chipset_pm,
+                    hotplug_bridges,
+                })
             }
         }
     }
@@ -288,10 +380,11 @@ impl<'a> MachineInitializer<'a> {
     pub fn initialize_uart(
         &mut self,
         chipset: &RegisteredChipset,
-    ) -> Result<Serial<LpcUart>, Error> {
-        use instance_spec::components::devices::SerialPortNumber;
+    ) -> Result<BTreeMap<SerialPortNumber, Serial<LpcUart>>, Error> {
+        let sink_size = NonZeroUsize::new(64).unwrap();
+        let source_size = NonZeroUsize::new(1024).unwrap();
 
-        let mut com1 = None;
+        let mut ports = BTreeMap::new();
         for (name, serial_spec) in &self.spec.devices.serial_ports {
             let (irq, port) = match serial_spec.num {
                 SerialPortNumber::Com1 => (ibmpc::IRQ_COM1, ibmpc::PORT_COM1),
@@ -304,15 +397,71 @@ impl<'a> MachineInitializer<'a> {
             dev.set_autodiscard(true);
             LpcUart::attach(&dev, &self.machine.bus_pio, port);
             self.devices.insert(name.clone(), dev.clone());
-            if matches!(serial_spec.num, SerialPortNumber::Com1) {
-                assert!(com1.is_none());
-                com1 = Some(dev);
+
+            let history_size = match serial_spec.history_size {
+                Some(size) => usize::try_from(size).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("serial history size {size} is too large"),
+                    )
+                })?,
+                None => TTY_BUFFER_SIZE,
+            };
+            let mut serial =
+                Serial::new(dev, sink_size, source_size, history_size);
+            let port_name = match serial_spec.num {
+                SerialPortNumber::Com1 => "com1",
+                SerialPortNumber::Com2 => "com2",
+                SerialPortNumber::Com3 => "com3",
+                SerialPortNumber::Com4 => "com4",
+            };
+            if let Some(cfg) = &self.toml_config.serial_log {
+                std::fs::create_dir_all(&cfg.directory)?;
+                let path = cfg
+                    .directory
+                    .join(format!("{}-{}.log", self.properties.id, port_name));
+                info!(self.log, "Logging serial output";
+                      "port" => port_name,
+                      "path" => %path.display());
+                let output_log = RotatingLog::open(
+                    &path,
+                    cfg.max_file_size,
+                    cfg.max_files,
+                    self.log.new(slog::o!("port" => port_name)),
+                )?;
+                serial = serial.with_output_log(output_log);
             }
+            if let Some(cfg) =
+                self.toml_config.recording.as_ref().filter(|cfg| cfg.serial)
+            {
+                std::fs::create_dir_all(&cfg.directory)?;
+                let path = cfg.directory.join(format!(
+                    "{}-{}.ttyrec",
+                    self.properties.id, port_name
+                ));
+                info!(self.log, "Recording serial output";
+                      "port" => port_name,
+                      "path" => %path.display());
+                let recording = TtyRecorder::open(
+                    &path,
+                    self.log.new(slog::o!("port" => port_name)),
+                )?;
+                serial = serial.with_recording(recording);
+            }
+            let _old = ports.insert(serial_spec.num, serial);
+            assert!(_old.is_none());
         }
 
-        let sink_size = NonZeroUsize::new(64).unwrap();
-        let source_size = NonZeroUsize::new(1024).unwrap();
-        Ok(Serial::new(com1.unwrap(), sink_size, source_size))
+        // COM1 hosts the instance's primary console, whose history is carried
+        // along when the instance migrates.
+        if !ports.contains_key(&SerialPortNumber::Com1) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "instance spec has no COM1 serial port",
+            ));
+        }
+
+        Ok(ports)
     }
 
     pub fn initialize_ps2(
@@ -327,6 +476,14 @@ impl<'a> MachineInitializer<'a> {
             chipset.irq_pin(ibmpc::IRQ_PS2_AUX).unwrap(),
             chipset.reset_pin(),
         );
+        if let Some(cfg) = &self.toml_config.keyboard {
+            ps2_ctrl.set_keymap(keymap_from_config(cfg).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid keyboard configuration: {:#}", e),
+                )
+            })?);
+        }
         self.devices.insert(ps2_ctrl.type_name().into(), ps2_ctrl.clone());
 
         Ok(ps2_ctrl)
@@ -346,7 +503,7 @@ impl<'a> MachineInitializer<'a> {
     pub fn initialize_qemu_pvpanic(
         &mut self,
         virtual_machine: VirtualMachine,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<Option<Arc<QemuPvpanic>>, anyhow::Error> {
         if let Some(ref spec) = self.spec.devices.qemu_pvpanic {
             if spec.enable_isa {
                 let pvpanic = QemuPvpanic::create(
@@ -359,120 +516,126 @@ impl<'a> MachineInitializer<'a> {
                 if let Some(ref registry) = self.producer_registry {
                     let producer = crate::stats::PvpanicProducer::new(
                         virtual_machine,
-                        pvpanic,
+                        pvpanic.clone(),
                     );
                     registry.register_producer(producer).context(
                         "failed to register PVPANIC Oximeter producer",
                     )?;
                 }
+                return Ok(Some(pvpanic));
             }
         }
 
+        Ok(None)
+    }
+
+    pub fn initialize_virtio_socket(
+        &mut self,
+        chipset: &RegisteredChipset,
+    ) -> Result<(), Error> {
+        let Some(vsock) = &self.spec.devices.virtio_socket else {
+            return Ok(());
+        };
+
+        let bdf: pci::Bdf = vsock.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Couldn't get PCI BDF for vsock device: {}", e),
+            )
+        })?;
+
+        info!(
+            self.log,
+            "Creating vsock device for guest CID {}", vsock.guest_cid;
+            "socket_path" => &vsock.socket_path,
+        );
+        let dev = virtio::PciVirtioSocket::new(
+            u64::from(vsock.guest_cid),
+            0x100,
+            &vsock.socket_path,
+        )?;
+        self.devices.insert(format!("pci-virtio-vsock-{}", bdf), dev.clone());
+        chipset.pci_attach(bdf, dev);
         Ok(())
     }
 
-    fn create_storage_backend_from_spec(
-        &self,
-        backend_spec: &instance_spec::v0::StorageBackendV0,
-        backend_name: &str,
-        nexus_client: &Option<NexusClient>,
-    ) -> Result<StorageBackendInstance, Error> {
-        match backend_spec {
-            instance_spec::v0::StorageBackendV0::Crucible(spec) => {
-                info!(self.log, "Creating Crucible disk";
-                      "backend_name" => backend_name);
-
-                let vcr: VolumeConstructionRequest =
-                    serde_json::from_str(&spec.request_json)?;
-
-                let cru_id = match vcr {
-                    VolumeConstructionRequest::Volume { id, .. } => {
-                        id.to_string()
-                    }
-                    VolumeConstructionRequest::File { id, .. } => {
-                        id.to_string()
-                    }
-                    VolumeConstructionRequest::Url { id, .. } => id.to_string(),
-                    VolumeConstructionRequest::Region { .. } => {
-                        "Region".to_string()
-                    }
-                };
+    pub fn initialize_virtio_console(
+        &mut self,
+        chipset: &RegisteredChipset,
+    ) -> Result<(), Error> {
+        let Some(console) = &self.spec.devices.virtio_console else {
+            return Ok(());
+        };
 
-                let be = propolis::block::CrucibleBackend::create(
-                    vcr,
-                    propolis::block::BackendOpts {
-                        read_only: Some(spec.readonly),
-                        ..Default::default()
-                    },
-                    self.producer_registry.clone(),
-                    nexus_client.clone(),
-                    self.log.new(
-                        slog::o!("component" => format!("crucible-{cru_id}")),
-                    ),
-                )?;
+        let bdf: pci::Bdf = console.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Couldn't get PCI BDF for virtio-console device: {}",
+                    e
+                ),
+            )
+        })?;
 
-                let crucible = Some((be.get_uuid()?, be.clone()));
-                Ok(StorageBackendInstance { be, crucible })
-            }
-            instance_spec::v0::StorageBackendV0::File(spec) => {
-                info!(self.log, "Creating file disk backend";
-                      "path" => &spec.path);
-
-                // Check if raw device is being used and gripe if it isn't
-                let meta = std::fs::metadata(&spec.path)?;
-                if meta.file_type().is_block_device() {
-                    slog::warn!(
-                        self.log,
-                        "Block backend using standard device rather than raw";
-                        "path" => &spec.path
-                    );
-                }
+        let mut ports = Vec::with_capacity(console.ports.len());
+        for port in console.ports.iter() {
+            info!(
+                self.log,
+                "Creating virtio-console port {}", port.name;
+                "socket_path" => &port.socket_path,
+            );
+            ports.push(virtio::console::ConsolePort {
+                name: port.name.clone(),
+                uds_path: port.socket_path.clone().into(),
+            });
+        }
 
-                let nworkers = NonZeroUsize::new(8).unwrap();
-                let be = propolis::block::FileBackend::create(
-                    &spec.path,
-                    propolis::block::BackendOpts {
-                        read_only: Some(spec.readonly),
-                        ..Default::default()
-                    },
-                    nworkers,
-                )?;
+        let dev = virtio::PciVirtioConsole::new(0x100, ports)?;
+        self.devices.insert(format!("pci-virtio-console-{}", bdf), dev.clone());
+        chipset.pci_attach(bdf, dev);
+        Ok(())
+    }
 
-                Ok(StorageBackendInstance { be, crucible: None })
-            }
-            instance_spec::v0::StorageBackendV0::Blob(spec) => {
-                let bytes = base64::Engine::decode(
-                    &base64::engine::general_purpose::STANDARD,
-                    &spec.base64,
-                )
-                .map_err(|e| {
-                    Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!(
-                            "failed to decode base64 contents of in-memory \
-                                disk: {}",
-                            e
-                        ),
-                    )
-                })?;
+    pub fn initialize_virtio_tablet(
+        &mut self,
+        chipset: &RegisteredChipset,
+    ) -> Result<Option<Arc<virtio::PciVirtioTablet>>, Error> {
+        let Some(tablet) = &self.spec.devices.virtio_tablet else {
+            return Ok(None);
+        };
 
-                info!(self.log, "Creating in-memory disk backend";
-                      "len" => bytes.len());
+        let bdf: pci::Bdf = tablet.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Couldn't get PCI BDF for tablet device: {}", e),
+            )
+        })?;
 
-                let nworkers = NonZeroUsize::new(8).unwrap();
-                let be = propolis::block::InMemoryBackend::create(
-                    bytes,
-                    propolis::block::BackendOpts {
-                        block_size: Some(512),
-                        read_only: Some(spec.readonly),
-                        ..Default::default()
-                    },
-                    nworkers,
-                )?;
+        let dev = virtio::PciVirtioTablet::new(0x40);
+        self.devices.insert(format!("pci-virtio-tablet-{}", bdf), dev.clone());
+        chipset.pci_attach(bdf, dev.clone());
+        Ok(Some(dev))
+    }
 
-                Ok(StorageBackendInstance { be, crucible: None })
+    /// Creates the pool of workers shared by all of the file backends in this
+    /// initializer's instance spec, sized by the sum of the workers each of
+    /// them contributes.  Returns `None` if there are no file backends.
+    fn create_file_worker_pool(
+        &self,
+    ) -> Result<Option<Arc<block::FileWorkerPool>>, Error> {
+        let mut total = 0usize;
+        for (name, backend) in &self.spec.backends.storage_backends {
+            if let instance_spec::v0::StorageBackendV0::File(spec) = backend {
+                total += file_backend_workers(name, spec)?.get();
             }
         }
+
+        let Some(total) = NonZeroUsize::new(total) else {
+            return Ok(None);
+        };
+        info!(self.log, "Creating file backend worker pool";
+              "workers" => total);
+        Ok(Some(block::FileWorkerPool::new(total)?))
     }
 
     /// Initializes the storage devices and backends listed in this
@@ -484,12 +647,23 @@ impl<'a> MachineInitializer<'a> {
         &mut self,
         chipset: &RegisteredChipset,
         nexus_client: Option<NexusClient>,
+        virtual_machine: VirtualMachine,
+        error_notifier: Arc<dyn block::ErrorNotifier>,
     ) -> Result<(), Error> {
-        enum DeviceInterface {
-            Virtio,
-            Nvme,
+        enum DeviceInterface<'a> {
+            Virtio {
+                num_queues: Option<u16>,
+                moderation: Option<virtio::IntrModeration>,
+            },
+            Nvme(&'a instance_spec::components::devices::NvmeDisk),
+            Sata,
+            SataCdrom,
+            Ide,
         }
 
+        let file_pool = self.create_file_worker_pool()?;
+        let mut ide_attached = false;
+
         for (name, device_spec) in &self.spec.devices.storage_devices {
             info!(
                 self.log,
@@ -499,11 +673,33 @@ impl<'a> MachineInitializer<'a> {
             );
 
             let (device_interface, backend_name, pci_path) = match device_spec {
-                instance_spec::v0::StorageDeviceV0::VirtioDisk(disk) => {
-                    (DeviceInterface::Virtio, &disk.backend_name, disk.pci_path)
+                instance_spec::v0::StorageDeviceV0::VirtioDisk(disk) => (
+                    DeviceInterface::Virtio {
+                        num_queues: disk.num_queues,
+                        moderation: interrupt_moderation(
+                            "virtio disk",
+                            name,
+                            disk.interrupt_moderation.as_ref(),
+                        )?,
+                    },
+                    &disk.backend_name,
+                    disk.pci_path,
+                ),
+                instance_spec::v0::StorageDeviceV0::NvmeDisk(disk) => (
+                    DeviceInterface::Nvme(disk),
+                    &disk.backend_name,
+                    disk.pci_path,
+                ),
+                instance_spec::v0::StorageDeviceV0::SataDisk(disk) => {
+                    (DeviceInterface::Sata, &disk.backend_name, disk.pci_path)
                 }
-                instance_spec::v0::StorageDeviceV0::NvmeDisk(disk) => {
-                    (DeviceInterface::Nvme, &disk.backend_name, disk.pci_path)
+                instance_spec::v0::StorageDeviceV0::SataCdrom(cdrom) => (
+                    DeviceInterface::SataCdrom,
+                    &cdrom.backend_name,
+                    cdrom.pci_path,
+                ),
+                instance_spec::v0::StorageDeviceV0::IdeDisk(disk) => {
+                    (DeviceInterface::Ide, &disk.backend_name, disk.pci_path)
                 }
             };
 
@@ -531,40 +727,189 @@ impl<'a> MachineInitializer<'a> {
                     ),
                 )
             })?;
+            if chipset.hotplug_bridge(bdf).is_some() {
+                check_hotplug_disk(name, device_spec, bdf)?;
+            }
 
-            let StorageBackendInstance { be: backend, crucible } = self
-                .create_storage_backend_from_spec(
+            let StorageBackendInstance { be: backend, crucible } =
+                create_storage_backend_from_spec(
+                    &self.log,
+                    self.producer_registry.as_ref(),
                     backend_spec,
                     backend_name,
                     &nexus_client,
+                    file_pool.as_ref(),
                 )?;
 
             self.block_backends.insert(backend_name.clone(), backend.clone());
-            match device_interface {
-                DeviceInterface::Virtio => {
-                    let vioblk = virtio::PciVirtioBlock::new(0x100);
+            let mut cdrom = None;
+            let (device, block_dev): (
+                Arc<dyn Lifecycle>,
+                Arc<dyn block::Device>,
+            ) = match device_interface {
+                DeviceInterface::Virtio { num_queues, moderation } => {
+                    let num_queues = num_queues.unwrap_or(1);
+                    if num_queues == 0
+                        || num_queues > virtio::block::VIRTIO_BLK_MAX_QUEUES
+                    {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "virtio disk {} must have between 1 and {} \
+                                queues",
+                                name,
+                                virtio::block::VIRTIO_BLK_MAX_QUEUES
+                            ),
+                        ));
+                    }
+                    let vioblk = virtio::PciVirtioBlock::new(
+                        0x100, num_queues, moderation,
+                    );
 
                     self.devices
                         .insert(format!("pci-virtio-{}", bdf), vioblk.clone());
-                    block::attach(vioblk.clone(), backend).unwrap();
-                    chipset.pci_attach(bdf, vioblk);
+                    self.virtio_devices.insert(name.clone(), vioblk.clone());
+                    block::attach(vioblk.clone(), backend.clone()).unwrap();
+                    chipset.pci_attach(bdf, vioblk.clone());
+                    (vioblk.clone(), vioblk)
+                }
+                DeviceInterface::Nvme(disk) => {
+                    let nvme = create_nvme_disk(&self.log, name, disk)?;
+                    self.devices
+                        .insert(format!("pci-nvme-{bdf}"), nvme.clone());
+                    block::attach(nvme.clone(), backend.clone()).unwrap();
+                    chipset.pci_attach(bdf, nvme.clone());
+                    (nvme.clone(), nvme)
                 }
-                DeviceInterface::Nvme => {
-                    // Limit data transfers to 1MiB (2^8 * 4k) in size
-                    let mdts = Some(8);
-                    let nvme = nvme::PciNvme::create(
+                DeviceInterface::Sata => {
+                    let ahci = ahci::PciAhci::create(
                         name.to_string(),
-                        mdts,
                         self.log.new(
-                            slog::o!("component" => format!("nvme-{}", name)),
+                            slog::o!("component" => format!("ahci-{}", name)),
                         ),
                     );
                     self.devices
-                        .insert(format!("pci-nvme-{bdf}"), nvme.clone());
-                    block::attach(nvme.clone(), backend).unwrap();
-                    chipset.pci_attach(bdf, nvme);
+                        .insert(format!("pci-ahci-{bdf}"), ahci.clone());
+                    block::attach(ahci.clone(), backend.clone()).unwrap();
+                    chipset.pci_attach(bdf, ahci.clone());
+                    (ahci.clone(), ahci)
+                }
+                DeviceInterface::SataCdrom => {
+                    let block_size = backend.info().block_size as usize;
+                    if ahci::CDROM_SECTOR_SIZE % block_size != 0 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "CD-ROM {} has backend with block size {}, \
+                                which does not divide the {}-byte sector size",
+                                name,
+                                block_size,
+                                ahci::CDROM_SECTOR_SIZE
+                            ),
+                        ));
+                    }
+                    let ahci = ahci::PciAhci::create_cdrom(
+                        name.to_string(),
+                        self.log.new(
+                            slog::o!("component" => format!("ahci-{}", name)),
+                        ),
+                    );
+                    self.devices
+                        .insert(format!("pci-ahci-{bdf}"), ahci.clone());
+                    block::attach(ahci.clone(), backend.clone()).unwrap();
+                    chipset.pci_attach(bdf, ahci.clone());
+                    cdrom = Some(ahci.clone());
+                    (ahci.clone(), ahci)
                 }
+                DeviceInterface::Ide => {
+                    // The IDE controller is slow enough that it's only made
+                    // available to instances on servers configured to run
+                    // the ancient guests which need it.
+                    let enabled = self
+                        .toml_config
+                        .chipset
+                        .options
+                        .get("enable-legacy-ide")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    if !enabled {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "IDE disk {} requires the enable-legacy-ide \
+                                chipset option, which is not set",
+                                name
+                            ),
+                        ));
+                    }
+                    if ide_attached {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "IDE disk {} cannot be attached, as only one \
+                                IDE disk is supported",
+                                name
+                            ),
+                        ));
+                    }
+                    ide_attached = true;
+
+                    let ide = ide::PiixIde::create(
+                        name.to_string(),
+                        chipset.irq_pin(ibmpc::IRQ_IDE_PRI).unwrap(),
+                        self.log.new(
+                            slog::o!("component" => format!("ide-{}", name)),
+                        ),
+                    );
+                    ide.attach_legacy(&self.machine.bus_pio);
+                    self.devices
+                        .insert(format!("pci-piix3-ide-{bdf}"), ide.clone());
+                    block::attach(ide.clone(), backend.clone()).unwrap();
+                    chipset.pci_attach(bdf, ide.clone());
+                    (ide.clone(), ide)
+                }
+            };
+            let error_policy = match backend_spec {
+                instance_spec::v0::StorageBackendV0::Crucible(spec) => {
+                    spec.error_policy
+                }
+                instance_spec::v0::StorageBackendV0::File(spec) => {
+                    spec.error_policy
+                }
+                instance_spec::v0::StorageBackendV0::Blob(_) => None,
             };
+            block_dev.attachment().set_name(name.clone());
+            block_dev.attachment().set_error_policy(
+                block_error_policy(error_policy.unwrap_or_default()),
+                Some(error_notifier.clone()),
+            );
+            if let Some(ref registry) = self.producer_registry {
+                let producer = crate::stats::BlockProducer::new(
+                    virtual_machine.clone(),
+                    name.clone(),
+                    block_dev.clone(),
+                );
+                registry.register_producer(producer).map_err(|e| {
+                    Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "failed to register block Oximeter producer \
+                            for {}: {}",
+                            name, e
+                        ),
+                    )
+                })?;
+            }
+            self.storage_devices.insert(
+                name.clone(),
+                StorageDevice {
+                    device,
+                    block_dev,
+                    backend,
+                    crucible: crucible.as_ref().map(|(_id, be)| be.clone()),
+                    cdrom,
+                },
+            );
 
             if let Some((id, backend)) = crucible {
                 let prev = self.crucible_backends.insert(id, backend);
@@ -582,57 +927,259 @@ impl<'a> MachineInitializer<'a> {
     pub fn initialize_network_devices(
         &mut self,
         chipset: &RegisteredChipset,
+        virtual_machine: VirtualMachine,
     ) -> Result<(), Error> {
-        for (name, vnic_spec) in &self.spec.devices.network_devices {
+        for (name, nic_spec) in &self.spec.devices.network_devices {
             info!(self.log, "Creating vNIC {}", name);
-            let instance_spec::v0::NetworkDeviceV0::VirtioNic(vnic_spec) =
-                vnic_spec;
+            let (backend_name, pci_path, rate_limit) = match nic_spec {
+                NetworkDeviceV0::VirtioNic(nic) => {
+                    (&nic.backend_name, nic.pci_path, nic.rate_limit)
+                }
+                NetworkDeviceV0::E1000Nic(nic) => {
+                    (&nic.backend_name, nic.pci_path, nic.rate_limit)
+                }
+                NetworkDeviceV0::PassthroughNic(nic) => {
+                    (&nic.backend_name, nic.pci_path, None)
+                }
+            };
+            let rate_limits = nic_rate_limits(name, rate_limit.as_ref())?;
 
             let backend_spec = self
                 .spec
                 .backends
                 .network_backends
-                .get(&vnic_spec.backend_name)
+                .get(backend_name)
                 .ok_or_else(|| {
                     Error::new(
                         ErrorKind::InvalidInput,
                         format!(
                             "Backend {} not found for vNIC {}",
-                            vnic_spec.backend_name, name
+                            backend_name, name
                         ),
                     )
                 })?;
-            let bdf: pci::Bdf = vnic_spec.pci_path.try_into().map_err(|e| {
+            let bdf: pci::Bdf = pci_path.try_into().map_err(|e| {
                 Error::new(
                     ErrorKind::InvalidInput,
                     format!("Couldn't get PCI BDF for vNIC {}: {}", name, e),
                 )
             })?;
+            if chipset.hotplug_bridge(bdf).is_some() {
+                check_hotplug_nic(name, bdf)?;
+            }
 
-            let vnic_name = match backend_spec {
-                instance_spec::v0::NetworkBackendV0::Virtio(spec) => {
-                    &spec.vnic_name
+            match (nic_spec, backend_spec) {
+                (
+                    NetworkDeviceV0::VirtioNic(nic),
+                    NetworkBackendV0::Virtio(spec),
+                ) => {
+                    check_viona_nic(name, nic)?;
+                    let viona = create_viona_nic(nic, spec, &self.machine.hdl)?;
+                    self.devices.insert(
+                        format!("pci-virtio-viona-{}", bdf),
+                        viona.clone(),
+                    );
+                    self.virtio_devices.insert(name.clone(), viona.clone());
+                    self.register_nic_link(name, nic, viona.clone());
+                    self.register_nic_stats(
+                        &virtual_machine,
+                        name,
+                        crate::stats::NicStatsSource::Vnic(
+                            viona.vnic_name().to_owned(),
+                        ),
+                    )?;
+                    chipset.pci_attach(bdf, viona);
+                }
+                (
+                    NetworkDeviceV0::PassthroughNic(_),
+                    NetworkBackendV0::Ppt(spec),
+                ) => {
+                    let ppt = ppt::PciPpt::create(
+                        Path::new(&spec.ppt_path),
+                        self.machine.hdl.clone(),
+                    )?;
+                    self.devices
+                        .insert(format!("pci-ppt-{}", bdf), ppt.clone());
+                    chipset.pci_attach(bdf, ppt);
                 }
-                instance_spec::v0::NetworkBackendV0::Dlpi(_) => {
+                (NetworkDeviceV0::PassthroughNic(_), _)
+                | (NetworkDeviceV0::VirtioNic(_), NetworkBackendV0::Ppt(_)) => {
                     return Err(Error::new(
                         ErrorKind::InvalidInput,
                         format!(
-                            "Network backend must be virtio for vNIC {}",
-                            name,
+                            "vNIC {} must be a passthrough NIC if, and only \
+                            if, it has a ppt backend",
+                            name
                         ),
                     ));
                 }
-            };
+                (
+                    NetworkDeviceV0::VirtioNic(nic),
+                    NetworkBackendV0::Dlpi(spec),
+                ) => {
+                    // Without viona, frames pass through a virtio-net device
+                    // emulated in userspace, which has only a single pair of
+                    // queues.
+                    if nic.num_queue_pairs.is_some_and(|n| n > 1) {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "vNIC {} requires a virtio backend for \
+                                multiple queue pairs",
+                                name
+                            ),
+                        ));
+                    }
+                    let backend = self.rate_limited_backend(
+                        name,
+                        dlpi_backend(&spec.vnic_name)?,
+                        rate_limits,
+                    );
+                    let vionet = virtio::PciVirtioNet::new(
+                        virtio::PciVirtioNet::default_mac(bdf),
+                        0x100,
+                        backend,
+                        interrupt_moderation(
+                            "vNIC",
+                            name,
+                            nic.interrupt_moderation.as_ref(),
+                        )?,
+                    );
+                    self.devices.insert(
+                        format!("pci-virtio-net-{}", bdf),
+                        vionet.clone(),
+                    );
+                    self.virtio_devices.insert(name.clone(), vionet.clone());
+                    self.register_nic_link(name, nic, vionet.clone());
+                    self.register_nic_stats(
+                        &virtual_machine,
+                        name,
+                        crate::stats::NicStatsSource::Device(vionet.clone()),
+                    )?;
+                    chipset.pci_attach(bdf, vionet);
+                }
+                (NetworkDeviceV0::E1000Nic(_), backend_spec) => {
+                    // The e1000's datapath is always in userspace, reaching
+                    // the named vNIC through DLPI whichever kind of backend
+                    // names it.
+                    let vnic_name = match backend_spec {
+                        NetworkBackendV0::Virtio(spec) => {
+                            if spec.vlan_id.is_some() {
+                                return Err(Error::new(
+                                    ErrorKind::InvalidInput,
+                                    format!(
+                                        "vNIC {} can't be given a VLAN, \
+                                        which only virtio NICs support",
+                                        name
+                                    ),
+                                ));
+                            }
+                            &spec.vnic_name
+                        }
+                        NetworkBackendV0::Dlpi(spec) => &spec.vnic_name,
+                        NetworkBackendV0::Ppt(_) => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                format!(
+                                    "vNIC {} can't use a ppt backend",
+                                    name
+                                ),
+                            ));
+                        }
+                    };
+                    let backend = self.rate_limited_backend(
+                        name,
+                        dlpi_backend(vnic_name)?,
+                        rate_limits,
+                    );
+                    let e1000 = e1000::PciE1000::new(
+                        virtio::PciVirtioNet::default_mac(bdf),
+                        backend,
+                    );
+                    self.devices
+                        .insert(format!("pci-e1000-{}", bdf), e1000.clone());
+                    chipset.pci_attach(bdf, e1000);
+                }
+            }
+        }
+        Ok(())
+    }
 
-            let viona = virtio::PciVirtioViona::new(
-                vnic_name,
-                0x100,
-                &self.machine.hdl,
-            )?;
-            self.devices
-                .insert(format!("pci-virtio-viona-{}", bdf), viona.clone());
-            chipset.pci_attach(bdf, viona);
+    /// Wraps the backend of the userspace NIC named `name` so that its traffic
+    /// is subject to `limits`, which may later be changed at runtime.
+    fn rate_limited_backend(
+        &mut self,
+        name: &str,
+        backend: Arc<dyn propolis::net::Backend>,
+        limits: propolis::net::RateLimits,
+    ) -> Arc<dyn propolis::net::Backend> {
+        let limited =
+            Arc::new(propolis::net::RateLimitedBackend::new(backend, limits));
+        self.nic_rate_limiters.insert(name.to_owned(), limited.clone());
+        limited
+    }
+
+    /// Makes the link of the virtio NIC named `name` controllable at runtime,
+    /// starting it in the state given by its spec.
+    fn register_nic_link(
+        &mut self,
+        name: &str,
+        nic: &instance_spec::components::devices::VirtioNic,
+        link: Arc<dyn virtio::NetLink>,
+    ) {
+        link.set_link_up(nic_link_up(nic));
+        self.nic_links.insert(name.to_owned(), link);
+    }
+
+    /// Registers an Oximeter producer for the traffic statistics of the virtio
+    /// NIC named `name`.
+    fn register_nic_stats(
+        &self,
+        virtual_machine: &VirtualMachine,
+        name: &str,
+        source: crate::stats::NicStatsSource,
+    ) -> Result<(), Error> {
+        let Some(ref registry) = self.producer_registry else {
+            return Ok(());
+        };
+        let producer = crate::stats::NicProducer::new(
+            virtual_machine.clone(),
+            name.to_owned(),
+            source,
+        );
+        registry.register_producer(producer).map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!(
+                    "failed to register NIC Oximeter producer for {}: {}",
+                    name, e
+                ),
+            )
+        })
+    }
+
+    /// Registers an Oximeter producer for the queue statistics of each of the
+    /// virtio devices created so far.
+    pub fn initialize_virtio_stats(
+        &self,
+        virtual_machine: VirtualMachine,
+    ) -> Result<(), anyhow::Error> {
+        let Some(ref registry) = self.producer_registry else {
+            return Ok(());
+        };
+        for (name, device) in self.virtio_devices.iter() {
+            let producer = crate::stats::VirtioProducer::new(
+                virtual_machine.clone(),
+                name.clone(),
+                device.clone(),
+            );
+            registry.register_producer(producer).with_context(|| {
+                format!(
+                    "failed to register virtio Oximeter producer for {name}"
+                )
+            })?;
         }
+
         Ok(())
     }
 
@@ -993,13 +1540,15 @@ impl<'a> MachineInitializer<'a> {
     }
 
     /// Initialize qemu `fw_cfg` device, and populate it with data including CPU
-    /// count, SMBIOS tables, and attached RAM-FB device.
+    /// count, SMBIOS tables, and attached RAM-FB device and its EDID.  If
+    /// `boot_menu` is set, the bootrom is also asked to show its boot menu.
     ///
     /// Should not be called before [`Self::initialize_rom()`].
     pub fn initialize_fwcfg(
         &mut self,
         cpus: u8,
-    ) -> Result<Arc<ramfb::RamFb>, Error> {
+        boot_menu: bool,
+    ) -> Result<(Arc<fwcfg::FwCfg>, Arc<ramfb::RamFb>), Error> {
         let fwcfg = fwcfg::FwCfg::new();
         fwcfg
             .insert_legacy(
@@ -1008,6 +1557,24 @@ impl<'a> MachineInitializer<'a> {
             )
             .unwrap();
 
+        if boot_menu {
+            // These are the entries QEMU provides for `-boot menu=on`: the
+            // bootrom shows its boot menu, and waits as long as it can (the
+            // wait is a 16-bit count of milliseconds) for a key press.
+            fwcfg
+                .insert_legacy(
+                    fwcfg::LegacyId::BootMenu,
+                    fwcfg::Entry::Bytes(1u16.to_le_bytes().to_vec()),
+                )
+                .unwrap();
+            fwcfg
+                .insert_named(
+                    "etc/boot-menu-wait",
+                    fwcfg::Entry::Bytes(u16::MAX.to_le_bytes().to_vec()),
+                )
+                .unwrap();
+        }
+
         let smbios::TableBytes { entry_point, structure_table } =
             self.generate_smbios();
         fwcfg
@@ -1030,13 +1597,19 @@ impl<'a> MachineInitializer<'a> {
         fwcfg
             .insert_named(ramfb::RamFb::FWCFG_ENTRY_NAME, fwcfg::Entry::RamFb)
             .unwrap();
+        fwcfg
+            .insert_named(
+                ramfb::RamFb::FWCFG_EDID_NAME,
+                fwcfg::Entry::Bytes(ramfb.edid()),
+            )
+            .unwrap();
         fwcfg.attach_ramfb(Some(ramfb.clone()));
 
         fwcfg.attach(&self.machine.bus_pio, &self.machine.acc_mem);
 
-        self.devices.insert(fwcfg.type_name().into(), fwcfg);
+        self.devices.insert(fwcfg.type_name().into(), fwcfg.clone());
         self.devices.insert(ramfb.type_name().into(), ramfb.clone());
-        Ok(ramfb)
+        Ok((fwcfg, ramfb))
     }
 
     pub fn initialize_cpus(&mut self) -> Result<(), Error> {
@@ -1049,3 +1622,496 @@ impl<'a> MachineInitializer<'a> {
         Ok(())
     }
 }
+
+/// Validates the configuration of the NVMe disk named `name` and creates its
+/// controller, which is not yet attached to a backend or to the PCI topology.
+pub(crate) fn create_nvme_disk(
+    log: &slog::Logger,
+    name: &str,
+    disk: &instance_spec::components::devices::NvmeDisk,
+) -> Result<Arc<nvme::PciNvme>, Error> {
+    if disk.num_queues.is_some_and(|n| n == 0 || n > nvme::MAX_NUM_IO_QUEUES) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "NVMe disk {} must have between 1 and {} queues",
+                name,
+                nvme::MAX_NUM_IO_QUEUES
+            ),
+        ));
+    }
+    if disk.queue_size.is_some_and(|n| {
+        !(nvme::MIN_IO_QUEUE_SIZE..=nvme::MAX_IO_QUEUE_SIZE).contains(&n)
+    }) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "NVMe disk {} must have a queue size between {} and {}",
+                name,
+                nvme::MIN_IO_QUEUE_SIZE,
+                nvme::MAX_IO_QUEUE_SIZE
+            ),
+        ));
+    }
+
+    for (field, value, max_len) in [
+        ("model number", &disk.model_number, nvme::MAX_MODEL_NUMBER_LEN),
+        (
+            "firmware revision",
+            &disk.firmware_revision,
+            nvme::MAX_FIRMWARE_REV_LEN,
+        ),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        if value.len() > max_len
+            || !value.bytes().all(|b| (0x20..0x7f).contains(&b))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "NVMe disk {} must have a {} of at most {} printable \
+                    ASCII characters",
+                    name, field, max_len
+                ),
+            ));
+        }
+    }
+
+    let identity = nvme::CtrlIdentity {
+        model_number: disk.model_number.clone(),
+        firmware_revision: disk.firmware_revision.clone(),
+        ieee_oui: disk.ieee_oui,
+    };
+    // Limit data transfers to 1MiB (2^8 * 4k) in size
+    let mdts = Some(8);
+    Ok(nvme::PciNvme::create(
+        name.to_string(),
+        identity,
+        mdts,
+        disk.num_queues,
+        disk.queue_size,
+        log.new(slog::o!("component" => format!("nvme-{}", name))),
+    ))
+}
+
+/// Checks that the storage device named `name`, which is at `bdf` beneath a
+/// hotplug bridge, can occupy that bridge's slot.
+pub(crate) fn check_hotplug_disk(
+    name: &str,
+    device_spec: &instance_spec::v0::StorageDeviceV0,
+    bdf: pci::Bdf,
+) -> Result<(), Error> {
+    if !matches!(device_spec, instance_spec::v0::StorageDeviceV0::NvmeDisk(_)) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Storage device {} is beneath a hotplug bridge, where only \
+                NVMe disks are supported",
+                name
+            ),
+        ));
+    }
+    if bdf.location != pci::hotplug::SLOT_LOCATION {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "NVMe disk {} is beneath a hotplug bridge, so must be at \
+                device 0, function 0 of its bus",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Creates the viona device for the virtio NIC `nic`, whose datapath runs
+/// in the host kernel over the VNIC named by `backend`.
+pub(crate) fn create_viona_nic(
+    nic: &instance_spec::components::devices::VirtioNic,
+    backend: &instance_spec::components::backends::VirtioNetworkBackend,
+    hdl: &vmm::VmmHdl,
+) -> Result<Arc<virtio::PciVirtioViona>, Error> {
+    let offloads = nic.offloads.unwrap_or_default();
+    virtio::PciVirtioViona::new(
+        &backend.vnic_name,
+        backend.vlan_id,
+        0x100,
+        nic.num_queue_pairs.unwrap_or(1),
+        virtio::viona::NetOffloads {
+            tx_checksum: offloads.tx_checksum,
+            tso: offloads.tso,
+            rx_checksum: offloads.rx_checksum,
+            lro: offloads.lro,
+        },
+        hdl,
+    )
+}
+
+/// Translates the rate limits in the spec of the NIC named `name` into those
+/// enforced by its backend, checking that they are sensible.
+pub(crate) fn nic_rate_limits(
+    name: &str,
+    spec: Option<&instance_spec::components::devices::NicRateLimit>,
+) -> Result<propolis::net::RateLimits, Error> {
+    let bucket = |limit: Option<
+        instance_spec::components::devices::RateLimit,
+    >,
+                  dir: &str| {
+        limit
+            .map(|l| {
+                propolis::net::TokenBucket::new(l.bytes_per_sec, l.burst_bytes)
+            })
+            .transpose()
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "invalid {} rate limit for vNIC {}: {}",
+                        dir, name, e
+                    ),
+                )
+            })
+    };
+    let Some(spec) = spec else {
+        return Ok(propolis::net::RateLimits::default());
+    };
+    Ok(propolis::net::RateLimits {
+        tx: bucket(spec.tx, "transmit")?,
+        rx: bucket(spec.rx, "receive")?,
+    })
+}
+
+/// Translates the interrupt moderation in the spec of the virtio device named
+/// `name` into that applied to its queues, checking that it is sensible.
+pub(crate) fn interrupt_moderation(
+    kind: &str,
+    name: &str,
+    spec: Option<
+        &instance_spec::components::devices::VirtioInterruptModeration,
+    >,
+) -> Result<Option<virtio::IntrModeration>, Error> {
+    spec.map(|m| virtio::IntrModeration::new(m.max_packets, m.max_usecs))
+        .transpose()
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid interrupt moderation for {} {}: {}",
+                    kind, name, e
+                ),
+            )
+        })
+}
+
+/// Rejects settings of the virtio NIC `nic` which cannot be honored if it is
+/// to be backed by viona: its datapath in the kernel is beyond the reach of
+/// the rate limiter, and it is the kernel which interrupts the guest.
+pub(crate) fn check_viona_nic(
+    name: &str,
+    nic: &instance_spec::components::devices::VirtioNic,
+) -> Result<(), Error> {
+    if nic.rate_limit.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "vNIC {} has a virtio backend, so cannot be rate limited; \
+                rate limits require a DLPI backend",
+                name
+            ),
+        ));
+    }
+    if nic.interrupt_moderation.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "vNIC {} has a virtio backend, so cannot moderate its \
+                interrupts; interrupt moderation requires a DLPI backend",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Is the link of the virtio NIC `nic` up, according to its spec?
+pub(crate) fn nic_link_up(
+    nic: &instance_spec::components::devices::VirtioNic,
+) -> bool {
+    use instance_spec::components::devices::NicLinkState;
+    nic.link_state != Some(NicLinkState::Down)
+}
+
+/// Checks that the NIC named `name`, which is at `bdf` beneath a hotplug
+/// bridge, can occupy that bridge's slot.
+pub(crate) fn check_hotplug_nic(
+    name: &str,
+    bdf: pci::Bdf,
+) -> Result<(), Error> {
+    if bdf.location != pci::hotplug::SLOT_LOCATION {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "vNIC {} is beneath a hotplug bridge, so must be at device 0, \
+                function 0 of its bus",
+                name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Translates an instance spec storage error policy into its block-layer
+/// equivalent.
+pub(crate) fn block_error_policy(
+    policy: instance_spec::components::backends::StorageErrorPolicy,
+) -> block::ErrorPolicy {
+    use instance_spec::components::backends::StorageErrorPolicy;
+    match policy {
+        StorageErrorPolicy::Report => block::ErrorPolicy::Report,
+        StorageErrorPolicy::Retry { max_attempts } => {
+            block::ErrorPolicy::Retry { max_attempts }
+        }
+        StorageErrorPolicy::Pause => block::ErrorPolicy::Pause,
+    }
+}
+
+/// Determines the number of workers a file backend contributes to the pool.
+fn file_backend_workers(
+    name: &str,
+    spec: &instance_spec::components::backends::FileStorageBackend,
+) -> Result<NonZeroUsize, Error> {
+    let workers = spec.workers.unwrap_or(DEFAULT_FILE_WORKERS);
+    NonZeroUsize::new(workers as usize).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("workers for file backend {} must be non-zero", name),
+        )
+    })
+}
+
+/// Opens the datalink `vnic_name` as the backend of a userspace NIC.
+#[cfg(feature = "dlpi")]
+fn dlpi_backend(
+    vnic_name: &str,
+) -> Result<Arc<dyn propolis::net::Backend>, Error> {
+    Ok(Arc::new(propolis::net::DlpiBackend::open(vnic_name)?))
+}
+
+#[cfg(not(feature = "dlpi"))]
+fn dlpi_backend(
+    vnic_name: &str,
+) -> Result<Arc<dyn propolis::net::Backend>, Error> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "cannot open {}: DLPI network backends are not supported by this \
+            build",
+            vnic_name
+        ),
+    ))
+}
+
+/// Creates the storage backend described by `backend_spec`.
+///
+/// File backends are serviced by the workers of `file_pool` if one is
+/// provided, or otherwise by a pool of their own.
+pub(crate) fn create_storage_backend_from_spec(
+    log: &slog::Logger,
+    producer_registry: Option<&ProducerRegistry>,
+    backend_spec: &instance_spec::v0::StorageBackendV0,
+    backend_name: &str,
+    nexus_client: &Option<NexusClient>,
+    file_pool: Option<&Arc<block::FileWorkerPool>>,
+) -> Result<StorageBackendInstance, Error> {
+    match backend_spec {
+        instance_spec::v0::StorageBackendV0::Crucible(spec) => {
+            info!(log, "Creating Crucible disk";
+                  "backend_name" => backend_name);
+
+            let vcr: VolumeConstructionRequest =
+                serde_json::from_str(&spec.request_json)?;
+
+            let cru_id = match vcr {
+                VolumeConstructionRequest::Volume { id, .. } => id.to_string(),
+                VolumeConstructionRequest::File { id, .. } => id.to_string(),
+                VolumeConstructionRequest::Url { id, .. } => id.to_string(),
+                VolumeConstructionRequest::Region { .. } => {
+                    "Region".to_string()
+                }
+            };
+
+            let be = propolis::block::CrucibleBackend::create(
+                vcr,
+                propolis::block::BackendOpts {
+                    read_only: Some(spec.readonly),
+                    lazy_attach: spec.lazy_attach,
+                    ..Default::default()
+                },
+                producer_registry.cloned(),
+                nexus_client.clone(),
+                log.new(slog::o!("component" => format!("crucible-{cru_id}"))),
+            )?;
+
+            let crucible = Some((be.get_uuid()?, be.clone()));
+            Ok(StorageBackendInstance { be, crucible })
+        }
+        instance_spec::v0::StorageBackendV0::File(spec) => {
+            info!(log, "Creating file disk backend";
+                  "path" => &spec.path);
+
+            // Check if raw device is being used and gripe if it isn't
+            let meta = std::fs::metadata(&spec.path)?;
+            if meta.file_type().is_block_device() {
+                slog::warn!(
+                    log,
+                    "Block backend using standard device rather than raw";
+                    "path" => &spec.path
+                );
+            }
+
+            let workers = file_backend_workers(backend_name, spec)?;
+            let pool = match file_pool {
+                Some(pool) => pool.clone(),
+                None => block::FileWorkerPool::new(workers)?,
+            };
+            let max_in_flight = match spec.max_in_flight {
+                Some(n) => NonZeroUsize::new(n as usize).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "max_in_flight for file backend {} must be \
+                            non-zero",
+                            backend_name
+                        ),
+                    )
+                })?,
+                None => workers,
+            };
+            let read_cache_size =
+                spec.read_cache_size.map(usize::try_from).transpose().map_err(
+                    |_| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "read cache for file backend {} is too large",
+                                backend_name
+                            ),
+                        )
+                    },
+                )?;
+            let opts = propolis::block::BackendOpts {
+                read_only: Some(spec.readonly),
+                read_cache_size,
+                ..Default::default()
+            };
+            let key = match &spec.encryption_key {
+                Some(key) => {
+                    let key = base64::Engine::decode(
+                        &base64::engine::general_purpose::STANDARD,
+                        key,
+                    )
+                    .map_err(|e| {
+                        Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "failed to decode encryption key for \
+                                file backend {}: {}",
+                                backend_name, e
+                            ),
+                        )
+                    })?;
+                    Some(block::XtsKey::try_from(key.as_slice())?)
+                }
+                None => None,
+            };
+            let be = propolis::block::FileBackend::create_pooled(
+                &spec.path,
+                opts,
+                pool,
+                max_in_flight,
+                key.as_ref(),
+                spec.integrity_sidecar.as_ref().map(std::path::Path::new),
+            )?;
+
+            Ok(StorageBackendInstance { be, crucible: None })
+        }
+        instance_spec::v0::StorageBackendV0::Blob(spec) => {
+            let bytes = base64::Engine::decode(
+                &base64::engine::general_purpose::STANDARD,
+                &spec.base64,
+            )
+            .map_err(|e| {
+                Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "failed to decode base64 contents of in-memory \
+                            disk: {}",
+                        e
+                    ),
+                )
+            })?;
+
+            info!(log, "Creating in-memory disk backend";
+                  "len" => bytes.len());
+
+            let nworkers = NonZeroUsize::new(8).unwrap();
+            let opts = propolis::block::BackendOpts {
+                block_size: Some(512),
+                read_only: Some(spec.readonly),
+                ..Default::default()
+            };
+            let be = match &spec.persist_path {
+                Some(path) => {
+                    // Seed the persistence file with the initial contents
+                    // if this is its first use.
+                    match std::fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(path)
+                    {
+                        Ok(mut fp) => {
+                            info!(log, "Seeding persisted blob";
+                                  "path" => path);
+                            std::io::Write::write_all(&mut fp, &bytes)?;
+                            fp.sync_all()?;
+                        }
+                        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                        Err(e) => return Err(e),
+                    }
+                    propolis::block::InMemoryBackend::create_persistent(
+                        path,
+                        opts,
+                        nworkers,
+                        log.new(slog::o!(
+                            "component" => format!("blob-{backend_name}")
+                        )),
+                    )?
+                }
+                None => propolis::block::InMemoryBackend::create(
+                    bytes, opts, nworkers,
+                )?,
+            };
+
+            Ok(StorageBackendInstance { be, crucible: None })
+        }
+    }
+}
+
+/// Builds the keymap for the VNC server's key events from the keyboard layout
+/// and any additional keysym translations in the server config.
+fn keymap_from_config(
+    cfg: &propolis_server_config::Keyboard,
+) -> Result<Keymap> {
+    let mut keymap = Keymap::for_layout(cfg.layout.as_deref().unwrap_or("us"))?;
+    for (keysym, scan_code) in &cfg.keysyms {
+        let parsed = u32::from_str_radix(
+            keysym.trim_start_matches("0x").trim_start_matches("0X"),
+            16,
+        )
+        .with_context(|| format!("invalid keysym {:?}", keysym))?;
+        keymap.insert(parsed, *scan_code)?;
+    }
+    Ok(keymap)
+}
@@ -2,13 +2,23 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod auth;
+mod clone;
 pub mod config;
+mod disk_export;
+mod fb_recording;
+mod guest_agent;
+mod history;
 mod initializer;
+mod limits;
+mod memdump;
 mod migrate;
 mod serial;
 pub mod server;
+mod snapshot;
 mod spec;
 mod stats;
 mod vcpu_tasks;
 mod vm;
 pub mod vnc;
+mod webhook;
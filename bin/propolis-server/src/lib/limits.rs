@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Limits on how many requests to an instance's more expensive endpoints may
+//! be served at once.
+//!
+//! Disk snapshots and migrations out of the instance are served one at a time:
+//! a request made while another is being served is refused with 409 Conflict,
+//! since waiting for the first to finish could take minutes and the second is
+//! most likely a retry of the first.  Only a few fetches of serial console
+//! history are served at once, and requests beyond those are refused with 429
+//! Too Many Requests, so that a client polling the history can't starve the
+//! serial tasks of the lock they need to record the guest's output.  Requests
+//! are refused rather than queued so that none of them can pile up behind a
+//! stuck operation.
+
+use dropshot::HttpError;
+use http::StatusCode;
+use propolis_api_types::ErrorCode;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// The number of fetches of serial console history served at once.
+const MAX_SERIAL_HISTORY_FETCHES: usize = 4;
+
+pub(crate) struct EndpointLimits {
+    snapshot: Semaphore,
+    migration: Semaphore,
+    serial_history: Semaphore,
+}
+
+impl Default for EndpointLimits {
+    fn default() -> Self {
+        Self {
+            snapshot: Semaphore::new(1),
+            migration: Semaphore::new(1),
+            serial_history: Semaphore::new(MAX_SERIAL_HISTORY_FETCHES),
+        }
+    }
+}
+
+impl EndpointLimits {
+    /// Admits a request to snapshot one of the instance's disks, or returns
+    /// an error if a snapshot is already being taken.
+    pub fn snapshot(&self) -> Result<SemaphorePermit<'_>, HttpError> {
+        self.snapshot.try_acquire().map_err(|_| {
+            HttpError::for_client_error(
+                Some(ErrorCode::SnapshotInProgress.to_string()),
+                StatusCode::CONFLICT,
+                "a disk snapshot is already being taken".to_string(),
+            )
+        })
+    }
+
+    /// Admits a request to start a migration out of the instance, or returns
+    /// an error if another is already being started.
+    pub fn migration(&self) -> Result<SemaphorePermit<'_>, HttpError> {
+        self.migration.try_acquire().map_err(|_| {
+            HttpError::for_client_error(
+                Some(ErrorCode::MigrationInProgress.to_string()),
+                StatusCode::CONFLICT,
+                "a migration is already being started".to_string(),
+            )
+        })
+    }
+
+    /// Admits a request to fetch serial console history, or returns an error
+    /// if too many fetches are already being served.
+    pub fn serial_history(&self) -> Result<SemaphorePermit<'_>, HttpError> {
+        self.serial_history.try_acquire().map_err(|_| {
+            HttpError::for_client_error(
+                Some(ErrorCode::TooManyRequests.to_string()),
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many serial history requests are being served".to_string(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_beyond_the_limits_are_refused() {
+        let limits = EndpointLimits::default();
+
+        let snapshot = limits.snapshot().unwrap();
+        let refused = limits.snapshot().unwrap_err();
+        assert_eq!(refused.status_code, StatusCode::CONFLICT);
+        assert_eq!(
+            refused.error_code,
+            Some(ErrorCode::SnapshotInProgress.to_string())
+        );
+        drop(snapshot);
+        assert!(limits.snapshot().is_ok());
+
+        let fetches: Vec<_> = (0..MAX_SERIAL_HISTORY_FETCHES)
+            .map(|_| limits.serial_history().unwrap())
+            .collect();
+        assert_eq!(
+            limits.serial_history().unwrap_err().status_code,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        drop(fetches);
+        assert!(limits.serial_history().is_ok());
+    }
+}
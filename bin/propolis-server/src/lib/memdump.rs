@@ -0,0 +1,209 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Dumps of guest memory, for analyzing wedged guests with tools such as mdb
+//! or crash without host-level tooling.
+//!
+//! A dump is an ELF core file with one `PT_LOAD` segment for each region of
+//! the guest's DRAM.  Each segment's physical address is the guest-physical
+//! address of its region, and its virtual address is zero, as the guest's
+//! page tables are not consulted.  The regions' contents follow the headers
+//! in order of address, each starting on a page boundary.
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use propolis::common::{GuestAddr, GuestRegion, PAGE_SIZE};
+use propolis_api_types::InstanceState as ApiInstanceState;
+
+use crate::vm::{VmController, VmControllerError};
+
+/// The size of each chunk of guest memory read into a dump.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How long to wait for the instance to pause before a dump.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PF_RWX: u32 = 0b111;
+
+/// Returns the offset in a dump of `region_count` regions at which the first
+/// region's contents begin.
+fn data_offset(region_count: usize) -> u64 {
+    let headers = ELF_HEADER_SIZE + region_count * PROGRAM_HEADER_SIZE;
+    headers.next_multiple_of(PAGE_SIZE) as u64
+}
+
+/// Builds the headers of a dump of `regions`, padded to the start of the
+/// first region's contents.
+fn elf_headers(regions: &[GuestRegion]) -> Vec<u8> {
+    let phnum = u16::try_from(regions.len())
+        .expect("guest has fewer than 65536 memory regions");
+    let mut buf = Vec::with_capacity(data_offset(regions.len()) as usize);
+
+    // e_ident: 64-bit, little-endian, current version, System V ABI.
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&ET_CORE.to_le_bytes());
+    buf.extend_from_slice(&EM_X86_64.to_le_bytes());
+    buf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    buf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    buf.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+    buf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    buf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    buf.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&phnum.to_le_bytes());
+    buf.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+    let mut offset = data_offset(regions.len());
+    for GuestRegion(GuestAddr(gpa), len) in regions {
+        let len = *len as u64;
+        buf.extend_from_slice(&PT_LOAD.to_le_bytes());
+        buf.extend_from_slice(&PF_RWX.to_le_bytes());
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes()); // p_vaddr
+        buf.extend_from_slice(&gpa.to_le_bytes()); // p_paddr
+        buf.extend_from_slice(&len.to_le_bytes()); // p_filesz
+        buf.extend_from_slice(&len.to_le_bytes()); // p_memsz
+        buf.extend_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+        offset += len.next_multiple_of(PAGE_SIZE as u64);
+    }
+
+    buf.resize(data_offset(regions.len()) as usize, 0);
+    buf
+}
+
+/// A dump of an instance's memory, produced a chunk at a time.
+pub(crate) struct MemoryDump {
+    vm: Arc<VmController>,
+    regions: Vec<GuestRegion>,
+    headers: Option<Vec<u8>>,
+
+    /// The index of the region being read.
+    region: usize,
+
+    /// The offset within that region of the next chunk to read.
+    offset: usize,
+}
+
+impl MemoryDump {
+    pub fn new(vm: Arc<VmController>) -> Self {
+        let regions = vm.machine().acc_mem.access().unwrap().dram_regions();
+        let headers = Some(elf_headers(&regions));
+        Self { vm, regions, headers, region: 0, offset: 0 }
+    }
+
+    /// Returns the size of the whole dump, in bytes.
+    pub fn size(&self) -> u64 {
+        self.regions.iter().fold(data_offset(self.regions.len()), |len, r| {
+            len + (r.1 as u64).next_multiple_of(PAGE_SIZE as u64)
+        })
+    }
+
+    /// Returns the next chunk of the dump, or `None` once it's complete.
+    ///
+    /// Reading guest memory can take a while, so this should be called from a
+    /// blocking context.
+    pub fn next_chunk(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if let Some(headers) = self.headers.take() {
+            return Some(Ok(headers));
+        }
+
+        let GuestRegion(start, region_len) = *self.regions.get(self.region)?;
+        let padded_len = region_len.next_multiple_of(PAGE_SIZE);
+        let len = (padded_len - self.offset).min(CHUNK_SIZE);
+        let addr = GuestAddr(start.0 + self.offset as u64);
+        let mut buf = vec![0; len];
+
+        // Only the padding at the end of a region lies outside of it.
+        let read_len = region_len.saturating_sub(self.offset).min(len);
+        let memctx = self.vm.machine().acc_mem.access().unwrap();
+        if memctx.direct_read_into(addr, &mut buf, read_len) != Some(read_len) {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "failed to read {read_len} bytes of guest memory at {:#x}",
+                    addr.0
+                ),
+            )));
+        }
+
+        self.offset += len;
+        if self.offset == padded_len {
+            self.region += 1;
+            self.offset = 0;
+        }
+        Some(Ok(buf))
+    }
+
+    /// Writes the whole dump to `out`.  This blocks until the dump is
+    /// complete.
+    pub fn write_to(mut self, out: &mut impl Write) -> io::Result<()> {
+        while let Some(chunk) = self.next_chunk() {
+            out.write_all(&chunk?)?;
+        }
+        out.flush()
+    }
+}
+
+/// Pauses the instance ahead of a dump of its memory, unless it's paused
+/// already.  Returns `true` if the instance was paused here, and so should be
+/// resumed once the dump is complete.
+pub(crate) async fn pause(
+    vm: &VmController,
+) -> Result<bool, VmControllerError> {
+    let mut state_rx = vm.state_watcher().clone();
+    if state_rx.borrow().state == ApiInstanceState::Paused {
+        return Ok(false);
+    }
+
+    vm.request_pause()?;
+    let paused = tokio::time::timeout(
+        PAUSE_TIMEOUT,
+        state_rx.wait_for(|s| s.state == ApiInstanceState::Paused),
+    )
+    .await;
+    match paused {
+        Ok(Ok(_)) => Ok(true),
+        _ => Err(VmControllerError::InstanceNotActive),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn headers_describe_each_region() {
+        let regions = [
+            GuestRegion(GuestAddr(0), 0xc000_0000),
+            GuestRegion(GuestAddr(0x1_0000_0000), 0x4000_0000),
+        ];
+        let headers = elf_headers(&regions);
+        assert_eq!(headers.len(), PAGE_SIZE);
+        assert_eq!(&headers[..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([headers[16], headers[17]]), ET_CORE);
+        assert_eq!(u16::from_le_bytes([headers[56], headers[57]]), 2);
+
+        let ph = |i: usize| ELF_HEADER_SIZE + i * PROGRAM_HEADER_SIZE;
+        assert_eq!(read_u64(&headers, ph(0) + 8), PAGE_SIZE as u64);
+        assert_eq!(read_u64(&headers, ph(0) + 24), 0);
+        assert_eq!(
+            read_u64(&headers, ph(1) + 8),
+            PAGE_SIZE as u64 + 0xc000_0000
+        );
+        assert_eq!(read_u64(&headers, ph(1) + 24), 0x1_0000_0000);
+        assert_eq!(read_u64(&headers, ph(1) + 32), 0x4000_0000);
+    }
+}
@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking whether an instance could be migrated to another server, without
+//! disturbing the instance.
+//!
+//! The would-be destination connects to the source's check endpoint and the
+//! two negotiate a protocol as they would to begin a migration.  The source
+//! then sends a [`Manifest`] describing its instance and host, which the
+//! destination compares with the spec of the instance it would create and
+//! with its own host.
+
+use std::net::SocketAddr;
+
+use futures::{SinkExt, StreamExt};
+use propolis::cpuid;
+use propolis_api_types::instance_spec::VersionedInstanceSpec;
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::{tungstenite, WebSocketStream};
+
+use crate::migrate::codec;
+use crate::migrate::preamble::Preamble;
+use crate::migrate::protocol;
+use crate::migrate::tls::SourceConnector;
+use crate::migrate::MigrateError;
+use crate::vm::VmController;
+
+/// The CPUID leaves holding feature bits which must all be present on the
+/// destination's host for a guest started on the source's to keep running,
+/// with which of their ebx, ecx and edx registers hold those bits.
+const FEATURE_LEAVES: [(u32, Option<u32>, [bool; 3]); 3] = [
+    (0x1, None, [false, true, true]),
+    (0x7, Some(0), [true, true, true]),
+    (0x8000_0001, None, [false, true, true]),
+];
+
+/// What the source sends the destination during a check.
+#[derive(Deserialize, Serialize, Debug)]
+struct Manifest {
+    /// The preamble the source would send to begin a migration.
+    preamble: Preamble,
+
+    /// The names of the instance's devices whose state cannot be migrated.
+    non_migratable_devices: Vec<String>,
+
+    /// The CPU vendor and features of the source's host.
+    cpuid: CpuidBaseline,
+}
+
+/// The CPU vendor and features of a host, which bound those its guests see.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+struct CpuidBaseline {
+    /// The ebx, ecx and edx registers of leaf 0, which name the vendor.
+    vendor: [u32; 3],
+
+    /// The ebx, ecx and edx registers of each of [`FEATURE_LEAVES`], with
+    /// those not holding feature bits zeroed.
+    features: Vec<[u32; 3]>,
+}
+
+impl CpuidBaseline {
+    fn host() -> Self {
+        let regs = |leaf, subleaf| {
+            let entry = cpuid::host_query(cpuid::Ident(leaf, subleaf));
+            [entry.ebx, entry.ecx, entry.edx]
+        };
+        Self {
+            vendor: regs(0, None),
+            features: FEATURE_LEAVES
+                .iter()
+                .map(|&(leaf, subleaf, mask)| {
+                    let mut regs = regs(leaf, subleaf);
+                    for (reg, keep) in regs.iter_mut().zip(mask) {
+                        if !keep {
+                            *reg = 0;
+                        }
+                    }
+                    regs
+                })
+                .collect(),
+        }
+    }
+
+    /// Describes each way in which guests on a host with this baseline could
+    /// fail to run on a host with the `target` baseline.
+    fn incompatibilities(&self, target: &Self) -> Vec<String> {
+        if self.vendor != target.vendor {
+            return vec!["source and target hosts have different CPU vendors"
+                .to_string()];
+        }
+        if self.features.len() != target.features.len() {
+            return vec![
+                "source and target describe different CPUID leaves".to_string()
+            ];
+        }
+
+        let mut problems = Vec::new();
+        for ((leaf, subleaf, _), (source, target)) in FEATURE_LEAVES
+            .iter()
+            .zip(self.features.iter().zip(target.features.iter()))
+        {
+            for (reg, (s, t)) in
+                ["ebx", "ecx", "edx"].iter().zip(source.iter().zip(target))
+            {
+                let missing = s & !t;
+                if missing != 0 {
+                    problems.push(format!(
+                        "target host lacks CPUID leaf {leaf:#x}{} {reg} \
+                         features {missing:#x}",
+                        subleaf.map(|s| format!(".{s}")).unwrap_or_default(),
+                    ));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Answers a check made by a would-be destination (source-side).
+pub async fn source_check<T: AsyncRead + AsyncWrite + Unpin + Send>(
+    controller: &VmController,
+    mut conn: WebSocketStream<T>,
+    log: &Logger,
+) -> Result<(), MigrateError> {
+    let offer = match conn.next().await {
+        Some(Ok(tungstenite::Message::Text(offer))) => offer,
+        _ => return Err(MigrateError::Initiate),
+    };
+    let Ok(Some(selected)) = protocol::select_protocol_from_offer(&offer)
+    else {
+        info!(log, "no protocol in common with checking destination";
+              "dst_protocols" => &offer);
+        conn.send(tungstenite::Message::Close(Some(CloseFrame {
+            code: CloseCode::Protocol,
+            reason: format!(
+                "no protocol in common with source (which supports {})",
+                protocol::make_protocol_offer()
+            )
+            .into(),
+        })))
+        .await?;
+        return Ok(());
+    };
+    conn.send(tungstenite::Message::Text(selected.offer_string())).await?;
+
+    let manifest = Manifest {
+        preamble: Preamble::new(
+            controller.instance_spec().await.clone(),
+            &[],
+            0,
+            super::device_payloads(controller),
+        ),
+        non_migratable_devices: super::passthrough_device(controller)
+            .await
+            .into_iter()
+            .collect(),
+        cpuid: CpuidBaseline::host(),
+    };
+    let manifest =
+        ron::ser::to_string(&manifest).map_err(codec::ProtocolError::from)?;
+    conn.send(codec::Message::Serialized(manifest).try_into()?).await?;
+    Ok(())
+}
+
+/// Checks whether an instance with the given spec could be migrated here
+/// from the instance at `src_addr`, returning the reasons it could not
+/// (destination-side).
+pub(crate) async fn dest_check(
+    src_addr: SocketAddr,
+    spec: &VersionedInstanceSpec,
+    connector: &SourceConnector,
+    log: &Logger,
+) -> Result<Vec<String>, MigrateError> {
+    let src_check_url = format!("ws://{}/instance/migration-check", src_addr);
+    info!(log, "Checking migration compatibility";
+          "src_check_url" => &src_check_url);
+    let mut conn = connector.connect(&src_check_url).await?;
+
+    conn.send(tungstenite::Message::Text(protocol::make_protocol_offer()))
+        .await?;
+    match conn.next().await {
+        Some(Ok(tungstenite::Message::Text(selected))) => {
+            if !matches!(
+                protocol::select_protocol_from_offer(&selected),
+                Ok(Some(_))
+            ) {
+                return Ok(vec![format!(
+                    "source selected protocol {selected:?}, which was not \
+                     offered"
+                )]);
+            }
+        }
+        Some(Ok(tungstenite::Message::Close(Some(frame)))) => {
+            return Ok(vec![frame.reason.into_owned()]);
+        }
+        _ => return Err(MigrateError::Initiate),
+    }
+
+    let manifest: Manifest = match conn.next().await {
+        Some(Ok(msg)) => match codec::Message::try_from(msg)? {
+            codec::Message::Serialized(s) => {
+                ron::de::from_str(&s).map_err(codec::ProtocolError::from)?
+            }
+            _ => return Err(MigrateError::UnexpectedMessage),
+        },
+        _ => return Err(MigrateError::Initiate),
+    };
+    let _ = conn.close(None).await;
+
+    let mut problems: Vec<String> = manifest
+        .non_migratable_devices
+        .iter()
+        .map(|name| format!("source has non-migratable device {name:?}"))
+        .collect();
+    if let Err(e) = manifest.preamble.is_migration_compatible(spec) {
+        problems.push(format!("instance specs are incompatible: {e}"));
+    }
+    problems.extend(manifest.cpuid.incompatibilities(&CpuidBaseline::host()));
+    info!(log, "Migration compatibility check complete";
+          "incompatibilities" => problems.len());
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpuid_incompatibilities() {
+        let source = CpuidBaseline {
+            vendor: [1, 2, 3],
+            features: vec![[0, 0b1010, 0], [0, 0, 0], [0, 0, 0b1]],
+        };
+        let mut target = CpuidBaseline {
+            vendor: [1, 2, 3],
+            features: vec![[0, 0b1110, 0], [0, 0, 0], [0, 0, 0b1]],
+        };
+        assert!(source.incompatibilities(&target).is_empty());
+
+        // The target lacking features the source has is a problem, but not
+        // the other way around.
+        target.features[0][1] = 0b0010;
+        target.features[2][2] = 0;
+        assert_eq!(
+            source.incompatibilities(&target),
+            [
+                "target host lacks CPUID leaf 0x1 ecx features 0x8",
+                "target host lacks CPUID leaf 0x80000001 edx features 0x1",
+            ]
+        );
+        assert!(target.incompatibilities(&source).is_empty());
+
+        target.vendor = [4, 5, 6];
+        assert_eq!(source.incompatibilities(&target).len(), 1);
+    }
+}
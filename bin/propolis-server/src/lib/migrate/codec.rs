@@ -89,6 +89,8 @@ pub(crate) enum Message {
     MemFetch(u64, u64, Vec<u8>),
     MemXfer(u64, u64, Vec<u8>),
     MemDone,
+    CompressedPage(Vec<u8>),
+    ZeroPage,
 }
 
 /// MessageType represents tags that are used in the protocol for
@@ -109,6 +111,8 @@ enum MessageType {
     MemFetch,
     MemXfer,
     MemDone,
+    CompressedPage,
+    ZeroPage,
 }
 
 /// By implementing `From<&Message>` on MessageType, we can translate
@@ -127,6 +131,8 @@ impl From<&Message> for MessageType {
             Message::MemFetch(_, _, _) => MessageType::MemFetch,
             Message::MemXfer(_, _, _) => MessageType::MemXfer,
             Message::MemDone => MessageType::MemDone,
+            Message::CompressedPage(_) => MessageType::CompressedPage,
+            Message::ZeroPage => MessageType::ZeroPage,
         }
     }
 }
@@ -137,13 +143,15 @@ impl std::convert::TryInto<tungstenite::Message> for Message {
         let mut dst = Vec::new();
         let tag = MessageType::from(&self) as u8;
         match self {
-            Message::Okay | Message::MemDone => {}
+            Message::Okay | Message::MemDone | Message::ZeroPage => {}
             Message::Error(e) => {
                 let serialized = ron::ser::to_string(&e)?;
                 dst.extend(serialized.as_bytes());
             }
             Message::Serialized(s) => dst.put_slice(s.as_bytes()),
-            Message::Blob(bytes) | Message::Page(bytes) => {
+            Message::Blob(bytes)
+            | Message::Page(bytes)
+            | Message::CompressedPage(bytes) => {
                 dst.put_slice(&bytes);
             }
             Message::MemQuery(start, end) | Message::MemEnd(start, end) => {
@@ -250,6 +258,24 @@ impl std::convert::TryInto<Message> for tungstenite::Message {
                         }
                         Message::MemDone
                     }
+                    MessageType::CompressedPage => {
+                        if src.is_empty() {
+                            return Err(ProtocolError::UnexpectedMessageLen(
+                                tag as u8,
+                                src.len(),
+                            ));
+                        }
+                        Message::CompressedPage(src.to_vec())
+                    }
+                    MessageType::ZeroPage => {
+                        if !src.is_empty() {
+                            return Err(ProtocolError::UnexpectedMessageLen(
+                                tag as u8,
+                                src.len(),
+                            ));
+                        }
+                        Message::ZeroPage
+                    }
                 };
                 Ok(m)
             }
@@ -364,6 +390,18 @@ mod encoder_tests {
         let bytes = encode(Message::MemDone);
         assert_eq!(&bytes[..], [MessageType::MemDone as u8]);
     }
+
+    #[test]
+    fn encode_compressed_page() {
+        let bytes = encode(Message::CompressedPage(vec![1, 2, 3]));
+        assert_eq!(&bytes[..], &[1, 2, 3, MessageType::CompressedPage as u8]);
+    }
+
+    #[test]
+    fn encode_zero_page() {
+        let bytes = encode(Message::ZeroPage);
+        assert_eq!(&bytes[..], [MessageType::ZeroPage as u8]);
+    }
 }
 
 #[cfg(test)]
@@ -517,4 +555,23 @@ mod decoder_tests {
         let decoded = tungstenite::Message::Binary(bytes).try_into().unwrap();
         assert!(matches!(decoded, Message::MemDone));
     }
+    #[test]
+    fn decode_compressed_page() {
+        let bytes = vec![1, 2, 3, MessageType::CompressedPage as u8];
+        let decoded = tungstenite::Message::Binary(bytes).try_into().unwrap();
+        assert!(matches!(decoded, Message::CompressedPage(p)
+            if p == vec![1, 2, 3]));
+
+        let bytes = vec![MessageType::CompressedPage as u8];
+        let decoded: Result<Message, _> =
+            tungstenite::Message::Binary(bytes).try_into();
+        assert!(decoded.is_err());
+    }
+
+    #[test]
+    fn decode_zero_page() {
+        let bytes = vec![MessageType::ZeroPage as u8];
+        let decoded = tungstenite::Message::Binary(bytes).try_into().unwrap();
+        assert!(matches!(decoded, Message::ZeroPage));
+    }
 }
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compression of guest memory pages sent during a migration.
+//!
+//! The source offers the algorithms it is configured to use, in order of
+//! preference, in its preamble. A destination which can use one of them names
+//! it in its reply; otherwise (including when the destination predates page
+//! compression) pages are sent uncompressed. Once an
+//! algorithm is selected, the source may send any page as a
+//! [`codec::Message::CompressedPage`], falling back to an uncompressed
+//! [`codec::Message::Page`] for pages that don't compress.
+//!
+//! [`codec::Message::CompressedPage`]: super::codec::Message::CompressedPage
+//! [`codec::Message::Page`]: super::codec::Message::Page
+
+use std::str::FromStr;
+
+use propolis::common::PAGE_SIZE;
+
+use super::MigrateError;
+
+/// The zstd compression level, chosen to favor speed.
+const ZSTD_LEVEL: i32 = 1;
+
+/// An algorithm with which pages of guest memory may be compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PageCompression {
+    Lz4,
+    Zstd,
+}
+
+impl PageCompression {
+    /// The name identifying this algorithm in configuration and in the
+    /// migration preamble.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PageCompression::Lz4 => "lz4",
+            PageCompression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, page: &[u8]) -> Vec<u8> {
+        match self {
+            PageCompression::Lz4 => lz4_flex::block::compress(page),
+            PageCompression::Zstd => {
+                // Compressing to memory can only fail if the output buffer is
+                // too small, which `bulk::compress` sizes to fit.
+                zstd::bulk::compress(page, ZSTD_LEVEL).unwrap()
+            }
+        }
+    }
+
+    /// Decompresses `data`, which must expand to exactly one page.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, MigrateError> {
+        let page = match self {
+            PageCompression::Lz4 => {
+                lz4_flex::block::decompress(data, PAGE_SIZE)
+                    .map_err(|e| e.to_string())
+            }
+            PageCompression::Zstd => zstd::bulk::decompress(data, PAGE_SIZE)
+                .map_err(|e| e.to_string()),
+        }
+        .map_err(|e| {
+            MigrateError::Codec(format!(
+                "failed to decompress {} page: {e}",
+                self.name()
+            ))
+        })?;
+
+        if page.len() != PAGE_SIZE {
+            return Err(MigrateError::Codec(format!(
+                "{} page decompressed to {} bytes",
+                self.name(),
+                page.len()
+            )));
+        }
+        Ok(page)
+    }
+}
+
+impl FromStr for PageCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lz4" => Ok(PageCompression::Lz4),
+            "zstd" => Ok(PageCompression::Zstd),
+            _ => Err(format!("unknown page compression algorithm {s:?}")),
+        }
+    }
+}
+
+/// Selects the first of the algorithms offered by a migration source that
+/// this Propolis supports.
+pub(crate) fn select(offered: &[String]) -> Option<PageCompression> {
+    offered.iter().find_map(|name| name.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut page = vec![0u8; PAGE_SIZE];
+        page[..11].copy_from_slice(b"hello world");
+        for alg in [PageCompression::Lz4, PageCompression::Zstd] {
+            let data = alg.compress(&page);
+            assert!(data.len() < PAGE_SIZE);
+            assert_eq!(alg.decompress(&data).unwrap(), page);
+            assert!(alg.decompress(&alg.compress(&page[..16])).is_err());
+        }
+    }
+
+    #[test]
+    fn select_first_supported() {
+        let offered = ["brotli".to_string(), "zstd".into(), "lz4".into()];
+        assert_eq!(select(&offered), Some(PageCompression::Zstd));
+        assert_eq!(select(&offered[..1]), None);
+    }
+}
@@ -9,18 +9,27 @@ use propolis::migrate::{
     MigrateCtx, MigrateStateError, Migrator, PayloadOffer, PayloadOffers,
 };
 use propolis::vmm;
+use propolis_api_types::{MigrationAutoConverge, MigrationDirtyTracking};
 use slog::{error, info, trace, warn};
 use std::convert::TryInto;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_tungstenite::WebSocketStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use crate::migrate::codec;
+use crate::migrate::compress::{self, PageCompression};
 use crate::migrate::memx;
-use crate::migrate::preamble::Preamble;
+use crate::migrate::payloads;
+use crate::migrate::preamble::{Preamble, PreambleReply};
 use crate::migrate::probes;
+use crate::migrate::progress::MigrationProgress;
+use crate::migrate::retry::Retrier;
+use crate::migrate::tls::SourceConnector;
 use crate::migrate::{
     Device, MigrateError, MigratePhase, MigrateRole, MigrationState, PageIter,
 };
@@ -28,30 +37,55 @@ use crate::vm::{MigrateTargetCommand, VmController};
 
 use super::protocol::Protocol;
 
+/// Time allowed for the guest agent asked to resynchronize the guest's clock
+/// to accept the request.
+const TIME_SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An additional connection to the source over which guest memory is fetched
+/// concurrently with the main migration connection.
+type RamStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
 /// Launches an attempt to migrate into a supplied instance using the supplied
 /// source connection.
+///
+/// If `retrier` is set, an attempt which fails before any guest state has
+/// been transferred is made again over a new connection, as its policy
+/// allows.
+#[allow(clippy::too_many_arguments)]
 pub async fn migrate<T: AsyncRead + AsyncWrite + Unpin + Send>(
     vm_controller: Arc<VmController>,
     command_tx: tokio::sync::mpsc::Sender<MigrateTargetCommand>,
-    conn: WebSocketStream<T>,
+    mut conn: WebSocketStream<T>,
     local_addr: SocketAddr,
-    protocol: Protocol,
+    mut protocol: Protocol,
+    progress: Arc<MigrationProgress>,
+    ram_stream_url: String,
+    connector: SourceConnector,
+    auto_converge: Option<MigrationAutoConverge>,
+    max_downtime_ms: Option<u64>,
+    dirty_tracking: Option<MigrationDirtyTracking>,
+    mut retrier: Option<Retrier<T>>,
 ) -> Result<(), MigrateError> {
-    let err_tx = command_tx.clone();
-    let mut proto = match protocol {
-        Protocol::RonV0 => DestinationProtocol::new(
-            vm_controller,
-            command_tx,
-            conn,
-            local_addr,
-        ),
-    };
+    let log = vm_controller.log().clone();
+    loop {
+        let mut proto = match protocol {
+            Protocol::RonV0 => DestinationProtocol::new(
+                vm_controller.clone(),
+                command_tx.clone(),
+                conn,
+                local_addr,
+                progress.clone(),
+                ram_stream_url.clone(),
+                connector.clone(),
+                auto_converge.clone(),
+                max_downtime_ms,
+                dirty_tracking.clone(),
+            ),
+        };
 
-    if let Err(err) = proto.run().await {
-        err_tx
-            .send(MigrateTargetCommand::UpdateState(MigrationState::Error))
-            .await
-            .unwrap();
+        let Err(mut err) = proto.run().await else {
+            return Ok(());
+        };
 
         // We encountered an error, try to inform the remote before bailing
         // Note, we don't use `?` here as this is a best effort and we don't
@@ -60,10 +94,31 @@ pub async fn migrate<T: AsyncRead + AsyncWrite + Unpin + Send>(
         if let Ok(e) = codec::Message::Error(err.clone()).try_into() {
             let _ = proto.conn.send(e).await;
         }
+        let phase = proto.state;
+        drop(proto);
+
+        // Nothing has been written to the guest until the RAM transfer
+        // begins, so until then the migration can start over.
+        if let (MigrationState::Sync, Some(retrier)) = (phase, retrier.as_mut())
+        {
+            if retrier.should_retry(&err, &log).await {
+                match retrier.connect(&log).await {
+                    Ok((new_conn, new_protocol)) => {
+                        conn = new_conn;
+                        protocol = new_protocol;
+                        continue;
+                    }
+                    Err(e) => err = e,
+                }
+            }
+        }
+
+        command_tx
+            .send(MigrateTargetCommand::Failed(err.to_failure(phase)))
+            .await
+            .unwrap();
         return Err(err);
     }
-
-    Ok(())
 }
 
 struct DestinationProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
@@ -77,9 +132,47 @@ struct DestinationProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
     /// Transport to the source Instance.
     conn: WebSocketStream<T>,
 
+    /// The migration's externally-visible state, as last reported to the
+    /// state worker.
+    state: MigrationState,
+
     /// Local propolis-server address
     /// (to inform the source-side where to redirect its clients)
     local_addr: SocketAddr,
+
+    /// The progress of this migration's RAM transfer, reported through the
+    /// API.
+    progress: Arc<MigrationProgress>,
+
+    /// The algorithm selected to compress pages of guest memory, if any.
+    compression: Option<PageCompression>,
+
+    /// Set if the source sends all-zero pages as markers.
+    zero_pages: bool,
+
+    /// The URL at which the source accepts additional RAM streams.
+    ram_stream_url: String,
+
+    /// Opens the additional RAM streams to the source.
+    connector: SourceConnector,
+
+    /// Additional connections over which guest memory is fetched in parallel.
+    ram_streams: Vec<RamStream>,
+
+    /// The limits within which the source is asked to throttle the guest's
+    /// vCPUs while pushing RAM in rounds.
+    auto_converge: Option<MigrationAutoConverge>,
+
+    /// The longest the source is asked to pause the guest for while the last
+    /// of its memory is sent.
+    max_downtime_ms: Option<u64>,
+
+    /// How the source is asked to track the pages dirtied while guest memory
+    /// is sent.
+    dirty_tracking: Option<MigrationDirtyTracking>,
+
+    /// Set if the source pushes RAM in rounds before pausing the guest.
+    precopy_rounds: bool,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
@@ -88,8 +181,30 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         command_tx: tokio::sync::mpsc::Sender<MigrateTargetCommand>,
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
+        progress: Arc<MigrationProgress>,
+        ram_stream_url: String,
+        connector: SourceConnector,
+        auto_converge: Option<MigrationAutoConverge>,
+        max_downtime_ms: Option<u64>,
+        dirty_tracking: Option<MigrationDirtyTracking>,
     ) -> Self {
-        Self { vm_controller, command_tx, conn, local_addr }
+        Self {
+            vm_controller,
+            command_tx,
+            conn,
+            state: MigrationState::Sync,
+            local_addr,
+            progress,
+            compression: None,
+            zero_pages: false,
+            ram_stream_url,
+            connector,
+            ram_streams: Vec::new(),
+            auto_converge,
+            max_downtime_ms,
+            dirty_tracking,
+            precopy_rounds: false,
+        }
     }
 
     fn log(&self) -> &slog::Logger {
@@ -100,6 +215,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         // When migrating into an instance, the VM state worker blocks waiting
         // for the disposition of the migration attempt, so the channel should
         // never be closed before the attempt completes.
+        self.state = state;
         self.command_tx
             .send(MigrateTargetCommand::UpdateState(state))
             .await
@@ -111,6 +227,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         step: MigratePhase,
     ) -> Result<(), MigrateError> {
         probes::migrate_phase_begin!(|| { step.to_string() });
+        let started = Instant::now();
 
         let res = match step {
             MigratePhase::MigrateSync => self.sync().await,
@@ -128,6 +245,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         };
 
         probes::migrate_phase_end!(|| { step.to_string() });
+        self.vm_controller.migration_stats().phase_finished(
+            MigrateRole::Destination,
+            &step.to_string(),
+            started.elapsed(),
+        );
 
         res
     }
@@ -152,6 +274,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         self.run_phase(MigratePhase::RamPull).await?;
         self.run_phase(MigratePhase::ServerState).await?;
         self.run_phase(MigratePhase::Finish).await?;
+        self.resync_time();
 
         info!(self.log(), "Destination Migration Successful");
 
@@ -174,7 +297,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         }?;
         info!(self.log(), "Destination read Preamble: {:?}", preamble);
         if let Err(e) = preamble
-            .is_migration_compatible(self.vm_controller.instance_spec().await)
+            .is_migration_compatible(&*self.vm_controller.instance_spec().await)
         {
             error!(
                 self.log(),
@@ -182,8 +305,69 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
             );
             return Err(MigrateError::InvalidInstanceState);
         }
+        if let Err(e) = preamble
+            .check_device_payloads(&super::device_schemas(&self.vm_controller))
+        {
+            error!(self.log(), "{e}");
+            return Err(e);
+        }
 
-        self.send_msg(codec::Message::Okay).await
+        // Open as many additional streams for fetching guest memory as both
+        // sides allow.
+        let streams = preamble
+            .ram_streams
+            .min(self.vm_controller.max_migration_ram_streams());
+        for _ in 1..streams {
+            let conn = self.connector.connect(&self.ram_stream_url).await?;
+            self.ram_streams.push(conn);
+        }
+        if !self.ram_streams.is_empty() {
+            info!(self.log(), "Fetching RAM over {} streams", streams);
+        }
+
+        // Select which of the optional features offered by the source to
+        // use. A source that offered none of them expects a bare
+        // acknowledgement.
+        self.compression = compress::select(&preamble.page_compression);
+        self.zero_pages = preamble.zero_pages;
+        let precopy =
+            self.auto_converge.is_some() || self.max_downtime_ms.is_some();
+        if precopy && !preamble.precopy_rounds {
+            warn!(
+                self.log(),
+                "Source cannot push RAM in rounds; ignoring convergence limits"
+            );
+        }
+        self.precopy_rounds = precopy && preamble.precopy_rounds;
+        if self.dirty_tracking.is_some() && !preamble.dirty_tracking {
+            warn!(
+                self.log(),
+                "Source cannot tune dirty page tracking; ignoring parameters"
+            );
+        }
+        let reply = PreambleReply {
+            page_compression: self.compression.map(|c| c.name().to_string()),
+            zero_pages: self.zero_pages,
+            auto_converge: self
+                .auto_converge
+                .clone()
+                .filter(|_| self.precopy_rounds),
+            max_downtime_ms: self
+                .max_downtime_ms
+                .filter(|_| self.precopy_rounds),
+            dirty_tracking: self
+                .dirty_tracking
+                .clone()
+                .filter(|_| preamble.dirty_tracking),
+        };
+        info!(self.log(), "Destination replying to preamble: {:?}", reply);
+        if reply == PreambleReply::default() {
+            self.send_msg(codec::Message::Okay).await
+        } else {
+            let s = ron::ser::to_string(&reply)
+                .map_err(codec::ProtocolError::from)?;
+            self.send_msg(codec::Message::Serialized(s)).await
+        }
     }
 
     async fn ram_push(
@@ -200,52 +384,53 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
             _ => unreachable!("should only push RAM in a RAM push phase"),
         }
 
-        let (dirty, highest) = self.query_ram().await?;
-        for (k, region) in dirty.as_raw_slice().chunks(4096).enumerate() {
-            if region.iter().all(|&b| b == 0) {
-                continue;
+        // When pushing RAM in rounds, the source ends the pre-pause phase
+        // with a round that offers nothing.
+        loop {
+            let offered = self.ram_round().await?;
+            if offered == 0
+                || !self.precopy_rounds
+                || !matches!(phase, MigratePhase::RamPushPrePause)
+            {
+                break;
             }
-
-            // This is an iteration over chunks of 4,096 bitmap bytes, so
-            // (k * 4096) is the offset (into the overall bitmap) of the first
-            // byte in the chunk. Multiply this by 8 bits/byte to get a number
-            // of bits, then multiply by PAGE_SIZE to get a physical address.
-            let start = (k * 4096 * 8 * PAGE_SIZE) as u64;
-            let end = start + (region.len() * 8 * PAGE_SIZE) as u64;
-            let end = highest.min(end);
-            self.send_msg(memx::make_mem_fetch(start, end, region)).await?;
-            let m = self.read_msg().await?;
-            trace!(
-                self.log(),
-                "ram_push ({:?}): source xfer phase recvd {:?}",
-                m,
-                phase
-            );
-            match m {
-                codec::Message::MemXfer(start, end, bits) => {
-                    if !memx::validate_bitmap(start, end, &bits) {
-                        error!(
-                            self.log(),
-                            "ram_push ({:?}): MemXfer received bad bitmap",
-                            phase
-                        );
-                        return Err(MigrateError::Phase);
-                    }
-                    // XXX: We should do stricter validation on the fetch
-                    // request here.  For instance, we shouldn't "push" MMIO
-                    // space or non-existent RAM regions.  While we de facto
-                    // do not because of the way access is implemented, we
-                    // should probably disallow it at the protocol level.
-                    self.xfer_ram(start, end, &bits).await?;
-                }
-                _ => return Err(MigrateError::UnexpectedMessage),
-            };
         }
-        self.send_msg(codec::Message::MemDone).await?;
         self.update_state(MigrationState::Pause).await;
         Ok(())
     }
 
+    /// Runs a single round of RAM transfer, returning the number of pages
+    /// offered.
+    async fn ram_round(&mut self) -> Result<u64, MigrateError> {
+        self.progress.begin_round();
+
+        let (dirty, highest) = self.query_ram().await?;
+        let offered = dirty.count_ones() as u64;
+        self.progress.pages_offered(offered);
+
+        // Each stream fetches the next region not yet fetched whenever it
+        // finishes with its last one.
+        let regions = Mutex::new(fetch_regions(dirty.as_raw_slice(), highest));
+        let fetcher = PageFetcher {
+            vm_controller: self.vm_controller.clone(),
+            progress: self.progress.clone(),
+            compression: self.compression,
+            zero_pages: self.zero_pages,
+        };
+        futures::try_join!(
+            fetcher.fetch(&mut self.conn, &regions),
+            futures::future::try_join_all(
+                self.ram_streams
+                    .iter_mut()
+                    .map(|conn| fetcher.fetch(conn, &regions))
+            ),
+        )?;
+
+        self.send_msg(codec::Message::MemDone).await?;
+        self.progress.end_round();
+        Ok(offered)
+    }
+
     async fn query_ram(
         &mut self,
     ) -> Result<(bv::BitVec<u8, bv::Lsb0>, u64), MigrateError> {
@@ -287,24 +472,10 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         Ok((dirty, highest))
     }
 
-    async fn xfer_ram(
-        &mut self,
-        start: u64,
-        end: u64,
-        bits: &[u8],
-    ) -> Result<(), MigrateError> {
-        info!(self.log(), "ram_push: xfer RAM between {} and {}", start, end);
-        for addr in PageIter::new(start, end, bits) {
-            let bytes = self.read_page().await?;
-            self.write_guest_ram(GuestAddr(addr), &bytes).await?;
-        }
-        Ok(())
-    }
-
     async fn device_state(&mut self) -> Result<(), MigrateError> {
         self.update_state(MigrationState::Device).await;
 
-        let devices: Vec<Device> = match self.read_msg().await? {
+        let mut devices: Vec<Device> = match self.read_msg().await? {
             codec::Message::Serialized(encoded) => {
                 ron::de::from_reader(encoded.as_bytes())
                     .map_err(codec::ProtocolError::from)?
@@ -316,6 +487,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         };
         self.read_ok().await?;
 
+        if let Some(dir) = &self.vm_controller.migration_payloads().import {
+            let replaced = payloads::import(dir, &mut devices)?;
+            warn!(self.log(), "Importing canned device payloads";
+                  "dir" => %dir.display(),
+                  "devices" => ?replaced);
+        }
+
         info!(self.log(), "Devices: {devices:#?}");
 
         {
@@ -388,6 +566,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
                     ))
                 })?;
 
+        // The guest's clocks are left ahead of this host's by however far this
+        // host's clock is behind the source's, since time can't be made to run
+        // backwards for the guest.
+        let skew = if adjust.migrate_delta_negative {
+            time_data_src.wall_clock().saturating_sub(dst_wc)
+        } else {
+            Duration::ZERO
+        };
+        self.progress.clock_skew_found(skew);
+
         // In case import fails, log adjustments made to time data and fire
         // dtrace probe first
         if adjust.migrate_delta_negative {
@@ -444,6 +632,34 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         self.send_msg(codec::Message::Okay).await
     }
 
+    /// Brings the guest's clocks back in line with this host's once control
+    /// of the guest has passed to this instance.  The RTC, whose state came
+    /// from the source, is set from this host's clock as it is when an
+    /// instance starts, and the guest agent is asked to do the same for the
+    /// guest's system clock, if one is configured.
+    fn resync_time(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time precedes UNIX epoch");
+        if let Err(e) = self.vm_controller.machine().hdl.rtc_settime(now) {
+            warn!(self.log(), "failed to set guest RTC after migration";
+                  "error" => %e);
+        }
+
+        let Some(socket) = self.vm_controller.time_sync_socket() else {
+            return;
+        };
+        let socket = socket.to_path_buf();
+        let log = self.log().clone();
+        tokio::spawn(async move {
+            if let Err(e) = request_guest_time_sync(&socket, now).await {
+                warn!(log, "failed to ask guest agent to set guest clock";
+                      "socket" => %socket.display(),
+                      "error" => %e);
+            }
+        });
+    }
+
     fn import_device(
         &self,
         target: &Arc<dyn Lifecycle>,
@@ -541,6 +757,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         Ok(())
     }
 
+    /// Post-copy is not supported (see `docs/migration-post-copy.md`), so
+    /// the source never has memory to offer here.
     async fn ram_pull(&mut self) -> Result<(), MigrateError> {
         self.update_state(MigrationState::RamPull).await;
         self.send_msg(codec::Message::MemQuery(0, !0)).await?;
@@ -589,25 +807,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
     }
 
     async fn read_msg(&mut self) -> Result<codec::Message, MigrateError> {
-        self.conn
-            .next()
-            .await
-            .ok_or_else(|| {
-                codec::ProtocolError::Io(io::Error::from(
-                    io::ErrorKind::BrokenPipe,
-                ))
-            })?
-            // If this is an error message, lift that out
-            .map(|msg| match msg.try_into()? {
-                codec::Message::Error(err) => {
-                    error!(self.log(), "remote error: {err}");
-                    Err(MigrateError::RemoteError(
-                        MigrateRole::Source,
-                        err.to_string(),
-                    ))
-                }
-                msg => Ok(msg),
-            })?
+        recv_msg(&mut self.conn, self.vm_controller.log()).await
     }
 
     async fn read_ok(&mut self) -> Result<(), MigrateError> {
@@ -620,29 +820,236 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         }
     }
 
-    async fn read_page(&mut self) -> Result<Vec<u8>, MigrateError> {
-        match self.read_msg().await? {
-            codec::Message::Page(bytes) => Ok(bytes),
-            _ => Err(MigrateError::UnexpectedMessage),
-        }
-    }
-
     async fn send_msg(
         &mut self,
         m: codec::Message,
     ) -> Result<(), MigrateError> {
         Ok(self.conn.send(m.try_into()?).await?)
     }
+}
 
-    async fn write_guest_ram(
-        &mut self,
-        addr: GuestAddr,
-        buf: &[u8],
+/// Asks the QEMU-compatible guest agent listening on the virtio-console port
+/// whose host socket is `socket` to set the guest's system clock to `now`,
+/// given as the time since the UNIX epoch.
+async fn request_guest_time_sync(
+    socket: &Path,
+    now: Duration,
+) -> io::Result<()> {
+    let request = serde_json::json!({
+        "execute": "guest-set-time",
+        "arguments": { "time": now.as_nanos() as i64 },
+    });
+    let mut conn = UnixStream::connect(socket).await?;
+    tokio::time::timeout(
+        TIME_SYNC_TIMEOUT,
+        conn.write_all(format!("{request}\n").as_bytes()),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "guest agent did not accept time sync request",
+        ))
+    })
+}
+
+/// Reads a message from the source over `conn`, lifting out any error the
+/// source reports.
+async fn recv_msg<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    conn: &mut WebSocketStream<S>,
+    log: &slog::Logger,
+) -> Result<codec::Message, MigrateError> {
+    conn.next()
+        .await
+        .ok_or_else(|| {
+            codec::ProtocolError::Io(io::Error::from(io::ErrorKind::BrokenPipe))
+        })?
+        // If this is an error message, lift that out
+        .map(|msg| match msg.try_into()? {
+            codec::Message::Error(err) => {
+                error!(log, "remote error: {err}");
+                Err(MigrateError::RemoteError(
+                    MigrateRole::Source,
+                    err.to_string(),
+                ))
+            }
+            msg => Ok(msg),
+        })?
+}
+
+/// Splits `bitmap`, of the pages of guest memory to fetch, into the regions
+/// covered by each fetch, in address order, skipping those with no pages to
+/// fetch.  No region extends past `highest`.
+fn fetch_regions(
+    bitmap: &[u8],
+    highest: u64,
+) -> impl Iterator<Item = (u64, u64, &[u8])> {
+    bitmap
+        .chunks(4096)
+        .enumerate()
+        .filter(|(_, region)| region.iter().any(|&b| b != 0))
+        .map(move |(k, region)| {
+            // This is an iteration over chunks of 4,096 bitmap bytes, so
+            // (k * 4096) is the offset (into the overall bitmap) of the first
+            // byte in the chunk. Multiply this by 8 bits/byte to get a number
+            // of bits, then multiply by PAGE_SIZE to get a physical address.
+            let start = (k * 4096 * 8 * PAGE_SIZE) as u64;
+            let end = start + (region.len() * 8 * PAGE_SIZE) as u64;
+            (start, highest.min(end), region)
+        })
+}
+
+/// Fetches pages of guest memory from the source, over either the main
+/// migration connection or one of the additional RAM streams.
+struct PageFetcher {
+    vm_controller: Arc<VmController>,
+    progress: Arc<MigrationProgress>,
+    compression: Option<PageCompression>,
+    zero_pages: bool,
+}
+
+impl PageFetcher {
+    /// Fetches regions taken from `regions` over `conn` until none remain.
+    async fn fetch<'a, S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        conn: &mut WebSocketStream<S>,
+        regions: &Mutex<impl Iterator<Item = (u64, u64, &'a [u8])>>,
+    ) -> Result<(), MigrateError> {
+        let log = self.vm_controller.log();
+        loop {
+            let Some((start, end, region)) = regions.lock().unwrap().next()
+            else {
+                return Ok(());
+            };
+            conn.send(memx::make_mem_fetch(start, end, region).try_into()?)
+                .await?;
+            let m = recv_msg(conn, log).await?;
+            trace!(log, "ram_push: source xfer phase recvd {:?}", m);
+            match m {
+                codec::Message::MemXfer(start, end, bits) => {
+                    if !memx::validate_bitmap(start, end, &bits) {
+                        error!(log, "ram_push: MemXfer received bad bitmap");
+                        return Err(MigrateError::Phase);
+                    }
+                    // XXX: We should do stricter validation on the fetch
+                    // request here.  For instance, we shouldn't "push" MMIO
+                    // space or non-existent RAM regions.  While we de facto
+                    // do not because of the way access is implemented, we
+                    // should probably disallow it at the protocol level.
+                    self.xfer_ram(conn, start, end, &bits).await?;
+                }
+                _ => return Err(MigrateError::UnexpectedMessage),
+            };
+        }
+    }
+
+    async fn xfer_ram<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        conn: &mut WebSocketStream<S>,
+        start: u64,
+        end: u64,
+        bits: &[u8],
     ) -> Result<(), MigrateError> {
+        let log = self.vm_controller.log();
+        info!(log, "ram_push: xfer RAM between {} and {}", start, end);
+        for addr in PageIter::new(start, end, bits) {
+            let bytes = match recv_msg(conn, log).await? {
+                codec::Message::Page(bytes) => bytes,
+                codec::Message::CompressedPage(data) => {
+                    match self.compression {
+                        Some(compression) => compression.decompress(&data)?,
+                        None => return Err(MigrateError::UnexpectedMessage),
+                    }
+                }
+                // The page may have held data before this round, so its
+                // zeroes must still be written.
+                codec::Message::ZeroPage if self.zero_pages => {
+                    vec![0u8; PAGE_SIZE]
+                }
+                _ => return Err(MigrateError::UnexpectedMessage),
+            };
+            self.write_guest_ram(GuestAddr(addr), &bytes);
+            self.progress.page_transferred();
+        }
+        Ok(())
+    }
+
+    fn write_guest_ram(&self, addr: GuestAddr, buf: &[u8]) {
         let machine = self.vm_controller.machine();
         let memctx = machine.acc_mem.access().unwrap();
         let len = buf.len();
         memctx.write_from(addr, buf, len);
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The number of pages covered by a single fetch
+    const REGION_PAGES: usize = 4096 * 8;
+
+    fn region_start(n: usize) -> u64 {
+        (n * REGION_PAGES * PAGE_SIZE) as u64
+    }
+
+    /// A bitmap spanning `regions` fetches, in which only `pages` are set
+    fn bitmap(regions: usize, pages: &[usize]) -> Vec<u8> {
+        let mut bits = vec![0u8; regions * REGION_PAGES / 8];
+        for &page in pages {
+            bits[page / 8] |= 1 << (page % 8);
+        }
+        bits
+    }
+
+    #[test]
+    fn regions_fetched_in_address_order() {
+        let bits = bitmap(4, &[3, 2 * REGION_PAGES, 3 * REGION_PAGES + 1]);
+        let highest = region_start(3) + 2 * PAGE_SIZE as u64;
+
+        // The region without pages to fetch is skipped, and the last stops
+        // at the highest address.
+        let regions: Vec<_> = fetch_regions(&bits, highest)
+            .map(|(start, end, _)| (start, end))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (region_start(0), region_start(1)),
+                (region_start(2), region_start(3)),
+                (region_start(3), highest),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_fetch_each_region_once() {
+        let pages: Vec<usize> = (0..16).map(|n| n * REGION_PAGES).collect();
+        let bits = bitmap(16, &pages);
+        let regions = Mutex::new(fetch_regions(&bits, u64::MAX));
+
+        // Like `PageFetcher::fetch`, each stream takes the next region, and
+        // then waits on the source for its pages.
+        let streams = (0..4).map(|_| async {
+            let mut fetched = Vec::new();
+            loop {
+                let Some((start, ..)) = regions.lock().unwrap().next() else {
+                    return fetched;
+                };
+                fetched.push(start);
+                tokio::task::yield_now().await;
+            }
+        });
+        let fetched = futures::future::join_all(streams).await;
+
+        // All the streams shared the work, each taking its regions in order,
+        // and no region was fetched twice.
+        for regions in &fetched {
+            assert!(!regions.is_empty());
+            assert!(regions.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        let mut all = fetched.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..16).map(region_start).collect::<Vec<_>>());
     }
 }
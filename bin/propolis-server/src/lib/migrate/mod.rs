@@ -2,20 +2,26 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use bit_field::BitField;
 use dropshot::{HttpError, RequestContext};
-use futures::{SinkExt, StreamExt};
-use propolis::migrate::MigrateStateError;
+use futures::{FutureExt, SinkExt, StreamExt};
+use propolis::migrate::{MigrateStateError, PayloadSchemas};
+use propolis_api_types::instance_spec::{
+    v0::NetworkDeviceV0, VersionedInstanceSpec,
+};
 use propolis_api_types::{self as api, MigrationState};
 use serde::{Deserialize, Serialize};
-use slog::{error, info, o};
+use slog::{error, info, o, Logger};
+use strum::IntoStaticStr;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
 use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
-use tokio_tungstenite::{tungstenite, WebSocketStream};
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
 use uuid::Uuid;
 
 use crate::{
@@ -23,12 +29,19 @@ use crate::{
     vm::{VmController, VmControllerError},
 };
 
+pub(crate) mod check;
 mod codec;
+pub(crate) mod compress;
 pub mod destination;
 mod memx;
+pub(crate) mod payloads;
 mod preamble;
+pub(crate) mod progress;
 pub mod protocol;
+pub(crate) mod save;
 pub mod source;
+pub(crate) mod throttle;
+pub(crate) mod tls;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum MigrateRole {
@@ -69,7 +82,14 @@ impl std::fmt::Display for MigratePhase {
 }
 
 /// Errors which may occur during the course of a migration
-#[derive(Clone, Debug, Error, Deserialize, PartialEq, Serialize)]
+///
+/// The `snake_case` name of each variant, obtained by converting the error to
+/// a `&'static str`, is reported as the reason for a failed migration in its
+/// metrics.
+#[derive(
+    Clone, Debug, Error, Deserialize, PartialEq, Serialize, IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
 pub enum MigrateError {
     /// An error as a result of some Websocket operation (i.e. establishing
     /// or maintaining the connection between the source and destination)
@@ -146,11 +166,56 @@ pub enum MigrateError {
     /// The other end of the migration ran into an error
     #[error("{0:?} migration instance encountered error: {1}")]
     RemoteError(MigrateRole, String),
+
+    /// The source instance has a device whose state cannot be migrated
+    #[error("instance has non-migratable device {0:?} attached")]
+    NonMigratableDevice(String),
+
+    /// The migration was cancelled through the API
+    #[error("migration was cancelled")]
+    Cancelled,
+
+    /// The destination opened more RAM streams than the source allows
+    #[error("too many RAM transfer streams")]
+    TooManyRamStreams,
+
+    /// Cancellation was requested for a migration into this instance
+    #[error("only outbound migrations can be cancelled")]
+    NotMigrationSource,
+
+    /// Cancellation was requested after control of the guest began moving to
+    /// the destination
+    #[error("migration has passed the point at which it can be cancelled")]
+    PastCutover,
+
+    /// Failed to write or read a saved-state file
+    #[error("saved state error: {0}")]
+    SavedState(String),
+
+    /// Failed to secure a migration connection with TLS
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    /// The destination's devices can't import some of the state the source
+    /// will send
+    #[error("unsupported device state: {0}")]
+    UnsupportedPayloads(String),
+
+    /// The connection to the other instance timed out
+    #[error("timed out waiting for the other instance")]
+    Timeout,
 }
 
 impl From<tokio_tungstenite::tungstenite::Error> for MigrateError {
     fn from(err: tokio_tungstenite::tungstenite::Error) -> MigrateError {
-        MigrateError::Websocket(err.to_string())
+        match err {
+            tungstenite::Error::Io(e)
+                if e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                MigrateError::Timeout
+            }
+            _ => MigrateError::Websocket(err.to_string()),
+        }
     }
 }
 
@@ -179,7 +244,7 @@ impl From<MigrateStateError> for MigrateError {
 impl From<MigrateError> for HttpError {
     fn from(err: MigrateError) -> Self {
         let msg = format!("migration failed: {}", err);
-        match &err {
+        let mut error = match &err {
             MigrateError::Websocket(_)
             | MigrateError::Initiate
             | MigrateError::ProtocolParse(_, _)
@@ -193,6 +258,11 @@ impl From<MigrateError> for HttpError {
             | MigrateError::TimeData(_)
             | MigrateError::DeviceState(_)
             | MigrateError::RemoteError(_, _)
+            | MigrateError::Cancelled
+            | MigrateError::SavedState(_)
+            | MigrateError::Tls(_)
+            | MigrateError::UnsupportedPayloads(_)
+            | MigrateError::Timeout
             | MigrateError::StateMachine(_) => {
                 HttpError::for_internal_error(msg)
             }
@@ -200,9 +270,117 @@ impl From<MigrateError> for HttpError {
             | MigrateError::NoMigrationInProgress
             | MigrateError::UuidMismatch
             | MigrateError::UpgradeExpected
-            | MigrateError::UnknownDevice(_) => {
+            | MigrateError::UnknownDevice(_)
+            | MigrateError::NonMigratableDevice(_)
+            | MigrateError::NotMigrationSource
+            | MigrateError::PastCutover
+            | MigrateError::TooManyRamStreams => {
                 HttpError::for_bad_request(None, msg)
             }
+        };
+        error.error_code = Some(err.error_code().to_string());
+        error
+    }
+}
+
+impl MigrateError {
+    /// Returns the code identifying this error to API clients.
+    pub(crate) fn error_code(&self) -> api::ErrorCode {
+        match self {
+            MigrateError::MigrationAlreadyInProgress => {
+                api::ErrorCode::MigrationInProgress
+            }
+            MigrateError::NoMigrationInProgress => {
+                api::ErrorCode::NoMigrationInProgress
+            }
+            MigrateError::InstanceNotInitialized => api::ErrorCode::NoInstance,
+            MigrateError::UuidMismatch
+            | MigrateError::UpgradeExpected
+            | MigrateError::UnknownDevice(_)
+            | MigrateError::NonMigratableDevice(_)
+            | MigrateError::NotMigrationSource
+            | MigrateError::PastCutover
+            | MigrateError::TooManyRamStreams => api::ErrorCode::InvalidRequest,
+            MigrateError::Websocket(_)
+            | MigrateError::Initiate
+            | MigrateError::ProtocolParse(_, _)
+            | MigrateError::NoMatchingProtocol(_, _)
+            | MigrateError::InvalidInstanceState
+            | MigrateError::Codec(_)
+            | MigrateError::UnexpectedMessage
+            | MigrateError::SourcePause
+            | MigrateError::Phase
+            | MigrateError::TimeData(_)
+            | MigrateError::DeviceState(_)
+            | MigrateError::RemoteError(_, _)
+            | MigrateError::Cancelled
+            | MigrateError::SavedState(_)
+            | MigrateError::Tls(_)
+            | MigrateError::UnsupportedPayloads(_)
+            | MigrateError::Timeout
+            | MigrateError::StateMachine(_) => api::ErrorCode::MigrationFailed,
+        }
+    }
+
+    /// Classifies an error which ended a migration in `phase`, for the
+    /// migration's status.
+    fn failure_cause(
+        &self,
+        phase: MigrationState,
+    ) -> api::MigrationFailureCause {
+        use api::MigrationFailureCause as Cause;
+        match self {
+            MigrateError::Websocket(_) | MigrateError::Tls(_)
+                if matches!(
+                    phase,
+                    MigrationState::RamPush
+                        | MigrationState::RamPushDirty
+                        | MigrationState::RamPull
+                ) =>
+            {
+                Cause::RamTransferIo
+            }
+            MigrateError::Websocket(_)
+            | MigrateError::Tls(_)
+            | MigrateError::Codec(_)
+            | MigrateError::UnexpectedMessage
+            | MigrateError::Phase
+            | MigrateError::TooManyRamStreams => Cause::Connection,
+            MigrateError::Timeout => Cause::Timeout,
+            MigrateError::Initiate
+            | MigrateError::ProtocolParse(_, _)
+            | MigrateError::NoMatchingProtocol(_, _)
+            | MigrateError::UpgradeExpected
+            | MigrateError::InstanceNotInitialized
+            | MigrateError::UuidMismatch
+            | MigrateError::MigrationAlreadyInProgress
+            | MigrateError::InvalidInstanceState => Cause::OfferRejected,
+            MigrateError::DeviceState(_)
+            | MigrateError::UnknownDevice(_)
+            | MigrateError::NonMigratableDevice(_)
+            | MigrateError::UnsupportedPayloads(_) => Cause::DeviceIncompatible,
+            MigrateError::RemoteError(_, _) => Cause::Remote,
+            MigrateError::Cancelled => Cause::Cancelled,
+            MigrateError::NoMigrationInProgress
+            | MigrateError::StateMachine(_)
+            | MigrateError::SourcePause
+            | MigrateError::TimeData(_)
+            | MigrateError::NotMigrationSource
+            | MigrateError::PastCutover
+            | MigrateError::SavedState(_) => Cause::Internal,
+        }
+    }
+
+    /// Describes this error, which ended a migration in `phase`, for the
+    /// migration's status.
+    pub(crate) fn to_failure(
+        &self,
+        phase: MigrationState,
+    ) -> api::MigrationFailure {
+        api::MigrationFailure {
+            cause: self.failure_cause(phase),
+            phase,
+            message: self.to_string(),
         }
     }
 }
@@ -251,6 +429,20 @@ pub async fn source_start<
     )
     .map_err(|_| MigrateError::InstanceNotInitialized)?;
 
+    // A passthrough device's state lives in host hardware, which can't follow
+    // the instance to another machine.  Refuse to start, rather than failing
+    // once the instance is already paused: such devices must be detached
+    // before the instance can migrate.
+    if let Some(name) = passthrough_device(&controller).await {
+        error!(log, "can't migrate with passthrough device"; "device" => &name);
+        conn.send(tungstenite::Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: format!("non-migratable device {name:?} attached").into(),
+        })))
+        .await?;
+        return Err(MigrateError::NonMigratableDevice(name));
+    }
+
     let selected = match conn.next().await {
         Some(Ok(tungstenite::Message::Text(dst_protocols))) => {
             info!(log, "destination offered protocols: {}", dst_protocols);
@@ -307,6 +499,47 @@ pub async fn source_start<
     Ok(())
 }
 
+/// Returns the name of a passthrough device attached to the instance, if there
+/// is one.
+async fn passthrough_device(controller: &VmController) -> Option<String> {
+    let spec = controller.instance_spec().await;
+    let VersionedInstanceSpec::V0(v0) = &*spec;
+    v0.devices.network_devices.iter().find_map(|(name, dev)| {
+        matches!(dev, NetworkDeviceV0::PassthroughNic(_)).then(|| name.clone())
+    })
+}
+
+/// Collects the schemas of the payloads exported and imported by each of the
+/// controller's migratable devices, keyed by the devices' names.
+fn device_schemas(
+    controller: &VmController,
+) -> BTreeMap<String, PayloadSchemas> {
+    let mut schemas = BTreeMap::new();
+    controller.for_each_device(|name, dev| {
+        if let Some(dev_schemas) = dev.migrate().schemas() {
+            schemas.insert(name.to_string(), dev_schemas);
+        }
+    });
+    schemas
+}
+
+/// Lists the kind and version of each payload the controller's devices will
+/// export, as declared in the migration preamble.
+fn device_payloads(
+    controller: &VmController,
+) -> BTreeMap<String, Vec<(String, u32)>> {
+    device_schemas(controller)
+        .into_iter()
+        .map(|(name, schemas)| {
+            let payloads = schemas
+                .exports()
+                .map(|(kind, version)| (kind.to_string(), version))
+                .collect();
+            (name, payloads)
+        })
+        .collect()
+}
+
 /// Initiate a migration to the given source instance.
 ///
 /// This will attempt to open a websocket to the given source instance and
@@ -330,15 +563,81 @@ pub(crate) async fn dest_initiate(
 
     // Build upgrade request to the source instance
     // (we do this by hand because it's hidden from the OpenAPI spec)
-    // TODO(#165): https (wss)
     // TODO: We need to make sure the src_addr is a valid target
     let src_migrate_url = format!(
         "ws://{}/instance/migrate/{}/start",
         migrate_info.src_addr, migration_id,
     );
-    info!(log, "Begin migration"; "src_migrate_url" => &src_migrate_url);
-    let (mut conn, _) =
-        tokio_tungstenite::connect_async(src_migrate_url).await?;
+    info!(log, "Begin migration";
+          "src_migrate_url" => &src_migrate_url,
+          "tls" => migrate_info.tls.is_some());
+    let connector =
+        tls::SourceConnector::new(rqctx.context(), migrate_info.tls.as_ref())?;
+    let (conn, selected, retrier) = match migrate_info.retry.clone() {
+        Some(policy) => {
+            let reconnect: retry::Reconnect<_> = {
+                let url = src_migrate_url.clone();
+                let connector = connector.clone();
+                let log = log.clone();
+                Box::new(move || {
+                    let url = url.clone();
+                    let connector = connector.clone();
+                    let log = log.clone();
+                    async move {
+                        connect_to_source(&url, &connector, &log).await
+                    }
+                    .boxed()
+                })
+            };
+            let mut retrier = retry::Retrier::new(policy, reconnect);
+            let (conn, selected) = retrier.connect(&log).await?;
+            (conn, selected, Some(retrier))
+        }
+        None => {
+            let (conn, selected) =
+                connect_to_source(&src_migrate_url, &connector, &log).await?;
+            (conn, selected, None)
+        }
+    };
+    let local_addr = rqctx.server.local_addr;
+    let ram_stream_url = format!(
+        "ws://{}/instance/migrate/{}/ram-stream",
+        migrate_info.src_addr, migration_id,
+    );
+    tokio::runtime::Handle::current()
+        .spawn_blocking(move || -> Result<(), MigrateError> {
+            // Now start using the websocket for the migration protocol
+            controller.request_migration_into(
+                migration_id,
+                conn,
+                local_addr,
+                selected,
+                ram_stream_url,
+                connector,
+                migrate_info.auto_converge,
+                migrate_info.max_downtime_ms,
+                migrate_info.dirty_tracking,
+                retrier,
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap()?;
+
+    Ok(api::InstanceMigrateInitiateResponse { migration_id })
+}
+
+/// Connects to the source of a migration at `src_migrate_url` and negotiates
+/// the protocol to use with it (destination-side).
+async fn connect_to_source(
+    src_migrate_url: &str,
+    connector: &tls::SourceConnector,
+    log: &Logger,
+) -> Result<
+    (WebSocketStream<MaybeTlsStream<TcpStream>>, protocol::Protocol),
+    MigrateError,
+> {
+    let mut conn = connector.connect(src_migrate_url).await?;
 
     let dst_protocols = protocol::make_protocol_offer();
     conn.send(tungstenite::Message::Text(dst_protocols)).await?;
@@ -382,22 +681,7 @@ pub(crate) async fn dest_initiate(
             return Err(MigrateError::Initiate);
         }
     };
-    let local_addr = rqctx.server.local_addr;
-    tokio::runtime::Handle::current()
-        .spawn_blocking(move || -> Result<(), MigrateError> {
-            // Now start using the websocket for the migration protocol
-            controller.request_migration_into(
-                migration_id,
-                conn,
-                local_addr,
-                selected,
-            )?;
-            Ok(())
-        })
-        .await
-        .unwrap()?;
-
-    Ok(api::InstanceMigrateInitiateResponse { migration_id })
+    Ok((conn, selected))
 }
 
 // We should probably turn this into some kind of ValidatedBitmap
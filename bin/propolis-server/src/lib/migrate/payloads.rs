@@ -0,0 +1,176 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Canned device payloads, for testing migration between Propolis builds.
+//!
+//! A server started with `--emit-migration-payloads <DIR>` writes the device
+//! state it sends in each migration out of its instance to `DIR`, one file
+//! per payload, at `<DIR>/<device>/<kind>.v<version>.ron`.  A server started
+//! with `--import-migration-payloads <DIR>` imports, in each migration into
+//! its instance, the payloads found in `<DIR>/<device>` in place of those the
+//! source sent for that device.  The payloads of one build can therefore be
+//! saved once and imported by another, catching changes that would break
+//! migration between them before they are released.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::migrate::{Device, DevicePayload, MigrateError};
+
+fn payload_error(path: &Path, e: impl std::fmt::Display) -> MigrateError {
+    MigrateError::DeviceState(format!("{}: {e}", path.display()))
+}
+
+/// Returns the directory in `dir` holding the payloads of the device named
+/// `name`.
+fn device_dir(dir: &Path, name: &str) -> Result<PathBuf, MigrateError> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(MigrateError::DeviceState(format!(
+            "device name {name:?} can't name a payload directory"
+        )));
+    }
+    Ok(dir.join(name))
+}
+
+fn file_name(payload: &DevicePayload) -> String {
+    format!("{}.v{}.ron", payload.kind, payload.version)
+}
+
+/// Parses the kind and version of a payload from the name of the file
+/// holding it.
+fn parse_file_name(name: &str) -> Option<(String, u32)> {
+    let (kind, version) = name.strip_suffix(".ron")?.rsplit_once(".v")?;
+    if kind.is_empty() {
+        return None;
+    }
+    Some((kind.to_string(), version.parse().ok()?))
+}
+
+/// Writes the payloads of `devices` to `dir`, replacing any written before.
+pub(crate) fn emit(dir: &Path, devices: &[Device]) -> Result<(), MigrateError> {
+    for device in devices {
+        let device_dir = device_dir(dir, &device.instance_name)?;
+        if device_dir.exists() {
+            fs::remove_dir_all(&device_dir)
+                .map_err(|e| payload_error(&device_dir, e))?;
+        }
+        fs::create_dir_all(&device_dir)
+            .map_err(|e| payload_error(&device_dir, e))?;
+        for payload in &device.payload {
+            let path = device_dir.join(file_name(payload));
+            fs::write(&path, &payload.data)
+                .map_err(|e| payload_error(&path, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the payloads of each of `devices` for which `dir` holds payloads
+/// with those payloads, returning the names of the devices replaced.
+pub(crate) fn import(
+    dir: &Path,
+    devices: &mut [Device],
+) -> Result<Vec<String>, MigrateError> {
+    let mut replaced = Vec::new();
+    for device in devices {
+        let device_dir = device_dir(dir, &device.instance_name)?;
+        if !device_dir.is_dir() {
+            continue;
+        }
+
+        let mut payloads = Vec::new();
+        let entries = fs::read_dir(&device_dir)
+            .map_err(|e| payload_error(&device_dir, e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| payload_error(&device_dir, e))?.path();
+            let Some((kind, version)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(parse_file_name)
+            else {
+                return Err(payload_error(&path, "not a device payload"));
+            };
+            let data = fs::read_to_string(&path)
+                .map_err(|e| payload_error(&path, e))?;
+            payloads.push(DevicePayload { kind, version, data });
+        }
+
+        // Directory order is arbitrary, so sort the payloads to import them in
+        // the same order every time.
+        payloads
+            .sort_by(|a, b| (&a.kind, a.version).cmp(&(&b.kind, b.version)));
+        device.payload = payloads;
+        replaced.push(device.instance_name.clone());
+    }
+    Ok(replaced)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn device(name: &str, payloads: &[(&str, u32, &str)]) -> Device {
+        Device {
+            instance_name: name.to_string(),
+            payload: payloads
+                .iter()
+                .map(|&(kind, version, data)| DevicePayload {
+                    kind: kind.to_string(),
+                    version,
+                    data: data.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(
+            parse_file_name("bhyve-rtc.v2.ron"),
+            Some(("bhyve-rtc".to_string(), 2))
+        );
+        assert_eq!(
+            parse_file_name("pci.v1.v3.ron"),
+            Some(("pci.v1".to_string(), 3))
+        );
+        assert_eq!(parse_file_name("bhyve-rtc.v2"), None);
+        assert_eq!(parse_file_name(".v2.ron"), None);
+        assert_eq!(parse_file_name("bhyve-rtc.vx.ron"), None);
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let emitted = [
+            device("com1", &[("uart", 2, "(esr:0)")]),
+            device("pci-ahci", &[("pci", 1, "(cfg:[])"), ("ahci", 1, "()")]),
+        ];
+        emit(dir.path(), &emitted).unwrap();
+
+        // Emitting again replaces whatever was there before.
+        emit(dir.path(), &[device("com1", &[("uart", 3, "(esr:1)")])]).unwrap();
+
+        let mut sent = vec![
+            device("com1", &[("uart", 2, "(esr:7)")]),
+            device("pci-ahci", &[("pci", 1, "(cfg:[1])")]),
+            device("rtc", &[("bhyve-rtc", 2, "()")]),
+        ];
+        let replaced = import(dir.path(), &mut sent).unwrap();
+        assert_eq!(replaced, ["com1", "pci-ahci"]);
+        assert_eq!(sent[0].payload.len(), 1);
+        assert_eq!(sent[0].payload[0].version, 3);
+        assert_eq!(sent[0].payload[0].data, "(esr:1)");
+        let kinds: Vec<_> =
+            sent[1].payload.iter().map(|p| p.kind.as_str()).collect();
+        assert_eq!(kinds, ["ahci", "pci"]);
+        assert_eq!(sent[2].payload[0].kind, "bhyve-rtc");
+    }
+
+    #[test]
+    fn device_names_stay_in_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(emit(dir.path(), &[device("../com1", &[])]).is_err());
+        assert!(emit(dir.path(), &[device("..", &[])]).is_err());
+    }
+}
@@ -2,21 +2,97 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
-use propolis_api_types::instance_spec::{
-    migration::{CollectionCompatibilityError, MigrationCompatibilityError},
-    v0::{DeviceSpecV0, InstanceSpecV0},
-    VersionedInstanceSpec,
+use propolis::migrate::PayloadSchemas;
+use propolis_api_types::{
+    instance_spec::{
+        migration::{
+            CollectionCompatibilityError, MigrationCompatibilityError,
+        },
+        v0::{DeviceSpecV0, InstanceSpecV0},
+        VersionedInstanceSpec,
+    },
+    MigrationAutoConverge, MigrationDirtyTracking,
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::MutexGuard;
+
+use crate::migrate::compress::PageCompression;
+use crate::migrate::MigrateError;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct Preamble {
     pub device_spec: DeviceSpecV0,
     pub backend_keys: BTreeSet<String>,
     pub blobs: Vec<Vec<u8>>,
+
+    /// The page compression algorithms the source offers to use, in order of
+    /// preference. These are names rather than an enum so that a destination
+    /// can ignore algorithms it doesn't know.
+    #[serde(default)]
+    pub page_compression: Vec<String>,
+
+    /// The number of connections, including the main migration connection,
+    /// over which the source will serve fetches of guest memory. Sources
+    /// which predate parallel RAM transfer omit this, and so offer zero.
+    #[serde(default)]
+    pub ram_streams: u32,
+
+    /// Set if the source can send all-zero pages as
+    /// [`codec::Message::ZeroPage`] markers.
+    ///
+    /// [`codec::Message::ZeroPage`]: super::codec::Message::ZeroPage
+    #[serde(default)]
+    pub zero_pages: bool,
+
+    /// Set if the source can push RAM in as many rounds as needed before
+    /// pausing the guest, throttling its vCPUs if need be.
+    #[serde(default)]
+    pub precopy_rounds: bool,
+
+    /// Set if the source can track dirty pages as the destination asks in
+    /// its reply.
+    #[serde(default)]
+    pub dirty_tracking: bool,
+
+    /// The kind and version of each payload the source will send for each of
+    /// its devices, keyed by the devices' names, so that the destination can
+    /// refuse the migration before any state moves if it can't import them.
+    /// Sources which predate this omit it, and their payloads are checked
+    /// only when they arrive.
+    #[serde(default)]
+    pub device_payloads: BTreeMap<String, Vec<(String, u32)>>,
+}
+
+/// The destination's reply to the preamble, selecting which of the optional
+/// features offered by the source will be used. A destination which selects
+/// none of them, including one that predates them, replies with a bare `Okay`
+/// instead.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PreambleReply {
+    /// The name of the page compression algorithm selected, if any.
+    #[serde(default)]
+    pub page_compression: Option<String>,
+
+    /// Set if the source should send all-zero pages as markers.
+    #[serde(default)]
+    pub zero_pages: bool,
+
+    /// If set, the source should push RAM in rounds before pausing the guest,
+    /// throttling its vCPUs within these limits, ending the pre-pause phase
+    /// with a round that offers no pages.
+    #[serde(default)]
+    pub auto_converge: Option<MigrationAutoConverge>,
+
+    /// If set, the source should push RAM in rounds before pausing the guest
+    /// until the pages left dirty can be sent within this many milliseconds.
+    #[serde(default)]
+    pub max_downtime_ms: Option<u64>,
+
+    /// If set, how the source should track the pages dirtied while guest
+    /// memory is sent.
+    #[serde(default)]
+    pub dirty_tracking: Option<MigrationDirtyTracking>,
 }
 
 fn get_spec_backend_keys(spec: &InstanceSpecV0) -> BTreeSet<String> {
@@ -29,20 +105,34 @@ fn get_spec_backend_keys(spec: &InstanceSpecV0) -> BTreeSet<String> {
 }
 
 impl Preamble {
-    pub fn new(instance_spec: VersionedInstanceSpec) -> Preamble {
+    pub fn new(
+        instance_spec: VersionedInstanceSpec,
+        page_compression: &[PageCompression],
+        ram_streams: u32,
+        device_payloads: BTreeMap<String, Vec<(String, u32)>>,
+    ) -> Preamble {
         let VersionedInstanceSpec::V0(instance_spec) = instance_spec;
         Preamble {
             device_spec: instance_spec.devices.clone(),
             backend_keys: get_spec_backend_keys(&instance_spec),
             blobs: Vec::new(),
+            page_compression: page_compression
+                .iter()
+                .map(|c| c.name().to_string())
+                .collect(),
+            ram_streams,
+            zero_pages: true,
+            precopy_rounds: true,
+            dirty_tracking: true,
+            device_payloads,
         }
     }
 
     pub fn is_migration_compatible(
         &self,
-        other_spec: MutexGuard<'_, VersionedInstanceSpec>,
+        other_spec: &VersionedInstanceSpec,
     ) -> Result<(), MigrationCompatibilityError> {
-        let VersionedInstanceSpec::V0(other_spec) = &*other_spec;
+        let VersionedInstanceSpec::V0(other_spec) = other_spec;
 
         self.device_spec.can_migrate_devices_from(&other_spec.devices)?;
         let other_keys = get_spec_backend_keys(other_spec);
@@ -71,4 +161,112 @@ impl Preamble {
 
         Ok(())
     }
+
+    /// Checks that the destination's devices, whose payload schemas are in
+    /// `local`, can import every payload the source declared it will send.
+    ///
+    /// Devices which aren't in `local` are skipped: they are reported as
+    /// unknown or non-migratable when their state arrives.
+    pub fn check_device_payloads(
+        &self,
+        local: &BTreeMap<String, PayloadSchemas>,
+    ) -> Result<(), MigrateError> {
+        let mut problems = Vec::new();
+        for (name, payloads) in &self.device_payloads {
+            let Some(schemas) = local.get(name) else {
+                continue;
+            };
+            for (kind, version) in payloads {
+                if schemas.can_import(kind, *version) {
+                    continue;
+                }
+                let supported = schemas
+                    .import_versions(kind)
+                    .map(|v| format!("v{v}"))
+                    .collect::<Vec<_>>();
+                let supported = if supported.is_empty() {
+                    "none".to_string()
+                } else {
+                    supported.join(", ")
+                };
+                problems.push(format!(
+                    "device {name:?}: state {kind:?} v{version} from the \
+                     source is not supported here (supported: {supported})"
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(MigrateError::UnsupportedPayloads(problems.join("; ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use propolis::migrate::Schema;
+
+    #[derive(Deserialize, Serialize)]
+    struct UartV1;
+    impl Schema<'_> for UartV1 {
+        fn id() -> propolis::migrate::SchemaId {
+            ("uart", 1)
+        }
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct UartV2;
+    impl Schema<'_> for UartV2 {
+        fn id() -> propolis::migrate::SchemaId {
+            ("uart", 2)
+        }
+    }
+
+    fn preamble(payloads: &[(&str, &str, u32)]) -> Preamble {
+        let mut device_payloads = BTreeMap::<_, Vec<_>>::new();
+        for (name, kind, version) in payloads {
+            device_payloads
+                .entry(name.to_string())
+                .or_default()
+                .push((kind.to_string(), *version));
+        }
+        Preamble {
+            device_spec: Default::default(),
+            backend_keys: Default::default(),
+            blobs: Vec::new(),
+            page_compression: Vec::new(),
+            ram_streams: 0,
+            zero_pages: false,
+            precopy_rounds: false,
+            dirty_tracking: false,
+            device_payloads,
+        }
+    }
+
+    #[test]
+    fn device_payloads_checked() {
+        let mut uart = PayloadSchemas::default();
+        uart.add::<UartV2>().add_import::<UartV1>();
+        let local = BTreeMap::from([("com1".to_string(), uart)]);
+
+        // Current and older versions can be imported, as can payloads for
+        // devices the destination doesn't have.
+        preamble(&[("com1", "uart", 2)]).check_device_payloads(&local).unwrap();
+        preamble(&[("com1", "uart", 1), ("com2", "uart", 3)])
+            .check_device_payloads(&local)
+            .unwrap();
+
+        let err = preamble(&[("com1", "uart", 3), ("com1", "rtc", 1)])
+            .check_device_payloads(&local)
+            .unwrap_err();
+        let MigrateError::UnsupportedPayloads(msg) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert!(msg.contains("\"uart\" v3"), "{msg}");
+        assert!(msg.contains("supported: v1, v2"), "{msg}");
+        assert!(msg.contains("supported: none"), "{msg}");
+    }
 }
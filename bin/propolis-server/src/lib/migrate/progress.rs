@@ -0,0 +1,149 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tracking of how far a migration's RAM transfer has progressed.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use propolis::common::PAGE_SIZE;
+
+#[derive(Default)]
+struct Inner {
+    /// When the first RAM push round began.
+    ram_started: Option<Instant>,
+    pages_transferred: u64,
+    /// The number of RAM push rounds begun so far.
+    iteration: u32,
+    /// Pages offered in the current round which are yet to be transferred.
+    backlog: u64,
+    /// The number of pages offered in each round begun so far.
+    round_pages: Vec<u64>,
+    /// How far the guest's clocks were found to be ahead of this host's.
+    clock_skew: Option<Duration>,
+}
+
+/// Progress of a migration's RAM transfer, updated by the migration task on
+/// either side and read by API handlers.
+#[derive(Default)]
+pub(crate) struct MigrationProgress {
+    inner: Mutex<Inner>,
+}
+
+/// A point-in-time copy of a migration's progress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProgressSnapshot {
+    pub bytes_transferred: u64,
+    pub iteration: u32,
+    pub dirty_pages_remaining: u64,
+    /// The number of pages offered in each round begun so far, in order.
+    pub dirty_pages_per_iteration: Vec<u64>,
+    /// The time needed to transfer the remaining dirty pages at the average
+    /// rate achieved so far, if anything remains and a rate is known.
+    pub estimated_remaining: Option<Duration>,
+    /// How far the guest's clocks were found to be ahead of this host's once
+    /// its time data was imported, on the destination.
+    pub clock_skew: Option<Duration>,
+}
+
+impl MigrationProgress {
+    /// Notes the start of a RAM push round.
+    pub fn begin_round(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ram_started.get_or_insert_with(Instant::now);
+        inner.iteration += 1;
+        inner.backlog = 0;
+        inner.round_pages.push(0);
+    }
+
+    /// Adds `pages` pages offered in the current round to the backlog.
+    pub fn pages_offered(&self, pages: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.backlog += pages;
+        if let Some(round) = inner.round_pages.last_mut() {
+            *round += pages;
+        }
+    }
+
+    /// Notes the transfer of a single page.
+    pub fn page_transferred(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pages_transferred += 1;
+        inner.backlog = inner.backlog.saturating_sub(1);
+    }
+
+    /// Notes the end of a RAM push round.  Any pages offered but not fetched
+    /// by the destination are no longer outstanding.
+    pub fn end_round(&self) {
+        self.inner.lock().unwrap().backlog = 0;
+    }
+
+    /// Notes how far the guest's clocks were found to be ahead of this host's
+    /// once its time data was imported.
+    pub fn clock_skew_found(&self, skew: Duration) {
+        self.inner.lock().unwrap().clock_skew = Some(skew);
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let elapsed = inner.ram_started.map(|t| t.elapsed());
+        snapshot_at(&inner, elapsed)
+    }
+}
+
+fn snapshot_at(inner: &Inner, elapsed: Option<Duration>) -> ProgressSnapshot {
+    let bytes_transferred = inner.pages_transferred * PAGE_SIZE as u64;
+    let estimated_remaining = match elapsed {
+        Some(elapsed) if inner.backlog > 0 && inner.pages_transferred > 0 => {
+            let secs_per_page =
+                elapsed.as_secs_f64() / inner.pages_transferred as f64;
+            Some(Duration::from_secs_f64(secs_per_page * inner.backlog as f64))
+        }
+        _ => None,
+    };
+    ProgressSnapshot {
+        bytes_transferred,
+        iteration: inner.iteration,
+        dirty_pages_remaining: inner.backlog,
+        dirty_pages_per_iteration: inner.round_pages.clone(),
+        estimated_remaining,
+        clock_skew: inner.clock_skew,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimate_from_rate() {
+        let progress = MigrationProgress::default();
+        assert_eq!(progress.snapshot().estimated_remaining, None);
+
+        progress.begin_round();
+        progress.pages_offered(768);
+        for _ in 0..256 {
+            progress.page_transferred();
+        }
+        let inner = progress.inner.lock().unwrap();
+        let snap = snapshot_at(&inner, Some(Duration::from_secs(4)));
+        assert_eq!(snap.bytes_transferred, 256 * PAGE_SIZE as u64);
+        assert_eq!(snap.iteration, 1);
+        assert_eq!(snap.dirty_pages_remaining, 512);
+        assert_eq!(snap.estimated_remaining, Some(Duration::from_secs(8)));
+        drop(inner);
+
+        progress.end_round();
+        progress.begin_round();
+        let snap = progress.snapshot();
+        assert_eq!(snap.iteration, 2);
+        assert_eq!(snap.dirty_pages_remaining, 0);
+        assert_eq!(snap.estimated_remaining, None);
+        assert_eq!(snap.dirty_pages_per_iteration, vec![768, 0]);
+
+        progress.pages_offered(16);
+        let snap = progress.snapshot();
+        assert_eq!(snap.dirty_pages_per_iteration, vec![768, 16]);
+    }
+}
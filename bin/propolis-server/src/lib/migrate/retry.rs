@@ -0,0 +1,143 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Retrying migrations into an instance which fail before any guest state
+//! has been transferred.
+//!
+//! Until the destination's guest memory and devices have been written, a
+//! failed attempt leaves the destination just as it was when the migration
+//! was requested, so it can simply connect to the source again.  Only errors
+//! which a new connection might not meet are retried: a source which refuses
+//! the migration outright would refuse it again.
+
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use propolis_api_types::MigrationRetryPolicy;
+use slog::{warn, Logger};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::migrate::protocol::Protocol;
+use crate::migrate::MigrateError;
+
+/// Opens a new connection to the source of a migration and negotiates the
+/// protocol to use over it.
+pub(crate) type Reconnect<T> = Box<
+    dyn FnMut() -> BoxFuture<
+            'static,
+            Result<(WebSocketStream<T>, Protocol), MigrateError>,
+        > + Send,
+>;
+
+/// Decides whether, and when, to make another attempt at a migration into
+/// this instance.
+pub(crate) struct Retrier<T> {
+    policy: MigrationRetryPolicy,
+
+    /// The number of attempts made so far, including the current one.
+    attempts: u32,
+
+    reconnect: Reconnect<T>,
+}
+
+impl<T> Retrier<T> {
+    pub fn new(policy: MigrationRetryPolicy, reconnect: Reconnect<T>) -> Self {
+        Self { policy, attempts: 1, reconnect }
+    }
+
+    /// Returns `true`, once the policy's backoff has passed, if another
+    /// attempt should be made after one failed with `err`.
+    pub async fn should_retry(
+        &mut self,
+        err: &MigrateError,
+        log: &Logger,
+    ) -> bool {
+        if !is_transient(err) || self.attempts >= self.policy.max_attempts {
+            return false;
+        }
+
+        self.attempts += 1;
+        warn!(log, "retrying migration";
+              "attempt" => self.attempts,
+              "max_attempts" => self.policy.max_attempts,
+              "error" => %err);
+        tokio::time::sleep(Duration::from_millis(self.policy.backoff_ms)).await;
+        true
+    }
+
+    /// Connects to the source, retrying within the policy if that fails.
+    pub async fn connect(
+        &mut self,
+        log: &Logger,
+    ) -> Result<(WebSocketStream<T>, Protocol), MigrateError> {
+        loop {
+            match (self.reconnect)().await {
+                Ok(conn) => return Ok(conn),
+                Err(e) if self.should_retry(&e, log).await => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Returns `true` if an attempt that failed with `err` might succeed if it
+/// were made again.
+fn is_transient(err: &MigrateError) -> bool {
+    matches!(
+        err,
+        MigrateError::Websocket(_)
+            | MigrateError::Timeout
+            | MigrateError::Initiate
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    fn make_retrier(max_attempts: u32) -> Retrier<tokio::io::DuplexStream> {
+        Retrier::new(
+            MigrationRetryPolicy { max_attempts, backoff_ms: 0 },
+            Box::new(|| async { Err(MigrateError::Initiate) }.boxed()),
+        )
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut retrier = make_retrier(3);
+        let err = MigrateError::Websocket("connection reset".to_string());
+        assert!(retrier.should_retry(&err, &log).await);
+        assert!(retrier.should_retry(&MigrateError::Timeout, &log).await);
+        assert!(!retrier.should_retry(&err, &log).await);
+
+        // Connecting counts against the same limit.
+        let mut retrier = make_retrier(2);
+        assert!(matches!(
+            retrier.connect(&log).await,
+            Err(MigrateError::Initiate)
+        ));
+        assert_eq!(retrier.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn refusals_are_not_retried() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut retrier = make_retrier(5);
+        assert!(
+            !retrier
+                .should_retry(&MigrateError::InvalidInstanceState, &log)
+                .await
+        );
+        assert!(
+            !retrier
+                .should_retry(
+                    &MigrateError::UnsupportedPayloads(String::new()),
+                    &log
+                )
+                .await
+        );
+    }
+}
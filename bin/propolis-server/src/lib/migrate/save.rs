@@ -0,0 +1,679 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Saving an instance's state to a file on the host, and restoring an
+//! instance from such a file.
+//!
+//! Saving runs an ordinary migration out of the instance, whose destination is
+//! a peer in this process that writes what the source sends to a file.  The
+//! file is written under a temporary name in the same directory, and renamed
+//! into place only once it's complete, so that a failed save leaves any file
+//! saved before it intact.  Since the preamble holds the instance's spec,
+//! including any disks' encryption keys, only the server's user may read it.
+//! Restoring runs an ordinary migration into the instance, whose source is a
+//! peer that replays the file.  Each peer is connected to the migration task
+//! by an in-memory stream.
+//!
+//! A saved-state file begins with [`MAGIC`], followed by messages sent by the
+//! source, each encoded as it is on the wire and preceded by its length as a
+//! little-endian `u64`:
+//!
+//! - the preamble;
+//! - for each region of guest memory fetched, a `MemXfer` message followed by
+//!   a `Page` or `ZeroPage` message for each page it names.  A page fetched
+//!   more than once appears each time, and its last copy is current;
+//! - the VMM time data, the device state and the history of the first serial
+//!   port, each as a `Serialized` message.
+
+use std::collections::BTreeMap;
+use std::io::{self, SeekFrom};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use propolis::common::PAGE_SIZE;
+use slog::{error, info, Logger};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter,
+    DuplexStream,
+};
+use tokio_tungstenite::tungstenite::{self, protocol::Role};
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+use crate::migrate::codec::{self, Message};
+use crate::migrate::memx;
+use crate::migrate::preamble::{Preamble, PreambleReply};
+use crate::migrate::protocol::Protocol;
+use crate::migrate::{MigrateError, MigrateRole, PageIter};
+use crate::vm::VmController;
+
+/// The first bytes of every saved-state file.
+const MAGIC: [u8; 8] = *b"PROPSAV1";
+
+/// The size of the largest message a saved-state file may hold.  Only the
+/// serialized device state comes close to this.
+const MAX_MESSAGE_SIZE: u64 = 1 << 30;
+
+/// The number of bytes of page bitmap in each memory offer made on restore.
+const OFFER_BITMAP_SIZE: usize = 4096;
+
+/// The capacity of the in-memory stream between a peer and the migration
+/// task.
+const STREAM_BUFFER_SIZE: usize = 1 << 20;
+
+type Conn = WebSocketStream<DuplexStream>;
+
+/// Saves the state of the instance to `path`.  Once its state has been saved,
+/// the instance stops, just as it would once migrated elsewhere.
+pub(crate) async fn save(
+    vm_controller: Arc<VmController>,
+    path: &Path,
+    local_addr: SocketAddr,
+    log: &Logger,
+) -> Result<(), MigrateError> {
+    if let Some(name) = super::passthrough_device(&vm_controller).await {
+        return Err(MigrateError::NonMigratableDevice(name));
+    }
+
+    // Nothing is written until the migration has been accepted, so that a
+    // refused save leaves the file system untouched.
+    let (mut conn, source_conn) = connect().await;
+    let migration_id = Uuid::new_v4();
+    info!(log, "Saving instance state";
+          "path" => %path.display(),
+          "migration_id" => %migration_id);
+    vm_controller.request_migration_from(
+        migration_id,
+        source_conn,
+        Protocol::RonV0,
+    )?;
+
+    let temp_path = temp_path(path, migration_id);
+    let res = match create_private(&temp_path).await {
+        Ok(file) => {
+            let mut saver = Saver {
+                conn,
+                file: BufWriter::new(file),
+                local_addr,
+                temp_path: temp_path.clone(),
+                path: path.to_path_buf(),
+            };
+            let res = saver.run().await;
+            conn = saver.conn;
+            res
+        }
+        Err(e) => Err(io_error(e)),
+    };
+    if let Err(e) = &res {
+        error!(log, "Failed to save instance state"; "error" => %e);
+
+        // Let the source know, so that the guest resumes, and don't leave a
+        // partial file behind.
+        let _ = send(&mut conn, Message::Error(e.clone())).await;
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    res
+}
+
+/// Returns the temporary name under which the state saved to `path` by the
+/// migration `migration_id` is written.
+fn temp_path(path: &Path, migration_id: Uuid) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{migration_id}.tmp"))
+}
+
+/// Creates a new file at `path` which only its owner may read or write.
+async fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path).await
+}
+
+/// Restores the instance, which must not yet have started, from the state
+/// saved in `path`.
+pub(crate) async fn restore(
+    vm_controller: Arc<VmController>,
+    path: &Path,
+    local_addr: SocketAddr,
+    log: &Logger,
+) -> Result<(), MigrateError> {
+    let mut file = BufReader::new(File::open(path).await.map_err(io_error)?);
+    let state = SavedState::load(&mut file).await?;
+    let (destination_conn, conn) = connect().await;
+    let migration_id = Uuid::new_v4();
+    info!(log, "Restoring instance state";
+          "path" => %path.display(),
+          "migration_id" => %migration_id,
+          "pages" => state.pages.len());
+    tokio::task::spawn_blocking(move || {
+        vm_controller.request_migration_into(
+            migration_id,
+            destination_conn,
+            local_addr,
+            Protocol::RonV0,
+            String::new(),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    })
+    .await
+    .unwrap()?;
+
+    let mut restorer = Restorer { conn, file, state };
+    let res = restorer.run().await;
+    if let Err(e) = &res {
+        error!(log, "Failed to restore instance state"; "error" => %e);
+        let _ = send(&mut restorer.conn, Message::Error(e.clone())).await;
+    }
+    res
+}
+
+/// Returns the ends of a new in-memory connection, the first acting as the
+/// destination's end of a migration connection and the second as the
+/// source's.
+async fn connect() -> (Conn, Conn) {
+    let (client, server) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+    (
+        WebSocketStream::from_raw_socket(client, Role::Client, None).await,
+        WebSocketStream::from_raw_socket(server, Role::Server, None).await,
+    )
+}
+
+fn io_error(e: io::Error) -> MigrateError {
+    MigrateError::SavedState(e.to_string())
+}
+
+fn corrupt(what: &str) -> MigrateError {
+    MigrateError::SavedState(format!("saved state is corrupt: {what}"))
+}
+
+fn encode(m: Message) -> Result<Vec<u8>, MigrateError> {
+    match m.try_into()? {
+        tungstenite::Message::Binary(bytes) => Ok(bytes),
+        _ => unreachable!("migration messages are binary frames"),
+    }
+}
+
+fn decode(bytes: Vec<u8>) -> Result<Message, MigrateError> {
+    Ok(tungstenite::Message::Binary(bytes).try_into()?)
+}
+
+async fn send(conn: &mut Conn, m: Message) -> Result<(), MigrateError> {
+    Ok(conn.send(m.try_into()?).await?)
+}
+
+/// Reads the next frame from `peer` over `conn`, returning both its encoded
+/// bytes and the message they hold, and lifting out any error `peer` reports.
+async fn recv_frame(
+    conn: &mut Conn,
+    peer: MigrateRole,
+) -> Result<(Vec<u8>, Message), MigrateError> {
+    let frame = conn.next().await.ok_or_else(|| {
+        codec::ProtocolError::Io(io::Error::from(io::ErrorKind::BrokenPipe))
+    })??;
+    let tungstenite::Message::Binary(bytes) = frame else {
+        return Err(
+            codec::ProtocolError::UnexpectedWebsocketMessage(frame).into()
+        );
+    };
+    match decode(bytes.clone())? {
+        Message::Error(e) => {
+            Err(MigrateError::RemoteError(peer, e.to_string()))
+        }
+        msg => Ok((bytes, msg)),
+    }
+}
+
+async fn recv(
+    conn: &mut Conn,
+    peer: MigrateRole,
+) -> Result<Message, MigrateError> {
+    recv_frame(conn, peer).await.map(|(_, msg)| msg)
+}
+
+async fn recv_ok(
+    conn: &mut Conn,
+    peer: MigrateRole,
+) -> Result<(), MigrateError> {
+    match recv(conn, peer).await? {
+        Message::Okay => Ok(()),
+        _ => Err(MigrateError::UnexpectedMessage),
+    }
+}
+
+/// Acts as the destination of a migration, writing the state the source sends
+/// to a file at `temp_path`, which is renamed to `path` once it's complete.
+struct Saver {
+    conn: Conn,
+    file: BufWriter<File>,
+    local_addr: SocketAddr,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Saver {
+    async fn run(&mut self) -> Result<(), MigrateError> {
+        self.file.write_all(&MAGIC).await.map_err(io_error)?;
+
+        // Ask for all-zero pages to be sent as markers, to keep them out of
+        // the file.
+        self.record_serialized().await?;
+        let reply = PreambleReply { zero_pages: true, ..Default::default() };
+        let reply =
+            ron::ser::to_string(&reply).map_err(codec::ProtocolError::from)?;
+        self.send(Message::Serialized(reply)).await?;
+
+        // RAM is pushed once before the guest is paused and once after.
+        self.ram_round().await?;
+        self.ram_round().await?;
+
+        self.record_serialized().await?;
+        self.send(Message::Okay).await?;
+
+        self.record_serialized().await?;
+        self.recv_ok().await?;
+        self.send(Message::Okay).await?;
+
+        // There's nothing to pull.
+        self.send(Message::MemQuery(0, !0)).await?;
+        match self.recv().await? {
+            Message::MemEnd(..) => {}
+            _ => return Err(MigrateError::UnexpectedMessage),
+        }
+        self.send(Message::MemDone).await?;
+
+        let addr = ron::ser::to_string(&self.local_addr)
+            .map_err(codec::ProtocolError::from)?;
+        self.send(Message::Serialized(addr)).await?;
+        self.record_serialized().await?;
+
+        // The file must be complete, and in place, before the source is told
+        // it may stop the guest.
+        self.file.flush().await.map_err(io_error)?;
+        self.file.get_ref().sync_all().await.map_err(io_error)?;
+        tokio::fs::rename(&self.temp_path, &self.path)
+            .await
+            .map_err(io_error)?;
+        self.send(Message::Okay).await?;
+
+        self.send(Message::Okay).await?;
+        self.recv_ok().await
+    }
+
+    /// Fetches every page the source offers in one round of RAM transfer.
+    async fn ram_round(&mut self) -> Result<(), MigrateError> {
+        self.send(Message::MemQuery(0, !0)).await?;
+        let mut offers = Vec::new();
+        loop {
+            match self.recv().await? {
+                Message::MemOffer(start, end, bits) => {
+                    if !memx::validate_bitmap(start, end, &bits) {
+                        return Err(MigrateError::Phase);
+                    }
+                    offers.push((start, end, bits));
+                }
+                Message::MemEnd(..) => break,
+                _ => return Err(MigrateError::UnexpectedMessage),
+            }
+        }
+
+        for (start, end, bits) in offers {
+            self.send(memx::make_mem_fetch(start, end, &bits)).await?;
+            let (frame, msg) = self.recv_frame().await?;
+            let Message::MemXfer(start, end, bits) = msg else {
+                return Err(MigrateError::UnexpectedMessage);
+            };
+            if !memx::validate_bitmap(start, end, &bits) {
+                return Err(MigrateError::Phase);
+            }
+            self.write_frame(&frame).await?;
+            for _ in PageIter::new(start, end, &bits) {
+                let (frame, msg) = self.recv_frame().await?;
+                match msg {
+                    Message::Page(_) | Message::ZeroPage => {}
+                    _ => return Err(MigrateError::UnexpectedMessage),
+                }
+                self.write_frame(&frame).await?;
+            }
+        }
+        self.send(Message::MemDone).await
+    }
+
+    /// Writes the next message from the source, which must be a
+    /// `Serialized` message, to the file.
+    async fn record_serialized(&mut self) -> Result<(), MigrateError> {
+        let (frame, msg) = self.recv_frame().await?;
+        let Message::Serialized(_) = msg else {
+            return Err(MigrateError::UnexpectedMessage);
+        };
+        self.write_frame(&frame).await
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), MigrateError> {
+        self.file
+            .write_all(&(frame.len() as u64).to_le_bytes())
+            .await
+            .map_err(io_error)?;
+        self.file.write_all(frame).await.map_err(io_error)
+    }
+
+    async fn send(&mut self, m: Message) -> Result<(), MigrateError> {
+        send(&mut self.conn, m).await
+    }
+
+    async fn recv_frame(&mut self) -> Result<(Vec<u8>, Message), MigrateError> {
+        recv_frame(&mut self.conn, MigrateRole::Source).await
+    }
+
+    async fn recv(&mut self) -> Result<Message, MigrateError> {
+        recv(&mut self.conn, MigrateRole::Source).await
+    }
+
+    async fn recv_ok(&mut self) -> Result<(), MigrateError> {
+        recv_ok(&mut self.conn, MigrateRole::Source).await
+    }
+}
+
+/// The contents of a saved-state file, less the contents of guest memory,
+/// which are left in the file until they are sent.
+#[derive(Debug)]
+struct SavedState {
+    preamble: String,
+    /// The offset within the file of the current contents of each page of
+    /// guest memory which isn't all zeroes.
+    pages: BTreeMap<u64, u64>,
+    time_data: String,
+    devices: String,
+    com1_history: String,
+}
+
+impl SavedState {
+    async fn load<R: AsyncRead + Unpin>(
+        file: &mut R,
+    ) -> Result<Self, MigrateError> {
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic).await.map_err(io_error)?;
+        if magic != MAGIC {
+            return Err(MigrateError::SavedState(
+                "not a saved-state file".to_string(),
+            ));
+        }
+
+        let mut offset = MAGIC.len() as u64;
+        let mut serialized = Vec::new();
+        let mut pages = BTreeMap::new();
+        let mut pending = Vec::new().into_iter();
+        loop {
+            let len = match file.read_u64_le().await {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(io_error(e)),
+            };
+            if len > MAX_MESSAGE_SIZE {
+                return Err(corrupt("message too large"));
+            }
+            let mut bytes = vec![0; len as usize];
+            file.read_exact(&mut bytes).await.map_err(io_error)?;
+            let frame_offset = offset + 8;
+            offset = frame_offset + len;
+
+            match decode(bytes)? {
+                Message::Serialized(s) => serialized.push(s),
+                Message::MemXfer(start, end, bits) => {
+                    if !pending.as_slice().is_empty() {
+                        return Err(corrupt("missing pages"));
+                    }
+                    if !memx::validate_bitmap(start, end, &bits) {
+                        return Err(corrupt("invalid page bitmap"));
+                    }
+                    pending = PageIter::new(start, end, &bits)
+                        .collect::<Vec<_>>()
+                        .into_iter();
+                }
+                Message::Page(page) if page.len() == PAGE_SIZE => {
+                    let addr =
+                        pending.next().ok_or_else(|| corrupt("stray page"))?;
+                    pages.insert(addr, frame_offset);
+                }
+                Message::ZeroPage => {
+                    let addr =
+                        pending.next().ok_or_else(|| corrupt("stray page"))?;
+                    pages.remove(&addr);
+                }
+                _ => return Err(corrupt("unexpected message")),
+            }
+        }
+        if !pending.as_slice().is_empty() {
+            return Err(corrupt("missing pages"));
+        }
+
+        let [preamble, time_data, devices, com1_history] =
+            <[String; 4]>::try_from(serialized)
+                .map_err(|_| corrupt("missing or extra state"))?;
+        Ok(Self { preamble, pages, time_data, devices, com1_history })
+    }
+
+    /// Returns memory offers covering every page which isn't all zeroes, in
+    /// ascending order of address.
+    fn offers(&self) -> Vec<(u64, u64, Vec<u8>)> {
+        let region_size = (OFFER_BITMAP_SIZE * 8 * PAGE_SIZE) as u64;
+        let mut offers: Vec<(u64, u64, Vec<u8>)> = Vec::new();
+        for &addr in self.pages.keys() {
+            let start = addr - addr % region_size;
+            if offers.last().map(|(s, _, _)| *s) != Some(start) {
+                offers.push((
+                    start,
+                    start + region_size,
+                    vec![0; OFFER_BITMAP_SIZE],
+                ));
+            }
+            let bits = &mut offers.last_mut().unwrap().2;
+            let page = ((addr - start) / PAGE_SIZE as u64) as usize;
+            bits[page / 8] |= 1 << (page % 8);
+        }
+        offers
+    }
+}
+
+/// Acts as the source of a migration, sending the state saved in a file.
+struct Restorer {
+    conn: Conn,
+    file: BufReader<File>,
+    state: SavedState,
+}
+
+impl Restorer {
+    async fn run(&mut self) -> Result<(), MigrateError> {
+        // None of the optional features offered when the state was saved are
+        // worth using to read it back from a local file.
+        let mut preamble: Preamble = ron::de::from_str(&self.state.preamble)
+            .map_err(codec::ProtocolError::from)?;
+        preamble.page_compression.clear();
+        preamble.ram_streams = 0;
+        preamble.zero_pages = false;
+        preamble.precopy_rounds = false;
+        preamble.dirty_tracking = false;
+        let preamble = ron::ser::to_string(&preamble)
+            .map_err(codec::ProtocolError::from)?;
+        self.send(Message::Serialized(preamble)).await?;
+        self.recv_ok().await?;
+
+        // Send every page before the "pause", leaving nothing dirty after it.
+        let query = self.recv_mem_query().await?;
+        for (start, end, bits) in self.state.offers() {
+            self.send(memx::make_mem_offer(start, end, &bits)).await?;
+        }
+        self.send(Message::MemEnd(query.0, query.1)).await?;
+        self.serve_fetches().await?;
+        let query = self.recv_mem_query().await?;
+        self.send(Message::MemEnd(query.0, query.1)).await?;
+        self.serve_fetches().await?;
+
+        let time_data = self.state.time_data.clone();
+        self.send(Message::Serialized(time_data)).await?;
+        self.recv_ok().await?;
+
+        let devices = self.state.devices.clone();
+        self.send(Message::Serialized(devices)).await?;
+        self.send(Message::Okay).await?;
+        self.recv_ok().await?;
+
+        let query = self.recv_mem_query().await?;
+        self.send(Message::MemEnd(query.0, query.1)).await?;
+        match self.recv().await? {
+            Message::MemDone => {}
+            _ => return Err(MigrateError::UnexpectedMessage),
+        }
+
+        match self.recv().await? {
+            Message::Serialized(_) => {}
+            _ => return Err(MigrateError::UnexpectedMessage),
+        }
+        let com1_history = self.state.com1_history.clone();
+        self.send(Message::Serialized(com1_history)).await?;
+        self.recv_ok().await?;
+
+        self.recv_ok().await?;
+        self.send(Message::Okay).await
+    }
+
+    /// Sends the pages the destination fetches until it is done.
+    async fn serve_fetches(&mut self) -> Result<(), MigrateError> {
+        loop {
+            let (start, end, bits) = match self.recv().await? {
+                Message::MemFetch(start, end, bits) => (start, end, bits),
+                Message::MemDone => return Ok(()),
+                _ => return Err(MigrateError::UnexpectedMessage),
+            };
+            if !memx::validate_bitmap(start, end, &bits) {
+                return Err(MigrateError::Phase);
+            }
+            self.send(memx::make_mem_xfer(start, end, &bits)).await?;
+            for addr in PageIter::new(start, end, &bits) {
+                let offset = *self
+                    .state
+                    .pages
+                    .get(&addr)
+                    .ok_or(MigrateError::UnexpectedMessage)?;
+                let mut page = vec![0; PAGE_SIZE];
+                self.file
+                    .seek(SeekFrom::Start(offset))
+                    .await
+                    .map_err(io_error)?;
+                self.file.read_exact(&mut page).await.map_err(io_error)?;
+                self.send(Message::Page(page)).await?;
+            }
+        }
+    }
+
+    async fn recv_mem_query(&mut self) -> Result<(u64, u64), MigrateError> {
+        match self.recv().await? {
+            Message::MemQuery(start, end) => Ok((start, end)),
+            _ => Err(MigrateError::UnexpectedMessage),
+        }
+    }
+
+    async fn send(&mut self, m: Message) -> Result<(), MigrateError> {
+        send(&mut self.conn, m).await
+    }
+
+    async fn recv(&mut self) -> Result<Message, MigrateError> {
+        recv(&mut self.conn, MigrateRole::Destination).await
+    }
+
+    async fn recv_ok(&mut self) -> Result<(), MigrateError> {
+        recv_ok(&mut self.conn, MigrateRole::Destination).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_frames(messages: Vec<Message>) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        for m in messages {
+            let frame = encode(m).unwrap();
+            file.extend_from_slice(&(frame.len() as u64).to_le_bytes());
+            file.extend_from_slice(&frame);
+        }
+        file
+    }
+
+    #[tokio::test]
+    async fn load_saved_state() {
+        let page = |b: u8| Message::Page(vec![b; PAGE_SIZE]);
+        let file = write_frames(vec![
+            Message::Serialized("preamble".to_string()),
+            memx::make_mem_xfer(0, 4 * PAGE_SIZE as u64, &[0b1011]),
+            page(1),
+            Message::ZeroPage,
+            page(4),
+            // The page at 0x3000 is fetched again after it is dirtied.
+            memx::make_mem_xfer(
+                3 * PAGE_SIZE as u64,
+                4 * PAGE_SIZE as u64,
+                &[1],
+            ),
+            Message::ZeroPage,
+            Message::Serialized("time".to_string()),
+            Message::Serialized("devices".to_string()),
+            Message::Serialized("com1".to_string()),
+        ]);
+
+        let state = SavedState::load(&mut file.as_slice()).await.unwrap();
+        assert_eq!(state.preamble, "preamble");
+        assert_eq!(state.time_data, "time");
+        assert_eq!(state.devices, "devices");
+        assert_eq!(state.com1_history, "com1");
+        assert_eq!(state.pages.keys().copied().collect::<Vec<_>>(), [0]);
+
+        // The page's contents start right after its length.
+        let offset = *state.pages.get(&0).unwrap() as usize;
+        assert_eq!(
+            file[offset - 8..offset],
+            (PAGE_SIZE as u64 + 1).to_le_bytes()
+        );
+        assert_eq!(file[offset..offset + PAGE_SIZE], [1; PAGE_SIZE]);
+
+        let offers = state.offers();
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].0, 0);
+        assert_eq!(offers[0].2[0], 1);
+        assert!(memx::validate_bitmap(offers[0].0, offers[0].1, &offers[0].2));
+    }
+
+    #[tokio::test]
+    async fn load_rejects_truncated_state() {
+        let file = write_frames(vec![
+            Message::Serialized("preamble".to_string()),
+            memx::make_mem_xfer(0, 2 * PAGE_SIZE as u64, &[0b11]),
+            Message::ZeroPage,
+        ]);
+        assert!(SavedState::load(&mut file.as_slice()).await.is_err());
+        assert!(SavedState::load(&mut &b"PROPSAV0"[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn state_written_beside_target_and_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("saved");
+        let temp = temp_path(&path, Uuid::new_v4());
+        assert_eq!(temp.parent(), path.parent());
+        assert_ne!(temp, path);
+
+        create_private(&temp).await.unwrap();
+        let mode = std::fs::metadata(&temp).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // An existing file is never reused.
+        assert!(create_private(&temp).await.is_err());
+    }
+}
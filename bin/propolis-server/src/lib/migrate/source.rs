@@ -6,28 +6,39 @@ use bitvec::prelude::{BitSlice, Lsb0};
 use futures::{SinkExt, StreamExt};
 use propolis::common::{GuestAddr, PAGE_SIZE};
 use propolis::migrate::{
-    MigrateCtx, MigrateStateError, Migrator, PayloadOutputs,
+    MigrateCtx, MigrateStateError, Migrator, PayloadOutputs, PayloadSchemas,
 };
 use propolis::vmm;
-use slog::{debug, error, info, trace};
+use propolis_api_types as api;
+use propolis_api_types::instance_spec::components::devices::SerialPortNumber;
+use slog::{debug, error, info, trace, warn};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io;
 use std::ops::{Range, RangeInclusive};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
 use tokio_tungstenite::WebSocketStream;
+use tokio_util::sync::CancellationToken;
 
 use crate::migrate::codec;
 use crate::migrate::codec::Message;
+use crate::migrate::compress::PageCompression;
 use crate::migrate::memx;
-use crate::migrate::preamble::Preamble;
+use crate::migrate::payloads;
+use crate::migrate::preamble::{Preamble, PreambleReply};
 use crate::migrate::probes;
+use crate::migrate::progress::MigrationProgress;
 use crate::migrate::protocol::Protocol;
+use crate::migrate::throttle::{BandwidthLimit, Throttle};
+use crate::migrate::tls;
 use crate::migrate::{
     Device, DevicePayload, MigrateError, MigratePhase, MigrateRole,
     MigrationState, PageIter,
 };
+use crate::vcpu_tasks::MAX_VCPU_THROTTLE_PCT;
 use crate::vm::{MigrateSourceCommand, MigrateSourceResponse, VmController};
 
 /// Specifies which pages should be offered during a RAM transfer phase.
@@ -109,23 +120,53 @@ enum RamOfferDiscipline {
     OfferDirty,
 }
 
+/// An additional connection opened by the destination to fetch guest memory
+/// concurrently with the main migration connection.
+pub(crate) type RamStream = WebSocketStream<tls::SourceStream>;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn migrate<T: AsyncRead + AsyncWrite + Unpin + Send>(
     vm_controller: Arc<VmController>,
     command_tx: tokio::sync::mpsc::Sender<MigrateSourceCommand>,
     response_rx: tokio::sync::mpsc::Receiver<MigrateSourceResponse>,
     conn: WebSocketStream<T>,
     protocol: super::protocol::Protocol,
+    progress: Arc<MigrationProgress>,
+    cancel: Arc<CancelHandle>,
+    bandwidth: Arc<BandwidthLimit>,
+    ram_streams: tokio::sync::mpsc::Receiver<RamStream>,
 ) -> Result<(), MigrateError> {
     let err_tx = command_tx.clone();
     let mut proto = match protocol {
-        Protocol::RonV0 => {
-            SourceProtocol::new(vm_controller, command_tx, response_rx, conn)
-        }
+        Protocol::RonV0 => SourceProtocol::new(
+            vm_controller,
+            command_tx,
+            response_rx,
+            conn,
+            progress,
+            cancel,
+            bandwidth,
+            ram_streams,
+        ),
     };
 
-    if let Err(err) = proto.run().await {
+    let res = proto.run().await;
+
+    // Whatever the outcome, no more memory will be fetched over any
+    // additional streams.
+    if let Some(task) = proto.ram_stream_task.take() {
+        task.abort();
+    }
+
+    // Nor is there any further need to slow the guest.
+    if let Some(task) = proto.vcpu_kick_task.take() {
+        task.abort();
+    }
+    proto.vm_controller.vcpu_throttle().set(0);
+
+    if let Err(err) = res {
         err_tx
-            .send(MigrateSourceCommand::UpdateState(MigrationState::Error))
+            .send(MigrateSourceCommand::Failed(err.to_failure(proto.state)))
             .await
             .unwrap();
 
@@ -193,6 +234,48 @@ pub(crate) struct PersistentState {
     has_redirtying_ever_failed: bool,
 }
 
+/// Allows an outbound migration to be cancelled up until the point at which
+/// control of the guest is handed to the destination.
+#[derive(Default)]
+pub(crate) struct CancelHandle {
+    /// Set once the migration has passed the point at which it can be
+    /// cancelled.
+    committed: Mutex<bool>,
+    token: CancellationToken,
+}
+
+impl CancelHandle {
+    /// Requests that the migration be cancelled. Returns `false` if it is too
+    /// late to do so.
+    pub fn cancel(&self) -> bool {
+        let committed = self.committed.lock().unwrap();
+        if *committed {
+            return false;
+        }
+        self.token.cancel();
+        true
+    }
+
+    /// Marks the migration as no longer cancellable. Returns `false` if it
+    /// has already been cancelled.
+    fn commit(&self) -> bool {
+        let mut committed = self.committed.lock().unwrap();
+        if self.token.is_cancelled() {
+            return false;
+        }
+        *committed = true;
+        true
+    }
+
+    fn check(&self) -> Result<(), MigrateError> {
+        if self.token.is_cancelled() {
+            Err(MigrateError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 struct SourceProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
     /// The VM controller for the instance of interest.
     vm_controller: Arc<VmController>,
@@ -208,6 +291,55 @@ struct SourceProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
     /// Transport to the destination Instance.
     conn: WebSocketStream<T>,
 
+    /// The migration's externally-visible state, as last reported to the
+    /// state worker.
+    state: MigrationState,
+
+    /// The progress of this migration's RAM transfer, reported through the
+    /// API.
+    progress: Arc<MigrationProgress>,
+
+    /// Set when the migration is cancelled through the API.
+    cancel: Arc<CancelHandle>,
+
+    /// Paces the transfer of guest memory to the instance's migration
+    /// bandwidth limit. This is shared by all the streams sending memory.
+    throttle: Arc<tokio::sync::Mutex<Throttle>>,
+
+    /// The algorithm the destination selected to compress pages of guest
+    /// memory, if any.
+    compression: Option<PageCompression>,
+
+    /// Set if the destination accepts all-zero pages as markers.
+    zero_pages: bool,
+
+    /// Additional connections opened by the destination to fetch guest memory
+    /// in parallel, handed over by the API as they arrive.
+    ram_streams: Option<tokio::sync::mpsc::Receiver<RamStream>>,
+
+    /// The task serving fetches made over the additional RAM streams.
+    ram_stream_task: Option<JoinHandle<()>>,
+
+    /// The limits within which the destination asked for the guest's vCPUs
+    /// to be throttled while RAM is pushed in rounds, if it did so.
+    auto_converge: Option<api::MigrationAutoConverge>,
+
+    /// The longest the destination asked for the guest to be paused while the
+    /// last of its memory is sent, if it did so.
+    max_downtime: Option<Duration>,
+
+    /// The number of bytes of dirty page bitmap read from the hypervisor, and
+    /// offered to the destination, at a time.
+    bitmap_size: usize,
+
+    /// The least time the destination asked for between the starts of
+    /// successive rounds of RAM pushed before the guest is paused, if it did
+    /// so.
+    scan_interval: Option<Duration>,
+
+    /// The task regularly forcing throttled vCPUs to exit.
+    vcpu_kick_task: Option<JoinHandle<()>>,
+
     /// Guest page table dirty bits to restore in the event of a migration
     /// failure, so that a subsequent migration can attempt to offer only dirty
     /// pages. These dirty bits are accumulated across all RAM push phases, so
@@ -227,18 +359,39 @@ struct SourceProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
     ///
     /// Otherwise, we must fall back to always offering all pages in the initial
     /// pre-pause RAM push phase.
-    dirt: Option<HashMap<GuestAddr, PageBitmap>>,
+    dirt: Option<HashMap<GuestAddr, Vec<u8>>>,
 }
 
-const PAGE_BITMAP_SIZE: usize = 4096;
-type PageBitmap = [u8; PAGE_BITMAP_SIZE];
+/// The number of bytes of dirty page bitmap read at a time, if the migration
+/// request doesn't say.
+const DEFAULT_PAGE_BITMAP_SIZE: usize = 4096;
+
+/// The most bytes of dirty page bitmap read at a time, whatever the migration
+/// request says.
+const MAX_PAGE_BITMAP_SIZE: usize = 65536;
+
+/// The longest the guest may be paused while the last of its memory is sent,
+/// if the migration request doesn't say.
+const DEFAULT_MAX_DOWNTIME: Duration = Duration::from_millis(300);
+
+/// The most rounds of RAM pushed before pausing the guest, if the migration
+/// request doesn't say.
+const DEFAULT_MAX_PRECOPY_ROUNDS: u32 = 16;
+
+/// How often throttled vCPUs are forced to exit.
+const VCPU_KICK_INTERVAL: Duration = Duration::from_millis(10);
 
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         vm_controller: Arc<VmController>,
         command_tx: tokio::sync::mpsc::Sender<MigrateSourceCommand>,
         response_rx: tokio::sync::mpsc::Receiver<MigrateSourceResponse>,
         conn: WebSocketStream<T>,
+        progress: Arc<MigrationProgress>,
+        cancel: Arc<CancelHandle>,
+        bandwidth: Arc<BandwidthLimit>,
+        ram_streams: tokio::sync::mpsc::Receiver<RamStream>,
     ) -> Self {
         let dirt = {
             let can_npt_operate = vm_controller.machine().hdl.can_npt_operate();
@@ -256,7 +409,28 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                 None
             }
         };
-        Self { vm_controller, command_tx, response_rx, conn, dirt }
+        Self {
+            vm_controller,
+            command_tx,
+            response_rx,
+            conn,
+            state: MigrationState::Sync,
+            progress,
+            cancel,
+            throttle: Arc::new(tokio::sync::Mutex::new(Throttle::new(
+                bandwidth,
+            ))),
+            compression: None,
+            zero_pages: false,
+            ram_streams: Some(ram_streams),
+            ram_stream_task: None,
+            auto_converge: None,
+            max_downtime: None,
+            bitmap_size: DEFAULT_PAGE_BITMAP_SIZE,
+            scan_interval: None,
+            vcpu_kick_task: None,
+            dirt,
+        }
     }
 
     fn log(&self) -> &slog::Logger {
@@ -267,6 +441,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         // When migrating into an instance, the VM state worker blocks waiting
         // for the disposition of the migration attempt, so the channel should
         // never be closed before the attempt completes.
+        self.state = state;
         self.command_tx
             .send(MigrateSourceCommand::UpdateState(state))
             .await
@@ -277,7 +452,17 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         &mut self,
         step: MigratePhase,
     ) -> Result<(), MigrateError> {
+        // Once the final phase begins, the destination may take control of
+        // the guest at any moment, so the migration can't be cancelled.
+        if let MigratePhase::Finish = step {
+            if !self.cancel.commit() {
+                return Err(MigrateError::Cancelled);
+            }
+        }
+        self.cancel.check()?;
+
         probes::migrate_phase_begin!(|| { step.to_string() });
+        let started = Instant::now();
 
         let res = match step {
             MigratePhase::MigrateSync => self.sync().await,
@@ -293,6 +478,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         };
 
         probes::migrate_phase_end!(|| { step.to_string() });
+        self.vm_controller.migration_stats().phase_finished(
+            MigrateRole::Source,
+            &step.to_string(),
+            started.elapsed(),
+        );
 
         res
     }
@@ -316,13 +506,57 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
 
     async fn sync(&mut self) -> Result<(), MigrateError> {
         self.update_state(MigrationState::Sync).await;
-        let preamble =
-            Preamble::new(self.vm_controller.instance_spec().await.clone());
+        let offered = self.vm_controller.migration_page_compression().to_vec();
+        let preamble = Preamble::new(
+            self.vm_controller.instance_spec().await.clone(),
+            &offered,
+            self.vm_controller.max_migration_ram_streams(),
+            super::device_payloads(&self.vm_controller),
+        );
         let s = ron::ser::to_string(&preamble)
             .map_err(codec::ProtocolError::from)?;
         self.send_msg(codec::Message::Serialized(s)).await?;
 
-        self.read_ok().await
+        // A destination that uses any of the optional features offered in the
+        // preamble names them; otherwise, including if the destination
+        // predates them, it just acknowledges the preamble.
+        let reply = match self.read_msg().await? {
+            codec::Message::Okay => PreambleReply::default(),
+            codec::Message::Serialized(s) => {
+                ron::de::from_str(&s).map_err(codec::ProtocolError::from)?
+            }
+            msg => {
+                error!(self.log(), "expected `Okay` but received: {msg:?}");
+                return Err(MigrateError::UnexpectedMessage);
+            }
+        };
+        info!(self.log(), "Source read preamble reply: {:?}", reply);
+
+        if let Some(name) = reply.page_compression {
+            let selected = name
+                .parse::<PageCompression>()
+                .ok()
+                .filter(|c| offered.contains(c));
+            let Some(compression) = selected else {
+                error!(
+                    self.log(),
+                    "destination selected unoffered page compression: \
+                     {name:?}"
+                );
+                return Err(MigrateError::UnexpectedMessage);
+            };
+            self.compression = Some(compression);
+        }
+        self.zero_pages = reply.zero_pages;
+        self.auto_converge = reply.auto_converge;
+        self.max_downtime = reply.max_downtime_ms.map(Duration::from_millis);
+        if let Some(tracking) = reply.dirty_tracking {
+            self.bitmap_size = page_bitmap_size(tracking.bitmap_pages);
+            self.scan_interval =
+                Some(Duration::from_millis(tracking.scan_interval_ms))
+                    .filter(|interval| !interval.is_zero());
+        }
+        Ok(())
     }
 
     async fn ram_push(
@@ -338,16 +572,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
             }
             _ => unreachable!("should only push RAM in a RAM push phase"),
         }
-
-        let vmm_ram_range = self.vmm_ram_bounds().await?;
-        let req_ram_range = self.read_mem_query().await?;
-        info!(
-            self.log(),
-            "ram_push ({:?}): got query for range {:#x?}, vm range {:#x?}",
-            phase,
-            req_ram_range,
-            vmm_ram_range
-        );
+        self.serve_ram_streams();
 
         // Determine whether we can offer only dirty pages, or if we must offer
         // all pages.
@@ -366,7 +591,184 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
             // need only offer pages that have their dirty bit set.
             _ => RamOfferDiscipline::OfferDirty,
         };
-        self.offer_ram(vmm_ram_range, req_ram_range, offer_discipline).await?;
+        let precopy =
+            self.auto_converge.is_some() || self.max_downtime.is_some();
+        match phase {
+            MigratePhase::RamPushPrePause if precopy => {
+                self.ram_precopy(phase, offer_discipline).await?;
+            }
+            _ => {
+                self.ram_round(phase, offer_discipline).await?;
+            }
+        }
+        info!(self.log(), "ram_push: done sending ram");
+        self.update_state(MigrationState::Pause).await;
+        Ok(())
+    }
+
+    /// Pushes RAM in rounds while the guest runs, each offering the pages
+    /// dirtied while the last was sent, until the pages left dirty can be
+    /// sent within the downtime budget once the guest is paused.
+    ///
+    /// If auto-convergence was requested and the guest dirties memory at
+    /// least as fast as it is sent for `trigger_rounds` rounds in a row, its
+    /// vCPUs are throttled, more heavily each time this recurs, up to
+    /// `max_throttle_pct`.
+    async fn ram_precopy(
+        &mut self,
+        phase: &MigratePhase,
+        mut offer_discipline: RamOfferDiscipline,
+    ) -> Result<(), MigrateError> {
+        let limits = self.auto_converge.clone();
+        let max_rounds = limits
+            .as_ref()
+            .map_or(DEFAULT_MAX_PRECOPY_ROUNDS, |l| l.max_rounds);
+        let max_downtime = self.max_downtime.unwrap_or(DEFAULT_MAX_DOWNTIME);
+        let mut rounds = 0;
+        let mut pages_sent = 0;
+        let mut time_sending = Duration::ZERO;
+        let mut last_round: Option<(Instant, u64)> = None;
+        let mut stalled = 0;
+        loop {
+            // Waiting between scans lets the pages the guest rewrites often
+            // be sent once rather than in every round.
+            if let (Some(interval), Some((last_started, _))) =
+                (self.scan_interval, last_round)
+            {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(
+                        (last_started + interval).into()
+                    ) => {}
+                    _ = self.cancel.token.cancelled() => {
+                        return Err(MigrateError::Cancelled);
+                    }
+                }
+            }
+            let started = Instant::now();
+            let offered = self.ram_round(phase, offer_discipline).await?;
+            offer_discipline = RamOfferDiscipline::OfferDirty;
+            rounds += 1;
+
+            // The destination stops asking for rounds once one offers
+            // nothing.
+            if offered == 0 {
+                return Ok(());
+            }
+            let elapsed = started.elapsed();
+            pages_sent += offered;
+            time_sending += elapsed;
+
+            // This round offered the pages dirtied since the last began,
+            // which gives the rate at which the guest dirties memory. From
+            // that, estimate how many pages were dirtied while this round was
+            // sent, and how long they would take to send at the rate achieved
+            // so far.
+            let downtime = last_round.map(|(last_started, _)| {
+                let dirtied = offered as f64 * elapsed.as_secs_f64()
+                    / (started - last_started).as_secs_f64().max(f64::EPSILON);
+                Duration::try_from_secs_f64(
+                    time_sending.as_secs_f64() * dirtied / pages_sent as f64,
+                )
+                .unwrap_or(Duration::MAX)
+            });
+            if downtime.is_some_and(|d| d <= max_downtime)
+                || rounds >= max_rounds
+            {
+                info!(self.log(), "ram_push: ending pre-copy";
+                      "rounds" => rounds,
+                      "last_round_pages" => offered,
+                      "estimated_downtime" => ?downtime);
+                break;
+            }
+            let last_offered = last_round.map(|(_, pages)| pages);
+            last_round = Some((started, offered));
+            let Some(limits) = &limits else {
+                continue;
+            };
+
+            // Each round offers the pages dirtied while the last was sent, so
+            // a round no smaller than the last means that the guest dirtied
+            // memory at least as fast as it was sent.
+            if last_offered.is_some_and(|last| offered >= last) {
+                stalled += 1;
+            } else {
+                stalled = 0;
+            }
+            if stalled >= limits.trigger_rounds.max(1) {
+                self.raise_vcpu_throttle(limits);
+                stalled = 0;
+            }
+        }
+
+        // End the phase with a round that offers nothing. Whatever remains
+        // dirty is sent once the guest is paused.
+        let req_ram_range = self.read_mem_query().await?;
+        self.send_msg(codec::Message::MemEnd(
+            req_ram_range.start,
+            req_ram_range.end,
+        ))
+        .await?;
+        match self.read_msg().await? {
+            codec::Message::MemDone => Ok(()),
+            _ => Err(MigrateError::UnexpectedMessage),
+        }
+    }
+
+    /// Throttles the guest's vCPUs one step further within `limits`.
+    fn raise_vcpu_throttle(&mut self, limits: &api::MigrationAutoConverge) {
+        let throttle = self.vm_controller.vcpu_throttle();
+        let current = throttle.get();
+        let next = if current == 0 {
+            limits.initial_throttle_pct
+        } else {
+            current.saturating_add(limits.throttle_step_pct)
+        }
+        .min(limits.max_throttle_pct)
+        .min(MAX_VCPU_THROTTLE_PCT);
+        if next <= current {
+            return;
+        }
+        info!(self.log(), "Throttling vCPUs"; "percent" => next);
+        throttle.set(next);
+
+        // A vCPU is only held to the throttle when it exits, so make sure
+        // that happens regularly even if the guest rarely causes an exit
+        // itself.
+        if self.vcpu_kick_task.is_none() {
+            let vm_controller = self.vm_controller.clone();
+            self.vcpu_kick_task = Some(tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(VCPU_KICK_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    for vcpu in vm_controller.machine().vcpus.iter() {
+                        let _ = vcpu.barrier();
+                    }
+                }
+            }));
+        }
+    }
+
+    /// Runs a single round of RAM transfer, returning the number of pages
+    /// offered.
+    async fn ram_round(
+        &mut self,
+        phase: &MigratePhase,
+        offer_discipline: RamOfferDiscipline,
+    ) -> Result<u64, MigrateError> {
+        self.progress.begin_round();
+
+        let vmm_ram_range = self.vmm_ram_bounds().await?;
+        let req_ram_range = self.read_mem_query().await?;
+        info!(
+            self.log(),
+            "ram_push ({:?}): got query for range {:#x?}, vm range {:#x?}",
+            phase,
+            req_ram_range,
+            vmm_ram_range
+        );
+        let offered = self
+            .offer_ram(vmm_ram_range, req_ram_range, offer_discipline)
+            .await?;
 
         loop {
             let m = self.read_msg().await?;
@@ -402,9 +804,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                 _ => return Err(MigrateError::UnexpectedMessage),
             };
         }
-        info!(self.log(), "ram_push: done sending ram");
-        self.update_state(MigrationState::Pause).await;
-        Ok(())
+        self.progress.end_round();
+        Ok(offered)
     }
 
     async fn offer_ram(
@@ -412,7 +813,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         vmm_ram_range: RangeInclusive<GuestAddr>,
         req_ram_range: Range<u64>,
         offer_discipline: RamOfferDiscipline,
-    ) -> Result<(), MigrateError> {
+    ) -> Result<u64, MigrateError> {
         info!(
             self.log(),
             "offering ram";
@@ -421,7 +822,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         );
         let vmm_ram_start = *vmm_ram_range.start();
         let vmm_ram_end = *vmm_ram_range.end();
-        let mut bits = [0u8; PAGE_BITMAP_SIZE];
+        let mut bits = vec![0u8; self.bitmap_size];
         let req_start_gpa = req_ram_range.start;
         let req_end_gpa = req_ram_range.end;
         let start_gpa = req_start_gpa.max(vmm_ram_start.0);
@@ -440,6 +841,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         let end_gpa = end_gpa + 1;
 
         let step = bits.len() * 8 * PAGE_SIZE;
+        let mut total_offered = 0;
         for gpa in (start_gpa..end_gpa).step_by(step) {
             let mut pages_offered = 0;
             // Always capture the dirty page mask even if the offer discipline
@@ -453,7 +855,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                     for byte in bits.iter_mut() {
                         *byte = 0xff;
                     }
-                    pages_offered = PAGE_BITMAP_SIZE * 8;
+                    pages_offered = bits.len() * 8;
                 }
                 RamOfferDiscipline::OfferDirty => {
                     let bits = BitSlice::<_, Lsb0>::from_slice(&bits);
@@ -477,7 +879,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                     if let Some(ref mut dirt) = self.dirt {
                         let saved = dirt
                             .entry(GuestAddr(gpa))
-                            .or_insert_with(|| [0u8; PAGE_BITMAP_SIZE]);
+                            .or_insert_with(|| vec![0u8; self.bitmap_size]);
                         let saved = BitSlice::<_, Lsb0>::from_slice_mut(saved);
                         *saved |= bits;
                     }
@@ -490,10 +892,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                 "ram_push: offering {pages_offered} pages between {gpa:#x} and {end:#x}"
             );
             if pages_offered > 0 {
+                let pages_in_range = (end - gpa) as usize / PAGE_SIZE;
+                let pages = pages_offered.min(pages_in_range) as u64;
+                self.progress.pages_offered(pages);
+                total_offered += pages;
                 self.send_msg(memx::make_mem_offer(gpa, end, &bits)).await?;
             }
         }
-        self.send_msg(codec::Message::MemEnd(req_start_gpa, req_end_gpa)).await
+        self.send_msg(codec::Message::MemEnd(req_start_gpa, req_end_gpa))
+            .await?;
+        Ok(total_offered)
     }
 
     async fn xfer_ram(
@@ -503,14 +911,45 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         bits: &[u8],
     ) -> Result<(), MigrateError> {
         info!(self.log(), "ram_push: xfer RAM between {start:#x} and {end:#x}",);
-        self.send_msg(memx::make_mem_xfer(start, end, bits)).await?;
-        for addr in PageIter::new(start, end, bits) {
-            let mut bytes = [0u8; PAGE_SIZE];
-            self.read_guest_mem(GuestAddr(addr), &mut bytes).await?;
-            self.send_msg(codec::Message::Page(bytes.into())).await?;
-            probes::migrate_xfer_ram_page!(|| (addr, PAGE_SIZE as u64));
+        self.page_sender().send(&mut self.conn, start, end, bits).await
+    }
+
+    fn page_sender(&self) -> PageSender {
+        PageSender {
+            vm_controller: self.vm_controller.clone(),
+            progress: self.progress.clone(),
+            cancel: self.cancel.clone(),
+            throttle: self.throttle.clone(),
+            compression: self.compression,
+            zero_pages: self.zero_pages,
         }
-        Ok(())
+    }
+
+    /// Starts serving fetches made over the destination's additional RAM
+    /// streams, including any it opens later. This happens once the
+    /// preamble has been exchanged, so that the compression the streams use
+    /// is known.
+    fn serve_ram_streams(&mut self) {
+        let Some(mut streams) = self.ram_streams.take() else {
+            return;
+        };
+        let sender = self.page_sender();
+        let log = self.log().clone();
+        self.ram_stream_task = Some(tokio::spawn(async move {
+            // Dropping the set when this task is aborted stops its workers.
+            let mut workers = tokio::task::JoinSet::new();
+            while let Some(conn) = streams.recv().await {
+                info!(log, "Serving RAM fetches over additional stream");
+                let sender = sender.clone();
+                let log = log.clone();
+                workers.spawn(async move {
+                    if let Err(e) = sender.serve(conn).await {
+                        error!(log, "RAM stream failed: {e}");
+                    }
+                });
+            }
+            while workers.join_next().await.is_some() {}
+        }));
     }
 
     async fn pause(&mut self) -> Result<(), MigrateError> {
@@ -546,7 +985,9 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                     instance_name: name.to_string(),
                     payload: Vec::new(),
                 };
-                match devop.migrate() {
+                let migrator = devop.migrate();
+                let declared = migrator.schemas().unwrap_or_default();
+                match migrator {
                     Migrator::NonMigratable => {
                         error!(self.log(),
                             "Can't migrate instance with non-migratable device ({})",
@@ -564,6 +1005,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                             data: ron::ser::to_string(&out.payload)
                                 .map_err(codec::ProtocolError::from)?,
                         });
+                        self.check_declared(&declared, &dev);
                         device_states.push(dev);
                     }
                     Migrator::Multi(mech) => {
@@ -578,6 +1020,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                                     .map_err(codec::ProtocolError::from)?,
                             });
                         }
+                        self.check_declared(&declared, &dev);
                         device_states.push(dev);
                     }
                 }
@@ -587,6 +1030,12 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
 
         info!(self.log(), "Device States: {device_states:#?}");
 
+        if let Some(dir) = &self.vm_controller.migration_payloads().emit {
+            payloads::emit(dir, &device_states)?;
+            info!(self.log(), "Wrote device payloads";
+                  "dir" => %dir.display());
+        }
+
         self.send_msg(codec::Message::Serialized(
             ron::ser::to_string(&device_states)
                 .map_err(codec::ProtocolError::from)?,
@@ -597,6 +1046,21 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         self.read_ok().await
     }
 
+    /// Warns of any payload exported by a device which it didn't declare in
+    /// its schemas, and so wasn't listed in the preamble.
+    fn check_declared(&self, declared: &PayloadSchemas, dev: &Device) {
+        for part in &dev.payload {
+            if !declared.exports().any(|(kind, version)| {
+                kind == part.kind && version == part.version
+            }) {
+                warn!(self.log(), "device exported undeclared payload";
+                      "device" => &dev.instance_name,
+                      "kind" => &part.kind,
+                      "version" => part.version);
+            }
+        }
+    }
+
     // Read and send over the time data
     async fn time_data(&mut self) -> Result<(), MigrateError> {
         let vmm_hdl = &self.vm_controller.machine().hdl.clone();
@@ -639,6 +1103,15 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         };
         let com1_history =
             self.vm_controller.com1().export_history(remote_addr).await?;
+
+        // Only COM1's history is carried to the destination. Clients of the
+        // other ports are still told where to reconnect, but will find only
+        // the output produced after the migration there.
+        for (port, serial) in self.vm_controller.serial_ports() {
+            if *port != SerialPortNumber::Com1 {
+                serial.notify_migration(remote_addr).await?;
+            }
+        }
         self.send_msg(codec::Message::Serialized(com1_history)).await?;
         self.read_ok().await
     }
@@ -668,7 +1141,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         // is not permitted because that will cause migration to unwind and the
         // VM to resume, which is forbidden at this point (see above).
         let vmm_range = self.vmm_ram_bounds().await.unwrap();
-        let mut bits = [0u8; PAGE_BITMAP_SIZE];
+        let mut bits = vec![0u8; self.bitmap_size];
         let step = bits.len() * 8 * PAGE_SIZE;
         for gpa in (vmm_range.start().0..vmm_range.end().0).step_by(step) {
             self.track_dirty(GuestAddr(gpa), &mut bits).unwrap();
@@ -687,28 +1160,32 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
     }
 
     async fn read_msg(&mut self) -> Result<codec::Message, MigrateError> {
-        self.conn
-            .next()
-            .await
-            .ok_or_else(|| {
-                codec::ProtocolError::Io(io::Error::from(
-                    io::ErrorKind::BrokenPipe,
+        // Don't wait indefinitely on a destination that may be stuck if the
+        // migration is cancelled in the meantime. Reading from the stream is
+        // cancel-safe, so no message is lost if cancellation wins.
+        let next = tokio::select! {
+            next = self.conn.next() => next,
+            _ = self.cancel.token.cancelled() => {
+                return Err(MigrateError::Cancelled);
+            }
+        };
+        next.ok_or_else(|| {
+            codec::ProtocolError::Io(io::Error::from(io::ErrorKind::BrokenPipe))
+        })?
+        .map_err(codec::ProtocolError::WebsocketError)
+        // convert tungstenite::Message to codec::Message
+        .and_then(std::convert::TryInto::try_into)
+        // If this is an error message, lift that out
+        .map(|msg| match msg {
+            codec::Message::Error(err) => {
+                error!(self.log(), "remote error: {err}");
+                Err(MigrateError::RemoteError(
+                    MigrateRole::Destination,
+                    err.to_string(),
                 ))
-            })?
-            .map_err(codec::ProtocolError::WebsocketError)
-            // convert tungstenite::Message to codec::Message
-            .and_then(std::convert::TryInto::try_into)
-            // If this is an error message, lift that out
-            .map(|msg| match msg {
-                codec::Message::Error(err) => {
-                    error!(self.log(), "remote error: {err}");
-                    Err(MigrateError::RemoteError(
-                        MigrateRole::Destination,
-                        err.to_string(),
-                    ))
-                }
-                msg => Ok(msg),
-            })?
+            }
+            msg => Ok(msg),
+        })?
     }
 
     async fn read_ok(&mut self) -> Result<(), MigrateError> {
@@ -764,16 +1241,91 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
             .track_dirty_pages(start_gpa.0, bits)
             .map_err(|_| MigrateError::InvalidInstanceState)
     }
+}
 
-    async fn read_guest_mem(
-        &mut self,
-        addr: GuestAddr,
-        buf: &mut [u8],
+/// Returns the size, in bytes, of a dirty page bitmap covering `pages` pages,
+/// within the limits this source allows.
+fn page_bitmap_size(pages: u32) -> usize {
+    (pages as usize).div_ceil(8).clamp(1, MAX_PAGE_BITMAP_SIZE)
+}
+
+/// Sends pages of guest memory fetched by the destination, over either the
+/// main migration connection or one of its additional RAM streams.
+#[derive(Clone)]
+struct PageSender {
+    vm_controller: Arc<VmController>,
+    progress: Arc<MigrationProgress>,
+    cancel: Arc<CancelHandle>,
+    throttle: Arc<tokio::sync::Mutex<Throttle>>,
+    compression: Option<PageCompression>,
+    zero_pages: bool,
+}
+
+impl PageSender {
+    /// Sends the pages between `start` and `end` selected by `bits`.
+    async fn send<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        conn: &mut WebSocketStream<S>,
+        start: u64,
+        end: u64,
+        bits: &[u8],
     ) -> Result<(), MigrateError> {
+        conn.send(memx::make_mem_xfer(start, end, bits).try_into()?).await?;
+        for addr in PageIter::new(start, end, bits) {
+            self.cancel.check()?;
+            let mut bytes = [0u8; PAGE_SIZE];
+            self.read_guest_mem(GuestAddr(addr), &mut bytes);
+            // All-zero pages are sent as markers, and pages that don't shrink
+            // when compressed are sent as they are.
+            let (msg, len) = if self.zero_pages && is_zero(&bytes) {
+                (codec::Message::ZeroPage, 0)
+            } else {
+                match self.compression.map(|c| c.compress(&bytes)) {
+                    Some(data) if data.len() < PAGE_SIZE => {
+                        let len = data.len();
+                        (codec::Message::CompressedPage(data), len)
+                    }
+                    _ => (codec::Message::Page(bytes.into()), PAGE_SIZE),
+                }
+            };
+            self.throttle.lock().await.wait(len as u64).await;
+            conn.send(msg.try_into()?).await?;
+            self.progress.page_transferred();
+            probes::migrate_xfer_ram_page!(|| (addr, PAGE_SIZE as u64));
+        }
+        Ok(())
+    }
+
+    /// Serves the fetches the destination makes over an additional RAM
+    /// stream until it closes the stream.
+    async fn serve(&self, mut conn: RamStream) -> Result<(), MigrateError> {
+        while let Some(msg) = conn.next().await {
+            let msg: codec::Message = msg
+                .map_err(codec::ProtocolError::WebsocketError)?
+                .try_into()?;
+            match msg {
+                codec::Message::MemFetch(start, end, bits) => {
+                    if !memx::validate_bitmap(start, end, &bits) {
+                        return Err(MigrateError::Phase);
+                    }
+                    self.send(&mut conn, start, end, &bits).await?;
+                }
+                _ => return Err(MigrateError::UnexpectedMessage),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_guest_mem(&self, addr: GuestAddr, buf: &mut [u8]) {
         let machine = self.vm_controller.machine();
         let memctx = machine.acc_mem.access().unwrap();
         let len = buf.len();
         memctx.direct_read_into(addr, buf, len);
-        Ok(())
     }
 }
+
+fn is_zero(page: &[u8; PAGE_SIZE]) -> bool {
+    // Comparing a word at a time is considerably faster than a byte at a time.
+    page.chunks_exact(8)
+        .all(|word| u64::from_ne_bytes(word.try_into().unwrap()) == 0)
+}
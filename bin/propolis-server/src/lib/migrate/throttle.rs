@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Limiting of the rate at which a migration source sends guest memory.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How far a migration may fall behind its limit before its pacing is
+/// restarted, so that a stall is not followed by an unthrottled burst.
+const MAX_DEFICIT: Duration = Duration::from_secs(1);
+
+/// A cap on a migration's transfer rate, shared between the API, which may
+/// change it at any time, and the migration task.
+#[derive(Default)]
+pub(crate) struct BandwidthLimit {
+    /// The limit in megabits per second, or 0 if transfers are unlimited.
+    mbps: AtomicU64,
+}
+
+impl BandwidthLimit {
+    pub fn new(mbps: Option<u64>) -> Self {
+        Self { mbps: AtomicU64::new(mbps.unwrap_or(0)) }
+    }
+
+    pub fn set(&self, mbps: Option<u64>) {
+        self.mbps.store(mbps.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        match self.mbps.load(Ordering::Relaxed) {
+            0 => None,
+            mbps => Some(mbps),
+        }
+    }
+}
+
+struct Window {
+    mbps: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+/// Paces a migration's transfers to stay within a [`BandwidthLimit`].
+pub(crate) struct Throttle {
+    limit: Arc<BandwidthLimit>,
+    window: Option<Window>,
+}
+
+impl Throttle {
+    pub fn new(limit: Arc<BandwidthLimit>) -> Self {
+        Self { limit, window: None }
+    }
+
+    /// Waits, if necessary, before `bytes` more bytes are sent.
+    pub async fn wait(&mut self, bytes: u64) {
+        if let Some(delay) = self.delay(bytes, Instant::now()) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Accounts for `bytes` about to be sent at `now`, returning how long to
+    /// wait before sending them to stay within the current limit.
+    fn delay(&mut self, bytes: u64, now: Instant) -> Option<Duration> {
+        let Some(mbps) = self.limit.get() else {
+            self.window = None;
+            return None;
+        };
+
+        let window = match &mut self.window {
+            Some(w) if w.mbps == mbps => w,
+            window => window.insert(Window { mbps, start: now, bytes: 0 }),
+        };
+        let due = window.start
+            + Duration::from_secs_f64(
+                (window.bytes * 8) as f64 / (mbps * 1_000_000) as f64,
+            );
+        if now.saturating_duration_since(due) > MAX_DEFICIT {
+            window.start = now;
+            window.bytes = bytes;
+            return None;
+        }
+        window.bytes += bytes;
+        due.checked_duration_since(now).filter(|d| !d.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paces_to_limit() {
+        let limit = Arc::new(BandwidthLimit::new(Some(8)));
+        let mut throttle = Throttle::new(limit.clone());
+        let start = Instant::now();
+
+        // At 8 Mbit/s, each 500 KB takes half a second to send.
+        assert_eq!(throttle.delay(500_000, start), None);
+        assert_eq!(
+            throttle.delay(500_000, start),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            throttle.delay(500_000, start + Duration::from_millis(500)),
+            Some(Duration::from_millis(500))
+        );
+
+        // A long stall doesn't allow a burst afterwards.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(throttle.delay(500_000, later), None);
+        assert_eq!(
+            throttle.delay(500_000, later),
+            Some(Duration::from_millis(500))
+        );
+
+        limit.set(None);
+        assert_eq!(throttle.delay(500_000, later), None);
+        assert_eq!(limit.get(), None);
+    }
+}
@@ -0,0 +1,387 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Securing a migration's connections with TLS.
+//!
+//! The source's server speaks plain HTTP, so each connection is upgraded to a
+//! WebSocket connection as usual, and the two sides then perform a TLS
+//! handshake over the upgraded connection before exchanging any WebSocket
+//! frames.  The destination asks for this by adding `tls=true` to the query
+//! string of the upgrade request, and accepts only the source certificate
+//! named in the migration request.
+//!
+//! If the source requires API tokens, the destination presents its own in
+//! each upgrade request.  If the source serves its API over TLS, the
+//! destination connects with `wss` instead, and the migration can't ask for
+//! TLS of its own.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use dropshot::WebsocketConnectionRaw;
+use propolis_api_types::MigrationTls;
+use propolis_server_config::Migration as MigrationConfig;
+use propolis_server_config::Tls as ApiTlsConfig;
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use tokio_util::either::Either;
+
+use crate::migrate::MigrateError;
+use crate::server::DropshotEndpointContext;
+
+/// The longest response to an upgrade request the destination will read.
+const MAX_UPGRADE_RESPONSE: usize = 4096;
+
+/// A connection from the destination to the source, as seen by the source.
+pub(crate) type SourceStream = Either<
+    WebsocketConnectionRaw,
+    tokio_rustls::server::TlsStream<WebsocketConnectionRaw>,
+>;
+
+fn tls_error(e: impl Display) -> MigrateError {
+    MigrateError::Tls(e.to_string())
+}
+
+fn load_certs(
+    path: &Path,
+) -> Result<Vec<CertificateDer<'static>>, MigrateError> {
+    let pem = std::fs::read(path)
+        .map_err(|e| tls_error(format!("{}: {e}", path.display())))?;
+    rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| tls_error(format!("{}: {e}", path.display())))
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, MigrateError> {
+    let pem = std::fs::read(path)
+        .map_err(|e| tls_error(format!("{}: {e}", path.display())))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| tls_error(format!("{}: {e}", path.display())))?
+        .ok_or_else(|| {
+            tls_error(format!("{}: no private key found", path.display()))
+        })
+}
+
+/// Loads the certificate chain and private key configured for this server,
+/// if there are any.
+fn load_identity(
+    cfg: Option<&MigrationConfig>,
+) -> Result<
+    Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    MigrateError,
+> {
+    match cfg.map(|c| (c.tls_cert.as_deref(), c.tls_key.as_deref())) {
+        Some((Some(cert), Some(key))) => {
+            Ok(Some((load_certs(cert)?, load_key(key)?)))
+        }
+        Some((None, None)) | None => Ok(None),
+        Some(_) => Err(tls_error("tls_cert and tls_key must be set together")),
+    }
+}
+
+/// Secures a connection from the destination of a migration out of the
+/// instance with TLS if the destination asked for it, enforcing the
+/// requirements in `cfg`.
+pub(crate) async fn accept(
+    conn: WebsocketConnectionRaw,
+    tls: bool,
+    cfg: Option<&MigrationConfig>,
+) -> Result<SourceStream, MigrateError> {
+    let client_ca = cfg.and_then(|c| c.tls_client_ca.as_deref());
+    if !tls {
+        if client_ca.is_some() {
+            return Err(tls_error(
+                "migrations out of this instance must use TLS",
+            ));
+        }
+        return Ok(Either::Left(conn));
+    }
+
+    let (certs, key) = load_identity(cfg)?.ok_or_else(|| {
+        tls_error("no certificate is configured for migrations over TLS")
+    })?;
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(tls_error)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(tls_error)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(tls_error)?;
+    let stream = TlsAcceptor::from(Arc::new(config))
+        .accept(conn)
+        .await
+        .map_err(tls_error)?;
+    Ok(Either::Right(stream))
+}
+
+/// Builds the connector with which the destination of a migration secures
+/// its connections to the source as `tls` asks, authenticating itself with
+/// the certificate in `cfg`, if there is one.
+fn connector(
+    tls: &MigrationTls,
+    cfg: Option<&MigrationConfig>,
+) -> Result<TlsConnector, MigrateError> {
+    let verifier = PinnedCertVerifier {
+        sha256: parse_sha256(&tls.source_cert_sha256)?,
+        algorithms: rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms,
+    };
+    let builder = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match load_identity(cfg)? {
+        Some((certs, key)) => {
+            builder.with_client_auth_cert(certs, key).map_err(tls_error)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn parse_sha256(digest_hex: &str) -> Result<[u8; 32], MigrateError> {
+    let mut digest = [0; 32];
+    hex::decode_to_slice(digest_hex, &mut digest).map_err(|_| {
+        tls_error(format!("invalid SHA-256 digest {digest_hex:?}"))
+    })?;
+    Ok(digest)
+}
+
+/// Builds the configuration with which the destination of a migration
+/// verifies the certificate of a source serving its API over TLS, if `cfg`
+/// says that sources do.
+fn api_client_config(
+    cfg: Option<&ApiTlsConfig>,
+) -> Result<Option<Arc<rustls::ClientConfig>>, MigrateError> {
+    let Some(peer_ca) = cfg.and_then(|c| c.peer_ca.as_deref()) else {
+        return Ok(None);
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(peer_ca)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Some(Arc::new(config)))
+}
+
+/// How the destination of a migration connects to its source.
+#[derive(Clone)]
+pub(crate) struct SourceConnector {
+    /// Secures the connections with TLS, if the migration uses it.
+    tls: Option<TlsConnector>,
+
+    /// Verifies the source's certificate, if it serves its API over TLS.
+    api_tls: Option<Arc<rustls::ClientConfig>>,
+
+    /// The bearer token with which the destination authorizes itself to the
+    /// source, if it has one.
+    token: Option<String>,
+}
+
+impl SourceConnector {
+    /// Builds a connector for a migration into the instance of the server
+    /// with context `ctx`, securing it as `tls` asks.
+    pub(crate) fn new(
+        ctx: &DropshotEndpointContext,
+        tls: Option<&MigrationTls>,
+    ) -> Result<Self, MigrateError> {
+        let api_tls = api_client_config(ctx.tls_config())?;
+        if tls.is_some() && api_tls.is_some() {
+            return Err(tls_error(
+                "migrations from sources serving their APIs over TLS are \
+                 already secured by it, and cannot use TLS of their own",
+            ));
+        }
+
+        Ok(Self {
+            tls: tls
+                .map(|t| connector(t, ctx.migration_config()))
+                .transpose()?,
+            api_tls,
+            token: ctx.migration_token().map(str::to_string),
+        })
+    }
+
+    /// Opens a connection to `url` on the source of a migration.  `url` is a
+    /// `ws` URL, which is changed to a `wss` URL if the source serves its API
+    /// over TLS.
+    pub(crate) async fn connect(
+        &self,
+        url: &str,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, MigrateError> {
+        let url = match (&self.tls, &self.api_tls) {
+            (Some(_), _) => format!("{url}?tls=true"),
+            (None, Some(_)) => url.replacen("ws://", "wss://", 1),
+            (None, None) => url.to_string(),
+        };
+        let mut request = url.as_str().into_client_request()?;
+        if let Some(token) = &self.token {
+            let value = format!("Bearer {token}")
+                .try_into()
+                .map_err(|_| MigrateError::Initiate)?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+
+        if let Some(tls) = &self.tls {
+            return connect_tls(request, tls).await;
+        }
+        let api_tls = self.api_tls.clone().map(Connector::Rustls);
+        let (conn, _) = tokio_tungstenite::connect_async_tls_with_config(
+            request, None, false, api_tls,
+        )
+        .await?;
+        Ok(conn)
+    }
+}
+
+/// Opens a connection on the source of a migration with `request`, securing
+/// it with `tls`.
+async fn connect_tls(
+    request: Request,
+    tls: &TlsConnector,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, MigrateError> {
+    let uri = request.uri();
+    let addr: SocketAddr = uri
+        .authority()
+        .and_then(|a| a.as_str().parse().ok())
+        .ok_or_else(|| tls_error(format!("invalid source address in {uri}")))?;
+    let mut tcp = TcpStream::connect(addr).await.map_err(tls_error)?;
+
+    // Upgrade the connection by hand, since it must be handed to TLS before
+    // any WebSocket frames are exchanged.
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let mut head = format!("GET {path} HTTP/1.1\r\n");
+    for (name, value) in request.headers() {
+        let value = value.to_str().map_err(tls_error)?;
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    tcp.write_all(head.as_bytes()).await.map_err(tls_error)?;
+
+    // Read the response a byte at a time so as not to consume the start of
+    // the TLS handshake.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_UPGRADE_RESPONSE {
+            return Err(MigrateError::Initiate);
+        }
+        response.push(tcp.read_u8().await.map_err(tls_error)?);
+    }
+    if !response.starts_with(b"HTTP/1.1 101 ") {
+        return Err(MigrateError::Initiate);
+    }
+
+    let stream = tls
+        .connect(ServerName::IpAddress(addr.ip().into()), tcp)
+        .await
+        .map_err(tls_error)?;
+    Ok(WebSocketStream::from_raw_socket(
+        MaybeTlsStream::Rustls(stream),
+        Role::Client,
+        None,
+    )
+    .await)
+}
+
+/// Accepts only a server certificate with a given SHA-256 digest, ignoring
+/// its issuer and the names in it.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    sha256: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+        if digest.as_ref() == self.sha256 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_pinned_digest() {
+        let hex = "00ff".repeat(16);
+        let digest = parse_sha256(&hex).unwrap();
+        assert_eq!(digest[..2], [0x00, 0xff]);
+        assert_eq!(parse_sha256(&hex.to_uppercase()).unwrap(), digest);
+
+        assert!(parse_sha256(&hex[2..]).is_err());
+        assert!(parse_sha256(&"zz".repeat(32)).is_err());
+        assert!(parse_sha256(&"é".repeat(32)).is_err());
+    }
+}
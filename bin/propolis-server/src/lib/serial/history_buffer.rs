@@ -3,7 +3,8 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Maintains a buffer of an instance's serial console data, holding both the
-//! first mebibyte and the most recent mebibyte of console output.
+//! beginning and the most recent portion of console output (by default, a
+//! mebibyte of each).
 
 use dropshot::HttpError;
 use propolis_api_types as api;
@@ -23,7 +24,7 @@ pub enum Error {
     },
 }
 
-const TTY_BUFFER_SIZE: usize = 1024 * 1024;
+pub(crate) const TTY_BUFFER_SIZE: usize = 1024 * 1024;
 const DEFAULT_MAX_LENGTH: isize = 16 * 1024;
 
 /// An abstraction for storing the contents of the instance's serial console
@@ -54,10 +55,12 @@ impl TryFrom<&api::InstanceSerialConsoleStreamRequest> for SerialHistoryOffset {
             api::InstanceSerialConsoleStreamRequest {
                 from_start: Some(offset),
                 most_recent: None,
+                ..
             } => Ok(SerialHistoryOffset::FromStart(*offset as usize)),
             api::InstanceSerialConsoleStreamRequest {
                 from_start: None,
                 most_recent: Some(offset),
+                ..
             } => Ok(SerialHistoryOffset::MostRecent(*offset as usize)),
             _ => Err(()),
         }
@@ -84,7 +87,7 @@ impl TryFrom<&api::InstanceSerialConsoleHistoryRequest>
                 ..
             } => Ok(SerialHistoryOffset::MostRecent(*offset as usize)),
             _ => Err(HttpError::for_bad_request(
-                None,
+                Some(api::ErrorCode::InvalidRequest.to_string()),
                 "Exactly one of 'from_start' or 'most_recent' must be specified."
                     .to_string(),
             )),
@@ -100,9 +103,12 @@ impl Default for HistoryBuffer {
 
 impl HistoryBuffer {
     pub fn new(buffer_size: usize) -> Self {
+        // Configured sizes may be large, so don't preallocate more than the
+        // default amount up front.
+        let capacity = buffer_size.min(TTY_BUFFER_SIZE);
         HistoryBuffer {
-            beginning: Vec::with_capacity(buffer_size),
-            rolling: VecDeque::with_capacity(buffer_size),
+            beginning: Vec::with_capacity(capacity),
+            rolling: VecDeque::with_capacity(capacity),
             buffer_size,
             total_bytes: 0,
         }
@@ -111,14 +117,43 @@ impl HistoryBuffer {
     /// Feeds the buffer new bytes from the serial console.
     pub fn consume(&mut self, data: &[u8]) {
         self.rolling.extend(data);
+        self.total_bytes += data.len();
+        self.trim_rolling();
+        super::probes::serial_buffer_size!(|| self.total_bytes);
+    }
+
+    /// Returns the maximum number of bytes retained in each of the beginning
+    /// and rolling portions of the buffer.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Changes the number of bytes retained in each portion of the buffer,
+    /// discarding already-retained data if the buffer shrinks. (Used when
+    /// importing history from a host with a different retention size.)
+    pub fn resize(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+        self.beginning.truncate(buffer_size);
+        self.trim_rolling();
+    }
+
+    /// Moves bytes in excess of `buffer_size` out of the rolling buffer. They
+    /// are captured into the beginning buffer only if it has room and all
+    /// the bytes preceding them are still there, so that its contents are
+    /// always the very first bytes of output.
+    fn trim_rolling(&mut self) {
         if self.rolling.len() > self.buffer_size {
+            let contiguous =
+                self.beginning.len() + self.rolling.len() == self.total_bytes;
             let to_drain = self.rolling.len() - self.buffer_size;
-            let to_capture = self.buffer_size - self.beginning.len();
+            let to_capture = if contiguous {
+                self.buffer_size - self.beginning.len()
+            } else {
+                0
+            };
             let drain = self.rolling.drain(0..to_drain).take(to_capture);
             self.beginning.extend(drain);
         }
-        self.total_bytes += data.len();
-        super::probes::serial_buffer_size!(|| self.total_bytes);
     }
 
     /// Returns a tuple containing:
@@ -277,4 +312,47 @@ mod test {
 
         assert!(buf.contents_vec(FromStart(16), None).is_err());
     }
+
+    #[test]
+    fn test_resize() {
+        let text = "0123456789abcdefghijklmnopqrstuv";
+
+        // Growing a buffer preserves its contents, and no bytes that fell
+        // out of the rolling buffer are misreported as part of the start.
+        let mut buf = HistoryBuffer::new(8);
+        buf.consume(&text.as_bytes()[..20]);
+        buf.resize(16);
+        buf.consume(&text.as_bytes()[20..]);
+        assert_eq!(sugar(&buf, FromStart(0), 32), ("01234567".to_string(), 8));
+        assert!(buf.contents_vec(FromStart(8), None).is_err());
+        assert_eq!(
+            sugar(&buf, MostRecent(16), 32),
+            ("ghijklmnopqrstuv".to_string(), 32)
+        );
+
+        // Shrinking a buffer discards the oldest rolling bytes and the
+        // newest beginning bytes.
+        let mut buf = HistoryBuffer::new(16);
+        buf.consume(&text.as_bytes()[..28]);
+        buf.resize(4);
+        assert_eq!(sugar(&buf, FromStart(0), 32), ("0123".to_string(), 4));
+        assert!(buf.contents_vec(FromStart(4), None).is_err());
+        assert_eq!(sugar(&buf, MostRecent(4), 32), ("opqr".to_string(), 28));
+
+        // Shrinking a buffer that hasn't wrapped yet keeps the first bytes of
+        // output contiguous.
+        let mut buf = HistoryBuffer::new(16);
+        buf.consume(&text.as_bytes()[..12]);
+        buf.resize(8);
+        assert_eq!(
+            sugar(&buf, FromStart(0), 32),
+            ("0123456789ab".to_string(), 12)
+        );
+        buf.consume(&text.as_bytes()[12..18]);
+        assert_eq!(sugar(&buf, FromStart(0), 32), ("01234567".to_string(), 8));
+        assert_eq!(
+            sugar(&buf, MostRecent(8), 32),
+            ("abcdefgh".to_string(), 18)
+        );
+    }
 }
@@ -0,0 +1,148 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Capture of serial port output to a set of size-limited files on the host.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use slog::{warn, Logger};
+
+/// A file to which serial output is appended, which is rotated once it grows
+/// beyond a configured size.
+///
+/// The file at `path` always receives the most recent output.  When it is
+/// rotated, it is renamed to `path.1`, with `path.1` becoming `path.2` and so
+/// on, and the oldest file beyond `max_files` discarded.
+pub struct RotatingLog {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    max_file_size: u64,
+    max_files: u32,
+    log: Logger,
+}
+
+impl RotatingLog {
+    /// Opens (or creates) the log file at `path`, appending to any output
+    /// already in it.
+    pub fn open(
+        path: &Path,
+        max_file_size: u64,
+        max_files: u32,
+        log: Logger,
+    ) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Some(file),
+            size,
+            max_file_size,
+            max_files,
+            log,
+        })
+    }
+
+    /// Appends `buf` to the log, rotating it first if the write would take it
+    /// past its maximum size.
+    ///
+    /// If writing fails, the error is logged and output is no longer captured.
+    pub fn write(&mut self, buf: &[u8]) {
+        if self.file.is_none() {
+            return;
+        }
+        let res = self.rotate_if_full(buf.len()).and_then(|_| {
+            self.file.as_mut().unwrap().write_all(buf)?;
+            self.size += buf.len() as u64;
+            Ok(())
+        });
+        if let Err(e) = res {
+            warn!(self.log, "Failed to write serial log, disabling it";
+                "path" => %self.path.display(),
+                "error" => %e,
+            );
+            self.file = None;
+        }
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate_if_full(&mut self, incoming: usize) -> std::io::Result<()> {
+        // A file is only rotated once it has some output in it, so that a
+        // single write larger than the limit is not lost.
+        if self.size == 0
+            || self.size.saturating_add(incoming as u64) <= self.max_file_size
+        {
+            return Ok(());
+        }
+
+        if self.max_files == 0 {
+            self.file.as_ref().unwrap().set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+        for n in (1..self.max_files).rev() {
+            match std::fs::rename(
+                self.rotated_path(n),
+                self.rotated_path(n + 1),
+            ) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e)
+                }
+                _ => {}
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = Some(
+            OpenOptions::new().create(true).append(true).open(&self.path)?,
+        );
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn contents(path: PathBuf) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com1.log");
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut out = RotatingLog::open(&path, 8, 2, log.clone()).unwrap();
+
+        out.write(b"abcd");
+        out.write(b"efgh");
+        assert_eq!(contents(path.clone()).unwrap(), "abcdefgh");
+
+        out.write(b"ijkl");
+        out.write(b"mnopqrstuv");
+        out.write(b"wx");
+        assert_eq!(contents(path.clone()).unwrap(), "wx");
+        assert_eq!(contents(out.rotated_path(1)).unwrap(), "mnopqrstuv");
+        assert_eq!(contents(out.rotated_path(2)).unwrap(), "ijkl");
+        assert_eq!(contents(out.rotated_path(3)), None);
+
+        // Reopening picks up where the existing file left off
+        drop(out);
+        let mut out = RotatingLog::open(&path, 8, 2, log).unwrap();
+        out.write(b"yz");
+        assert_eq!(contents(path.clone()).unwrap(), "wxyz");
+        out.write(b"01234");
+        assert_eq!(contents(path).unwrap(), "01234");
+        assert_eq!(contents(out.rotated_path(1)).unwrap(), "wxyz");
+        assert_eq!(contents(out.rotated_path(2)).unwrap(), "mnopqrstuv");
+    }
+}
@@ -14,12 +14,18 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::serial::history_buffer::{HistoryBuffer, SerialHistoryOffset};
+use crate::serial::log_file::RotatingLog;
+use crate::serial::recording::TtyRecorder;
 use futures::future::Fuse;
 use futures::stream::SplitSink;
 use futures::{FutureExt, SinkExt, StreamExt};
 use hyper::upgrade::Upgraded;
 use propolis::chardev::{pollers, Sink, Source};
-use propolis_api_types::InstanceSerialConsoleControlMessage;
+use propolis::hw::uart::LpcUart;
+use propolis_api_types::{
+    InstanceSerialConsoleClientMessage, InstanceSerialConsoleControlMessage,
+    InstanceSerialConsoleHistoryRequest,
+};
 use slog::{info, warn, Logger};
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot, Mutex, RwLock as AsyncRwLock};
@@ -31,6 +37,8 @@ use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 
 pub(crate) mod history_buffer;
+pub(crate) mod log_file;
+pub(crate) mod recording;
 
 #[usdt::provider(provider = "propolis")]
 mod probes {
@@ -71,6 +79,14 @@ pub enum SerialTaskControlMessage {
     Migration { destination: SocketAddr, from_start: u64 },
 }
 
+/// A websocket connection handed off to the serial task.
+pub struct SerialClient {
+    pub ws: WebSocketStream<Upgraded>,
+    /// Read-only clients receive console output, but any input they send is
+    /// discarded. At most one read-write client may be attached at a time.
+    pub read_only: bool,
+}
+
 pub struct SerialTask {
     /// Handle to attached serial session
     pub task: JoinHandle<()>,
@@ -78,13 +94,29 @@ pub struct SerialTask {
     /// clients of a migration
     pub control_ch: mpsc::Sender<SerialTaskControlMessage>,
     /// Channel used to send new client connections to the streaming task
-    pub websocks_ch: mpsc::Sender<WebSocketStream<Upgraded>>,
+    pub websocks_ch: mpsc::Sender<SerialClient>,
+}
+
+/// Returns the bytes to send to the guest following a break to deliver the
+/// magic SysRq key `sysrq`, if any.
+pub(crate) fn sysrq_bytes(sysrq: Option<char>) -> Result<Vec<u8>, String> {
+    match sysrq {
+        Some(key) if key.is_ascii_graphic() => Ok(vec![key as u8]),
+        Some(key) => Err(format!("invalid sysrq key {key:?}")),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn control_message(
+    msg: &InstanceSerialConsoleControlMessage,
+) -> Result<Message, SerialTaskError> {
+    Ok(Message::Text(serde_json::to_string(msg)?))
 }
 
-pub async fn instance_serial_task<Device: Sink + Source>(
-    mut websocks_recv: mpsc::Receiver<WebSocketStream<Upgraded>>,
+pub async fn instance_serial_task(
+    mut websocks_recv: mpsc::Receiver<SerialClient>,
     mut control_recv: mpsc::Receiver<SerialTaskControlMessage>,
-    serial: Arc<Serial<Device>>,
+    serial: Arc<Serial<LpcUart>>,
     log: Logger,
 ) -> Result<(), SerialTaskError> {
     info!(log, "Entered serial task");
@@ -104,6 +136,10 @@ pub async fn instance_serial_task<Device: Sink + Source>(
     let (send_ch, mut recv_ch) = mpsc::channel(4);
 
     let mut next_stream_id = 0usize;
+    // The ID of the single client (if any) whose input is fed to the UART.
+    let mut writer_id: Option<usize> = None;
+    // The size of the read-write client's terminal, if it has reported one.
+    let mut window_size: Option<(u16, u16)> = None;
 
     loop {
         let (uart_read, ws_send) =
@@ -198,7 +234,24 @@ pub async fn instance_serial_task<Device: Sink + Source>(
 
             new_ws = new_ws_recv => {
                 probes::serial_new_ws!(|| {});
-                if let Some(ws) = new_ws {
+                if let Some(SerialClient { mut ws, read_only }) = new_ws {
+                    if !read_only && writer_id.is_some() {
+                        info!(log, "Refusing additional read-write serial connection");
+                        let _ = ws.close(Some(CloseFrame {
+                            code: CloseCode::Policy,
+                            reason: "A read-write client is already attached".into(),
+                        })).await;
+                        continue;
+                    }
+                    if !read_only {
+                        writer_id = Some(next_stream_id);
+                    }
+                    if let Some((rows, cols)) = window_size {
+                        let msg = control_message(
+                            &InstanceSerialConsoleControlMessage::WindowSize { rows, cols },
+                        )?;
+                        let _ = ws.send(msg).await;
+                    }
                     let (ws_sink, ws_stream) = ws.split();
                     ws_sinks.insert(next_stream_id, ws_sink);
                     ws_streams.insert(next_stream_id, ws_stream);
@@ -254,10 +307,89 @@ pub async fn instance_serial_task<Device: Sink + Source>(
                 if let Some((i, msg)) = pair {
                     match msg {
                         Some(Ok(Message::Binary(input))) => {
-                            cur_input = Some((input, 0));
+                            if writer_id == Some(i) {
+                                cur_input = Some((input, 0));
+                            }
+                        }
+                        Some(Ok(Message::Text(json))) => {
+                            let is_writer = writer_id == Some(i);
+                            let mut replies = Vec::new();
+                            let request = serde_json::from_str::<InstanceSerialConsoleClientMessage>(&json);
+                            let error = match request {
+                                Err(e) => Some(format!("invalid control message: {e}")),
+                                Ok(InstanceSerialConsoleClientMessage::WindowSize { rows, cols }) if is_writer => {
+                                    window_size = Some((rows, cols));
+                                    let msg = control_message(
+                                        &InstanceSerialConsoleControlMessage::WindowSize { rows, cols },
+                                    )?;
+                                    for (_, sink) in ws_sinks.iter_mut().filter(|(id, _)| **id != i) {
+                                        let _ = sink.send(msg.clone()).await;
+                                    }
+                                    None
+                                }
+                                Ok(InstanceSerialConsoleClientMessage::Break { sysrq }) if is_writer => {
+                                    // Any input from this client has already
+                                    // been passed to the UART, since its
+                                    // messages are only read once that is
+                                    // done, so the break follows it.
+                                    match sysrq_bytes(sysrq) {
+                                        Ok(then) => serial
+                                            .send_break(&then)
+                                            .await
+                                            .err()
+                                            .map(|e| format!("failed to send break: {e}")),
+                                        Err(e) => Some(e),
+                                    }
+                                }
+                                Ok(InstanceSerialConsoleClientMessage::WindowSize { .. })
+                                | Ok(InstanceSerialConsoleClientMessage::Break { .. }) => {
+                                    Some("read-only clients cannot make this request".to_string())
+                                }
+                                Ok(InstanceSerialConsoleClientMessage::History { from_start, most_recent, max_bytes }) => {
+                                    let req = InstanceSerialConsoleHistoryRequest { from_start, most_recent, max_bytes };
+                                    let max_bytes = req.max_bytes.map(|x| x as usize);
+                                    let history = match SerialHistoryOffset::try_from(&req) {
+                                        Ok(offset) => serial
+                                            .history_vec(offset, max_bytes)
+                                            .await
+                                            .map_err(|e| e.to_string()),
+                                        Err(e) => Err(e.external_message),
+                                    };
+                                    match history {
+                                        Ok((data, end)) => {
+                                            replies.push(control_message(
+                                                &InstanceSerialConsoleControlMessage::History {
+                                                    from_start: (end - data.len()) as u64,
+                                                    len: data.len() as u64,
+                                                },
+                                            )?);
+                                            if !data.is_empty() {
+                                                replies.push(Message::Binary(data));
+                                            }
+                                            None
+                                        }
+                                        Err(e) => Some(e),
+                                    }
+                                }
+                            };
+                            if let Some(message) = error {
+                                info!(log, "Failed serial control request from connection {}: {}", i, message);
+                                replies.push(control_message(
+                                    &InstanceSerialConsoleControlMessage::Error { message },
+                                )?);
+                            }
+                            if let Some(sink) = ws_sinks.get_mut(&i) {
+                                for reply in replies {
+                                    let _ = sink.send(reply).await;
+                                }
+                            }
                         }
                         Some(Ok(Message::Close(..))) | None => {
                             info!(log, "Removing closed serial connection {}.", i);
+                            if writer_id == Some(i) {
+                                writer_id = None;
+                                window_size = None;
+                            }
                             let sink = ws_sinks.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
                             let stream = ws_streams.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
                             if let Err(e) = sink.reunite(stream).map_err(|_| SerialTaskError::MismatchedStreams)?.close(None).await {
@@ -280,6 +412,14 @@ pub async fn instance_serial_task<Device: Sink + Source>(
     Ok(())
 }
 
+/// Time allowed for the guest to make room for a break, and then to accept any
+/// input following it
+const BREAK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval at which delivery of a break is retried while the UART's receive
+/// buffer is full
+const BREAK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 /// Represents a serial connection into the VM.
 pub struct Serial<Device: Sink + Source> {
     uart: Arc<Device>,
@@ -289,6 +429,8 @@ pub struct Serial<Device: Sink + Source> {
     sink_poller: Arc<pollers::SinkBuffer>,
     source_poller: Arc<pollers::SourceBuffer>,
     history: AsyncRwLock<HistoryBuffer>,
+    output_log: Option<std::sync::Mutex<RotatingLog>>,
+    recording: Option<std::sync::Mutex<TtyRecorder>>,
 }
 
 impl<Device: Sink + Source> Serial<Device> {
@@ -302,10 +444,13 @@ impl<Device: Sink + Source> Serial<Device> {
     /// * `uart` - The device which data will be read from / written to.
     /// * `sink_size` - A lower bound on the size of the writeback buffer.
     /// * `source_size` - A lower bound on the size of the read buffer.
+    /// * `history_size` - The number of bytes of output to retain from both
+    ///   the start of the connection and its most recent output.
     pub fn new(
         uart: Arc<Device>,
         sink_size: NonZeroUsize,
         source_size: NonZeroUsize,
+        history_size: usize,
     ) -> Serial<Device> {
         let sink_poller = pollers::SinkBuffer::new(sink_size);
         let source_poller = pollers::SourceBuffer::new(pollers::Params {
@@ -313,20 +458,48 @@ impl<Device: Sink + Source> Serial<Device> {
             poll_interval: Duration::from_millis(10),
             poll_miss_thresh: 5,
         });
-        let history = Default::default();
+        let history = AsyncRwLock::new(HistoryBuffer::new(history_size));
         sink_poller.attach(uart.as_ref());
         source_poller.attach(uart.as_ref());
         uart.set_autodiscard(false);
 
         let task_control_ch = Default::default();
 
-        Serial { uart, task_control_ch, sink_poller, source_poller, history }
+        Serial {
+            uart,
+            task_control_ch,
+            sink_poller,
+            source_poller,
+            history,
+            output_log: None,
+            recording: None,
+        }
+    }
+
+    /// Copies all output read from the device to `log`, whether or not any
+    /// client is connected.
+    pub fn with_output_log(mut self, log: RotatingLog) -> Self {
+        self.output_log = Some(std::sync::Mutex::new(log));
+        self
+    }
+
+    /// Records all output read from the device, along with when it was read,
+    /// to `recording`.
+    pub fn with_recording(mut self, recording: TtyRecorder) -> Self {
+        self.recording = Some(std::sync::Mutex::new(recording));
+        self
     }
 
     pub async fn read_source(&self, buf: &mut [u8]) -> Option<usize> {
         let uart = self.uart.clone();
         let bytes_read = self.source_poller.read(buf, uart.as_ref()).await?;
         self.history.write().await.consume(&buf[..bytes_read]);
+        if let Some(output_log) = &self.output_log {
+            output_log.lock().unwrap().write(&buf[..bytes_read]);
+        }
+        if let Some(recording) = &self.recording {
+            recording.lock().unwrap().write(&buf[..bytes_read]);
+        }
         Some(bytes_read)
     }
 
@@ -343,6 +516,12 @@ impl<Device: Sink + Source> Serial<Device> {
         self.history.read().await.contents_vec(byte_offset, max_bytes)
     }
 
+    /// Returns the number of bytes the guest has written to the port since it
+    /// was created.
+    pub(crate) async fn bytes_output(&self) -> usize {
+        self.history.read().await.bytes_from_start()
+    }
+
     // provide the channel through which we inform connected websocket clients
     // that a migration has occurred, and where to reconnect.
     // (the server's serial-to-websocket task -- and thus the receiving end of
@@ -364,6 +543,25 @@ impl<Device: Sink + Source> Serial<Device> {
         let encoded = ron::to_string(&*read_hist)
             .map_err(|e| MigrateError::Codec(e.to_string()))?;
         drop(read_hist);
+        self.send_migration_notice(destination, from_start).await?;
+        Ok(encoded)
+    }
+
+    /// Tells this port's connected clients that the instance is migrating to
+    /// `destination`, without exporting the port's history.
+    pub(crate) async fn notify_migration(
+        &self,
+        destination: SocketAddr,
+    ) -> Result<(), MigrateError> {
+        let from_start = self.history.read().await.bytes_from_start() as u64;
+        self.send_migration_notice(destination, from_start).await
+    }
+
+    async fn send_migration_notice(
+        &self,
+        destination: SocketAddr,
+        from_start: u64,
+    ) -> Result<(), MigrateError> {
         if let Some(ch) = self.task_control_ch.lock().await.as_ref() {
             ch.send(SerialTaskControlMessage::Migration {
                 destination,
@@ -372,7 +570,7 @@ impl<Device: Sink + Source> Serial<Device> {
             .await
             .map_err(|_| MigrateError::InvalidInstanceState)?;
         }
-        Ok(encoded)
+        Ok(())
     }
 
     pub(crate) async fn import(
@@ -382,14 +580,54 @@ impl<Device: Sink + Source> Serial<Device> {
         self.sink_poller.attach(self.uart.as_ref());
         self.source_poller.attach(self.uart.as_ref());
         self.uart.set_autodiscard(false);
-        let decoded = ron::from_str(serialized_hist)
+        let mut decoded: HistoryBuffer = ron::from_str(serialized_hist)
             .map_err(|e| MigrateError::Codec(e.to_string()))?;
         let mut write_hist = self.history.write().await;
+        // Retain as much history as this host is configured to, not as much
+        // as the source was.
+        decoded.resize(write_hist.buffer_size());
         *write_hist = decoded;
         Ok(())
     }
 }
 
+impl Serial<LpcUart> {
+    /// Sends a break to the guest, once any input already buffered for it has
+    /// been delivered, followed by `then`.
+    ///
+    /// A break followed by a key is how a magic SysRq request is made over a
+    /// serial console.
+    pub async fn send_break(&self, then: &[u8]) -> std::io::Result<()> {
+        let deliver = async {
+            self.sink_poller.wait_empty().await;
+            while !self.uart.send_break() {
+                tokio::time::sleep(BREAK_RETRY_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(BREAK_TIMEOUT, deliver).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "guest did not make room for break",
+            )
+        })?;
+
+        if then.is_empty() {
+            return Ok(());
+        }
+        match tokio::time::timeout(BREAK_TIMEOUT, self.write_sink(then)).await {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "serial port is not accepting input",
+            )),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "guest did not accept input following break",
+            )),
+        }
+    }
+}
+
 impl<Device: Sink + Source> Drop for Serial<Device> {
     fn drop(&mut self) {
         self.uart.set_autodiscard(true);
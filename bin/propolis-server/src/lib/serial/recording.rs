@@ -0,0 +1,97 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Recording of serial port output in the ttyrec format.
+//!
+//! A ttyrec file is a sequence of records, each holding a chunk of output
+//! preceded by a 12-byte header: the time at which the output was produced, as
+//! seconds and microseconds since the Unix epoch, followed by the length of
+//! the chunk, all as little-endian `u32`s.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use slog::{warn, Logger};
+
+/// A ttyrec file to which serial output is appended as it is read from the
+/// device.
+pub struct TtyRecorder {
+    path: PathBuf,
+    file: Option<File>,
+    log: Logger,
+}
+
+impl TtyRecorder {
+    /// Opens (or creates) the recording at `path`.  Since a ttyrec file is
+    /// just a series of records, output is appended to any already recorded.
+    pub fn open(path: &Path, log: Logger) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { path: path.to_path_buf(), file: Some(file), log })
+    }
+
+    /// Records `buf` as output produced now.
+    ///
+    /// If writing fails, the error is logged and recording stops.
+    pub fn write(&mut self, buf: &[u8]) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.write_at(now, buf);
+    }
+
+    fn write_at(&mut self, time: Duration, buf: &[u8]) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        if buf.is_empty() {
+            return;
+        }
+
+        let mut record = Vec::with_capacity(12 + buf.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        record.extend_from_slice(buf);
+
+        // Each record is written whole, so that a player is never left with a
+        // header lacking its data.
+        if let Err(e) = file.write_all(&record) {
+            warn!(self.log, "Failed to write serial recording, disabling it";
+                "path" => %self.path.display(),
+                "error" => %e,
+            );
+            self.file = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("com1.ttyrec");
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut rec = TtyRecorder::open(&path, log).unwrap();
+
+        rec.write_at(Duration::new(1700000000, 250_000_999), b"login: ");
+        rec.write_at(Duration::new(1700000001, 0), b"");
+        rec.write_at(Duration::new(1700000002, 1_000), b"root\r\n");
+
+        let data = std::fs::read(&path).unwrap();
+        let mut expected = Vec::new();
+        for (secs, usecs, out) in [
+            (1700000000u32, 250_000u32, &b"login: "[..]),
+            (1700000002, 1, &b"root\r\n"[..]),
+        ] {
+            expected.extend_from_slice(&secs.to_le_bytes());
+            expected.extend_from_slice(&usecs.to_le_bytes());
+            expected.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            expected.extend_from_slice(out);
+        }
+        assert_eq!(data, expected);
+    }
+}
@@ -12,40 +12,55 @@
 use std::convert::TryFrom;
 use std::net::Ipv6Addr;
 use std::net::SocketAddrV6;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::BTreeMap, net::SocketAddr};
 
+use crate::auth::Authorizer;
+use crate::disk_export;
+use crate::guest_agent::{GuestAgent, GuestAgentError};
+use crate::history::EventHistory;
+use crate::limits::EndpointLimits;
+use crate::memdump::{self, MemoryDump};
+use crate::migrate::tls::SourceConnector;
 use crate::migrate::MigrateError;
 use crate::serial::history_buffer::SerialHistoryOffset;
 use crate::serial::SerialTaskControlMessage;
 use dropshot::{
     channel, endpoint, ApiDescription, HttpError, HttpResponseCreated,
-    HttpResponseOk, HttpResponseUpdatedNoContent, Path, Query, RequestContext,
-    TypedBody, WebsocketConnection,
+    HttpResponseDeleted, HttpResponseOk, HttpResponseUpdatedNoContent, Path,
+    Query, RequestContext, TypedBody, WebsocketConnection,
 };
 use futures::SinkExt;
+use hyper::{Body, Response};
 use internal_dns::resolver::{ResolveError, Resolver};
 use internal_dns::ServiceName;
 pub use nexus_client::Client as NexusClient;
 use oximeter::types::ProducerRegistry;
+use propolis::hw::qemu::ramfb::Resolution;
 use propolis_api_types as api;
 use propolis_api_types::instance_spec::{
-    self, components::backends::CrucibleStorageBackend, v0::StorageBackendV0,
+    self, components::backends::CrucibleStorageBackend,
+    components::devices::SerialPortNumber, v0::StorageBackendV0,
     VersionedInstanceSpec,
 };
 
+use propolis_server_config::AuthScope;
 pub use propolis_server_config::Config as VmTomlConfig;
 use rfb::server::VncServer;
 use slog::{error, info, o, warn, Logger};
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot, MappedMutexGuard, Mutex, MutexGuard};
+use tokio::sync::{mpsc, oneshot, MappedMutexGuard, Mutex, MutexGuard, Notify};
+use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
 use tokio_tungstenite::WebSocketStream;
 
 use crate::spec::{ServerSpecBuilder, ServerSpecBuilderError};
 use crate::stats::virtual_machine::VirtualMachine;
-use crate::vm::VmController;
+use crate::vm::{VmController, VmControllerError};
 use crate::vnc::PropolisVncServer;
+use crate::webhook::{self, WebhookRegistry};
 
 pub(crate) type DeviceMap =
     BTreeMap<String, Arc<dyn propolis::common::Lifecycle>>;
@@ -53,6 +68,38 @@ pub(crate) type BlockBackendMap =
     BTreeMap<String, Arc<dyn propolis::block::Backend>>;
 pub(crate) type CrucibleBackendMap =
     BTreeMap<uuid::Uuid, Arc<propolis::block::CrucibleBackend>>;
+pub(crate) type StorageDeviceMap = BTreeMap<String, StorageDevice>;
+/// The instance's virtio devices, keyed by their names in the instance spec.
+pub(crate) type VirtioDeviceMap =
+    BTreeMap<String, Arc<dyn propolis::hw::virtio::pci::PciVirtio>>;
+/// The rate-limited backends of the instance's NICs whose datapaths are in
+/// userspace, keyed by the NICs' names in the instance spec.
+pub(crate) type NicRateLimiterMap =
+    BTreeMap<String, Arc<propolis::net::RateLimitedBackend>>;
+/// The link controls of the instance's virtio NICs, keyed by the NICs' names
+/// in the instance spec.
+pub(crate) type NicLinkMap =
+    BTreeMap<String, Arc<dyn propolis::hw::virtio::NetLink>>;
+
+/// A storage device in an instance, keyed in a [`StorageDeviceMap`] by the
+/// device's name in the instance spec.
+#[derive(Clone)]
+pub(crate) struct StorageDevice {
+    /// The emulated device the guest uses to access the disk.
+    pub device: Arc<dyn propolis::common::Lifecycle>,
+
+    /// The block device interface of `device`.
+    pub block_dev: Arc<dyn propolis::block::Device>,
+
+    /// The block backend attached to `device`.
+    pub backend: Arc<dyn propolis::block::Backend>,
+
+    /// The backend, if it is a Crucible backend.
+    pub crucible: Option<Arc<propolis::block::CrucibleBackend>>,
+
+    /// The device, if it is a CD-ROM drive (whose media can be changed).
+    pub cdrom: Option<Arc<propolis::hw::ahci::PciAhci>>,
+}
 
 /// Configuration used to set this server up to provide Oximeter metrics.
 #[derive(Debug, Clone)]
@@ -72,6 +119,19 @@ impl MetricsEndpointConfig {
     }
 }
 
+/// Directories of canned device payloads used to test migration between
+/// Propolis builds.
+#[derive(Clone, Debug, Default)]
+pub struct MigrationPayloadDirs {
+    /// If set, the device state sent in each migration out of the instance is
+    /// also written here.
+    pub emit: Option<PathBuf>,
+
+    /// If set, payloads read from here replace those sent by the source of a
+    /// migration into the instance.
+    pub import: Option<PathBuf>,
+}
+
 /// Static configuration for objects owned by this server. The server obtains
 /// this configuration at startup time and refers to it when manipulating its
 /// objects.
@@ -85,6 +145,9 @@ pub struct StaticConfig {
     /// The configuration to use when setting up this server's Oximeter
     /// endpoint.
     metrics: Option<MetricsEndpointConfig>,
+
+    /// Where to write and read canned device payloads, if anywhere.
+    pub migration_payloads: MigrationPayloadDirs,
 }
 
 /// The state of the current VM controller in this server, if there is one, or
@@ -115,6 +178,9 @@ pub enum VmControllerState {
         // types is complete.
         last_instance_spec: Box<VersionedInstanceSpec>,
 
+        /// The generation of `last_instance_spec`.
+        last_spec_generation: u64,
+
         /// A clone of the receiver side of the server's state watcher, used to
         /// serve subsequent `instance_state_monitor` requests. Note that an
         /// outgoing controller can publish new state changes even after the
@@ -142,12 +208,15 @@ impl VmControllerState {
         if let VmControllerState::Created(vm) = self {
             let state = vm.state_watcher().borrow().state;
             let last_instance = api::Instance {
-                properties: vm.properties().clone(),
+                properties: vm.properties(),
                 state,
                 disks: vec![],
                 nics: vec![],
             };
-            let last_instance_spec = vm.instance_spec().await.clone();
+            let (last_instance_spec, last_spec_generation) = {
+                let spec = vm.instance_spec().await;
+                (spec.clone(), vm.spec_generation())
+            };
 
             // Preserve the state watcher so that subsequent updates to the VM's
             // state are visible to calls to query/monitor that state. Note that
@@ -161,6 +230,7 @@ impl VmControllerState {
                 VmControllerState::Destroyed {
                     last_instance: Box::new(last_instance),
                     last_instance_spec: Box::new(last_instance_spec),
+                    last_spec_generation,
                     state_watcher,
                 },
             ) {
@@ -192,24 +262,81 @@ pub struct ServiceProviders {
     /// `None` until a guest is created via `instance_ensure`.
     pub vm: Mutex<VmControllerState>,
 
-    /// The currently active serial console handling task, if present.
-    serial_task: Mutex<Option<super::serial::SerialTask>>,
+    /// The currently active serial port handling tasks, one for each of the
+    /// instance's serial ports.
+    serial_tasks: Mutex<BTreeMap<SerialPortNumber, super::serial::SerialTask>>,
 
     /// State related to the Propolis Oximeter server and actual statistics.
     oximeter_state: Mutex<OximeterState>,
 
-    /// The VNC server hosted within this process. Note that this server always
-    /// exists irrespective of whether there is an instance. Creating an
-    /// instance hooks this server up to the instance's framebuffer.
-    vnc_server: Arc<VncServer<PropolisVncServer>>,
+    /// The VNC server hosted within this process, if these are the services
+    /// of the server's primary instance. Note that this server always exists
+    /// irrespective of whether there is an instance. Creating an instance hooks
+    /// this server up to the instance's framebuffer.
+    vnc_server: Option<Arc<VncServer<PropolisVncServer>>>,
+
+    /// The task capturing the instance's framebuffer to files on the host, if
+    /// framebuffer recording is configured.
+    fb_recorder: Mutex<Option<JoinHandle<()>>>,
+
+    /// The URLs registered to be notified of changes to the instance.  These
+    /// are kept for the life of the server, so may be registered before the
+    /// instance is created.
+    webhooks: Arc<WebhookRegistry>,
+
+    /// The history of the instance, kept for the life of the server so that
+    /// it outlives the instance.
+    history: Arc<EventHistory>,
+
+    /// Limits on the requests to the instance's expensive endpoints which may
+    /// be served at once.
+    limits: Arc<EndpointLimits>,
 }
 
 impl ServiceProviders {
+    fn new(
+        vnc_server: Option<Arc<VncServer<PropolisVncServer>>>,
+        webhooks: Arc<WebhookRegistry>,
+        history: Arc<EventHistory>,
+    ) -> Self {
+        Self {
+            vm: Mutex::new(VmControllerState::NotCreated),
+            serial_tasks: Mutex::new(BTreeMap::new()),
+            oximeter_state: Mutex::new(OximeterState {
+                server: None,
+                stats: None,
+            }),
+            vnc_server,
+            fb_recorder: Mutex::new(None),
+            webhooks,
+            history,
+            limits: Arc::new(EndpointLimits::default()),
+        }
+    }
+
+    /// Get access to the VM controller for this instance, emitting a
+    /// consistent error if it is absent.
+    async fn vm(
+        &self,
+    ) -> Result<MappedMutexGuard<Arc<VmController>>, HttpError> {
+        MutexGuard::try_map(
+            self.vm.lock().await,
+            VmControllerState::as_controller,
+        )
+        .map_err(|_| not_created_error())
+    }
+
     /// Directs the current set of per-instance service providers to stop in an
     /// orderly fashion, then drops them all.
     async fn stop(&self, log: &Logger) {
         // Stop the VNC server
-        self.vnc_server.stop().await;
+        if let Some(vnc_server) = &self.vnc_server {
+            vnc_server.stop().await;
+        }
+
+        if let Some(fb_recorder) = self.fb_recorder.lock().await.take() {
+            fb_recorder.abort();
+        }
 
         if let Some(vm) = self.vm.lock().await.take_controller().await {
             slog::info!(log, "Dropping server's VM controller reference";
@@ -217,7 +344,8 @@ impl ServiceProviders {
                 "weak_refs" => Arc::weak_count(&vm),
             );
         }
-        if let Some(serial_task) = self.serial_task.lock().await.take() {
+        let serial_tasks = std::mem::take(&mut *self.serial_tasks.lock().await);
+        for serial_task in serial_tasks.into_values() {
             let _ = serial_task
                 .control_ch
                 .send(SerialTaskControlMessage::Stopping)
@@ -244,7 +372,19 @@ impl ServiceProviders {
 /// Context accessible from HTTP callbacks.
 pub struct DropshotEndpointContext {
     static_config: StaticConfig,
+    auth: Authorizer,
+
+    /// The services of the server's primary instance, which the `/instance`
+    /// endpoints address.
     pub services: Arc<ServiceProviders>,
+
+    /// The services of the instances addressed by ID, which the
+    /// `/instances/{id}` endpoints address, if multi-instance mode is
+    /// configured.
+    instances: Mutex<BTreeMap<uuid::Uuid, Arc<ServiceProviders>>>,
+
+    /// Notified once the server has been asked to shut down.
+    shutdown: Notify,
     log: Logger,
 }
 
@@ -256,39 +396,101 @@ impl DropshotEndpointContext {
         use_reservoir: bool,
         log: slog::Logger,
         metric_config: Option<MetricsEndpointConfig>,
+        migration_payloads: MigrationPayloadDirs,
+        auth: Authorizer,
     ) -> Self {
         Self {
             static_config: StaticConfig {
                 vm: config,
                 use_reservoir,
                 metrics: metric_config,
+                migration_payloads,
             },
-            services: Arc::new(ServiceProviders {
-                vm: Mutex::new(VmControllerState::NotCreated),
-                serial_task: Mutex::new(None),
-                oximeter_state: Mutex::new(OximeterState {
-                    server: None,
-                    stats: None,
-                }),
-                vnc_server,
-            }),
+            auth,
+            services: Arc::new(ServiceProviders::new(
+                Some(vnc_server),
+                Arc::new(WebhookRegistry::new()),
+                Arc::new(EventHistory::default()),
+            )),
+            instances: Mutex::new(BTreeMap::new()),
+            shutdown: Notify::new(),
             log,
         }
     }
 
+    /// Waits until the server has been asked to shut down through the API.
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await
+    }
+
+    /// Stops the services of all of the server's instances, as the server
+    /// shuts down.
+    pub async fn stop(&self) {
+        let instances = std::mem::take(&mut *self.instances.lock().await);
+        for services in instances.into_values() {
+            services.stop(&self.log).await;
+        }
+        self.services.stop(&self.log).await;
+    }
+
+    /// The configuration for live migrations into and out of this server's
+    /// instance, if any.
+    pub(crate) fn migration_config(
+        &self,
+    ) -> Option<&propolis_server_config::Migration> {
+        self.static_config.vm.migration.as_ref()
+    }
+
+    /// The configuration for serving this server's API over TLS, if any.
+    pub(crate) fn tls_config(&self) -> Option<&propolis_server_config::Tls> {
+        self.static_config.vm.tls.as_ref()
+    }
+
+    /// The token to present to the source of a migration into this server's
+    /// instance, if any.
+    pub(crate) fn migration_token(&self) -> Option<&str> {
+        self.auth.migration_token()
+    }
+
     /// Get access to the VM controller for this context, emitting a consistent
     /// error if it is absent.
     pub(crate) async fn vm(
         &self,
     ) -> Result<MappedMutexGuard<Arc<VmController>>, HttpError> {
-        MutexGuard::try_map(
-            self.services.vm.lock().await,
-            VmControllerState::as_controller,
-        )
-        .map_err(|_| not_created_error())
+        self.services.vm().await
+    }
+
+    /// Returns the services of the instance with the given ID, which must be
+    /// addressed by ID.
+    async fn instance(
+        &self,
+        id: uuid::Uuid,
+    ) -> Result<Arc<ServiceProviders>, HttpError> {
+        self.instances.lock().await.get(&id).cloned().ok_or_else(|| {
+            HttpError::for_not_found(
+                Some(api::ErrorCode::NoInstance.to_string()),
+                format!("No instance with ID {id}"),
+            )
+        })
     }
 }
 
+/// Checks that a request carries a token granting `scope`.
+fn authorize(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+    scope: AuthScope,
+) -> Result<(), HttpError> {
+    rqctx.context().auth.check(rqctx.request.headers(), Some(scope))
+}
+
+/// Checks that a request to an endpoint which only reports on the instance
+/// carries a token of any scope.
+fn authenticate(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<(), HttpError> {
+    rqctx.context().auth.check(rqctx.request.headers(), None)
+}
+
 #[derive(Debug, Error)]
 enum SpecCreationError {
     #[error(transparent)]
@@ -472,11 +674,23 @@ async fn find_local_nexus_client(
 
 async fn instance_ensure_common(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    services: Arc<ServiceProviders>,
     request: api::InstanceSpecEnsureRequest,
 ) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
     let server_context = rqctx.context();
-    let api::InstanceSpecEnsureRequest { properties, instance_spec, migrate } =
-        request;
+    let api::InstanceSpecEnsureRequest {
+        properties,
+        instance_spec,
+        migrate,
+        start_paused,
+        boot_to_firmware_setup,
+        supervision,
+    } = request;
+    // Creating the instance by migrating it here needs the migration scope
+    // as well.
+    if migrate.is_some() {
+        authorize(&rqctx, AuthScope::Migration)?;
+    }
 
     // Handle requests to an instance that has already been initialized. Treat
     // the instances as compatible (and return Ok) if they have the same
@@ -484,9 +698,7 @@ async fn instance_ensure_common(
     //
     // TODO(#205): Consider whether to use this interface to change an
     // instance's devices and backends at runtime.
-    if let VmControllerState::Created(existing) =
-        &*server_context.services.vm.lock().await
-    {
+    if let VmControllerState::Created(existing) = &*services.vm.lock().await {
         let existing_properties = existing.properties();
         if existing_properties.id != properties.id {
             return Err(HttpError::for_client_error(
@@ -499,7 +711,7 @@ async fn instance_ensure_common(
             ));
         }
 
-        if *existing_properties != properties {
+        if existing_properties != properties {
             return Err(HttpError::for_client_error(
                 Some(api::ErrorCode::AlreadyRunning.to_string()),
                 http::status::StatusCode::CONFLICT,
@@ -512,6 +724,36 @@ async fn instance_ensure_common(
         }));
     }
 
+    // Without a guest agent to ping, a guest which is quiet on its serial ports
+    // can't be told apart from a hung one, so check that the instance has one
+    // if the policy would have it reset.
+    if let Some(policy) = &supervision {
+        let VersionedInstanceSpec::V0(v0_spec) = &instance_spec;
+        let has_agent =
+            server_context.static_config.vm.guest_agent.as_ref().is_some_and(
+                |agent| {
+                    v0_spec
+                        .devices
+                        .virtio_console
+                        .iter()
+                        .flat_map(|console| console.ports.iter())
+                        .any(|port| port.name == agent.port)
+                },
+            );
+        crate::vm::supervisor::check_policy(policy, has_agent).map_err(
+            |e| {
+                HttpError::for_bad_request(
+                    Some(api::ErrorCode::InvalidRequest.to_string()),
+                    e,
+                )
+            },
+        )?;
+    }
+
+    // The target identifying the instance in its metrics is shared by all of
+    // the producers reporting them, so that it can be updated with the
+    // instance's properties.
+    let virtual_machine = VirtualMachine::from(&properties);
     let producer_registry =
         if let Some(cfg) = server_context.static_config.metrics.as_ref() {
             // Create a registry and spawn tasks to register with Nexus as an
@@ -522,12 +764,11 @@ async fn instance_ensure_common(
             // (which may spin indefinitely) so that we can continue to initialize
             // the VM instance without blocking for that to succeed.
             let registry = ProducerRegistry::with_id(properties.id);
-            let virtual_machine = VirtualMachine::from(&properties);
             register_oximeter_producer(
-                server_context.services.clone(),
+                services.clone(),
                 cfg.clone(),
                 &registry,
-                virtual_machine,
+                virtual_machine.clone(),
                 rqctx.log.clone(),
             )
             .await;
@@ -554,6 +795,7 @@ async fn instance_ensure_common(
     let vm = {
         let properties = properties.clone();
         let server_context = server_context.clone();
+        let history = services.history.clone();
         let log = server_context.log.clone();
         let hdl = tokio::runtime::Handle::current();
         let ctrl_hdl = hdl.clone();
@@ -564,6 +806,10 @@ async fn instance_ensure_common(
                 &server_context.static_config,
                 producer_registry,
                 nexus_client,
+                virtual_machine,
+                history,
+                start_paused,
+                boot_to_firmware_setup,
                 log,
                 ctrl_hdl,
                 stop_ch,
@@ -577,37 +823,137 @@ async fn instance_ensure_common(
     })?;
 
     if let Some(ramfb) = vm.framebuffer() {
-        // Get a framebuffer description from the wrapped instance.
-        let fb_spec = ramfb.get_framebuffer_spec();
-        let vnc_fb = crate::vnc::RamFb::new(fb_spec);
-
-        // Get a reference to the PS2 controller so that we can pass keyboard input.
-        let ps2ctrl = vm.ps2ctrl().clone();
-
-        // Get a reference to the outward-facing VNC server in this process.
-        let vnc_server = server_context.services.vnc_server.clone();
-
-        // Initialize the Propolis VNC adapter with references to the VM's Instance,
-        // framebuffer, and PS2 controller.
-        vnc_server.server.initialize(vnc_fb, ps2ctrl, vm.clone()).await;
-
-        // Hook up the framebuffer notifier to update the Propolis VNC adapter
-        let notifier_server_ref = vnc_server.clone();
-        let rt = tokio::runtime::Handle::current();
-        ramfb.set_notifier(Box::new(move |config, is_valid| {
-            let vnc = notifier_server_ref.clone();
-            rt.block_on(vnc.server.update(config, is_valid, &vnc));
-        }));
+        // Only the primary instance is exposed through the outward-facing VNC
+        // server in this process.
+        if let Some(vnc_server) = services.vnc_server.clone() {
+            // Get a framebuffer description from the wrapped instance.
+            let fb_spec = ramfb.get_framebuffer_spec();
+            let vnc_fb = crate::vnc::RamFb::new(fb_spec);
+
+            // Get a reference to the PS2 controller so that we can pass
+            // keyboard input.
+            let ps2ctrl = vm.ps2ctrl().clone();
+
+            // Find the socket of the virtio-console port, if any, through which
+            // clipboard text from clients is passed to the guest.
+            let clipboard_port = server_context
+                .static_config
+                .vm
+                .vnc
+                .as_ref()
+                .and_then(|vnc| vnc.clipboard_port.as_deref());
+            let clipboard_socket = match clipboard_port {
+                Some(name) => {
+                    let spec = vm.instance_spec().await;
+                    let VersionedInstanceSpec::V0(v0_spec) = &*spec;
+                    let socket = v0_spec
+                        .devices
+                        .virtio_console
+                        .iter()
+                        .flat_map(|console| console.ports.iter())
+                        .find(|port| port.name == name)
+                        .map(|port| {
+                            std::path::PathBuf::from(&port.socket_path)
+                        });
+                    if socket.is_none() {
+                        warn!(server_context.log,
+                              "VNC clipboard port not found in virtio-console";
+                              "port" => name);
+                    }
+                    socket
+                }
+                None => None,
+            };
+
+            // Initialize the Propolis VNC adapter with references to the VM's
+            // Instance, framebuffer, and PS2 controller.
+            vnc_server
+                .server
+                .initialize(vnc_fb, ps2ctrl, vm.clone(), clipboard_socket)
+                .await;
+
+            // Hook up the framebuffer notifier to update the Propolis VNC
+            // adapter
+            let notifier_server_ref = vnc_server.clone();
+            let rt = tokio::runtime::Handle::current();
+            ramfb.set_notifier(Box::new(move |config, is_valid| {
+                let vnc = notifier_server_ref.clone();
+                rt.block_on(vnc.server.update(config, is_valid, &vnc));
+            }));
+        }
+
+        let recording = server_context.static_config.vm.recording.as_ref();
+        if let Some((cfg, secs)) = recording.and_then(|cfg| {
+            cfg.framebuffer_interval_secs.map(|secs| (cfg, secs))
+        }) {
+            match std::fs::create_dir_all(&cfg.directory) {
+                Ok(()) => {
+                    *services.fb_recorder.lock().await =
+                        Some(crate::fb_recording::spawn_recorder(
+                            vm.clone(),
+                            cfg.directory.clone(),
+                            Duration::from_secs(secs.max(1)),
+                            rqctx.log.new(o!("component" => "fb-recorder")),
+                        ));
+                }
+                Err(e) => {
+                    warn!(server_context.log,
+                          "Failed to create framebuffer recording directory";
+                          "directory" => %cfg.directory.display(),
+                          "error" => %e);
+                }
+            }
+        }
+    }
+
+    // The notifier runs until the instance is destroyed, and holds no
+    // reference to its controller, so it needn't be stopped with the rest of
+    // the instance's services.
+    webhook::spawn_notifier(
+        services.webhooks.clone(),
+        properties.id,
+        vm.state_watcher().clone(),
+        vm.pvpanic().cloned(),
+        rqctx.log.new(o!("component" => "webhooks")),
+    );
+
+    // Like the notifier, the snapshot scheduler exits once the instance is
+    // destroyed, releasing its reference to the controller.
+    if let Some(schedule) = &server_context.static_config.vm.snapshot_schedule {
+        crate::snapshot::spawn_scheduler(
+            vm.clone(),
+            services.limits.clone(),
+            Duration::from_secs(schedule.interval_secs.max(1)),
+            schedule.quiesce,
+            schedule.keep,
+            rqctx.log.new(o!("component" => "snapshot-scheduler")),
+        );
+    }
+
+    // The supervisor, too, exits once the instance is destroyed.
+    if let Some(policy) = supervision {
+        crate::vm::supervisor::spawn(
+            vm.clone(),
+            policy,
+            rqctx.log.new(o!("component" => "supervisor")),
+        );
     }
 
-    let mut serial_task = server_context.services.serial_task.lock().await;
-    if serial_task.is_none() {
+    let mut serial_tasks = services.serial_tasks.lock().await;
+    for (port, serial) in vm.serial_ports() {
+        if serial_tasks.contains_key(port) {
+            continue;
+        }
+
         let (websocks_ch, websocks_recv) = mpsc::channel(1);
         let (control_ch, control_recv) = mpsc::channel(1);
 
-        let serial = vm.com1().clone();
+        let serial = serial.clone();
         serial.set_task_control_sender(control_ch.clone()).await;
-        let err_log = rqctx.log.new(o!("component" => "serial task"));
+        let err_log = rqctx.log.new(o!(
+            "component" => "serial task",
+            "port" => format!("{port:?}"),
+        ));
         let task = tokio::spawn(async move {
             if let Err(e) = super::serial::instance_serial_task(
                 websocks_recv,
@@ -620,21 +966,22 @@ async fn instance_ensure_common(
                 error!(err_log, "Failure in serial task: {}", e);
             }
         });
-        *serial_task =
-            Some(super::serial::SerialTask { task, control_ch, websocks_ch });
+        serial_tasks.insert(
+            *port,
+            super::serial::SerialTask { task, control_ch, websocks_ch },
+        );
     }
 
     let log = server_context.log.clone();
-    let services = Arc::clone(&server_context.services);
+    let stop_services = Arc::clone(&services);
     tokio::task::spawn(async move {
         // Once the VmController has signaled that it is shutting down,
         // we'll clean up the per-instance service providers as well.
         let _ = stop_recv.await;
-        services.stop(&log).await;
+        stop_services.stop(&log).await;
     });
 
-    *server_context.services.vm.lock().await =
-        VmControllerState::Created(vm.clone());
+    *services.vm.lock().await = VmControllerState::Created(vm.clone());
 
     let migrate = if let Some(migrate_request) = migrate {
         let res = crate::migrate::dest_initiate(&rqctx, vm, migrate_request)
@@ -656,13 +1003,14 @@ async fn instance_ensure(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceEnsureRequest>,
 ) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
     let server_context = rqctx.context();
     let request = request.into_inner();
     let instance_spec =
         instance_spec_from_request(&request, &server_context.static_config.vm)
             .map_err(|e| {
                 HttpError::for_bad_request(
-                    None,
+                    Some(api::ErrorCode::InvalidRequest.to_string()),
                     format!(
                         "failed to generate instance spec from request: {}",
                         e
@@ -670,12 +1018,17 @@ async fn instance_ensure(
                 )
             })?;
 
+    let services = server_context.services.clone();
     instance_ensure_common(
         rqctx,
+        services,
         api::InstanceSpecEnsureRequest {
             properties: request.properties,
             instance_spec,
             migrate: request.migrate,
+            start_paused: request.start_paused,
+            boot_to_firmware_setup: request.boot_to_firmware_setup,
+            supervision: request.supervision,
         },
     )
     .await
@@ -689,19 +1042,22 @@ async fn instance_spec_ensure(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceSpecEnsureRequest>,
 ) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
-    instance_ensure_common(rqctx, request.into_inner()).await
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let services = rqctx.context().services.clone();
+    instance_ensure_common(rqctx, services, request.into_inner()).await
 }
 
+/// Returns the instance's properties, its spec, and the spec's generation.
 async fn instance_get_common(
-    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
-) -> Result<(api::Instance, VersionedInstanceSpec), HttpError> {
-    let ctx = rqctx.context();
-    match &*ctx.services.vm.lock().await {
+    services: &ServiceProviders,
+) -> Result<(api::Instance, VersionedInstanceSpec, u64), HttpError> {
+    match &*services.vm.lock().await {
         VmControllerState::NotCreated => Err(not_created_error()),
         VmControllerState::Created(vm) => {
+            let spec = vm.instance_spec().await;
             Ok((
                 api::Instance {
-                    properties: vm.properties().clone(),
+                    properties: vm.properties(),
                     state: vm.external_instance_state(),
                     disks: vec![],
                     // TODO: Fix this; we need a way to enumerate attached NICs.
@@ -713,23 +1069,34 @@ async fn instance_get_common(
                     // (i.e., has the device faulted, etc).
                     nics: vec![],
                 },
-                vm.instance_spec().await.clone(),
+                spec.clone(),
+                vm.spec_generation(),
             ))
         }
         VmControllerState::Destroyed {
             last_instance,
             last_instance_spec,
+            last_spec_generation,
             state_watcher,
             ..
         } => {
             let watcher = state_watcher.borrow();
             let mut last_instance = last_instance.clone();
             last_instance.state = watcher.state;
-            Ok((*last_instance, *last_instance_spec.clone()))
+            Ok((
+                *last_instance,
+                *last_instance_spec.clone(),
+                *last_spec_generation,
+            ))
         }
     }
 }
 
+/// Returns the instance's current spec.
+///
+/// The spec reflects every change made to the instance since it was created,
+/// including devices attached or detached and backends replaced while it ran.
+/// Its generation increases with each such change.
 #[endpoint {
     method = GET,
     path = "/instance/spec",
@@ -737,14 +1104,32 @@ async fn instance_get_common(
 async fn instance_spec_get(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<api::InstanceSpecGetResponse>, HttpError> {
-    let (instance, spec) = instance_get_common(&rqctx).await?;
+    authenticate(&rqctx)?;
+    let (instance, mut spec, spec_generation) =
+        instance_get_common(&rqctx.context().services).await?;
+    redact_encryption_keys(&mut spec);
     Ok(HttpResponseOk(api::InstanceSpecGetResponse {
         properties: instance.properties,
         state: instance.state,
         spec,
+        spec_generation,
     }))
 }
 
+/// Replaces the encryption keys of the file backends in `spec` with a
+/// placeholder.  Any authenticated client may read the instance's spec, but
+/// not every client may read the contents of its disks.
+fn redact_encryption_keys(spec: &mut VersionedInstanceSpec) {
+    let VersionedInstanceSpec::V0(spec) = spec;
+    for backend in spec.backends.storage_backends.values_mut() {
+        if let StorageBackendV0::File(file) = backend {
+            if let Some(key) = file.encryption_key.as_mut() {
+                *key = "<redacted>".to_string();
+            }
+        }
+    }
+}
+
 #[endpoint {
     method = GET,
     path = "/instance",
@@ -752,7 +1137,8 @@ async fn instance_spec_get(
 async fn instance_get(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<api::InstanceGetResponse>, HttpError> {
-    let (instance, _) = instance_get_common(&rqctx).await?;
+    authenticate(&rqctx)?;
+    let (instance, ..) = instance_get_common(&rqctx.context().services).await?;
     Ok(HttpResponseOk(api::InstanceGetResponse { instance }))
 }
 
@@ -764,11 +1150,19 @@ async fn instance_state_monitor(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceStateMonitorRequest>,
 ) -> Result<HttpResponseOk<api::InstanceStateMonitorResponse>, HttpError> {
-    let ctx = rqctx.context();
+    authenticate(&rqctx)?;
     let gen = request.into_inner().gen;
+    state_monitor(&rqctx.context().services, gen).await
+}
+
+/// Waits for the instance's state to reach generation `gen`.
+async fn state_monitor(
+    services: &ServiceProviders,
+    gen: u64,
+) -> Result<HttpResponseOk<api::InstanceStateMonitorResponse>, HttpError> {
     let mut state_watcher = {
         // N.B. This lock must be dropped before entering the loop below.
-        let vm_state = ctx.services.vm.lock().await;
+        let vm_state = services.vm.lock().await;
         match &*vm_state {
             VmControllerState::NotCreated => {
                 return Err(not_created_error());
@@ -803,6 +1197,82 @@ async fn instance_state_monitor(
     }
 }
 
+/// Returns the history of the server's instance.
+///
+/// The history holds the most recent of the instance's state transitions,
+/// migration phases, guest halts and resets, device errors, and the requests
+/// made to change it.  It is kept after the instance is destroyed.
+#[endpoint {
+    method = GET,
+    path = "/instance/history",
+}]
+async fn instance_history(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceHistoryResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    Ok(HttpResponseOk(rqctx.context().services.history.snapshot()))
+}
+
+/// Registers a URL to be sent notifications of changes to the instance.
+///
+/// Notifications are POSTed to the URL when the instance's state changes,
+/// when a migration into or out of it finishes, and when its guest panics.
+/// Registrations last for the life of the server, and can be made before the
+/// instance is created.
+#[endpoint {
+    method = POST,
+    path = "/instance/webhooks",
+}]
+async fn instance_webhook_register(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::WebhookRegistration>,
+) -> Result<HttpResponseCreated<api::Webhook>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let webhooks = &rqctx.context().services.webhooks;
+    let hook = webhooks.register(request.into_inner()).map_err(|e| {
+        HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            e,
+        )
+    })?;
+    info!(rqctx.log, "registered webhook";
+          "id" => %hook.id, "url" => &hook.url);
+    Ok(HttpResponseCreated(hook))
+}
+
+/// Lists the URLs registered to be sent notifications of changes to the
+/// instance.
+#[endpoint {
+    method = GET,
+    path = "/instance/webhooks",
+}]
+async fn instance_webhook_list(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<Vec<api::Webhook>>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    Ok(HttpResponseOk(rqctx.context().services.webhooks.list()))
+}
+
+/// Unregisters a URL from notifications of changes to the instance.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/webhooks/{id}",
+}]
+async fn instance_webhook_unregister(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::WebhookPathParams>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let id = path_params.into_inner().id;
+    if !rqctx.context().services.webhooks.unregister(id) {
+        return Err(HttpError::for_not_found(
+            Some(api::ErrorCode::NoSuchWebhook.to_string()),
+            format!("No webhook with ID {id}"),
+        ));
+    }
+    Ok(HttpResponseDeleted())
+}
+
 #[endpoint {
     method = PUT,
     path = "/instance/state",
@@ -811,9 +1281,16 @@ async fn instance_state_put(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceStateRequested>,
 ) -> Result<HttpResponseUpdatedNoContent, HttpError> {
-    let ctx = rqctx.context();
-    let requested_state = request.into_inner();
-    let vm = ctx.vm().await?;
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    state_put(&rqctx.context().services, request.into_inner()).await
+}
+
+/// Asks the instance to move to `requested_state`.
+async fn state_put(
+    services: &ServiceProviders,
+    requested_state: api::InstanceStateRequested,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let vm = services.vm().await?;
     let result = vm
         .put_state(requested_state)
         .map(|_| HttpResponseUpdatedNoContent {})
@@ -823,7 +1300,7 @@ async fn instance_state_put(
     if result.is_ok() {
         if let api::InstanceStateRequested::Reboot = requested_state {
             let stats = MutexGuard::map(
-                ctx.services.oximeter_state.lock().await,
+                services.oximeter_state.lock().await,
                 |state| &mut state.stats,
             );
             if let Some(stats) = stats.as_ref() {
@@ -835,275 +1312,1851 @@ async fn instance_state_put(
     result
 }
 
+/// Shuts the instance down by pressing its ACPI power button, giving the guest
+/// a grace period to power itself off before the instance is stopped forcibly.
 #[endpoint {
-    method = GET,
-    path = "/instance/serial/history",
+    method = POST,
+    path = "/instance/shutdown",
 }]
-async fn instance_serial_history_get(
+async fn instance_shutdown(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    query: Query<api::InstanceSerialConsoleHistoryRequest>,
-) -> Result<HttpResponseOk<api::InstanceSerialConsoleHistoryResponse>, HttpError>
-{
-    let ctx = rqctx.context();
-    let vm = ctx.vm().await?;
-    let serial = vm.com1().clone();
-    let query_params = query.into_inner();
-
-    let byte_offset = SerialHistoryOffset::try_from(&query_params)?;
-
-    let max_bytes = query_params.max_bytes.map(|x| x as usize);
-    let (data, end) = serial
-        .history_vec(byte_offset, max_bytes)
-        .await
-        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
-
-    Ok(HttpResponseOk(api::InstanceSerialConsoleHistoryResponse {
-        data,
-        last_byte_offset: end as u64,
-    }))
+    request: TypedBody<api::InstanceShutdownRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let grace_period =
+        Duration::from_secs(request.into_inner().grace_period_secs);
+    let vm = rqctx.context().vm().await?;
+    vm.request_shutdown(grace_period)?;
+    Ok(HttpResponseUpdatedNoContent {})
 }
 
-#[channel {
-    protocol = WEBSOCKETS,
-    path = "/instance/serial",
+/// Pauses the instance's vCPUs and devices without tearing anything down,
+/// e.g. to hold its disks still while their storage is maintained. The instance
+/// is reported as `Paused` once they have all paused.
+#[endpoint {
+    method = POST,
+    path = "/instance/pause",
 }]
-async fn instance_serial(
+async fn instance_pause(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    query: Query<api::InstanceSerialConsoleStreamRequest>,
-    websock: WebsocketConnection,
-) -> dropshot::WebsocketChannelResult {
-    let ctx = rqctx.context();
-    let vm = ctx.vm().await?;
-    let serial = vm.com1().clone();
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let vm = rqctx.context().vm().await?;
+    vm.request_pause()?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
 
-    // Use the default buffering paramters for the websocket configuration
-    //
-    // Because messages are written with [`StreamExt::send`], the buffer on the
-    // websocket is flushed for every message, preventing both unecessary delays
-    // of messages and the potential for the buffer to grow without bound.
-    let config = WebSocketConfig::default();
+/// Resumes an instance paused by a request to `/instance/pause`.
+#[endpoint {
+    method = POST,
+    path = "/instance/resume",
+}]
+async fn instance_resume(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let vm = rqctx.context().vm().await?;
+    vm.request_resume()?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
 
-    let mut ws_stream = WebSocketStream::from_raw_socket(
-        websock.into_inner(),
-        Role::Server,
-        Some(config),
-    )
-    .await;
+/// Renames the instance or changes its description or metadata, returning its
+/// updated properties.
+///
+/// Properties left unset in the request are not changed. New metadata is
+/// attached to the instance's metrics from then on.
+#[endpoint {
+    method = PATCH,
+    path = "/instance/properties",
+}]
+async fn instance_properties_update(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstancePropertiesUpdateRequest>,
+) -> Result<HttpResponseOk<api::InstanceProperties>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let vm = rqctx.context().vm().await?;
+    Ok(HttpResponseOk(vm.update_properties(request.into_inner())))
+}
 
-    let byte_offset = SerialHistoryOffset::try_from(&query.into_inner()).ok();
-    if let Some(mut byte_offset) = byte_offset {
-        loop {
-            let (data, offset) = serial.history_vec(byte_offset, None).await?;
-            if data.is_empty() {
-                break;
+/// Checks that the state drivers of all of the server's instances are
+/// responsive, returning the number of instances whose VM controllers are
+/// initialized.
+async fn check_health(ctx: &DropshotEndpointContext) -> Result<u32, HttpError> {
+    let mut all = vec![ctx.services.clone()];
+    all.extend(ctx.instances.lock().await.values().cloned());
+    let mut instances = 0;
+    for services in all {
+        if let VmControllerState::Created(vm) = &*services.vm.lock().await {
+            if !vm.state_driver_responsive() {
+                return Err(HttpError::for_unavail(
+                    Some(api::ErrorCode::StateDriverUnresponsive.to_string()),
+                    format!(
+                        "the state driver of instance {} is unresponsive",
+                        vm.properties().id
+                    ),
+                ));
             }
-            ws_stream
-                .send(tokio_tungstenite::tungstenite::Message::Binary(data))
-                .await?;
-            byte_offset = SerialHistoryOffset::FromStart(offset);
+            instances += 1;
         }
     }
-
-    // Get serial task's handle and send it the websocket stream
-    ctx.services
-        .serial_task
-        .lock()
-        .await
-        .as_ref()
-        .ok_or("Instance has no serial task")?
-        .websocks_ch
-        .send(ws_stream)
-        .await
-        .map_err(|e| format!("Serial socket hand-off failed: {}", e).into())
+    Ok(instances)
 }
 
-// This endpoint is meant to only be called during a migration from the destination
-// instance to the source instance as part of the HTTP connection upgrade used to
-// establish the migration link. We don't actually want this exported via OpenAPI
-// clients.
-#[channel {
-    protocol = WEBSOCKETS,
-    path = "/instance/migrate/{migration_id}/start",
-    unpublished = true,
+/// Reports whether the server is alive.
+///
+/// Fails with 503 Service Unavailable if the state driver of any of the
+/// server's instances has spent over a minute handling a single request or
+/// event (other than a migration), in which case the server should be
+/// restarted.  Needs no token, so that the server can be monitored without
+/// one.
+#[endpoint {
+    method = GET,
+    path = "/healthz",
 }]
-async fn instance_migrate_start(
+async fn healthz(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    path_params: Path<api::InstanceMigrateStartRequest>,
-    websock: WebsocketConnection,
-) -> dropshot::WebsocketChannelResult {
-    let migration_id = path_params.into_inner().migration_id;
-    let conn = WebSocketStream::from_raw_socket(
-        websock.into_inner(),
-        Role::Server,
-        None,
-    )
-    .await;
-    crate::migrate::source_start(rqctx, migration_id, conn).await?;
-    Ok(())
+) -> Result<HttpResponseOk<api::HealthResponse>, HttpError> {
+    let instances = check_health(rqctx.context()).await?;
+    Ok(HttpResponseOk(api::HealthResponse { instances }))
 }
 
+/// Reports whether the server is ready to serve requests for an instance.
+///
+/// Fails with 503 Service Unavailable until the VM controller of one of the
+/// server's instances is initialized, and whenever `/healthz` fails.  Needs no
+/// token, so that the server can be monitored without one.
 #[endpoint {
     method = GET,
-    path = "/instance/migrate/{migration_id}/status"
+    path = "/readyz",
 }]
-async fn instance_migrate_status(
+async fn readyz(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    path_params: Path<api::InstanceMigrateStatusRequest>,
+) -> Result<HttpResponseOk<api::HealthResponse>, HttpError> {
+    let instances = check_health(rqctx.context()).await?;
+    if instances == 0 {
+        return Err(HttpError::for_unavail(
+            Some(api::ErrorCode::NoInstance.to_string()),
+            "no instance has been initialized".to_string(),
+        ));
+    }
+    Ok(HttpResponseOk(api::HealthResponse { instances }))
+}
+
+/// Lists the instances addressed by ID, including those stopped but not yet
+/// deleted.
+#[endpoint {
+    method = GET,
+    path = "/instances",
+}]
+async fn instances_list(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceListResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let all: Vec<_> =
+        rqctx.context().instances.lock().await.values().cloned().collect();
+    let mut instances = Vec::with_capacity(all.len());
+    for services in all {
+        // Instances still being created have nothing to report yet.
+        if let Ok((instance, ..)) = instance_get_common(&services).await {
+            instances.push(instance);
+        }
+    }
+    Ok(HttpResponseOk(api::InstanceListResponse { instances }))
+}
+
+/// Creates an instance addressed by ID, alongside the server's primary
+/// instance.
+///
+/// The ID in the path must match the one in the instance's properties.  The
+/// server must be configured to host instances addressed by ID.  These
+/// instances can't be migrated, and aren't exposed through the server's VNC
+/// server.
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}",
+}]
+async fn instances_ensure(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceEnsureRequest>,
+) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let id = path_params.into_inner().instance_id;
+    let request = request.into_inner();
+    let ctx = Arc::clone(rqctx.context());
+    if request.properties.id != id {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            format!(
+                "instance properties have ID {}, not {id}",
+                request.properties.id
+            ),
+        ));
+    }
+    if request.migrate.is_some() {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            "instances addressed by ID cannot be migrated".to_string(),
+        ));
+    }
+    let instance_spec = instance_spec_from_request(
+        &request,
+        &ctx.static_config.vm,
+    )
+    .map_err(|e| {
+        HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            format!("failed to generate instance spec from request: {}", e),
+        )
+    })?;
+
+    let max_instances = match &ctx.static_config.vm.multi_instance {
+        Some(cfg) => cfg.max_instances,
+        None => {
+            return Err(HttpError::for_bad_request(
+                Some(api::ErrorCode::InvalidRequest.to_string()),
+                "server is not configured to host instances by ID".to_string(),
+            ));
+        }
+    };
+    let services = {
+        let mut instances = ctx.instances.lock().await;
+        match instances.get(&id) {
+            Some(services) => services.clone(),
+            None if instances.len() >= max_instances => {
+                return Err(HttpError::for_unavail(
+                    Some(api::ErrorCode::InstanceLimitReached.to_string()),
+                    format!("server already hosts {max_instances} instances"),
+                ));
+            }
+            None => {
+                let services = Arc::new(ServiceProviders::new(
+                    None,
+                    ctx.services.webhooks.clone(),
+                    Arc::new(EventHistory::default()),
+                ));
+                instances.insert(id, services.clone());
+                services
+            }
+        }
+    };
+
+    let result = instance_ensure_common(
+        rqctx,
+        services.clone(),
+        api::InstanceSpecEnsureRequest {
+            properties: request.properties,
+            instance_spec,
+            migrate: None,
+            start_paused: request.start_paused,
+            boot_to_firmware_setup: request.boot_to_firmware_setup,
+            supervision: request.supervision,
+        },
+    )
+    .await;
+
+    // Don't keep a slot for an instance that failed to be created.
+    if result.is_err()
+        && matches!(*services.vm.lock().await, VmControllerState::NotCreated)
+    {
+        let mut instances = ctx.instances.lock().await;
+        if instances.get(&id).is_some_and(|s| Arc::ptr_eq(s, &services)) {
+            instances.remove(&id);
+        }
+    }
+    result
+}
+
+#[endpoint {
+    method = GET,
+    path = "/instances/{instance_id}",
+}]
+async fn instances_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+) -> Result<HttpResponseOk<api::InstanceGetResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let id = path_params.into_inner().instance_id;
+    let services = rqctx.context().instance(id).await?;
+    let (instance, ..) = instance_get_common(&services).await?;
+    Ok(HttpResponseOk(api::InstanceGetResponse { instance }))
+}
+
+/// Deletes a stopped instance addressed by ID, freeing its place among the
+/// instances the server may host.
+#[endpoint {
+    method = DELETE,
+    path = "/instances/{instance_id}",
+}]
+async fn instances_delete(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+) -> Result<HttpResponseDeleted, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let id = path_params.into_inner().instance_id;
+    let mut instances = rqctx.context().instances.lock().await;
+    let services = instances.get(&id).ok_or_else(|| {
+        HttpError::for_not_found(
+            Some(api::ErrorCode::NoInstance.to_string()),
+            format!("No instance with ID {id}"),
+        )
+    })?;
+    if let VmControllerState::Created(_) = &*services.vm.lock().await {
+        return Err(HttpError::for_client_error(
+            Some(api::ErrorCode::AlreadyRunning.to_string()),
+            http::status::StatusCode::CONFLICT,
+            "Cannot delete an instance that has not stopped".to_string(),
+        ));
+    }
+    instances.remove(&id);
+    Ok(HttpResponseDeleted())
+}
+
+#[endpoint {
+    method = PUT,
+    path = "/instances/{instance_id}/state",
+}]
+async fn instances_state_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceStateRequested>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let id = path_params.into_inner().instance_id;
+    let services = rqctx.context().instance(id).await?;
+    state_put(&services, request.into_inner()).await
+}
+
+#[endpoint {
+    method = GET,
+    path = "/instances/{instance_id}/state-monitor",
+}]
+async fn instances_state_monitor(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+    request: TypedBody<api::InstanceStateMonitorRequest>,
+) -> Result<HttpResponseOk<api::InstanceStateMonitorResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let id = path_params.into_inner().instance_id;
+    let services = rqctx.context().instance(id).await?;
+    state_monitor(&services, request.into_inner().gen).await
+}
+
+/// Returns the history of an instance addressed by ID.
+#[endpoint {
+    method = GET,
+    path = "/instances/{instance_id}/history",
+}]
+async fn instances_history(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+) -> Result<HttpResponseOk<api::InstanceHistoryResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let id = path_params.into_inner().instance_id;
+    let services = rqctx.context().instance(id).await?;
+    Ok(HttpResponseOk(services.history.snapshot()))
+}
+
+/// Connects to the first serial port of an instance addressed by ID via
+/// websocket, as `/instance/serial` does for the primary instance.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instances/{instance_id}/serial",
+}]
+async fn instances_serial(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstancePathParams>,
+    query: Query<api::InstanceSerialConsoleStreamRequest>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx, AuthScope::Console)?;
+    let id = path_params.into_inner().instance_id;
+    let services = rqctx.context().instance(id).await?;
+    serial_connect(
+        &services,
+        SerialPortNumber::Com1,
+        query.into_inner(),
+        websock,
+    )
+    .await
+}
+
+#[endpoint {
+    method = GET,
+    path = "/instance/serial/history",
+}]
+async fn instance_serial_history_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::InstanceSerialConsoleHistoryRequest>,
+) -> Result<HttpResponseOk<api::InstanceSerialConsoleHistoryResponse>, HttpError>
+{
+    authorize(&rqctx, AuthScope::Console)?;
+    serial_history_get(rqctx, SerialPortNumber::Com1, query.into_inner()).await
+}
+
+/// Retrieves a range of the output history of one of the instance's serial
+/// ports.
+///
+/// Only a few requests for history are served at once; those made beyond that
+/// fail with 429 Too Many Requests, and may be retried.
+#[endpoint {
+    method = GET,
+    path = "/instance/serial-ports/{port}/history",
+}]
+async fn instance_serial_port_history_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::SerialPortPathParams>,
+    query: Query<api::InstanceSerialConsoleHistoryRequest>,
+) -> Result<HttpResponseOk<api::InstanceSerialConsoleHistoryResponse>, HttpError>
+{
+    authorize(&rqctx, AuthScope::Console)?;
+    let port = path_params.into_inner().port;
+    serial_history_get(rqctx, port, query.into_inner()).await
+}
+
+async fn serial_history_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    port: SerialPortNumber,
+    query_params: api::InstanceSerialConsoleHistoryRequest,
+) -> Result<HttpResponseOk<api::InstanceSerialConsoleHistoryResponse>, HttpError>
+{
+    let ctx = rqctx.context();
+    let _permit = ctx.services.limits.serial_history()?;
+    let vm = ctx.vm().await?;
+    let serial = vm
+        .serial_port(port)
+        .ok_or_else(|| {
+            HttpError::for_not_found(
+                None,
+                format!("instance has no serial port {port:?}"),
+            )
+        })?
+        .clone();
+
+    let byte_offset = SerialHistoryOffset::try_from(&query_params)?;
+
+    let max_bytes = query_params.max_bytes.map(|x| x as usize);
+    let (data, end) =
+        serial.history_vec(byte_offset, max_bytes).await.map_err(|e| {
+            HttpError::for_bad_request(
+                Some(api::ErrorCode::InvalidRequest.to_string()),
+                e.to_string(),
+            )
+        })?;
+
+    Ok(HttpResponseOk(api::InstanceSerialConsoleHistoryResponse {
+        data,
+        last_byte_offset: end as u64,
+    }))
+}
+
+/// Sends a break to one of the instance's serial ports, optionally followed by
+/// a magic SysRq key, so that a stuck guest can be forced into its debugger or
+/// rebooted.
+#[endpoint {
+    method = POST,
+    path = "/instance/serial-ports/{port}/break",
+}]
+async fn instance_serial_port_break(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::SerialPortPathParams>,
+    request: TypedBody<api::SerialBreakRequest>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Console)?;
+    let port = path_params.into_inner().port;
+    let request = request.into_inner();
+    let then = super::serial::sysrq_bytes(request.sysrq).map_err(|e| {
+        HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            e,
+        )
+    })?;
+
+    let vm = rqctx.context().vm().await?;
+    let serial = vm
+        .serial_port(port)
+        .ok_or_else(|| {
+            HttpError::for_not_found(
+                None,
+                format!("instance has no serial port {port:?}"),
+            )
+        })?
+        .clone();
+    serial.send_break(&then).await.map_err(|e| {
+        HttpError::for_unavail(
+            Some(api::ErrorCode::OperationFailed.to_string()),
+            format!("failed to send break to {port:?}: {e}"),
+        )
+    })?;
+    info!(rqctx.log, "sent break to serial port";
+          "port" => ?port,
+          "sysrq" => ?request.sysrq);
+    Ok(HttpResponseOk(()))
+}
+
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/serial",
+}]
+async fn instance_serial(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::InstanceSerialConsoleStreamRequest>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx, AuthScope::Console)?;
+    serial_connect(
+        &rqctx.context().services,
+        SerialPortNumber::Com1,
+        query.into_inner(),
+        websock,
+    )
+    .await
+}
+
+/// Connects to one of the instance's serial ports via websocket. Its
+/// behavior is otherwise identical to that of `/instance/serial`.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/serial-ports/{port}",
+}]
+async fn instance_serial_port(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::SerialPortPathParams>,
+    query: Query<api::InstanceSerialConsoleStreamRequest>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx, AuthScope::Console)?;
+    let port = path_params.into_inner().port;
+    serial_connect(&rqctx.context().services, port, query.into_inner(), websock)
+        .await
+}
+
+async fn serial_connect(
+    services: &ServiceProviders,
+    port: SerialPortNumber,
+    query: api::InstanceSerialConsoleStreamRequest,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    let vm = services.vm().await?;
+    let serial = vm
+        .serial_port(port)
+        .ok_or_else(|| format!("Instance has no serial port {port:?}"))?
+        .clone();
+
+    // Use the default buffering paramters for the websocket configuration
+    //
+    // Because messages are written with [`StreamExt::send`], the buffer on the
+    // websocket is flushed for every message, preventing both unecessary delays
+    // of messages and the potential for the buffer to grow without bound.
+    let config = WebSocketConfig::default();
+
+    let mut ws_stream = WebSocketStream::from_raw_socket(
+        websock.into_inner(),
+        Role::Server,
+        Some(config),
+    )
+    .await;
+
+    let read_only = query.read_only.unwrap_or(false);
+    let byte_offset = SerialHistoryOffset::try_from(&query).ok();
+    if let Some(mut byte_offset) = byte_offset {
+        loop {
+            let (data, offset) = serial.history_vec(byte_offset, None).await?;
+            if data.is_empty() {
+                break;
+            }
+            ws_stream
+                .send(tokio_tungstenite::tungstenite::Message::Binary(data))
+                .await?;
+            byte_offset = SerialHistoryOffset::FromStart(offset);
+        }
+    }
+
+    // Get serial task's handle and send it the websocket stream
+    services
+        .serial_tasks
+        .lock()
+        .await
+        .get(&port)
+        .ok_or("Instance has no serial task")?
+        .websocks_ch
+        .send(super::serial::SerialClient { ws: ws_stream, read_only })
+        .await
+        .map_err(|e| format!("Serial socket hand-off failed: {}", e).into())
+}
+
+// This endpoint is meant to only be called during a migration from the destination
+// instance to the source instance as part of the HTTP connection upgrade used to
+// establish the migration link. We don't actually want this exported via OpenAPI
+// clients.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/migrate/{migration_id}/start",
+    unpublished = true,
+}]
+async fn instance_migrate_start(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstanceMigrateStartRequest>,
+    query_params: Query<api::MigrationConnectionQuery>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx, AuthScope::Migration)?;
+    let services = rqctx.context().services.clone();
+    let _permit = services.limits.migration()?;
+    let migration_id = path_params.into_inner().migration_id;
+    let raw = crate::migrate::tls::accept(
+        websock.into_inner(),
+        query_params.into_inner().tls,
+        rqctx.context().migration_config(),
+    )
+    .await?;
+    let conn = WebSocketStream::from_raw_socket(raw, Role::Server, None).await;
+    crate::migrate::source_start(rqctx, migration_id, conn).await?;
+    Ok(())
+}
+
+// Like `/start`, this endpoint is only meant to be called by the destination
+// of a migration, which opens additional connections here over which it
+// fetches guest memory in parallel.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/migrate/{migration_id}/ram-stream",
+    unpublished = true,
+}]
+async fn instance_migrate_ram_stream(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstanceMigrateStartRequest>,
+    query_params: Query<api::MigrationConnectionQuery>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx, AuthScope::Migration)?;
+    let migration_id = path_params.into_inner().migration_id;
+    let raw = crate::migrate::tls::accept(
+        websock.into_inner(),
+        query_params.into_inner().tls,
+        rqctx.context().migration_config(),
+    )
+    .await?;
+    let conn = WebSocketStream::from_raw_socket(raw, Role::Server, None).await;
+    let vm = rqctx.context().vm().await?;
+    vm.add_migration_ram_stream(migration_id, conn)?;
+    Ok(())
+}
+
+// Like `/start`, this endpoint is only meant to be called by another Propolis,
+// here one checking whether the instance could migrate to it.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/migration-check",
+    unpublished = true,
+}]
+async fn instance_migrate_check_source(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx, AuthScope::Migration)?;
+    let conn = WebSocketStream::from_raw_socket(
+        websock.into_inner(),
+        Role::Server,
+        None,
+    )
+    .await;
+    let vm = rqctx.context().vm().await?.clone();
+    crate::migrate::check::source_check(&vm, conn, &rqctx.log).await?;
+    Ok(())
+}
+
+/// Checks, without disturbing either instance, whether an instance could be
+/// migrated here from a source instance, by exchanging the source's device
+/// manifest, supported protocols and host CPU features with it.
+#[endpoint {
+    method = POST,
+    path = "/instance/migration-check"
+}]
+async fn instance_migrate_check(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceMigrateCheckRequest>,
+) -> Result<HttpResponseOk<api::InstanceMigrateCheckResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Migration)?;
+    let request = request.into_inner();
+    let spec = match request.instance_spec {
+        Some(spec) => spec,
+        None => rqctx.context().vm().await?.instance_spec().await.clone(),
+    };
+    let connector = SourceConnector::new(rqctx.context(), None)?;
+    let incompatibilities = crate::migrate::check::dest_check(
+        request.src_addr,
+        &spec,
+        &connector,
+        &rqctx.log,
+    )
+    .await?;
+    Ok(HttpResponseOk(api::InstanceMigrateCheckResponse { incompatibilities }))
+}
+
+#[endpoint {
+    method = GET,
+    path = "/instance/migrate/{migration_id}/status"
+}]
+async fn instance_migrate_status(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstanceMigrateStatusRequest>,
 ) -> Result<HttpResponseOk<api::InstanceMigrateStatusResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let migration_id = path_params.into_inner().migration_id;
+    let ctx = rqctx.context();
+    match &*ctx.services.vm.lock().await {
+        VmControllerState::NotCreated => Err(not_created_error()),
+        VmControllerState::Created(vm) => vm
+            .migrate_status(migration_id)
+            .map_err(Into::into)
+            .map(HttpResponseOk),
+        VmControllerState::Destroyed { state_watcher, .. } => {
+            let watcher = state_watcher.borrow();
+            match &watcher.migration {
+                None => Err((MigrateError::NoMigrationInProgress).into()),
+                Some(migration_status)
+                    if migration_status.migration_id == migration_id =>
+                {
+                    Ok(HttpResponseOk(migration_status.clone()))
+                }
+                Some(_) => Err((MigrateError::UuidMismatch).into()),
+            }
+        }
+    }
+}
+
+/// Reports the progress of a live migration into or out of the instance: its
+/// phase, how much guest memory has been transferred, and how much remains in
+/// the current round of the transfer.
+#[endpoint {
+    method = GET,
+    path = "/instance/migrate/{migration_id}/progress"
+}]
+async fn instance_migrate_progress(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstanceMigrateStatusRequest>,
+) -> Result<HttpResponseOk<api::InstanceMigrateProgressResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let migration_id = path_params.into_inner().migration_id;
+    let vm = rqctx.context().vm().await?;
+    let (state, progress) = vm.migrate_progress(migration_id)?;
+    Ok(HttpResponseOk(api::InstanceMigrateProgressResponse {
+        migration_id,
+        state,
+        bytes_transferred: progress.bytes_transferred,
+        iteration: progress.iteration,
+        dirty_pages_remaining: progress.dirty_pages_remaining,
+        dirty_pages_per_iteration: progress.dirty_pages_per_iteration,
+        estimated_remaining_ms: progress
+            .estimated_remaining
+            .map(|d| d.as_millis() as u64),
+        clock_skew_ms: progress.clock_skew.map(|d| d.as_millis() as u64),
+    }))
+}
+
+/// Cancels an outbound live migration that has not yet handed control of the
+/// guest to the destination. The instance resumes running here, and the
+/// destination is told that the migration failed.
+#[endpoint {
+    method = POST,
+    path = "/instance/migrate/{migration_id}/cancel"
+}]
+async fn instance_migrate_cancel(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::InstanceMigrateStatusRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Migration)?;
     let migration_id = path_params.into_inner().migration_id;
+    let vm = rqctx.context().vm().await?;
+    vm.cancel_migration(migration_id)?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Limits the rate at which guest memory is sent during live migrations out
+/// of the instance, including one already underway.
+#[endpoint {
+    method = PUT,
+    path = "/instance/migration-bandwidth"
+}]
+async fn instance_migrate_bandwidth_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceMigrateBandwidthRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Migration)?;
+    let max_mbps = request.into_inner().max_bandwidth_mbps;
+    if max_mbps == Some(0) {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            "bandwidth limit must be nonzero".to_string(),
+        ));
+    }
+    let vm = rqctx.context().vm().await?;
+    vm.set_migration_bandwidth(max_mbps);
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Checks that `name`, the name of a `kind` file to be created by a request,
+/// names a file within a directory rather than a path leading out of it.
+fn check_file_name(name: &str, kind: &str) -> Result<(), HttpError> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            format!("invalid {kind} file name {name:?}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the path of the saved-state file named `name`, which must lie in
+/// the directory configured for saved state.
+fn saved_state_path(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+    name: &str,
+) -> Result<std::path::PathBuf, HttpError> {
+    let Some(cfg) = rqctx.context().static_config.vm.saved_state.as_ref()
+    else {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            "no directory is configured for saved state".to_string(),
+        ));
+    };
+    check_file_name(name, "saved-state")?;
+    Ok(cfg.directory.join(name))
+}
+
+/// Returns the path of the memory dump named `name`, which must lie in the
+/// directory configured for memory dumps.
+fn memory_dump_path(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+    name: &str,
+) -> Result<std::path::PathBuf, HttpError> {
+    let Some(cfg) = rqctx.context().static_config.vm.memory_dump.as_ref()
+    else {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            "no directory is configured for memory dumps".to_string(),
+        ));
+    };
+    check_file_name(name, "memory dump")?;
+    Ok(cfg.directory.join(name))
+}
+
+/// Saves the instance's state, including guest memory and the state of its
+/// devices, to a file on the host. The instance stops once its state has been
+/// saved.
+#[endpoint {
+    method = POST,
+    path = "/instance/save"
+}]
+async fn instance_save(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceSavedStateRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let path = saved_state_path(&rqctx, &request.into_inner().name)?;
+    let vm = rqctx.context().vm().await?.clone();
+    crate::migrate::save::save(vm, &path, rqctx.server.local_addr, &rqctx.log)
+        .await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Restores a newly created instance, which must have the same spec as the
+/// instance whose state was saved, from a saved-state file on the host.
+#[endpoint {
+    method = POST,
+    path = "/instance/restore"
+}]
+async fn instance_restore(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceSavedStateRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let path = saved_state_path(&rqctx, &request.into_inner().name)?;
+    let vm = rqctx.context().vm().await?.clone();
+    crate::migrate::save::restore(
+        vm,
+        &path,
+        rqctx.server.local_addr,
+        &rqctx.log,
+    )
+    .await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Shuts down the server, first preserving the state of its instances.
+///
+/// With `mode=save`, the state of each of the server's running or paused
+/// instances is saved, as by `/instance/save`, to a file named with the
+/// instance's ID, from which an instance created by a new server can be
+/// restored.  The instances stop once their state has been saved, and the
+/// server exits once it has responded.  If any instance's state can't be
+/// saved, the server doesn't shut down, though instances whose state has
+/// already been saved have stopped.
+#[endpoint {
+    method = POST,
+    path = "/server/shutdown"
+}]
+async fn server_shutdown(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::ServerShutdownRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
     let ctx = rqctx.context();
-    match &*ctx.services.vm.lock().await {
-        VmControllerState::NotCreated => Err(not_created_error()),
-        VmControllerState::Created(vm) => {
-            vm.migrate_status(migration_id).map_err(Into::into).map(|state| {
-                HttpResponseOk(api::InstanceMigrateStatusResponse {
-                    migration_id,
-                    state,
-                })
+    match query.into_inner().mode {
+        api::ServerShutdownMode::Save => {
+            let mut all = vec![ctx.services.clone()];
+            all.extend(ctx.instances.lock().await.values().cloned());
+            for services in all {
+                let Ok(vm) = services.vm().await.map(|vm| vm.clone()) else {
+                    continue;
+                };
+                // Instances which have stopped or failed have no state worth
+                // preserving.
+                if matches!(
+                    vm.external_instance_state(),
+                    api::InstanceState::Stopping
+                        | api::InstanceState::Stopped
+                        | api::InstanceState::Failed
+                        | api::InstanceState::Destroyed
+                ) {
+                    continue;
+                }
+                let id = vm.properties().id;
+                let path = saved_state_path(&rqctx, &id.to_string())?;
+                crate::migrate::save::save(
+                    vm,
+                    &path,
+                    rqctx.server.local_addr,
+                    &rqctx.log,
+                )
+                .await?;
+                info!(rqctx.log, "saved instance state for shutdown";
+                      "instance" => %id, "path" => %path.display());
+            }
+        }
+    }
+
+    info!(rqctx.log, "shutting down server as requested");
+    ctx.shutdown.notify_one();
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Resumes an instance paused for a dump of its memory.
+fn resume_after_memory_dump(vm: &VmController, log: &Logger) {
+    if let Err(e) = vm.request_resume() {
+        warn!(log, "failed to resume instance after memory dump";
+              "error" => %e);
+    }
+}
+
+/// Writes a dump of the instance's memory, as an ELF core file, to a new file
+/// on the host, readable only by the server's user.
+///
+/// If `pause` is set, the instance is paused while its memory is dumped, and
+/// is resumed afterwards unless it was paused already.
+#[endpoint {
+    method = POST,
+    path = "/instance/memory-dump"
+}]
+async fn instance_memory_dump(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceMemoryDumpRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let api::InstanceMemoryDumpRequest { name, pause } = request.into_inner();
+    let path = memory_dump_path(&rqctx, &name)?;
+    let vm = rqctx.context().vm().await?.clone();
+
+    // The dump holds all of guest memory, so keep it from other users, and
+    // never write it over an existing file.
+    let file = {
+        use std::os::unix::fs::OpenOptionsExt;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+    }
+    .map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            format!("memory dump file {name:?} already exists"),
+        ),
+        _ => HttpError::for_internal_error(format!(
+            "failed to create memory dump file: {e}"
+        )),
+    })?;
+    let resume = if pause {
+        memdump::pause(&vm).await.map_err(|e| {
+            let _ = std::fs::remove_file(&path);
+            HttpError::from(e)
+        })?
+    } else {
+        false
+    };
+
+    info!(rqctx.log, "writing memory dump"; "path" => %path.display());
+    let dump = MemoryDump::new(vm.clone());
+    let res = tokio::task::spawn_blocking(move || {
+        let mut file = std::io::BufWriter::new(file);
+        dump.write_to(&mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()
+    })
+    .await
+    .expect("memory dump should not panic");
+    if resume {
+        resume_after_memory_dump(&vm, &rqctx.log);
+    }
+
+    res.map_err(|e| {
+        let _ = std::fs::remove_file(&path);
+        HttpError::for_internal_error(format!(
+            "failed to write memory dump: {e}"
+        ))
+    })?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Streams a dump of the instance's memory as an ELF core file.
+///
+/// If `pause` is set, the instance is paused while its memory is dumped, and
+/// is resumed afterwards unless it was paused already.
+#[endpoint {
+    method = GET,
+    path = "/instance/memory-dump"
+}]
+async fn instance_memory_dump_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::InstanceMemoryDumpQuery>,
+) -> Result<Response<Body>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let pause = query.into_inner().pause;
+    let vm = rqctx.context().vm().await?.clone();
+    let resume = pause && memdump::pause(&vm).await?;
+
+    let mut dump = MemoryDump::new(vm.clone());
+    let length = dump.size();
+    let log = rqctx.log.new(o!("component" => "memory_dump"));
+    info!(log, "streaming memory dump"; "length" => length);
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let (returned, chunk) = tokio::task::spawn_blocking(move || {
+                let chunk = dump.next_chunk();
+                (dump, chunk)
             })
+            .await
+            .expect("memory dump reads should not panic");
+            dump = returned;
+
+            match chunk {
+                None => break,
+                Some(Ok(buf)) => {
+                    if tx.send_data(buf.into()).await.is_err() {
+                        warn!(log, "memory dump client went away");
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    error!(log, "failed to read memory for dump";
+                           "error" => %e);
+                    tx.abort();
+                    break;
+                }
+            }
         }
-        VmControllerState::Destroyed { state_watcher, .. } => {
-            let watcher = state_watcher.borrow();
-            match &watcher.migration {
-                None => Err((MigrateError::NoMigrationInProgress).into()),
-                Some(migration_status)
-                    if migration_status.migration_id == migration_id =>
-                {
-                    Ok(HttpResponseOk(migration_status.clone()))
+
+        info!(log, "memory dump finished");
+        if resume {
+            resume_after_memory_dump(&vm, &log);
+        }
+    });
+
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(http::header::CONTENT_LENGTH, length)
+        .body(body)
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+/// Issues a snapshot request to a crucible backend.
+///
+/// Only one of the instance's disks is snapshotted at a time; a request made
+/// while a snapshot is being taken fails with 409 Conflict.
+#[endpoint {
+    method = POST,
+    path = "/instance/disk/{id}/snapshot/{snapshot_id}",
+}]
+async fn instance_issue_crucible_snapshot_request(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::SnapshotRequestPathParams>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let _permit = rqctx.context().services.limits.snapshot()?;
+    let inst = rqctx.context().vm().await?;
+    let path_params = path_params.into_inner();
+
+    let backend = inst.crucible_backend(&path_params.id).ok_or_else(|| {
+        no_such_device(format!("no disk with id {}!", path_params.id))
+    })?;
+    backend.snapshot(path_params.snapshot_id).await.map_err(|e| {
+        HttpError::for_bad_request(
+            Some(api::ErrorCode::OperationFailed.to_string()),
+            e.to_string(),
+        )
+    })?;
+
+    Ok(HttpResponseOk(()))
+}
+
+/// Gets the status of a Crucible volume backing a disk
+#[endpoint {
+    method = GET,
+    path = "/instance/disk/{id}/status",
+}]
+async fn disk_volume_status(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::VolumeStatusPathParams>,
+) -> Result<HttpResponseOk<api::VolumeStatus>, HttpError> {
+    authenticate(&rqctx)?;
+    let path_params = path_params.into_inner();
+
+    let vm_controller = rqctx.context().vm().await?;
+
+    let backend =
+        vm_controller.crucible_backend(&path_params.id).ok_or_else(|| {
+            no_such_device(format!(
+                "No crucible backend for id {}",
+                path_params.id
+            ))
+        })?;
+
+    Ok(HttpResponseOk(api::VolumeStatus {
+        active: backend.volume_is_active().await.map_err(|e| {
+            HttpError::for_bad_request(
+                Some(api::ErrorCode::BackendUnreachable.to_string()),
+                e.to_string(),
+            )
+        })?,
+    }))
+}
+
+/// Issues a volume_construction_request replace to a crucible backend.
+#[endpoint {
+    method = PUT,
+    path = "/instance/disk/{id}/vcr",
+}]
+async fn instance_issue_crucible_vcr_request(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::VCRRequestPathParams>,
+    request: TypedBody<api::InstanceVCRReplace>,
+) -> Result<HttpResponseOk<crucible_client_types::ReplaceResult>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let path_params = path_params.into_inner();
+    let request = request.into_inner();
+    let new_vcr_json = request.vcr_json;
+    let disk_name = request.name;
+    let log = rqctx.log.clone();
+
+    // Get the instance spec for storage backend from the disk name.  We use
+    // the VCR stored there to send to crucible along with the new VCR we want
+    // to replace it.
+    let vm_controller = rqctx.context().vm().await?;
+
+    // TODO(#205): Mutating a VM's configuration should be a first-class
+    // operation in the VM controller that synchronizes with ongoing migrations
+    // and other attempts to mutate the VM. For the time being, use the instance
+    // spec lock to exclude other concurrent attempts to reconfigure this
+    // backend.
+    let mut spec = vm_controller.instance_spec().await;
+    let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+
+    let (readonly, error_policy, lazy_attach, old_vcr_json) = {
+        let bes = &v0_spec.backends.storage_backends.get(&disk_name);
+        if let Some(StorageBackendV0::Crucible(bes)) = bes {
+            (bes.readonly, bes.error_policy, bes.lazy_attach, &bes.request_json)
+        } else {
+            return Err(no_such_device(format!(
+                "Crucible backend for {:?} not found",
+                disk_name
+            )));
+        }
+    };
+
+    // Get the crucible backend so we can call the replacement method on it.
+    let backend =
+        vm_controller.crucible_backend(&path_params.id).ok_or_else(|| {
+            no_such_device(format!(
+                "No crucible backend for id {}",
+                path_params.id
+            ))
+        })?;
+
+    slog::info!(
+        log,
+        "{:?} {:?} vcr replace requested",
+        disk_name,
+        path_params.id,
+    );
+
+    // Try the replacement.
+    // Crucible does the heavy lifting here to verify that the old/new
+    // VCRs are different in just the correct way and will return error
+    // if there is any mismatch.
+    let replace_result = backend
+        .vcr_replace(old_vcr_json, &new_vcr_json)
+        .await
+        .map_err(|e| {
+            HttpError::for_bad_request(
+                Some(api::ErrorCode::InvalidRequest.to_string()),
+                e.to_string(),
+            )
+        })?;
+
+    // Our replacement request was accepted.  We now need to update the
+    // spec stored in propolis so it matches what the downstairs now has.
+    let new_storage_backend: StorageBackendV0 =
+        StorageBackendV0::Crucible(CrucibleStorageBackend {
+            readonly,
+            request_json: new_vcr_json,
+            error_policy,
+            lazy_attach,
+        });
+    v0_spec.backends.storage_backends.insert(disk_name, new_storage_backend);
+    vm_controller.note_spec_change();
+
+    slog::info!(log, "Replaced the VCR in backend of {:?}", path_params.id);
+
+    Ok(HttpResponseOk(replace_result))
+}
+
+/// Streams the contents of a range of one of the instance's disks.
+///
+/// The disk's device stops accepting new I/O from the guest, and waits for any
+/// of its in-flight I/O to complete, while the range is copied to a staging
+/// file on the host, so the exported range is a crash-consistent copy of the
+/// disk as of the time of the request.  Guest I/O to the disk resumes once the
+/// copy is made, and the copy is then streamed to the client.  The rest of the
+/// instance keeps running throughout.  The instance must be running.
+#[endpoint {
+    method = GET,
+    path = "/instance/disks/{name}/export",
+}]
+async fn instance_disk_export(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    query_params: Query<api::DiskExportRequest>,
+) -> Result<Response<Body>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let query = query_params.into_inner();
+    let vm = rqctx.context().vm().await?.clone();
+    let StorageDevice { backend, .. } = vm
+        .storage_device(&name)
+        .ok_or_else(|| no_such_device(format!("no disk named {name:?}")))?;
+
+    let info = backend.info();
+    let block_size = u64::from(info.block_size);
+    let disk_size = info.total_size * block_size;
+    let offset = query.offset.unwrap_or(0);
+    let length = query.length.unwrap_or(disk_size.saturating_sub(offset));
+    if offset % block_size != 0 || length % block_size != 0 {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            format!(
+                "offset and length must be multiples of the disk's block \
+                size ({block_size})"
+            ),
+        ));
+    }
+    if !offset.checked_add(length).is_some_and(|end| end <= disk_size) {
+        return Err(HttpError::for_bad_request(
+            Some(api::ErrorCode::InvalidRequest.to_string()),
+            format!(
+                "range of {length} bytes at offset {offset} extends past the \
+                end of the disk ({disk_size} bytes)"
+            ),
+        ));
+    }
+
+    let log = rqctx.log.new(o!("disk" => name.clone()));
+    let (StorageDevice { backend, .. }, hold) =
+        vm.hold_storage_device(&name).await?;
+    info!(log, "staging disk export"; "offset" => offset, "length" => length);
+    let staged = tokio::task::spawn_blocking(move || {
+        disk_export::stage(&*backend, offset, length)
+    })
+    .await
+    .expect("disk export staging should not panic");
+    drop(hold);
+    let mut file = staged.map_err(|e| {
+        error!(log, "failed to stage disk export"; "error" => %e);
+        let mut error = HttpError::for_internal_error(format!(
+            "failed to read disk for export: {e}"
+        ));
+        error.error_code = Some(api::ErrorCode::OperationFailed.to_string());
+        error
+    })?;
+
+    info!(log, "streaming disk export, resumed guest I/O");
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let (returned, chunk) = tokio::task::spawn_blocking(move || {
+                let chunk = disk_export::next_chunk(&mut file);
+                (file, chunk)
+            })
+            .await
+            .expect("disk export reads should not panic");
+            file = returned;
+
+            match chunk {
+                None => break,
+                Some(Ok(buf)) => {
+                    if tx.send_data(buf.into()).await.is_err() {
+                        warn!(log, "disk export client went away");
+                        break;
+                    }
                 }
-                Some(_) => Err((MigrateError::UuidMismatch).into()),
+                Some(Err(e)) => {
+                    error!(log, "failed to read staged disk export";
+                           "error" => %e);
+                    tx.abort();
+                    break;
+                }
+            }
+        }
+        info!(log, "disk export finished");
+    });
+
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(http::header::CONTENT_LENGTH, length)
+        .body(body)
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+/// Snapshots a file-backed disk by cloning its backing file.
+///
+/// The snapshot is created alongside the backing file, and shares its blocks
+/// rather than duplicating its data, so the host's file system must support
+/// cloning files.  The disk's device stops accepting new I/O from the guest,
+/// and waits for any of its in-flight I/O to complete, before the clone is
+/// made, so the snapshot is crash-consistent.  Guest I/O to the disk resumes
+/// once the clone has been made.  The instance must be running.
+///
+/// Only one of the instance's disks is snapshotted at a time; a request made
+/// while a snapshot is being taken fails with 409 Conflict.
+#[endpoint {
+    method = POST,
+    path = "/instance/disks/{name}/snapshot",
+}]
+async fn instance_disk_snapshot(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskSnapshotRequest>,
+) -> Result<HttpResponseOk<api::DiskSnapshotResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let _permit = rqctx.context().services.limits.snapshot()?;
+    let name = path_params.into_inner().name;
+    let request = request.into_inner();
+    if let Some(snapshot_name) = &request.name {
+        check_file_name(snapshot_name, "snapshot")?;
+    }
+    let vm = rqctx.context().vm().await?.clone();
+    let not_found = || no_such_device(format!("no disk named {name:?}"));
+
+    let source = {
+        let spec = vm.instance_spec().await;
+        let VersionedInstanceSpec::V0(spec) = &*spec;
+        let backend_name = spec
+            .devices
+            .storage_devices
+            .get(&name)
+            .ok_or_else(not_found)?
+            .backend_name();
+        match spec.backends.storage_backends.get(backend_name) {
+            Some(StorageBackendV0::File(file)) => file.path.clone(),
+            _ => {
+                return Err(HttpError::for_bad_request(
+                    Some(api::ErrorCode::InvalidRequest.to_string()),
+                    format!("disk {name:?} is not backed by a file"),
+                ));
             }
         }
+    };
+    let source_path = std::path::Path::new(&source);
+    let snapshot_name = request.name.unwrap_or_else(|| {
+        let file_name = source_path.file_name().unwrap_or_default();
+        format!(
+            "{}.snapshot-{}",
+            file_name.to_string_lossy(),
+            uuid::Uuid::new_v4()
+        )
+    });
+    let dest = source_path
+        .with_file_name(snapshot_name)
+        .to_string_lossy()
+        .into_owned();
+
+    let log = rqctx.log.new(o!("disk" => name.clone()));
+    let (_, hold) = vm.hold_storage_device(&name).await?;
+    info!(log, "snapshotting disk"; "source" => &source, "dest" => &dest);
+    let clone_dest = dest.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        crate::snapshot::clone_file(&source, &clone_dest)
+    })
+    .await
+    .expect("disk snapshot should not panic");
+
+    drop(hold);
+    info!(log, "disk snapshot finished, resumed guest I/O");
+
+    match res {
+        Ok(()) => Ok(HttpResponseOk(api::DiskSnapshotResponse { path: dest })),
+        Err(e) => {
+            error!(log, "failed to snapshot disk"; "error" => %e);
+            Err(HttpError::for_internal_error(format!(
+                "failed to snapshot disk: {e}"
+            )))
+        }
     }
 }
 
-/// Issues a snapshot request to a crucible backend.
+/// Snapshots all of the instance's disks together.
+///
+/// Disks backed by writable files are cloned alongside their backing files,
+/// and those backed by Crucible volumes are snapshotted by Crucible, with the
+/// snapshot's ID.  Guest I/O to all of the disks is held until every disk has
+/// been snapshotted, so the snapshots capture the disks at a single point in
+/// time.  The instance must be running.  If `quiesce` is set, the guest's file
+/// systems are also frozen through its agent while the snapshots are taken.
+///
+/// Only one snapshot is taken at a time; a request made while another snapshot
+/// is being taken fails with 409 Conflict.
+#[endpoint {
+    method = POST,
+    path = "/instance/snapshot",
+}]
+async fn instance_snapshot(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceSnapshotRequest>,
+) -> Result<HttpResponseOk<api::InstanceSnapshotResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let _permit = rqctx.context().services.limits.snapshot()?;
+    let vm = rqctx.context().vm().await?.clone();
+    if vm.external_instance_state() != api::InstanceState::Running {
+        return Err(VmControllerError::InstanceNotActive.into());
+    }
+
+    let quiesce = request.into_inner().quiesce;
+    let snapshot =
+        crate::snapshot::snapshot_instance(&vm, quiesce, &rqctx.log).await?;
+    Ok(HttpResponseOk(snapshot))
+}
+
+/// Clones the instance, which must be paused, by copying its disks.
+///
+/// Each writable file-backed disk is copied in full alongside its backing file,
+/// as is its integrity sidecar if it has one, and the spec returned is the
+/// instance's, with those disks backed by the copies instead.  An instance
+/// created with that spec runs the clone.  Read-only disks are shared with the
+/// clone, and writable disks backed by Crucible or held in memory can't be
+/// copied.  The clone's network backends are the instance's, so must be
+/// replaced before the clone can run on the same host.  If the instance is
+/// resumed before its disks have been copied, the clone fails.
+#[endpoint {
+    method = POST,
+    path = "/instance/clone",
+}]
+async fn instance_clone(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceCloneResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let vm = rqctx.context().vm().await?.clone();
+    let clone = crate::clone::clone_instance(&vm, &rqctx.log).await?;
+    Ok(HttpResponseOk(clone))
+}
+
+/// Determines the status of the disk named `name`.
+async fn disk_status(
+    name: &str,
+    disk: StorageDevice,
+) -> Result<api::DiskStatus, HttpError> {
+    use propolis::block::{ActivationState, ReplicaHealth};
+
+    // Only Crucible backends need to attach to anything before servicing I/O.
+    let state = match disk.crucible.as_ref().map(|be| be.activation_state()) {
+        Some(ActivationState::Inactive) => api::DiskState::Inactive,
+        Some(ActivationState::Activating { attempts, last_error }) => {
+            api::DiskState::Attaching { attempts, last_error }
+        }
+        Some(ActivationState::Active) | None => api::DiskState::Active,
+        Some(ActivationState::Failed { attempts, error }) => {
+            api::DiskState::Failed { attempts, error }
+        }
+    };
+
+    let replicas = match disk.crucible {
+        Some(be) => {
+            let health = be.replica_health().await.map_err(|e| {
+                HttpError::for_internal_error(format!(
+                    "failed to query replicas of disk {name:?}: {e}"
+                ))
+            })?;
+            Some(
+                health
+                    .into_iter()
+                    .map(|h| match h {
+                        ReplicaHealth::Connecting => {
+                            api::ReplicaState::Connecting
+                        }
+                        ReplicaHealth::Active => api::ReplicaState::Active,
+                        ReplicaHealth::LiveRepair => {
+                            api::ReplicaState::LiveRepair
+                        }
+                        ReplicaHealth::Offline => api::ReplicaState::Offline,
+                        ReplicaHealth::Faulted => api::ReplicaState::Faulted,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
+        None => None,
+    };
+    let degraded = replicas.as_ref().is_some_and(|r| {
+        r.iter().any(|s| !matches!(s, api::ReplicaState::Active))
+    });
+
+    Ok(api::DiskStatus { state, replicas, degraded })
+}
+
+/// Reports the status of each of the instance's disks.
+///
+/// An instance whose Crucible disks are attached lazily may run while some of
+/// them are still attaching; this shows which.
+#[endpoint {
+    method = GET,
+    path = "/instance/disks",
+}]
+async fn instance_disks_status(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceDiskStatusResponse>, HttpError> {
+    authenticate(&rqctx)?;
+    let devices = rqctx.context().vm().await?.storage_devices();
+    let mut disks = BTreeMap::new();
+    for (name, disk) in devices {
+        let status = disk_status(&name, disk).await?;
+        disks.insert(name, status);
+    }
+    Ok(HttpResponseOk(api::InstanceDiskStatusResponse { disks }))
+}
+
+/// Reports the status of one of the instance's disks.
+#[endpoint {
+    method = GET,
+    path = "/instance/disks/{name}/status",
+}]
+async fn instance_disk_status(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+) -> Result<HttpResponseOk<api::DiskStatus>, HttpError> {
+    authenticate(&rqctx)?;
+    let name = path_params.into_inner().name;
+    let disk = rqctx
+        .context()
+        .vm()
+        .await?
+        .storage_device(&name)
+        .ok_or_else(|| no_such_device(format!("no disk named {name:?}")))?;
+    Ok(HttpResponseOk(disk_status(&name, disk).await?))
+}
+
+/// Replaces the backend of one of the instance's disks, without detaching the
+/// disk from the guest.
+///
+/// Guest I/O to the disk is held while the existing backend is stopped and the
+/// new one started in its place.  The new backend must have the same block
+/// size and capacity as the existing one.  If the new backend cannot be
+/// started, the existing one is restored.  The instance must be running.
 #[endpoint {
-    method = POST,
-    path = "/instance/disk/{id}/snapshot/{snapshot_id}",
+    method = PUT,
+    path = "/instance/disks/{name}/backend",
 }]
-async fn instance_issue_crucible_snapshot_request(
+async fn instance_disk_backend_replace(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    path_params: Path<api::SnapshotRequestPathParams>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskBackendReplaceRequest>,
 ) -> Result<HttpResponseOk<()>, HttpError> {
-    let inst = rqctx.context().vm().await?;
-    let crucible_backends = inst.crucible_backends();
-    let path_params = path_params.into_inner();
-
-    let backend = crucible_backends.get(&path_params.id).ok_or_else(|| {
-        let s = format!("no disk with id {}!", path_params.id);
-        HttpError::for_not_found(Some(s.clone()), s)
-    })?;
-    backend.snapshot(path_params.snapshot_id).await.map_err(|e| {
-        HttpError::for_bad_request(Some(e.to_string()), e.to_string())
-    })?;
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let api::DiskBackendReplaceRequest { backend_name, backend } =
+        request.into_inner();
+    let vm = rqctx.context().vm().await?.clone();
+    tokio::task::spawn_blocking(move || {
+        vm.replace_storage_backend(&name, backend_name, backend)
+    })
+    .await
+    .expect("backend replacement should not panic")?;
 
     Ok(HttpResponseOk(()))
 }
 
-/// Gets the status of a Crucible volume backing a disk
+/// Changes the media in one of the instance's CD-ROM drives.
+///
+/// New media is inserted by replacing the drive's backend, after which the
+/// guest is told that the media has changed.  Ejecting the media leaves the
+/// existing backend in place, but the drive appears empty to the guest until
+/// media is inserted again.
 #[endpoint {
-    method = GET,
-    path = "/instance/disk/{id}/status",
+    method = PUT,
+    path = "/instance/cdrom/{name}/media",
 }]
-async fn disk_volume_status(
+async fn instance_cdrom_media_put(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    path_params: Path<api::VolumeStatusPathParams>,
-) -> Result<HttpResponseOk<api::VolumeStatus>, HttpError> {
-    let path_params = path_params.into_inner();
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::CdromMediaRequest>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let api::CdromMediaRequest { media } = request.into_inner();
+    let vm = rqctx.context().vm().await?.clone();
 
-    let vm_controller = rqctx.context().vm().await?;
+    if vm.external_instance_state() != api::InstanceState::Running {
+        return Err(VmControllerError::InstanceNotActive.into());
+    }
 
-    let crucible_backends = vm_controller.crucible_backends();
-    let backend = crucible_backends.get(&path_params.id).ok_or_else(|| {
-        let s = format!("No crucible backend for id {}", path_params.id);
-        HttpError::for_not_found(Some(s.clone()), s)
-    })?;
+    tokio::task::spawn_blocking(move || vm.change_cdrom_media(&name, media))
+        .await
+        .expect("CD-ROM media change should not panic")?;
 
-    Ok(HttpResponseOk(api::VolumeStatus {
-        active: backend.volume_is_active().await.map_err(|e| {
-            HttpError::for_bad_request(Some(e.to_string()), e.to_string())
-        })?,
-    }))
+    Ok(HttpResponseOk(()))
 }
 
-/// Issues a volume_construction_request replace to a crucible backend.
+/// Attaches a new NVMe disk to the instance by inserting it into the empty
+/// PCIe hotplug slot of the bridge above the disk's PCI path.
+///
+/// The disk is added to the instance spec once the guest has been told of its
+/// arrival.
 #[endpoint {
     method = PUT,
-    path = "/instance/disk/{id}/vcr",
+    path = "/instance/disks/{name}",
 }]
-async fn instance_issue_crucible_vcr_request(
+async fn instance_disk_attach(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    path_params: Path<api::VCRRequestPathParams>,
-    request: TypedBody<api::InstanceVCRReplace>,
-) -> Result<HttpResponseOk<crucible_client_types::ReplaceResult>, HttpError> {
-    let path_params = path_params.into_inner();
-    let request = request.into_inner();
-    let new_vcr_json = request.vcr_json;
-    let disk_name = request.name;
-    let log = rqctx.log.clone();
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskAttachRequest>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let api::DiskAttachRequest { device, backend } = request.into_inner();
+    let vm = rqctx.context().vm().await?.clone();
+    vm.attach_disk(name, device, backend).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    // Get the instance spec for storage backend from the disk name.  We use
-    // the VCR stored there to send to crucible along with the new VCR we want
-    // to replace it.
-    let vm_controller = rqctx.context().vm().await?;
+/// Detaches a hotplugged NVMe disk from the instance.
+///
+/// The attention button of the disk's hotplug slot is pressed, and the disk
+/// is removed once the guest has released it and powered off the slot.  The
+/// instance must be running.  If the guest does not power off the slot within
+/// 30 seconds, the request fails, and the button press is withdrawn if the
+/// guest has yet to notice it.  Should the guest power off the slot later
+/// anyway, the disk is removed by the next request to detach it.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/disks/{name}",
+}]
+async fn instance_disk_detach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let vm = rqctx.context().vm().await?.clone();
+    vm.detach_disk(name).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    // TODO(#205): Mutating a VM's configuration should be a first-class
-    // operation in the VM controller that synchronizes with ongoing migrations
-    // and other attempts to mutate the VM. For the time being, use the instance
-    // spec lock to exclude other concurrent attempts to reconfigure this
-    // backend.
-    let mut spec = vm_controller.instance_spec().await;
-    let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+/// Attaches a new virtio NIC to the instance by inserting it into the empty
+/// PCIe hotplug slot of the bridge above the NIC's PCI path.
+///
+/// The NIC and its backend are added to the instance spec once the guest has
+/// been told of its arrival, so they are recreated if the instance migrates.
+#[endpoint {
+    method = PUT,
+    path = "/instance/nics/{name}",
+}]
+async fn instance_nic_attach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+    request: TypedBody<api::NicAttachRequest>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let api::NicAttachRequest { device, backend } = request.into_inner();
+    let vm = rqctx.context().vm().await?.clone();
+    vm.attach_nic(name, device, backend).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    let (readonly, old_vcr_json) = {
-        let bes = &v0_spec.backends.storage_backends.get(&disk_name);
-        if let Some(StorageBackendV0::Crucible(bes)) = bes {
-            (bes.readonly, &bes.request_json)
-        } else {
-            let s = format!("Crucible backend for {:?} not found", disk_name);
-            return Err(HttpError::for_not_found(Some(s.clone()), s));
-        }
-    };
+/// Detaches a hotplugged virtio NIC, or a passthrough NIC in a hotplug slot,
+/// from the instance.
+///
+/// As with disks, the NIC is removed only once the guest has released it and
+/// powered off its slot, which must happen within 30 seconds.  Detaching its
+/// passthrough NICs allows an instance to be migrated.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/nics/{name}",
+}]
+async fn instance_nic_detach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let vm = rqctx.context().vm().await?.clone();
+    vm.detach_nic(name).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    // Get the crucible backend so we can call the replacement method on it.
-    let crucible_backends = vm_controller.crucible_backends();
-    let backend = crucible_backends.get(&path_params.id).ok_or_else(|| {
-        let s = format!("No crucible backend for id {}", path_params.id);
-        HttpError::for_not_found(Some(s.clone()), s)
-    })?;
+/// Attaches a new device, described by instance spec fragments, to the
+/// instance.
+///
+/// This accepts any kind of device that can be hotplugged, currently NVMe
+/// disks and virtio NICs, and refuses the rest. The device is added to the
+/// instance spec once the guest has been told of its arrival.
+#[endpoint {
+    method = PUT,
+    path = "/instance/devices",
+}]
+async fn instance_device_attach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::DeviceAttachRequest>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let api::DeviceAttachRequest { name, fragment } = request.into_inner();
+    let vm = rqctx.context().vm().await?.clone();
+    vm.attach_device(name, fragment).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    slog::info!(
-        log,
-        "{:?} {:?} vcr replace requested",
-        disk_name,
-        path_params.id,
-    );
+/// Detaches a device from the instance, if devices of its kind can be
+/// hotplugged.
+///
+/// The device is removed, along with its backend, once the guest has released
+/// it and powered off its slot, which must happen in a timely fashion.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/devices/{name}",
+}]
+async fn instance_device_detach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DevicePathParams>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let vm = rqctx.context().vm().await?.clone();
+    vm.detach_device(name).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    // Try the replacement.
-    // Crucible does the heavy lifting here to verify that the old/new
-    // VCRs are different in just the correct way and will return error
-    // if there is any mismatch.
-    let replace_result =
-        backend.vcr_replace(old_vcr_json, &new_vcr_json).await.map_err(
-            |e| HttpError::for_bad_request(Some(e.to_string()), e.to_string()),
-        )?;
+/// Replaces the rate limits of one of the instance's NICs.
+///
+/// Only NICs whose datapath is in userspace (those with DLPI backends) can be
+/// rate limited. The new limits take effect immediately, and are recorded in
+/// the instance spec.
+#[endpoint {
+    method = PUT,
+    path = "/instance/nics/{name}/rate-limit",
+}]
+async fn instance_nic_rate_limit_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+    request: TypedBody<instance_spec::components::devices::NicRateLimit>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let vm = rqctx.context().vm().await?.clone();
+    vm.set_nic_rate_limit(&name, request.into_inner()).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    // Our replacement request was accepted.  We now need to update the
-    // spec stored in propolis so it matches what the downstairs now has.
-    let new_storage_backend: StorageBackendV0 =
-        StorageBackendV0::Crucible(CrucibleStorageBackend {
-            readonly,
-            request_json: new_vcr_json,
-        });
-    v0_spec.backends.storage_backends.insert(disk_name, new_storage_backend);
+/// Sets the link state one of the instance's virtio NICs reports to the
+/// guest.
+///
+/// A change of state is signalled to the guest with a config-change
+/// interrupt. NICs whose datapath is in userspace also stop passing traffic
+/// while their link is down; for those backed by viona, the state is only
+/// advisory. The new state is recorded in the instance spec.
+#[endpoint {
+    method = PUT,
+    path = "/instance/nics/{name}/link",
+}]
+async fn instance_nic_link_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+    request: TypedBody<api::NicLinkStateRequest>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let name = path_params.into_inner().name;
+    let vm = rqctx.context().vm().await?.clone();
+    vm.set_nic_link_state(&name, request.into_inner().link_state).await?;
+    Ok(HttpResponseOk(()))
+}
 
-    slog::info!(log, "Replaced the VCR in backend of {:?}", path_params.id);
+/// Reports the resolution suggested to the guest for its display, and that of
+/// the framebuffer it has configured.
+#[endpoint {
+    method = GET,
+    path = "/instance/display",
+}]
+async fn instance_display_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceDisplay>, HttpError> {
+    authorize(&rqctx, AuthScope::Console)?;
+    let vm = rqctx.context().vm().await?;
+    let ramfb = vm.framebuffer().ok_or(VmControllerError::NoFramebuffer)?;
+    let preferred = ramfb.preferred_resolution();
+    let fb = ramfb.get_framebuffer_spec();
+    Ok(HttpResponseOk(api::InstanceDisplay {
+        preferred: api::DisplayResolution {
+            width: preferred.width,
+            height: preferred.height,
+        },
+        current: (fb.addr != 0).then_some(api::DisplayResolution {
+            width: fb.width,
+            height: fb.height,
+        }),
+    }))
+}
 
-    Ok(HttpResponseOk(replace_result))
+/// Sets the resolution suggested to the guest for its display, which the
+/// guest adopts when it next configures its framebuffer. The EDID offered to
+/// the guest is updated to advertise the new resolution as preferred, and
+/// connected VNC clients are resized once the guest's framebuffer changes.
+#[endpoint {
+    method = PUT,
+    path = "/instance/display",
+}]
+async fn instance_display_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::DisplayResolution>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Console)?;
+    let api::DisplayResolution { width, height } = request.into_inner();
+    let vm = rqctx.context().vm().await?;
+    vm.set_display_resolution(Resolution { width, height })?;
+    Ok(HttpResponseOk(()))
 }
 
-/// Issues an NMI to the instance.
+/// Issues an NMI to the running instance.
+///
+/// Guests can be configured to panic on receiving an NMI, so this can be used
+/// to take a crash dump of a guest kernel that has stopped responding.
 #[endpoint {
     method = POST,
     path = "/instance/nmi",
@@ -1111,33 +3164,226 @@ async fn instance_issue_crucible_vcr_request(
 async fn instance_issue_nmi(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
     let vm = rqctx.context().vm().await?;
-    vm.inject_nmi();
+    vm.inject_nmi()?;
 
     Ok(HttpResponseOk(()))
 }
 
+/// Reports per-queue statistics for one of the instance's virtio devices, to
+/// help diagnose misbehaving guest drivers.
+#[endpoint {
+    method = GET,
+    path = "/instance/devices/{name}/virtio-stats",
+}]
+async fn instance_virtio_stats(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DevicePathParams>,
+) -> Result<HttpResponseOk<api::VirtioDeviceStats>, HttpError> {
+    authenticate(&rqctx)?;
+    use propolis::util::latency::{LatencyHistogram, LATENCY_BUCKETS};
+
+    let name = path_params.into_inner().name;
+    let device =
+        rqctx.context().vm().await?.virtio_device(&name).ok_or_else(|| {
+            no_such_device(format!("no virtio device named {name:?}"))
+        })?;
+
+    let queues = device
+        .virtio_state()
+        .queue_stats()
+        .into_iter()
+        .map(|q| api::VirtqueueStats {
+            queue_id: q.queue_id,
+            kicks: q.kicks,
+            interrupts: q.interrupts,
+            chains: q.chains,
+            descriptors: q.descriptors,
+            bytes: q.bytes,
+            latency: (0..LATENCY_BUCKETS)
+                .map(|idx| api::LatencyBucket {
+                    start_ns: LatencyHistogram::bucket_start_ns(idx),
+                    count: q.latency.counts[idx],
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(HttpResponseOk(api::VirtioDeviceStats { queues }))
+}
+
+/// Returns the agent in the instance's guest, or an error if it has none.
+async fn guest_agent(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<Arc<GuestAgent>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    vm.guest_agent().ok_or_else(|| GuestAgentError::NotConfigured.into())
+}
+
+/// Runs a command in the guest through its agent, and waits for it to exit.
+///
+/// If the command doesn't exit in the time allowed, it is left running and the
+/// request fails.
+#[endpoint {
+    method = POST,
+    path = "/instance/agent/exec",
+}]
+async fn instance_agent_exec(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::GuestExecRequest>,
+) -> Result<HttpResponseOk<api::GuestExecResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let agent = guest_agent(&rqctx).await?;
+    Ok(HttpResponseOk(agent.exec(request.into_inner()).await?))
+}
+
+/// Reads part of a file in the guest through its agent.
+#[endpoint {
+    method = POST,
+    path = "/instance/agent/file-read",
+}]
+async fn instance_agent_file_read(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::GuestFileReadRequest>,
+) -> Result<HttpResponseOk<api::GuestFileReadResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let agent = guest_agent(&rqctx).await?;
+    Ok(HttpResponseOk(agent.read_file(request.into_inner()).await?))
+}
+
+/// Writes a file in the guest through its agent.
+#[endpoint {
+    method = POST,
+    path = "/instance/agent/file-write",
+}]
+async fn instance_agent_file_write(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::GuestFileWriteRequest>,
+) -> Result<HttpResponseOk<api::GuestFileWriteResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let agent = guest_agent(&rqctx).await?;
+    Ok(HttpResponseOk(agent.write_file(request.into_inner()).await?))
+}
+
+/// Freezes the guest's file systems through its agent.
+///
+/// Writes to the guest's file systems are held, with their data flushed to
+/// its disks, until they are thawed, so that snapshots taken in the meantime
+/// capture consistent file systems.
+#[endpoint {
+    method = POST,
+    path = "/instance/agent/fsfreeze",
+}]
+async fn instance_agent_fsfreeze(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::GuestFsFreezeResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let agent = guest_agent(&rqctx).await?;
+    let filesystems = agent.freeze().await?;
+    Ok(HttpResponseOk(api::GuestFsFreezeResponse { filesystems }))
+}
+
+/// Thaws the guest's file systems through its agent.
+#[endpoint {
+    method = POST,
+    path = "/instance/agent/fsthaw",
+}]
+async fn instance_agent_fsthaw(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::GuestFsFreezeResponse>, HttpError> {
+    authorize(&rqctx, AuthScope::Lifecycle)?;
+    let agent = guest_agent(&rqctx).await?;
+    let filesystems = agent.thaw().await?;
+    Ok(HttpResponseOk(api::GuestFsFreezeResponse { filesystems }))
+}
+
 /// Returns a Dropshot [`ApiDescription`] object to launch a server.
 pub fn api() -> ApiDescription<Arc<DropshotEndpointContext>> {
     let mut api = ApiDescription::new();
+    api.register(healthz).unwrap();
+    api.register(readyz).unwrap();
     api.register(instance_ensure).unwrap();
     api.register(instance_spec_ensure).unwrap();
     api.register(instance_get).unwrap();
     api.register(instance_spec_get).unwrap();
     api.register(instance_state_monitor).unwrap();
+    api.register(instance_history).unwrap();
+    api.register(instance_webhook_register).unwrap();
+    api.register(instance_webhook_list).unwrap();
+    api.register(instance_webhook_unregister).unwrap();
     api.register(instance_state_put).unwrap();
+    api.register(instance_shutdown).unwrap();
+    api.register(instance_pause).unwrap();
+    api.register(instance_resume).unwrap();
+    api.register(instance_properties_update).unwrap();
+    api.register(instances_list).unwrap();
+    api.register(instances_ensure).unwrap();
+    api.register(instances_get).unwrap();
+    api.register(instances_delete).unwrap();
+    api.register(instances_state_put).unwrap();
+    api.register(instances_state_monitor).unwrap();
+    api.register(instances_history).unwrap();
+    api.register(instances_serial).unwrap();
     api.register(instance_serial).unwrap();
     api.register(instance_serial_history_get).unwrap();
+    api.register(instance_serial_port).unwrap();
+    api.register(instance_serial_port_history_get).unwrap();
+    api.register(instance_serial_port_break).unwrap();
     api.register(instance_migrate_start).unwrap();
+    api.register(instance_migrate_ram_stream).unwrap();
     api.register(instance_migrate_status).unwrap();
+    api.register(instance_migrate_progress).unwrap();
+    api.register(instance_migrate_cancel).unwrap();
+    api.register(instance_migrate_bandwidth_put).unwrap();
+    api.register(instance_migrate_check_source).unwrap();
+    api.register(instance_migrate_check).unwrap();
+    api.register(instance_save).unwrap();
+    api.register(instance_restore).unwrap();
+    api.register(server_shutdown).unwrap();
+    api.register(instance_memory_dump).unwrap();
+    api.register(instance_memory_dump_get).unwrap();
     api.register(instance_issue_crucible_snapshot_request).unwrap();
     api.register(disk_volume_status).unwrap();
     api.register(instance_issue_crucible_vcr_request).unwrap();
+    api.register(instance_disk_export).unwrap();
+    api.register(instance_disks_status).unwrap();
+    api.register(instance_disk_status).unwrap();
+    api.register(instance_disk_snapshot).unwrap();
+    api.register(instance_snapshot).unwrap();
+    api.register(instance_clone).unwrap();
+    api.register(instance_disk_backend_replace).unwrap();
+    api.register(instance_cdrom_media_put).unwrap();
+    api.register(instance_disk_attach).unwrap();
+    api.register(instance_disk_detach).unwrap();
+    api.register(instance_nic_attach).unwrap();
+    api.register(instance_nic_detach).unwrap();
+    api.register(instance_device_attach).unwrap();
+    api.register(instance_device_detach).unwrap();
+    api.register(instance_nic_rate_limit_put).unwrap();
+    api.register(instance_nic_link_put).unwrap();
+    api.register(instance_display_get).unwrap();
+    api.register(instance_display_put).unwrap();
     api.register(instance_issue_nmi).unwrap();
+    api.register(instance_virtio_stats).unwrap();
+    api.register(instance_agent_exec).unwrap();
+    api.register(instance_agent_file_read).unwrap();
+    api.register(instance_agent_file_write).unwrap();
+    api.register(instance_agent_fsfreeze).unwrap();
+    api.register(instance_agent_fsthaw).unwrap();
 
     api
 }
 
+/// Returns an error for a request naming a device or backend the instance
+/// doesn't have.
+fn no_such_device(message: String) -> HttpError {
+    HttpError::for_not_found(
+        Some(api::ErrorCode::NoSuchDevice.to_string()),
+        message,
+    )
+}
+
 fn not_created_error() -> HttpError {
     HttpError::for_client_error(
         Some(api::ErrorCode::NoInstance.to_string()),
@@ -1148,6 +3394,255 @@ fn not_created_error() -> HttpError {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    use dropshot::{ConfigDropshot, HttpServer, HttpServerStarter};
+    use propolis_server_config::MultiInstance;
+
+    /// A server, without a VMM to create instances with, hosting up to
+    /// `max_instances` instances addressed by ID
+    fn test_server(
+        max_instances: usize,
+    ) -> (HttpServer<Arc<DropshotEndpointContext>>, String) {
+        let log = Logger::root(slog::Discard, o!());
+        let config = VmTomlConfig {
+            multi_instance: Some(MultiInstance { max_instances }),
+            ..Default::default()
+        };
+        let vnc_server =
+            crate::vnc::setup_vnc(&log, "127.0.0.1:0".parse().unwrap());
+        let context = DropshotEndpointContext::new(
+            config,
+            vnc_server,
+            false,
+            log.clone(),
+            None,
+            MigrationPayloadDirs::default(),
+            Authorizer::new(None).unwrap(),
+        );
+        let config = ConfigDropshot {
+            bind_address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        };
+        let server =
+            HttpServerStarter::new(&config, api(), Arc::new(context), &log)
+                .unwrap()
+                .start();
+        let url = format!("http://{}", server.local_addr());
+        (server, url)
+    }
+
+    /// Adds the services of an instance which was stopped (or never created)
+    /// to the instances `server` addresses by ID.
+    async fn add_instance(
+        server: &HttpServer<Arc<DropshotEndpointContext>>,
+        id: uuid::Uuid,
+    ) -> Arc<ServiceProviders> {
+        let ctx = server.app_private();
+        let services = Arc::new(ServiceProviders::new(
+            None,
+            ctx.services.webhooks.clone(),
+            Arc::new(EventHistory::default()),
+        ));
+        ctx.instances.lock().await.insert(id, services.clone());
+        services
+    }
+
+    /// A request to create the instance `id`, which fails once a slot has
+    /// been taken for it, as its supervision policy needs a guest agent which
+    /// the server isn't configured with.
+    fn failing_ensure(id: uuid::Uuid) -> api::InstanceEnsureRequest {
+        api::InstanceEnsureRequest {
+            properties: api::InstanceProperties {
+                id,
+                name: "test".to_string(),
+                description: String::new(),
+                metadata: api::InstanceMetadata {
+                    silo_id: uuid::Uuid::new_v4(),
+                    project_id: uuid::Uuid::new_v4(),
+                },
+                image_id: uuid::Uuid::new_v4(),
+                bootrom_id: uuid::Uuid::new_v4(),
+                memory: 256,
+                vcpus: 1,
+            },
+            nics: vec![],
+            disks: vec![],
+            migrate: None,
+            cloud_init_bytes: None,
+            start_paused: false,
+            boot_to_firmware_setup: false,
+            supervision: Some(api::SupervisionPolicy {
+                hang_timeout_secs: 60,
+                action: api::HangAction::Reset,
+            }),
+        }
+    }
+
+    /// Asks the server at `url` to create the instance `id`, as described by
+    /// [`failing_ensure`].
+    async fn ensure_failing(
+        client: &reqwest::Client,
+        url: &str,
+        id: uuid::Uuid,
+    ) -> reqwest::Response {
+        client
+            .put(format!("{url}/instances/{id}"))
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&failing_ensure(id)).unwrap())
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Returns the status and the error code (if any) of `response`.
+    async fn outcome(
+        response: reqwest::Response,
+    ) -> (http::StatusCode, Option<String>) {
+        let status = response.status();
+        let body = response.bytes().await.unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(&body).unwrap_or_default();
+        let code = body["error_code"].as_str().map(str::to_string);
+        (status, code)
+    }
+
+    #[tokio::test]
+    async fn instances_limited_to_slots() {
+        let (server, url) = test_server(1);
+        let client = reqwest::Client::new();
+        let held = uuid::Uuid::new_v4();
+        add_instance(&server, held).await;
+
+        // With its one slot taken, the server refuses another instance.
+        let id = uuid::Uuid::new_v4();
+        let response = ensure_failing(&client, &url, id).await;
+        assert_eq!(
+            outcome(response).await,
+            (
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                Some(api::ErrorCode::InstanceLimitReached.to_string())
+            )
+        );
+
+        // Until that's deleted, when the instance gets as far as failing to
+        // be created.
+        let response =
+            client.delete(format!("{url}/instances/{held}")).send().await;
+        assert_eq!(response.unwrap().status(), http::StatusCode::NO_CONTENT);
+        let response = ensure_failing(&client, &url, id).await;
+        assert_eq!(outcome(response).await.0, http::StatusCode::BAD_REQUEST);
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_instance_frees_slot() {
+        let (server, url) = test_server(1);
+        let client = reqwest::Client::new();
+
+        // An instance which fails to be created doesn't keep its slot, so
+        // every attempt fails for the same reason, rather than the first
+        // leaving the rest to find the server full.
+        for _ in 0..2 {
+            let id = uuid::Uuid::new_v4();
+            let response = ensure_failing(&client, &url, id).await;
+            assert_eq!(
+                outcome(response).await,
+                (
+                    http::StatusCode::BAD_REQUEST,
+                    Some(api::ErrorCode::InvalidRequest.to_string())
+                )
+            );
+            assert!(server.app_private().instances.lock().await.is_empty());
+
+            let response =
+                client.get(format!("{url}/instances/{id}")).send().await;
+            assert_eq!(
+                outcome(response.unwrap()).await,
+                (
+                    http::StatusCode::NOT_FOUND,
+                    Some(api::ErrorCode::NoInstance.to_string())
+                )
+            );
+        }
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_only_known_instances() {
+        let (server, url) = test_server(2);
+        let client = reqwest::Client::new();
+        let id = uuid::Uuid::new_v4();
+        add_instance(&server, id).await;
+
+        let response = client.delete(format!("{url}/instances/{id}")).send();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+        assert!(server.app_private().instances.lock().await.is_empty());
+
+        // Once deleted, the instance is gone.
+        for _ in 0..2 {
+            let response = client.delete(format!("{url}/instances/{id}"));
+            assert_eq!(
+                outcome(response.send().await.unwrap()).await,
+                (
+                    http::StatusCode::NOT_FOUND,
+                    Some(api::ErrorCode::NoInstance.to_string())
+                )
+            );
+        }
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn instances_routed_by_id() {
+        let (server, url) = test_server(2);
+        let client = reqwest::Client::new();
+        let (a, b) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let services = add_instance(&server, a).await;
+        add_instance(&server, b).await;
+        services.history.record(api::InstanceEventKind::Request {
+            request: "test".to_string(),
+            denied: None,
+        });
+
+        let history = |path: String| {
+            let request = client.get(format!("{url}{path}")).send();
+            async move {
+                let response = request.await.unwrap();
+                assert_eq!(response.status(), http::StatusCode::OK);
+                let body = response.bytes().await.unwrap();
+                let history: api::InstanceHistoryResponse =
+                    serde_json::from_slice(&body).unwrap();
+                history.events.len()
+            }
+        };
+
+        // Each instance's endpoints reach that instance, and no other.
+        assert_eq!(history(format!("/instances/{a}/history")).await, 1);
+        assert_eq!(history(format!("/instances/{b}/history")).await, 0);
+        assert_eq!(history("/instance/history".to_string()).await, 0);
+
+        let unknown = uuid::Uuid::new_v4();
+        let response = client
+            .get(format!("{url}/instances/{unknown}/history"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome(response).await,
+            (
+                http::StatusCode::NOT_FOUND,
+                Some(api::ErrorCode::NoInstance.to_string())
+            )
+        );
+
+        server.close().await.unwrap();
+    }
+
     #[test]
     fn test_propolis_server_openapi() {
         let mut buf: Vec<u8> = vec![];
@@ -0,0 +1,427 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Snapshots of all of an instance's disks, taken together.
+//!
+//! Each disk backed by a writable file or by a Crucible volume is snapshotted:
+//! file-backed disks by cloning their backing files alongside the originals
+//! (which shares the files' blocks, so takes little time however large the
+//! disks, but needs a file system which can clone files), and Crucible disks
+//! by asking their volumes for snapshots named with the ID of the instance's
+//! snapshot.  All of these disks' devices stop accepting new
+//! I/O from the guest, and wait for their in-flight I/O to complete, before any
+//! of the disks is snapshotted, so the snapshots capture the disks at a single
+//! point in time; the instance must be running for them to do so.  If the
+//! guest's file systems are to be quiesced, they are frozen through the guest
+//! agent beforehand, and thawed once the devices have resumed, whether or not
+//! the snapshot succeeded.  A failure to thaw them is reported as a failure of
+//! the snapshot, though any snapshots taken of the disks are kept.
+//!
+//! If any disk can't be snapshotted, the clones already made of file-backed
+//! disks are removed.  Snapshots already taken of Crucible volumes can only be
+//! removed through Crucible itself, and are left in place.
+//!
+//! Snapshots may also be taken on a schedule, configured in the server's
+//! `[snapshot_schedule]` section, while the instance runs.  If the schedule
+//! sets `keep`, the clones of file-backed disks taken for the oldest scheduled
+//! snapshots are removed once more than that many have been taken; again,
+//! Crucible snapshots are left in place.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dropshot::HttpError;
+use propolis_api_types::instance_spec::v0::{
+    StorageBackendV0, StorageDeviceV0,
+};
+use propolis_api_types::instance_spec::VersionedInstanceSpec;
+use propolis_api_types::{
+    DiskGroupSnapshot, ErrorCode, InstanceSnapshotResponse, InstanceState,
+};
+use slog::{error, info, warn, Logger};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::guest_agent::GuestAgentError;
+use crate::limits::EndpointLimits;
+use crate::server::StorageDevice;
+use crate::vm::{DeviceHold, VmController, VmControllerError};
+
+#[derive(Debug, Error)]
+pub(crate) enum SnapshotError {
+    #[error("Failed to quiesce guest file systems: {0}")]
+    Quiesce(#[from] GuestAgentError),
+
+    #[error("Failed to thaw guest file systems: {0}")]
+    Thaw(GuestAgentError),
+
+    #[error("Failed to snapshot disk {0:?}: {1}")]
+    Disk(String, io::Error),
+
+    #[error(transparent)]
+    Hold(#[from] VmControllerError),
+}
+
+impl From<SnapshotError> for HttpError {
+    fn from(e: SnapshotError) -> Self {
+        let msg = e.to_string();
+        match e {
+            SnapshotError::Quiesce(e) | SnapshotError::Thaw(e) => e.into(),
+            SnapshotError::Hold(e) => e.into(),
+            SnapshotError::Disk(..) => {
+                let mut error = HttpError::for_internal_error(msg);
+                error.error_code = Some(ErrorCode::OperationFailed.to_string());
+                error
+            }
+        }
+    }
+}
+
+/// Copies the file at `source` to a new file at `dest`, which must not already
+/// exist.  All of the file's data is read and written, so this takes time in
+/// proportion to its size; see [`clone_file`] for a copy which shares the
+/// source's blocks.  Should the copy fail, nothing is left at `dest`.
+pub(crate) fn copy_file(source: &str, dest: &str) -> io::Result<()> {
+    // Refuse to clobber an existing file
+    std::fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+    let copied = std::fs::copy(source, dest)
+        .and_then(|_| std::fs::File::open(dest)?.sync_all());
+    if copied.is_err() {
+        let _ = std::fs::remove_file(dest);
+    }
+    copied
+}
+
+/// Clones the file at `source` into a new file at `dest`, which must not
+/// already exist, sharing the source's blocks rather than copying its data.
+///
+/// This fails if the host's file system can't clone the file, as when `dest`
+/// is on a different file system than `source`.  Should the clone fail,
+/// nothing is left at `dest`.
+pub(crate) fn clone_file(source: &str, dest: &str) -> io::Result<()> {
+    #[cfg(target_os = "illumos")]
+    {
+        use std::ffi::{c_char, c_int, CString};
+
+        extern "C" {
+            fn reflink(
+                path1: *const c_char,
+                path2: *const c_char,
+                preserve: bool,
+            ) -> c_int;
+        }
+
+        let source = CString::new(source)?;
+        let dest_c = CString::new(dest)?;
+        // Safety: both paths are valid NUL-terminated strings.
+        if unsafe { reflink(source.as_ptr(), dest_c.as_ptr(), false) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let synced = std::fs::File::open(dest).and_then(|f| f.sync_all());
+        if synced.is_err() {
+            let _ = std::fs::remove_file(dest);
+        }
+        synced
+    }
+    #[cfg(not(target_os = "illumos"))]
+    {
+        let _ = (source, dest);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cloning files is only supported on illumos",
+        ))
+    }
+}
+
+/// A disk to be snapshotted with the others.
+struct Target {
+    name: String,
+    device: StorageDevice,
+    backing_file: Option<String>,
+}
+
+/// Finds the instance's disks which can be snapshotted.
+async fn targets(vm: &VmController) -> Vec<Target> {
+    let spec = vm.instance_spec().await;
+    let VersionedInstanceSpec::V0(spec) = &*spec;
+    let mut targets = vec![];
+    for (name, device_spec) in spec.devices.storage_devices.iter() {
+        if matches!(device_spec, StorageDeviceV0::SataCdrom(_)) {
+            continue;
+        }
+        let Some(device) = vm.storage_device(name) else {
+            continue;
+        };
+        let backing_file = match spec
+            .backends
+            .storage_backends
+            .get(device_spec.backend_name())
+        {
+            _ if device.crucible.is_some() => None,
+            Some(StorageBackendV0::File(file)) if !file.readonly => {
+                Some(file.path.clone())
+            }
+            _ => continue,
+        };
+        targets.push(Target { name: name.clone(), device, backing_file });
+    }
+    targets
+}
+
+/// Pauses the devices of each of `targets`, returning the holds which keep
+/// them paused.
+async fn hold_targets(
+    vm: &VmController,
+    targets: &[Target],
+) -> Result<Vec<DeviceHold>, VmControllerError> {
+    let mut holds = Vec::with_capacity(targets.len());
+    for target in targets {
+        let (_, hold) = vm.hold_storage_device(&target.name).await?;
+        holds.push(hold);
+    }
+    Ok(holds)
+}
+
+/// Snapshots each of `targets`, returning the snapshots taken, or an error
+/// once one of them can't be taken.
+async fn snapshot_targets(
+    targets: &[Target],
+    snapshot_id: Uuid,
+    log: &Logger,
+) -> Result<BTreeMap<String, DiskGroupSnapshot>, SnapshotError> {
+    let mut disks = BTreeMap::new();
+    for target in targets {
+        let result = match (&target.device.crucible, &target.backing_file) {
+            (Some(crucible), _) => crucible
+                .snapshot(snapshot_id)
+                .await
+                .map(|()| DiskGroupSnapshot::Crucible),
+            (None, Some(source)) => {
+                let source = source.clone();
+                let path = format!("{source}.snapshot-{snapshot_id}");
+                let dest = path.clone();
+                tokio::task::spawn_blocking(move || clone_file(&source, &dest))
+                    .await
+                    .expect("disk snapshot should not panic")
+                    .map(|()| DiskGroupSnapshot::File { path })
+            }
+            (None, None) => unreachable!("targets have snapshottable backends"),
+        };
+        match result {
+            Ok(snapshot) => {
+                info!(log, "snapshotted disk";
+                      "disk" => &target.name, "snapshot" => ?snapshot);
+                disks.insert(target.name.clone(), snapshot);
+            }
+            Err(e) => {
+                for snapshot in disks.values() {
+                    if let DiskGroupSnapshot::File { path } = snapshot {
+                        let _ = std::fs::remove_file(path);
+                    }
+                }
+                return Err(SnapshotError::Disk(target.name.clone(), e));
+            }
+        }
+    }
+    Ok(disks)
+}
+
+/// Snapshots all of the instance's disks together, first freezing the guest's
+/// file systems if `quiesce` is set.
+pub(crate) async fn snapshot_instance(
+    vm: &VmController,
+    quiesce: bool,
+    log: &Logger,
+) -> Result<InstanceSnapshotResponse, SnapshotError> {
+    let snapshot_id = Uuid::new_v4();
+    let log = log.new(slog::o!("snapshot_id" => snapshot_id.to_string()));
+    let agent = if quiesce {
+        let agent = vm.guest_agent().ok_or(GuestAgentError::NotConfigured)?;
+        let frozen = agent.freeze().await?;
+        info!(log, "froze guest file systems"; "filesystems" => frozen);
+        Some(agent)
+    } else {
+        None
+    };
+
+    let targets = targets(vm).await;
+
+    let result = match hold_targets(vm, &targets).await {
+        Ok(_holds) => snapshot_targets(&targets, snapshot_id, &log).await,
+        Err(e) => Err(e.into()),
+    };
+
+    if let Some(agent) = agent {
+        if let Err(e) = agent.thaw().await {
+            // The guest's file systems remain frozen, so report the failure
+            // even if the snapshot was taken (which is left in place), to
+            // prompt another attempt to thaw them.
+            error!(log, "failed to thaw guest file systems"; "error" => %e);
+            return Err(SnapshotError::Thaw(e));
+        }
+    }
+
+    let disks = result?;
+    info!(log, "instance snapshot finished"; "disks" => disks.len());
+    Ok(InstanceSnapshotResponse { snapshot_id, disks, quiesced: quiesce })
+}
+
+/// Records `snapshot` as the newest of the scheduled snapshots in `taken`,
+/// dropping the oldest of them so that no more than `keep` are kept, and
+/// returning the paths of the dropped snapshots' file-backed disks.
+fn retain(
+    taken: &mut VecDeque<InstanceSnapshotResponse>,
+    snapshot: InstanceSnapshotResponse,
+    keep: Option<usize>,
+) -> Vec<String> {
+    let Some(keep) = keep else {
+        return vec![];
+    };
+    taken.push_back(snapshot);
+    let excess = taken.len().saturating_sub(keep.max(1));
+    taken
+        .drain(..excess)
+        .flat_map(|snapshot| snapshot.disks.into_values())
+        .filter_map(|disk| match disk {
+            DiskGroupSnapshot::File { path } => Some(path),
+            DiskGroupSnapshot::Crucible => None,
+        })
+        .collect()
+}
+
+/// Spawns a task which snapshots the instance's disks every `interval` while
+/// it runs, until it is destroyed.
+///
+/// A scheduled snapshot is skipped if another snapshot is being taken when it
+/// is due.  Failures are logged, and the next snapshot is attempted on
+/// schedule.  If `keep` is set, the file-backed disks' snapshots from all but
+/// the latest `keep` scheduled snapshots are removed.
+pub(crate) fn spawn_scheduler(
+    vm: Arc<VmController>,
+    limits: Arc<EndpointLimits>,
+    interval: Duration,
+    quiesce: bool,
+    keep: Option<usize>,
+    log: Logger,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut state_rx = vm.state_watcher().clone();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut taken = VecDeque::new();
+
+        // The first tick completes immediately; the first snapshot is taken
+        // one interval after the instance is created.
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                changed = state_rx.changed() => {
+                    let destroyed = changed.is_err()
+                        || state_rx.borrow_and_update().state
+                            == InstanceState::Destroyed;
+                    if destroyed {
+                        break;
+                    }
+                }
+                _ = ticker.tick() => {
+                    if state_rx.borrow().state != InstanceState::Running {
+                        continue;
+                    }
+                    let Ok(_permit) = limits.snapshot() else {
+                        warn!(log, "skipping scheduled snapshot: \
+                                    another snapshot is being taken");
+                        continue;
+                    };
+                    let snapshot =
+                        match snapshot_instance(&vm, quiesce, &log).await {
+                            Ok(snapshot) => snapshot,
+                            Err(e) => {
+                                error!(log, "scheduled snapshot failed";
+                                       "error" => %e);
+                                continue;
+                            }
+                        };
+                    for path in retain(&mut taken, snapshot, keep) {
+                        if let Err(e) = tokio::fs::remove_file(&path).await {
+                            warn!(log, "failed to remove old snapshot";
+                                  "path" => &path, "error" => %e);
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(paths: &[&str]) -> InstanceSnapshotResponse {
+        let mut disks: BTreeMap<_, _> = paths
+            .iter()
+            .map(|path| {
+                let disk = DiskGroupSnapshot::File { path: path.to_string() };
+                (path.to_string(), disk)
+            })
+            .collect();
+        disks.insert("crucible".to_string(), DiskGroupSnapshot::Crucible);
+        InstanceSnapshotResponse {
+            snapshot_id: Uuid::new_v4(),
+            disks,
+            quiesced: false,
+        }
+    }
+
+    #[test]
+    fn oldest_scheduled_snapshots_removed() {
+        let mut taken = VecDeque::new();
+        let keep = Some(2);
+        assert!(retain(&mut taken, snapshot(&["a1", "b1"]), keep).is_empty());
+        assert!(retain(&mut taken, snapshot(&["a2", "b2"]), keep).is_empty());
+        assert_eq!(
+            retain(&mut taken, snapshot(&["a3", "b3"]), keep),
+            ["a1", "b1"]
+        );
+        assert_eq!(taken.len(), 2);
+
+        // Without a limit, nothing is removed (or remembered).
+        let mut taken = VecDeque::new();
+        for _ in 0..3 {
+            assert!(retain(&mut taken, snapshot(&["a"]), None).is_empty());
+        }
+        assert!(taken.is_empty());
+    }
+
+    #[test]
+    fn failed_copy_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let dest = dir.path().join("dest");
+        let (missing, dest) =
+            (missing.to_str().unwrap(), dest.to_str().unwrap());
+
+        assert!(copy_file(missing, dest).is_err());
+        assert!(!std::path::Path::new(dest).exists());
+        assert!(clone_file(missing, dest).is_err());
+        assert!(!std::path::Path::new(dest).exists());
+    }
+
+    #[test]
+    fn copy_never_clobbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("dest");
+        std::fs::write(&source, b"new").unwrap();
+        std::fs::write(&dest, b"old").unwrap();
+        let (source, dest) = (source.to_str().unwrap(), dest.to_str().unwrap());
+
+        assert!(copy_file(source, dest).is_err());
+        assert!(clone_file(source, dest).is_err());
+        assert_eq!(std::fs::read(dest).unwrap(), b"old");
+    }
+}
@@ -4,6 +4,7 @@
 
 //! Helper functions for building instance specs from server parameters.
 
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 use crate::config;
@@ -48,6 +49,12 @@ pub enum ServerSpecBuilderError {
 
     #[error("Error serializing {0} into spec element: {1}")]
     SerializationError(String, serde_json::error::Error),
+
+    #[error("Host vNIC {1} for device {0} could not be found: {2}")]
+    VnicNotFound(String, String, String),
+
+    #[error("Devices {0} and {1} would present the same MAC address, {2}")]
+    DuplicateMac(String, String, String),
 }
 
 /// A type of PCI device. Device numbers on the PCI bus are partitioned by slot
@@ -94,10 +101,33 @@ fn pci_path_to_nic_names(path: PciPath) -> (String, String) {
     (format!("vnic-{}", path), format!("vnic-{}-backend", path))
 }
 
+/// Looks up the MAC address of the host VNIC `name`, failing if there is no
+/// such VNIC.
+#[cfg(all(not(test), target_os = "illumos"))]
+fn host_vnic_mac(name: &str) -> std::io::Result<Option<[u8; 6]>> {
+    let info = dladm::Handle::new()?.query_vnic(name)?;
+    Ok(Some(info.mac_addr))
+}
+
+/// Host VNICs can only be inspected on illumos, so elsewhere they are assumed
+/// to exist, with addresses which cannot be known.
+#[cfg(not(all(not(test), target_os = "illumos")))]
+fn host_vnic_mac(_name: &str) -> std::io::Result<Option<[u8; 6]>> {
+    Ok(None)
+}
+
 fn make_storage_backend_from_config(
     name: &str,
     backend: &config::BlockDevice,
 ) -> Result<StorageBackendV0, ServerSpecBuilderError> {
+    // Keys have no business sitting in a config file on disk.
+    if backend.options.contains_key("encryption_key") {
+        return Err(ServerSpecBuilderError::ConfigTomlError(format!(
+            "Encryption key for backend {} must be supplied through the API",
+            name
+        )));
+    }
+
     let backend_spec = match backend.bdtype.as_str() {
         "file" => {
             StorageBackendV0::File(components::backends::FileStorageBackend {
@@ -124,6 +154,42 @@ fn make_storage_backend_from_config(
                     _ => None,
                 }
                 .unwrap_or(false),
+                error_policy: make_error_policy_from_config(name, backend)?,
+                encryption_key: None,
+                workers: get_int_option(
+                    "backend",
+                    name,
+                    &backend.options,
+                    "workers",
+                )?,
+                max_in_flight: get_int_option(
+                    "backend",
+                    name,
+                    &backend.options,
+                    "max_in_flight",
+                )?,
+                read_cache_size: get_int_option(
+                    "backend",
+                    name,
+                    &backend.options,
+                    "read_cache_size",
+                )?,
+                integrity_sidecar: match backend
+                    .options
+                    .get("integrity_sidecar")
+                {
+                    Some(toml::Value::String(path)) => Some(path.clone()),
+                    Some(_) => {
+                        return Err(ServerSpecBuilderError::ConfigTomlError(
+                            format!(
+                                "Couldn't parse integrity_sidecar for file \
+                                backend {}",
+                                name
+                            ),
+                        ))
+                    }
+                    None => None,
+                },
             })
         }
         _ => {
@@ -136,6 +202,169 @@ fn make_storage_backend_from_config(
     Ok(backend_spec)
 }
 
+/// Parses the `error_policy` (and, for the "retry" policy, the
+/// `error_max_attempts`) options of a block device in the config TOML.
+fn make_error_policy_from_config(
+    name: &str,
+    backend: &config::BlockDevice,
+) -> Result<
+    Option<components::backends::StorageErrorPolicy>,
+    ServerSpecBuilderError,
+> {
+    use components::backends::StorageErrorPolicy;
+
+    /// Number of attempts made under the "retry" policy when the config does
+    /// not specify otherwise
+    const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+    let Some(policy) = backend.options.get("error_policy") else {
+        return Ok(None);
+    };
+    let policy = match policy.as_str() {
+        Some("report") => StorageErrorPolicy::Report,
+        Some("pause") => StorageErrorPolicy::Pause,
+        Some("retry") => {
+            let max_attempts = get_int_option(
+                "backend",
+                name,
+                &backend.options,
+                "error_max_attempts",
+            )?
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+            StorageErrorPolicy::Retry { max_attempts }
+        }
+        _ => {
+            return Err(ServerSpecBuilderError::ConfigTomlError(format!(
+                "Unrecognized error_policy {} for backend {}",
+                policy, name
+            )))
+        }
+    };
+
+    Ok(Some(policy))
+}
+
+/// Parses an optional integer option of a component (the `kind` of which is
+/// included in any error message) in the config TOML.
+fn get_int_option<T: TryFrom<i64>>(
+    kind: &str,
+    name: &str,
+    options: &BTreeMap<String, toml::Value>,
+    key: &str,
+) -> Result<Option<T>, ServerSpecBuilderError> {
+    match options.get(key) {
+        None => Ok(None),
+        Some(toml::Value::Integer(n)) => {
+            T::try_from(*n).map(Some).map_err(|_| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Invalid {} {} for {} {}",
+                    key, n, kind, name
+                ))
+            })
+        }
+        Some(_) => Err(ServerSpecBuilderError::ConfigTomlError(format!(
+            "Couldn't parse {} for {} {}",
+            key, kind, name
+        ))),
+    }
+}
+
+fn get_str_option(
+    kind: &str,
+    name: &str,
+    options: &BTreeMap<String, toml::Value>,
+    key: &str,
+) -> Result<Option<String>, ServerSpecBuilderError> {
+    match options.get(key) {
+        None => Ok(None),
+        Some(toml::Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(ServerSpecBuilderError::ConfigTomlError(format!(
+            "Couldn't parse {} for {} {}",
+            key, kind, name
+        ))),
+    }
+}
+
+fn get_bool_option(
+    kind: &str,
+    name: &str,
+    options: &BTreeMap<String, toml::Value>,
+    key: &str,
+) -> Result<Option<bool>, ServerSpecBuilderError> {
+    match options.get(key) {
+        None => Ok(None),
+        Some(toml::Value::Boolean(b)) => Ok(Some(*b)),
+        Some(_) => Err(ServerSpecBuilderError::ConfigTomlError(format!(
+            "Couldn't parse {} for {} {}",
+            key, kind, name
+        ))),
+    }
+}
+
+/// Reads the offloads of a virtio NIC from its config options, returning
+/// `None` if none of them are set.
+fn get_virtio_nic_offloads(
+    name: &str,
+    options: &BTreeMap<String, toml::Value>,
+) -> Result<
+    Option<components::devices::VirtioNicOffloads>,
+    ServerSpecBuilderError,
+> {
+    let get = |key: &str| get_bool_option("network device", name, options, key);
+    let (tx_checksum, tso, rx_checksum, lro) =
+        (get("tx_checksum")?, get("tso")?, get("rx_checksum")?, get("lro")?);
+    if [tx_checksum, tso, rx_checksum, lro].iter().all(Option::is_none) {
+        return Ok(None);
+    }
+    let all = components::devices::VirtioNicOffloads::default();
+    Ok(Some(components::devices::VirtioNicOffloads {
+        tx_checksum: tx_checksum.unwrap_or(all.tx_checksum),
+        tso: tso.unwrap_or(all.tso),
+        rx_checksum: rx_checksum.unwrap_or(all.rx_checksum),
+        lro: lro.unwrap_or(all.lro),
+    }))
+}
+
+/// Reads the interrupt moderation of a virtio device from its config options,
+/// returning `None` if it is not set.  The packet and delay limits must be
+/// given together.
+fn get_interrupt_moderation(
+    kind: &str,
+    name: &str,
+    options: &BTreeMap<String, toml::Value>,
+) -> Result<
+    Option<components::devices::VirtioInterruptModeration>,
+    ServerSpecBuilderError,
+> {
+    let max_packets = get_int_option(kind, name, options, "intr_max_packets")?;
+    let max_usecs = get_int_option(kind, name, options, "intr_max_usecs")?;
+    match (max_packets, max_usecs) {
+        (None, None) => Ok(None),
+        (Some(max_packets), Some(max_usecs)) => {
+            Ok(Some(components::devices::VirtioInterruptModeration {
+                max_packets,
+                max_usecs,
+            }))
+        }
+        _ => Err(ServerSpecBuilderError::ConfigTomlError(format!(
+            "intr_max_packets and intr_max_usecs must be set together for \
+            {} {}",
+            kind, name
+        ))),
+    }
+}
+
+/// Parses an IEEE OUI written as three hex bytes separated by colons (e.g.
+/// "a8:40:25").
+fn parse_oui(s: &str) -> Option<[u8; 3]> {
+    let mut parts = s.split(':');
+    let mut oui = [0u8; 3];
+    for byte in oui.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(oui)
+}
+
 fn make_storage_device_from_config(
     name: &str,
     device: &config::Device,
@@ -143,11 +372,17 @@ fn make_storage_device_from_config(
     enum DeviceInterface {
         Virtio,
         Nvme,
+        Sata,
+        SataCdrom,
+        Ide,
     }
 
     let interface = match device.driver.as_str() {
         "pci-virtio-block" => DeviceInterface::Virtio,
         "pci-nvme" => DeviceInterface::Nvme,
+        "pci-ahci" => DeviceInterface::Sata,
+        "pci-ahci-cdrom" => DeviceInterface::SataCdrom,
+        "pci-piix3-ide" => DeviceInterface::Ide,
         _ => {
             return Err(ServerSpecBuilderError::ConfigTomlError(format!(
                 "storage device {} has invalid driver {}",
@@ -186,12 +421,80 @@ fn make_storage_device_from_config(
             StorageDeviceV0::VirtioDisk(components::devices::VirtioDisk {
                 backend_name,
                 pci_path,
+                num_queues: get_int_option(
+                    "storage device",
+                    name,
+                    &device.options,
+                    "num_queues",
+                )?,
+                interrupt_moderation: get_interrupt_moderation(
+                    "storage device",
+                    name,
+                    &device.options,
+                )?,
             })
         }
         DeviceInterface::Nvme => {
             StorageDeviceV0::NvmeDisk(components::devices::NvmeDisk {
                 backend_name,
                 pci_path,
+                num_queues: get_int_option(
+                    "storage device",
+                    name,
+                    &device.options,
+                    "num_queues",
+                )?,
+                queue_size: get_int_option(
+                    "storage device",
+                    name,
+                    &device.options,
+                    "queue_size",
+                )?,
+                model_number: get_str_option(
+                    "storage device",
+                    name,
+                    &device.options,
+                    "model_number",
+                )?,
+                firmware_revision: get_str_option(
+                    "storage device",
+                    name,
+                    &device.options,
+                    "firmware_revision",
+                )?,
+                ieee_oui: get_str_option(
+                    "storage device",
+                    name,
+                    &device.options,
+                    "ieee_oui",
+                )?
+                .map(|oui| {
+                    parse_oui(&oui).ok_or_else(|| {
+                        ServerSpecBuilderError::ConfigTomlError(format!(
+                            "Invalid ieee_oui {} for storage device {}",
+                            oui, name
+                        ))
+                    })
+                })
+                .transpose()?,
+            })
+        }
+        DeviceInterface::Sata => {
+            StorageDeviceV0::SataDisk(components::devices::SataDisk {
+                backend_name,
+                pci_path,
+            })
+        }
+        DeviceInterface::SataCdrom => {
+            StorageDeviceV0::SataCdrom(components::devices::SataCdrom {
+                backend_name,
+                pci_path,
+            })
+        }
+        DeviceInterface::Ide => {
+            StorageDeviceV0::IdeDisk(components::devices::IdeDisk {
+                backend_name,
+                pci_path,
             })
         }
     })
@@ -200,6 +503,12 @@ fn make_storage_device_from_config(
 /// A helper for building instance specs out of component parts.
 pub struct ServerSpecBuilder {
     builder: SpecBuilder,
+
+    /// Looks up the MAC address of a host VNIC
+    vnic_mac: fn(&str) -> std::io::Result<Option<[u8; 6]>>,
+
+    /// The NIC presenting each MAC address (on each VLAN) to the guest
+    nic_macs: BTreeMap<(Option<u16>, [u8; 6]), String>,
 }
 
 impl ServerSpecBuilder {
@@ -229,7 +538,60 @@ impl ServerSpecBuilder {
             enable_isa: true,
         })?;
 
-        Ok(Self { builder })
+        Ok(Self { builder, vnic_mac: host_vnic_mac, nic_macs: BTreeMap::new() })
+    }
+
+    /// Adds a NIC, carrying its traffic over a host VNIC, to the spec.
+    ///
+    /// The VNIC is checked to exist now, rather than failing the creation of
+    /// the device as the instance starts.  For viona devices, which present
+    /// the VNIC's own MAC address to the guest, the address is checked not to
+    /// be presented by another NIC on the same VLAN.  Other devices derive
+    /// their addresses from their (unique) PCI paths.
+    fn add_vnic_device(
+        &mut self,
+        device_name: String,
+        device_spec: NetworkDeviceV0,
+        backend_name: String,
+        backend_spec: NetworkBackendV0,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let (vnic_name, vlan_id) = match &backend_spec {
+            NetworkBackendV0::Virtio(be) => (be.vnic_name.clone(), be.vlan_id),
+            NetworkBackendV0::Dlpi(be) => (be.vnic_name.clone(), None),
+            NetworkBackendV0::Ppt(_) => {
+                unreachable!("passthrough NICs are not carried by VNICs")
+            }
+        };
+        let is_viona = matches!(
+            (&device_spec, &backend_spec),
+            (NetworkDeviceV0::VirtioNic(_), NetworkBackendV0::Virtio(_))
+        );
+
+        self.builder.add_network_device(
+            device_name.clone(),
+            device_spec,
+            backend_name,
+            backend_spec,
+        )?;
+
+        let mac = (self.vnic_mac)(&vnic_name).map_err(|e| {
+            ServerSpecBuilderError::VnicNotFound(
+                device_name.clone(),
+                vnic_name,
+                e.to_string(),
+            )
+        })?;
+        if let Some(mac) = mac.filter(|_| is_viona) {
+            if let Some(other) = self.nic_macs.get(&(vlan_id, mac)) {
+                return Err(ServerSpecBuilderError::DuplicateMac(
+                    other.clone(),
+                    device_name,
+                    mac.map(|b| format!("{b:02x}")).join(":"),
+                ));
+            }
+            self.nic_macs.insert((vlan_id, mac), device_name);
+        }
+        Ok(())
     }
 
     /// Converts an HTTP API request to add a NIC to an instance into
@@ -244,22 +606,26 @@ impl ServerSpecBuilder {
             NetworkDeviceV0::VirtioNic(components::devices::VirtioNic {
                 backend_name: backend_name.clone(),
                 pci_path,
+                num_queue_pairs: None,
+                rate_limit: None,
+                offloads: None,
+                link_state: None,
+                interrupt_moderation: None,
             });
 
         let backend_spec = NetworkBackendV0::Virtio(
             components::backends::VirtioNetworkBackend {
                 vnic_name: nic.name.to_string(),
+                vlan_id: None,
             },
         );
 
-        self.builder.add_network_device(
+        self.add_vnic_device(
             device_name,
             device_spec,
             backend_name,
             backend_spec,
-        )?;
-
-        Ok(())
+        )
     }
 
     /// Converts an HTTP API request to add a disk to an instance into
@@ -283,6 +649,8 @@ impl ServerSpecBuilder {
                     )
                 })?,
                 readonly: disk.read_only,
+                error_policy: None,
+                lazy_attach: disk.lazy_attach.then_some(true),
             },
         );
 
@@ -292,14 +660,31 @@ impl ServerSpecBuilder {
                 StorageDeviceV0::VirtioDisk(components::devices::VirtioDisk {
                     backend_name: disk.name.to_string(),
                     pci_path,
+                    num_queues: None,
+                    interrupt_moderation: None,
                 })
             }
             "nvme" => {
                 StorageDeviceV0::NvmeDisk(components::devices::NvmeDisk {
                     backend_name: disk.name.to_string(),
                     pci_path,
+                    num_queues: None,
+                    queue_size: None,
+                    model_number: None,
+                    firmware_revision: None,
+                    ieee_oui: None,
                 })
             }
+            "sata" => {
+                StorageDeviceV0::SataDisk(components::devices::SataDisk {
+                    backend_name: disk.name.to_string(),
+                    pci_path,
+                })
+            }
+            "ide" => StorageDeviceV0::IdeDisk(components::devices::IdeDisk {
+                backend_name: disk.name.to_string(),
+                pci_path,
+            }),
             _ => {
                 return Err(ServerSpecBuilderError::UnrecognizedStorageDevice(
                     disk.device.clone(),
@@ -330,6 +715,7 @@ impl ServerSpecBuilder {
             StorageBackendV0::Blob(components::backends::BlobStorageBackend {
                 base64,
                 readonly: true,
+                persist_path: None,
             });
 
         let device_name = name.to_string();
@@ -337,6 +723,8 @@ impl ServerSpecBuilder {
             StorageDeviceV0::VirtioDisk(components::devices::VirtioDisk {
                 backend_name: name.to_string(),
                 pci_path,
+                num_queues: None,
+                interrupt_moderation: None,
             });
 
         self.builder.add_storage_device(
@@ -372,25 +760,185 @@ impl ServerSpecBuilder {
         let backend_spec = NetworkBackendV0::Virtio(
             components::backends::VirtioNetworkBackend {
                 vnic_name: vnic_name.to_string(),
+                vlan_id: get_int_option(
+                    "network device",
+                    name,
+                    &device.options,
+                    "vlan",
+                )?,
             },
         );
 
-        let device_spec =
+        let device_spec = if device.driver == "pci-e1000" {
+            NetworkDeviceV0::E1000Nic(components::devices::E1000Nic {
+                backend_name: backend_name.clone(),
+                pci_path,
+                rate_limit: None,
+            })
+        } else {
             NetworkDeviceV0::VirtioNic(components::devices::VirtioNic {
                 backend_name: backend_name.clone(),
                 pci_path,
-            });
+                num_queue_pairs: get_int_option(
+                    "network device",
+                    name,
+                    &device.options,
+                    "num_queue_pairs",
+                )?,
+                rate_limit: None,
+                offloads: get_virtio_nic_offloads(name, &device.options)?,
+                link_state: None,
+                interrupt_moderation: None,
+            })
+        };
 
-        self.builder.add_network_device(
+        self.add_vnic_device(
             device_name,
             device_spec,
             backend_name,
             backend_spec,
+        )
+    }
+
+    fn add_passthrough_nic_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let ppt_path = device.get_string("ppt").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get ppt device for passthrough NIC {}",
+                name
+            ))
+        })?;
+
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for network device {}",
+                name
+            ))
+        })?;
+
+        let (device_name, backend_name) = pci_path_to_nic_names(pci_path);
+        self.builder.add_network_device(
+            device_name,
+            NetworkDeviceV0::PassthroughNic(
+                components::devices::PassthroughNic {
+                    backend_name: backend_name.clone(),
+                    pci_path,
+                },
+            ),
+            backend_name,
+            NetworkBackendV0::Ppt(components::backends::PptNetworkBackend {
+                ppt_path: ppt_path.to_string(),
+            }),
         )?;
 
         Ok(())
     }
 
+    fn add_virtio_socket_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let guest_cid: u32 =
+            get_int_option("vsock device", name, &device.options, "guest_cid")?
+                .ok_or_else(|| {
+                    ServerSpecBuilderError::ConfigTomlError(format!(
+                        "Failed to get guest CID for vsock device {}",
+                        name
+                    ))
+                })?;
+
+        let socket_path =
+            device.get_string("socket_path").ok_or_else(|| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Failed to get socket path for vsock device {}",
+                    name
+                ))
+            })?;
+
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for vsock device {}",
+                name
+            ))
+        })?;
+
+        self.builder.set_virtio_socket(components::devices::VirtioSocket {
+            guest_cid,
+            socket_path: socket_path.to_string(),
+            pci_path,
+        })?;
+
+        Ok(())
+    }
+
+    fn add_virtio_console_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let entries =
+            device.options.get("ports").and_then(|v| v.as_array()).ok_or_else(
+                || {
+                    ServerSpecBuilderError::ConfigTomlError(format!(
+                        "Failed to get ports for virtio-console device {}",
+                        name
+                    ))
+                },
+            )?;
+
+        let mut ports = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let get = |key: &str| {
+                entry.get(key).and_then(|v| v.as_str()).ok_or_else(|| {
+                    ServerSpecBuilderError::ConfigTomlError(format!(
+                        "Failed to get {} of port for virtio-console device {}",
+                        key, name
+                    ))
+                })
+            };
+            ports.push(components::devices::VirtioConsolePort {
+                name: get("name")?.to_string(),
+                socket_path: get("socket_path")?.to_string(),
+            });
+        }
+
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for virtio-console device {}",
+                name
+            ))
+        })?;
+
+        self.builder.set_virtio_console(
+            components::devices::VirtioConsole { ports, pci_path },
+        )?;
+
+        Ok(())
+    }
+
+    fn add_virtio_tablet_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for tablet device {}",
+                name
+            ))
+        })?;
+
+        self.builder.set_virtio_tablet(components::devices::VirtioTablet {
+            pci_path,
+        })?;
+
+        Ok(())
+    }
+
     fn add_pci_bridge_from_config(
         &mut self,
         bridge: &config::PciBridge,
@@ -405,6 +953,7 @@ impl ServerSpecBuilder {
             components::devices::PciPciBridge {
                 downstream_bus: bridge.downstream_bus,
                 pci_path,
+                hotplug_slot: bridge.hotplug_slot,
             },
         )?;
 
@@ -422,7 +971,8 @@ impl ServerSpecBuilder {
             match driver {
                 // If this is a storage device, parse its "block_dev" property
                 // to get the name of its corresponding backend.
-                "pci-virtio-block" | "pci-nvme" => {
+                "pci-virtio-block" | "pci-nvme" | "pci-ahci"
+                | "pci-ahci-cdrom" | "pci-piix3-ide" => {
                     let device_spec =
                         make_storage_device_from_config(device_name, device)?;
 
@@ -433,6 +983,15 @@ impl ServerSpecBuilder {
                         StorageDeviceV0::NvmeDisk(disk) => {
                             disk.backend_name.clone()
                         }
+                        StorageDeviceV0::SataDisk(disk) => {
+                            disk.backend_name.clone()
+                        }
+                        StorageDeviceV0::SataCdrom(cdrom) => {
+                            cdrom.backend_name.clone()
+                        }
+                        StorageDeviceV0::IdeDisk(disk) => {
+                            disk.backend_name.clone()
+                        }
                     };
 
                     let backend_config = config
@@ -457,9 +1016,21 @@ impl ServerSpecBuilder {
                         backend_spec,
                     )?;
                 }
-                "pci-virtio-viona" => {
+                "pci-virtio-viona" | "pci-e1000" => {
                     self.add_network_device_from_config(device_name, device)?
                 }
+                "pci-ppt" => {
+                    self.add_passthrough_nic_from_config(device_name, device)?
+                }
+                "pci-virtio-vsock" => {
+                    self.add_virtio_socket_from_config(device_name, device)?
+                }
+                "pci-virtio-console" => {
+                    self.add_virtio_console_from_config(device_name, device)?
+                }
+                "pci-virtio-tablet" => {
+                    self.add_virtio_tablet_from_config(device_name, device)?
+                }
                 #[cfg(feature = "falcon")]
                 "softnpu-pci-port" => {
                     self.add_softnpu_pci_port_from_config(device_name, device)?
@@ -660,6 +1231,7 @@ mod test {
                     block_size: 512,
                     path: "disk1.img".to_string()
                 },
+                lazy_attach: false,
             })
             .is_ok());
         assert!(matches!(
@@ -675,6 +1247,7 @@ mod test {
                             block_size: 512,
                             path: "disk2.img".to_string()
                         },
+                    lazy_attach: false,
                 })
                 .err(),
             Some(ServerSpecBuilderError::InnerBuilderError(
@@ -716,9 +1289,60 @@ mod test {
                             block_size: 512,
                             path: "disk3.img".to_string()
                         },
+                    lazy_attach: false,
                 })
                 .err(),
             Some(ServerSpecBuilderError::UnrecognizedStorageDevice(_))
         ));
     }
+
+    fn nic_request(vnic: &str, slot: u8) -> NetworkInterfaceRequest {
+        NetworkInterfaceRequest { name: vnic.to_string(), slot: Slot(slot) }
+    }
+
+    #[test]
+    fn duplicate_vnic() {
+        let mut builder = default_spec_builder().unwrap();
+        assert!(builder.add_nic_from_request(&nic_request("vnic0", 0)).is_ok());
+        assert!(matches!(
+            builder.add_nic_from_request(&nic_request("vnic0", 1)).err(),
+            Some(ServerSpecBuilderError::InnerBuilderError(
+                SpecBuilderError::VnicInUse(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn duplicate_mac() {
+        let mut builder = default_spec_builder().unwrap();
+        builder.vnic_mac = |name| match name {
+            "vnic0" | "vnic1" => Ok(Some([0xa8, 0x40, 0x25, 0, 0, 1])),
+            "vnic2" => Ok(Some([0xa8, 0x40, 0x25, 0, 0, 2])),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+        };
+
+        assert!(builder.add_nic_from_request(&nic_request("vnic0", 0)).is_ok());
+        assert!(builder.add_nic_from_request(&nic_request("vnic2", 1)).is_ok());
+        match builder.add_nic_from_request(&nic_request("vnic1", 2)).err() {
+            Some(ServerSpecBuilderError::DuplicateMac(first, second, mac)) => {
+                assert_eq!(first, "vnic-0.8.0");
+                assert_eq!(second, "vnic-0.10.0");
+                assert_eq!(mac, "a8:40:25:00:00:01");
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+        assert!(matches!(
+            builder.add_nic_from_request(&nic_request("vnic3", 3)).err(),
+            Some(ServerSpecBuilderError::VnicNotFound(..))
+        ));
+    }
+
+    #[test]
+    fn oui_parsing() {
+        assert_eq!(parse_oui("a8:40:25"), Some([0xa8, 0x40, 0x25]));
+        assert_eq!(parse_oui("A8:40:25"), Some([0xa8, 0x40, 0x25]));
+        assert_eq!(parse_oui("a8:40"), None);
+        assert_eq!(parse_oui("a8:40:25:00"), None);
+        assert_eq!(parse_oui("a8:40:zz"), None);
+    }
 }
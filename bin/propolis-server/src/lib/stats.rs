@@ -7,10 +7,12 @@
 use omicron_common::api::internal::nexus::ProducerEndpoint;
 use omicron_common::api::internal::nexus::ProducerKind;
 use oximeter::{
+    histogram::{BinRange, Histogram},
     types::{Cumulative, ProducerRegistry, Sample},
     Metric, MetricsError, Producer,
 };
 use oximeter_producer::{Config, Error, Server};
+use propolis::util::latency::{LatencyHistogram, LATENCY_BUCKETS};
 use slog::{info, Logger};
 
 use std::net::SocketAddr;
@@ -26,9 +28,17 @@ use crate::stats::virtual_machine::VirtualMachine;
 #[cfg(all(not(test), target_os = "illumos"))]
 use oximeter_instruments::kstat::KstatSampler;
 
+mod block;
+mod migration;
+mod network;
 mod pvpanic;
+mod virtio;
 pub(crate) mod virtual_machine;
+pub use self::block::BlockProducer;
+pub use self::migration::{MigrationProducer, MigrationStats};
+pub use self::network::{NicProducer, NicStatsSource};
 pub use self::pvpanic::PvpanicProducer;
+pub use self::virtio::VirtioProducer;
 
 // Interval on which we ask `oximeter` to poll us for metric data.
 const OXIMETER_STAT_INTERVAL: tokio::time::Duration =
@@ -115,6 +125,26 @@ impl Producer for ServerStatsOuter {
     }
 }
 
+/// Converts a histogram of request latencies, as kept by devices, into an
+/// Oximeter histogram with one bin per bucket, measured in nanoseconds.
+fn latency_histogram(
+    hist: &LatencyHistogram,
+) -> Result<Histogram<u64>, MetricsError> {
+    let bins = (0..LATENCY_BUCKETS)
+        .map(|idx| {
+            let start = LatencyHistogram::bucket_start_ns(idx);
+            let range = if idx + 1 < LATENCY_BUCKETS {
+                let end = LatencyHistogram::bucket_start_ns(idx + 1);
+                BinRange::range(start, end)
+            } else {
+                BinRange::from(start)
+            };
+            (range, hist.counts[idx])
+        })
+        .collect::<Vec<_>>();
+    Ok(Histogram::with_bins(&bins)?)
+}
+
 /// Launches and returns an Oximeter metrics server.
 ///
 /// # Parameters
@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metrics describing the I/O performance of an instance's disks.
+
+use super::latency_histogram;
+use super::virtual_machine::VirtualMachine;
+use chrono::Utc;
+use oximeter::{
+    histogram::Histogram, types::Sample, Metric, MetricsError, Producer,
+};
+use propolis::block;
+use propolis::util::latency::LatencyHistogram;
+use std::sync::Arc;
+
+/// An Oximeter `Metric` holding the distribution of request latencies, in
+/// nanoseconds, for a class of I/O operations issued to a disk.
+#[derive(Debug, Clone, Metric)]
+struct BlockLatency {
+    /// The name of the disk in the instance spec.
+    disk_name: String,
+    /// The kind of operation: "read", "write", or "flush".
+    operation: String,
+    /// Distribution of request latencies, in nanoseconds.
+    #[datum]
+    latency: Histogram<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of requests which have been issued
+/// to a disk's backend but not yet completed.
+#[derive(Debug, Clone, Metric)]
+struct BlockQueueDepth {
+    /// The name of the disk in the instance spec.
+    disk_name: String,
+    /// Number of outstanding requests.
+    #[datum]
+    depth: u64,
+}
+
+/// Produces latency and queue depth metrics for a single block device.
+pub struct BlockProducer {
+    /// The oximeter Target identifying this instance as the source of metric
+    /// data.
+    virtual_machine: VirtualMachine,
+
+    disk_name: String,
+    device: Arc<dyn block::Device>,
+}
+
+impl std::fmt::Debug for BlockProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockProducer")
+            .field("virtual_machine", &self.virtual_machine)
+            .field("disk_name", &self.disk_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlockProducer {
+    pub fn new(
+        virtual_machine: VirtualMachine,
+        disk_name: String,
+        device: Arc<dyn block::Device>,
+    ) -> Self {
+        Self { virtual_machine, disk_name, device }
+    }
+
+    fn latency(
+        &self,
+        operation: &str,
+        hist: &LatencyHistogram,
+    ) -> Result<BlockLatency, MetricsError> {
+        Ok(BlockLatency {
+            disk_name: self.disk_name.clone(),
+            operation: operation.to_string(),
+            latency: latency_histogram(hist)?,
+        })
+    }
+}
+
+impl Producer for BlockProducer {
+    fn produce(
+        &mut self,
+    ) -> Result<Box<dyn Iterator<Item = Sample> + 'static>, MetricsError> {
+        let stats = self.device.attachment().stats().snapshot();
+
+        let queue_depth = BlockQueueDepth {
+            disk_name: self.disk_name.clone(),
+            depth: stats.queue_depth,
+        };
+        let read = self.latency("read", &stats.read)?;
+        let write = self.latency("write", &stats.write)?;
+        let flush = self.latency("flush", &stats.flush)?;
+
+        // Provide all samples with the same timestamp, to simplify alignment.
+        let now = Utc::now();
+        let data = [
+            Sample::new_with_timestamp(now, &self.virtual_machine, &read)?,
+            Sample::new_with_timestamp(now, &self.virtual_machine, &write)?,
+            Sample::new_with_timestamp(now, &self.virtual_machine, &flush)?,
+            Sample::new_with_timestamp(
+                now,
+                &self.virtual_machine,
+                &queue_depth,
+            )?,
+        ];
+
+        Ok(Box::new(data.into_iter()))
+    }
+}
@@ -0,0 +1,281 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metrics describing the live migrations into and out of an instance.
+//!
+//! Migration tasks record what they do in the instance's [`MigrationStats`],
+//! which a [`MigrationProducer`] reports to oximeter.  An instance is the
+//! destination of at most one migration, but may be the source of several,
+//! since a failed migration out leaves the guest running where it was.
+
+use super::virtual_machine::VirtualMachine;
+use crate::migrate::MigrateRole;
+use chrono::{DateTime, Utc};
+use oximeter::{
+    types::{Cumulative, Sample},
+    Metric, MetricsError, Producer,
+};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An Oximeter `Metric` holding the number of migrations which have
+/// completed successfully.
+#[derive(Debug, Clone, Metric)]
+struct MigrationCompleted {
+    /// This instance's role in the migrations: "source" or "destination".
+    role: String,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of migrations which have failed
+/// for a given reason.
+#[derive(Debug, Clone, Metric)]
+struct MigrationFailed {
+    /// This instance's role in the migrations: "source" or "destination".
+    role: String,
+    /// The kind of error which ended the migrations, e.g. "websocket" or
+    /// "device_state".
+    reason: String,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of migrations out of an instance
+/// which were started after the previous one failed.
+#[derive(Debug, Clone, Metric)]
+struct MigrationRetries {
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of bytes of guest memory
+/// transferred by migrations, whether or not they succeeded.
+#[derive(Debug, Clone, Metric)]
+struct MigrationBytes {
+    /// This instance's role in the migrations: "source" or "destination".
+    role: String,
+    #[datum]
+    bytes: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the total time, in nanoseconds, migrations
+/// have spent in a phase of the migration protocol.
+#[derive(Debug, Clone, Metric)]
+struct MigrationPhaseTime {
+    /// This instance's role in the migrations: "source" or "destination".
+    role: String,
+    /// The name of the phase, e.g. "RamPushPrePause".
+    phase: String,
+    #[datum]
+    nanoseconds: Cumulative<u64>,
+}
+
+fn role_name(role: MigrateRole) -> &'static str {
+    match role {
+        MigrateRole::Source => "source",
+        MigrateRole::Destination => "destination",
+    }
+}
+
+/// The counters kept for migrations in which an instance has one role.
+#[derive(Debug, Default)]
+struct RoleStats {
+    completed: u64,
+    failed: BTreeMap<&'static str, u64>,
+    bytes: u64,
+    phase_ns: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    source: RoleStats,
+    destination: RoleStats,
+    retries: u64,
+
+    /// Set if the most recent migration out of this instance failed.
+    last_source_failed: bool,
+}
+
+impl Inner {
+    fn role(&mut self, role: MigrateRole) -> &mut RoleStats {
+        match role {
+            MigrateRole::Source => &mut self.source,
+            MigrateRole::Destination => &mut self.destination,
+        }
+    }
+}
+
+/// The migration counters for an instance, shared by its migration tasks and
+/// its [`MigrationProducer`].
+#[derive(Clone, Debug)]
+pub struct MigrationStats {
+    inner: Arc<Mutex<Inner>>,
+
+    /// When the counters began accumulating.
+    start_time: DateTime<Utc>,
+}
+
+impl Default for MigrationStats {
+    fn default() -> Self {
+        Self { inner: Default::default(), start_time: Utc::now() }
+    }
+}
+
+impl MigrationStats {
+    /// Notes the start of a migration out of the instance.
+    pub fn source_started(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.last_source_failed {
+            inner.retries += 1;
+        }
+    }
+
+    /// Adds `elapsed` to the time spent in `phase` by migrations in which the
+    /// instance had the given role.
+    pub fn phase_finished(
+        &self,
+        role: MigrateRole,
+        phase: &str,
+        elapsed: Duration,
+    ) {
+        let elapsed = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let mut inner = self.inner.lock().unwrap();
+        let total =
+            inner.role(role).phase_ns.entry(phase.to_string()).or_default();
+        *total = total.saturating_add(elapsed);
+    }
+
+    /// Notes the end of a migration which transferred `bytes` bytes of guest
+    /// memory, and which failed with an error of the kind `failure`, if it
+    /// failed.
+    pub fn finished(
+        &self,
+        role: MigrateRole,
+        bytes: u64,
+        failure: Option<&'static str>,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        if let MigrateRole::Source = role {
+            inner.last_source_failed = failure.is_some();
+        }
+        let stats = inner.role(role);
+        stats.bytes += bytes;
+        match failure {
+            Some(reason) => *stats.failed.entry(reason).or_default() += 1,
+            None => stats.completed += 1,
+        }
+    }
+}
+
+/// Produces metrics describing the migrations into and out of an instance.
+#[derive(Clone, Debug)]
+pub struct MigrationProducer {
+    /// The oximeter Target identifying this instance as the source of metric
+    /// data.
+    virtual_machine: VirtualMachine,
+
+    stats: MigrationStats,
+}
+
+impl MigrationProducer {
+    pub fn new(virtual_machine: VirtualMachine, stats: MigrationStats) -> Self {
+        Self { virtual_machine, stats }
+    }
+}
+
+impl Producer for MigrationProducer {
+    fn produce(
+        &mut self,
+    ) -> Result<Box<dyn Iterator<Item = Sample> + 'static>, MetricsError> {
+        let counter = |v| Cumulative::with_start_time(self.stats.start_time, v);
+        let target = &self.virtual_machine;
+        let inner = self.stats.inner.lock().unwrap();
+
+        // Provide all samples with the same timestamp, to simplify alignment.
+        let now = Utc::now();
+        let mut data = vec![Sample::new_with_timestamp(
+            now,
+            target,
+            &MigrationRetries { count: counter(inner.retries) },
+        )?];
+        for (role, stats) in [
+            (MigrateRole::Source, &inner.source),
+            (MigrateRole::Destination, &inner.destination),
+        ] {
+            let role = role_name(role).to_string();
+            let completed = MigrationCompleted {
+                role: role.clone(),
+                count: counter(stats.completed),
+            };
+            let bytes = MigrationBytes {
+                role: role.clone(),
+                bytes: counter(stats.bytes),
+            };
+            data.push(Sample::new_with_timestamp(now, target, &completed)?);
+            data.push(Sample::new_with_timestamp(now, target, &bytes)?);
+            for (reason, &count) in &stats.failed {
+                let failed = MigrationFailed {
+                    role: role.clone(),
+                    reason: reason.to_string(),
+                    count: counter(count),
+                };
+                data.push(Sample::new_with_timestamp(now, target, &failed)?);
+            }
+            for (phase, &ns) in &stats.phase_ns {
+                let time = MigrationPhaseTime {
+                    role: role.clone(),
+                    phase: phase.clone(),
+                    nanoseconds: counter(ns),
+                };
+                data.push(Sample::new_with_timestamp(now, target, &time)?);
+            }
+        }
+
+        Ok(Box::new(data.into_iter()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_follow_failed_migrations_out() {
+        let stats = MigrationStats::default();
+        stats.source_started();
+        stats.finished(MigrateRole::Source, 4096, Some("websocket"));
+        stats.source_started();
+        stats.finished(MigrateRole::Source, 8192, Some("websocket"));
+        stats.source_started();
+        stats.finished(MigrateRole::Source, 8192, None);
+
+        // A success resets the streak of failures.
+        stats.source_started();
+
+        // Migrations in don't count as retries of those out.
+        stats.finished(MigrateRole::Destination, 0, Some("initiate"));
+
+        let inner = stats.inner.lock().unwrap();
+        assert_eq!(inner.retries, 2);
+        assert_eq!(inner.source.completed, 1);
+        assert_eq!(inner.source.failed.get("websocket"), Some(&2));
+        assert_eq!(inner.source.bytes, 20480);
+        assert_eq!(inner.destination.failed.get("initiate"), Some(&1));
+    }
+
+    #[test]
+    fn phase_times_accumulate() {
+        let stats = MigrationStats::default();
+        let ms = Duration::from_millis;
+        stats.phase_finished(MigrateRole::Source, "Sync", ms(2));
+        stats.phase_finished(MigrateRole::Source, "Sync", ms(3));
+        stats.phase_finished(MigrateRole::Destination, "Sync", ms(7));
+
+        let inner = stats.inner.lock().unwrap();
+        assert_eq!(inner.source.phase_ns["Sync"], 5_000_000);
+        assert_eq!(inner.destination.phase_ns["Sync"], 7_000_000);
+    }
+}
@@ -0,0 +1,243 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metrics describing the traffic passed by an instance's virtio NICs.
+//!
+//! NICs whose datapath is in userspace count their own traffic.  The traffic
+//! of those backed by viona is instead taken from the kstats of the VNIC atop
+//! which each sits, much as vCPU usage is taken from the kstats of the VMM.
+
+// As in `virtual_machine`, the kstat-reading half of this module is meaningful
+// only on illumos.
+#![cfg_attr(any(test, not(target_os = "illumos")), allow(dead_code))]
+
+use super::virtual_machine::{Data, NamedData, VirtualMachine};
+use chrono::{DateTime, Utc};
+use oximeter::{
+    types::{Cumulative, Sample},
+    Metric, MetricsError, Producer,
+};
+use propolis::hw::virtio::PciVirtioNet;
+use propolis::net::NicStatsSnapshot;
+use std::sync::Arc;
+
+/// An Oximeter `Metric` holding the number of bytes a NIC has passed in one
+/// direction.
+#[derive(Debug, Clone, Metric)]
+struct NicBytes {
+    /// The name of the NIC in the instance spec.
+    nic_name: String,
+    /// The direction of the traffic, from the guest's perspective: "rx" or
+    /// "tx".
+    direction: String,
+    #[datum]
+    bytes: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of packets a NIC has passed in one
+/// direction.
+#[derive(Debug, Clone, Metric)]
+struct NicPackets {
+    /// The name of the NIC in the instance spec.
+    nic_name: String,
+    /// The direction of the traffic, from the guest's perspective: "rx" or
+    /// "tx".
+    direction: String,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of packets a NIC has dropped, or
+/// failed to pass, in one direction.
+#[derive(Debug, Clone, Metric)]
+struct NicErrors {
+    /// The name of the NIC in the instance spec.
+    nic_name: String,
+    /// The direction of the traffic, from the guest's perspective: "rx" or
+    /// "tx".
+    direction: String,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// Where the traffic counters of a NIC are to be found.
+pub enum NicStatsSource {
+    /// A virtio-net device emulated in userspace, which keeps its own
+    /// counters.
+    Device(Arc<PciVirtioNet>),
+    /// A viona device, whose traffic is counted by the kstats of the named
+    /// VNIC.
+    Vnic(String),
+}
+
+/// Produces traffic metrics for a single NIC.
+pub struct NicProducer {
+    /// The oximeter Target identifying this instance as the source of metric
+    /// data.
+    virtual_machine: VirtualMachine,
+
+    nic_name: String,
+    source: NicStatsSource,
+
+    /// When the device's counters began accumulating.
+    start_time: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for NicProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NicProducer")
+            .field("virtual_machine", &self.virtual_machine)
+            .field("nic_name", &self.nic_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NicProducer {
+    pub fn new(
+        virtual_machine: VirtualMachine,
+        nic_name: String,
+        source: NicStatsSource,
+    ) -> Self {
+        Self { virtual_machine, nic_name, source, start_time: Utc::now() }
+    }
+
+    fn samples(
+        &self,
+        now: DateTime<Utc>,
+        start_time: DateTime<Utc>,
+        stats: &NicStatsSnapshot,
+    ) -> Result<Vec<Sample>, MetricsError> {
+        let counter = |v| Cumulative::with_start_time(start_time, v);
+        let target = &self.virtual_machine;
+        let mut out = Vec::with_capacity(6);
+        for (direction, bytes, packets, errors) in [
+            ("rx", stats.rx_bytes, stats.rx_packets, stats.rx_errors),
+            ("tx", stats.tx_bytes, stats.tx_packets, stats.tx_errors),
+        ] {
+            let bytes = NicBytes {
+                nic_name: self.nic_name.clone(),
+                direction: direction.to_string(),
+                bytes: counter(bytes),
+            };
+            let packets = NicPackets {
+                nic_name: self.nic_name.clone(),
+                direction: direction.to_string(),
+                count: counter(packets),
+            };
+            let errors = NicErrors {
+                nic_name: self.nic_name.clone(),
+                direction: direction.to_string(),
+                count: counter(errors),
+            };
+            out.push(Sample::new_with_timestamp(now, target, &bytes)?);
+            out.push(Sample::new_with_timestamp(now, target, &packets)?);
+            out.push(Sample::new_with_timestamp(now, target, &errors)?);
+        }
+        Ok(out)
+    }
+}
+
+impl Producer for NicProducer {
+    fn produce(
+        &mut self,
+    ) -> Result<Box<dyn Iterator<Item = Sample> + 'static>, MetricsError> {
+        let (start_time, stats) = match &self.source {
+            NicStatsSource::Device(dev) => (self.start_time, dev.stats()),
+            NicStatsSource::Vnic(vnic) => match read_vnic_kstats(vnic) {
+                Some(found) => found,
+                // Without the kstats, there is nothing to report for now.
+                None => return Ok(Box::new(std::iter::empty())),
+            },
+        };
+
+        // Provide all samples with the same timestamp, to simplify alignment.
+        let data = self.samples(Utc::now(), start_time, &stats)?;
+        Ok(Box::new(data.into_iter()))
+    }
+}
+
+// The kstats of a VNIC, like those of any other datalink, are found at
+// `link:0:<link name>`.
+const LINK_KSTAT_MODULE_NAME: &str = "link";
+
+/// Read the traffic counters of the VNIC `vnic`, along with the time at which
+/// they began accumulating.
+#[cfg(all(not(test), target_os = "illumos"))]
+fn read_vnic_kstats(vnic: &str) -> Option<(DateTime<Utc>, NicStatsSnapshot)> {
+    // A fresh handle is opened each time: they cannot be shared across
+    // threads, and metrics are produced only every few seconds in any case.
+    let ctl = kstat_rs::Ctl::new().ok()?;
+    let mut kstat =
+        ctl.filter(Some(LINK_KSTAT_MODULE_NAME), Some(0), Some(vnic)).next()?;
+    let data = ctl.read(&mut kstat).ok()?;
+    let start_time =
+        oximeter_instruments::kstat::hrtime_to_utc(kstat.ks_crtime).ok()?;
+    Some((start_time, vnic_stats_from_kstat(&data)?))
+}
+
+#[cfg(not(all(not(test), target_os = "illumos")))]
+fn read_vnic_kstats(_vnic: &str) -> Option<(DateTime<Utc>, NicStatsSnapshot)> {
+    None
+}
+
+/// Extract traffic counters from the named kstat of a datalink.  The link
+/// receives what is destined for the guest, and transmits what the guest
+/// sends.
+fn vnic_stats_from_kstat(data: &Data<'_>) -> Option<NicStatsSnapshot> {
+    let Data::Named(named) = data else {
+        return None;
+    };
+    let mut stats = NicStatsSnapshot::default();
+    for nd in named.iter() {
+        let value = match nd.value {
+            NamedData::UInt64(v) => v,
+            NamedData::UInt32(v) => u64::from(v),
+            _ => continue,
+        };
+        match nd.name {
+            "rbytes64" => stats.rx_bytes = value,
+            "ipackets64" => stats.rx_packets = value,
+            "ierrors" => stats.rx_errors = value,
+            "obytes64" => stats.tx_bytes = value,
+            "opackets64" => stats.tx_packets = value,
+            "oerrors" => stats.tx_errors = value,
+            _ => {}
+        }
+    }
+    Some(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::virtual_machine::Named;
+
+    #[test]
+    fn vnic_kstat_counters() {
+        let data = Data::Named(vec![
+            Named { name: "rbytes64", value: NamedData::UInt64(1500) },
+            Named { name: "ipackets64", value: NamedData::UInt64(2) },
+            Named { name: "ierrors", value: NamedData::UInt32(1) },
+            Named { name: "obytes64", value: NamedData::UInt64(128) },
+            Named { name: "opackets64", value: NamedData::UInt64(3) },
+            Named { name: "oerrors", value: NamedData::UInt32(0) },
+            // The 32-bit counterparts of the above are ignored
+            Named { name: "rbytes", value: NamedData::UInt32(7) },
+            Named { name: "ifspeed", value: NamedData::UInt64(10_000) },
+            Named { name: "link_state", value: NamedData::String("up") },
+        ]);
+        assert_eq!(
+            vnic_stats_from_kstat(&data),
+            Some(NicStatsSnapshot {
+                rx_bytes: 1500,
+                rx_packets: 2,
+                rx_errors: 1,
+                tx_bytes: 128,
+                tx_packets: 3,
+                tx_errors: 0,
+            })
+        );
+        assert_eq!(vnic_stats_from_kstat(&Data::Null), None);
+    }
+}
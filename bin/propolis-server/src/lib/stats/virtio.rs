@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metrics describing the activity in the virtqueues of an instance's virtio
+//! devices.
+
+use super::latency_histogram;
+use super::virtual_machine::VirtualMachine;
+use chrono::{DateTime, Utc};
+use oximeter::{
+    histogram::Histogram,
+    types::{Cumulative, Sample},
+    Metric, MetricsError, Producer,
+};
+use propolis::hw::virtio::{pci::PciVirtio, VqStatsSnapshot};
+use std::sync::Arc;
+
+/// An Oximeter `Metric` holding the number of notifications a guest driver has
+/// sent for a virtqueue.
+#[derive(Debug, Clone, Metric)]
+struct VirtqueueKicks {
+    /// The name of the device in the instance spec.
+    device_name: String,
+    /// The index of the queue within the device.
+    queue_id: u16,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of interrupts sent to a guest driver
+/// for a virtqueue.
+#[derive(Debug, Clone, Metric)]
+struct VirtqueueInterrupts {
+    /// The name of the device in the instance spec.
+    device_name: String,
+    /// The index of the queue within the device.
+    queue_id: u16,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of descriptor chains processed from
+/// a virtqueue.
+#[derive(Debug, Clone, Metric)]
+struct VirtqueueChains {
+    /// The name of the device in the instance spec.
+    device_name: String,
+    /// The index of the queue within the device.
+    queue_id: u16,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of descriptors, across all chains,
+/// processed from a virtqueue.
+#[derive(Debug, Clone, Metric)]
+struct VirtqueueDescriptors {
+    /// The name of the device in the instance spec.
+    device_name: String,
+    /// The index of the queue within the device.
+    queue_id: u16,
+    #[datum]
+    count: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the number of bytes described by the chains
+/// processed from a virtqueue.
+#[derive(Debug, Clone, Metric)]
+struct VirtqueueBytes {
+    /// The name of the device in the instance spec.
+    device_name: String,
+    /// The index of the queue within the device.
+    queue_id: u16,
+    #[datum]
+    bytes: Cumulative<u64>,
+}
+
+/// An Oximeter `Metric` holding the distribution of times, in nanoseconds,
+/// from a guest driver's kick to the completion of each chain in a virtqueue.
+#[derive(Debug, Clone, Metric)]
+struct VirtqueueLatency {
+    /// The name of the device in the instance spec.
+    device_name: String,
+    /// The index of the queue within the device.
+    queue_id: u16,
+    #[datum]
+    latency: Histogram<u64>,
+}
+
+/// Produces per-queue activity metrics for a single virtio device.
+pub struct VirtioProducer {
+    /// The oximeter Target identifying this instance as the source of metric
+    /// data.
+    virtual_machine: VirtualMachine,
+
+    device_name: String,
+    device: Arc<dyn PciVirtio>,
+
+    /// When the device's counters began accumulating.
+    start_time: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for VirtioProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtioProducer")
+            .field("virtual_machine", &self.virtual_machine)
+            .field("device_name", &self.device_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VirtioProducer {
+    pub fn new(
+        virtual_machine: VirtualMachine,
+        device_name: String,
+        device: Arc<dyn PciVirtio>,
+    ) -> Self {
+        Self { virtual_machine, device_name, device, start_time: Utc::now() }
+    }
+
+    fn queue_samples(
+        &self,
+        now: DateTime<Utc>,
+        stats: &VqStatsSnapshot,
+    ) -> Result<[Sample; 6], MetricsError> {
+        let device_name = self.device_name.clone();
+        let queue_id = stats.queue_id;
+        let counter = |v| Cumulative::with_start_time(self.start_time, v);
+
+        let kicks = VirtqueueKicks {
+            device_name: device_name.clone(),
+            queue_id,
+            count: counter(stats.kicks),
+        };
+        let interrupts = VirtqueueInterrupts {
+            device_name: device_name.clone(),
+            queue_id,
+            count: counter(stats.interrupts),
+        };
+        let chains = VirtqueueChains {
+            device_name: device_name.clone(),
+            queue_id,
+            count: counter(stats.chains),
+        };
+        let descriptors = VirtqueueDescriptors {
+            device_name: device_name.clone(),
+            queue_id,
+            count: counter(stats.descriptors),
+        };
+        let bytes = VirtqueueBytes {
+            device_name: device_name.clone(),
+            queue_id,
+            bytes: counter(stats.bytes),
+        };
+        let latency = VirtqueueLatency {
+            device_name,
+            queue_id,
+            latency: latency_histogram(&stats.latency)?,
+        };
+
+        let target = &self.virtual_machine;
+        Ok([
+            Sample::new_with_timestamp(now, target, &kicks)?,
+            Sample::new_with_timestamp(now, target, &interrupts)?,
+            Sample::new_with_timestamp(now, target, &chains)?,
+            Sample::new_with_timestamp(now, target, &descriptors)?,
+            Sample::new_with_timestamp(now, target, &bytes)?,
+            Sample::new_with_timestamp(now, target, &latency)?,
+        ])
+    }
+}
+
+impl Producer for VirtioProducer {
+    fn produce(
+        &mut self,
+    ) -> Result<Box<dyn Iterator<Item = Sample> + 'static>, MetricsError> {
+        let stats = self.device.virtio_state().queue_stats();
+
+        // Provide all samples with the same timestamp, to simplify alignment.
+        let now = Utc::now();
+        let mut data = Vec::with_capacity(stats.len() * 6);
+        for queue in stats.iter() {
+            data.extend(self.queue_samples(now, queue)?);
+        }
+
+        Ok(Box::new(data.into_iter()))
+    }
+}
@@ -17,7 +17,9 @@ use chrono::{DateTime, Utc};
 use oximeter::{
     types::Cumulative, FieldType, FieldValue, Metric, Sample, Target,
 };
+use propolis_api_types::InstanceMetadata;
 use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
 #[cfg(all(not(test), target_os = "illumos"))]
@@ -117,12 +119,14 @@ mod kstat_types {
 pub use kstat_types::*;
 
 /// A single virtual machine instance.
+///
+/// Clones of a target share its metadata, so that an update to the silo and
+/// project to which the instance belongs is seen by every producer reporting
+/// metrics for it.
 #[derive(Clone, Debug)]
 pub struct VirtualMachine {
-    /// The silo to which the instance belongs.
-    pub silo_id: Uuid,
-    /// The project to which the instance belongs.
-    pub project_id: Uuid,
+    /// The silo and project to which the instance belongs.
+    metadata: Arc<Mutex<InstanceMetadata>>,
     /// The ID of the instance.
     pub instance_id: Uuid,
 
@@ -145,13 +149,17 @@ impl VirtualMachine {
     pub(crate) fn n_vcpus(&self) -> u32 {
         self.n_vcpus
     }
+
+    /// Changes the silo and project to which the instance belongs.
+    pub(crate) fn set_metadata(&self, metadata: InstanceMetadata) {
+        *self.metadata.lock().unwrap() = metadata;
+    }
 }
 
 impl From<&propolis_api_types::InstanceProperties> for VirtualMachine {
     fn from(properties: &propolis_api_types::InstanceProperties) -> Self {
         Self {
-            silo_id: properties.metadata.silo_id,
-            project_id: properties.metadata.project_id,
+            metadata: Arc::new(Mutex::new(properties.metadata.clone())),
             instance_id: properties.id,
             n_vcpus: properties.vcpus.into(),
             vm_name: properties.vm_name(),
@@ -173,9 +181,10 @@ impl Target for VirtualMachine {
     }
 
     fn field_values(&self) -> Vec<FieldValue> {
+        let metadata = self.metadata.lock().unwrap();
         vec![
-            self.silo_id.into(),
-            self.project_id.into(),
+            metadata.silo_id.into(),
+            metadata.project_id.into(),
             self.instance_id.into(),
         ]
     }
@@ -419,7 +428,9 @@ mod test {
     use oximeter::types::Cumulative;
     use oximeter::Datum;
     use oximeter::FieldValue;
+    use propolis_api_types::InstanceMetadata;
     use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
     use uuid::Uuid;
 
     fn test_virtual_machine() -> VirtualMachine {
@@ -430,8 +441,10 @@ mod test {
         const SILO_ID: Uuid =
             uuid::uuid!("6a4bd4b6-e9aa-44d1-b616-399d48baa173");
         VirtualMachine {
-            silo_id: SILO_ID,
-            project_id: PROJECT_ID,
+            metadata: Arc::new(Mutex::new(InstanceMetadata {
+                silo_id: SILO_ID,
+                project_id: PROJECT_ID,
+            })),
             instance_id: INSTANCE_ID,
             n_vcpus: 4,
             vm_name: INSTANCE_ID.to_string(),
@@ -5,9 +5,10 @@
 //! Tasks for vCPU backing threads and controls for them.
 
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicU8, AtomicUsize, Ordering},
     Arc,
 };
+use std::time::{Duration, Instant};
 
 use propolis::{
     bhyve_api,
@@ -29,6 +30,43 @@ pub struct VcpuTasks {
     generation: Arc<AtomicUsize>,
 }
 
+/// The greatest percentage of time that vCPUs may be kept from running.
+pub(crate) const MAX_VCPU_THROTTLE_PCT: u8 = 99;
+
+/// Limits the share of time each vCPU spends running guest code, slowing the
+/// guest (and, chiefly, the rate at which it dirties memory) while it is being
+/// migrated.
+///
+/// A throttled vCPU sleeps after each exit for long enough to keep to the
+/// limit. A guest which rarely exits must be made to do so regularly, by
+/// kicking its vCPUs, for the limit to be enforced.
+#[derive(Default)]
+pub(crate) struct VcpuThrottle {
+    percent: AtomicU8,
+}
+
+impl VcpuThrottle {
+    /// Keeps vCPUs from running for `percent` percent of the time, clamped to
+    /// [`MAX_VCPU_THROTTLE_PCT`]. Zero removes the throttle.
+    pub fn set(&self, percent: u8) {
+        self.percent
+            .store(percent.min(MAX_VCPU_THROTTLE_PCT), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u8 {
+        self.percent.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long a vCPU which has just run for `ran` should sleep.
+    fn delay(&self, ran: Duration) -> Option<Duration> {
+        let pct = u32::from(self.get());
+        if pct == 0 {
+            return None;
+        }
+        Some(ran * pct / (100 - pct))
+    }
+}
+
 #[cfg_attr(test, mockall::automock)]
 pub(crate) trait VcpuTaskController {
     fn new_generation(&self);
@@ -41,6 +79,7 @@ impl VcpuTasks {
     pub(crate) fn new(
         machine: &propolis::Machine,
         event_handler: Arc<super::vm::SharedVmState>,
+        throttle: Arc<VcpuThrottle>,
         log: slog::Logger,
     ) -> Result<Self, VcpuTaskError> {
         let generation = Arc::new(AtomicUsize::new(0));
@@ -51,6 +90,7 @@ impl VcpuTasks {
             let task_log = log.new(slog::o!("vcpu" => vcpu.id));
             let task_event_handler = event_handler.clone();
             let task_gen = generation.clone();
+            let task_throttle = throttle.clone();
             let thread = std::thread::Builder::new()
                 .name(format!("vcpu-{}", vcpu.id))
                 .spawn(move || {
@@ -59,6 +99,7 @@ impl VcpuTasks {
                         task,
                         task_event_handler,
                         task_gen,
+                        task_throttle,
                         task_log,
                     )
                 })
@@ -74,6 +115,7 @@ impl VcpuTasks {
         task: propolis::tasks::TaskHdl,
         event_handler: Arc<super::vm::SharedVmState>,
         generation: Arc<AtomicUsize>,
+        throttle: Arc<VcpuThrottle>,
         log: slog::Logger,
     ) {
         info!(log, "Starting vCPU thread");
@@ -115,6 +157,7 @@ impl VcpuTasks {
                 None => {}
             }
 
+            let entered = Instant::now();
             exit = match vcpu.run(&entry, force_exit_when_consistent) {
                 Err(e) => {
                     event_handler.io_error_event(vcpu.id, e);
@@ -123,6 +166,9 @@ impl VcpuTasks {
                 }
                 Ok(exit) => exit,
             };
+            if let Some(delay) = throttle.delay(entered.elapsed()) {
+                std::thread::sleep(delay);
+            }
 
             entry = vcpu.process_vmexit(&exit).unwrap_or_else(|| {
                 match exit.kind {
@@ -225,3 +271,30 @@ impl VcpuTaskController for VcpuTasks {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn throttle_delay() {
+        let throttle = VcpuThrottle::default();
+        assert_eq!(throttle.delay(Duration::from_millis(10)), None);
+
+        throttle.set(75);
+        assert_eq!(
+            throttle.delay(Duration::from_millis(10)),
+            Some(Duration::from_millis(30))
+        );
+
+        throttle.set(100);
+        assert_eq!(throttle.get(), MAX_VCPU_THROTTLE_PCT);
+        assert_eq!(
+            throttle.delay(Duration::from_millis(10)),
+            Some(Duration::from_millis(990))
+        );
+
+        throttle.set(0);
+        assert_eq!(throttle.delay(Duration::from_millis(10)), None);
+    }
+}
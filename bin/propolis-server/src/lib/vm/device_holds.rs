@@ -0,0 +1,216 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Holds on individual devices, pausing them while the rest of the instance
+//! keeps running.
+//!
+//! Some requests made of a running instance (exporting or snapshotting one of
+//! its disks, or replacing a disk's backend) need one device to stop taking new
+//! I/O from the guest for a while.  They pause the device by taking a
+//! [`DeviceHold`] on it.  Several requests may hold the same device at once:
+//! it is paused when the first hold is taken, and resumed when the last one is
+//! released.
+//!
+//! The state driver pauses and resumes all of the instance's devices itself,
+//! e.g. to migrate the instance or at a client's request, and does so through
+//! [`DeviceHolds`] so that the two don't undo each other's work.  No holds can
+//! be taken while the state driver has the devices paused.  A device whose
+//! last hold is released while they're paused stays paused until the state
+//! driver resumes them, and a device which is still held when the state driver
+//! resumes the others stays paused until its last hold is released.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use propolis::common::Lifecycle;
+
+use crate::server::DeviceMap;
+
+type Device = Arc<dyn Lifecycle>;
+
+/// Identifies a device by the address of its object, since one object (an IDE
+/// controller, say) may serve several disks.
+fn key(device: &Device) -> usize {
+    Arc::as_ptr(device) as *const () as usize
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Whether the state driver has paused all of the instance's devices.
+    vm_paused: bool,
+
+    /// The number of holds on each held device.
+    held: BTreeMap<usize, usize>,
+}
+
+/// The holds on an instance's devices.
+#[derive(Default)]
+pub(crate) struct DeviceHolds {
+    inner: Mutex<Inner>,
+}
+
+impl DeviceHolds {
+    /// Takes a hold on `device`, asking it to pause if it isn't already held,
+    /// or returns `None` if the state driver has paused the instance's devices.
+    ///
+    /// The device may not have finished pausing when this returns; callers
+    /// wait for [`DeviceHold::paused`] before relying on it.
+    pub(crate) fn hold(self: &Arc<Self>, device: Device) -> Option<DeviceHold> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.vm_paused {
+            return None;
+        }
+        let count = inner.held.entry(key(&device)).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            device.pause();
+        }
+        Some(DeviceHold { holds: self.clone(), device })
+    }
+
+    /// Records that the state driver is pausing all of `devices`, calling
+    /// `pause` on each of those which isn't already paused by a hold.
+    pub(crate) fn pause_all(
+        &self,
+        devices: &DeviceMap,
+        mut pause: impl FnMut(&str, &Device),
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.vm_paused = true;
+        for (name, dev) in devices.iter() {
+            if !inner.held.contains_key(&key(dev)) {
+                pause(name, dev);
+            }
+        }
+    }
+
+    /// Records that the state driver is resuming all of `devices`, calling
+    /// `resume` on each of those which isn't held.
+    pub(crate) fn resume_all(
+        &self,
+        devices: &DeviceMap,
+        mut resume: impl FnMut(&str, &Device),
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.vm_paused = false;
+        for (name, dev) in devices.iter() {
+            if !inner.held.contains_key(&key(dev)) {
+                resume(name, dev);
+            }
+        }
+    }
+}
+
+/// A hold on one device, which keeps it paused until the hold is dropped.
+pub(crate) struct DeviceHold {
+    holds: Arc<DeviceHolds>,
+    device: Device,
+}
+
+impl DeviceHold {
+    /// Returns a future which completes once the held device has paused.
+    pub(crate) fn paused(&self) -> BoxFuture<'static, ()> {
+        self.device.paused()
+    }
+}
+
+impl Drop for DeviceHold {
+    fn drop(&mut self) {
+        let mut inner = self.holds.inner.lock().unwrap();
+        let key = key(&self.device);
+        let count = inner.held.get_mut(&key).expect("held device is counted");
+        *count -= 1;
+        if *count == 0 {
+            inner.held.remove(&key);
+            if !inner.vm_paused {
+                self.device.resume();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct TestDevice {
+        pauses: AtomicUsize,
+        resumes: AtomicUsize,
+    }
+
+    impl Lifecycle for TestDevice {
+        fn type_name(&self) -> &'static str {
+            "test-device"
+        }
+
+        fn pause(&self) {
+            self.pauses.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn resume(&self) {
+            self.resumes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counts(dev: &TestDevice) -> (usize, usize) {
+        (dev.pauses.load(Ordering::SeqCst), dev.resumes.load(Ordering::SeqCst))
+    }
+
+    fn setup() -> (Arc<DeviceHolds>, Arc<TestDevice>, DeviceMap) {
+        let dev = Arc::new(TestDevice::default());
+        let mut devices = DeviceMap::new();
+        devices.insert("dev".to_string(), dev.clone() as Device);
+        (Arc::new(DeviceHolds::default()), dev, devices)
+    }
+
+    #[test]
+    fn overlapping_holds_pause_and_resume_once() {
+        let (holds, dev, _) = setup();
+        let first = holds.hold(dev.clone()).unwrap();
+        let second = holds.hold(dev.clone()).unwrap();
+        assert_eq!(counts(&dev), (1, 0));
+        drop(first);
+        assert_eq!(counts(&dev), (1, 0));
+        drop(second);
+        assert_eq!(counts(&dev), (1, 1));
+    }
+
+    #[test]
+    fn no_holds_while_instance_paused() {
+        let (holds, dev, devices) = setup();
+        holds.pause_all(&devices, |_, d| d.pause());
+        assert!(holds.hold(dev.clone()).is_none());
+        holds.resume_all(&devices, |_, d| d.resume());
+        assert!(holds.hold(dev.clone()).is_some());
+    }
+
+    #[test]
+    fn release_while_instance_paused_leaves_device_paused() {
+        let (holds, dev, devices) = setup();
+        let hold = holds.hold(dev.clone()).unwrap();
+        holds.pause_all(&devices, |_, d| d.pause());
+        // The held device isn't paused a second time.
+        assert_eq!(counts(&dev), (1, 0));
+        drop(hold);
+        assert_eq!(counts(&dev), (1, 0));
+        holds.resume_all(&devices, |_, d| d.resume());
+        assert_eq!(counts(&dev), (1, 1));
+    }
+
+    #[test]
+    fn held_device_stays_paused_when_instance_resumes() {
+        let (holds, dev, devices) = setup();
+        holds.pause_all(&devices, |_, d| d.pause());
+        holds.resume_all(&devices, |_, d| d.resume());
+        let hold = holds.hold(dev.clone()).unwrap();
+        holds.pause_all(&devices, |_, d| d.pause());
+        holds.resume_all(&devices, |_, d| d.resume());
+        assert_eq!(counts(&dev), (2, 1));
+        drop(hold);
+        assert_eq!(counts(&dev), (2, 2));
+    }
+}
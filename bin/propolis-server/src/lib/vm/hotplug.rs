@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The kinds of device which can be attached to or detached from a running
+//! instance.
+//!
+//! Requests to the generic device endpoints are checked against this registry
+//! before being handed to the hotplug routine for the device's kind, so that
+//! requests for kinds which can't be hotplugged are refused in one place, with
+//! one explanation.  Supporting a new kind means adding it here and giving it
+//! attach and detach routines that keep the instance spec up to date.
+
+use propolis_api_types::{
+    instance_spec::v0::{NetworkDeviceV0, StorageDeviceV0},
+    DeviceSpecFragment,
+};
+
+use super::VmControllerError;
+
+/// An operation on a running instance's devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum HotplugOp {
+    Attach,
+    Detach,
+}
+
+impl std::fmt::Display for HotplugOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Attach => write!(f, "attached to"),
+            Self::Detach => write!(f, "detached from"),
+        }
+    }
+}
+
+/// The operations supported on one kind of device, named as it is in the
+/// instance spec.
+struct HotplugCapability {
+    kind: &'static str,
+    attach: bool,
+    detach: bool,
+}
+
+const CAPABILITIES: &[HotplugCapability] = &[
+    HotplugCapability { kind: "NvmeDisk", attach: true, detach: true },
+    HotplugCapability { kind: "VirtioNic", attach: true, detach: true },
+    // Passthrough NICs are detached to ready an instance for migration, but
+    // can't be attached to a running instance.
+    HotplugCapability { kind: "PassthroughNic", attach: false, detach: true },
+];
+
+/// Returns the kind of a storage device, named as it is in the instance spec.
+pub(super) fn storage_kind(device: &StorageDeviceV0) -> &'static str {
+    match device {
+        StorageDeviceV0::VirtioDisk(_) => "VirtioDisk",
+        StorageDeviceV0::NvmeDisk(_) => "NvmeDisk",
+        StorageDeviceV0::SataDisk(_) => "SataDisk",
+        StorageDeviceV0::SataCdrom(_) => "SataCdrom",
+        StorageDeviceV0::IdeDisk(_) => "IdeDisk",
+    }
+}
+
+/// Returns the kind of a network device, named as it is in the instance spec.
+pub(super) fn network_kind(device: &NetworkDeviceV0) -> &'static str {
+    match device {
+        NetworkDeviceV0::VirtioNic(_) => "VirtioNic",
+        NetworkDeviceV0::E1000Nic(_) => "E1000Nic",
+        NetworkDeviceV0::PassthroughNic(_) => "PassthroughNic",
+    }
+}
+
+/// Returns the kind of the device described by `fragment`.
+pub(super) fn fragment_kind(fragment: &DeviceSpecFragment) -> &'static str {
+    match fragment {
+        DeviceSpecFragment::Storage { device, .. } => storage_kind(device),
+        DeviceSpecFragment::Network { device, .. } => network_kind(device),
+    }
+}
+
+/// Checks that devices of `kind` support `op`.
+pub(super) fn check(
+    kind: &'static str,
+    op: HotplugOp,
+) -> Result<(), VmControllerError> {
+    let supported = CAPABILITIES.iter().find(|c| c.kind == kind).is_some_and(
+        |c| match op {
+            HotplugOp::Attach => c.attach,
+            HotplugOp::Detach => c.detach,
+        },
+    );
+    if supported {
+        Ok(())
+    } else {
+        Err(VmControllerError::InvalidHotplugRequest(format!(
+            "{kind} devices cannot be {op} a running instance"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities_by_kind() {
+        assert!(check("NvmeDisk", HotplugOp::Attach).is_ok());
+        assert!(check("VirtioNic", HotplugOp::Detach).is_ok());
+        assert!(check("PassthroughNic", HotplugOp::Detach).is_ok());
+        assert!(check("PassthroughNic", HotplugOp::Attach).is_err());
+        assert!(check("SataCdrom", HotplugOp::Attach).is_err());
+        assert!(check("E1000Nic", HotplugOp::Detach).is_err());
+    }
+}
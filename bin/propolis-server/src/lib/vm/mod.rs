@@ -35,47 +35,101 @@ use std::{
     collections::{BTreeMap, VecDeque},
     fmt::Debug,
     net::SocketAddr,
+    path::{Path, PathBuf},
     pin::Pin,
-    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, Weak,
+    },
     task::{Context, Poll},
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use oximeter::types::ProducerRegistry;
 use propolis::{
-    hw::{ps2::ctrl::PS2Ctrl, qemu::ramfb::RamFb, uart::LpcUart},
+    block,
+    hw::{
+        chipset::i440fx,
+        pci,
+        ps2::ctrl::PS2Ctrl,
+        qemu::{
+            fwcfg::{Entry as FwCfgEntry, FwCfg},
+            pvpanic::QemuPvpanic,
+            ramfb::{RamFb, Resolution},
+        },
+        uart::LpcUart,
+        virtio::PciVirtioTablet,
+    },
     vmm::Machine,
 };
 use propolis_api_types::{
-    instance_spec::VersionedInstanceSpec, InstanceProperties,
-    InstanceState as ApiInstanceState,
+    instance_spec::{
+        components::devices::{
+            NicLinkState, NicRateLimit, NvmeDisk, SerialPortNumber, VirtioNic,
+        },
+        v0::{
+            DeviceSpecV0, NetworkBackendV0, NetworkDeviceV0, StorageBackendV0,
+            StorageDeviceV0,
+        },
+        PciPath, VersionedInstanceSpec,
+    },
+    CdromMedia, DeviceSpecFragment, ErrorCode, InstanceEventKind,
+    InstanceMigrateStatusResponse as ApiMigrationStatus, InstanceProperties,
+    InstancePropertiesUpdateRequest, InstanceState as ApiInstanceState,
     InstanceStateMonitorResponse as ApiMonitoredState,
-    InstanceStateRequested as ApiInstanceStateRequested,
+    InstanceStateRequested as ApiInstanceStateRequested, MigrationAutoConverge,
+    MigrationDirtyTracking, MigrationFailure as ApiMigrationFailure,
     MigrationState as ApiMigrationState,
 };
-use slog::{debug, error, info, Logger};
+use slog::{debug, error, info, warn, Logger};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc::error::TrySendError, oneshot};
 use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
 use crate::{
+    guest_agent::GuestAgent,
+    history::EventHistory,
     initializer::{
-        build_instance, MachineInitializer, MachineInitializerState,
+        block_error_policy, build_instance, check_hotplug_disk,
+        check_hotplug_nic, check_viona_nic, create_nvme_disk,
+        create_storage_backend_from_spec, create_viona_nic, nic_link_up,
+        nic_rate_limits, HotplugBridgeMap, MachineInitializer,
+        MachineInitializerState, StorageBackendInstance,
+    },
+    migrate::{
+        self,
+        progress::{MigrationProgress, ProgressSnapshot},
+        retry::Retrier,
+        source::{CancelHandle, RamStream},
+        throttle::BandwidthLimit,
+        tls::SourceConnector,
+        MigrateError, MigrateRole,
     },
-    migrate::{self, MigrateError},
     serial::Serial,
-    server::{BlockBackendMap, CrucibleBackendMap, DeviceMap, StaticConfig},
+    server::{
+        BlockBackendMap, CrucibleBackendMap, DeviceMap, MigrationPayloadDirs,
+        NicLinkMap, NicRateLimiterMap, StaticConfig, StorageDevice,
+        StorageDeviceMap, VirtioDeviceMap,
+    },
+    stats::{virtual_machine::VirtualMachine, MigrationStats},
+    vcpu_tasks::VcpuThrottle,
     vm::request_queue::ExternalRequest,
 };
 
+pub(crate) use self::device_holds::DeviceHold;
+use self::device_holds::DeviceHolds;
+use self::hotplug::HotplugOp;
 use self::request_queue::{ExternalRequestQueue, RequestDeniedReason};
 pub use nexus_client::Client as NexusClient;
 
+mod device_holds;
+mod hotplug;
 mod request_queue;
 mod state_driver;
+pub(crate) mod supervisor;
 
 #[derive(Debug, Error)]
 pub enum VmControllerError {
@@ -114,12 +168,130 @@ pub enum VmControllerError {
 
     #[error("Failed to create state worker: {0}")]
     StateWorkerCreationFailed(std::io::Error),
+
+    #[error("The instance must be running, but is {0:?}")]
+    InstanceNotRunning(ApiInstanceState),
+
+    #[error("No storage device named {0:?}")]
+    NoSuchStorageDevice(String),
+
+    #[error("No network device named {0:?}")]
+    NoSuchNetworkDevice(String),
+
+    #[error("No device named {0:?}")]
+    NoSuchDevice(String),
+
+    #[error("Invalid storage backend replacement: {0}")]
+    InvalidBackendReplacement(String),
+
+    #[error("Failed to replace storage backend: {0}")]
+    BackendReplacementFailed(std::io::Error),
+
+    #[error("Failed to open storage backend {0:?}: {1}")]
+    BackendUnreachable(String, std::io::Error),
+
+    #[error("A device is already attached at PCI path {0}")]
+    PciSlotInUse(PciPath),
+
+    #[error("Storage device {0:?} does not have removable media")]
+    NotRemovableMedia(String),
+
+    #[error("Invalid hotplug request: {0}")]
+    InvalidHotplugRequest(String),
+
+    #[error("Hotplug failed: {0}")]
+    HotplugFailed(String),
+
+    #[error("Invalid NIC rate limit: {0}")]
+    InvalidRateLimit(String),
+
+    #[error("NIC {0:?} does not support link state control")]
+    NoLinkStateControl(String),
+
+    #[error("The instance has no framebuffer")]
+    NoFramebuffer,
+
+    #[error("Invalid display resolution: {0}")]
+    InvalidDisplayResolution(String),
+
+    #[error("Failed to inject NMI: {0}")]
+    NmiInjectionFailed(std::io::Error),
+}
+
+impl VmControllerError {
+    /// Returns the code identifying this error to API clients.
+    fn error_code(&self) -> ErrorCode {
+        use request_queue::RequestDeniedReason as Denied;
+        match self {
+            VmControllerError::InstanceNotActive
+            | VmControllerError::StateChangeRequestDenied(
+                Denied::InstanceNotActive,
+            ) => ErrorCode::InstanceNotActive,
+            VmControllerError::InstanceHaltPending
+            | VmControllerError::StateChangeRequestDenied(
+                Denied::HaltPending,
+            ) => ErrorCode::HaltPending,
+            VmControllerError::InstanceNotRunning(ApiInstanceState::Paused) => {
+                ErrorCode::InstancePaused
+            }
+            VmControllerError::InstanceNotRunning(_) => {
+                ErrorCode::InstanceNotActive
+            }
+            VmControllerError::AlreadyMigrationSource
+            | VmControllerError::InvalidRequestForMigrationSource(_)
+            | VmControllerError::MigrationTargetInProgress
+            | VmControllerError::StateChangeRequestDenied(
+                Denied::MigrationTargetInProgress
+                | Denied::AlreadyMigrationSource
+                | Denied::InvalidRequestForMigrationSource,
+            ) => ErrorCode::MigrationInProgress,
+            VmControllerError::MigrationTargetPreviouslyCompleted
+            | VmControllerError::MigrationTargetFailed
+            | VmControllerError::TooLateToBeMigrationTarget => {
+                ErrorCode::NotMigrationTarget
+            }
+            VmControllerError::StateChangeRequestDenied(
+                Denied::StartInProgress,
+            ) => ErrorCode::StartInProgress,
+            VmControllerError::StateChangeRequestDenied(
+                Denied::InstancePaused,
+            ) => ErrorCode::InstancePaused,
+            VmControllerError::StateChangeRequestDenied(
+                Denied::InstanceFailed,
+            ) => ErrorCode::InstanceFailed,
+            VmControllerError::MigrationProtocolError(e) => e.error_code(),
+            VmControllerError::NoSuchStorageDevice(_)
+            | VmControllerError::NoSuchNetworkDevice(_)
+            | VmControllerError::NoSuchDevice(_) => ErrorCode::NoSuchDevice,
+            VmControllerError::PciSlotInUse(_) => ErrorCode::PciSlotInUse,
+            VmControllerError::BackendUnreachable(..) => {
+                ErrorCode::BackendUnreachable
+            }
+            VmControllerError::InvalidBackendReplacement(_)
+            | VmControllerError::NotRemovableMedia(_)
+            | VmControllerError::InvalidHotplugRequest(_)
+            | VmControllerError::InvalidRateLimit(_)
+            | VmControllerError::NoLinkStateControl(_)
+            | VmControllerError::NoFramebuffer
+            | VmControllerError::InvalidDisplayResolution(_) => {
+                ErrorCode::InvalidRequest
+            }
+            VmControllerError::VcpuWorkerCreationFailed(_)
+            | VmControllerError::StateWorkerCreationFailed(_)
+            | VmControllerError::BackendReplacementFailed(_)
+            | VmControllerError::HotplugFailed(_)
+            | VmControllerError::NmiInjectionFailed(_) => {
+                ErrorCode::OperationFailed
+            }
+        }
+    }
 }
 
 impl From<VmControllerError> for dropshot::HttpError {
     fn from(vm_error: VmControllerError) -> Self {
         use dropshot::HttpError;
-        match vm_error {
+        let code = vm_error.error_code();
+        let mut error = match vm_error {
             VmControllerError::AlreadyMigrationSource
             | VmControllerError::InvalidRequestForMigrationSource(_)
             | VmControllerError::MigrationTargetInProgress
@@ -127,6 +299,7 @@ impl From<VmControllerError> for dropshot::HttpError {
             | VmControllerError::TooLateToBeMigrationTarget
             | VmControllerError::StateChangeRequestDenied(_)
             | VmControllerError::InstanceNotActive
+            | VmControllerError::InstanceNotRunning(_)
             | VmControllerError::InstanceHaltPending
             | VmControllerError::MigrationTargetPreviouslyCompleted => {
                 HttpError::for_status(
@@ -134,15 +307,61 @@ impl From<VmControllerError> for dropshot::HttpError {
                     http::status::StatusCode::FORBIDDEN,
                 )
             }
+            VmControllerError::NoSuchStorageDevice(_)
+            | VmControllerError::NoSuchNetworkDevice(_)
+            | VmControllerError::NoSuchDevice(_) => {
+                HttpError::for_not_found(None, vm_error.to_string())
+            }
+            VmControllerError::PciSlotInUse(_) => HttpError::for_client_error(
+                None,
+                http::status::StatusCode::CONFLICT,
+                vm_error.to_string(),
+            ),
+            VmControllerError::InvalidBackendReplacement(_)
+            | VmControllerError::NotRemovableMedia(_)
+            | VmControllerError::InvalidHotplugRequest(_)
+            | VmControllerError::InvalidRateLimit(_)
+            | VmControllerError::NoLinkStateControl(_)
+            | VmControllerError::NoFramebuffer
+            | VmControllerError::InvalidDisplayResolution(_) => {
+                HttpError::for_bad_request(None, vm_error.to_string())
+            }
+            VmControllerError::BackendUnreachable(..) => {
+                HttpError::for_unavail(None, vm_error.to_string())
+            }
             VmControllerError::MigrationProtocolError(_)
             | VmControllerError::VcpuWorkerCreationFailed(_)
-            | VmControllerError::StateWorkerCreationFailed(_) => {
+            | VmControllerError::StateWorkerCreationFailed(_)
+            | VmControllerError::BackendReplacementFailed(_)
+            | VmControllerError::HotplugFailed(_)
+            | VmControllerError::NmiInjectionFailed(_) => {
                 HttpError::for_internal_error(format!(
                     "Instance operation failed: {}",
                     vm_error
                 ))
             }
-        }
+        };
+        error.error_code = Some(code.to_string());
+        error
+    }
+}
+
+/// Checks that none of the storage or network devices in `devices` is attached
+/// at `path`.
+fn check_pci_path_free(
+    devices: &DeviceSpecV0,
+    path: PciPath,
+) -> Result<(), VmControllerError> {
+    let in_use = devices
+        .storage_devices
+        .values()
+        .map(StorageDeviceV0::pci_path)
+        .chain(devices.network_devices.values().map(NetworkDeviceV0::pci_path))
+        .any(|p| p == path);
+    if in_use {
+        Err(VmControllerError::PciSlotInUse(path))
+    } else {
+        Ok(())
     }
 }
 
@@ -152,31 +371,81 @@ pub(crate) struct VmObjects {
     /// The underlying Propolis `Machine` this controller is managing.
     machine: Option<Machine>,
 
-    /// The instance properties supplied when this controller was created.
-    properties: InstanceProperties,
+    /// The instance's properties, as supplied when this controller was
+    /// created and changed since.
+    properties: Mutex<InstanceProperties>,
+
+    /// The target identifying the instance in the metrics reported for it.
+    virtual_machine: VirtualMachine,
 
-    /// The instance spec used to create this controller's VM.
+    /// The instance spec used to create this controller's VM, updated as
+    /// its devices and backends are changed at runtime.
     spec: tokio::sync::Mutex<VersionedInstanceSpec>,
 
+    /// The number of times the instance spec has changed since the VM was
+    /// created.  This is only changed with the spec's lock held.
+    spec_generation: AtomicU64,
+
     /// Map of the emulated devices associated with the VM
-    devices: DeviceMap,
+    devices: Mutex<DeviceMap>,
+
+    /// The instance's bridges with PCIe hotplug slots, keyed by their
+    /// downstream bus numbers.
+    hotplug_bridges: HotplugBridgeMap,
 
     /// Map of the instance's active block backends.
-    block_backends: BlockBackendMap,
+    block_backends: Mutex<BlockBackendMap>,
 
     /// Map of the instance's active Crucible backends.
-    crucible_backends: CrucibleBackendMap,
+    crucible_backends: Mutex<CrucibleBackendMap>,
 
-    /// A wrapper around the instance's first COM port, suitable for providing a
-    /// connection to a guest's serial console.
-    com1: Arc<Serial<LpcUart>>,
+    /// Map of the instance's storage devices, keyed by the names given to them
+    /// in the instance spec.
+    ///
+    /// These three maps change only when a storage device's backend is
+    /// replaced (see [`VmController::replace_storage_backend`]), or when a
+    /// disk is hotplugged.  When more than one of them is locked, they are
+    /// locked in the order declared here.
+    storage_devices: Mutex<StorageDeviceMap>,
+
+    /// Map of the instance's virtio devices, keyed by the names given to them
+    /// in the instance spec.
+    virtio_devices: VirtioDeviceMap,
+
+    /// Map of the rate limiters of the instance's userspace NICs, keyed by
+    /// the names given to the NICs in the instance spec.
+    nic_rate_limiters: NicRateLimiterMap,
+
+    /// Map of the link controls of the instance's virtio NICs, keyed by the
+    /// names given to the NICs in the instance spec.  Hotplugged NICs are
+    /// added and removed as they are attached and detached.
+    nic_links: Mutex<NicLinkMap>,
+
+    /// Wrappers around each of the instance's COM ports, suitable for
+    /// providing connections to them.  COM1, the guest's serial console, is
+    /// always present.
+    serial_ports: BTreeMap<SerialPortNumber, Arc<Serial<LpcUart>>>,
 
     /// An optional reference to the guest's framebuffer.
     framebuffer: Option<Arc<RamFb>>,
 
+    /// The guest's `fw_cfg` device, through which the framebuffer's EDID is
+    /// published.
+    fwcfg: Arc<FwCfg>,
+
     /// A reference to the guest's PS/2 controller.
     ps2ctrl: Arc<PS2Ctrl>,
 
+    /// The chipset's power management device, through which the guest's ACPI
+    /// power button is pressed.
+    chipset_pm: Arc<i440fx::Piix3PM>,
+
+    /// A reference to the guest's tablet, if it has one.
+    tablet: Option<Arc<PciVirtioTablet>>,
+
+    /// A reference to the guest's pvpanic device, if it has one.
+    pvpanic: Option<Arc<QemuPvpanic>>,
+
     /// A notification receiver to which the state worker publishes the most
     /// recent instance state information.
     monitor_rx: tokio::sync::watch::Receiver<ApiMonitoredState>,
@@ -184,19 +453,27 @@ pub(crate) struct VmObjects {
 
 /// A message sent from a live migration destination task to update the
 /// externally visible state of the migration attempt.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum MigrateTargetCommand {
     /// Update the externally-visible migration state.
     UpdateState(ApiMigrationState),
+
+    /// Move the externally-visible migration state to `Error`, recording how
+    /// the migration failed.
+    Failed(ApiMigrationFailure),
 }
 
 /// A message sent from a live migration driver to the state worker, asking it
 /// to act on source instance components on the task's behalf.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum MigrateSourceCommand {
     /// Update the externally-visible migration state.
     UpdateState(ApiMigrationState),
 
+    /// Move the externally-visible migration state to `Error`, recording how
+    /// the migration failed.
+    Failed(ApiMigrationFailure),
+
     /// Pause the instance's devices and CPUs.
     Pause,
 }
@@ -226,7 +503,7 @@ enum MigrateTaskEvent<T> {
 /// The vCPU-sourced events carry a time element (duration since VM boot) as
 /// emitted by the kernel vmm.  This is used to deduplicate events when all
 /// vCPUs running in-kernel are kicked out for the suspend state.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum GuestEvent {
     /// VM entered halt state
     VcpuSuspendHalt(Duration),
@@ -238,6 +515,11 @@ enum GuestEvent {
     ChipsetHalt,
     /// Chipset signaled reboot condition
     ChipsetReset,
+    /// I/O to the named block device is failing and being retried, per the
+    /// device's `Pause` error policy
+    BlockIoStalled(String),
+    /// I/O to the named block device, which had stalled, has recovered
+    BlockIoResumed(String),
 }
 
 /// Shared instance state guarded by the controller's state mutex. This state is
@@ -255,6 +537,10 @@ struct SharedVmStateInner {
     /// progress even if the state driver has yet to pick up the live migration
     /// tasks from its queue.
     pending_migration_id: Option<Uuid>,
+
+    /// When the state driver began handling its current event, or `None` if
+    /// it's waiting for one.
+    handling_since: Option<Instant>,
 }
 
 impl SharedVmStateInner {
@@ -265,6 +551,7 @@ impl SharedVmStateInner {
             external_request_queue: ExternalRequestQueue::new(queue_log),
             guest_event_queue: VecDeque::new(),
             pending_migration_id: None,
+            handling_since: None,
         }
     }
 }
@@ -273,6 +560,10 @@ impl SharedVmStateInner {
 pub(crate) struct SharedVmState {
     inner: Mutex<SharedVmStateInner>,
     cv: Condvar,
+
+    /// The server's history of its instance, in which external requests and
+    /// the state driver's actions are recorded.
+    history: Arc<EventHistory>,
 }
 
 /// A VM controller: a wrapper around a Propolis instance that supplies the
@@ -295,6 +586,15 @@ pub struct VmController {
         Option<JoinHandle<tokio::sync::watch::Sender<ApiMonitoredState>>>,
     >,
 
+    /// The limit on the share of time this instance's vCPUs spend running,
+    /// applied by outbound migrations to guests which dirty memory too
+    /// quickly.
+    vcpu_throttle: Arc<VcpuThrottle>,
+
+    /// The holds taken on individual devices, pausing them while the rest of
+    /// the instance runs.
+    device_holds: Arc<DeviceHolds>,
+
     /// This controller's logger.
     log: Logger,
 
@@ -302,19 +602,67 @@ pub struct VmController {
     /// (e.g. migration tasks).
     runtime_hdl: tokio::runtime::Handle,
 
+    /// The Oximeter producer registry and Nexus client given to the storage
+    /// backends created during initialization, retained for use by any
+    /// backends created to replace them.
+    producer_registry: Option<ProducerRegistry>,
+    nexus_client: Option<NexusClient>,
+
     /// Migration source state persisted across multiple migration attempts.
     migration_src_state: Mutex<migrate::source::PersistentState>,
 
+    /// The ID and RAM transfer progress of the most recently launched
+    /// migration task, in either direction.
+    migration_progress: Mutex<Option<(Uuid, Arc<MigrationProgress>)>>,
+
+    /// The ID of the most recently launched outbound migration and the handle
+    /// through which it can be cancelled.
+    migration_cancel: Mutex<Option<(Uuid, Arc<CancelHandle>)>>,
+
+    /// The limit on the rate at which outbound migrations send guest memory.
+    migration_bandwidth: Arc<BandwidthLimit>,
+
+    /// The counters describing this instance's migrations, reported to
+    /// Oximeter if there is a producer registry.
+    migration_stats: MigrationStats,
+
+    /// The algorithms outbound migrations offer for compressing guest memory,
+    /// in order of preference.
+    migration_page_compression: Vec<PageCompression>,
+
+    /// The number of connections, including the main migration connection,
+    /// over which migrations may transfer guest memory.
+    max_migration_ram_streams: u32,
+
+    /// The host socket of the virtio-console port on which the guest agent
+    /// asked to resynchronize the guest's clock after a migration into this
+    /// instance listens, if one is configured.
+    time_sync_socket: Option<PathBuf>,
+
+    /// The agent in the guest through which commands are run and files moved,
+    /// if one is configured.
+    guest_agent: Option<Arc<GuestAgent>>,
+
+    /// Where migrations write and read canned device payloads, if anywhere.
+    migration_payloads: MigrationPayloadDirs,
+
+    /// The ID of the most recently launched outbound migration and the channel
+    /// through which additional RAM streams opened by its destination are
+    /// passed to its task.
+    migration_ram_streams:
+        Mutex<Option<(Uuid, tokio::sync::mpsc::Sender<RamStream>)>>,
+
     /// A weak reference to this controller, suitable for upgrading and passing
     /// to tasks the controller spawns.
     this: Weak<Self>,
 }
 
 impl SharedVmState {
-    fn new(parent_log: &Logger) -> Self {
+    fn new(parent_log: &Logger, history: Arc<EventHistory>) -> Self {
         Self {
             inner: Mutex::new(SharedVmStateInner::new(parent_log)),
             cv: Condvar::new(),
+            history,
         }
     }
 
@@ -322,16 +670,22 @@ impl SharedVmState {
         &self,
         request: ExternalRequest,
     ) -> Result<(), RequestDeniedReason> {
+        let description = request.description();
         let mut inner = self.inner.lock().unwrap();
         let result = inner.external_request_queue.try_queue(request);
         if result.is_ok() {
             self.cv.notify_one();
         }
+        self.history.record(InstanceEventKind::Request {
+            request: description,
+            denied: result.as_ref().err().map(ToString::to_string),
+        });
         result
     }
 
     fn wait_for_next_event(&self) -> StateDriverEvent {
-        let guard = self.inner.lock().unwrap();
+        let mut guard = self.inner.lock().unwrap();
+        guard.handling_since = None;
         let mut guard = self
             .cv
             .wait_while(guard, |i| {
@@ -340,6 +694,7 @@ impl SharedVmState {
             })
             .unwrap();
 
+        guard.handling_since = Some(Instant::now());
         if let Some(guest_event) = guard.guest_event_queue.pop_front() {
             StateDriverEvent::Guest(guest_event)
         } else {
@@ -360,6 +715,21 @@ impl SharedVmState {
         }
     }
 
+    /// Queue a change in whether I/O to the block device `device` is stalled.
+    /// Only the most recent such change for each device is relevant, so any
+    /// for the same device which have not yet been processed are superseded
+    /// by it.
+    fn enqueue_block_io_event(&self, device: &str, event: GuestEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.guest_event_queue.retain(|ev| match ev {
+            GuestEvent::BlockIoStalled(dev)
+            | GuestEvent::BlockIoResumed(dev) => dev != device,
+            _ => true,
+        });
+        inner.guest_event_queue.push_back(event);
+        self.cv.notify_one();
+    }
+
     pub fn suspend_halt_event(&self, when: Duration) {
         self.enqueue_guest_event(GuestEvent::VcpuSuspendHalt(when));
     }
@@ -404,14 +774,39 @@ impl ChipsetEventHandler for SharedVmState {
     }
 }
 
+impl block::ErrorNotifier for SharedVmState {
+    fn io_stalled(&self, device: &str) {
+        self.enqueue_block_io_event(
+            device,
+            GuestEvent::BlockIoStalled(device.to_owned()),
+        );
+    }
+
+    fn io_resumed(&self, device: &str) {
+        self.enqueue_block_io_event(
+            device,
+            GuestEvent::BlockIoResumed(device.to_owned()),
+        );
+    }
+}
+
 impl VmController {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         instance_spec: VersionedInstanceSpec,
         properties: InstanceProperties,
-        &StaticConfig { vm: ref toml_config, use_reservoir, .. }: &StaticConfig,
+        &StaticConfig {
+            vm: ref toml_config,
+            use_reservoir,
+            ref migration_payloads,
+            ..
+        }: &StaticConfig,
         producer_registry: Option<ProducerRegistry>,
         nexus_client: Option<NexusClient>,
+        virtual_machine: VirtualMachine,
+        history: Arc<EventHistory>,
+        start_paused: bool,
+        boot_to_firmware_setup: bool,
         log: Logger,
         runtime_hdl: tokio::runtime::Handle,
         stop_ch: oneshot::Sender<()>,
@@ -423,6 +818,54 @@ impl VmController {
               "use_reservoir" => use_reservoir,
               "bootrom" => %bootrom.display());
 
+        let migration_config = toml_config.migration.as_ref();
+        let migration_page_compression = migration_config
+            .map(|m| m.page_compression.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<Vec<PageCompression>, _>>()
+            .map_err(|e| anyhow::anyhow!(e))?;
+
+        let time_sync_socket =
+            match migration_config.and_then(|m| m.time_sync_port.as_deref()) {
+                Some(name) => {
+                    let VersionedInstanceSpec::V0(v0_spec) = &instance_spec;
+                    let socket = v0_spec
+                        .devices
+                        .virtio_console
+                        .iter()
+                        .flat_map(|console| console.ports.iter())
+                        .find(|port| port.name == name)
+                        .map(|port| PathBuf::from(&port.socket_path));
+                    if socket.is_none() {
+                        warn!(log, "time sync port not found in virtio-console";
+                          "port" => name);
+                    }
+                    socket
+                }
+                None => None,
+            };
+
+        let guest_agent = match &toml_config.guest_agent {
+            Some(config) => {
+                let VersionedInstanceSpec::V0(v0_spec) = &instance_spec;
+                let socket = v0_spec
+                    .devices
+                    .virtio_console
+                    .iter()
+                    .flat_map(|console| console.ports.iter())
+                    .find(|port| port.name == config.port)
+                    .map(|port| PathBuf::from(&port.socket_path));
+                if socket.is_none() {
+                    warn!(log, "guest agent port not found in virtio-console";
+                          "port" => &config.port);
+                }
+                socket.map(|socket| Arc::new(GuestAgent::new(socket)))
+            }
+            None => None,
+        };
+
         let vmm_log = log.new(slog::o!("component" => "vmm"));
 
         // Set up the 'shell' instance into which the rest of this routine will
@@ -445,7 +888,7 @@ impl VmController {
                 migration: None,
             });
 
-        let worker_state = Arc::new(SharedVmState::new(&log));
+        let worker_state = Arc::new(SharedVmState::new(&log, history));
 
         // Create and initialize devices in the new instance.
         let mut init = MachineInitializer {
@@ -454,10 +897,14 @@ impl VmController {
             devices: DeviceMap::new(),
             block_backends: BlockBackendMap::new(),
             crucible_backends: CrucibleBackendMap::new(),
+            storage_devices: StorageDeviceMap::new(),
+            virtio_devices: VirtioDeviceMap::new(),
+            nic_rate_limiters: NicRateLimiterMap::new(),
+            nic_links: NicLinkMap::new(),
             spec: v0_spec,
             properties: &properties,
             toml_config,
-            producer_registry,
+            producer_registry: producer_registry.clone(),
             state: MachineInitializerState::default(),
         };
 
@@ -468,11 +915,18 @@ impl VmController {
         init.initialize_rtc(&chipset)?;
         init.initialize_hpet()?;
 
-        let com1 = Arc::new(init.initialize_uart(&chipset)?);
+        let serial_ports = init
+            .initialize_uart(&chipset)?
+            .into_iter()
+            .map(|(port, serial)| (port, Arc::new(serial)))
+            .collect();
         let ps2ctrl = init.initialize_ps2(&chipset)?;
         init.initialize_qemu_debug_port()?;
-        init.initialize_qemu_pvpanic((&properties).into())?;
-        init.initialize_network_devices(&chipset)?;
+        let pvpanic = init.initialize_qemu_pvpanic(virtual_machine.clone())?;
+        init.initialize_network_devices(&chipset, virtual_machine.clone())?;
+        init.initialize_virtio_socket(&chipset)?;
+        init.initialize_virtio_console(&chipset)?;
+        let tablet = init.initialize_virtio_tablet(&chipset)?;
 
         #[cfg(not(feature = "omicron-build"))]
         init.initialize_test_devices(&toml_config.devices)?;
@@ -486,19 +940,49 @@ impl VmController {
         init.initialize_softnpu_ports(&chipset)?;
         #[cfg(feature = "falcon")]
         init.initialize_9pfs(&chipset)?;
-        init.initialize_storage_devices(&chipset, nexus_client)?;
-        let ramfb = init.initialize_fwcfg(v0_spec.devices.board.cpus)?;
+        init.initialize_storage_devices(
+            &chipset,
+            nexus_client.clone(),
+            virtual_machine.clone(),
+            worker_state.clone() as Arc<dyn block::ErrorNotifier>,
+        )?;
+        init.initialize_virtio_stats(virtual_machine.clone())?;
+        let (fwcfg, ramfb) = init.initialize_fwcfg(
+            v0_spec.devices.board.cpus,
+            boot_to_firmware_setup,
+        )?;
         init.initialize_cpus()?;
+        let vcpu_throttle = Arc::new(VcpuThrottle::default());
         let vcpu_tasks = super::vcpu_tasks::VcpuTasks::new(
             &machine,
             worker_state.clone(),
+            vcpu_throttle.clone(),
             log.new(slog::o!("component" => "vcpu_tasks")),
         )?;
 
+        let migration_stats = MigrationStats::default();
+        if let Some(ref registry) = producer_registry {
+            let producer = crate::stats::MigrationProducer::new(
+                virtual_machine.clone(),
+                migration_stats.clone(),
+            );
+            registry.register_producer(producer).map_err(|e| {
+                anyhow::anyhow!(
+                    "failed to register migration Oximeter producer: {e}"
+                )
+            })?;
+        }
+
+        let hotplug_bridges = chipset.hotplug_bridges().clone();
+        let chipset_pm = chipset.pm().clone();
         let MachineInitializer {
             devices,
             block_backends,
             crucible_backends,
+            storage_devices,
+            virtio_devices,
+            nic_rate_limiters,
+            nic_links,
             ..
         } = init;
 
@@ -507,21 +991,51 @@ impl VmController {
         let controller = Arc::new_cyclic(|this| Self {
             vm_objects: VmObjects {
                 machine: Some(machine),
-                properties,
+                properties: Mutex::new(properties),
+                virtual_machine,
                 spec: tokio::sync::Mutex::new(instance_spec),
-                devices,
-                block_backends,
-                crucible_backends,
-                com1,
+                spec_generation: AtomicU64::new(0),
+                devices: Mutex::new(devices),
+                hotplug_bridges,
+                block_backends: Mutex::new(block_backends),
+                crucible_backends: Mutex::new(crucible_backends),
+                storage_devices: Mutex::new(storage_devices),
+                virtio_devices,
+                nic_rate_limiters,
+                nic_links: Mutex::new(nic_links),
+                serial_ports,
                 framebuffer: Some(ramfb),
+                fwcfg,
                 ps2ctrl,
+                chipset_pm,
+                tablet,
+                pvpanic,
                 monitor_rx,
             },
             worker_state,
             worker_thread: Mutex::new(None),
+            vcpu_throttle,
             migration_src_state: Default::default(),
+            migration_progress: Mutex::new(None),
+            migration_cancel: Mutex::new(None),
+            migration_bandwidth: Arc::new(BandwidthLimit::new(
+                migration_config.and_then(|m| m.max_bandwidth_mbps),
+            )),
+            migration_stats,
+            migration_page_compression,
+            max_migration_ram_streams: migration_config
+                .and_then(|m| m.ram_streams)
+                .unwrap_or(1)
+                .max(1),
+            time_sync_socket,
+            guest_agent,
+            migration_payloads: migration_payloads.clone(),
+            migration_ram_streams: Mutex::new(None),
+            device_holds: Default::default(),
             log: log.new(slog::o!("component" => "vm_controller")),
             runtime_hdl: runtime_hdl.clone(),
+            producer_registry,
+            nexus_client,
             this: this.clone(),
         });
 
@@ -539,6 +1053,7 @@ impl VmController {
                     ctrl_for_worker,
                     shared_state_for_worker,
                     vcpu_tasks,
+                    start_paused,
                     log_for_worker,
                     monitor_tx,
                 );
@@ -555,8 +1070,31 @@ impl VmController {
         Ok(controller)
     }
 
-    pub fn properties(&self) -> &InstanceProperties {
-        &self.vm_objects.properties
+    pub fn properties(&self) -> InstanceProperties {
+        self.vm_objects.properties.lock().unwrap().clone()
+    }
+
+    /// Renames the instance or changes its description or metadata, returning
+    /// its updated properties. New metadata is reported with the instance's
+    /// metrics from then on.
+    pub fn update_properties(
+        &self,
+        update: InstancePropertiesUpdateRequest,
+    ) -> InstanceProperties {
+        let mut properties = self.vm_objects.properties.lock().unwrap();
+        let InstancePropertiesUpdateRequest { name, description, metadata } =
+            update;
+        if let Some(name) = name {
+            properties.name = name;
+        }
+        if let Some(description) = description {
+            properties.description = description;
+        }
+        if let Some(metadata) = metadata {
+            self.vm_objects.virtual_machine.set_metadata(metadata.clone());
+            properties.metadata = metadata;
+        }
+        properties.clone()
     }
 
     pub fn machine(&self) -> &Machine {
@@ -568,6 +1106,10 @@ impl VmController {
             .expect("VM controller always has a valid machine")
     }
 
+    pub(crate) fn vcpu_throttle(&self) -> &VcpuThrottle {
+        &self.vcpu_throttle
+    }
+
     pub(crate) fn migration_src_state(
         &self,
     ) -> MutexGuard<'_, migrate::source::PersistentState> {
@@ -580,22 +1122,351 @@ impl VmController {
         self.vm_objects.spec.lock().await
     }
 
+    /// Returns the number of times the instance spec has changed since this
+    /// VM was created.  Read with the spec locked, this identifies the
+    /// version of the spec that was read.
+    pub fn spec_generation(&self) -> u64 {
+        self.vm_objects.spec_generation.load(Ordering::Acquire)
+    }
+
+    /// Records a change to the instance spec.  Callers must still hold the
+    /// spec's lock.
+    pub(crate) fn note_spec_change(&self) {
+        self.vm_objects.spec_generation.fetch_add(1, Ordering::AcqRel);
+    }
+
     pub fn com1(&self) -> &Arc<Serial<LpcUart>> {
-        &self.vm_objects.com1
+        &self.vm_objects.serial_ports[&SerialPortNumber::Com1]
+    }
+
+    pub fn serial_port(
+        &self,
+        port: SerialPortNumber,
+    ) -> Option<&Arc<Serial<LpcUart>>> {
+        self.vm_objects.serial_ports.get(&port)
+    }
+
+    pub fn serial_ports(
+        &self,
+    ) -> &BTreeMap<SerialPortNumber, Arc<Serial<LpcUart>>> {
+        &self.vm_objects.serial_ports
     }
 
     pub fn framebuffer(&self) -> Option<&Arc<RamFb>> {
         self.vm_objects.framebuffer.as_ref()
     }
 
+    /// Sets the resolution suggested to the guest for its framebuffer, and
+    /// republishes the framebuffer's EDID to advertise it.
+    ///
+    /// The guest adopts the new resolution when it next configures the
+    /// framebuffer.
+    pub fn set_display_resolution(
+        &self,
+        res: Resolution,
+    ) -> Result<(), VmControllerError> {
+        let ramfb =
+            self.framebuffer().ok_or(VmControllerError::NoFramebuffer)?;
+        if !res.is_valid() {
+            return Err(VmControllerError::InvalidDisplayResolution(format!(
+                "{}x{} is outside of {}x{} to {}x{}",
+                res.width,
+                res.height,
+                Resolution::MIN.width,
+                Resolution::MIN.height,
+                Resolution::MAX.width,
+                Resolution::MAX.height,
+            )));
+        }
+
+        ramfb.set_preferred_resolution(res);
+        let old = self.vm_objects.fwcfg.replace_named(
+            RamFb::FWCFG_EDID_NAME,
+            FwCfgEntry::Bytes(ramfb.edid()),
+        );
+        assert!(old.is_some(), "EDID is published at initialization");
+        info!(self.log, "Set preferred display resolution";
+              "width" => res.width,
+              "height" => res.height);
+        Ok(())
+    }
+
     pub fn ps2ctrl(&self) -> &Arc<PS2Ctrl> {
         &self.vm_objects.ps2ctrl
     }
 
-    pub fn crucible_backends(
+    pub fn tablet(&self) -> Option<&Arc<PciVirtioTablet>> {
+        self.vm_objects.tablet.as_ref()
+    }
+
+    pub fn pvpanic(&self) -> Option<&Arc<QemuPvpanic>> {
+        self.vm_objects.pvpanic.as_ref()
+    }
+
+    pub fn crucible_backend(
         &self,
-    ) -> &BTreeMap<Uuid, Arc<propolis::block::CrucibleBackend>> {
-        &self.vm_objects.crucible_backends
+        id: &Uuid,
+    ) -> Option<Arc<propolis::block::CrucibleBackend>> {
+        self.vm_objects.crucible_backends.lock().unwrap().get(id).cloned()
+    }
+
+    pub(crate) fn storage_device(&self, name: &str) -> Option<StorageDevice> {
+        self.vm_objects.storage_devices.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns all of the instance's storage devices, keyed by name.
+    pub(crate) fn storage_devices(&self) -> StorageDeviceMap {
+        self.vm_objects.storage_devices.lock().unwrap().clone()
+    }
+
+    /// Pauses the storage device named `name` while the rest of the instance
+    /// keeps running, returning the device once it has paused, along with a
+    /// hold which resumes it when dropped.
+    ///
+    /// The instance must be running.  The device stays paused (whether or not
+    /// the hold is dropped) while the state driver has the instance's devices
+    /// paused.
+    pub(crate) async fn hold_storage_device(
+        &self,
+        name: &str,
+    ) -> Result<(StorageDevice, DeviceHold), VmControllerError> {
+        let disk = self.storage_device(name).ok_or_else(|| {
+            VmControllerError::NoSuchStorageDevice(name.to_owned())
+        })?;
+        let state = self.external_instance_state();
+        let hold = (state == ApiInstanceState::Running)
+            .then(|| self.device_holds.hold(disk.device.clone()))
+            .flatten()
+            .ok_or(VmControllerError::InstanceNotRunning(state))?;
+        hold.paused().await;
+        Ok((disk, hold))
+    }
+
+    pub(crate) fn virtio_device(
+        &self,
+        name: &str,
+    ) -> Option<Arc<dyn propolis::hw::virtio::pci::PciVirtio>> {
+        self.vm_objects.virtio_devices.get(name).cloned()
+    }
+
+    /// Replaces the backend of the storage device named `device_name` with a
+    /// new backend, created from `backend_spec` and named `backend_name` in
+    /// the instance spec.
+    ///
+    /// The device is held (see [`Self::hold_storage_device`]) while the old
+    /// backend is stopped and detached and the new one attached in its place,
+    /// so the guest sees only a stall in I/O to the device, which otherwise
+    /// remains as it was.  The new backend must have the same block size as
+    /// the old one and, unless the device is a CD-ROM drive, the same
+    /// capacity.  If the new backend cannot be attached or started, the old one
+    /// is restored.  The instance must be running.
+    ///
+    /// This blocks while the device is paused and the backends are created and
+    /// started, so it must not be called from an async context.
+    pub(crate) fn replace_storage_backend(
+        &self,
+        device_name: &str,
+        backend_name: String,
+        backend_spec: StorageBackendV0,
+    ) -> Result<(), VmControllerError> {
+        let _rtguard = self.runtime_hdl.enter();
+        let invalid = VmControllerError::InvalidBackendReplacement;
+
+        // Hold the spec for the duration, so that the replacement cannot race
+        // with a migration (or another replacement) reading it.
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let old_backend_name = match v0_spec
+            .devices
+            .storage_devices
+            .get(device_name)
+            .ok_or_else(|| {
+                VmControllerError::NoSuchStorageDevice(device_name.to_owned())
+            })? {
+            StorageDeviceV0::VirtioDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::NvmeDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::SataDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::SataCdrom(cdrom) => cdrom.backend_name.clone(),
+            StorageDeviceV0::IdeDisk(disk) => disk.backend_name.clone(),
+        };
+        if v0_spec.backends.storage_backends.contains_key(&backend_name) {
+            return Err(invalid(format!(
+                "backend name {backend_name:?} is already in use"
+            )));
+        }
+        let old = self.storage_device(device_name).ok_or_else(|| {
+            VmControllerError::NoSuchStorageDevice(device_name.to_owned())
+        })?;
+
+        info!(self.log, "Replacing storage backend";
+              "device" => device_name,
+              "old_backend" => &old_backend_name,
+              "new_backend" => &backend_name);
+        let StorageBackendInstance { be: backend, crucible } =
+            create_storage_backend_from_spec(
+                &self.log,
+                self.producer_registry.as_ref(),
+                &backend_spec,
+                &backend_name,
+                &self.nexus_client,
+                None,
+            )
+            .map_err(|e| {
+                VmControllerError::BackendUnreachable(backend_name.clone(), e)
+            })?;
+
+        let (old_info, new_info) = (old.backend.info(), backend.info());
+        if old_info.block_size != new_info.block_size {
+            return Err(invalid(format!(
+                "block size of new backend ({}) differs from that of the \
+                existing one ({})",
+                new_info.block_size, old_info.block_size
+            )));
+        }
+        // The guest may be told of new media in a CD-ROM drive, but not of a
+        // disk changing size out from under it.
+        if old.cdrom.is_none() && old_info.total_size != new_info.total_size {
+            return Err(invalid(format!(
+                "capacity of new backend ({} blocks) differs from that of the \
+                existing one ({} blocks)",
+                new_info.total_size, old_info.total_size
+            )));
+        }
+        if let Some((id, _)) = &crucible {
+            if self
+                .vm_objects
+                .crucible_backends
+                .lock()
+                .unwrap()
+                .contains_key(id)
+            {
+                return Err(invalid(format!(
+                    "a disk with Crucible volume {id} already exists"
+                )));
+            }
+        }
+
+        let (_, hold) =
+            self.runtime_hdl.block_on(self.hold_storage_device(device_name))?;
+        if let Err(msg) =
+            swap_backend(&self.log, &old.block_dev, &old.backend, &backend)
+        {
+            error!(self.log, "Storage backend replacement failed, restored \
+                   old backend";
+                   "device" => device_name,
+                   "error" => &msg);
+            return Err(VmControllerError::BackendReplacementFailed(
+                std::io::Error::new(std::io::ErrorKind::Other, msg),
+            ));
+        }
+
+        let error_policy = match &backend_spec {
+            StorageBackendV0::Crucible(spec) => spec.error_policy,
+            StorageBackendV0::File(spec) => spec.error_policy,
+            StorageBackendV0::Blob(_) => None,
+        };
+        old.block_dev.attachment().set_error_policy(
+            block_error_policy(error_policy.unwrap_or_default()),
+            Some(self.worker_state.clone() as Arc<dyn block::ErrorNotifier>),
+        );
+        drop(hold);
+
+        // Record the new backend in place of the old one.
+        {
+            let mut block_backends =
+                self.vm_objects.block_backends.lock().unwrap();
+            let mut crucible_backends =
+                self.vm_objects.crucible_backends.lock().unwrap();
+            let mut storage_devices =
+                self.vm_objects.storage_devices.lock().unwrap();
+
+            block_backends.remove(&old_backend_name);
+            block_backends.insert(backend_name.clone(), backend.clone());
+            if let Some(old_crucible) = &old.crucible {
+                crucible_backends
+                    .retain(|_, be| !Arc::ptr_eq(be, old_crucible));
+            }
+            if let Some((id, be)) = &crucible {
+                crucible_backends.insert(*id, be.clone());
+            }
+            storage_devices.insert(
+                device_name.to_owned(),
+                StorageDevice {
+                    backend,
+                    crucible: crucible.map(|(_id, be)| be),
+                    ..old
+                },
+            );
+        }
+
+        v0_spec.backends.storage_backends.remove(&old_backend_name);
+        v0_spec
+            .backends
+            .storage_backends
+            .insert(backend_name.clone(), backend_spec);
+        match v0_spec.devices.storage_devices.get_mut(device_name) {
+            Some(StorageDeviceV0::VirtioDisk(disk)) => {
+                disk.backend_name = backend_name;
+            }
+            Some(StorageDeviceV0::NvmeDisk(disk)) => {
+                disk.backend_name = backend_name;
+            }
+            Some(StorageDeviceV0::SataDisk(disk)) => {
+                disk.backend_name = backend_name;
+            }
+            Some(StorageDeviceV0::SataCdrom(cdrom)) => {
+                cdrom.backend_name = backend_name;
+            }
+            Some(StorageDeviceV0::IdeDisk(disk)) => {
+                disk.backend_name = backend_name;
+            }
+            None => unreachable!("device was found in the spec above"),
+        }
+        self.note_spec_change();
+
+        Ok(())
+    }
+
+    /// Changes the media in the CD-ROM drive named `device_name`.  With no
+    /// `media`, the drive's existing media is ejected.  Otherwise, the drive's
+    /// backend is replaced (as by [`Self::replace_storage_backend`]) with one
+    /// providing the new media, which is then inserted.
+    ///
+    /// Like backend replacement, this must not be called from an async
+    /// context.
+    pub(crate) fn change_cdrom_media(
+        &self,
+        device_name: &str,
+        media: Option<CdromMedia>,
+    ) -> Result<(), VmControllerError> {
+        let cdrom = self
+            .storage_device(device_name)
+            .ok_or_else(|| {
+                VmControllerError::NoSuchStorageDevice(device_name.to_owned())
+            })?
+            .cdrom
+            .ok_or_else(|| {
+                VmControllerError::NotRemovableMedia(device_name.to_owned())
+            })?;
+
+        match media {
+            None => {
+                info!(self.log, "Ejecting CD-ROM media";
+                      "device" => device_name);
+                cdrom.eject_media();
+            }
+            Some(CdromMedia { backend_name, backend }) => {
+                self.replace_storage_backend(
+                    device_name,
+                    backend_name,
+                    backend,
+                )?;
+                info!(self.log, "Inserted CD-ROM media";
+                      "device" => device_name);
+                cdrom.insert_media();
+            }
+        }
+        Ok(())
     }
 
     pub fn log(&self) -> &Logger {
@@ -606,17 +1477,37 @@ impl VmController {
         self.vm_objects.monitor_rx.borrow().state
     }
 
-    pub fn inject_nmi(&self) {
-        if let Some(machine) = &self.vm_objects.machine {
-            match machine.inject_nmi() {
-                Ok(_) => {
-                    info!(self.log, "Sending NMI to instance");
-                }
-                Err(e) => {
-                    error!(self.log, "Could not send NMI to instance: {}", e);
-                }
-            };
+    /// Injects an NMI into the instance's boot processor.
+    pub fn inject_nmi(&self) -> Result<(), VmControllerError> {
+        if self.external_instance_state() != ApiInstanceState::Running {
+            return Err(VmControllerError::InstanceNotActive);
         }
+
+        info!(self.log, "Sending NMI to instance");
+        self.worker_state.history.record(InstanceEventKind::Request {
+            request: "inject NMI".to_string(),
+            denied: None,
+        });
+        self.machine().inject_nmi().map_err(|e| {
+            error!(self.log, "Could not send NMI to instance: {}", e);
+            VmControllerError::NmiInjectionFailed(e)
+        })
+    }
+
+    /// Returns whether the state driver is handling events promptly, i.e.
+    /// whether it has spent no longer than [`STATE_DRIVER_STALL_TIMEOUT`]
+    /// handling its current event.  Migrations are handled by the state driver
+    /// for as long as they last, so it's always considered responsive while
+    /// the instance migrates.
+    pub fn state_driver_responsive(&self) -> bool {
+        if self.external_instance_state() == ApiInstanceState::Migrating {
+            return true;
+        }
+        let inner = self.worker_state.inner.lock().unwrap();
+        !matches!(
+            inner.handling_since,
+            Some(since) if since.elapsed() > STATE_DRIVER_STALL_TIMEOUT
+        )
     }
 
     pub fn state_watcher(
@@ -679,6 +1570,16 @@ impl VmController {
         let (start_tx, start_rx) = tokio::sync::oneshot::channel();
         let (command_tx, command_rx) = tokio::sync::mpsc::channel(1);
         let (response_tx, response_rx) = tokio::sync::mpsc::channel(1);
+        let progress = self.track_migration_progress(migration_id);
+        let cancel = Arc::new(CancelHandle::default());
+        *self.migration_cancel.lock().unwrap() =
+            Some((migration_id, cancel.clone()));
+        let bandwidth = self.migration_bandwidth.clone();
+        let (ram_stream_tx, ram_stream_rx) =
+            tokio::sync::mpsc::channel(self.max_migration_ram_streams as usize);
+        *self.migration_ram_streams.lock().unwrap() =
+            Some((migration_id, ram_stream_tx));
+        let stats = self.migration_stats.clone();
 
         // The migration process uses async operations when communicating with
         // the migration target. Run that work on the async runtime.
@@ -688,15 +1589,25 @@ impl VmController {
             start_rx.await.unwrap();
 
             info!(log_for_task, "Starting migration procedure");
-            if let Err(e) = crate::migrate::source::migrate(
+            stats.source_started();
+            let res = crate::migrate::source::migrate(
                 ctrl_for_task,
                 command_tx,
                 response_rx,
                 conn,
                 protocol,
+                progress.clone(),
+                cancel,
+                bandwidth,
+                ram_stream_rx,
             )
-            .await
-            {
+            .await;
+            stats.finished(
+                MigrateRole::Source,
+                progress.snapshot().bytes_transferred,
+                res.as_ref().err().map(Into::into),
+            );
+            if let Err(e) = res {
                 error!(log_for_task, "Migration task failed: {}", e);
                 return Err(e);
             }
@@ -726,6 +1637,7 @@ impl VmController {
     ///
     /// On success, clients may query the instance's migration status to
     /// determine how the migration has progressed.
+    #[allow(clippy::too_many_arguments)]
     pub fn request_migration_into<
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     >(
@@ -734,6 +1646,12 @@ impl VmController {
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
         protocol: crate::migrate::protocol::Protocol,
+        ram_stream_url: String,
+        connector: SourceConnector,
+        auto_converge: Option<MigrationAutoConverge>,
+        max_downtime_ms: Option<u64>,
+        dirty_tracking: Option<MigrationDirtyTracking>,
+        retrier: Option<Retrier<T>>,
     ) -> Result<(), VmControllerError> {
         let mut inner = self.worker_state.inner.lock().unwrap();
         if !inner.external_request_queue.migrate_as_target_will_enqueue()? {
@@ -747,6 +1665,12 @@ impl VmController {
             conn,
             local_addr,
             protocol,
+            ram_stream_url,
+            connector,
+            auto_converge,
+            max_downtime_ms,
+            dirty_tracking,
+            retrier,
         );
 
         // Unwrap is safe because the queue state was checked under the lock.
@@ -758,6 +1682,7 @@ impl VmController {
     /// Launches a task that will execute a live migration into this VM.
     /// Returns a state change request message to queue to the state driver,
     /// which will coordinate with this task to run the migration.
+    #[allow(clippy::too_many_arguments)]
     fn launch_target_migration_task<
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     >(
@@ -766,12 +1691,20 @@ impl VmController {
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
         protocol: crate::migrate::protocol::Protocol,
+        ram_stream_url: String,
+        connector: SourceConnector,
+        auto_converge: Option<MigrationAutoConverge>,
+        max_downtime_ms: Option<u64>,
+        dirty_tracking: Option<MigrationDirtyTracking>,
+        retrier: Option<Retrier<T>>,
     ) -> ExternalRequest {
         let log_for_task =
             self.log.new(slog::o!("component" => "migrate_source_task"));
         let ctrl_for_task = self.this.upgrade().unwrap();
         let (start_tx, start_rx) = tokio::sync::oneshot::channel();
         let (command_tx, command_rx) = tokio::sync::mpsc::channel(1);
+        let progress = self.track_migration_progress(migration_id);
+        let stats = self.migration_stats.clone();
 
         // The migration process uses async operations when communicating with
         // the migration target. Run that work on the async runtime.
@@ -781,15 +1714,27 @@ impl VmController {
             start_rx.await.unwrap();
 
             info!(log_for_task, "Starting migration procedure");
-            if let Err(e) = crate::migrate::destination::migrate(
+            let res = crate::migrate::destination::migrate(
                 ctrl_for_task,
                 command_tx,
                 conn,
                 local_addr,
                 protocol,
+                progress.clone(),
+                ram_stream_url,
+                connector,
+                auto_converge,
+                max_downtime_ms,
+                dirty_tracking,
+                retrier,
             )
-            .await
-            {
+            .await;
+            stats.finished(
+                MigrateRole::Destination,
+                progress.snapshot().bytes_transferred,
+                res.as_ref().err().map(Into::into),
+            );
+            if let Err(e) = res {
                 error!(log_for_task, "Migration task failed: {}", e);
                 return Err(e);
             }
@@ -821,17 +1766,342 @@ impl VmController {
             .map_err(Into::into)
     }
 
+    /// Asks the state driver to press the guest's ACPI power button, and to
+    /// stop the instance if the guest hasn't powered off after `grace_period`.
+    pub fn request_shutdown(
+        &self,
+        grace_period: Duration,
+    ) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested shutdown via API";
+              "grace_period" => ?grace_period);
+        self.worker_state
+            .queue_external_request(ExternalRequest::Shutdown { grace_period })
+            .map_err(Into::into)
+    }
+
+    /// Asks the state driver to pause the instance's vCPUs and devices,
+    /// leaving them ready to be resumed.
+    pub fn request_pause(&self) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested pause via API");
+        self.worker_state
+            .queue_external_request(ExternalRequest::Pause)
+            .map_err(Into::into)
+    }
+
+    /// Asks the state driver to resume an instance paused by
+    /// [`VmController::request_pause`].
+    pub fn request_resume(&self) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested resume via API");
+        self.worker_state
+            .queue_external_request(ExternalRequest::Resume)
+            .map_err(Into::into)
+    }
+
+    /// Asks the state driver to attach a new NVMe disk, named `name` in the
+    /// instance spec, through a PCIe hotplug slot, and waits for it to do so.
+    pub async fn attach_disk(
+        &self,
+        name: String,
+        device: NvmeDisk,
+        backend: StorageBackendV0,
+    ) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested attachment of disk {} via API", name);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.worker_state.queue_external_request(
+            ExternalRequest::AttachDisk { name, device, backend, result_tx },
+        )?;
+
+        // The state driver drops queued requests if it exits first.
+        result_rx.await.unwrap_or(Err(VmControllerError::InstanceNotActive))
+    }
+
+    /// Asks the guest to release the hotplugged NVMe disk named `name`, then
+    /// asks the state driver to detach it, and waits for it to do so.
+    pub async fn detach_disk(
+        &self,
+        name: String,
+    ) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested detachment of disk {} via API", name);
+        let pci_path = {
+            let spec = self.vm_objects.spec.lock().await;
+            let VersionedInstanceSpec::V0(v0_spec) = &*spec;
+            match v0_spec.devices.storage_devices.get(&name) {
+                Some(StorageDeviceV0::NvmeDisk(disk)) => Some(disk.pci_path),
+                _ => None,
+            }
+        };
+        // The state driver explains why any other device can't be detached.
+        if let Some(pci_path) = pci_path {
+            self.await_hotplug_release(&name, pci_path).await?;
+        }
+
+        let (result_tx, result_rx) = oneshot::channel();
+        self.worker_state.queue_external_request(
+            ExternalRequest::DetachDisk { name, result_tx },
+        )?;
+        result_rx.await.unwrap_or(Err(VmControllerError::InstanceNotActive))
+    }
+
+    /// Asks the state driver to attach a new virtio NIC, named `name` in the
+    /// instance spec, through a PCIe hotplug slot, and waits for it to do so.
+    pub async fn attach_nic(
+        &self,
+        name: String,
+        device: VirtioNic,
+        backend: NetworkBackendV0,
+    ) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested attachment of NIC {} via API", name);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.worker_state.queue_external_request(
+            ExternalRequest::AttachNic { name, device, backend, result_tx },
+        )?;
+        result_rx.await.unwrap_or(Err(VmControllerError::InstanceNotActive))
+    }
+
+    /// Asks the guest to release the hotplugged NIC named `name`, then asks
+    /// the state driver to detach it, and waits for it to do so.
+    pub async fn detach_nic(
+        &self,
+        name: String,
+    ) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested detachment of NIC {} via API", name);
+        let pci_path = {
+            let spec = self.vm_objects.spec.lock().await;
+            let VersionedInstanceSpec::V0(v0_spec) = &*spec;
+            match v0_spec.devices.network_devices.get(&name) {
+                Some(NetworkDeviceV0::VirtioNic(nic)) => Some(nic.pci_path),
+                Some(NetworkDeviceV0::PassthroughNic(nic)) => {
+                    Some(nic.pci_path)
+                }
+                _ => None,
+            }
+        };
+        if let Some(pci_path) = pci_path {
+            self.await_hotplug_release(&name, pci_path).await?;
+        }
+
+        let (result_tx, result_rx) = oneshot::channel();
+        self.worker_state.queue_external_request(
+            ExternalRequest::DetachNic { name, result_tx },
+        )?;
+        result_rx.await.unwrap_or(Err(VmControllerError::InstanceNotActive))
+    }
+
+    /// Presses the attention button of the hotplug slot at `pci_path`, holding
+    /// the device named `name`, and waits for the guest to release the device
+    /// and power off the slot.
+    ///
+    /// This waits on the calling task, leaving the state driver free to handle
+    /// other requests in the meantime.  If the guest hasn't powered off the
+    /// slot within [`HOTPLUG_REMOVAL_TIMEOUT`], the press is withdrawn, unless
+    /// the guest has already acknowledged it.  A guest which releases the
+    /// device anyway leaves its slot powered off, and the device is then
+    /// removed by the next request to detach it, without waiting.
+    async fn await_hotplug_release(
+        &self,
+        name: &str,
+        pci_path: PciPath,
+    ) -> Result<(), VmControllerError> {
+        let failed = VmControllerError::HotplugFailed;
+        // The state driver explains why a device in no hotplug slot can't be
+        // detached.
+        let Ok(bdf) = pci::Bdf::try_from(pci_path) else {
+            return Ok(());
+        };
+        let Some(bridge) = self.vm_objects.hotplug_bridges.get(&bdf.bus.get())
+        else {
+            return Ok(());
+        };
+
+        // A paused guest can't release the device.
+        let state = self.external_instance_state();
+        if state != ApiInstanceState::Running {
+            return Err(VmControllerError::InstanceNotRunning(state));
+        }
+
+        // Pressing the button of a slot which is already powered off would ask
+        // the guest to power it back on.
+        if bridge
+            .hotplug_wait_power_off(Duration::ZERO)
+            .map_err(|e| failed(e.to_string()))?
+        {
+            return Ok(());
+        }
+
+        info!(self.log, "Requesting removal of hotplugged device";
+              "device" => name,
+              "bdf" => %bdf);
+        bridge.hotplug_request_removal().map_err(|e| failed(e.to_string()))?;
+        let waiter = bridge.clone();
+        let powered_off = tokio::task::spawn_blocking(move || {
+            waiter.hotplug_wait_power_off(HOTPLUG_REMOVAL_TIMEOUT)
+        })
+        .await
+        .expect("waiting for a hotplug slot should not panic")
+        .map_err(|e| failed(e.to_string()))?;
+        if !powered_off {
+            let withdrawn = bridge
+                .hotplug_cancel_removal()
+                .map_err(|e| failed(e.to_string()))?;
+            warn!(self.log, "Guest did not release hotplugged device";
+                  "device" => name,
+                  "request_withdrawn" => withdrawn);
+            return Err(failed(format!(
+                "guest did not release device {name:?} within {} seconds",
+                HOTPLUG_REMOVAL_TIMEOUT.as_secs()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Attaches the device described by `fragment`, named `name` in the
+    /// instance spec, if devices of its kind can be hotplugged.
+    pub async fn attach_device(
+        &self,
+        name: String,
+        fragment: DeviceSpecFragment,
+    ) -> Result<(), VmControllerError> {
+        let kind = hotplug::fragment_kind(&fragment);
+        hotplug::check(kind, HotplugOp::Attach)?;
+        match fragment {
+            DeviceSpecFragment::Storage {
+                device: StorageDeviceV0::NvmeDisk(device),
+                backend,
+            } => self.attach_disk(name, device, backend).await,
+            DeviceSpecFragment::Network {
+                device: NetworkDeviceV0::VirtioNic(device),
+                backend,
+            } => self.attach_nic(name, device, backend).await,
+            _ => Err(VmControllerError::InvalidHotplugRequest(format!(
+                "{kind} devices cannot be attached through this API"
+            ))),
+        }
+    }
+
+    /// Detaches the device named `name` in the instance spec, if devices of
+    /// its kind can be hotplugged.
+    pub async fn detach_device(
+        &self,
+        name: String,
+    ) -> Result<(), VmControllerError> {
+        let (kind, storage) = {
+            let spec = self.vm_objects.spec.lock().await;
+            let VersionedInstanceSpec::V0(v0_spec) = &*spec;
+            if let Some(dev) = v0_spec.devices.storage_devices.get(&name) {
+                (hotplug::storage_kind(dev), true)
+            } else if let Some(dev) = v0_spec.devices.network_devices.get(&name)
+            {
+                (hotplug::network_kind(dev), false)
+            } else {
+                return Err(VmControllerError::NoSuchDevice(name));
+            }
+        };
+
+        hotplug::check(kind, HotplugOp::Detach)?;
+        if storage {
+            self.detach_disk(name).await
+        } else {
+            self.detach_nic(name).await
+        }
+    }
+
+    /// Replaces the rate limits of the userspace NIC named `name`, and records
+    /// the new limits in the instance spec.
+    pub async fn set_nic_rate_limit(
+        &self,
+        name: &str,
+        limit: NicRateLimit,
+    ) -> Result<(), VmControllerError> {
+        let invalid = VmControllerError::InvalidRateLimit;
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let spec_limit = match v0_spec.devices.network_devices.get_mut(name) {
+            Some(NetworkDeviceV0::VirtioNic(nic)) => &mut nic.rate_limit,
+            Some(NetworkDeviceV0::E1000Nic(nic)) => &mut nic.rate_limit,
+            Some(NetworkDeviceV0::PassthroughNic(_)) => {
+                return Err(invalid(format!(
+                    "NIC {name:?} is a passthrough device, so cannot be rate \
+                    limited"
+                )))
+            }
+            None => {
+                return Err(VmControllerError::NoSuchNetworkDevice(
+                    name.to_owned(),
+                ))
+            }
+        };
+        let limiter =
+            self.vm_objects.nic_rate_limiters.get(name).ok_or_else(|| {
+                invalid(format!(
+                    "NIC {name:?} has its datapath in the kernel, so cannot be \
+                    rate limited"
+                ))
+            })?;
+        let limits = nic_rate_limits(name, Some(&limit))
+            .map_err(|e| invalid(e.to_string()))?;
+
+        info!(self.log(), "Setting NIC rate limits";
+              "nic" => name,
+              "limits" => ?limits);
+        limiter.set_limits(limits);
+        *spec_limit = (limit != NicRateLimit::default()).then_some(limit);
+        self.note_spec_change();
+        Ok(())
+    }
+
+    /// Sets the link state the virtio NIC named `name` reports to the guest,
+    /// and records the new state in the instance spec.
+    pub async fn set_nic_link_state(
+        &self,
+        name: &str,
+        state: NicLinkState,
+    ) -> Result<(), VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let nic = match v0_spec.devices.network_devices.get_mut(name) {
+            Some(NetworkDeviceV0::VirtioNic(nic)) => nic,
+            Some(_) => {
+                return Err(VmControllerError::NoLinkStateControl(
+                    name.to_owned(),
+                ))
+            }
+            None => {
+                return Err(VmControllerError::NoSuchNetworkDevice(
+                    name.to_owned(),
+                ))
+            }
+        };
+        let link = self
+            .vm_objects
+            .nic_links
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| {
+                VmControllerError::NoLinkStateControl(name.to_owned())
+            })?;
+
+        info!(self.log(), "Setting NIC link state";
+              "nic" => name,
+              "state" => ?state);
+        link.set_link_up(state == NicLinkState::Up);
+        nic.link_state = (state != NicLinkState::Up).then_some(state);
+        self.note_spec_change();
+        Ok(())
+    }
+
     pub fn migrate_status(
         &self,
         migration_id: Uuid,
-    ) -> Result<ApiMigrationState, MigrateError> {
+    ) -> Result<ApiMigrationStatus, MigrateError> {
         // If the state worker has published migration state with a matching ID,
         // report the status from the worker. Note that this call to `borrow`
         // takes a lock on the channel.
         let published = self.vm_objects.monitor_rx.borrow();
         if let Some(status) = &published.migration {
             if status.migration_id == migration_id {
-                return Ok(status.state);
+                return Ok(status.clone());
             }
         }
         drop(published);
@@ -844,18 +2114,143 @@ impl VmController {
             if migration_id != id {
                 Err(MigrateError::UuidMismatch)
             } else {
-                Ok(ApiMigrationState::Sync)
+                Ok(ApiMigrationStatus {
+                    migration_id,
+                    state: ApiMigrationState::Sync,
+                    failure: None,
+                })
             }
         } else {
             Err(MigrateError::NoMigrationInProgress)
         }
     }
 
+    /// Reports the phase of the migration with ID `migration_id` and how far
+    /// its RAM transfer has progressed.
+    pub fn migrate_progress(
+        &self,
+        migration_id: Uuid,
+    ) -> Result<(ApiMigrationState, ProgressSnapshot), MigrateError> {
+        let state = self.migrate_status(migration_id)?.state;
+        let progress = match &*self.migration_progress.lock().unwrap() {
+            Some((id, progress)) if *id == migration_id => progress.snapshot(),
+            _ => MigrationProgress::default().snapshot(),
+        };
+        Ok((state, progress))
+    }
+
+    /// Cancels the outbound migration with ID `migration_id`. The migration
+    /// task unwinds as it does on any other failure: the destination is told
+    /// that the migration failed, and this VM resumes running.
+    pub fn cancel_migration(
+        &self,
+        migration_id: Uuid,
+    ) -> Result<(), MigrateError> {
+        match self.migrate_status(migration_id)?.state {
+            ApiMigrationState::Finish => return Err(MigrateError::PastCutover),
+            ApiMigrationState::Error => {
+                return Err(MigrateError::NoMigrationInProgress)
+            }
+            _ => {}
+        }
+
+        let cancel = match &*self.migration_cancel.lock().unwrap() {
+            Some((id, cancel)) if *id == migration_id => cancel.clone(),
+            _ => return Err(MigrateError::NotMigrationSource),
+        };
+        if !cancel.cancel() {
+            return Err(MigrateError::PastCutover);
+        }
+
+        info!(self.log, "Cancelling migration";
+              "migration_id" => %migration_id);
+        Ok(())
+    }
+
+    /// Yields the page compression algorithms outbound migrations offer, in
+    /// order of preference.
+    pub(crate) fn migration_page_compression(&self) -> &[PageCompression] {
+        &self.migration_page_compression
+    }
+
+    /// Yields the number of connections, including the main migration
+    /// connection, over which migrations may transfer guest memory.
+    pub(crate) fn max_migration_ram_streams(&self) -> u32 {
+        self.max_migration_ram_streams
+    }
+
+    /// Yields the host socket of the virtio-console port on which the guest
+    /// agent asked to resynchronize the guest's clock after a migration into
+    /// this instance listens, if one is configured.
+    pub(crate) fn time_sync_socket(&self) -> Option<&Path> {
+        self.time_sync_socket.as_deref()
+    }
+
+    /// Yields the agent in the guest, if one is configured.
+    pub(crate) fn guest_agent(&self) -> Option<Arc<GuestAgent>> {
+        self.guest_agent.clone()
+    }
+
+    /// Yields the directories to which migrations write, and from which they
+    /// read, canned device payloads.
+    pub(crate) fn migration_payloads(&self) -> &MigrationPayloadDirs {
+        &self.migration_payloads
+    }
+
+    /// Passes an additional RAM stream opened by the destination of the
+    /// outbound migration with ID `migration_id` to the migration's task.
+    pub(crate) fn add_migration_ram_stream(
+        &self,
+        migration_id: Uuid,
+        conn: RamStream,
+    ) -> Result<(), MigrateError> {
+        let guard = self.migration_ram_streams.lock().unwrap();
+        let Some((id, tx)) = &*guard else {
+            return Err(MigrateError::NoMigrationInProgress);
+        };
+        if *id != migration_id {
+            return Err(MigrateError::UuidMismatch);
+        }
+        tx.try_send(conn).map_err(|e| match e {
+            TrySendError::Full(_) => MigrateError::TooManyRamStreams,
+            TrySendError::Closed(_) => MigrateError::NoMigrationInProgress,
+        })
+    }
+
+    /// Limits outbound migrations, including any now underway, to sending
+    /// guest memory at `max_mbps` megabits per second, or removes the limit
+    /// if `max_mbps` is `None`.
+    pub fn set_migration_bandwidth(&self, max_mbps: Option<u64>) {
+        info!(self.log, "Setting migration bandwidth limit";
+              "max_bandwidth_mbps" => ?max_mbps);
+        self.migration_bandwidth.set(max_mbps);
+    }
+
+    /// The counters describing this instance's migrations.
+    pub(crate) fn migration_stats(&self) -> &MigrationStats {
+        &self.migration_stats
+    }
+
+    /// Starts tracking the progress of a new migration with ID `migration_id`,
+    /// returning the tracker to hand to its task.
+    fn track_migration_progress(
+        &self,
+        migration_id: Uuid,
+    ) -> Arc<MigrationProgress> {
+        let progress = Arc::new(MigrationProgress::default());
+        *self.migration_progress.lock().unwrap() =
+            Some((migration_id, progress.clone()));
+        progress
+    }
+
     pub(crate) fn for_each_device(
         &self,
         mut func: impl FnMut(&str, &Arc<dyn propolis::common::Lifecycle>),
     ) {
-        for (name, dev) in self.vm_objects.devices.iter() {
+        // Iterate over a snapshot of the map, which can change while disks
+        // are hotplugged.
+        let devices = self.vm_objects.devices.lock().unwrap().clone();
+        for (name, dev) in devices.iter() {
             func(name, dev);
         }
     }
@@ -870,7 +2265,8 @@ impl VmController {
             &Arc<dyn propolis::common::Lifecycle>,
         ) -> std::result::Result<(), E>,
     {
-        for (name, dev) in self.vm_objects.devices.iter() {
+        let devices = self.vm_objects.devices.lock().unwrap().clone();
+        for (name, dev) in devices.iter() {
             func(name, dev)?;
         }
         Ok(())
@@ -880,7 +2276,7 @@ impl VmController {
         &self,
         name: &String,
     ) -> Option<Arc<dyn propolis::common::Lifecycle>> {
-        self.vm_objects.devices.get(name).cloned()
+        self.vm_objects.devices.lock().unwrap().get(name).cloned()
     }
 }
 
@@ -899,7 +2295,7 @@ impl Drop for VmController {
 
         // Detach block backends so they can do any final clean-up
         debug!(self.log, "Detaching block backends");
-        for backend in self.vm_objects.block_backends.values() {
+        for backend in self.vm_objects.block_backends.lock().unwrap().values() {
             let _ = backend.attachment().detach();
         }
 
@@ -918,6 +2314,9 @@ impl Drop for VmController {
                 return;
             }
 
+            self.worker_state.history.record(InstanceEventKind::StateChanged {
+                state: ApiInstanceState::Destroyed,
+            });
             let gen = old_state.gen + 1;
             let _ = api_state.send(ApiMonitoredState {
                 gen,
@@ -977,8 +2376,46 @@ trait StateDriverVmController {
 
     /// Resets the state of each vCPU in the instance to its on-reboot state.
     fn reset_vcpu_state(&self);
+
+    /// Presses the guest's ACPI power button.
+    fn press_power_button(&self);
+
+    /// Creates a new NVMe disk and its backend, and inserts the disk into the
+    /// empty PCIe hotplug slot above the disk's PCI path.
+    fn hotplug_attach_disk(
+        &self,
+        name: &str,
+        device: NvmeDisk,
+        backend: StorageBackendV0,
+    ) -> Result<(), VmControllerError>;
+
+    /// Removes the NVMe disk named `name` from its hotplug slot, which the
+    /// guest must already have powered off (see [`VmController::detach_disk`]).
+    fn hotplug_detach_disk(&self, name: &str) -> Result<(), VmControllerError>;
+
+    /// Creates a new virtio NIC over a viona backend, and inserts the NIC into
+    /// the empty PCIe hotplug slot above its PCI path.
+    fn hotplug_attach_nic(
+        &self,
+        name: &str,
+        device: VirtioNic,
+        backend: NetworkBackendV0,
+    ) -> Result<(), VmControllerError>;
+
+    /// Removes the NIC named `name` from its hotplug slot, which the guest must
+    /// already have powered off (see [`VmController::detach_nic`]).
+    fn hotplug_detach_nic(&self, name: &str) -> Result<(), VmControllerError>;
 }
 
+/// How long to wait for the guest to power off a hotplug slot after its
+/// attention button is pressed, before withdrawing the request to remove its
+/// device.
+const HOTPLUG_REMOVAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the state driver may spend handling one event, other than a
+/// migration, before it's considered unresponsive.
+const STATE_DRIVER_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
 impl StateDriverVmController for VmController {
     fn pause_vm(&self) {
         info!(self.log, "Pausing kernel VMM resources");
@@ -1010,7 +2447,9 @@ impl StateDriverVmController for VmController {
             }
             res
         })?;
-        for (name, backend) in self.vm_objects.block_backends.iter() {
+        for (name, backend) in
+            self.vm_objects.block_backends.lock().unwrap().iter()
+        {
             debug!(self.log, "Starting block backend {}", name);
             let res = backend.start();
             if let Err(e) = &res {
@@ -1023,7 +2462,8 @@ impl StateDriverVmController for VmController {
 
     fn pause_devices(&self) {
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_device(|name, dev| {
+        let devices = self.vm_objects.devices.lock().unwrap().clone();
+        self.device_holds.pause_all(&devices, |name, dev| {
             info!(self.log, "Sending pause request to {}", name);
             dev.pause();
         });
@@ -1053,9 +2493,7 @@ impl StateDriverVmController for VmController {
 
         info!(self.log, "Waiting for devices to pause");
         self.runtime_hdl.block_on(async {
-            let mut stream: FuturesUnordered<_> = self
-                .vm_objects
-                .devices
+            let mut stream: FuturesUnordered<_> = devices
                 .iter()
                 .map(|(name, dev)| {
                     info!(self.log, "Got paused future from dev {}", name);
@@ -1081,7 +2519,8 @@ impl StateDriverVmController for VmController {
 
     fn resume_devices(&self) {
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_device(|name, dev| {
+        let devices = self.vm_objects.devices.lock().unwrap().clone();
+        self.device_holds.resume_all(&devices, |name, dev| {
             info!(self.log, "Sending resume request to {}", name);
             dev.resume();
         });
@@ -1093,7 +2532,9 @@ impl StateDriverVmController for VmController {
             info!(self.log, "Sending halt request to {}", name);
             dev.halt();
         });
-        for (name, backend) in self.vm_objects.block_backends.iter() {
+        for (name, backend) in
+            self.vm_objects.block_backends.lock().unwrap().iter()
+        {
             debug!(self.log, "Stopping and detaching block backend {}", name);
             backend.stop();
             if let Err(err) = backend.detach() {
@@ -1121,4 +2562,708 @@ impl StateDriverVmController for VmController {
             }
         }
     }
+
+    fn press_power_button(&self) {
+        info!(self.log, "Pressing ACPI power button");
+        self.vm_objects.chipset_pm.press_power_button();
+    }
+
+    fn hotplug_attach_disk(
+        &self,
+        name: &str,
+        device: NvmeDisk,
+        backend_spec: StorageBackendV0,
+    ) -> Result<(), VmControllerError> {
+        let _rtguard = self.runtime_hdl.enter();
+        let invalid = VmControllerError::InvalidHotplugRequest;
+        let failed = VmControllerError::HotplugFailed;
+
+        // As with backend replacement, hold the spec for the duration so that
+        // it can't be read while the disk is half-attached.
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let backend_name = device.backend_name.clone();
+        if v0_spec.devices.storage_devices.contains_key(name) {
+            return Err(invalid(format!(
+                "a storage device named {name:?} already exists"
+            )));
+        }
+        if v0_spec.backends.storage_backends.contains_key(&backend_name) {
+            return Err(invalid(format!(
+                "backend name {backend_name:?} is already in use"
+            )));
+        }
+
+        check_pci_path_free(&v0_spec.devices, device.pci_path)?;
+
+        let bdf: pci::Bdf = device
+            .pci_path
+            .try_into()
+            .map_err(|e| invalid(format!("invalid PCI path: {e}")))?;
+        let bridge =
+            self.vm_objects.hotplug_bridges.get(&bdf.bus.get()).ok_or_else(
+                || invalid(format!("{bdf} is not beneath a hotplug bridge")),
+            )?;
+        let device_spec = StorageDeviceV0::NvmeDisk(device.clone());
+        check_hotplug_disk(name, &device_spec, bdf)
+            .map_err(|e| invalid(e.to_string()))?;
+        let nvme = create_nvme_disk(&self.log, name, &device)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        info!(self.log, "Attaching hotplugged disk";
+              "disk" => name,
+              "backend" => &backend_name,
+              "bdf" => %bdf);
+        let StorageBackendInstance { be: backend, crucible } =
+            create_storage_backend_from_spec(
+                &self.log,
+                self.producer_registry.as_ref(),
+                &backend_spec,
+                &backend_name,
+                &self.nexus_client,
+                None,
+            )
+            .map_err(|e| {
+                VmControllerError::BackendUnreachable(backend_name.clone(), e)
+            })?;
+        if let Some((id, _)) = &crucible {
+            if self
+                .vm_objects
+                .crucible_backends
+                .lock()
+                .unwrap()
+                .contains_key(id)
+            {
+                return Err(invalid(format!(
+                    "a disk with Crucible volume {id} already exists"
+                )));
+            }
+        }
+
+        let device: Arc<dyn propolis::common::Lifecycle> = nvme.clone();
+        let block_dev: Arc<dyn block::Device> = nvme.clone();
+        block::attach(block_dev.clone(), backend.clone())
+            .map_err(|e| failed(format!("failed to attach backend: {e}")))?;
+        let error_policy = match &backend_spec {
+            StorageBackendV0::Crucible(spec) => spec.error_policy,
+            StorageBackendV0::File(spec) => spec.error_policy,
+            StorageBackendV0::Blob(_) => None,
+        };
+        block_dev.attachment().set_name(name.to_owned());
+        block_dev.attachment().set_error_policy(
+            block_error_policy(error_policy.unwrap_or_default()),
+            Some(self.worker_state.clone() as Arc<dyn block::ErrorNotifier>),
+        );
+
+        // Start the disk before inserting it, so that it's ready for use as
+        // soon as the guest learns of it.
+        let started = device
+            .start()
+            .map_err(|e| format!("failed to start device: {e}"))
+            .and_then(|_| {
+                backend
+                    .start()
+                    .map_err(|e| format!("failed to start backend: {e}"))
+            })
+            .and_then(|_| {
+                bridge
+                    .hotplug_insert(nvme)
+                    .map_err(|e| format!("failed to insert device: {e}"))
+            });
+        if let Err(msg) = started {
+            error!(self.log, "Failed to attach hotplugged disk";
+                   "disk" => name,
+                   "error" => &msg);
+            device.halt();
+            backend.stop();
+            let _ = backend.detach();
+            return Err(failed(msg));
+        }
+
+        // Block metrics aren't registered for hotplugged disks, as there is no
+        // way to unregister them if the disk is later detached.
+        {
+            let mut block_backends =
+                self.vm_objects.block_backends.lock().unwrap();
+            let mut crucible_backends =
+                self.vm_objects.crucible_backends.lock().unwrap();
+            let mut storage_devices =
+                self.vm_objects.storage_devices.lock().unwrap();
+
+            block_backends.insert(backend_name.clone(), backend.clone());
+            if let Some((id, be)) = &crucible {
+                crucible_backends.insert(*id, be.clone());
+            }
+            storage_devices.insert(
+                name.to_owned(),
+                StorageDevice {
+                    device: device.clone(),
+                    block_dev,
+                    backend,
+                    crucible: crucible.map(|(_id, be)| be),
+                    cdrom: None,
+                },
+            );
+        }
+        self.vm_objects
+            .devices
+            .lock()
+            .unwrap()
+            .insert(format!("pci-nvme-{bdf}"), device);
+
+        v0_spec.devices.storage_devices.insert(name.to_owned(), device_spec);
+        v0_spec.backends.storage_backends.insert(backend_name, backend_spec);
+        self.note_spec_change();
+        Ok(())
+    }
+
+    fn hotplug_detach_disk(&self, name: &str) -> Result<(), VmControllerError> {
+        let _rtguard = self.runtime_hdl.enter();
+        let invalid = VmControllerError::InvalidHotplugRequest;
+        let failed = VmControllerError::HotplugFailed;
+
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let (pci_path, backend_name) =
+            match v0_spec.devices.storage_devices.get(name) {
+                Some(StorageDeviceV0::NvmeDisk(disk)) => {
+                    (disk.pci_path, disk.backend_name.clone())
+                }
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "storage device {name:?} is not an NVMe disk"
+                    )))
+                }
+                None => {
+                    return Err(VmControllerError::NoSuchStorageDevice(
+                        name.to_owned(),
+                    ))
+                }
+            };
+        let bdf: pci::Bdf = pci_path
+            .try_into()
+            .map_err(|e| failed(format!("invalid PCI path: {e}")))?;
+        let bridge =
+            self.vm_objects.hotplug_bridges.get(&bdf.bus.get()).ok_or_else(
+                || invalid(format!("disk {name:?} is not in a hotplug slot")),
+            )?;
+        let disk = self.storage_device(name).ok_or_else(|| {
+            VmControllerError::NoSuchStorageDevice(name.to_owned())
+        })?;
+
+        if !bridge
+            .hotplug_wait_power_off(Duration::ZERO)
+            .map_err(|e| failed(e.to_string()))?
+        {
+            return Err(failed(format!(
+                "guest has not released disk {name:?}"
+            )));
+        }
+
+        // The guest has quiesced the disk, so any requests still in flight
+        // can be drained before it is torn down.
+        disk.device.pause();
+        self.runtime_hdl.block_on(disk.device.paused());
+        disk.backend.stop();
+        if let Err(e) = disk.backend.detach() {
+            error!(self.log, "Error while detaching hotplugged disk backend";
+                   "disk" => name,
+                   "error" => ?e);
+        }
+        disk.device.halt();
+        bridge.hotplug_remove().map_err(|e| failed(e.to_string()))?;
+        info!(self.log, "Removed hotplugged disk"; "disk" => name);
+
+        {
+            let mut block_backends =
+                self.vm_objects.block_backends.lock().unwrap();
+            let mut crucible_backends =
+                self.vm_objects.crucible_backends.lock().unwrap();
+            let mut storage_devices =
+                self.vm_objects.storage_devices.lock().unwrap();
+
+            block_backends.remove(&backend_name);
+            if let Some(crucible) = &disk.crucible {
+                crucible_backends.retain(|_, be| !Arc::ptr_eq(be, crucible));
+            }
+            storage_devices.remove(name);
+        }
+        self.vm_objects
+            .devices
+            .lock()
+            .unwrap()
+            .remove(&format!("pci-nvme-{bdf}"));
+
+        v0_spec.devices.storage_devices.remove(name);
+        v0_spec.backends.storage_backends.remove(&backend_name);
+        self.note_spec_change();
+        Ok(())
+    }
+
+    fn hotplug_attach_nic(
+        &self,
+        name: &str,
+        device: VirtioNic,
+        backend_spec: NetworkBackendV0,
+    ) -> Result<(), VmControllerError> {
+        let _rtguard = self.runtime_hdl.enter();
+        let invalid = VmControllerError::InvalidHotplugRequest;
+        let failed = VmControllerError::HotplugFailed;
+
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let backend_name = device.backend_name.clone();
+        if v0_spec.devices.network_devices.contains_key(name) {
+            return Err(invalid(format!(
+                "a network device named {name:?} already exists"
+            )));
+        }
+        if v0_spec.backends.network_backends.contains_key(&backend_name) {
+            return Err(invalid(format!(
+                "backend name {backend_name:?} is already in use"
+            )));
+        }
+        let NetworkBackendV0::Virtio(viona_spec) = &backend_spec else {
+            return Err(invalid(format!(
+                "NIC {name:?} must have a virtio backend to be hotplugged"
+            )));
+        };
+        check_pci_path_free(&v0_spec.devices, device.pci_path)?;
+
+        let bdf: pci::Bdf = device
+            .pci_path
+            .try_into()
+            .map_err(|e| invalid(format!("invalid PCI path: {e}")))?;
+        let bridge =
+            self.vm_objects.hotplug_bridges.get(&bdf.bus.get()).ok_or_else(
+                || invalid(format!("{bdf} is not beneath a hotplug bridge")),
+            )?;
+        check_hotplug_nic(name, bdf).map_err(|e| invalid(e.to_string()))?;
+        check_viona_nic(name, &device).map_err(|e| invalid(e.to_string()))?;
+
+        info!(self.log, "Attaching hotplugged NIC";
+              "nic" => name,
+              "vnic" => &viona_spec.vnic_name,
+              "bdf" => %bdf);
+        let viona = create_viona_nic(&device, viona_spec, &self.machine().hdl)
+            .map_err(|e| failed(format!("failed to create device: {e}")))?;
+        let link: Arc<dyn propolis::hw::virtio::NetLink> = viona.clone();
+        link.set_link_up(nic_link_up(&device));
+        let nic: Arc<dyn propolis::common::Lifecycle> = viona.clone();
+        let started = nic
+            .start()
+            .map_err(|e| format!("failed to start device: {e}"))
+            .and_then(|_| {
+                bridge
+                    .hotplug_insert(viona)
+                    .map_err(|e| format!("failed to insert device: {e}"))
+            });
+        if let Err(msg) = started {
+            error!(self.log, "Failed to attach hotplugged NIC";
+                   "nic" => name,
+                   "error" => &msg);
+            nic.halt();
+            return Err(failed(msg));
+        }
+
+        // As with hotplugged disks, no virtio queue statistics are produced
+        // for the NIC, since they could not be withdrawn when it's detached.
+        self.vm_objects
+            .devices
+            .lock()
+            .unwrap()
+            .insert(format!("pci-virtio-viona-{bdf}"), nic);
+        self.vm_objects.nic_links.lock().unwrap().insert(name.to_owned(), link);
+
+        v0_spec
+            .devices
+            .network_devices
+            .insert(name.to_owned(), NetworkDeviceV0::VirtioNic(device));
+        v0_spec.backends.network_backends.insert(backend_name, backend_spec);
+        self.note_spec_change();
+        Ok(())
+    }
+
+    fn hotplug_detach_nic(&self, name: &str) -> Result<(), VmControllerError> {
+        let _rtguard = self.runtime_hdl.enter();
+        let invalid = VmControllerError::InvalidHotplugRequest;
+        let failed = VmControllerError::HotplugFailed;
+
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        // Passthrough NICs may be detached too, which is how an instance with
+        // one is made ready to migrate.
+        let (pci_path, backend_name, passthrough) =
+            match v0_spec.devices.network_devices.get(name) {
+                Some(NetworkDeviceV0::VirtioNic(nic)) => {
+                    (nic.pci_path, nic.backend_name.clone(), false)
+                }
+                Some(NetworkDeviceV0::PassthroughNic(nic)) => {
+                    (nic.pci_path, nic.backend_name.clone(), true)
+                }
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "network device {name:?} is not a virtio or \
+                        passthrough NIC"
+                    )))
+                }
+                None => {
+                    return Err(VmControllerError::NoSuchNetworkDevice(
+                        name.to_owned(),
+                    ))
+                }
+            };
+        match (
+            v0_spec.backends.network_backends.get(&backend_name),
+            passthrough,
+        ) {
+            (Some(NetworkBackendV0::Virtio(_)), false)
+            | (Some(NetworkBackendV0::Ppt(_)), true) => {}
+            (_, false) => {
+                return Err(invalid(format!(
+                    "NIC {name:?} does not have a virtio backend"
+                )));
+            }
+            (_, true) => {
+                return Err(invalid(format!(
+                    "NIC {name:?} does not have a ppt backend"
+                )));
+            }
+        }
+        let bdf: pci::Bdf = pci_path
+            .try_into()
+            .map_err(|e| failed(format!("invalid PCI path: {e}")))?;
+        let bridge =
+            self.vm_objects.hotplug_bridges.get(&bdf.bus.get()).ok_or_else(
+                || invalid(format!("NIC {name:?} is not in a hotplug slot")),
+            )?;
+        let device_key = if passthrough {
+            format!("pci-ppt-{bdf}")
+        } else {
+            format!("pci-virtio-viona-{bdf}")
+        };
+        let nic = self
+            .vm_objects
+            .devices
+            .lock()
+            .unwrap()
+            .get(&device_key)
+            .cloned()
+            .ok_or_else(|| failed(format!("no device found at {bdf}")))?;
+
+        if !bridge
+            .hotplug_wait_power_off(Duration::ZERO)
+            .map_err(|e| failed(e.to_string()))?
+        {
+            return Err(failed(format!("guest has not released NIC {name:?}")));
+        }
+
+        // Halting the device tears down its in-kernel state, releasing the
+        // VNIC (or host function) for use elsewhere.
+        nic.halt();
+        bridge.hotplug_remove().map_err(|e| failed(e.to_string()))?;
+        info!(self.log, "Removed hotplugged NIC"; "nic" => name);
+
+        self.vm_objects.devices.lock().unwrap().remove(&device_key);
+        self.vm_objects.nic_links.lock().unwrap().remove(name);
+        v0_spec.devices.network_devices.remove(name);
+        v0_spec.backends.network_backends.remove(&backend_name);
+        self.note_spec_change();
+        Ok(())
+    }
+}
+
+/// Swaps `new` in for `old` as the backend of `device`, which must be paused
+/// with its in-flight requests drained.  If `new` cannot be attached or
+/// started, `old` is attached and started again, and the error returned.
+fn swap_backend(
+    log: &Logger,
+    device: &Arc<dyn block::Device>,
+    old: &Arc<dyn block::Backend>,
+    new: &Arc<dyn block::Backend>,
+) -> Result<(), String> {
+    old.stop();
+    let swapped = old
+        .detach()
+        .map_err(|e| format!("failed to detach old backend: {e}"))
+        .and_then(|_| {
+            block::attach(device.clone(), new.clone())
+                .map_err(|e| format!("failed to attach new backend: {e}"))
+        })
+        .and_then(|_| {
+            new.start().map_err(|e| {
+                new.stop();
+                let _ = new.detach();
+                format!("failed to start new backend: {e}")
+            })
+        });
+    if swapped.is_err() {
+        // The old backend may or may not still be attached, depending on how
+        // far the replacement got.
+        let _ = block::attach(device.clone(), old.clone());
+        if let Err(e) = old.start() {
+            error!(log, "Failed to restart old storage backend"; "error" => ?e);
+        }
+    }
+    swapped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use propolis::accessors::MemAccessor;
+    use propolis::block::{
+        tracking::Tracking, BackendAttachment, BackendOpts, DeviceAttachment,
+        DeviceInfo, InMemoryBackend, ReqId, Request,
+    };
+    use std::num::NonZeroUsize;
+
+    /// A device which submits the requests queued in it, recording the names
+    /// of those completed successfully
+    struct TestDevice {
+        att: DeviceAttachment,
+        tracking: Tracking<&'static str>,
+        queued: Mutex<VecDeque<(Request, &'static str)>>,
+        completed: Mutex<Vec<&'static str>>,
+    }
+    impl TestDevice {
+        fn new() -> Arc<Self> {
+            Arc::new_cyclic(|me: &Weak<Self>| Self {
+                att: DeviceAttachment::new(),
+                tracking: Tracking::new(me.clone()),
+                queued: Mutex::new(VecDeque::new()),
+                completed: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn queue(&self, name: &'static str) {
+            let req = Request::new_flush();
+            self.queued.lock().unwrap().push_back((req, name));
+            self.att.notify();
+        }
+
+        fn completed(&self) -> Vec<&'static str> {
+            self.completed.lock().unwrap().clone()
+        }
+    }
+    impl block::Device for TestDevice {
+        fn attachment(&self) -> &DeviceAttachment {
+            &self.att
+        }
+        fn next(&self) -> Option<Request> {
+            let (req, name) = self.queued.lock().unwrap().pop_front()?;
+            Some(self.tracking.track(req, name))
+        }
+        fn complete(&self, res: block::Result, id: ReqId) {
+            let (_, name) = self.tracking.complete(id, res);
+            if !res.is_err() {
+                self.completed.lock().unwrap().push(name);
+            }
+        }
+        fn accessor_mem(&self) -> MemAccessor {
+            MemAccessor::new_orphan()
+        }
+    }
+
+    /// A backend which holds the requests it takes until the test completes
+    /// them.
+    struct HoldingBackend(BackendAttachment);
+    impl HoldingBackend {
+        fn take(&self) -> Option<Request> {
+            self.0.next_req().ok()
+        }
+    }
+    impl block::Backend for HoldingBackend {
+        fn attachment(&self) -> &BackendAttachment {
+            &self.0
+        }
+        fn info(&self) -> DeviceInfo {
+            DeviceInfo { block_size: 512, total_size: 8, read_only: false }
+        }
+        fn start(&self) -> anyhow::Result<()> {
+            self.0.start();
+            Ok(())
+        }
+        fn stop(&self) {
+            self.0.stop();
+        }
+    }
+
+    /// A backend which can be attached, but fails to start.
+    struct FailingBackend(BackendAttachment);
+    impl block::Backend for FailingBackend {
+        fn attachment(&self) -> &BackendAttachment {
+            &self.0
+        }
+        fn info(&self) -> DeviceInfo {
+            DeviceInfo { block_size: 512, total_size: 8, read_only: false }
+        }
+        fn start(&self) -> anyhow::Result<()> {
+            anyhow::bail!("backend is unreachable")
+        }
+        fn stop(&self) {
+            self.0.stop();
+        }
+    }
+
+    fn test_log() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn in_memory_backend() -> Arc<dyn block::Backend> {
+        InMemoryBackend::create(
+            vec![0; 8 * 512],
+            BackendOpts { block_size: Some(512), ..Default::default() },
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn is_attached(backend: &Arc<dyn block::Backend>) -> bool {
+        backend.attachment().accessor_mem(|_| ()).is_some()
+    }
+
+    fn attached_device(
+        backend: &Arc<dyn block::Backend>,
+    ) -> Arc<dyn block::Device> {
+        let device: Arc<dyn block::Device> = TestDevice::new();
+        block::attach(device.clone(), backend.clone()).unwrap();
+        backend.start().unwrap();
+        device
+    }
+
+    #[test]
+    fn swapped_backend_replaces_old() {
+        let old = in_memory_backend();
+        let new = in_memory_backend();
+        let device = attached_device(&old);
+
+        swap_backend(&test_log(), &device, &old, &new).unwrap();
+        assert!(!is_attached(&old));
+        assert!(is_attached(&new));
+
+        new.stop();
+        new.detach().unwrap();
+    }
+
+    #[test]
+    fn old_backend_restored_when_new_fails_to_start() {
+        let old = in_memory_backend();
+        let new: Arc<dyn block::Backend> =
+            Arc::new(FailingBackend(BackendAttachment::new()));
+        let device = attached_device(&old);
+
+        assert!(swap_backend(&test_log(), &device, &old, &new).is_err());
+        assert!(is_attached(&old));
+        assert!(!is_attached(&new));
+
+        old.stop();
+        old.detach().unwrap();
+    }
+
+    #[test]
+    fn in_flight_io_completes_across_swap() {
+        let old = Arc::new(HoldingBackend(BackendAttachment::new()));
+        let new = Arc::new(HoldingBackend(BackendAttachment::new()));
+        let dev = TestDevice::new();
+        let device: Arc<dyn block::Device> = dev.clone();
+        block::attach(device.clone(), old.clone()).unwrap();
+        block::Backend::start(&*old).unwrap();
+
+        dev.queue("a");
+        dev.queue("b");
+        let a = old.take().unwrap();
+
+        // Once paused, the device hands out no more requests, but the one in
+        // flight still completes through the old backend.
+        dev.att.pause();
+        assert!(old.take().is_none());
+        a.complete(block::Result::Success);
+        assert_eq!(dev.completed(), ["a"]);
+
+        let old_be: Arc<dyn block::Backend> = old.clone();
+        let new_be: Arc<dyn block::Backend> = new.clone();
+        swap_backend(&test_log(), &device, &old_be, &new_be).unwrap();
+        dev.att.resume();
+
+        // The request left queued goes to the new backend.
+        assert!(old.take().is_none());
+        new.take().unwrap().complete(block::Result::Success);
+        assert_eq!(dev.completed(), ["a", "b"]);
+        assert!(!dev.tracking.any_outstanding());
+
+        new_be.stop();
+        new_be.detach().unwrap();
+    }
+
+    #[test]
+    fn errors_carry_status_and_code() {
+        use http::status::StatusCode;
+
+        let timed_out = || std::io::Error::from(std::io::ErrorKind::TimedOut);
+        let cases = [
+            (
+                VmControllerError::InstanceNotActive,
+                StatusCode::FORBIDDEN,
+                ErrorCode::InstanceNotActive,
+            ),
+            (
+                VmControllerError::StateChangeRequestDenied(
+                    RequestDeniedReason::HaltPending,
+                ),
+                StatusCode::FORBIDDEN,
+                ErrorCode::HaltPending,
+            ),
+            (
+                VmControllerError::NoSuchNetworkDevice("net0".to_string()),
+                StatusCode::NOT_FOUND,
+                ErrorCode::NoSuchDevice,
+            ),
+            (
+                VmControllerError::PciSlotInUse(PciPath::new(0, 8, 0).unwrap()),
+                StatusCode::CONFLICT,
+                ErrorCode::PciSlotInUse,
+            ),
+            (
+                VmControllerError::BackendUnreachable(
+                    "disk0-backend".to_string(),
+                    timed_out(),
+                ),
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::BackendUnreachable,
+            ),
+            (
+                VmControllerError::InvalidHotplugRequest("no slot".to_string()),
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidRequest,
+            ),
+            (
+                VmControllerError::HotplugFailed("no slot".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::OperationFailed,
+            ),
+            (
+                VmControllerError::MigrationProtocolError(
+                    MigrateError::MigrationAlreadyInProgress,
+                ),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::MigrationInProgress,
+            ),
+        ];
+        for (err, status, code) in cases {
+            let msg = err.to_string();
+            let err = dropshot::HttpError::from(err);
+            assert_eq!(err.status_code, status, "{msg}");
+            assert_eq!(err.error_code, Some(code.to_string()), "{msg}");
+        }
+
+        // Errors from migrations are coded the same way when returned alone.
+        let err =
+            dropshot::HttpError::from(MigrateError::NoMigrationInProgress);
+        assert_eq!(err.status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            err.error_code,
+            Some(ErrorCode::NoMigrationInProgress.to_string())
+        );
+    }
 }
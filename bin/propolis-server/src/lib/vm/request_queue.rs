@@ -28,9 +28,14 @@ use thiserror::Error;
 use uuid::Uuid;
 
 use crate::migrate::MigrateError;
+use propolis_api_types::instance_spec::{
+    components::devices::{NvmeDisk, VirtioNic},
+    v0::{NetworkBackendV0, StorageBackendV0},
+};
 
 use super::{
     MigrateSourceCommand, MigrateSourceResponse, MigrateTargetCommand,
+    VmControllerError,
 };
 
 /// An external request made of a VM controller via the server API. Handled by
@@ -84,6 +89,95 @@ pub enum ExternalRequest {
     /// Halts the VM. Note that this is not a graceful shutdown and does not
     /// coordinate with guest software.
     Stop,
+
+    /// Presses the VM's ACPI power button, then halts the VM if the guest has
+    /// not powered it off once the grace period has passed.
+    Shutdown {
+        /// How long to wait for the guest to power off.
+        grace_period: std::time::Duration,
+    },
+
+    /// Pauses the VM's vCPUs and devices, leaving them ready to resume.
+    Pause,
+
+    /// Resumes a VM paused by a previous request to pause it.
+    Resume,
+
+    /// Attaches a new NVMe disk to the VM through a PCIe hotplug slot.
+    AttachDisk {
+        /// The name of the disk in the instance spec.
+        name: String,
+
+        /// The disk to attach.
+        device: NvmeDisk,
+
+        /// The disk's backend.
+        backend: StorageBackendV0,
+
+        /// A channel on which to send the result of the attachment.
+        result_tx: tokio::sync::oneshot::Sender<Result<(), VmControllerError>>,
+    },
+
+    /// Detaches a hotplugged NVMe disk from the VM, after asking the guest to
+    /// release it.
+    DetachDisk {
+        /// The name of the disk in the instance spec.
+        name: String,
+
+        /// A channel on which to send the result of the detachment.
+        result_tx: tokio::sync::oneshot::Sender<Result<(), VmControllerError>>,
+    },
+
+    /// Attaches a new virtio NIC to the VM through a PCIe hotplug slot.
+    AttachNic {
+        /// The name of the NIC in the instance spec.
+        name: String,
+
+        /// The NIC to attach.
+        device: VirtioNic,
+
+        /// The NIC's backend.
+        backend: NetworkBackendV0,
+
+        /// A channel on which to send the result of the attachment.
+        result_tx: tokio::sync::oneshot::Sender<Result<(), VmControllerError>>,
+    },
+
+    /// Detaches a hotplugged virtio NIC from the VM, after asking the guest to
+    /// release it.
+    DetachNic {
+        /// The name of the NIC in the instance spec.
+        name: String,
+
+        /// A channel on which to send the result of the detachment.
+        result_tx: tokio::sync::oneshot::Sender<Result<(), VmControllerError>>,
+    },
+}
+
+impl ExternalRequest {
+    /// Describes this request for the instance's history.
+    pub(super) fn description(&self) -> String {
+        match self {
+            Self::MigrateAsTarget { migration_id, .. } => {
+                format!("migrate in (migration {migration_id})")
+            }
+            Self::Start => "start".to_string(),
+            Self::MigrateAsSource { migration_id, .. } => {
+                format!("migrate out (migration {migration_id})")
+            }
+            Self::Reboot => "reboot".to_string(),
+            Self::Stop => "stop".to_string(),
+            Self::Shutdown { grace_period } => {
+                format!("shut down (grace period {grace_period:?})")
+            }
+            Self::Pause => "pause".to_string(),
+            Self::Resume => "resume".to_string(),
+            Self::AttachDisk { name, .. } => format!("attach disk {name:?}"),
+            Self::DetachDisk { name, .. } => format!("detach disk {name:?}"),
+            Self::AttachNic { name, .. } => format!("attach NIC {name:?}"),
+            Self::DetachNic { name, .. } => format!("detach NIC {name:?}"),
+        }
+    }
 }
 
 /// A set of reasons why a request to queue an external state transition can
@@ -108,6 +202,9 @@ pub enum RequestDeniedReason {
     #[error("Instance is preparing to stop")]
     HaltPending,
 
+    #[error("Operation cannot be performed while the instance is paused")]
+    InstancePaused,
+
     #[error("Instance failed to start or halted due to a failure")]
     InstanceFailed,
 }
@@ -117,6 +214,7 @@ pub enum RequestDeniedReason {
 #[derive(Copy, Clone, Debug)]
 pub enum InstanceStateChange {
     StartedRunning,
+    StartedPaused,
     Rebooted,
     Stopped,
     Failed,
@@ -152,6 +250,10 @@ struct AllowedRequests {
     migrate_as_source: RequestDisposition,
     reboot: RequestDisposition,
     stop: RequestDisposition,
+    hotplug: RequestDisposition,
+    pause: RequestDisposition,
+    resume: RequestDisposition,
+    shutdown: RequestDisposition,
 }
 
 #[derive(Debug)]
@@ -176,6 +278,18 @@ impl ExternalRequestQueue {
                     RequestDeniedReason::InstanceNotActive,
                 ),
                 stop: RequestDisposition::Enqueue,
+                hotplug: RequestDisposition::Deny(
+                    RequestDeniedReason::InstanceNotActive,
+                ),
+                pause: RequestDisposition::Deny(
+                    RequestDeniedReason::InstanceNotActive,
+                ),
+                resume: RequestDisposition::Deny(
+                    RequestDeniedReason::InstanceNotActive,
+                ),
+                shutdown: RequestDisposition::Deny(
+                    RequestDeniedReason::InstanceNotActive,
+                ),
             },
             log,
         }
@@ -211,6 +325,13 @@ impl ExternalRequestQueue {
             // that hasn't started should still be queued to the state worker so
             // that the worker can exit and drop its references to the instance.
             ExternalRequest::Stop => self.allowed.stop,
+            ExternalRequest::Shutdown { .. } => self.allowed.shutdown,
+            ExternalRequest::Pause => self.allowed.pause,
+            ExternalRequest::Resume => self.allowed.resume,
+            ExternalRequest::AttachDisk { .. }
+            | ExternalRequest::DetachDisk { .. }
+            | ExternalRequest::AttachNic { .. }
+            | ExternalRequest::DetachNic { .. } => self.allowed.hotplug,
         };
 
         info!(&self.log, "Queuing external request";
@@ -326,6 +447,10 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Deny(deny_reason),
                     reboot: Disposition::Deny(deny_reason),
                     stop: self.allowed.stop,
+                    hotplug: self.allowed.hotplug,
+                    pause: Disposition::Deny(deny_reason),
+                    resume: Disposition::Deny(deny_reason),
+                    shutdown: Disposition::Deny(deny_reason),
                 }
             }
             ChangeReason::ApiRequest(ExternalRequest::MigrateAsSource {
@@ -351,6 +476,18 @@ impl ExternalRequestQueue {
                         DenyReason::InvalidRequestForMigrationSource,
                     ),
                     stop: self.allowed.stop,
+                    hotplug: Disposition::Deny(
+                        DenyReason::InvalidRequestForMigrationSource,
+                    ),
+                    pause: Disposition::Deny(
+                        DenyReason::InvalidRequestForMigrationSource,
+                    ),
+                    resume: Disposition::Deny(
+                        DenyReason::InvalidRequestForMigrationSource,
+                    ),
+                    shutdown: Disposition::Deny(
+                        DenyReason::InvalidRequestForMigrationSource,
+                    ),
                 }
             }
 
@@ -366,6 +503,98 @@ impl ExternalRequestQueue {
                 AllowedRequests { reboot: Disposition::Ignore, ..self.allowed }
             }
 
+            // Disks and NICs may be attached and detached any number of times
+            // while the instance runs, and doing so doesn't affect other
+            // operations.
+            ChangeReason::ApiRequest(ExternalRequest::AttachDisk {
+                ..
+            })
+            | ChangeReason::ApiRequest(ExternalRequest::DetachDisk {
+                ..
+            })
+            | ChangeReason::ApiRequest(ExternalRequest::AttachNic { .. })
+            | ChangeReason::ApiRequest(ExternalRequest::DetachNic { .. }) => {
+                self.allowed
+            }
+
+            // A paused instance can't be rebooted, migrated, shut down, or have
+            // devices hotplugged until it's resumed, since each of these needs the
+            // instance to be running. Further requests to pause it are ignored
+            // for idempotency. A reboot requested before the pause is still
+            // carried out, but new reboot requests are denied even once it
+            // completes.
+            ChangeReason::ApiRequest(ExternalRequest::Pause) => {
+                let deny_if_allowed =
+                    |disposition: Disposition| match disposition {
+                        Disposition::Enqueue | Disposition::Ignore => {
+                            Disposition::Deny(DenyReason::InstancePaused)
+                        }
+                        Disposition::Deny(_) => disposition,
+                    };
+
+                AllowedRequests {
+                    migrate_as_target: self.allowed.migrate_as_target,
+                    start: self.allowed.start,
+                    migrate_as_source: deny_if_allowed(
+                        self.allowed.migrate_as_source,
+                    ),
+                    reboot: deny_if_allowed(self.allowed.reboot),
+                    stop: self.allowed.stop,
+                    hotplug: deny_if_allowed(self.allowed.hotplug),
+                    pause: Disposition::Ignore,
+                    resume: Disposition::Enqueue,
+                    shutdown: deny_if_allowed(self.allowed.shutdown),
+                }
+            }
+
+            // Resuming the instance allows whatever pausing it denied.
+            // Further requests to resume it are ignored for idempotency.
+            ChangeReason::ApiRequest(ExternalRequest::Resume) => {
+                let allow_if_paused =
+                    |disposition: Disposition| match disposition {
+                        Disposition::Deny(DenyReason::InstancePaused) => {
+                            Disposition::Enqueue
+                        }
+                        _ => disposition,
+                    };
+
+                AllowedRequests {
+                    migrate_as_target: self.allowed.migrate_as_target,
+                    start: self.allowed.start,
+                    migrate_as_source: allow_if_paused(
+                        self.allowed.migrate_as_source,
+                    ),
+                    reboot: allow_if_paused(self.allowed.reboot),
+                    stop: self.allowed.stop,
+                    hotplug: allow_if_paused(self.allowed.hotplug),
+                    pause: Disposition::Enqueue,
+                    resume: Disposition::Ignore,
+                    shutdown: allow_if_paused(self.allowed.shutdown),
+                }
+            }
+
+            // Requests to shut the instance down block requests other than
+            // those to stop it, which remain allowed so that the instance can
+            // be stopped without waiting for the guest. Additional requests to
+            // shut down are ignored for idempotency.
+            ChangeReason::ApiRequest(ExternalRequest::Shutdown { .. }) => {
+                AllowedRequests {
+                    migrate_as_target: Disposition::Deny(
+                        DenyReason::HaltPending,
+                    ),
+                    start: Disposition::Deny(DenyReason::HaltPending),
+                    migrate_as_source: Disposition::Deny(
+                        DenyReason::HaltPending,
+                    ),
+                    reboot: Disposition::Deny(DenyReason::HaltPending),
+                    stop: self.allowed.stop,
+                    hotplug: Disposition::Deny(DenyReason::HaltPending),
+                    pause: Disposition::Deny(DenyReason::HaltPending),
+                    resume: Disposition::Deny(DenyReason::HaltPending),
+                    shutdown: Disposition::Ignore,
+                }
+            }
+
             // Requests to stop the instance block other requests from being
             // queued. Additional requests to stop are ignored for idempotency.
             ChangeReason::ApiRequest(ExternalRequest::Stop) => {
@@ -379,11 +608,16 @@ impl ExternalRequestQueue {
                     ),
                     reboot: Disposition::Deny(DenyReason::HaltPending),
                     stop: Disposition::Ignore,
+                    hotplug: Disposition::Deny(DenyReason::HaltPending),
+                    pause: Disposition::Deny(DenyReason::HaltPending),
+                    resume: Disposition::Deny(DenyReason::HaltPending),
+                    shutdown: Disposition::Deny(DenyReason::HaltPending),
                 }
             }
 
-            // When an instance begins running, requests to migrate out of it or
-            // to reboot it become valid.
+            // When an instance begins running, requests to migrate out of it,
+            // to reboot it, to hotplug its devices, to pause it, or to shut it
+            // down become valid. Requests to resume it are ignored until it's paused.
             ChangeReason::StateChange(InstanceStateChange::StartedRunning) => {
                 AllowedRequests {
                     migrate_as_target: self.allowed.migrate_as_target,
@@ -391,6 +625,28 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Enqueue,
                     reboot: Disposition::Enqueue,
                     stop: self.allowed.stop,
+                    hotplug: Disposition::Enqueue,
+                    pause: Disposition::Enqueue,
+                    resume: Disposition::Ignore,
+                    shutdown: Disposition::Enqueue,
+                }
+            }
+
+            // An instance asked to start paused allows the same requests as
+            // one paused after it began running.
+            ChangeReason::StateChange(InstanceStateChange::StartedPaused) => {
+                AllowedRequests {
+                    migrate_as_target: self.allowed.migrate_as_target,
+                    start: self.allowed.start,
+                    migrate_as_source: Disposition::Deny(
+                        DenyReason::InstancePaused,
+                    ),
+                    reboot: Disposition::Deny(DenyReason::InstancePaused),
+                    stop: self.allowed.stop,
+                    hotplug: Disposition::Deny(DenyReason::InstancePaused),
+                    pause: Disposition::Ignore,
+                    resume: Disposition::Enqueue,
+                    shutdown: Disposition::Deny(DenyReason::InstancePaused),
                 }
             }
 
@@ -424,6 +680,10 @@ impl ExternalRequestQueue {
                     ),
                     reboot: Disposition::Deny(DenyReason::InstanceNotActive),
                     stop: Disposition::Ignore,
+                    hotplug: Disposition::Deny(DenyReason::InstanceNotActive),
+                    pause: Disposition::Deny(DenyReason::InstanceNotActive),
+                    resume: Disposition::Deny(DenyReason::InstanceNotActive),
+                    shutdown: Disposition::Deny(DenyReason::InstanceNotActive),
                 }
             }
             ChangeReason::StateChange(InstanceStateChange::Failed) => {
@@ -437,6 +697,10 @@ impl ExternalRequestQueue {
                     ),
                     reboot: Disposition::Deny(DenyReason::InstanceFailed),
                     stop: self.allowed.stop,
+                    hotplug: Disposition::Deny(DenyReason::InstanceFailed),
+                    pause: Disposition::Deny(DenyReason::InstanceFailed),
+                    resume: Disposition::Deny(DenyReason::InstanceFailed),
+                    shutdown: Disposition::Deny(DenyReason::InstanceFailed),
                 }
             }
         }
@@ -479,6 +743,16 @@ mod test {
         }
     }
 
+    fn make_detach_disk_request() -> ExternalRequest {
+        let (result_tx, _) = tokio::sync::oneshot::channel();
+        ExternalRequest::DetachDisk { name: "disk".to_string(), result_tx }
+    }
+
+    fn make_detach_nic_request() -> ExternalRequest {
+        let (result_tx, _) = tokio::sync::oneshot::channel();
+        ExternalRequest::DetachNic { name: "nic".to_string(), result_tx }
+    }
+
     #[tokio::test]
     async fn migrate_as_target_is_idempotent() {
         let mut queue = ExternalRequestQueue::new(test_logger());
@@ -601,4 +875,155 @@ mod test {
         queue.notify_instance_state_change(InstanceStateChange::Rebooted);
         assert!(queue.try_queue(ExternalRequest::Reboot).is_err());
     }
+
+    #[tokio::test]
+    async fn hotplug_requests_require_running_instance() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        assert!(queue.try_queue(make_detach_disk_request()).is_err());
+        assert!(queue.try_queue(make_detach_nic_request()).is_err());
+        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
+        assert!(queue.try_queue(make_detach_disk_request()).is_err());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        // Hotplug requests are never idempotent, so each one is queued.
+        for _ in 0..3 {
+            assert!(queue.try_queue(make_detach_disk_request()).is_ok());
+        }
+        for _ in 0..3 {
+            assert!(matches!(
+                queue.pop_front(),
+                Some(ExternalRequest::DetachDisk { .. })
+            ));
+        }
+
+        // Hotplugging is forbidden while migrating out or stopping.
+        assert!(queue.try_queue(make_migrate_as_source_request()).is_ok());
+        assert!(queue.try_queue(make_detach_disk_request()).is_err());
+        queue.pop_front();
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+        assert!(queue.try_queue(make_detach_disk_request()).is_ok());
+        assert!(queue.try_queue(make_detach_nic_request()).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
+        assert!(queue.try_queue(make_detach_disk_request()).is_err());
+        assert!(queue.try_queue(make_detach_nic_request()).is_err());
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_are_idempotent() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        assert!(queue.try_queue(ExternalRequest::Pause).is_err());
+        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Pause).is_err());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        // A running instance ignores requests to resume it, and queues only
+        // the first of several requests to pause it.
+        assert!(queue.try_queue(ExternalRequest::Resume).is_ok());
+        assert!(queue.is_empty());
+        for _ in 0..3 {
+            assert!(queue.try_queue(ExternalRequest::Pause).is_ok());
+        }
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Pause)));
+        assert!(queue.is_empty());
+
+        // Likewise, only the first request to resume it is queued.
+        for _ in 0..3 {
+            assert!(queue.try_queue(ExternalRequest::Resume).is_ok());
+        }
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Resume)));
+        assert!(queue.is_empty());
+
+        // Stopping the instance forbids pausing or resuming it.
+        assert!(queue.try_queue(ExternalRequest::Pause).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Resume).is_err());
+    }
+
+    #[tokio::test]
+    async fn paused_instances_only_resume_or_stop() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        // A reboot queued before the pause still happens, but further reboots
+        // are denied until the instance resumes.
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Pause).is_ok());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Reboot)));
+        queue.notify_instance_state_change(InstanceStateChange::Rebooted);
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Pause)));
+        assert!(matches!(
+            queue.try_queue(ExternalRequest::Reboot),
+            Err(RequestDeniedReason::InstancePaused)
+        ));
+        assert!(queue.migrate_as_source_will_enqueue().is_err());
+        assert!(queue.try_queue(make_detach_disk_request()).is_err());
+
+        // Resuming the instance allows everything pausing it forbade.
+        assert!(queue.try_queue(ExternalRequest::Resume).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+        assert!(queue.try_queue(make_detach_disk_request()).is_ok());
+        assert!(queue.migrate_as_source_will_enqueue().unwrap());
+
+        // Migrating out forbids pausing the instance.
+        assert!(queue.try_queue(make_migrate_as_source_request()).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Pause).is_err());
+    }
+
+    #[tokio::test]
+    async fn instances_started_paused_only_resume_or_stop() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        queue.notify_instance_state_change(InstanceStateChange::StartedPaused);
+
+        assert!(matches!(
+            queue.try_queue(ExternalRequest::Reboot),
+            Err(RequestDeniedReason::InstancePaused)
+        ));
+        assert!(queue.migrate_as_source_will_enqueue().is_err());
+        assert!(queue.try_queue(ExternalRequest::Pause).is_ok());
+        assert!(queue.is_empty());
+
+        assert!(queue.try_queue(ExternalRequest::Resume).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shutdown_requests_allow_stopping() {
+        let shutdown = || ExternalRequest::Shutdown {
+            grace_period: std::time::Duration::from_secs(30),
+        };
+
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        assert!(queue.try_queue(shutdown()).is_err());
+        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        // Only the first of several requests to shut down is queued, and
+        // nothing but requests to stop is allowed once one has been.
+        for _ in 0..3 {
+            assert!(queue.try_queue(shutdown()).is_ok());
+        }
+        assert!(matches!(
+            queue.pop_front(),
+            Some(ExternalRequest::Shutdown { .. })
+        ));
+        assert!(queue.is_empty());
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_err());
+        assert!(queue.try_queue(ExternalRequest::Pause).is_err());
+        assert!(queue.try_queue(make_detach_disk_request()).is_err());
+        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Stop)));
+
+        // The guest powering off before the grace period passes still allows
+        // the fallback request to stop.
+        queue.notify_instance_state_change(InstanceStateChange::Stopped);
+        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
+        assert!(queue.try_queue(shutdown()).is_err());
+    }
 }
@@ -2,7 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeSet;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::migrate::MigrateError;
 use crate::vcpu_tasks::VcpuTaskController;
@@ -14,9 +16,10 @@ use super::{
 };
 
 use propolis_api_types::{
-    InstanceMigrateStatusResponse as ApiMigrationStatus,
+    InstanceEventKind, InstanceMigrateStatusResponse as ApiMigrationStatus,
     InstanceState as ApiInstanceState,
     InstanceStateMonitorResponse as ApiMonitoredState,
+    MigrationFailure as ApiMigrationFailure,
     MigrationState as ApiMigrationState,
 };
 use slog::{error, info, Logger};
@@ -72,6 +75,15 @@ pub(super) struct StateDriver<
     /// Whether the worker's VM's devices are paused.
     paused: bool,
 
+    /// Whether to leave the VM paused, rather than running, when it is
+    /// started by explicit request.
+    start_paused: bool,
+
+    /// The block devices whose I/O has stalled, pending recovery of their
+    /// backends.  The VM's vCPUs (and kernel VMM resources) are paused while
+    /// any are.
+    io_stalled: BTreeSet<String>,
+
     /// The sender side of the monitor that reflects the instance's current
     /// externally-visible state (including migration state).
     api_state_tx: tokio::sync::watch::Sender<ApiMonitoredState>,
@@ -88,6 +100,7 @@ where
         controller: Arc<V>,
         shared_controller_state: Arc<SharedVmState>,
         vcpu_tasks: C,
+        start_paused: bool,
         log: Logger,
         api_state_tx: tokio::sync::watch::Sender<ApiMonitoredState>,
     ) -> Self {
@@ -99,10 +112,21 @@ where
             log,
             state_gen: 0,
             paused: false,
+            start_paused,
+            io_stalled: BTreeSet::new(),
             api_state_tx,
         }
     }
 
+    /// Records an event in the server's history of its instance.
+    fn record(&self, kind: InstanceEventKind) {
+        self.shared_state.history.record(kind);
+    }
+
+    fn record_guest_event(&self, event: &str) {
+        self.record(InstanceEventKind::Guest { event: event.to_string() });
+    }
+
     /// Yields the current externally-visible instance state.
     fn get_instance_state(&self) -> ApiInstanceState {
         self.api_state_tx.borrow().state
@@ -112,6 +136,7 @@ where
     /// instance state channel.
     fn set_instance_state(&mut self, state: ApiInstanceState) {
         let old = self.api_state_tx.borrow().clone();
+        self.record(InstanceEventKind::StateChanged { state });
 
         self.state_gen += 1;
         let _ = self.api_state_tx.send(ApiMonitoredState {
@@ -138,11 +163,45 @@ where
         state: ApiMigrationState,
     ) {
         let old = self.api_state_tx.borrow().clone();
+        self.record(InstanceEventKind::MigrationStateChanged {
+            migration_id,
+            state,
+            failure: None,
+        });
 
         self.state_gen += 1;
         let _ = self.api_state_tx.send(ApiMonitoredState {
             gen: self.state_gen,
-            migration: Some(ApiMigrationStatus { migration_id, state }),
+            migration: Some(ApiMigrationStatus {
+                migration_id,
+                state,
+                failure: None,
+            }),
+            ..old
+        });
+    }
+
+    /// Publishes that the migration with the supplied ID failed, and how.
+    fn set_migration_failure(
+        &mut self,
+        migration_id: Uuid,
+        failure: ApiMigrationFailure,
+    ) {
+        let old = self.api_state_tx.borrow().clone();
+        self.record(InstanceEventKind::MigrationStateChanged {
+            migration_id,
+            state: ApiMigrationState::Error,
+            failure: Some(failure.clone()),
+        });
+
+        self.state_gen += 1;
+        let _ = self.api_state_tx.send(ApiMonitoredState {
+            gen: self.state_gen,
+            migration: Some(ApiMigrationStatus {
+                migration_id,
+                state: ApiMigrationState::Error,
+                failure: Some(failure),
+            }),
             ..old
         });
     }
@@ -220,6 +279,73 @@ where
                 self.do_halt();
                 HandleEventOutcome::Exit
             }
+            ExternalRequest::Shutdown { grace_period } => {
+                self.do_shutdown(grace_period);
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::Pause => {
+                self.do_pause();
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::Resume => {
+                self.do_resume();
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::AttachDisk {
+                name,
+                device,
+                backend,
+                result_tx,
+            } => {
+                let res =
+                    self.controller.hotplug_attach_disk(&name, device, backend);
+                if let Err(e) = &res {
+                    error!(self.log, "Failed to attach disk {}: {}", name, e);
+                    self.record(InstanceEventKind::DeviceError {
+                        device: name.clone(),
+                        error: format!("failed to attach: {e}"),
+                    });
+                }
+                let _ = result_tx.send(res);
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::DetachDisk { name, result_tx } => {
+                let res = self.controller.hotplug_detach_disk(&name);
+                if let Err(e) = &res {
+                    error!(self.log, "Failed to detach disk {}: {}", name, e);
+                    self.record(InstanceEventKind::DeviceError {
+                        device: name.clone(),
+                        error: format!("failed to detach: {e}"),
+                    });
+                }
+                let _ = result_tx.send(res);
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::AttachNic { name, device, backend, result_tx } => {
+                let res =
+                    self.controller.hotplug_attach_nic(&name, device, backend);
+                if let Err(e) = &res {
+                    error!(self.log, "Failed to attach NIC {}: {}", name, e);
+                    self.record(InstanceEventKind::DeviceError {
+                        device: name.clone(),
+                        error: format!("failed to attach: {e}"),
+                    });
+                }
+                let _ = result_tx.send(res);
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::DetachNic { name, result_tx } => {
+                let res = self.controller.hotplug_detach_nic(&name);
+                if let Err(e) = &res {
+                    error!(self.log, "Failed to detach NIC {}: {}", name, e);
+                    self.record(InstanceEventKind::DeviceError {
+                        device: name.clone(),
+                        error: format!("failed to detach: {e}"),
+                    });
+                }
+                let _ = result_tx.send(res);
+                HandleEventOutcome::Continue
+            }
         }
     }
 
@@ -227,11 +353,13 @@ where
         match event {
             GuestEvent::VcpuSuspendHalt(_when) => {
                 info!(self.log, "Halting due to VM suspend event",);
+                self.record_guest_event("halted by vCPU suspend");
                 self.do_halt();
                 HandleEventOutcome::Exit
             }
             GuestEvent::VcpuSuspendReset(_when) => {
                 info!(self.log, "Resetting due to VM suspend event");
+                self.record_guest_event("reset by vCPU suspend");
                 self.do_reboot();
                 HandleEventOutcome::Continue
             }
@@ -240,19 +368,66 @@ where
                     self.log,
                     "Resetting due to triple fault on vCPU {}", vcpu_id
                 );
+                self.record_guest_event(&format!(
+                    "triple fault on vCPU {vcpu_id}"
+                ));
                 self.do_reboot();
                 HandleEventOutcome::Continue
             }
             GuestEvent::ChipsetHalt => {
                 info!(self.log, "Halting due to chipset-driven halt");
+                self.record_guest_event("halted by chipset");
                 self.do_halt();
                 HandleEventOutcome::Exit
             }
             GuestEvent::ChipsetReset => {
                 info!(self.log, "Resetting due to chipset-driven reset");
+                self.record_guest_event("reset by chipset");
                 self.do_reboot();
                 HandleEventOutcome::Continue
             }
+            GuestEvent::BlockIoStalled(device) => {
+                // Stalls are only tracked while the vCPUs can be paused for
+                // them, or are already paused for others.
+                let trackable = !self.io_stalled.is_empty()
+                    || (!self.paused
+                        && self.get_instance_state()
+                            == ApiInstanceState::Running);
+                if trackable && !self.io_stalled.contains(&device) {
+                    info!(self.log, "Block I/O stalled"; "device" => &device);
+                    self.record(InstanceEventKind::DeviceError {
+                        device: device.clone(),
+                        error: "I/O stalled; vCPUs paused".to_string(),
+                    });
+                    if self.io_stalled.is_empty() {
+                        info!(self.log, "Pausing vCPUs while I/O is stalled");
+                        self.vcpu_tasks.pause_all();
+                        self.controller.pause_vm();
+                    }
+                    self.io_stalled.insert(device);
+                }
+                HandleEventOutcome::Continue
+            }
+            GuestEvent::BlockIoResumed(device) => {
+                if self.io_stalled.remove(&device) {
+                    info!(self.log, "Block I/O recovered"; "device" => &device);
+                    if self.io_stalled.is_empty() {
+                        info!(self.log, "Resuming vCPUs after I/O recovered");
+                        self.controller.resume_vm();
+                        self.vcpu_tasks.resume_all();
+                    }
+                }
+                HandleEventOutcome::Continue
+            }
+        }
+    }
+
+    /// Resume the kernel VMM resources paused when block I/O stalled, leaving
+    /// the vCPUs held for the caller to deal with.
+    fn clear_io_stall(&mut self) {
+        if !self.io_stalled.is_empty() {
+            self.controller.resume_vm();
+            self.io_stalled.clear();
         }
     }
 
@@ -286,6 +461,21 @@ where
         }
 
         match self.controller.start_devices() {
+            // If asked to start paused, hold the vCPUs before the guest runs
+            // its first instruction, leaving the instance in the same state
+            // as one paused by request.
+            Ok(())
+                if self.start_paused
+                    && start_reason == VmStartReason::ExplicitRequest =>
+            {
+                self.controller.pause_devices();
+                self.controller.pause_vm();
+                self.paused = true;
+                self.notify_request_queue(
+                    request_queue::InstanceStateChange::StartedPaused,
+                );
+                self.set_instance_state(ApiInstanceState::Paused);
+            }
             Ok(()) => {
                 self.vcpu_tasks.resume_all();
                 self.publish_steady_state(ApiInstanceState::Running);
@@ -305,7 +495,10 @@ where
         // Reboot is implemented as a pause -> reset -> resume transition.
         //
         // First, pause the vCPUs and all devices so no partially-completed
-        // work is present.
+        // work is present.  Pausing the devices will fail any block requests
+        // held due to stalled I/O, so the stall is over as far as the VM is
+        // concerned.
+        self.clear_io_stall();
         self.vcpu_tasks.pause_all();
         self.controller.pause_devices();
 
@@ -343,6 +536,55 @@ where
         self.publish_steady_state(ApiInstanceState::Stopped);
     }
 
+    fn do_shutdown(&mut self, grace_period: Duration) {
+        info!(self.log, "Shutting down instance";
+              "grace_period" => ?grace_period);
+        self.set_instance_state(ApiInstanceState::Stopping);
+        self.controller.press_power_button();
+
+        // A guest that powers off in time halts the instance through its
+        // chipset, after which this request to stop is ignored.
+        let shared_state = self.shared_state.clone();
+        let log = self.log.clone();
+        self.runtime_hdl.spawn(async move {
+            tokio::time::sleep(grace_period).await;
+            if shared_state
+                .queue_external_request(ExternalRequest::Stop)
+                .is_ok()
+            {
+                info!(log, "Shutdown grace period passed, stopping instance");
+            }
+        });
+    }
+
+    fn do_pause(&mut self) {
+        info!(self.log, "Pausing instance");
+
+        // The request queue only admits requests to pause a running instance
+        // and to resume a paused one, but check anyway rather than pausing or
+        // resuming twice.
+        if self.paused || self.get_instance_state() != ApiInstanceState::Running
+        {
+            info!(self.log, "Instance not running, ignoring pause request");
+            return;
+        }
+
+        self.pause();
+        self.set_instance_state(ApiInstanceState::Paused);
+    }
+
+    fn do_resume(&mut self) {
+        info!(self.log, "Resuming instance");
+        if !self.paused || self.get_instance_state() != ApiInstanceState::Paused
+        {
+            info!(self.log, "Instance not paused, ignoring resume request");
+            return;
+        }
+
+        self.resume();
+        self.set_instance_state(ApiInstanceState::Running);
+    }
+
     fn migrate_as_target(
         &mut self,
         migration_id: Uuid,
@@ -406,6 +648,11 @@ where
                 ) => {
                     self.set_migration_state(migration_id, state);
                 }
+                MigrateTaskEvent::Command(MigrateTargetCommand::Failed(
+                    failure,
+                )) => {
+                    self.set_migration_failure(migration_id, failure);
+                }
             }
         }
     }
@@ -469,6 +716,9 @@ where
                     MigrateSourceCommand::UpdateState(state) => {
                         self.set_migration_state(migration_id, state);
                     }
+                    MigrateSourceCommand::Failed(failure) => {
+                        self.set_migration_failure(migration_id, failure);
+                    }
                     MigrateSourceCommand::Pause => {
                         self.pause();
                         response_tx
@@ -512,6 +762,7 @@ where
     fn pause(&mut self) {
         assert!(!self.paused);
         probes::state_driver_pause!(|| ());
+        self.clear_io_stall();
         self.vcpu_tasks.pause_all();
         self.controller.pause_devices();
         self.controller.pause_vm();
@@ -573,7 +824,12 @@ mod test {
 
     use super::*;
     use crate::vcpu_tasks::MockVcpuTaskController;
-    use crate::vm::MockStateDriverVmController;
+    use crate::vm::{MockStateDriverVmController, VmControllerError};
+    use propolis_api_types::instance_spec::{
+        components::{backends::VirtioNetworkBackend, devices::VirtioNic},
+        v0::NetworkBackendV0,
+        PciPath,
+    };
 
     struct TestStateDriver {
         driver:
@@ -608,6 +864,7 @@ mod test {
                 Arc::new(objects.vm_ctrl),
                 objects.shared_state.clone(),
                 objects.vcpu_ctrl,
+                false,
                 logger,
                 state_tx,
             ),
@@ -624,7 +881,10 @@ mod test {
         TestObjects {
             vm_ctrl,
             vcpu_ctrl,
-            shared_state: Arc::new(SharedVmState::new(&logger)),
+            shared_state: Arc::new(SharedVmState::new(
+                &logger,
+                Default::default(),
+            )),
         }
     }
 
@@ -756,6 +1016,66 @@ mod test {
         assert!(matches!(driver.api_state(), ApiInstanceState::Running));
     }
 
+    #[tokio::test]
+    async fn start_paused_holds_vcpus_until_resumed() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_new_generation()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_reset_vcpu_state()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_start_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+        vm_ctrl
+            .expect_pause_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_resume_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.start_paused = true;
+        driver
+            .driver
+            .handle_event(StateDriverEvent::External(ExternalRequest::Start));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Paused));
+
+        driver
+            .driver
+            .handle_event(StateDriverEvent::External(ExternalRequest::Resume));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+    }
+
     #[tokio::test]
     async fn device_start_failure_causes_instance_failure() {
         let mut test_objects = make_default_mocks();
@@ -831,6 +1151,221 @@ mod test {
         assert!(matches!(driver.api_state(), ApiInstanceState::Stopped));
     }
 
+    fn block_io_stalled(device: &str) -> StateDriverEvent {
+        StateDriverEvent::Guest(GuestEvent::BlockIoStalled(device.to_string()))
+    }
+
+    fn block_io_resumed(device: &str) -> StateDriverEvent {
+        StateDriverEvent::Guest(GuestEvent::BlockIoResumed(device.to_string()))
+    }
+
+    #[tokio::test]
+    async fn vcpus_pause_while_block_io_stalled() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+        let mut seq = Sequence::new();
+
+        // Only the vCPUs and kernel VMM resources are paused: the devices must
+        // keep running so the stalled I/O can be retried.
+        vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_resume_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.set_instance_state(ApiInstanceState::Running);
+        driver.driver.handle_event(block_io_stalled("disk0"));
+
+        // A repeated stall notification should not try to pause again.
+        driver.driver.handle_event(block_io_stalled("disk0"));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+
+        driver.driver.handle_event(block_io_resumed("disk0"));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+    }
+
+    #[tokio::test]
+    async fn vcpus_resume_once_all_block_devices_recover() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+
+        // The vCPUs are paused once, when the first device stalls, and
+        // resumed once, when the last one recovers; the expectations are
+        // checked as the mocks are dropped.
+        vcpu_ctrl.expect_pause_all().times(1).returning(|| ());
+        vm_ctrl.expect_pause_vm().times(1).returning(|| ());
+        vm_ctrl.expect_resume_vm().times(1).returning(|| ());
+        vcpu_ctrl.expect_resume_all().times(1).returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.set_instance_state(ApiInstanceState::Running);
+        driver.driver.handle_event(block_io_stalled("disk0"));
+        driver.driver.handle_event(block_io_stalled("disk1"));
+
+        // A recovery of a device which never stalled changes nothing.
+        driver.driver.handle_event(block_io_resumed("disk2"));
+        driver.driver.handle_event(block_io_resumed("disk0"));
+        assert_eq!(driver.driver.io_stalled.len(), 1);
+
+        driver.driver.handle_event(block_io_resumed("disk1"));
+        assert!(driver.driver.io_stalled.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_after_grace_period() {
+        let mut test_objects = make_default_mocks();
+        test_objects
+            .vm_ctrl
+            .expect_press_power_button()
+            .times(1)
+            .returning(|| ());
+        let shared_state = test_objects.shared_state.clone();
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.set_instance_state(ApiInstanceState::Running);
+        assert_eq!(
+            driver.driver.handle_event(StateDriverEvent::External(
+                ExternalRequest::Shutdown {
+                    grace_period: Duration::from_millis(10)
+                }
+            )),
+            HandleEventOutcome::Continue
+        );
+        assert!(matches!(driver.api_state(), ApiInstanceState::Stopping));
+
+        // The guest never powers off, so the instance is stopped once the
+        // grace period passes.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let request = shared_state
+            .inner
+            .lock()
+            .unwrap()
+            .external_request_queue
+            .pop_front();
+        assert!(matches!(request, Some(ExternalRequest::Stop)));
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_requests() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_resume_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.set_instance_state(ApiInstanceState::Running);
+
+        // Resuming an instance that isn't paused does nothing.
+        driver
+            .driver
+            .handle_event(StateDriverEvent::External(ExternalRequest::Resume));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+
+        // Pausing twice pauses only once.
+        for _ in 0..2 {
+            driver.driver.handle_event(StateDriverEvent::External(
+                ExternalRequest::Pause,
+            ));
+            assert!(matches!(driver.api_state(), ApiInstanceState::Paused));
+        }
+
+        driver
+            .driver
+            .handle_event(StateDriverEvent::External(ExternalRequest::Resume));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+    }
+
+    #[tokio::test]
+    async fn paused_vm_halts_without_pausing_again() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_exit_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_halt_devices()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.set_instance_state(ApiInstanceState::Running);
+        driver
+            .driver
+            .handle_event(StateDriverEvent::External(ExternalRequest::Pause));
+        driver
+            .driver
+            .handle_event(StateDriverEvent::External(ExternalRequest::Stop));
+        assert!(matches!(driver.api_state(), ApiInstanceState::Stopped));
+    }
+
     #[tokio::test]
     async fn devices_pause_once_when_halting_after_migration_out() {
         let migration_id = Uuid::new_v4();
@@ -907,7 +1442,8 @@ mod test {
             driver.driver.get_migration_status().unwrap(),
             ApiMigrationStatus {
                 migration_id,
-                state: ApiMigrationState::Finish
+                state: ApiMigrationState::Finish,
+                failure: None,
             }
         );
 
@@ -976,10 +1512,12 @@ mod test {
         let resp = response_rx.recv().await.unwrap();
         assert!(matches!(resp, MigrateSourceResponse::Pause(Ok(()))));
 
-        // Simulate failure. The migration protocol must both update the state
-        // to Error and make the task return `Err`.
+        // Simulate failure. The migration protocol must both report the
+        // failure and make the task return `Err`.
+        let failure = MigrateError::UnexpectedMessage
+            .to_failure(ApiMigrationState::RamPushDirty);
         command_tx
-            .send(MigrateSourceCommand::UpdateState(ApiMigrationState::Error))
+            .send(MigrateSourceCommand::Failed(failure.clone()))
             .await
             .unwrap();
         drop(command_tx);
@@ -999,7 +1537,8 @@ mod test {
             driver.driver.get_migration_status().unwrap(),
             ApiMigrationStatus {
                 migration_id,
-                state: ApiMigrationState::Error
+                state: ApiMigrationState::Error,
+                failure: Some(failure),
             }
         );
     }
@@ -1073,7 +1612,8 @@ mod test {
             driver.driver.get_migration_status().unwrap(),
             ApiMigrationStatus {
                 migration_id,
-                state: ApiMigrationState::Finish
+                state: ApiMigrationState::Finish,
+                failure: None,
             }
         );
         assert!(matches!(driver.api_state(), ApiInstanceState::Running));
@@ -1116,10 +1656,12 @@ mod test {
             (driver, outcome)
         });
 
-        // The migration task is required to update the migration state to
-        // "Error" before exiting when migration fails.
+        // The migration task is required to report the failure, moving the
+        // migration state to "Error", before exiting when migration fails.
+        let failure =
+            MigrateError::UnexpectedMessage.to_failure(ApiMigrationState::Sync);
         command_tx
-            .send(MigrateTargetCommand::UpdateState(ApiMigrationState::Error))
+            .send(MigrateTargetCommand::Failed(failure.clone()))
             .await
             .unwrap();
         drop(command_tx);
@@ -1139,7 +1681,8 @@ mod test {
             driver.driver.get_migration_status().unwrap(),
             ApiMigrationStatus {
                 migration_id,
-                state: ApiMigrationState::Error
+                state: ApiMigrationState::Error,
+                failure: Some(failure),
             }
         );
     }
@@ -1222,7 +1765,8 @@ mod test {
             driver.driver.get_migration_status().unwrap(),
             ApiMigrationStatus {
                 migration_id,
-                state: ApiMigrationState::Finish
+                state: ApiMigrationState::Finish,
+                failure: None,
             }
         );
     }
@@ -1271,4 +1815,104 @@ mod test {
         assert!(matches!(new_state.state, ApiInstanceState::Running));
         assert_eq!(new_state.gen, migrating_gen + 1);
     }
+
+    /// A NIC with a virtio backend, as might be hot-added
+    fn hotplug_nic() -> (VirtioNic, NetworkBackendV0) {
+        let device = VirtioNic {
+            backend_name: "net0-backend".to_string(),
+            pci_path: PciPath::new(0, 16, 0).unwrap(),
+            num_queue_pairs: None,
+            rate_limit: None,
+            offloads: None,
+            link_state: None,
+            interrupt_moderation: None,
+        };
+        let backend = NetworkBackendV0::Virtio(VirtioNetworkBackend {
+            vnic_name: "vnic0".to_string(),
+            vlan_id: None,
+        });
+        (device, backend)
+    }
+
+    #[tokio::test]
+    async fn nic_hotplug_requests_reach_controller() {
+        let mut test_objects = make_default_mocks();
+        test_objects
+            .vm_ctrl
+            .expect_hotplug_attach_nic()
+            .withf(|name, device, _| {
+                name == "net0" && device.backend_name == "net0-backend"
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        test_objects
+            .vm_ctrl
+            .expect_hotplug_detach_nic()
+            .withf(|name| name == "net0")
+            .times(1)
+            .returning(|_| Ok(()));
+        let mut driver = make_state_driver(test_objects);
+
+        let (device, backend) = hotplug_nic();
+        let (result_tx, mut result_rx) = tokio::sync::oneshot::channel();
+        let outcome = driver.driver.handle_event(StateDriverEvent::External(
+            ExternalRequest::AttachNic {
+                name: "net0".to_string(),
+                device,
+                backend,
+                result_tx,
+            },
+        ));
+        assert_eq!(outcome, HandleEventOutcome::Continue);
+        assert!(result_rx.try_recv().unwrap().is_ok());
+
+        let (result_tx, mut result_rx) = tokio::sync::oneshot::channel();
+        let outcome = driver.driver.handle_event(StateDriverEvent::External(
+            ExternalRequest::DetachNic { name: "net0".to_string(), result_tx },
+        ));
+        assert_eq!(outcome, HandleEventOutcome::Continue);
+        assert!(result_rx.try_recv().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_nic_hotplug_reported_to_requester() {
+        let mut test_objects = make_default_mocks();
+        test_objects.vm_ctrl.expect_hotplug_attach_nic().times(1).returning(
+            |_, _, _| {
+                Err(VmControllerError::HotplugFailed("no free slot".into()))
+            },
+        );
+        test_objects.vm_ctrl.expect_hotplug_detach_nic().times(1).returning(
+            |name| {
+                Err(VmControllerError::NoSuchNetworkDevice(name.to_string()))
+            },
+        );
+        let mut driver = make_state_driver(test_objects);
+
+        let (device, backend) = hotplug_nic();
+        let (result_tx, mut result_rx) = tokio::sync::oneshot::channel();
+        let outcome = driver.driver.handle_event(StateDriverEvent::External(
+            ExternalRequest::AttachNic {
+                name: "net0".to_string(),
+                device,
+                backend,
+                result_tx,
+            },
+        ));
+        assert_eq!(outcome, HandleEventOutcome::Continue);
+        assert!(matches!(
+            result_rx.try_recv().unwrap(),
+            Err(VmControllerError::HotplugFailed(_))
+        ));
+
+        let (result_tx, mut result_rx) = tokio::sync::oneshot::channel();
+        let outcome = driver.driver.handle_event(StateDriverEvent::External(
+            ExternalRequest::DetachNic { name: "net1".to_string(), result_tx },
+        ));
+        assert_eq!(outcome, HandleEventOutcome::Continue);
+        assert!(matches!(
+            result_rx.try_recv().unwrap(),
+            Err(VmControllerError::NoSuchNetworkDevice(name)) if name == "net1"
+        ));
+    }
 }
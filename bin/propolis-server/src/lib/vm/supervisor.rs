@@ -0,0 +1,203 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Supervision of a running guest, to detect that it has hung.
+//!
+//! While the instance runs, the supervisor looks for signs of life from the
+//! guest: new output on any of its serial ports or, failing that, an answer
+//! to a ping sent to its guest agent, if one is configured.  If the guest
+//! shows no sign of life for the period set by the instance's supervision
+//! policy, the hang is recorded in the instance's history and, if the policy
+//! says so, the instance is reset through the state driver just as if a
+//! reboot had been requested.  Time during which the instance isn't running
+//! (e.g. while it's paused or migrating) doesn't count toward the period, and
+//! a guest which has been found hung is given another full period to recover
+//! or to boot again before it's considered hung anew.
+//!
+//! Without a guest agent, a guest which is healthy but writes nothing to its
+//! serial ports can't be told apart from a hung one, so a policy which resets
+//! the instance is only accepted for instances with an agent.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use propolis_api_types::{
+    HangAction, InstanceEventKind, InstanceState, InstanceStateRequested,
+    SupervisionPolicy,
+};
+use slog::{error, warn, Logger};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use super::VmController;
+
+/// The shortest interval at which the guest is checked for signs of life.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks when the guest last showed a sign of life.
+struct Liveness {
+    timeout: Duration,
+    last_alive: Instant,
+    bytes_output: usize,
+}
+
+impl Liveness {
+    fn new(timeout: Duration, now: Instant) -> Self {
+        Self { timeout, last_alive: now, bytes_output: 0 }
+    }
+
+    /// Starts a new period in which the guest must show a sign of life.
+    fn restart(&mut self, now: Instant) {
+        self.last_alive = now;
+    }
+
+    /// Notes the number of bytes the guest has written to its serial ports,
+    /// returning whether it has written any since this was last called.
+    fn saw_output(&mut self, bytes_output: usize, now: Instant) -> bool {
+        if bytes_output == self.bytes_output {
+            return false;
+        }
+        self.bytes_output = bytes_output;
+        self.restart(now);
+        true
+    }
+
+    /// Returns whether the guest has shown no sign of life for a full period.
+    fn hung(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_alive) >= self.timeout
+    }
+}
+
+/// Checks that `policy` can be followed for an instance which does, or
+/// doesn't, have a guest agent, returning the reason if it can't.
+pub(crate) fn check_policy(
+    policy: &SupervisionPolicy,
+    has_agent: bool,
+) -> Result<(), String> {
+    if policy.action == HangAction::Reset && !has_agent {
+        return Err("a supervision policy which resets the instance requires \
+                    a guest agent"
+            .to_string());
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes the guest has written to all of its serial
+/// ports.
+async fn serial_bytes_output(vm: &VmController) -> usize {
+    let mut total = 0;
+    for serial in vm.serial_ports().values() {
+        total += serial.bytes_output().await;
+    }
+    total
+}
+
+/// Spawns a task which watches the instance's guest for hangs, as directed by
+/// `policy`, until the instance is destroyed.
+pub(crate) fn spawn(
+    vm: Arc<VmController>,
+    policy: SupervisionPolicy,
+    log: Logger,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let timeout = Duration::from_secs(policy.hang_timeout_secs.max(1));
+        let interval = (timeout / 4).max(MIN_CHECK_INTERVAL);
+        let mut state_rx = vm.state_watcher().clone();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut liveness = Liveness::new(timeout, Instant::now());
+        let mut running =
+            state_rx.borrow_and_update().state == InstanceState::Running;
+
+        loop {
+            tokio::select! {
+                changed = state_rx.changed() => {
+                    let state = match changed {
+                        Ok(()) => state_rx.borrow_and_update().state,
+                        Err(_) => InstanceState::Destroyed,
+                    };
+                    if state == InstanceState::Destroyed {
+                        break;
+                    }
+                    let now_running = state == InstanceState::Running;
+                    if now_running && !running {
+                        liveness.restart(Instant::now());
+                    }
+                    running = now_running;
+                }
+                _ = ticker.tick() => {
+                    if !running {
+                        continue;
+                    }
+                    let bytes_output = serial_bytes_output(&vm).await;
+                    if liveness.saw_output(bytes_output, Instant::now()) {
+                        continue;
+                    }
+                    if let Some(agent) = vm.guest_agent() {
+                        let ping = tokio::time::timeout(interval, agent.ping());
+                        if let Ok(Ok(())) = ping.await {
+                            liveness.restart(Instant::now());
+                            continue;
+                        }
+                    }
+                    if !liveness.hung(Instant::now()) {
+                        continue;
+                    }
+
+                    warn!(log, "guest appears to be hung";
+                          "timeout" => ?timeout,
+                          "action" => ?policy.action);
+                    vm.worker_state.history.record(InstanceEventKind::Guest {
+                        event: format!(
+                            "hung: no sign of life for {}s",
+                            timeout.as_secs()
+                        ),
+                    });
+                    if policy.action == HangAction::Reset {
+                        if let Err(e) =
+                            vm.put_state(InstanceStateRequested::Reboot)
+                        {
+                            error!(log, "failed to reset hung guest";
+                                   "error" => %e);
+                        }
+                    }
+                    liveness.restart(Instant::now());
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guest_is_hung_after_a_period_without_output() {
+        let timeout = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut liveness = Liveness::new(timeout, start);
+
+        assert!(!liveness.hung(start + Duration::from_secs(9)));
+        assert!(liveness.saw_output(100, start + Duration::from_secs(9)));
+        assert!(!liveness.hung(start + Duration::from_secs(18)));
+
+        // Seeing the same amount of output again isn't a sign of life.
+        assert!(!liveness.saw_output(100, start + Duration::from_secs(18)));
+        assert!(liveness.hung(start + Duration::from_secs(19)));
+
+        liveness.restart(start + Duration::from_secs(19));
+        assert!(!liveness.hung(start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn reset_policy_requires_guest_agent() {
+        let policy =
+            |action| SupervisionPolicy { hang_timeout_secs: 60, action };
+
+        assert!(check_policy(&policy(HangAction::Reset), false).is_err());
+        assert!(check_policy(&policy(HangAction::Reset), true).is_ok());
+        assert!(check_policy(&policy(HangAction::Report), false).is_ok());
+    }
+}
@@ -6,16 +6,21 @@ use async_trait::async_trait;
 use propolis::common::GuestAddr;
 use propolis::hw::ps2::ctrl::PS2Ctrl;
 use propolis::hw::qemu::ramfb::{Config, FramebufferSpec};
-use rfb::encodings::RawEncoding;
-use rfb::pixel_formats::fourcc;
+use propolis::hw::virtio::input::{PointerButtons, TABLET_ABS_MAX};
+use rfb::encodings::{Encoding, EncodingType, RawEncoding};
+use rfb::pixel_formats::{fourcc, PixelFormat};
 use rfb::rfb::{
-    FramebufferUpdate, KeyEvent, ProtoVersion, Rectangle, SecurityType,
-    SecurityTypes,
+    FramebufferUpdate, KeyEvent, PointerEvent, ProtoVersion, Rectangle,
+    SecurityType, SecurityTypes,
 };
 use rfb::server::{Server, VncServer, VncServerConfig, VncServerData};
-use slog::{debug, error, info, o, trace, Logger};
+use slog::{debug, error, info, o, trace, warn, Logger};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
 use tokio::sync::Mutex;
 
 use crate::vm::VmController;
@@ -52,8 +57,117 @@ enum Framebuffer {
     Initialized(RamFb),
 }
 
+impl Framebuffer {
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Framebuffer::Uninitialized(fb) => {
+                (u32::from(fb.width), u32::from(fb.height))
+            }
+            Framebuffer::Initialized(fb) => (fb.width, fb.height),
+        }
+    }
+}
+
+/// The DesktopSize pseudo-encoding, whose (empty) rectangle tells a client
+/// that the desktop has been resized to the rectangle's dimensions.
+struct DesktopSizeEncoding {
+    data: Vec<u8>,
+}
+
+impl DesktopSizeEncoding {
+    fn rectangle(width: u16, height: u16) -> Rectangle {
+        Rectangle::new(
+            0,
+            0,
+            width,
+            height,
+            Box::new(DesktopSizeEncoding { data: Vec::new() }),
+        )
+    }
+}
+
+impl Encoding for DesktopSizeEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::DesktopSizePseudo
+    }
+
+    fn encode(&self) -> &Vec<u8> {
+        &self.data
+    }
+
+    fn transform(
+        &self,
+        _input: &PixelFormat,
+        _output: &PixelFormat,
+    ) -> Box<dyn Encoding> {
+        Box::new(DesktopSizeEncoding { data: Vec::new() })
+    }
+}
+
+/// Scales a coordinate within a display `size` pixels across to the range
+/// reported by the guest's tablet.
+fn scale_to_tablet(pos: u16, size: u32) -> u32 {
+    let max = u64::from(size.saturating_sub(1).max(1));
+    let pos = u64::from(pos).min(max);
+    (pos * u64::from(TABLET_ABS_MAX) / max) as u32
+}
+
+/// Largest amount of cut text passed on to the guest
+const MAX_CLIPBOARD_LEN: usize = 1024 * 1024;
+
+/// Time allowed for the guest to accept cut text before the connection to its
+/// clipboard port is abandoned
+const CLIPBOARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The channel to the guest through which text cut by clients is passed: a
+/// virtio-console port, reached through its socket on the host.
+///
+/// Each piece of cut text is sent as its length in bytes, as a little-endian
+/// `u32`, followed by the text itself in UTF-8.
+struct Clipboard {
+    socket_path: PathBuf,
+    conn: Option<UnixStream>,
+}
+
+impl Clipboard {
+    async fn send(&mut self, text: &str) -> std::io::Result<()> {
+        let mut msg = Vec::with_capacity(4 + text.len());
+        msg.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        msg.extend_from_slice(text.as_bytes());
+
+        // A connection left over from an earlier paste may since have been
+        // closed, by a reset of the device for instance, so a failed write is
+        // retried once on a fresh connection.
+        if let Some(conn) = self.conn.as_mut() {
+            if Self::write(conn, &msg).await.is_ok() {
+                return Ok(());
+            }
+        }
+        self.conn = None;
+        let mut conn = UnixStream::connect(&self.socket_path).await?;
+        Self::write(&mut conn, &msg).await?;
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    async fn write(conn: &mut UnixStream, msg: &[u8]) -> std::io::Result<()> {
+        tokio::time::timeout(CLIPBOARD_TIMEOUT, conn.write_all(msg))
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "guest did not accept cut text",
+                ))
+            })
+    }
+}
+
 struct PropolisVncServerInner {
     framebuffer: Framebuffer,
+    /// The desktop size last reported to clients, if known.  When the
+    /// framebuffer's dimensions no longer match it, the next update carries a
+    /// DesktopSize rectangle to resize the client.
+    reported_size: Option<(u16, u16)>,
     ps2ctrl: Option<Arc<PS2Ctrl>>,
     vm: Option<Arc<VmController>>,
 }
@@ -61,6 +175,9 @@ struct PropolisVncServerInner {
 #[derive(Clone)]
 pub struct PropolisVncServer {
     inner: Arc<Mutex<PropolisVncServerInner>>,
+    /// Kept apart from the rest of the state so that a guest slow to accept
+    /// cut text does not hold up input.
+    clipboard: Arc<Mutex<Option<Clipboard>>>,
     log: Logger,
 }
 
@@ -72,9 +189,11 @@ impl PropolisVncServer {
                     width: initial_width,
                     height: initial_height,
                 }),
+                reported_size: Some((initial_width, initial_height)),
                 ps2ctrl: None,
                 vm: None,
             })),
+            clipboard: Arc::new(Mutex::new(None)),
             log,
         }
     }
@@ -84,9 +203,16 @@ impl PropolisVncServer {
         fb: RamFb,
         ps2ctrl: Arc<PS2Ctrl>,
         vm: Arc<VmController>,
+        clipboard_socket: Option<PathBuf>,
     ) {
+        *self.clipboard.lock().await = clipboard_socket
+            .map(|socket_path| Clipboard { socket_path, conn: None });
+
         let mut inner = self.inner.lock().await;
-        inner.framebuffer = Framebuffer::Initialized(fb);
+        // A framebuffer the guest has yet to place has no meaningful size.
+        if fb.addr != 0 {
+            inner.framebuffer = Framebuffer::Initialized(fb);
+        }
         inner.ps2ctrl = Some(ps2ctrl);
         inner.vm = Some(vm);
     }
@@ -107,6 +233,12 @@ impl PropolisVncServer {
 
             if fb.addr != 0 {
                 inner.framebuffer = Framebuffer::Initialized(fb);
+
+                // Clients connecting from now on should be told of the new
+                // size from the outset.
+                rfb_server
+                    .set_resolution(fb.width as u16, fb.height as u16)
+                    .await;
             }
 
             match fourcc::fourcc_to_pixel_format(fb.fourcc) {
@@ -130,9 +262,19 @@ impl PropolisVncServer {
 #[async_trait]
 impl Server for PropolisVncServer {
     async fn get_framebuffer_update(&self) -> FramebufferUpdate {
-        let inner = self.inner.lock().await;
+        let mut inner = self.inner.lock().await;
+
+        let (width, height) = inner.framebuffer.dimensions();
+        let size = (width as u16, height as u16);
+        let resize = if inner.reported_size != Some(size) {
+            info!(self.log, "resizing desktop to {}x{}", width, height);
+            inner.reported_size = Some(size);
+            Some(DesktopSizeEncoding::rectangle(size.0, size.1))
+        } else {
+            None
+        };
 
-        match &inner.framebuffer {
+        let mut update = match &inner.framebuffer {
             Framebuffer::Uninitialized(fb) => {
                 debug!(self.log, "framebuffer: uninitialized");
 
@@ -147,7 +289,7 @@ impl Server for PropolisVncServer {
                     fb.height,
                     Box::new(RawEncoding::new(pixels)),
                 );
-                FramebufferUpdate::new(vec![r])
+                vec![r]
             }
             Framebuffer::Initialized(fb) => {
                 debug!(self.log, "framebuffer initialized: fb={:?}", fb);
@@ -171,9 +313,15 @@ impl Server for PropolisVncServer {
                     fb.height as u16,
                     Box::new(RawEncoding::new(buf)),
                 );
-                FramebufferUpdate::new(vec![r])
+                vec![r]
             }
+        };
+
+        // The resize must precede any pixel data at the new size.
+        if let Some(r) = resize {
+            update.insert(0, r);
         }
+        FramebufferUpdate::new(update)
     }
 
     async fn key_event(&self, ke: KeyEvent) {
@@ -188,14 +336,55 @@ impl Server for PropolisVncServer {
         }
     }
 
+    async fn pointer_event(&self, pe: PointerEvent) {
+        let inner = self.inner.lock().await;
+        let tablet = inner.vm.as_ref().and_then(|vm| vm.tablet());
+
+        // The guest's PS/2 mouse reports only relative motion, which cannot be
+        // kept in step with the client's pointer, so pointer input is passed
+        // on only to a tablet.
+        if let Some(tablet) = tablet {
+            trace!(self.log, "pointerevent: {:?}", pe);
+            let (width, height) = inner.framebuffer.dimensions();
+            tablet.pointer_event(
+                scale_to_tablet(pe.position.x, width),
+                scale_to_tablet(pe.position.y, height),
+                PointerButtons::from_bits_truncate(pe.pressed.bits()),
+            );
+        } else {
+            trace!(self.log, "no tablet; dropping pointerevent");
+        }
+    }
+
+    async fn cut_text(&self, text: String) {
+        let mut clipboard = self.clipboard.lock().await;
+        let Some(clipboard) = clipboard.as_mut() else {
+            trace!(self.log, "no clipboard port; dropping cut text");
+            return;
+        };
+        if text.len() > MAX_CLIPBOARD_LEN {
+            warn!(self.log, "dropping oversized cut text"; "len" => text.len());
+            return;
+        }
+        if let Err(e) = clipboard.send(&text).await {
+            warn!(self.log, "failed to pass cut text to guest";
+                "socket" => %clipboard.socket_path.display(),
+                "error" => %e);
+        }
+    }
+
     async fn stop(&self) {
         info!(self.log, "stopping VNC server");
 
+        *self.clipboard.lock().await = None;
+
         let mut inner = self.inner.lock().await;
         inner.framebuffer = Framebuffer::Uninitialized(DefaultFb {
             width: INITIAL_WIDTH,
             height: INITIAL_HEIGHT,
         });
+        // The size clients were last told of is no longer known to match.
+        inner.reported_size = None;
         inner.ps2ctrl = None;
         inner.vm = None;
     }
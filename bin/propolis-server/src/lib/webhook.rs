@@ -0,0 +1,366 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Notifications of changes to the instance, POSTed to URLs registered
+//! through the API.
+//!
+//! Each notification is a JSON object with a unique `id`, the `instance_id`,
+//! the `time` at which the change was seen, and an `event`, tagged by its
+//! `type`: `state_changed` when the instance's state changes,
+//! `migration_finished` when a migration into or out of the instance
+//! succeeds or fails, and `guest_panic` when the guest reports a kernel panic
+//! through its pvpanic device.  Deliveries which fail are retried with
+//! exponential backoff, a few times, after which the notification is dropped.
+//! Notifications are delivered to each URL independently, and so are not
+//! guaranteed to arrive in order.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use propolis::hw::qemu::pvpanic::{PanicCounts, QemuPvpanic};
+use propolis_api_types::{
+    InstanceState, InstanceStateMonitorResponse, MigrationState, Webhook,
+    WebhookRegistration,
+};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// The header carrying a notification's signature.
+const SIGNATURE_HEADER: &str = "X-Propolis-Signature";
+
+/// The number of attempts made to deliver each notification.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry of a delivery, doubled for each one after.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long to wait for a response to each delivery attempt.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check for guest panics.
+const PANIC_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WebhookEvent {
+    StateChanged { state: InstanceState },
+    MigrationFinished { migration_id: Uuid, state: MigrationState },
+    GuestPanic { host_handled: usize, guest_handled: usize },
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    id: Uuid,
+    instance_id: Uuid,
+    time: DateTime<Utc>,
+    event: &'a WebhookEvent,
+}
+
+/// Returns the value of the signature header for a notification with `body`.
+fn signature(secret: &str, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", hex::encode(ring::hmac::sign(&key, body)))
+}
+
+/// The URLs registered to be notified of changes to the instance.
+pub(crate) struct WebhookRegistry {
+    hooks: Mutex<BTreeMap<Uuid, WebhookRegistration>>,
+    client: reqwest::Client,
+}
+
+impl WebhookRegistry {
+    pub fn new() -> Self {
+        Self {
+            hooks: Mutex::new(BTreeMap::new()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Registers a URL to be notified, or returns an error if the URL isn't a
+    /// valid `http` or `https` URL.
+    pub fn register(
+        &self,
+        registration: WebhookRegistration,
+    ) -> Result<Webhook, String> {
+        let url = reqwest::Url::parse(&registration.url)
+            .map_err(|e| format!("invalid webhook URL: {e}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!(
+                "webhook URL has unsupported scheme {:?}",
+                url.scheme()
+            ));
+        }
+
+        let id = Uuid::new_v4();
+        let hook = Webhook {
+            id,
+            url: registration.url.clone(),
+            signed: registration.secret.is_some(),
+        };
+        self.hooks.lock().unwrap().insert(id, registration);
+        Ok(hook)
+    }
+
+    /// Unregisters the URL with the given ID, returning `false` if there was
+    /// none.
+    pub fn unregister(&self, id: Uuid) -> bool {
+        self.hooks.lock().unwrap().remove(&id).is_some()
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.hooks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, hook)| Webhook {
+                id: *id,
+                url: hook.url.clone(),
+                signed: hook.secret.is_some(),
+            })
+            .collect()
+    }
+
+    /// Sends a notification of `event` to each registered URL.
+    fn notify(&self, instance_id: Uuid, event: &WebhookEvent, log: &Logger) {
+        let notification = Notification {
+            id: Uuid::new_v4(),
+            instance_id,
+            time: Utc::now(),
+            event,
+        };
+        let body = serde_json::to_vec(&notification)
+            .expect("notifications can be serialized");
+        info!(log, "sending webhook notifications";
+              "notification" => %notification.id,
+              "event" => ?event);
+
+        for (id, hook) in self.hooks.lock().unwrap().iter() {
+            let mut request = self
+                .client
+                .post(&hook.url)
+                .timeout(DELIVERY_TIMEOUT)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &hook.secret {
+                request =
+                    request.header(SIGNATURE_HEADER, signature(secret, &body));
+            }
+            let log = log.new(slog::o!(
+                "webhook" => id.to_string(),
+                "notification" => notification.id.to_string(),
+            ));
+            tokio::spawn(deliver(request, log));
+        }
+    }
+}
+
+/// Sends a notification, retrying if it isn't accepted.
+async fn deliver(request: reqwest::RequestBuilder, log: Logger) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let request =
+            request.try_clone().expect("notification bodies are not streams");
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => format!("status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+
+        warn!(log, "webhook delivery failed";
+              "attempt" => attempt, "error" => error);
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    warn!(log, "giving up on webhook delivery");
+}
+
+/// Tracks what has been reported of an instance, to find the events to report
+/// as it changes.
+#[derive(Default)]
+struct Observer {
+    state: Option<InstanceState>,
+    migration: Option<(Uuid, MigrationState)>,
+    panics: Option<(usize, usize)>,
+}
+
+impl Observer {
+    fn observe_state(
+        &mut self,
+        current: &InstanceStateMonitorResponse,
+    ) -> Vec<WebhookEvent> {
+        let mut events = vec![];
+        if self.state != Some(current.state) {
+            self.state = Some(current.state);
+            events.push(WebhookEvent::StateChanged { state: current.state });
+        }
+
+        if let Some(migration) = &current.migration {
+            let finished = matches!(
+                migration.state,
+                MigrationState::Finish | MigrationState::Error
+            );
+            let seen = Some((migration.migration_id, migration.state));
+            if finished && self.migration != seen {
+                events.push(WebhookEvent::MigrationFinished {
+                    migration_id: migration.migration_id,
+                    state: migration.state,
+                });
+            }
+            self.migration = seen;
+        }
+        events
+    }
+
+    fn observe_panics(&mut self, counts: PanicCounts) -> Option<WebhookEvent> {
+        let current = (counts.host_handled, counts.guest_handled);
+        let last = self.panics.replace(current);
+
+        // Panics reported before the first observation were not seen to
+        // happen, and an instance that has just migrated in starts counting
+        // again.
+        match last {
+            Some(last) if current.0 > last.0 || current.1 > last.1 => {
+                Some(WebhookEvent::GuestPanic {
+                    host_handled: current.0,
+                    guest_handled: current.1,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Spawns a task which notifies `registry`'s URLs of changes to the instance
+/// with the given ID until the instance is destroyed.
+pub(crate) fn spawn_notifier(
+    registry: Arc<WebhookRegistry>,
+    instance_id: Uuid,
+    mut state_rx: watch::Receiver<InstanceStateMonitorResponse>,
+    pvpanic: Option<Arc<QemuPvpanic>>,
+    log: Logger,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut observer = Observer::default();
+
+        // Only changes made after the instance was created are reported.
+        observer.observe_state(&state_rx.borrow_and_update());
+        if let Some(pvpanic) = &pvpanic {
+            observer.observe_panics(pvpanic.panic_counts());
+        }
+
+        let mut ticker = tokio::time::interval(PANIC_POLL_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                changed = state_rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let current = state_rx.borrow_and_update().clone();
+                    for event in observer.observe_state(&current) {
+                        registry.notify(instance_id, &event, &log);
+                    }
+                    if current.state == InstanceState::Destroyed {
+                        break;
+                    }
+                }
+                _ = ticker.tick(), if pvpanic.is_some() => {
+                    let counts = pvpanic.as_ref().unwrap().panic_counts();
+                    if let Some(event) = observer.observe_panics(counts) {
+                        registry.notify(instance_id, &event, &log);
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use propolis_api_types::InstanceMigrateStatusResponse;
+
+    fn monitor(
+        state: InstanceState,
+        migration: Option<(Uuid, MigrationState)>,
+    ) -> InstanceStateMonitorResponse {
+        InstanceStateMonitorResponse {
+            gen: 0,
+            state,
+            migration: migration.map(|(migration_id, state)| {
+                InstanceMigrateStatusResponse {
+                    migration_id,
+                    state,
+                    failure: None,
+                }
+            }),
+        }
+    }
+
+    #[test]
+    fn signatures_match_hmac_sha256() {
+        // From RFC 4231, test case 2.
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c7\
+             5a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn state_changes_and_finished_migrations_are_reported_once() {
+        let mut observer = Observer::default();
+        let id = Uuid::new_v4();
+        observer.observe_state(&monitor(InstanceState::Running, None));
+
+        let migrating = monitor(
+            InstanceState::Migrating,
+            Some((id, MigrationState::RamPush)),
+        );
+        assert_eq!(
+            observer.observe_state(&migrating),
+            [WebhookEvent::StateChanged { state: InstanceState::Migrating }]
+        );
+        assert!(observer.observe_state(&migrating).is_empty());
+
+        let finished =
+            monitor(InstanceState::Stopped, Some((id, MigrationState::Finish)));
+        assert_eq!(
+            observer.observe_state(&finished),
+            [
+                WebhookEvent::StateChanged { state: InstanceState::Stopped },
+                WebhookEvent::MigrationFinished {
+                    migration_id: id,
+                    state: MigrationState::Finish
+                },
+            ]
+        );
+        assert!(observer.observe_state(&finished).is_empty());
+    }
+
+    #[test]
+    fn only_new_panics_are_reported() {
+        let mut observer = Observer::default();
+        let counts = |host_handled, guest_handled| PanicCounts {
+            host_handled,
+            guest_handled,
+        };
+        assert_eq!(observer.observe_panics(counts(1, 0)), None);
+        assert_eq!(observer.observe_panics(counts(1, 0)), None);
+        assert_eq!(
+            observer.observe_panics(counts(1, 1)),
+            Some(WebhookEvent::GuestPanic {
+                host_handled: 1,
+                guest_handled: 1
+            })
+        );
+    }
+}
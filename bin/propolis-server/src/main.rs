@@ -4,7 +4,7 @@
 
 use anyhow::{anyhow, Context};
 use clap::Parser;
-use dropshot::{ConfigDropshot, HandlerTaskMode, HttpServerStarter};
+use dropshot::{ConfigDropshot, ConfigTls, HandlerTaskMode, HttpServerStarter};
 use futures::join;
 use propolis::usdt::register_probes;
 use slog::info;
@@ -13,8 +13,9 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use propolis_server::{
+    auth::Authorizer,
     config,
-    server::{self, MetricsEndpointConfig},
+    server::{self, MetricsEndpointConfig, MigrationPayloadDirs},
     vnc::setup_vnc,
 };
 
@@ -42,6 +43,17 @@ enum Args {
             action
         )]
         vnc_addr: SocketAddr,
+
+        /// Directory to which to write the device state sent in migrations
+        /// out of the instance, as canned payloads for compatibility testing
+        #[clap(long, action)]
+        emit_migration_payloads: Option<PathBuf>,
+
+        /// Directory of canned payloads, as written with
+        /// --emit-migration-payloads, to import in place of the device state
+        /// sent in migrations into the instance, for compatibility testing
+        #[clap(long, action)]
+        import_migration_payloads: Option<PathBuf>,
     },
 }
 
@@ -62,6 +74,7 @@ async fn run_server(
     config_dropshot: dropshot::ConfigDropshot,
     metrics_addr: Option<SocketAddr>,
     vnc_addr: SocketAddr,
+    migration_payloads: MigrationPayloadDirs,
     log: slog::Logger,
 ) -> anyhow::Result<()> {
     use propolis::api_version;
@@ -77,6 +90,13 @@ async fn run_server(
         Err(e).context("API version checks")?;
     }
 
+    let auth = Authorizer::new(config_app.auth.as_ref())
+        .context("failed to read API tokens")?;
+    let config_tls = config_app.tls.as_ref().map(|tls| ConfigTls::AsFile {
+        cert_file: tls.cert.clone(),
+        key_file: tls.key.clone(),
+    });
+
     let vnc_server = setup_vnc(&log, vnc_addr);
     let vnc_server_hdl = vnc_server.clone();
     let use_reservoir = config::reservoir_decide(&log);
@@ -94,20 +114,35 @@ async fn run_server(
         use_reservoir,
         log.new(slog::o!()),
         config_metrics,
+        migration_payloads,
+        auth,
     );
 
     info!(log, "Starting server...");
 
-    let server = HttpServerStarter::new(
+    let context = Arc::new(context);
+    let mut server = HttpServerStarter::new_with_tls(
         &config_dropshot,
         server::api(),
-        Arc::new(context),
+        context.clone(),
         &log,
+        config_tls,
     )
     .map_err(|error| anyhow!("Failed to start server: {}", error))?
     .start();
 
-    let server_res = join!(server, vnc_server_hdl.start()).0;
+    let serve = async { join!(&mut server, vnc_server_hdl.start()).0 };
+    let server_res = tokio::select! {
+        res = serve => res,
+        () = context.shutdown_requested() => {
+            // Let the server finish responding to the request to shut down
+            // before its instances are stopped.
+            let res = server.close().await;
+            context.stop().await;
+            info!(log, "Server shut down");
+            res
+        }
+    };
 
     server_res.map_err(|e| anyhow!("Server exited with an error: {}", e))
 }
@@ -158,7 +193,14 @@ async fn main() -> anyhow::Result<()> {
     match args {
         Args::OpenApi => run_openapi()
             .map_err(|e| anyhow!("Cannot generate OpenAPI spec: {}", e)),
-        Args::Run { cfg, propolis_addr, metric_addr, vnc_addr } => {
+        Args::Run {
+            cfg,
+            propolis_addr,
+            metric_addr,
+            vnc_addr,
+            emit_migration_payloads,
+            import_migration_payloads,
+        } => {
             let config = config::parse(&cfg)?;
 
             // Dropshot configuration.
@@ -169,9 +211,20 @@ async fn main() -> anyhow::Result<()> {
             };
 
             let log = build_logger();
+            let migration_payloads = MigrationPayloadDirs {
+                emit: emit_migration_payloads,
+                import: import_migration_payloads,
+            };
 
-            run_server(config, config_dropshot, metric_addr, vnc_addr, log)
-                .await
+            run_server(
+                config,
+                config_dropshot,
+                metric_addr,
+                vnc_addr,
+                migration_payloads,
+                log,
+            )
+            .await
         }
     }
 }
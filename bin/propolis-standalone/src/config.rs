@@ -3,8 +3,10 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
 use std::num::NonZeroUsize;
 use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -15,6 +17,7 @@ use cpuid_profile_config::*;
 use propolis::block;
 use propolis::cpuid;
 use propolis::hw::pci::Bdf;
+use propolis::net;
 
 use crate::cidata::build_cidata_be;
 
@@ -78,6 +81,8 @@ pub struct BlockOpts {
     pub block_size: Option<u32>,
     pub read_only: Option<bool>,
     pub skip_flush: Option<bool>,
+    pub read_cache_size: Option<usize>,
+    pub lazy_attach: Option<bool>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -109,6 +114,9 @@ pub struct CloudInit {
 struct FileConfig {
     path: String,
     workers: Option<usize>,
+    /// Path to a file holding the raw 64-byte AES-256-XTS key with which the
+    /// contents of the backing file are encrypted.
+    encryption_key_file: Option<String>,
 }
 #[derive(Deserialize)]
 struct MemAsyncConfig {
@@ -116,6 +124,36 @@ struct MemAsyncConfig {
     workers: Option<usize>,
 }
 
+#[derive(Deserialize)]
+struct NetConfig {
+    dlpi: Option<String>,
+    socket: Option<String>,
+    peer: Option<String>,
+    tx_bytes_per_sec: Option<u64>,
+    tx_burst_bytes: Option<u64>,
+    rx_bytes_per_sec: Option<u64>,
+    rx_burst_bytes: Option<u64>,
+    dhcp: Option<bool>,
+    dhcp_guest_addr: Option<Ipv4Addr>,
+    dhcp_server_addr: Option<Ipv4Addr>,
+    dhcp_prefix_len: Option<u8>,
+    dns: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct OffloadConfig {
+    tx_checksum: Option<bool>,
+    tso: Option<bool>,
+    rx_checksum: Option<bool>,
+    lro: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ModerationConfig {
+    intr_max_packets: Option<u32>,
+    intr_max_usecs: Option<u32>,
+}
+
 // Try to turn unmatched flattened options into a config struct
 fn opt_deser<'de, T: Deserialize<'de>>(
     value: &BTreeMap<String, toml::Value>,
@@ -138,6 +176,8 @@ pub fn block_backend(
         block_size: be.block_opts.block_size,
         read_only: be.block_opts.read_only,
         skip_flush: be.block_opts.skip_flush,
+        read_cache_size: be.block_opts.read_cache_size,
+        lazy_attach: be.block_opts.lazy_attach,
     };
 
     let be = match &be.bdtype as &str {
@@ -152,15 +192,27 @@ pub fn block_backend(
                     "path" => &parsed.path);
             }
 
-            block::FileBackend::create(
-                &parsed.path,
-                opts,
-                NonZeroUsize::new(
-                    parsed.workers.unwrap_or(DEFAULT_WORKER_COUNT),
-                )
-                .unwrap(),
+            let workers = NonZeroUsize::new(
+                parsed.workers.unwrap_or(DEFAULT_WORKER_COUNT),
             )
-            .unwrap()
+            .unwrap();
+            match parsed.encryption_key_file {
+                Some(key_path) => {
+                    let key = std::fs::read(&key_path)
+                        .expect("encryption key file is readable");
+                    let key = block::XtsKey::try_from(key.as_slice())
+                        .expect("encryption key file holds a valid key");
+                    block::FileBackend::create_encrypted(
+                        &parsed.path,
+                        opts,
+                        workers,
+                        &key,
+                    )
+                    .unwrap()
+                }
+                None => block::FileBackend::create(&parsed.path, opts, workers)
+                    .unwrap(),
+            }
         }
         "crucible" => create_crucible_backend(be, opts, log),
         "crucible-mem" => create_crucible_mem_backend(be, opts, log),
@@ -239,6 +291,179 @@ pub fn parse_cpuid(config: &Config) -> anyhow::Result<Option<cpuid::Set>> {
     }
 }
 
+/// Create the backend for a NIC whose datapath is in userspace (unlike viona),
+/// from the options of its device entry: either `dlpi` naming a datalink, or
+/// the `socket` and `peer` paths of a pair of unix datagram sockets.
+///
+/// With `dhcp` set, DHCP and DNS services are answered for the guest from
+/// within the backend, in which case neither `dlpi` nor `socket` is required:
+/// the guest is then left isolated, with nothing beyond those services.
+///
+/// The NIC's traffic in each direction is limited to `{tx,rx}_bytes_per_sec`,
+/// in bursts of up to `{tx,rx}_burst_bytes` (by default, a second's worth),
+/// where those options are present.
+pub fn net_backend(dev: &Device) -> anyhow::Result<Arc<dyn net::Backend>> {
+    let parsed: NetConfig = opt_deser(&dev.options)?;
+    let limits = net::RateLimits {
+        tx: rate_limit("tx", parsed.tx_bytes_per_sec, parsed.tx_burst_bytes)?,
+        rx: rate_limit("rx", parsed.rx_bytes_per_sec, parsed.rx_burst_bytes)?,
+    };
+    let services = services_config(&parsed)?;
+    let backend: Option<Arc<dyn net::Backend>> = match parsed {
+        NetConfig { dlpi: Some(link), socket: None, peer: None, .. } => {
+            Some(dlpi_backend(&link)?)
+        }
+        NetConfig {
+            dlpi: None,
+            socket: Some(socket),
+            peer: Some(peer),
+            ..
+        } => {
+            let be = net::DgramBackend::connect(
+                Path::new(&socket),
+                Path::new(&peer),
+            )
+            .with_context(|| {
+                format!("failed to bind network socket at {socket}")
+            })?;
+            Some(Arc::new(be))
+        }
+        NetConfig { dlpi: None, socket: None, peer: None, .. } => None,
+        _ => anyhow::bail!(
+            "network backend requires either `dlpi`, or `socket` and `peer`"
+        ),
+    };
+    let backend: Arc<dyn net::Backend> = match (backend, services) {
+        (backend, Some(config)) => Arc::new(
+            net::ServicesBackend::new(backend, config)
+                .context("failed to start DHCP services")?,
+        ),
+        (Some(backend), None) => backend,
+        (None, None) => anyhow::bail!(
+            "network backend requires `dlpi`, `socket` and `peer`, or `dhcp`"
+        ),
+    };
+    if limits == net::RateLimits::default() {
+        return Ok(backend);
+    }
+    Ok(Arc::new(net::RateLimitedBackend::new(backend, limits)))
+}
+
+/// Configuration of the DHCP (and DNS) services for a NIC, if enabled
+fn services_config(
+    parsed: &NetConfig,
+) -> anyhow::Result<Option<net::ServicesConfig>> {
+    if !parsed.dhcp.unwrap_or(false) {
+        if parsed.dhcp_guest_addr.is_some()
+            || parsed.dhcp_server_addr.is_some()
+            || parsed.dhcp_prefix_len.is_some()
+            || parsed.dns.is_some()
+        {
+            anyhow::bail!("`dhcp_*` and `dns` options require `dhcp = true`");
+        }
+        return Ok(None);
+    }
+    let default = net::ServicesConfig::default();
+    Ok(Some(net::ServicesConfig {
+        guest_addr: parsed.dhcp_guest_addr.unwrap_or(default.guest_addr),
+        server_addr: parsed.dhcp_server_addr.unwrap_or(default.server_addr),
+        prefix_len: parsed.dhcp_prefix_len.unwrap_or(default.prefix_len),
+        dns: parsed.dns.unwrap_or(default.dns),
+        ..default
+    }))
+}
+
+fn rate_limit(
+    dir: &str,
+    rate: Option<u64>,
+    burst: Option<u64>,
+) -> anyhow::Result<Option<net::TokenBucket>> {
+    match (rate, burst) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => {
+            anyhow::bail!("{dir}_burst_bytes requires {dir}_bytes_per_sec")
+        }
+        (Some(rate), burst) => {
+            let burst =
+                burst.unwrap_or_else(|| rate.max(net::MAX_FRAME_LEN as u64));
+            let limit = net::TokenBucket::new(rate, burst)
+                .with_context(|| format!("invalid {dir} rate limit"))?;
+            Ok(Some(limit))
+        }
+    }
+}
+
+#[cfg(feature = "dlpi")]
+fn dlpi_backend(link: &str) -> anyhow::Result<Arc<dyn net::Backend>> {
+    let be = net::DlpiBackend::open(link)
+        .with_context(|| format!("failed to open datalink {link}"))?;
+    Ok(Arc::new(be))
+}
+
+#[cfg(not(feature = "dlpi"))]
+fn dlpi_backend(_link: &str) -> anyhow::Result<Arc<dyn net::Backend>> {
+    anyhow::bail!("DLPI network backends require the `dlpi` feature")
+}
+
+/// Offloads to be offered by a viona NIC, any of which may be disabled by
+/// setting its option (`tx_checksum`, `tso`, `rx_checksum`, or `lro`) to false.
+pub fn viona_offloads(
+    dev: &Device,
+) -> anyhow::Result<propolis::hw::virtio::viona::NetOffloads> {
+    let parsed: OffloadConfig = opt_deser(&dev.options)?;
+    let all = propolis::hw::virtio::viona::NetOffloads::default();
+    Ok(propolis::hw::virtio::viona::NetOffloads {
+        tx_checksum: parsed.tx_checksum.unwrap_or(all.tx_checksum),
+        tso: parsed.tso.unwrap_or(all.tso),
+        rx_checksum: parsed.rx_checksum.unwrap_or(all.rx_checksum),
+        lro: parsed.lro.unwrap_or(all.lro),
+    })
+}
+
+/// Interrupt moderation for a virtio device, from its `intr_max_packets` and
+/// `intr_max_usecs` options, which must be given together.
+pub fn intr_moderation(
+    dev: &Device,
+) -> anyhow::Result<Option<propolis::hw::virtio::IntrModeration>> {
+    let parsed: ModerationConfig = opt_deser(&dev.options)?;
+    match (parsed.intr_max_packets, parsed.intr_max_usecs) {
+        (None, None) => Ok(None),
+        (Some(packets), Some(usecs)) => {
+            let moderation =
+                propolis::hw::virtio::IntrModeration::new(packets, usecs)
+                    .context("invalid interrupt moderation")?;
+            Ok(Some(moderation))
+        }
+        _ => anyhow::bail!(
+            "intr_max_packets and intr_max_usecs must be given together"
+        ),
+    }
+}
+
+/// MAC address of the NIC at `bdf`: either as specified by its `mac` option,
+/// or otherwise derived from its location.
+pub fn nic_mac(dev: &Device, bdf: Bdf) -> anyhow::Result<[u8; 6]> {
+    match dev.options.get("mac") {
+        Some(mac) => parse_mac(mac.as_str().context("mac must be a string")?),
+        None => Ok(propolis::hw::virtio::PciVirtioNet::default_mac(bdf)),
+    }
+}
+
+/// Parse a MAC address in the usual colon-separated form.
+fn parse_mac(mac: &str) -> anyhow::Result<[u8; 6]> {
+    let mut addr = [0u8; 6];
+    let mut octets = mac.split(':');
+    for byte in addr.iter_mut() {
+        let octet = octets.next().unwrap_or_default();
+        *byte = u8::from_str_radix(octet, 16)
+            .with_context(|| format!("invalid MAC address {mac}"))?;
+    }
+    if octets.next().is_some() {
+        anyhow::bail!("invalid MAC address {mac}");
+    }
+    Ok(addr)
+}
+
 #[cfg(feature = "crucible")]
 fn create_crucible_backend(
     be: &BlockDevice,
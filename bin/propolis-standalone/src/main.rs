@@ -1119,7 +1119,17 @@ fn setup_instance(
                         config::block_backend(&config, dev, log);
                     let bdf = bdf.unwrap();
 
-                    let vioblk = hw::virtio::PciVirtioBlock::new(0x100);
+                    let num_queues = dev
+                        .options
+                        .get("num_queues")
+                        .and_then(|v| v.as_integer())
+                        .and_then(|n| u16::try_from(n).ok())
+                        .unwrap_or(1);
+                    let vioblk = hw::virtio::PciVirtioBlock::new(
+                        0x100,
+                        num_queues,
+                        config::intr_moderation(dev)?,
+                    );
 
                     guard
                         .inventory
@@ -1134,12 +1144,120 @@ fn setup_instance(
                         dev.options.get("vnic").unwrap().as_str().unwrap();
                     let bdf = bdf.unwrap();
 
+                    let num_queue_pairs = dev
+                        .options
+                        .get("num_queue_pairs")
+                        .and_then(|v| v.as_integer())
+                        .and_then(|n| u16::try_from(n).ok())
+                        .unwrap_or(1);
+                    let vlan_id = dev
+                        .options
+                        .get("vlan")
+                        .map(|v| {
+                            v.as_integer()
+                                .and_then(|n| u16::try_from(n).ok())
+                                .context("vlan must be a VLAN ID")
+                        })
+                        .transpose()?;
                     let viona = hw::virtio::PciVirtioViona::new(
-                        vnic_name, 0x100, &hdl,
+                        vnic_name,
+                        vlan_id,
+                        0x100,
+                        num_queue_pairs,
+                        config::viona_offloads(dev)?,
+                        &hdl,
                     )?;
                     guard.inventory.register_instance(&viona, &bdf.to_string());
                     chipset_pci_attach(bdf, viona);
                 }
+                "pci-virtio-net" => {
+                    let bdf = bdf.unwrap();
+                    let backend = config::net_backend(dev)?;
+                    let mac = config::nic_mac(dev, bdf)?;
+
+                    let vionet = hw::virtio::PciVirtioNet::new(
+                        mac,
+                        0x100,
+                        backend,
+                        config::intr_moderation(dev)?,
+                    );
+                    guard
+                        .inventory
+                        .register_instance(&vionet, &bdf.to_string());
+                    chipset_pci_attach(bdf, vionet);
+                }
+                "pci-e1000" => {
+                    let bdf = bdf.unwrap();
+                    let backend = config::net_backend(dev)?;
+                    let mac = config::nic_mac(dev, bdf)?;
+
+                    let e1000 = hw::e1000::PciE1000::new(mac, backend);
+                    guard.inventory.register_instance(&e1000, &bdf.to_string());
+                    chipset_pci_attach(bdf, e1000);
+                }
+                "pci-ppt" => {
+                    let bdf = bdf.unwrap();
+                    let path = dev
+                        .options
+                        .get("ppt")
+                        .and_then(|v| v.as_str())
+                        .context("pci-ppt requires a ppt device path")?;
+
+                    let ppt =
+                        hw::ppt::PciPpt::create(Path::new(path), hdl.clone())?;
+                    guard.inventory.register_instance(&ppt, &bdf.to_string());
+                    chipset_pci_attach(bdf, ppt);
+                }
+                "pci-virtio-vsock" => {
+                    let bdf = bdf.unwrap();
+                    let guest_cid = dev
+                        .options
+                        .get("guest_cid")
+                        .and_then(|v| v.as_integer())
+                        .and_then(|v| u64::try_from(v).ok())
+                        .context("pci-virtio-vsock requires a guest_cid")?;
+                    let path = dev
+                        .options
+                        .get("socket_path")
+                        .and_then(|v| v.as_str())
+                        .context("pci-virtio-vsock requires a socket_path")?;
+
+                    let vsock = hw::virtio::PciVirtioSocket::new(
+                        guest_cid, 0x100, path,
+                    )?;
+                    guard.inventory.register_instance(&vsock, &bdf.to_string());
+                    chipset_pci_attach(bdf, vsock);
+                }
+                "pci-virtio-console" => {
+                    let bdf = bdf.unwrap();
+                    let entries = dev
+                        .options
+                        .get("ports")
+                        .and_then(|v| v.as_array())
+                        .context("pci-virtio-console requires ports")?;
+                    let mut ports = Vec::with_capacity(entries.len());
+                    for entry in entries {
+                        let name = entry
+                            .get("name")
+                            .and_then(|v| v.as_str())
+                            .context("console port requires a name")?;
+                        let path = entry
+                            .get("socket_path")
+                            .and_then(|v| v.as_str())
+                            .context("console port requires a socket_path")?;
+                        ports.push(hw::virtio::console::ConsolePort {
+                            name: name.to_string(),
+                            uds_path: path.into(),
+                        });
+                    }
+
+                    let console =
+                        hw::virtio::PciVirtioConsole::new(0x100, ports)?;
+                    guard
+                        .inventory
+                        .register_instance(&console, &bdf.to_string());
+                    chipset_pci_attach(bdf, console);
+                }
                 "pci-nvme" => {
                     let (backend, name) =
                         config::block_backend(&config, dev, log);
@@ -1156,7 +1274,32 @@ fn setup_instance(
                         log.new(slog::o!("dev" => format!("nvme-{}", name)));
                     // Limit data transfers to 1MiB (2^8 * 4k) in size
                     let mdts = Some(8);
-                    let nvme = hw::nvme::PciNvme::create(dev_serial, mdts, log);
+                    let num_queues = dev
+                        .options
+                        .get("num_queues")
+                        .and_then(|v| v.as_integer())
+                        .and_then(|n| u16::try_from(n).ok());
+                    let queue_size = dev
+                        .options
+                        .get("queue_size")
+                        .and_then(|v| v.as_integer())
+                        .and_then(|n| u32::try_from(n).ok());
+                    let identity = hw::nvme::CtrlIdentity {
+                        model_number: dev
+                            .options
+                            .get("model_number")
+                            .and_then(|v| v.as_str())
+                            .map(str::to_string),
+                        firmware_revision: dev
+                            .options
+                            .get("firmware_revision")
+                            .and_then(|v| v.as_str())
+                            .map(str::to_string),
+                        ieee_oui: None,
+                    };
+                    let nvme = hw::nvme::PciNvme::create(
+                        dev_serial, identity, mdts, num_queues, queue_size, log,
+                    );
 
                     guard.inventory.register_instance(&nvme, &bdf.to_string());
                     guard.inventory.register_block(&backend, name);
@@ -1164,6 +1307,60 @@ fn setup_instance(
                     block::attach(nvme.clone(), backend).unwrap();
                     chipset_pci_attach(bdf, nvme);
                 }
+                "pci-ahci" | "pci-ahci-cdrom" => {
+                    let (backend, name) =
+                        config::block_backend(&config, dev, log);
+                    let bdf = bdf.unwrap();
+
+                    let dev_serial = dev
+                        .options
+                        .get("block_dev")
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .to_string();
+                    let log =
+                        log.new(slog::o!("dev" => format!("ahci-{}", name)));
+                    let ahci = match driver {
+                        "pci-ahci-cdrom" => {
+                            hw::ahci::PciAhci::create_cdrom(dev_serial, log)
+                        }
+                        _ => hw::ahci::PciAhci::create(dev_serial, log),
+                    };
+
+                    guard.inventory.register_instance(&ahci, &bdf.to_string());
+                    guard.inventory.register_block(&backend, name);
+
+                    block::attach(ahci.clone(), backend).unwrap();
+                    chipset_pci_attach(bdf, ahci);
+                }
+                "pci-piix3-ide" => {
+                    let (backend, name) =
+                        config::block_backend(&config, dev, log);
+                    let bdf = bdf.unwrap();
+
+                    let dev_serial = dev
+                        .options
+                        .get("block_dev")
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .to_string();
+                    let log =
+                        log.new(slog::o!("dev" => format!("ide-{}", name)));
+                    let ide = hw::ide::PiixIde::create(
+                        dev_serial,
+                        chipset_lpc.irq_pin(ibmpc::IRQ_IDE_PRI).unwrap(),
+                        log,
+                    );
+                    ide.attach_legacy(pio);
+
+                    guard.inventory.register_instance(&ide, &bdf.to_string());
+                    guard.inventory.register_block(&backend, name);
+
+                    block::attach(ide.clone(), backend).unwrap();
+                    chipset_pci_attach(bdf, ide);
+                }
                 qemu::pvpanic::DEVICE_NAME => {
                     let enable_isa = dev
                         .options
@@ -1212,6 +1409,12 @@ fn setup_instance(
             fwcfg::Entry::RamFb,
         )
         .unwrap();
+    fwcfg
+        .insert_named(
+            hw::qemu::ramfb::RamFb::FWCFG_EDID_NAME,
+            fwcfg::Entry::Bytes(ramfb.edid()),
+        )
+        .unwrap();
     fwcfg.attach_ramfb(Some(ramfb.clone()));
 
     let cpuid_profile = config::parse_cpuid(&config)?;
@@ -79,6 +79,7 @@ fn main() {
     cfg.header("sys/vmm.h");
     cfg.header("sys/vmm_dev.h");
     cfg.header("sys/vmm_data.h");
+    cfg.header("sys/ppt_dev.h");
 
     cfg.skip_const(move |name| match name {
         _n if _n.starts_with("SEG_") => true,
@@ -87,6 +88,10 @@ fn main() {
         "VMM_PATH_PREFIX" => true,
         "VMM_CTL_PATH" => true,
 
+        // Mirrored from sys/pci.h for ppt_bar_query`pbq_type, rather than
+        // pulling all of that header in
+        _n if _n.starts_with("PCI_ADDR_") => true,
+
         // This was recently hidden from userspace.
         // We expose our own copy for now for us as a constraint.
         "VM_MAXCPU" => true,
@@ -7,7 +7,7 @@ use std::io::{Error, ErrorKind, Result};
 use std::mem::{size_of, size_of_val};
 use std::os::fd::*;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicI64, Ordering};
 use std::time::Duration;
 
@@ -258,6 +258,89 @@ impl AsRawFd for VmmFd {
     }
 }
 
+/// Handle to a host PCI device which has been attached to the ppt (PCI
+/// passthrough) driver, making it available for assignment to a VM.
+pub struct PptFd(File);
+impl PptFd {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let fp = OpenOptions::new().write(true).read(true).open(path)?;
+        Ok(Self(fp))
+    }
+
+    /// Issue ioctl against open ppt device
+    ///
+    /// # Safety
+    ///
+    /// Caller is charged with providing `data` argument which is adequate for
+    /// any copyin/copyout actions which may occur as part of the ioctl
+    /// processing.
+    pub unsafe fn ioctl<T>(&self, cmd: i32, data: *mut T) -> Result<i32> {
+        ioctl(self.as_raw_fd(), cmd, data as *mut libc::c_void)
+    }
+
+    /// Read `width` (1, 2, or 4) bytes from the config space of the host
+    /// device at offset `off`.
+    pub fn cfg_read(&self, off: u64, width: u32) -> Result<u32> {
+        let mut req =
+            ppt_cfg_io { pci_off: off, pci_width: width, pci_data: 0 };
+        unsafe { self.ioctl(ioctls::PPT_CFG_READ, &mut req) }?;
+        Ok(req.pci_data)
+    }
+
+    /// Write `width` (1, 2, or 4) bytes of `data` to the config space of the
+    /// host device at offset `off`.
+    pub fn cfg_write(&self, off: u64, width: u32, data: u32) -> Result<()> {
+        let mut req =
+            ppt_cfg_io { pci_off: off, pci_width: width, pci_data: data };
+        unsafe { self.ioctl(ioctls::PPT_CFG_WRITE, &mut req) }?;
+        Ok(())
+    }
+
+    /// Query the type, host address, and size of BAR `idx` on the device.
+    pub fn bar_query(&self, idx: u32) -> Result<ppt_bar_query> {
+        let mut req = ppt_bar_query { pbq_baridx: idx, ..Default::default() };
+        unsafe { self.ioctl(ioctls::PPT_BAR_QUERY, &mut req) }?;
+        Ok(req)
+    }
+
+    /// Read `width` bytes from offset `off` within BAR `bar` of the device.
+    pub fn bar_read(&self, bar: u32, off: u32, width: u32) -> Result<u32> {
+        let mut req = ppt_bar_io {
+            pbi_bar: bar,
+            pbi_off: off,
+            pbi_width: width,
+            pbi_data: 0,
+        };
+        unsafe { self.ioctl(ioctls::PPT_BAR_READ, &mut req) }?;
+        Ok(req.pbi_data)
+    }
+
+    /// Write `width` bytes of `data` to offset `off` within BAR `bar` of the
+    /// device.
+    pub fn bar_write(
+        &self,
+        bar: u32,
+        off: u32,
+        width: u32,
+        data: u32,
+    ) -> Result<()> {
+        let mut req = ppt_bar_io {
+            pbi_bar: bar,
+            pbi_off: off,
+            pbi_width: width,
+            pbi_data: data,
+        };
+        unsafe { self.ioctl(ioctls::PPT_BAR_WRITE, &mut req) }?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for PptFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
 pub type VmmDataResult<T> = std::result::Result<T, VmmDataError>;
 
 /// Encompasses the configuration and context to perform a vmm-data operation
@@ -115,3 +115,14 @@ pub const VM_VCPU_BARRIER: i32 = VMM_IOC_BASE | 0x27;
 pub const VM_NPT_OPERATION: i32 = VMM_IOC_BASE | 0x28;
 
 pub const VM_DEVMEM_GETOFFSET: i32 = VMM_IOC_BASE | 0xff;
+
+// Define constants from sys/ppt_dev.h
+
+const PPT_IOC: i32 = ((b'P' as i32) << 16) | ((b'T' as i32) << 8);
+
+// Operations performed on a passthrough (ppt) device
+pub const PPT_CFG_READ: i32 = PPT_IOC | 0x01;
+pub const PPT_CFG_WRITE: i32 = PPT_IOC | 0x02;
+pub const PPT_BAR_QUERY: i32 = PPT_IOC | 0x03;
+pub const PPT_BAR_READ: i32 = PPT_IOC | 0x04;
+pub const PPT_BAR_WRITE: i32 = PPT_IOC | 0x05;
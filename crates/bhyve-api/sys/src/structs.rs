@@ -281,6 +281,50 @@ pub struct vm_capability {
     pub allcpus: c_int,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev {
+    pub pptfd: c_int,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_mmio {
+    pub pptfd: c_int,
+    pub gpa: u64,
+    pub hpa: u64,
+    pub len: size_t,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_msi {
+    pub vcpu: c_int,
+    pub pptfd: c_int,
+    pub numvec: c_int,
+    pub msg: u64,
+    pub addr: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_msix {
+    pub vcpu: c_int,
+    pub pptfd: c_int,
+    pub idx: c_int,
+    pub msg: u64,
+    pub vector_control: u32,
+    pub addr: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_pptdev_limits {
+    pub pptfd: c_int,
+    pub msi_limit: c_int,
+    pub msix_limit: c_int,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
 pub struct vm_nmi {
@@ -611,3 +655,38 @@ pub const VNO_OP_EN_TRACK_DIRTY: u32 = 0x21;
 pub const VNO_OP_DIS_TRACK_DIRTY: u32 = 0x22;
 pub const VNO_FLAG_BITMAP_IN: u32 = 1 << 30;
 pub const VNO_FLAG_BITMAP_OUT: u32 = 1 << 31;
+
+// Definitions from sys/ppt_dev.h
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct ppt_cfg_io {
+    pub pci_off: u64,
+    pub pci_width: u32,
+    pub pci_data: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct ppt_bar_io {
+    pub pbi_bar: u32,
+    pub pbi_off: u32,
+    pub pbi_width: u32,
+    pub pbi_data: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct ppt_bar_query {
+    pub pbq_baridx: u32,
+    /// Acceptable values defined by `PCI_ADDR_*`
+    pub pbq_type: u32,
+    pub pbq_base: u64,
+    pub pbq_size: u64,
+}
+
+// Values for ppt_bar_query`pbq_type, mirroring the PCI address space types
+pub const PCI_ADDR_CONFIG: u32 = 0x00;
+pub const PCI_ADDR_IO: u32 = 0x01;
+pub const PCI_ADDR_MEM32: u32 = 0x02;
+pub const PCI_ADDR_MEM64: u32 = 0x03;
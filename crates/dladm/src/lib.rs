@@ -85,33 +85,14 @@ impl Handle {
     fn get_vnic_mac(name: &str, mac: &mut [u8]) -> Result<()> {
         // dladm show-vnic -p -o macaddress <VNIC_NAME>
         // 2:8:20:2d:e9:24
-        let output = Command::new("dladm")
-            .args(["show-vnic", "-p", "-o", "macaddress"])
-            .arg(name)
-            .stderr(Stdio::null())
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .output()?;
-        if !output.status.success() {
-            return Err(Error::new(ErrorKind::Other, "failed dladm"));
+        let line = show_vnic_field(name, "macaddress")?;
+        let addr: Vec<u8> = line
+            .split(':')
+            .filter_map(|f| u8::from_str_radix(f, 16).ok())
+            .collect();
+        if addr.len() != ETHERADDRL {
+            return Err(Error::new(ErrorKind::Other, "cannot query mac addr"));
         }
-        let addr = BufReader::new(&output.stdout[..])
-            .lines()
-            .next()
-            .and_then(Result::ok)
-            .and_then(|line| {
-                let fields: Vec<u8> = line
-                    .split(':')
-                    .filter_map(|f| u8::from_str_radix(f, 16).ok())
-                    .collect();
-                match fields.len() {
-                    ETHERADDRL => Some(fields),
-                    _ => None,
-                }
-            })
-            .ok_or_else(|| {
-                Error::new(ErrorKind::Other, "cannot query mac addr")
-            })?;
         mac.copy_from_slice(&addr[..]);
         Ok(())
     }
@@ -134,6 +115,93 @@ impl Drop for Handle {
 
 const ETHERADDRL: usize = 6;
 
+/// Longest permissible datalink name (excluding the NUL terminator)
+const MAXLINKNAMELEN: usize = 31;
+
+/// Query a single field of `dladm show-vnic` for the VNIC `name`.
+fn show_vnic_field(name: &str, field: &str) -> Result<String> {
+    let output = Command::new("dladm")
+        .args(["show-vnic", "-p", "-o", field])
+        .arg(name)
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::new(ErrorKind::Other, "failed dladm"));
+    }
+    BufReader::new(&output.stdout[..])
+        .lines()
+        .next()
+        .and_then(Result::ok)
+        .ok_or_else(|| {
+            Error::new(ErrorKind::Other, format!("cannot query vnic {field}"))
+        })
+}
+
+/// A temporary VNIC carrying a single VLAN, created over the same link, and
+/// with the same MAC address, as an existing (untagged) VNIC.  It is deleted
+/// when dropped.
+///
+/// This spares the operator from creating a tagged VNIC for each VLAN a
+/// consumer might want: the untagged VNIC serves to reserve the address.
+pub struct VlanVnic {
+    name: String,
+}
+impl VlanVnic {
+    /// Create a VNIC tagged with `vid`, patterned after the VNIC `vnic`.
+    pub fn create(vnic: &str, vid: u16) -> Result<Self> {
+        if !(1..=4094).contains(&vid) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("VLAN ID {vid} is outside of 1-4094"),
+            ));
+        }
+        let name = format!("{vnic}_vlan{vid}");
+        if name.len() > MAXLINKNAMELEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("VNIC name {vnic} is too long to derive a VLAN name"),
+            ));
+        }
+        let over = show_vnic_field(vnic, "over")?;
+        let mac = show_vnic_field(vnic, "macaddress")?;
+
+        // dladm create-vnic -t -l <LINK> -m <MAC> -v <VID> <NAME>
+        let status = Command::new("dladm")
+            .args(["create-vnic", "-t", "-l", &over, "-m", &mac, "-v"])
+            .arg(vid.to_string())
+            .arg(&name)
+            .stderr(Stdio::null())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("failed to create VLAN VNIC {name}"),
+            ));
+        }
+        Ok(Self { name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+impl Drop for VlanVnic {
+    fn drop(&mut self) {
+        // dladm delete-vnic -t <NAME>
+        let _ = Command::new("dladm")
+            .args(["delete-vnic", "-t"])
+            .arg(&self.name)
+            .stderr(Stdio::null())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status();
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct LinkInfo {
     pub link_id: u32,
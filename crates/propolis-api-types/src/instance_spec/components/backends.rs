@@ -11,6 +11,39 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// How a storage backend handles I/O which fails in a way that may be
+/// transient (such as an unreachable Crucible downstairs).
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    JsonSchema,
+    PartialEq,
+    Eq,
+)]
+#[serde(
+    deny_unknown_fields,
+    rename_all = "snake_case",
+    tag = "type",
+    content = "value"
+)]
+pub enum StorageErrorPolicy {
+    /// Report failed I/O to the guest as an error.
+    #[default]
+    Report,
+
+    /// Retry failed I/O (with backoff) up to `max_attempts` times before
+    /// reporting the failure to the guest.
+    Retry { max_attempts: u32 },
+
+    /// Pause the instance's vCPUs while failed I/O is retried, resuming them
+    /// once the backend recovers.
+    Pause,
+}
+
 /// A Crucible storage backend.
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -27,6 +60,20 @@ pub struct CrucibleStorageBackend {
 
     /// Indicates whether the storage is read-only.
     pub readonly: bool,
+
+    /// How I/O failures are handled.  Defaults to reporting them to the
+    /// guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_policy: Option<StorageErrorPolicy>,
+
+    /// Indicates whether the volume is activated in the background, so that
+    /// the instance may start before it has, with guest I/O to the disk held
+    /// until it does.  Failed attempts at activating it are then retried until
+    /// one succeeds or an error which can't be retried occurs.  Defaults to
+    /// false, in which case the volume is activated once as the instance
+    /// starts, and the instance fails to start if that fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lazy_attach: Option<bool>,
 }
 
 impl MigrationElement for CrucibleStorageBackend {
@@ -58,12 +105,14 @@ impl std::fmt::Debug for CrucibleStorageBackend {
         f.debug_struct("CrucibleStorageBackend")
             .field("request_json", &"<redacted>".to_string())
             .field("readonly", &self.readonly)
+            .field("error_policy", &self.error_policy)
+            .field("lazy_attach", &self.lazy_attach)
             .finish()
     }
 }
 
 /// A storage backend backed by a file in the host system's file system.
-#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct FileStorageBackend {
     /// A path to a file that backs a disk.
@@ -71,6 +120,60 @@ pub struct FileStorageBackend {
 
     /// Indicates whether the storage is read-only.
     pub readonly: bool,
+
+    /// How I/O failures are handled.  Defaults to reporting them to the
+    /// guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_policy: Option<StorageErrorPolicy>,
+
+    /// A base64-encoded, 64-byte AES-256-XTS key with which the contents of
+    /// the file are encrypted.  If absent, the file is not encrypted.  Specs
+    /// read back from a server give `<redacted>` in place of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<String>,
+
+    /// The number of worker threads this backend contributes to the pool
+    /// which services requests to file backends.  Defaults to 8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workers: Option<u32>,
+
+    /// The maximum number of requests from this backend which may be
+    /// processed (or queued for processing) at once.  Defaults to the number
+    /// of workers it contributes to the pool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_in_flight: Option<u32>,
+
+    /// The size, in bytes, of an in-memory cache of data read from the file.
+    /// Only read-only backends may be cached.  If absent, reads are not
+    /// cached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_cache_size: Option<u64>,
+
+    /// A path to a file in which a checksum of each block of the disk is
+    /// kept, created if it does not exist.  Blocks are verified against their
+    /// checksums as they are read, with mismatches reported to the guest as
+    /// I/O errors.  Intended for testing; if absent, no checksums are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity_sidecar: Option<String>,
+}
+
+impl std::fmt::Debug for FileStorageBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Redact the encryption key, if there is one.
+        f.debug_struct("FileStorageBackend")
+            .field("path", &self.path)
+            .field("readonly", &self.readonly)
+            .field("error_policy", &self.error_policy)
+            .field(
+                "encryption_key",
+                &self.encryption_key.as_ref().map(|_| "<redacted>"),
+            )
+            .field("workers", &self.workers)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("read_cache_size", &self.read_cache_size)
+            .field("integrity_sidecar", &self.integrity_sidecar)
+            .finish()
+    }
 }
 
 impl MigrationElement for FileStorageBackend {
@@ -89,6 +192,15 @@ impl MigrationElement for FileStorageBackend {
                 self.readonly, other.readonly,
             ))
             .into())
+        } else if self.encryption_key.is_some()
+            != other.encryption_key.is_some()
+        {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "encryption mismatch (self: {}, other: {})",
+                self.encryption_key.is_some(),
+                other.encryption_key.is_some(),
+            ))
+            .into())
         } else {
             Ok(())
         }
@@ -105,6 +217,14 @@ pub struct BlobStorageBackend {
 
     /// Indicates whether the storage is read-only.
     pub readonly: bool,
+
+    /// A path to a file in which the disk's contents persist.  If the file
+    /// exists, the disk's initial contents are loaded from it (rather than
+    /// from `base64`); otherwise it is created with the contents of `base64`.
+    /// Unless the storage is read-only, its contents are written back to the
+    /// file when the instance halts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persist_path: Option<String>,
 }
 
 impl std::fmt::Debug for BlobStorageBackend {
@@ -112,6 +232,7 @@ impl std::fmt::Debug for BlobStorageBackend {
         f.debug_struct("BlobStorageBackend")
             .field("base64", &"<redacted>".to_string())
             .field("readonly", &self.readonly)
+            .field("persist_path", &self.persist_path)
             .finish()
     }
 }
@@ -144,6 +265,13 @@ impl MigrationElement for BlobStorageBackend {
 pub struct VirtioNetworkBackend {
     /// The name of the viona VNIC to use as a backend.
     pub vnic_name: String,
+
+    /// A VLAN ID (1-4094) with which to tag the device's traffic. When given,
+    /// a VNIC carrying that VLAN is created for the device over the same link,
+    /// and with the same MAC address, as `vnic_name`, and is removed when the
+    /// device is torn down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
 }
 
 impl MigrationElement for VirtioNetworkBackend {
@@ -182,6 +310,30 @@ impl MigrationElement for DlpiNetworkBackend {
     }
 }
 
+/// A network backend naming a host PCI function which has been attached to the
+/// ppt (PCI passthrough) driver, for use by a passthrough NIC.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PptNetworkBackend {
+    /// The path to the function's ppt device, e.g. `/dev/ppt0`.
+    pub ppt_path: String,
+}
+
+impl MigrationElement for PptNetworkBackend {
+    fn kind(&self) -> &'static str {
+        "PptNetworkBackend"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        _other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        // The device using this backend refuses to migrate on its own.
+        Ok(())
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum MigrationCompatibilityError {
     #[error("component configurations incompatible: {0}")]
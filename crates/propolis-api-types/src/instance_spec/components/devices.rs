@@ -35,6 +35,23 @@ fn pci_path_matches(
     }
 }
 
+/// Limits on how long a virtio device may defer the interrupts announcing
+/// the work it has completed, so that one interrupt can announce several
+/// completions.  The guest is interrupted once `max_packets` completions are
+/// pending, or once the oldest has waited `max_usecs`, whichever comes first.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioInterruptModeration {
+    /// The number of completions (packets or requests) which may await an
+    /// interrupt.
+    pub max_packets: u32,
+
+    /// How long, in microseconds, a completion may await an interrupt.
+    pub max_usecs: u32,
+}
+
 /// A disk that presents a virtio-block interface to the guest.
 #[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -44,6 +61,17 @@ pub struct VirtioDisk {
 
     /// The PCI bus/device/function at which this disk should be attached.
     pub pci_path: PciPath,
+
+    /// The number of request queues the device exposes to the guest.  More
+    /// than one allows a guest to issue I/O from several vCPUs without
+    /// contending for a single queue.  Defaults to 1 if not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_queues: Option<u16>,
+
+    /// Moderation of the device's interrupts.  If not specified, the guest
+    /// is interrupted for each completed request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupt_moderation: Option<VirtioInterruptModeration>,
 }
 
 impl MigrationElement for VirtioDisk {
@@ -58,6 +86,16 @@ impl MigrationElement for VirtioDisk {
     {
         backend_name_matches(&self.backend_name, &other.backend_name)?;
         pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if self.num_queues != other.num_queues {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "virtio-block queue count mismatch (self: {:?}, other: \
+                    {:?})",
+                    self.num_queues, other.num_queues
+                ),
+            )
+            .into());
+        }
         Ok(())
     }
 }
@@ -71,6 +109,32 @@ pub struct NvmeDisk {
 
     /// The PCI bus/device/function at which this disk should be attached.
     pub pci_path: PciPath,
+
+    /// The number of I/O submission/completion queue pairs the controller
+    /// supports.  Defaults to 15 if not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_queues: Option<u16>,
+
+    /// The maximum number of entries in each I/O queue.  Defaults to 65536 if
+    /// not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_size: Option<u32>,
+
+    /// The model number the controller reports to the guest, of at most 40
+    /// printable ASCII characters.  Left blank if not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_number: Option<String>,
+
+    /// The firmware revision the controller reports to the guest, of at most
+    /// 8 printable ASCII characters.  Left blank if not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_revision: Option<String>,
+
+    /// The IEEE OUI the controller reports to the guest, as the three bytes
+    /// of the identifier in order (e.g. `[0xa8, 0x40, 0x25]` for A8-40-25).
+    /// Defaults to Oxide's OUI if not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ieee_oui: Option<[u8; 3]>,
 }
 
 impl MigrationElement for NvmeDisk {
@@ -78,6 +142,132 @@ impl MigrationElement for NvmeDisk {
         "NvmeDisk"
     }
 
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        backend_name_matches(&self.backend_name, &other.backend_name)?;
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if (self.num_queues, self.queue_size)
+            != (other.num_queues, other.queue_size)
+        {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "NVMe queue configuration mismatch (self: {:?}/{:?}, \
+                    other: {:?}/{:?})",
+                    self.num_queues,
+                    self.queue_size,
+                    other.num_queues,
+                    other.queue_size
+                ),
+            )
+            .into());
+        }
+        if (&self.model_number, &self.firmware_revision, self.ieee_oui)
+            != (&other.model_number, &other.firmware_revision, other.ieee_oui)
+        {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "NVMe controller identity mismatch (self: {:?}/{:?}/{:?}, \
+                    other: {:?}/{:?}/{:?})",
+                    self.model_number,
+                    self.firmware_revision,
+                    self.ieee_oui,
+                    other.model_number,
+                    other.firmware_revision,
+                    other.ieee_oui
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// A disk attached to an AHCI controller, presenting a SATA interface to the
+/// guest.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SataDisk {
+    /// The name of the disk's backend component.
+    pub backend_name: String,
+
+    /// The PCI bus/device/function at which the disk's controller should be
+    /// attached.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for SataDisk {
+    fn kind(&self) -> &'static str {
+        "SataDisk"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        backend_name_matches(&self.backend_name, &other.backend_name)?;
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        Ok(())
+    }
+}
+
+/// A CD-ROM drive attached to an AHCI controller, presenting an ATAPI
+/// interface to the guest.
+///
+/// The drive's media is provided by its backend, which can be replaced (or
+/// ejected) while the instance is running.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SataCdrom {
+    /// The name of the backend providing the drive's media.
+    pub backend_name: String,
+
+    /// The PCI bus/device/function at which the drive's controller should be
+    /// attached.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for SataCdrom {
+    fn kind(&self) -> &'static str {
+        "SataCdrom"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        backend_name_matches(&self.backend_name, &other.backend_name)?;
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        Ok(())
+    }
+}
+
+/// A disk attached to a PIIX3-compatible IDE controller, for guests too old to
+/// support any other kind of storage controller.
+///
+/// The controller occupies the legacy I/O ports and interrupt of the primary
+/// IDE channel, so an instance may have at most one such disk, and only if
+/// the server has been configured to allow them.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct IdeDisk {
+    /// The name of the disk's backend component.
+    pub backend_name: String,
+
+    /// The PCI bus/device/function at which the disk's controller should be
+    /// attached.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for IdeDisk {
+    fn kind(&self) -> &'static str {
+        "IdeDisk"
+    }
+
     fn can_migrate_from_element(
         &self,
         other: &Self,
@@ -89,6 +279,94 @@ impl MigrationElement for NvmeDisk {
     }
 }
 
+/// A token-bucket limit on the traffic a NIC passes in one direction.
+///
+/// Frames which would exceed the limit are dropped.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// The sustained rate, in bytes per second, at which traffic may pass.
+    pub bytes_per_sec: u64,
+
+    /// The largest burst of traffic, in bytes, which may pass at once after
+    /// a quiet period. This must be at least as large as the largest frame
+    /// (1518 bytes).
+    pub burst_bytes: u64,
+}
+
+/// Limits on the traffic a NIC transmits and receives. A direction without a
+/// limit is unrestricted.
+///
+/// Rate limits are a matter of host policy, not of the guest-visible device,
+/// so they need not match across a migration, and may be changed while the
+/// instance runs.
+#[derive(
+    Clone,
+    Copy,
+    Default,
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Eq,
+    JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct NicRateLimit {
+    /// The limit on traffic sent by the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx: Option<RateLimit>,
+
+    /// The limit on traffic delivered to the guest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx: Option<RateLimit>,
+}
+
+/// Offloads which a virtio NIC may offer to its guest, where its backend
+/// supports them. Disabling one also disables those that depend on it: TSO
+/// depends on TX checksum offload, and LRO on RX checksum offload.
+///
+/// Only NICs with virtio (viona) backends offer offloads at all.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirtioNicOffloads {
+    /// Whether the guest may leave the checksums of the packets it sends for
+    /// the device to compute.
+    pub tx_checksum: bool,
+
+    /// Whether the guest may send TCP segments larger than the MTU for the
+    /// device to split (TSO).
+    pub tso: bool,
+
+    /// Whether the device may deliver packets to the guest without completing
+    /// their checksums.
+    pub rx_checksum: bool,
+
+    /// Whether the device may coalesce received TCP segments into ones larger
+    /// than the MTU (LRO).
+    pub lro: bool,
+}
+
+impl Default for VirtioNicOffloads {
+    fn default() -> Self {
+        Self { tx_checksum: true, tso: true, rx_checksum: true, lro: true }
+    }
+}
+
+/// The state of a NIC's link, as reported to the guest.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NicLinkState {
+    Up,
+    Down,
+}
+
 /// A network card that presents a virtio-net interface to the guest.
 #[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -98,6 +376,33 @@ pub struct VirtioNic {
 
     /// The PCI path at which to attach this device.
     pub pci_path: PciPath,
+
+    /// The number of TX/RX queue pairs the device offers to the guest.  More
+    /// than one allows a guest with several vCPUs to spread its network
+    /// traffic across them.  Defaults to 1 if not specified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_queue_pairs: Option<u16>,
+
+    /// Limits on the traffic the device passes. These are enforced only for
+    /// devices whose datapath is in userspace, i.e. those with DLPI backends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<NicRateLimit>,
+
+    /// The offloads the device may offer the guest. If not specified, all
+    /// those supported by the backend are offered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offloads: Option<VirtioNicOffloads>,
+
+    /// The link state the device reports to the guest. If not specified, the
+    /// link is up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_state: Option<NicLinkState>,
+
+    /// Moderation of the device's interrupts.  If not specified, the guest
+    /// is interrupted for each packet.  This is supported only for devices
+    /// whose datapath is in userspace, i.e. those with DLPI backends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupt_moderation: Option<VirtioInterruptModeration>,
 }
 
 impl MigrationElement for VirtioNic {
@@ -105,6 +410,64 @@ impl MigrationElement for VirtioNic {
         "VirtioNic"
     }
 
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        backend_name_matches(&self.backend_name, &other.backend_name)?;
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if self.num_queue_pairs != other.num_queue_pairs {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "virtio-net queue pair count mismatch (self: {:?}, \
+                    other: {:?})",
+                    self.num_queue_pairs, other.num_queue_pairs
+                ),
+            )
+            .into());
+        }
+        // Offloads are negotiated with the guest, which expects those it has
+        // accepted to remain available.
+        if self.offloads.unwrap_or_default()
+            != other.offloads.unwrap_or_default()
+        {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "virtio-net offload mismatch (self: {:?}, other: {:?})",
+                    self.offloads, other.offloads
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// A network card that presents an Intel 82540EM (e1000) interface to the
+/// guest, for guests without virtio drivers.
+///
+/// The device's datapath is emulated in userspace, so it carries less traffic
+/// than a [VirtioNic] would over the same backend.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct E1000Nic {
+    /// The name of the device's backend.
+    pub backend_name: String,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+
+    /// Limits on the traffic the device passes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<NicRateLimit>,
+}
+
+impl MigrationElement for E1000Nic {
+    fn kind(&self) -> &'static str {
+        "E1000Nic"
+    }
+
     fn can_migrate_from_element(
         &self,
         other: &Self,
@@ -116,10 +479,54 @@ impl MigrationElement for VirtioNic {
     }
 }
 
+/// A network card whose host PCI function (typically an SR-IOV virtual
+/// function) is assigned directly to the guest.
+///
+/// The guest drives the host hardware itself, so the device's state lives
+/// outside of Propolis and cannot be migrated: an instance with a passthrough
+/// NIC must have it detached before it can migrate.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PassthroughNic {
+    /// The name of the device's backend, which names the host function to
+    /// assign.
+    pub backend_name: String,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for PassthroughNic {
+    fn kind(&self) -> &'static str {
+        "PassthroughNic"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        _other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        Err(MigrationCompatibilityError::ComponentConfiguration(
+            "passthrough NICs cannot be migrated".to_string(),
+        )
+        .into())
+    }
+}
+
 /// A serial port identifier, which determines what I/O ports a guest can use to
 /// access a port.
 #[derive(
-    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+    Clone,
+    Copy,
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    JsonSchema,
 )]
 #[serde(deny_unknown_fields, rename_all = "snake_case")]
 pub enum SerialPortNumber {
@@ -137,6 +544,13 @@ pub enum SerialPortNumber {
 pub struct SerialPort {
     /// The serial port number for this port.
     pub num: SerialPortNumber,
+
+    /// The number of bytes of console output to retain for retrieval through
+    /// the serial history API. Up to this many bytes are kept from both the
+    /// start of the port's output and its most recent output. If not
+    /// specified, the server's default (1 MiB) is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_size: Option<u64>,
 }
 
 impl MigrationElement for SerialPort {
@@ -149,10 +563,12 @@ impl MigrationElement for SerialPort {
         other: &Self,
     ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
     {
-        if self != other {
+        // The amount of retained history is host-local configuration, so
+        // only the port number needs to match.
+        if self.num != other.num {
             Err(MigrationCompatibilityError::ComponentConfiguration(format!(
                 "serial port number mismatch (self: {0:?}, other: {1:?})",
-                self, other
+                self.num, other.num
             ))
             .into())
         } else {
@@ -174,6 +590,13 @@ pub struct PciPciBridge {
 
     /// The PCI path at which to attach this bridge.
     pub pci_path: PciPath,
+
+    /// If set, the bridge is presented to the guest as a PCIe root port with
+    /// a hotplug slot bearing this physical slot number. A single NVMe disk
+    /// or virtio NIC may be attached to (and detached from) the slot while
+    /// the instance is running. Requires PCIe to be enabled in the board.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotplug_slot: Option<u16>,
 }
 
 impl MigrationElement for PciPciBridge {
@@ -193,6 +616,12 @@ impl MigrationElement for PciPciBridge {
                 self.downstream_bus, other.downstream_bus
             ))
             .into())
+        } else if self.hotplug_slot != other.hotplug_slot {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "bridge hotplug slot mismatch (self: {0:?}, other: {1:?})",
+                self.hotplug_slot, other.hotplug_slot
+            ))
+            .into())
         } else {
             Ok(())
         }
@@ -254,6 +683,174 @@ impl MigrationElement for Option<QemuPvpanic> {
     }
 }
 
+/// A virtio-vsock device, through which software in the guest can reach
+/// processes on the host over `AF_VSOCK` stream sockets.
+///
+/// Guest connections to port `P` of the host (CID 2) are passed to the Unix
+/// socket at `<socket_path>_P`.  Host processes reach the guest by connecting
+/// to the Unix socket at `socket_path` and writing `CONNECT <port>\n`.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioSocket {
+    /// The context ID by which the guest is addressed.  CIDs 0 through 2 are
+    /// reserved.
+    pub guest_cid: u32,
+
+    /// The path on the host of the Unix socket through which host processes
+    /// connect to the guest.
+    pub socket_path: String,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for Option<VirtioSocket> {
+    fn kind(&self) -> &'static str {
+        "VirtioSocket"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        // The host socket path is local to each host, and so may differ, but
+        // the guest must find its device where it left it.
+        match (self, other) {
+            (Some(this), Some(other)) if this.guest_cid != other.guest_cid => {
+                let msg = format!(
+                    "vsock guest CID mismatch (self: {0}, other: {1})",
+                    this.guest_cid, other.guest_cid
+                );
+                Err(MigrationCompatibilityError::ComponentConfiguration(msg)
+                    .into())
+            }
+            (Some(this), Some(other)) => {
+                pci_path_matches(&this.pci_path, &other.pci_path)?;
+                Ok(())
+            }
+            (None, None) => Ok(()),
+            (_, _) => Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "vsock device presence mismatch (self: {0}, other: {1})",
+                    self.is_some(),
+                    other.is_some()
+                ),
+            )
+            .into()),
+        }
+    }
+}
+
+/// A named port presented by a virtio-console device.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioConsolePort {
+    /// The name by which the guest identifies the port, which appears in the
+    /// guest as `/dev/virtio-ports/<name>`.
+    pub name: String,
+
+    /// The path on the host of the Unix socket exposing the port.
+    pub socket_path: String,
+}
+
+/// A virtio-console device presenting one or more named ports, each of which
+/// is a flow-controlled byte stream between the guest and a Unix socket on the
+/// host.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioConsole {
+    /// The ports presented to the guest, in the order in which it will
+    /// discover them.
+    pub ports: Vec<VirtioConsolePort>,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for Option<VirtioConsole> {
+    fn kind(&self) -> &'static str {
+        "VirtioConsole"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        // As with vsock, the host socket paths may differ, but the guest must
+        // find the same ports where it left them.
+        match (self, other) {
+            (Some(this), Some(other)) => {
+                let this_names: Vec<_> =
+                    this.ports.iter().map(|p| &p.name).collect();
+                let other_names: Vec<_> =
+                    other.ports.iter().map(|p| &p.name).collect();
+                if this_names != other_names {
+                    let msg = format!(
+                        "virtio-console ports mismatch (self: {0:?}, other: {1:?})",
+                        this_names, other_names
+                    );
+                    return Err(
+                        MigrationCompatibilityError::ComponentConfiguration(
+                            msg,
+                        )
+                        .into(),
+                    );
+                }
+                pci_path_matches(&this.pci_path, &other.pci_path)?;
+                Ok(())
+            }
+            (None, None) => Ok(()),
+            (_, _) => Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "virtio-console device presence mismatch (self: {0}, other: {1})",
+                    self.is_some(),
+                    other.is_some()
+                ),
+            )
+            .into()),
+        }
+    }
+}
+
+/// A virtio-input device presenting an absolute pointer (a tablet), whose
+/// position tracks that of the pointer in a remote console such as VNC.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioTablet {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for Option<VirtioTablet> {
+    fn kind(&self) -> &'static str {
+        "VirtioTablet"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        match (self, other) {
+            (Some(this), Some(other)) => {
+                pci_path_matches(&this.pci_path, &other.pci_path)?;
+                Ok(())
+            }
+            (None, None) => Ok(()),
+            (_, _) => Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "tablet device presence mismatch (self: {0}, other: {1})",
+                    self.is_some(),
+                    other.is_some()
+                ),
+            )
+            .into()),
+        }
+    }
+}
+
 //
 // Structs for Falcon devices. These devices don't support live migration.
 //
@@ -312,8 +909,20 @@ mod test {
         let d1 = VirtioDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            num_queues: None,
+            interrupt_moderation: None,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
+
+        // Interrupt moderation is invisible to the guest's driver
+        let d2 = VirtioDisk {
+            interrupt_moderation: Some(VirtioInterruptModeration {
+                max_packets: 16,
+                max_usecs: 50,
+            }),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
     }
 
     #[test]
@@ -321,6 +930,8 @@ mod test {
         let d1 = VirtioDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            num_queues: None,
+            interrupt_moderation: None,
         };
 
         let d2 = VirtioDisk { backend_name: "other_backend".to_string(), ..d1 };
@@ -331,6 +942,9 @@ mod test {
             ..d1.clone()
         };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioDisk { num_queues: Some(4), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
     }
 
     #[test]
@@ -338,6 +952,11 @@ mod test {
         let d1 = NvmeDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            num_queues: None,
+            queue_size: None,
+            model_number: None,
+            firmware_revision: None,
+            ieee_oui: None,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
     }
@@ -347,14 +966,117 @@ mod test {
         let d1 = NvmeDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            num_queues: None,
+            queue_size: None,
+            model_number: None,
+            firmware_revision: None,
+            ieee_oui: None,
         };
 
-        let d2 = NvmeDisk { backend_name: "other_backend".to_string(), ..d1 };
+        let d2 = NvmeDisk {
+            backend_name: "other_backend".to_string(),
+            ..d1.clone()
+        };
         assert!(d1.can_migrate_from_element(&d2).is_err());
 
         let d2 =
             NvmeDisk { pci_path: PciPath::new(0, 6, 0).unwrap(), ..d1.clone() };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk { num_queues: Some(4), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk { queue_size: Some(1024), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk {
+            model_number: Some("Other Model".to_string()),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk {
+            firmware_revision: Some("2.0".to_string()),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk { ieee_oui: Some([0, 0, 1]), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+    }
+
+    #[test]
+    fn compatible_sata_disk() {
+        let d1 = SataDisk {
+            backend_name: "storage_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+        };
+        assert!(d1.can_migrate_from_element(&d1).is_ok());
+    }
+
+    #[test]
+    fn incompatible_sata_disk() {
+        let d1 = SataDisk {
+            backend_name: "storage_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+        };
+
+        let d2 = SataDisk { backend_name: "other_backend".to_string(), ..d1 };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 =
+            SataDisk { pci_path: PciPath::new(0, 6, 0).unwrap(), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+    }
+
+    #[test]
+    fn compatible_sata_cdrom() {
+        let d1 = SataCdrom {
+            backend_name: "iso_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+        };
+        assert!(d1.can_migrate_from_element(&d1).is_ok());
+    }
+
+    #[test]
+    fn incompatible_sata_cdrom() {
+        let d1 = SataCdrom {
+            backend_name: "iso_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+        };
+
+        let d2 = SataCdrom { backend_name: "other_backend".to_string(), ..d1 };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = SataCdrom {
+            pci_path: PciPath::new(0, 6, 0).unwrap(),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+    }
+
+    #[test]
+    fn compatible_ide_disk() {
+        let d1 = IdeDisk {
+            backend_name: "storage_backend".to_string(),
+            pci_path: PciPath::new(0, 1, 1).unwrap(),
+        };
+        assert!(d1.can_migrate_from_element(&d1).is_ok());
+    }
+
+    #[test]
+    fn incompatible_ide_disk() {
+        let d1 = IdeDisk {
+            backend_name: "storage_backend".to_string(),
+            pci_path: PciPath::new(0, 1, 1).unwrap(),
+        };
+
+        let d2 = IdeDisk { backend_name: "other_backend".to_string(), ..d1 };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 =
+            IdeDisk { pci_path: PciPath::new(0, 6, 0).unwrap(), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
     }
 
     #[test]
@@ -362,8 +1084,33 @@ mod test {
         let d1 = VirtioNic {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            num_queue_pairs: None,
+            rate_limit: None,
+            offloads: None,
+            link_state: None,
+            interrupt_moderation: None,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
+
+        // Rate limits are host policy, so may differ across a migration
+        let limit = RateLimit { bytes_per_sec: 1 << 20, burst_bytes: 1 << 16 };
+        let d2 = VirtioNic {
+            rate_limit: Some(NicRateLimit { tx: Some(limit), rx: None }),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
+
+        // Explicitly enabling every offload is the same as the default
+        let d2 = VirtioNic {
+            offloads: Some(VirtioNicOffloads::default()),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
+
+        // The link state is set by the host, and reapplied after migration
+        let d2 =
+            VirtioNic { link_state: Some(NicLinkState::Down), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
     }
 
     #[test]
@@ -371,6 +1118,11 @@ mod test {
         let d1 = VirtioNic {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            num_queue_pairs: None,
+            rate_limit: None,
+            offloads: None,
+            link_state: None,
+            interrupt_moderation: None,
         };
 
         let d2 = VirtioNic { backend_name: "other_backend".to_string(), ..d1 };
@@ -381,6 +1133,53 @@ mod test {
             ..d1.clone()
         };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioNic { num_queue_pairs: Some(4), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioNic {
+            offloads: Some(VirtioNicOffloads {
+                lro: false,
+                ..Default::default()
+            }),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+    }
+
+    #[test]
+    fn compatible_e1000_nic() {
+        let d1 = E1000Nic {
+            backend_name: "net_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+            rate_limit: None,
+        };
+        assert!(d1.can_migrate_from_element(&d1).is_ok());
+    }
+
+    #[test]
+    fn incompatible_e1000_nic() {
+        let d1 = E1000Nic {
+            backend_name: "net_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+            rate_limit: None,
+        };
+
+        let d2 = E1000Nic { backend_name: "other_backend".to_string(), ..d1 };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 =
+            E1000Nic { pci_path: PciPath::new(0, 6, 0).unwrap(), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+    }
+
+    #[test]
+    fn passthrough_nic_never_migrates() {
+        let d1 = PassthroughNic {
+            backend_name: "vf0".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+        };
+        assert!(d1.can_migrate_from_element(&d1).is_err());
     }
 
     #[test]
@@ -395,8 +1194,11 @@ mod test {
         for (p1, p2) in
             ports.into_iter().flat_map(|p| std::iter::repeat(p).zip(ports))
         {
-            let can_migrate = SerialPort { num: p1 }
-                .can_migrate_from_element(&SerialPort { num: p2 });
+            let can_migrate = SerialPort { num: p1, history_size: None }
+                .can_migrate_from_element(&SerialPort {
+                    num: p2,
+                    history_size: Some(4096),
+                });
 
             assert_eq!(
                 p1 == p2,
@@ -413,6 +1215,7 @@ mod test {
         let b1 = PciPciBridge {
             downstream_bus: 1,
             pci_path: PciPath::new(1, 2, 3).unwrap(),
+            hotplug_slot: None,
         };
 
         let mut b2 = b1;
@@ -422,6 +1225,10 @@ mod test {
         assert!(b1.can_migrate_from_element(&b2).is_err());
         b2.downstream_bus = b1.downstream_bus;
 
+        b2.hotplug_slot = Some(1);
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+        b2.hotplug_slot = b1.hotplug_slot;
+
         b2.pci_path = PciPath::new(4, 5, 6).unwrap();
         assert!(b1.can_migrate_from_element(&b2).is_err());
     }
@@ -444,4 +1251,76 @@ mod test {
         let d2 = Some(QemuPvpanic { enable_isa: false });
         assert!(d1.can_migrate_from_element(&d2).is_ok());
     }
+
+    #[test]
+    fn virtio_socket_compatibility() {
+        let d1 = Some(VirtioSocket {
+            guest_cid: 3,
+            socket_path: "/tmp/vsock-a.sock".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+        });
+
+        // Only the host-side socket path may differ
+        let mut d2 = d1.clone();
+        d2.as_mut().unwrap().socket_path = "/tmp/vsock-b.sock".to_string();
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
+
+        d2.as_mut().unwrap().guest_cid = 4;
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let mut d2 = d1.clone();
+        d2.as_mut().unwrap().pci_path = PciPath::new(0, 6, 0).unwrap();
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        assert!(d1.can_migrate_from_element(&None).is_err());
+        assert!(None.can_migrate_from_element(&d1).is_err());
+    }
+
+    #[test]
+    fn virtio_console_compatibility() {
+        let port = |name: &str, path: &str| VirtioConsolePort {
+            name: name.to_string(),
+            socket_path: path.to_string(),
+        };
+        let d1 = Some(VirtioConsole {
+            ports: vec![
+                port("agent", "/tmp/a.sock"),
+                port("log", "/tmp/b.sock"),
+            ],
+            pci_path: PciPath::new(0, 8, 0).unwrap(),
+        });
+
+        // Only the host-side socket paths may differ
+        let mut d2 = d1.clone();
+        d2.as_mut().unwrap().ports[0].socket_path = "/tmp/c.sock".to_string();
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
+
+        d2.as_mut().unwrap().ports.swap(0, 1);
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let mut d2 = d1.clone();
+        d2.as_mut().unwrap().ports.pop();
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let mut d2 = d1.clone();
+        d2.as_mut().unwrap().pci_path = PciPath::new(0, 9, 0).unwrap();
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        assert!(d1.can_migrate_from_element(&None).is_err());
+        assert!(None.can_migrate_from_element(&d1).is_err());
+    }
+
+    #[test]
+    fn virtio_tablet_compatibility() {
+        let d1 =
+            Some(VirtioTablet { pci_path: PciPath::new(0, 9, 0).unwrap() });
+        assert!(d1.can_migrate_from_element(&d1.clone()).is_ok());
+
+        let d2 =
+            Some(VirtioTablet { pci_path: PciPath::new(0, 10, 0).unwrap() });
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        assert!(d1.can_migrate_from_element(&None).is_err());
+        assert!(None.can_migrate_from_element(&d1).is_err());
+    }
 }
@@ -26,6 +26,19 @@ pub enum SpecBuilderError {
 
     #[error("SoftNpu port {0:?} is already specified")]
     SoftNpuPortInUse(String),
+
+    #[error("vNIC {0} is already the backend of another device")]
+    VnicInUse(String),
+}
+
+/// Returns the host VNIC through which a network backend passes traffic, along
+/// with the VLAN (if any) with which it tags that traffic.
+fn backend_vnic(backend: &NetworkBackendV0) -> Option<(&str, Option<u16>)> {
+    match backend {
+        NetworkBackendV0::Virtio(be) => Some((&be.vnic_name, be.vlan_id)),
+        NetworkBackendV0::Dlpi(be) => Some((&be.vnic_name, None)),
+        NetworkBackendV0::Ppt(_) => None,
+    }
 }
 
 /// A builder that constructs instance specs incrementally and catches basic
@@ -116,6 +129,20 @@ impl SpecBuilder {
             return Err(SpecBuilderError::BackendNameInUse(backend_name));
         }
 
+        // Devices may share a VNIC only by tagging their traffic with
+        // different VLANs.
+        if let Some(vnic) = backend_vnic(&backend_spec) {
+            if self
+                .spec
+                .backends
+                .network_backends
+                .values()
+                .any(|other| backend_vnic(other) == Some(vnic))
+            {
+                return Err(SpecBuilderError::VnicInUse(vnic.0.to_owned()));
+            }
+        }
+
         self.register_pci_device(device_spec.pci_path())?;
         let _old =
             self.spec.devices.network_devices.insert(device_name, device_spec);
@@ -153,6 +180,16 @@ impl SpecBuilder {
     pub fn add_serial_port(
         &mut self,
         port: components::devices::SerialPortNumber,
+    ) -> Result<&Self, SpecBuilderError> {
+        self.add_serial_port_with_history_size(port, None)
+    }
+
+    /// Adds a serial port that retains up to `history_size` bytes of output
+    /// history (or the server's default amount if `None`).
+    pub fn add_serial_port_with_history_size(
+        &mut self,
+        port: components::devices::SerialPortNumber,
+        history_size: Option<u64>,
     ) -> Result<&Self, SpecBuilderError> {
         if self
             .spec
@@ -166,7 +203,7 @@ impl SpecBuilder {
                     components::devices::SerialPortNumber::Com4 => "com4",
                 }
                 .to_string(),
-                components::devices::SerialPort { num: port },
+                components::devices::SerialPort { num: port, history_size },
             )
             .is_some()
         {
@@ -192,6 +229,54 @@ impl SpecBuilder {
         Ok(self)
     }
 
+    /// Adds a virtio-vsock device.
+    pub fn set_virtio_socket(
+        &mut self,
+        vsock: components::devices::VirtioSocket,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_socket.is_some() {
+            return Err(SpecBuilderError::DeviceNameInUse(
+                "virtio-vsock".to_string(),
+            ));
+        }
+
+        self.register_pci_device(vsock.pci_path)?;
+        self.spec.devices.virtio_socket = Some(vsock);
+        Ok(self)
+    }
+
+    /// Adds a virtio-console device.
+    pub fn set_virtio_console(
+        &mut self,
+        console: components::devices::VirtioConsole,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_console.is_some() {
+            return Err(SpecBuilderError::DeviceNameInUse(
+                "virtio-console".to_string(),
+            ));
+        }
+
+        self.register_pci_device(console.pci_path)?;
+        self.spec.devices.virtio_console = Some(console);
+        Ok(self)
+    }
+
+    /// Adds a virtio tablet device.
+    pub fn set_virtio_tablet(
+        &mut self,
+        tablet: components::devices::VirtioTablet,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_tablet.is_some() {
+            return Err(SpecBuilderError::DeviceNameInUse(
+                "virtio-tablet".to_string(),
+            ));
+        }
+
+        self.register_pci_device(tablet.pci_path)?;
+        self.spec.devices.virtio_tablet = Some(tablet);
+        Ok(self)
+    }
+
     #[cfg(feature = "falcon")]
     pub fn set_softnpu_pci_port(
         &mut self,
@@ -39,13 +39,31 @@ pub mod builder;
 pub enum StorageDeviceV0 {
     VirtioDisk(components::devices::VirtioDisk),
     NvmeDisk(components::devices::NvmeDisk),
+    SataDisk(components::devices::SataDisk),
+    SataCdrom(components::devices::SataCdrom),
+    IdeDisk(components::devices::IdeDisk),
 }
 
 impl StorageDeviceV0 {
-    fn pci_path(&self) -> PciPath {
+    /// Returns the PCI path at which the device is attached.
+    pub fn pci_path(&self) -> PciPath {
         match self {
             Self::VirtioDisk(disk) => disk.pci_path,
             Self::NvmeDisk(disk) => disk.pci_path,
+            Self::SataDisk(disk) => disk.pci_path,
+            Self::SataCdrom(cdrom) => cdrom.pci_path,
+            Self::IdeDisk(disk) => disk.pci_path,
+        }
+    }
+
+    /// Returns the name of the device's backend.
+    pub fn backend_name(&self) -> &str {
+        match self {
+            Self::VirtioDisk(disk) => &disk.backend_name,
+            Self::NvmeDisk(disk) => &disk.backend_name,
+            Self::SataDisk(disk) => &disk.backend_name,
+            Self::SataCdrom(cdrom) => &cdrom.backend_name,
+            Self::IdeDisk(disk) => &disk.backend_name,
         }
     }
 }
@@ -55,6 +73,9 @@ impl MigrationElement for StorageDeviceV0 {
         match self {
             StorageDeviceV0::VirtioDisk(_) => "StorageDevice(VirtioDisk)",
             StorageDeviceV0::NvmeDisk(_) => "StorageDevice(NvmeDisk)",
+            StorageDeviceV0::SataDisk(_) => "StorageDevice(SataDisk)",
+            StorageDeviceV0::SataCdrom(_) => "StorageDevice(SataCdrom)",
+            StorageDeviceV0::IdeDisk(_) => "StorageDevice(IdeDisk)",
         }
     }
 
@@ -69,6 +90,15 @@ impl MigrationElement for StorageDeviceV0 {
             (Self::NvmeDisk(this), Self::NvmeDisk(other)) => {
                 this.can_migrate_from_element(other)
             }
+            (Self::SataDisk(this), Self::SataDisk(other)) => {
+                this.can_migrate_from_element(other)
+            }
+            (Self::SataCdrom(this), Self::SataCdrom(other)) => {
+                this.can_migrate_from_element(other)
+            }
+            (Self::IdeDisk(this), Self::IdeDisk(other)) => {
+                this.can_migrate_from_element(other)
+            }
             (_, _) => Err(ElementCompatibilityError::ComponentsIncomparable(
                 self.kind(),
                 other.kind(),
@@ -81,27 +111,49 @@ impl MigrationElement for StorageDeviceV0 {
 #[serde(deny_unknown_fields, tag = "type", content = "component")]
 pub enum NetworkDeviceV0 {
     VirtioNic(components::devices::VirtioNic),
+    E1000Nic(components::devices::E1000Nic),
+    PassthroughNic(components::devices::PassthroughNic),
 }
 
 impl NetworkDeviceV0 {
-    fn pci_path(&self) -> PciPath {
+    /// Returns the PCI path at which the device is attached.
+    pub fn pci_path(&self) -> PciPath {
         match self {
             Self::VirtioNic(nic) => nic.pci_path,
+            Self::E1000Nic(nic) => nic.pci_path,
+            Self::PassthroughNic(nic) => nic.pci_path,
         }
     }
 }
 
 impl MigrationElement for NetworkDeviceV0 {
     fn kind(&self) -> &'static str {
-        "NetworkDevice(VirtioNic)"
+        match self {
+            Self::VirtioNic(_) => "NetworkDevice(VirtioNic)",
+            Self::E1000Nic(_) => "NetworkDevice(E1000Nic)",
+            Self::PassthroughNic(_) => "NetworkDevice(PassthroughNic)",
+        }
     }
 
     fn can_migrate_from_element(
         &self,
         other: &Self,
     ) -> Result<(), ElementCompatibilityError> {
-        let (Self::VirtioNic(this), Self::VirtioNic(other)) = (self, other);
-        this.can_migrate_from_element(other)
+        match (self, other) {
+            (Self::VirtioNic(this), Self::VirtioNic(other)) => {
+                this.can_migrate_from_element(other)
+            }
+            (Self::E1000Nic(this), Self::E1000Nic(other)) => {
+                this.can_migrate_from_element(other)
+            }
+            (Self::PassthroughNic(this), Self::PassthroughNic(other)) => {
+                this.can_migrate_from_element(other)
+            }
+            (_, _) => Err(ElementCompatibilityError::ComponentsIncomparable(
+                self.kind(),
+                other.kind(),
+            )),
+        }
     }
 }
 
@@ -128,6 +180,18 @@ pub struct DeviceSpecV0 {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub qemu_pvpanic: Option<components::devices::QemuPvpanic>,
 
+    // As with the pvpanic device above, this field is optional, and omitted
+    // when absent, so that specs without a vsock device remain compatible
+    // with Propolis versions that predate it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtio_socket: Option<components::devices::VirtioSocket>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtio_console: Option<components::devices::VirtioConsole>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtio_tablet: Option<components::devices::VirtioTablet>,
+
     #[cfg(feature = "falcon")]
     pub softnpu_pci_port: Option<components::devices::SoftNpuPciPort>,
     #[cfg(feature = "falcon")]
@@ -192,6 +256,33 @@ impl DeviceSpecV0 {
                 )
             })?;
 
+        self.virtio_socket
+            .can_migrate_from_element(&other.virtio_socket)
+            .map_err(|e| {
+                MigrationCompatibilityError::ElementMismatch(
+                    "virtio-vsock device".to_string(),
+                    e,
+                )
+            })?;
+
+        self.virtio_console
+            .can_migrate_from_element(&other.virtio_console)
+            .map_err(|e| {
+                MigrationCompatibilityError::ElementMismatch(
+                    "virtio-console device".to_string(),
+                    e,
+                )
+            })?;
+
+        self.virtio_tablet
+            .can_migrate_from_element(&other.virtio_tablet)
+            .map_err(|e| {
+                MigrationCompatibilityError::ElementMismatch(
+                    "virtio-tablet device".to_string(),
+                    e,
+                )
+            })?;
+
         Ok(())
     }
 }
@@ -209,6 +300,7 @@ pub enum StorageBackendV0 {
 pub enum NetworkBackendV0 {
     Virtio(components::backends::VirtioNetworkBackend),
     Dlpi(components::backends::DlpiNetworkBackend),
+    Ppt(components::backends::PptNetworkBackend),
 }
 
 #[derive(Default, Clone, Deserialize, Serialize, Debug, JsonSchema)]
@@ -4,7 +4,7 @@
 
 //! Definitions for types exposed by the propolis-server API
 
-use std::{fmt, net::SocketAddr};
+use std::{collections::BTreeMap, fmt, net::SocketAddr};
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -52,6 +52,22 @@ pub struct InstanceEnsureRequest {
 
     // base64 encoded cloud-init ISO
     pub cloud_init_bytes: Option<String>,
+
+    /// If set, the instance's vCPUs are held when it's started, leaving it
+    /// paused with its devices ready until it is resumed.
+    #[serde(default)]
+    pub start_paused: bool,
+
+    /// If set, the instance's bootrom shows its boot menu when the guest
+    /// boots, waiting about a minute for a key press before booting as usual,
+    /// so that its setup UI can be reached to inspect the boot order or
+    /// secure boot configuration.
+    #[serde(default)]
+    pub boot_to_firmware_setup: bool,
+
+    /// If set, the server watches the guest for signs that it has hung, and
+    /// acts as the policy directs when it does.
+    pub supervision: Option<SupervisionPolicy>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
@@ -59,6 +75,53 @@ pub struct InstanceSpecEnsureRequest {
     pub properties: InstanceProperties,
     pub instance_spec: VersionedInstanceSpec,
     pub migrate: Option<InstanceMigrateInitiateRequest>,
+
+    /// If set, the instance's vCPUs are held when it's started, leaving it
+    /// paused with its devices ready until it is resumed.
+    #[serde(default)]
+    pub start_paused: bool,
+
+    /// If set, the instance's bootrom shows its boot menu when the guest
+    /// boots, waiting about a minute for a key press before booting as usual,
+    /// so that its setup UI can be reached to inspect the boot order or
+    /// secure boot configuration.
+    #[serde(default)]
+    pub boot_to_firmware_setup: bool,
+
+    /// If set, the server watches the guest for signs that it has hung, and
+    /// acts as the policy directs when it does.
+    pub supervision: Option<SupervisionPolicy>,
+}
+
+/// What the server does when an instance's guest appears to have hung.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum HangAction {
+    /// Record the hang in the instance's history, and leave the guest be.
+    #[default]
+    Report,
+    /// Record the hang, and reset the instance as if a reboot were requested.
+    /// Only allowed if a guest agent is configured, as a healthy guest which
+    /// is merely quiet on its serial ports would otherwise be reset.
+    Reset,
+}
+
+/// How the server watches a running instance's guest for signs that it has
+/// hung.
+///
+/// The guest shows signs of life by writing to any of its serial ports or, if
+/// a guest agent is configured, by answering the agent's pings.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SupervisionPolicy {
+    /// How long the guest may run without showing a sign of life before it's
+    /// considered hung.
+    pub hang_timeout_secs: u64,
+
+    /// What to do when the guest is found to have hung.
+    #[serde(default)]
+    pub action: HangAction,
 }
 
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
@@ -71,6 +134,77 @@ pub struct InstanceMigrateInitiateRequest {
     pub migration_id: Uuid,
     pub src_addr: SocketAddr,
     pub src_uuid: Uuid,
+    /// If set, the source keeps pushing guest memory while the guest runs
+    /// until few enough pages remain dirty, throttling its vCPUs within these
+    /// limits if the guest dirties memory faster than it can be sent.
+    pub auto_converge: Option<MigrationAutoConverge>,
+    /// The longest the guest should be paused while the last of its memory is
+    /// sent, in milliseconds. If set, the source keeps pushing guest memory
+    /// while the guest runs until the pages left dirty can be sent within
+    /// this time.
+    pub max_downtime_ms: Option<u64>,
+    /// If set, the migration's connections to the source are secured with
+    /// TLS.
+    pub tls: Option<MigrationTls>,
+    /// If set, a migration which fails before any guest state has been
+    /// transferred, because of a transient error such as a lost connection,
+    /// is retried within these limits instead of failing the instance.
+    pub retry: Option<MigrationRetryPolicy>,
+    /// If set, how the source tracks the guest memory dirtied while it is
+    /// being sent.
+    pub dirty_tracking: Option<MigrationDirtyTracking>,
+}
+
+/// How the source of a migration tracks the guest memory dirtied while it is
+/// being sent.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct MigrationDirtyTracking {
+    /// The least time, in milliseconds, between the starts of successive
+    /// scans for dirty pages while memory is pushed in rounds with the guest
+    /// running. A longer interval lets pages the guest rewrites often be
+    /// sent once rather than in every round.
+    pub scan_interval_ms: u64,
+    /// The number of pages whose dirty bits are read from the hypervisor, and
+    /// offered to the destination, at a time. This is rounded up to a
+    /// multiple of 8, and limited to between 8 and 524,288 pages.
+    pub bitmap_pages: u32,
+}
+
+/// Limits on how a migration into an instance is retried.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct MigrationRetryPolicy {
+    /// The greatest number of attempts to make, including the first.
+    pub max_attempts: u32,
+    /// The time to wait before each retry, in milliseconds.
+    pub backoff_ms: u64,
+}
+
+/// How a migration's connections to the source are secured with TLS.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct MigrationTls {
+    /// The SHA-256 digest, in hex, of the DER encoding of the certificate the
+    /// source must present.
+    pub source_cert_sha256: String,
+}
+
+/// Limits on how a migration source throttles its vCPUs so that a guest which
+/// dirties memory quickly can still be migrated.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct MigrationAutoConverge {
+    /// The number of consecutive rounds in which the guest dirties memory at
+    /// least as fast as it is sent before throttling begins or increases.
+    pub trigger_rounds: u32,
+    /// The percentage of vCPU time taken from the guest when throttling
+    /// begins.
+    pub initial_throttle_pct: u8,
+    /// The percentage by which throttling increases each time it is raised.
+    pub throttle_step_pct: u8,
+    /// The greatest percentage of vCPU time that may be taken from the guest.
+    /// Values above 99 are treated as 99.
+    pub max_throttle_pct: u8,
+    /// The most rounds of memory to send before pausing the guest whether or
+    /// not the migration has converged.
+    pub max_rounds: u32,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
@@ -88,10 +222,160 @@ pub struct InstanceMigrateStatusRequest {
     pub migration_id: Uuid,
 }
 
+/// Options for a connection from the destination of a migration to its source.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct MigrationConnectionQuery {
+    /// Set if the connection is secured with TLS once upgraded.
+    #[serde(default)]
+    pub tls: bool,
+}
+
+/// A limit on the rate at which live migrations out of an instance send guest
+/// memory.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMigrateBandwidthRequest {
+    /// The limit in megabits per second, or none to remove any limit.
+    pub max_bandwidth_mbps: Option<u64>,
+}
+
+/// A request to check whether an instance could be migrated to this server.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMigrateCheckRequest {
+    /// The address of the server running the source instance.
+    pub src_addr: SocketAddr,
+    /// The spec of the instance that would be created here to receive the
+    /// migration.  If absent, the spec of this server's instance is used.
+    pub instance_spec: Option<VersionedInstanceSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMigrateCheckResponse {
+    /// The reasons a migration from the source would fail.  A migration is
+    /// expected to succeed if there are none.
+    pub incompatibilities: Vec<String>,
+}
+
+/// Names a file holding an instance's saved state.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSavedStateRequest {
+    /// The name of the file, within the directory configured on the server
+    /// for saved state.
+    pub name: String,
+}
+
+/// What the server does with its instances when it's asked to shut down.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerShutdownMode {
+    /// Save the state of each instance to a file named with the instance's ID,
+    /// within the directory configured on the server for saved state.
+    Save,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ServerShutdownRequest {
+    pub mode: ServerShutdownMode,
+}
+
+/// Names a file to which to write a dump of an instance's memory.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMemoryDumpRequest {
+    /// The name of the file, within the directory configured on the server
+    /// for memory dumps.  No file of that name may exist already.
+    pub name: String,
+
+    /// Whether to pause the instance while its memory is dumped, so that the
+    /// dump is consistent.
+    #[serde(default)]
+    pub pause: bool,
+}
+
+/// Options for a dump of an instance's memory streamed to the client.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMemoryDumpQuery {
+    /// Whether to pause the instance while its memory is dumped, so that the
+    /// dump is consistent.
+    #[serde(default)]
+    pub pause: bool,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
 pub struct InstanceMigrateStatusResponse {
     pub migration_id: Uuid,
     pub state: MigrationState,
+    /// If the migration failed, how and where it did.
+    pub failure: Option<MigrationFailure>,
+}
+
+/// A description of a failed migration, as seen by the instance on one side
+/// of it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct MigrationFailure {
+    /// The kind of error which ended the migration.
+    pub cause: MigrationFailureCause,
+    /// The phase the migration was in when it failed.
+    pub phase: MigrationState,
+    /// A description of the error, meant for people rather than programs.
+    pub message: String,
+}
+
+/// The kinds of error which can end a migration.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum MigrationFailureCause {
+    /// The other instance refused the migration before it began, e.g.
+    /// because the two have no protocol version in common or their instance
+    /// specs are incompatible.
+    OfferRejected,
+    /// The connection failed while guest memory was being transferred.
+    RamTransferIo,
+    /// The connection failed, or carried an unexpected message, outside the
+    /// transfer of guest memory.
+    Connection,
+    /// The other instance stopped responding.
+    Timeout,
+    /// A device's state could not be moved, because the destination's
+    /// device can't import it, the device doesn't exist there, or the device
+    /// can't be migrated at all.
+    DeviceIncompatible,
+    /// The other instance reported that the migration failed on its side.
+    Remote,
+    /// The migration was cancelled through the API.
+    Cancelled,
+    /// This instance ran into an error of its own, such as failing to pause
+    /// or to read the guest's time data.
+    Internal,
+}
+
+/// Progress of a live migration, as seen by the instance on either side of it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct InstanceMigrateProgressResponse {
+    pub migration_id: Uuid,
+    /// The migration's current phase.
+    pub state: MigrationState,
+    /// The number of bytes of guest memory transferred so far.
+    pub bytes_transferred: u64,
+    /// The number of RAM transfer rounds begun so far: one (or, if the
+    /// migration auto-converges, several) while RAM is pushed with the guest
+    /// running, and another for the pages dirtied in the meantime once it is
+    /// paused.
+    pub iteration: u32,
+    /// The number of pages offered in the current RAM transfer round that are
+    /// yet to be transferred.
+    pub dirty_pages_remaining: u64,
+    /// The number of pages offered in each RAM transfer round begun so far,
+    /// in order.
+    pub dirty_pages_per_iteration: Vec<u64>,
+    /// The time, in milliseconds, needed to transfer the remaining dirty pages
+    /// at the average rate achieved so far, if any remain.
+    pub estimated_remaining_ms: Option<u64>,
+    /// On the destination, once the guest's time data has been imported: how
+    /// far, in milliseconds, the guest's clocks were ahead of the destination
+    /// host's, which happens when the destination's clock is behind the
+    /// source's.  The guest's clocks are resynchronized with the host's
+    /// before it resumes.
+    pub clock_skew_ms: Option<u64>,
 }
 
 #[derive(
@@ -124,11 +408,21 @@ pub struct InstanceGetResponse {
     pub instance: Instance,
 }
 
+/// The instances a server hosts in addition to its primary instance.
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceListResponse {
+    pub instances: Vec<Instance>,
+}
+
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
 pub struct InstanceSpecGetResponse {
     pub properties: InstanceProperties,
     pub state: InstanceState,
     pub spec: VersionedInstanceSpec,
+    /// The number of times the spec has changed since the instance was
+    /// created, as devices were attached or detached or their backends were
+    /// replaced.
+    pub spec_generation: u64,
 }
 
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
@@ -156,6 +450,102 @@ pub enum InstanceStateRequested {
     Reboot,
 }
 
+/// A request to shut an instance down by pressing its ACPI power button.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceShutdownRequest {
+    /// How long, in seconds, to wait for the guest to power off before
+    /// stopping the instance forcibly.
+    pub grace_period_secs: u64,
+}
+
+/// Something that happened to an instance, recorded in its history.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InstanceEventKind {
+    /// The instance's state changed.
+    StateChanged { state: InstanceState },
+
+    /// A migration into or out of the instance moved to a new phase, or
+    /// failed.
+    MigrationStateChanged {
+        migration_id: Uuid,
+        state: MigrationState,
+        failure: Option<MigrationFailure>,
+    },
+
+    /// A request to change the instance was made, through the API or by the
+    /// server itself (e.g. to stop the source of a finished migration).
+    Request {
+        request: String,
+        /// Why the request was refused, if it was.
+        denied: Option<String>,
+    },
+
+    /// The guest halted, rebooted, or faulted.
+    Guest { event: String },
+
+    /// A device, or the backend of one, failed or stalled.
+    DeviceError { device: String, error: String },
+}
+
+/// An entry in an instance's history.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceEvent {
+    /// The event's position in the history, starting from 0.
+    pub seq: u64,
+    /// When the event happened, in milliseconds since the Unix epoch.
+    pub time_ms: u64,
+    pub kind: InstanceEventKind,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceHistoryResponse {
+    /// The most recent events, oldest first.
+    pub events: Vec<InstanceEvent>,
+    /// The number of older events discarded to bound the history's size.
+    pub dropped: u64,
+}
+
+/// The health of the server, as reported by its probe endpoints.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct HealthResponse {
+    /// The number of the server's instances whose VM controllers are
+    /// initialized.
+    pub instances: u32,
+}
+
+/// Request to register a URL to be sent notifications of changes to the
+/// instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct WebhookRegistration {
+    /// The `http` or `https` URL to which to POST notifications.
+    pub url: String,
+
+    /// A secret with which to sign each notification. If set, each request
+    /// carries an `X-Propolis-Signature` header holding `sha256=` and the
+    /// hex-encoded HMAC-SHA256 of the request body keyed by this secret.
+    pub secret: Option<String>,
+}
+
+/// A URL registered to be sent notifications of changes to the instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Whether notifications to this URL are signed.
+    pub signed: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct WebhookPathParams {
+    pub id: Uuid,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct InstancePathParams {
+    pub instance_id: Uuid,
+}
+
 /// Current state of an Instance.
 #[derive(
     Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
@@ -167,6 +557,7 @@ pub enum InstanceState {
     Stopping,
     Stopped,
     Rebooting,
+    Paused,
     Migrating,
     Repairing,
     Failed,
@@ -201,6 +592,19 @@ impl InstanceProperties {
     }
 }
 
+/// Changes to the properties of an instance. Properties left unset are not
+/// changed.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstancePropertiesUpdateRequest {
+    /// The instance's new name.
+    pub name: Option<String>,
+    /// The instance's new description.
+    pub description: Option<String>,
+    /// The instance's new metadata, which is attached to the metrics reported
+    /// for it from then on.
+    pub metadata: Option<InstanceMetadata>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Instance {
     pub properties: InstanceProperties,
@@ -251,6 +655,27 @@ pub struct InstanceSerialConsoleStreamRequest {
     /// recently buffered data retrieved from the instance. (See note on `from_start` about mutual
     /// exclusivity)
     pub most_recent: Option<u64>,
+    /// If true, attach to the console as an observer: output is streamed as
+    /// usual, but input sent over the websocket is discarded. Any number of
+    /// read-only clients may be attached at once, while only one read-write
+    /// client (the default) may be attached at a time.
+    pub read_only: Option<bool>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SerialPortPathParams {
+    /// The serial port to access.
+    pub port: instance_spec::components::devices::SerialPortNumber,
+}
+
+/// Request to send a break to one of an instance's serial ports.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct SerialBreakRequest {
+    /// A key to send immediately after the break. Guests such as Linux treat
+    /// this as a magic SysRq command (for instance, `b` to reboot or `c` to
+    /// crash), while others, such as illumos, enter their kernel debugger on
+    /// the break alone.
+    pub sysrq: Option<char>,
 }
 
 /// Control message(s) sent through the websocket to serial console clients.
@@ -262,7 +687,49 @@ pub struct InstanceSerialConsoleStreamRequest {
 /// of this type in order to consume it.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum InstanceSerialConsoleControlMessage {
-    Migrating { destination: SocketAddr, from_start: u64 },
+    Migrating {
+        destination: SocketAddr,
+        from_start: u64,
+    },
+    /// The size of the read-write client's terminal, sent to other clients
+    /// when it changes and to new clients when they attach.
+    WindowSize {
+        rows: u16,
+        cols: u16,
+    },
+    /// Precedes the output history replayed in response to a client's
+    /// `History` request, which follows in binary messages totalling `len`
+    /// bytes, starting at `from_start` bytes since instance start.
+    History {
+        from_start: u64,
+        len: u64,
+    },
+    /// A control request from this client could not be carried out.
+    Error {
+        message: String,
+    },
+}
+
+/// Control message(s) sent through the websocket by serial console clients.
+///
+/// Clients send console input in binary messages, and these requests as JSON
+/// in text messages.  As with [`InstanceSerialConsoleControlMessage`], clients
+/// must define their own copy of this type.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum InstanceSerialConsoleClientMessage {
+    /// The client's terminal has been resized.  Only honored from the
+    /// read-write client.
+    WindowSize { rows: u16, cols: u16 },
+    /// Sends a break to the serial port, as with the port's `break` endpoint.
+    /// Only honored from the read-write client.
+    Break { sysrq: Option<char> },
+    /// Replays a range of the port's output history to this client.  The
+    /// fields are as in [`InstanceSerialConsoleHistoryRequest`].
+    History {
+        from_start: Option<u64>,
+        most_recent: Option<u64>,
+        max_bytes: Option<u64>,
+    },
 }
 
 /// Describes how to connect to one or more storage agent services.
@@ -338,6 +805,14 @@ pub struct DiskRequest {
     // Crucible related opts
     pub volume_construction_request:
         crucible_client_types::VolumeConstructionRequest,
+
+    /// Whether the disk's volume is activated in the background, retrying
+    /// failed attempts, so that the instance may start before it has, holding
+    /// guest I/O to the disk until it does.  If not, the volume is activated
+    /// once as the instance starts, and the instance fails to start if that
+    /// fails.
+    #[serde(default)]
+    pub lazy_attach: bool,
 }
 
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
@@ -406,7 +881,402 @@ pub struct VolumeStatus {
     pub active: bool,
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct DiskPathParams {
+    /// The name of the disk's storage device in the instance spec.
+    pub name: String,
+}
+
+/// Request the contents of a range of an instance's disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskExportRequest {
+    /// Byte offset at which to start reading. Must be a multiple of the disk's
+    /// block size. Defaults to the beginning of the disk.
+    pub offset: Option<u64>,
+    /// Number of bytes to read. Must be a multiple of the disk's block size.
+    /// Defaults to reading through the end of the disk.
+    pub length: Option<u64>,
+}
+
+/// Request a snapshot of a file-backed disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskSnapshotRequest {
+    /// Name of the snapshot, a file created alongside the disk's backing file,
+    /// which must not already exist.  Defaults to a unique name derived from
+    /// that of the backing file.
+    pub name: Option<String>,
+}
+
+/// Request to replace the backend of one of an instance's disks.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskBackendReplaceRequest {
+    /// The name to give the new backend in the instance spec, which must not
+    /// be that of any existing backend.
+    pub backend_name: String,
+
+    /// The new backend, whose block size and capacity must match those of the
+    /// disk's existing backend.
+    pub backend: instance_spec::v0::StorageBackendV0,
+}
+
+/// Request to change the media in one of an instance's CD-ROM drives.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CdromMediaRequest {
+    /// The media to insert in place of any already in the drive, or `None` to
+    /// eject the drive's media.
+    pub media: Option<CdromMedia>,
+}
+
+/// Media to insert into a CD-ROM drive.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct CdromMedia {
+    /// The name to give the media's backend in the instance spec, which must
+    /// not be that of any existing backend.
+    pub backend_name: String,
+
+    /// The backend providing the contents of the media, whose block size must
+    /// match that of the drive's existing backend.
+    pub backend: instance_spec::v0::StorageBackendV0,
+}
+
+/// Request to attach a new NVMe disk to a running instance by inserting it
+/// into a PCIe hotplug slot.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskAttachRequest {
+    /// The disk to attach. Its PCI path must be device 0, function 0 of the
+    /// downstream bus of a bridge with an empty hotplug slot.
+    pub device: instance_spec::components::devices::NvmeDisk,
+
+    /// The disk's backend, which is given the name in the disk's
+    /// `backend_name`. This must not be the name of any existing backend.
+    pub backend: instance_spec::v0::StorageBackendV0,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct NicPathParams {
+    /// The name of the NIC's network device in the instance spec.
+    pub name: String,
+}
+
+/// Request to change the link state a virtio NIC reports to the guest.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicLinkStateRequest {
+    pub link_state: instance_spec::components::devices::NicLinkState,
+}
+
+/// Request to attach a new virtio NIC to a running instance by inserting it
+/// into a PCIe hotplug slot.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicAttachRequest {
+    /// The NIC to attach. Its PCI path must be device 0, function 0 of the
+    /// downstream bus of a bridge with an empty hotplug slot.
+    pub device: instance_spec::components::devices::VirtioNic,
+
+    /// The NIC's backend, which is given the name in the NIC's
+    /// `backend_name`. This must be a virtio (viona) backend, and must not
+    /// share its name with any existing backend.
+    pub backend: instance_spec::v0::NetworkBackendV0,
+}
+
+/// The instance spec components describing a device to attach to a running
+/// instance, along with its backend.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, tag = "type", content = "component")]
+pub enum DeviceSpecFragment {
+    Storage {
+        device: instance_spec::v0::StorageDeviceV0,
+        backend: instance_spec::v0::StorageBackendV0,
+    },
+    Network {
+        device: instance_spec::v0::NetworkDeviceV0,
+        backend: instance_spec::v0::NetworkBackendV0,
+    },
+}
+
+/// Request to attach a new device to a running instance.
+///
+/// Only some kinds of device can be attached at runtime; the rest are
+/// refused. The device is placed as its spec fragment directs, which for PCI
+/// devices means at device 0, function 0 of the downstream bus of a bridge
+/// with an empty hotplug slot.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DeviceAttachRequest {
+    /// The name to give the device in the instance spec.
+    pub name: String,
+
+    /// The device and its backend, which is given the name in the device's
+    /// `backend_name`. This must not be the name of any existing backend.
+    pub fragment: DeviceSpecFragment,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DevicePathParams {
+    /// The name of the device in the instance spec.
+    pub name: String,
+}
+
+/// A display resolution, in pixels.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq,
+)]
+pub struct DisplayResolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The state of an instance's display.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceDisplay {
+    /// The resolution suggested to the guest, both in the EDID it is offered
+    /// and as the size of its framebuffer before it is configured.
+    pub preferred: DisplayResolution,
+
+    /// The resolution of the framebuffer as configured by the guest, if it has
+    /// configured it.
+    pub current: Option<DisplayResolution>,
+}
+
+/// The result of a snapshot of a file-backed disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskSnapshotResponse {
+    /// Path of the file holding the snapshot.
+    pub path: String,
+}
+
+/// Request a snapshot of all of an instance's disks, taken together.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSnapshotRequest {
+    /// If set, the guest's file systems are frozen through its agent while the
+    /// disks are snapshotted, and thawed afterwards.
+    #[serde(default)]
+    pub quiesce: bool,
+}
+
+/// The snapshot of one of an instance's disks taken with the others.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum DiskGroupSnapshot {
+    /// A clone of the disk's backing file, at `path`.
+    File { path: String },
+    /// A snapshot of the disk's Crucible volume, named by the ID of the
+    /// instance's snapshot.
+    Crucible,
+}
+
+/// The result of a snapshot of all of an instance's disks.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSnapshotResponse {
+    /// The ID of the snapshot, which names the snapshots of its disks.
+    pub snapshot_id: Uuid,
+    /// The snapshots of the instance's disks, keyed by the names of their
+    /// storage devices.  Read-only disks, CD-ROMs, and disks with other kinds
+    /// of backends aren't snapshotted.
+    pub disks: BTreeMap<String, DiskGroupSnapshot>,
+    /// Whether the guest's file systems were frozen while the snapshot was
+    /// taken.
+    pub quiesced: bool,
+}
+
+/// The result of a clone of an instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceCloneResponse {
+    /// The ID of the clone, which names the copies of the instance's disks.
+    pub clone_id: Uuid,
+    /// The spec with which to create the clone: the instance's spec, with
+    /// each writable file-backed disk backed by a copy of its file instead.
+    pub instance_spec: VersionedInstanceSpec,
+    /// The paths of the copies made, keyed by the names of the storage
+    /// backends they back in `instance_spec`.
+    pub copies: BTreeMap<String, String>,
+}
+
+/// The state of one of an instance's disks.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type", content = "value")]
+pub enum DiskState {
+    /// The disk's backend has not been started.
+    Inactive,
+    /// The disk's backend is being attached, after `attempts` failed attempts
+    /// (the most recent of which failed with `last_error`).  Guest I/O to the
+    /// disk is held until it succeeds.
+    Attaching { attempts: u32, last_error: Option<String> },
+    /// The disk's backend is attached and servicing guest I/O.
+    Active,
+    /// Attaching the disk's backend failed after `attempts` attempts, the last
+    /// with an `error` which retrying would not fix.  Guest I/O to the disk
+    /// fails.
+    Failed { attempts: u32, error: String },
+}
+
+/// The health of one of the replicas of a Crucible disk.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaState {
+    /// The replica is being connected to.
+    Connecting,
+    /// The replica is servicing I/O.
+    Active,
+    /// The replica is being repaired to match the others, and does not yet
+    /// service reads.
+    LiveRepair,
+    /// The replica is unreachable.
+    Offline,
+    /// The replica has been faulted (or is being replaced), and is not
+    /// servicing I/O.
+    Faulted,
+}
+
+/// The status of one of an instance's disks.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskStatus {
+    pub state: DiskState,
+
+    /// For Crucible disks, the state of each replica of the disk's data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replicas: Option<Vec<ReplicaState>>,
+
+    /// Whether any replica of the disk is not active.  A degraded disk
+    /// continues to service I/O, but may become read-only (or unavailable)
+    /// should further replicas fail.
+    #[serde(default)]
+    pub degraded: bool,
+}
+
+/// The status of each of an instance's disks.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceDiskStatusResponse {
+    /// The status of each disk, keyed by the disk's name.
+    pub disks: BTreeMap<String, DiskStatus>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DevicePathParams {
+    /// The name of the device in the instance spec.
+    pub name: String,
+}
+
+/// One bucket of a latency histogram.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct LatencyBucket {
+    /// Lower bound (inclusive) of the latencies counted in this bucket, in
+    /// nanoseconds.  Each bucket extends to the start of the next; the last
+    /// has no upper bound.
+    pub start_ns: u64,
+    /// Number of events whose latency fell in this bucket.
+    pub count: u64,
+}
+
+/// Activity counters for one of a virtio device's virtqueues, accumulated
+/// since the instance started.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct VirtqueueStats {
+    /// The queue's index within the device.
+    pub queue_id: u16,
+    /// Notifications from the guest driver that buffers are available.
+    pub kicks: u64,
+    /// Interrupts sent to the guest driver for completed buffers.
+    pub interrupts: u64,
+    /// Descriptor chains processed and returned to the guest driver.
+    pub chains: u64,
+    /// Descriptors making up those chains.
+    pub descriptors: u64,
+    /// Total size, in bytes, of the buffers in those chains.
+    pub bytes: u64,
+    /// Distribution of the time from the driver's kick to the return of each
+    /// chain to the guest.
+    pub latency: Vec<LatencyBucket>,
+}
+
+/// Per-queue statistics for a virtio device.
+///
+/// The rings of virtio NICs are processed in the host kernel, so only their
+/// kicks (and interrupts delivered through legacy interrupt pins) are counted.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct VirtioDeviceStats {
+    pub queues: Vec<VirtqueueStats>,
+}
+
+/// A command to run in the guest through its agent.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestExecRequest {
+    /// The path of the program to run.
+    pub path: String,
+    /// The arguments passed to the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Variables added to the program's environment, as `NAME=value`.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Base64-encoded data written to the program's standard input.
+    pub input: Option<String>,
+    /// How long to wait for the program to exit, in seconds.  Defaults to 30.
+    pub timeout_secs: Option<u64>,
+}
+
+/// The result of a command run in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestExecResponse {
+    /// The program's exit status, if it exited normally.
+    pub exit_code: Option<i32>,
+    /// The signal which terminated the program, if one did.
+    pub signal: Option<i32>,
+    /// The base64-encoded standard output of the program.
+    pub stdout: String,
+    /// The base64-encoded standard error of the program.
+    pub stderr: String,
+    /// Whether the agent kept only the start of the program's output.
+    pub output_truncated: bool,
+}
+
+/// Request to read part of a file in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestFileReadRequest {
+    pub path: String,
+    /// Byte offset at which to start reading.  Defaults to the start of the
+    /// file.
+    pub offset: Option<u64>,
+    /// The most bytes to read, up to 1 MiB.  Defaults to 64 KiB.
+    pub max_bytes: Option<u64>,
+}
+
+/// Data read from a file in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestFileReadResponse {
+    /// The base64-encoded data read.
+    pub data: String,
+    /// Whether the end of the file was reached.
+    pub eof: bool,
+}
+
+/// Request to write a file in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestFileWriteRequest {
+    /// The path of the file, which is created if it doesn't exist.
+    pub path: String,
+    /// The base64-encoded data to write.
+    pub data: String,
+    /// If set, the data is appended to the file's contents rather than
+    /// replacing them.
+    #[serde(default)]
+    pub append: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestFileWriteResponse {
+    pub bytes_written: u64,
+}
+
+/// The result of freezing or thawing the guest's file systems.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestFsFreezeResponse {
+    /// The number of file systems frozen or thawed.
+    pub filesystems: u64,
+}
+
 /// Error codes used to populate the `error_code` field of Dropshot API responses.
+///
+/// Clients can use these to decide how to handle a failed request without
+/// parsing its message.
 #[derive(
     Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
 )]
@@ -421,6 +1291,88 @@ pub enum ErrorCode {
     AlreadyRunning,
     /// Instance creation failed
     CreateFailed,
+    /// The server already hosts as many instances as it may.
+    InstanceLimitReached,
+    /// The instance isn't running: it hasn't started yet, or has stopped.
+    InstanceNotActive,
+    /// The instance failed to start, or halted because of a failure.
+    InstanceFailed,
+    /// The instance is stopping, or has been asked to stop.
+    HaltPending,
+    /// The instance is starting.
+    StartInProgress,
+    /// The request can't be carried out while the instance is paused.
+    InstancePaused,
+    /// A migration into or out of the instance is in progress.
+    MigrationInProgress,
+    /// No migration into or out of the instance is in progress.
+    NoMigrationInProgress,
+    /// The instance can no longer be the target of a migration, because it
+    /// has started or a migration into it has already finished or failed.
+    NotMigrationTarget,
+    /// A migration into or out of the instance failed.
+    MigrationFailed,
+    /// The instance has no device or backend with the name given.
+    NoSuchDevice,
+    /// No webhook is registered with the ID given.
+    NoSuchWebhook,
+    /// A device is already attached at the PCI path given.
+    PciSlotInUse,
+    /// A snapshot of one of the instance's disks is already being taken.
+    SnapshotInProgress,
+    /// A storage backend couldn't be opened, or its storage couldn't be
+    /// reached.
+    BackendUnreachable,
+    /// The request is invalid, or asks for something the instance doesn't
+    /// support.
+    InvalidRequest,
+    /// The server failed to carry out the request.
+    OperationFailed,
+    /// The request carries no bearer token, or one the server doesn't accept.
+    Unauthorized,
+    /// The request's bearer token doesn't grant the access it needs.
+    Forbidden,
+    /// Too many requests like this one are already being served; the request
+    /// may be retried later.
+    TooManyRequests,
+    /// The instance has no guest agent, or its agent isn't responding.
+    GuestAgentUnavailable,
+    /// The guest agent failed to carry out the request.
+    GuestAgentFailed,
+    /// An instance's state driver has stopped handling requests promptly.
+    StateDriverUnresponsive,
+}
+
+impl ErrorCode {
+    const ALL: &'static [ErrorCode] = &[
+        Self::NoInstance,
+        Self::AlreadyInitialized,
+        Self::AlreadyRunning,
+        Self::CreateFailed,
+        Self::InstanceLimitReached,
+        Self::InstanceNotActive,
+        Self::InstanceFailed,
+        Self::HaltPending,
+        Self::StartInProgress,
+        Self::InstancePaused,
+        Self::MigrationInProgress,
+        Self::NoMigrationInProgress,
+        Self::NotMigrationTarget,
+        Self::MigrationFailed,
+        Self::NoSuchDevice,
+        Self::NoSuchWebhook,
+        Self::PciSlotInUse,
+        Self::SnapshotInProgress,
+        Self::BackendUnreachable,
+        Self::InvalidRequest,
+        Self::OperationFailed,
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::TooManyRequests,
+        Self::GuestAgentUnavailable,
+        Self::GuestAgentFailed,
+        Self::StateDriverUnresponsive,
+    ];
 }
 
 impl fmt::Display for ErrorCode {
@@ -432,20 +1384,27 @@ impl fmt::Display for ErrorCode {
 impl std::str::FromStr for ErrorCode {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.trim() {
-            s if s.eq_ignore_ascii_case("NoInstance") => Ok(Self::NoInstance),
-            s if s.eq_ignore_ascii_case("AlreadyInitialized") => {
-                Ok(ErrorCode::AlreadyInitialized)
-            }
-            s if s.eq_ignore_ascii_case("AlreadyRunning") => {
-                Ok(ErrorCode::AlreadyRunning)
-            }
-            s if s.eq_ignore_ascii_case("CreateFailed") => {
-                Ok(ErrorCode::CreateFailed)
-            }
-            _ => Err("unknown error code, expected one of: \
-                'NoInstance', 'AlreadyInitialized', 'AlreadyRunning', \
-                'CreateFailed'"),
+        let s = s.trim();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|code| s.eq_ignore_ascii_case(&code.to_string()))
+            .ok_or("unknown error code")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_codes_parsed_from_their_names() {
+        for &code in ErrorCode::ALL {
+            let name = code.to_string();
+            assert_eq!(name.parse::<ErrorCode>(), Ok(code));
+            let padded = format!(" {} ", name.to_lowercase());
+            assert_eq!(padded.parse::<ErrorCode>(), Ok(code));
         }
+        assert!("NoSuchErrorCode".parse::<ErrorCode>().is_err());
     }
 }
@@ -34,6 +34,42 @@ pub struct Config {
 
     #[serde(default, rename = "cpuid")]
     pub cpuid_profiles: BTreeMap<String, CpuidProfile>,
+
+    #[serde(default)]
+    pub serial_log: Option<SerialLog>,
+
+    #[serde(default)]
+    pub keyboard: Option<Keyboard>,
+
+    #[serde(default)]
+    pub vnc: Option<Vnc>,
+
+    #[serde(default)]
+    pub recording: Option<Recording>,
+
+    #[serde(default)]
+    pub migration: Option<Migration>,
+
+    #[serde(default)]
+    pub saved_state: Option<SavedState>,
+
+    #[serde(default)]
+    pub memory_dump: Option<MemoryDump>,
+
+    #[serde(default)]
+    pub auth: Option<Auth>,
+
+    #[serde(default)]
+    pub tls: Option<Tls>,
+
+    #[serde(default)]
+    pub multi_instance: Option<MultiInstance>,
+
+    #[serde(default)]
+    pub guest_agent: Option<GuestAgent>,
+
+    #[serde(default)]
+    pub snapshot_schedule: Option<SnapshotSchedule>,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -45,6 +81,18 @@ impl Default for Config {
             devices: BTreeMap::new(),
             block_devs: BTreeMap::new(),
             cpuid_profiles: BTreeMap::new(),
+            serial_log: None,
+            keyboard: None,
+            vnc: None,
+            recording: None,
+            migration: None,
+            saved_state: None,
+            memory_dump: None,
+            auth: None,
+            tls: None,
+            multi_instance: None,
+            guest_agent: None,
+            snapshot_schedule: None,
         }
     }
 }
@@ -66,6 +114,234 @@ impl Chipset {
     }
 }
 
+/// Capture of the output of an instance's serial ports to files on the host.
+///
+/// Output is written whether or not any client is attached to a port.  Each
+/// port's output goes to `<directory>/<instance-id>-<port>.log`; once that file
+/// reaches `max_file_size` bytes it is renamed with a `.1` suffix (and any
+/// older files shifted to `.2`, `.3`, and so on), keeping at most `max_files`
+/// of these rotated files.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerialLog {
+    pub directory: PathBuf,
+
+    #[serde(default = "SerialLog::default_max_file_size")]
+    pub max_file_size: u64,
+
+    #[serde(default = "SerialLog::default_max_files")]
+    pub max_files: u32,
+}
+
+impl SerialLog {
+    fn default_max_file_size() -> u64 {
+        1024 * 1024
+    }
+
+    fn default_max_files() -> u32 {
+        4
+    }
+}
+
+/// Translation of the keys pressed by VNC clients for the guest's keyboard
+/// layout.
+///
+/// VNC clients send the symbol (keysym) each key produces on the client's
+/// layout, which must be translated to the key producing that symbol on the
+/// guest's layout.  `layout` selects one of the built-in layouts ("us", the
+/// default, "de" or "gb"), and `keysyms` adds to or overrides its entries,
+/// mapping keysyms (as hex strings, such as "0x20ac") to the Scan Code Set 1
+/// make code of a key, with `0xe0` in the upper byte for extended keys.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Keyboard {
+    pub layout: Option<String>,
+
+    #[serde(default)]
+    pub keysyms: BTreeMap<String, u16>,
+}
+
+/// Options for the VNC server exposing the instance's display.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Vnc {
+    /// The name of a port of the instance's virtio-console device to which
+    /// text cut by VNC clients is passed, so that a guest agent may place it
+    /// on the guest's clipboard.
+    pub clipboard_port: Option<String>,
+}
+
+/// Recording of the instance's console sessions to files on the host, for
+/// audit and postmortem debugging.
+///
+/// Unless `serial` is false, each serial port's output is recorded to
+/// `<directory>/<instance-id>-<port>.ttyrec` in the ttyrec format, which
+/// players such as `ttyplay` replay with its original timing.  If
+/// `framebuffer_interval_secs` is set, the guest's framebuffer is captured
+/// that often to `<directory>/<instance-id>-fb-<unix-time-ms>.png`, skipping
+/// frames unchanged since the last capture.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Recording {
+    pub directory: PathBuf,
+
+    #[serde(default = "Recording::default_serial")]
+    pub serial: bool,
+
+    #[serde(default)]
+    pub framebuffer_interval_secs: Option<u64>,
+}
+
+impl Recording {
+    fn default_serial() -> bool {
+        true
+    }
+}
+
+/// Options for live migrations out of the instance.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Migration {
+    /// The rate, in megabits per second, to which the transfer of guest memory
+    /// is limited so that it does not crowd out other traffic on the links it
+    /// shares.  The limit can be changed at runtime through the API.
+    pub max_bandwidth_mbps: Option<u64>,
+
+    /// The algorithms ("lz4" or "zstd") offered to the destination for
+    /// compressing pages of guest memory, in order of preference.  Pages are
+    /// sent uncompressed if this is empty or the destination supports none of
+    /// them.
+    #[serde(default)]
+    pub page_compression: Vec<String>,
+    /// The number of connections, including the main migration connection,
+    /// over which guest memory is transferred in parallel.  A migration uses
+    /// the lesser of the values configured for its source and destination, and
+    /// a single connection if this is unset.
+    pub ram_streams: Option<u32>,
+
+    /// PEM files holding the certificate chain and private key with which
+    /// this server serves migrations out of the instance over TLS, and with
+    /// which it authenticates itself to the source of a migration into the
+    /// instance over TLS.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+
+    /// A PEM file of CA certificates.  If set, migrations out of the instance
+    /// must use TLS, and the destination must present a certificate issued by
+    /// one of these CAs.
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// The name of a port of the instance's virtio-console device on which a
+    /// QEMU-compatible guest agent listens.  When a migration into the
+    /// instance completes, the agent is asked to set the guest's system clock
+    /// from the host's.
+    pub time_sync_port: Option<String>,
+}
+
+/// Saving of the instance's state to files on the host, and restoring of
+/// instances from them, through `POST /instance/save` and
+/// `POST /instance/restore`.  Requests name a file within `directory`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SavedState {
+    pub directory: PathBuf,
+}
+
+/// Dumping of the instance's memory to files on the host through
+/// `POST /instance/memory-dump`.  Requests name a new file within `directory`,
+/// which should not be the directory configured for saved state.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct MemoryDump {
+    pub directory: PathBuf,
+}
+
+/// Authorization of requests to the server's API.
+///
+/// When this section is present, each request must carry one of the
+/// configured tokens as a bearer token (`Authorization: Bearer <token>`), and
+/// the token must grant the scope required by the endpoint requested.
+/// Requests which only report on the instance are allowed with any of the
+/// tokens.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Auth {
+    /// A file holding the token this server presents to the source of a
+    /// migration into its instance.  The source must accept it with the
+    /// `migration` scope.
+    pub migration_token_file: Option<PathBuf>,
+
+    #[serde(default, rename = "token")]
+    pub tokens: Vec<AuthToken>,
+}
+
+/// A token accepted by the server, read from `file` (ignoring surrounding
+/// whitespace) when the server starts.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AuthToken {
+    pub file: PathBuf,
+    pub scopes: Vec<AuthScope>,
+}
+
+/// The sets of endpoints to which a token may grant access.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthScope {
+    /// The instance's serial consoles and display.
+    Console,
+
+    /// Creating the instance and changing its state, devices and disks.
+    Lifecycle,
+
+    /// Migrations into and out of the instance, including the connections
+    /// made to the source of a migration by its destination.
+    Migration,
+}
+
+/// Serving of the server's API, including its WebSocket endpoints, over TLS.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Tls {
+    /// PEM files holding the certificate chain and private key with which the
+    /// API is served.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+
+    /// A PEM file of CA certificates.  If set, the sources of migrations into
+    /// the instance are expected to serve their APIs over TLS too, and are
+    /// connected to only if they present a certificate issued by one of these
+    /// CAs for their address.
+    pub peer_ca: Option<PathBuf>,
+}
+
+/// Hosting of instances addressed by ID, through the `/instances/{id}`
+/// endpoints, alongside the server's primary instance.  Each is created from
+/// this configuration, as the primary instance is, but has no VNC server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct MultiInstance {
+    /// The most instances, including those stopped but not yet deleted, that
+    /// may be addressed by ID at once.
+    pub max_instances: usize,
+}
+
+/// The agent in the guest through which the API runs commands in the guest,
+/// reads and writes files in it, and freezes its file systems.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct GuestAgent {
+    /// The name of a port of the instance's virtio-console device on which a
+    /// QEMU-compatible guest agent listens.
+    pub port: String,
+}
+
+/// Snapshots of all of the instance's disks, taken together every
+/// `interval_secs` seconds while the instance runs, as they are by
+/// `POST /instance/snapshot`.  If `quiesce` is set, the guest's file systems
+/// are frozen through its agent while each snapshot is taken.  If `keep` is
+/// set, the snapshots of file-backed disks taken on this schedule are removed
+/// once `keep` newer ones have been taken; otherwise (and for snapshots of
+/// Crucible volumes) old snapshots are not removed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SnapshotSchedule {
+    pub interval_secs: u64,
+
+    #[serde(default)]
+    pub quiesce: bool,
+
+    #[serde(default)]
+    pub keep: Option<usize>,
+}
+
 /// A PCI-PCI bridge.
 #[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct PciBridge {
@@ -81,6 +357,11 @@ pub struct PciBridge {
     /// set by the guest at runtime.
     #[serde(rename = "downstream-bus")]
     pub downstream_bus: u8,
+
+    /// If set, the physical slot number of a PCIe hotplug slot beneath this
+    /// bridge.
+    #[serde(rename = "hotplug-slot", default)]
+    pub hotplug_slot: Option<u16>,
 }
 
 /// A hard-coded device, either enabled by default or accessible locally
@@ -180,6 +461,63 @@ slump = "4in"
 [block_dev.block1]
 type = "file"
 path = "/etc/passwd"
+
+[serial_log]
+directory = "/var/log/propolis"
+max_files = 2
+
+[keyboard]
+layout = "de"
+keysyms = { "0x20ac" = 0x12 }
+
+[vnc]
+clipboard_port = "org.propolis.clipboard"
+
+[recording]
+directory = "/var/log/propolis/recordings"
+framebuffer_interval_secs = 10
+
+[migration]
+max_bandwidth_mbps = 2000
+page_compression = ["zstd", "lz4"]
+ram_streams = 4
+tls_cert = "/etc/propolis/migration.crt"
+tls_key = "/etc/propolis/migration.key"
+tls_client_ca = "/etc/propolis/migration-ca.crt"
+time_sync_port = "org.qemu.guest_agent.0"
+
+[saved_state]
+directory = "/var/lib/propolis/saved"
+
+[memory_dump]
+directory = "/var/lib/propolis/dumps"
+
+[auth]
+migration_token_file = "/etc/propolis/migration.token"
+
+[[auth.token]]
+file = "/etc/propolis/control-plane.token"
+scopes = ["lifecycle", "migration"]
+
+[[auth.token]]
+file = "/etc/propolis/console.token"
+scopes = ["console"]
+
+[tls]
+cert = "/etc/propolis/api.crt"
+key = "/etc/propolis/api.key"
+peer_ca = "/etc/propolis/api-ca.crt"
+
+[multi_instance]
+max_instances = 16
+
+[guest_agent]
+port = "org.qemu.guest_agent.0"
+
+[snapshot_schedule]
+interval_secs = 3600
+quiesce = true
+keep = 24
 "#;
         let cfg: Config = toml::de::from_str(raw).unwrap();
 
@@ -214,5 +552,92 @@ path = "/etc/passwd"
             bdev1.options.get("path").map(Value::as_str).unwrap(),
             Some("/etc/passwd")
         );
+
+        let serial_log = cfg.serial_log.unwrap();
+        assert_eq!(serial_log.directory, PathBuf::from("/var/log/propolis"));
+        assert_eq!(serial_log.max_file_size, 1024 * 1024);
+        assert_eq!(serial_log.max_files, 2);
+
+        let keyboard = cfg.keyboard.unwrap();
+        assert_eq!(keyboard.layout.as_deref(), Some("de"));
+        assert_eq!(keyboard.keysyms.get("0x20ac"), Some(&0x12));
+
+        let vnc = cfg.vnc.unwrap();
+        assert_eq!(
+            vnc.clipboard_port.as_deref(),
+            Some("org.propolis.clipboard")
+        );
+
+        let recording = cfg.recording.unwrap();
+        assert_eq!(
+            recording.directory,
+            PathBuf::from("/var/log/propolis/recordings")
+        );
+        assert!(recording.serial);
+        assert_eq!(recording.framebuffer_interval_secs, Some(10));
+
+        let migration = cfg.migration.unwrap();
+        assert_eq!(migration.max_bandwidth_mbps, Some(2000));
+        assert_eq!(migration.page_compression, ["zstd", "lz4"]);
+        assert_eq!(migration.ram_streams, Some(4));
+        assert_eq!(
+            migration.tls_cert,
+            Some(PathBuf::from("/etc/propolis/migration.crt"))
+        );
+        assert_eq!(
+            migration.tls_key,
+            Some(PathBuf::from("/etc/propolis/migration.key"))
+        );
+        assert_eq!(
+            migration.tls_client_ca,
+            Some(PathBuf::from("/etc/propolis/migration-ca.crt"))
+        );
+        assert_eq!(
+            migration.time_sync_port.as_deref(),
+            Some("org.qemu.guest_agent.0")
+        );
+
+        let saved_state = cfg.saved_state.unwrap();
+        assert_eq!(
+            saved_state.directory,
+            PathBuf::from("/var/lib/propolis/saved")
+        );
+
+        let memory_dump = cfg.memory_dump.unwrap();
+        assert_eq!(
+            memory_dump.directory,
+            PathBuf::from("/var/lib/propolis/dumps")
+        );
+
+        let auth = cfg.auth.unwrap();
+        assert_eq!(
+            auth.migration_token_file,
+            Some(PathBuf::from("/etc/propolis/migration.token"))
+        );
+        assert_eq!(auth.tokens.len(), 2);
+        assert_eq!(
+            auth.tokens[0].scopes,
+            [AuthScope::Lifecycle, AuthScope::Migration]
+        );
+        assert_eq!(
+            auth.tokens[1].file,
+            PathBuf::from("/etc/propolis/console.token")
+        );
+
+        let tls = cfg.tls.unwrap();
+        assert_eq!(tls.cert, PathBuf::from("/etc/propolis/api.crt"));
+        assert_eq!(tls.key, PathBuf::from("/etc/propolis/api.key"));
+        assert_eq!(
+            tls.peer_ca,
+            Some(PathBuf::from("/etc/propolis/api-ca.crt"))
+        );
+
+        assert_eq!(cfg.multi_instance.unwrap().max_instances, 16);
+        assert_eq!(cfg.guest_agent.unwrap().port, "org.qemu.guest_agent.0");
+
+        let snapshot_schedule = cfg.snapshot_schedule.unwrap();
+        assert_eq!(snapshot_schedule.interval_secs, 3600);
+        assert!(snapshot_schedule.quiesce);
+        assert_eq!(snapshot_schedule.keep, Some(24));
     }
 }
@@ -41,6 +41,8 @@ fn main() {
         // C header currently lacks explicit pad fields
         ("vioc_ring_init", "_pad") => true,
         ("vioc_ring_msi", "_pad") => true,
+        ("vioc_intr_poll_mq", "_pad") => true,
+        ("vioc_mac_filter", "_pad") => true,
 
         _ => false,
     });
@@ -49,6 +51,8 @@ fn main() {
         // lack of explicit padding causes round-trip problems
         "vioc_ring_init" => true,
         "vioc_ring_msi" => true,
+        "vioc_intr_poll_mq" => true,
+        "vioc_mac_filter" => true,
 
         _ => false,
     });
@@ -76,6 +76,9 @@ impl VionaFd {
                 | ioctls::VNA_IOC_RING_PAUSE
                 | ioctls::VNA_IOC_RING_INTR_CLR
                 | ioctls::VNA_IOC_VERSION
+                | ioctls::VNA_IOC_SET_PAIRS
+                | ioctls::VNA_IOC_SET_USEPAIRS
+                | ioctls::VNA_IOC_SET_PROMISC
         )
     }
 }
@@ -107,6 +110,12 @@ unsafe fn ioctl(
 #[repr(u32)]
 #[derive(Copy, Clone)]
 pub enum ApiVersion {
+    /// Adds support for receive filtering (promiscuity, MAC and VLAN filters)
+    V4 = 4,
+
+    /// Adds support for multiple TX/RX queue pairs
+    V3 = 3,
+
     /// Adds support for non-vnic datalink devices
     V2 = 2,
 
@@ -115,7 +124,7 @@ pub enum ApiVersion {
 }
 impl ApiVersion {
     pub const fn current() -> Self {
-        Self::V2
+        Self::V4
     }
 }
 impl PartialEq<ApiVersion> for u32 {
@@ -22,14 +22,29 @@ pub mod ioctls {
     pub const VNA_IOC_SET_FEATURES: i32 = VNA_IOC | 0x21;
     pub const VNA_IOC_GET_FEATURES: i32 = VNA_IOC | 0x22;
     pub const VNA_IOC_SET_NOTIFY_IOP: i32 = VNA_IOC | 0x23;
+    pub const VNA_IOC_SET_PAIRS: i32 = VNA_IOC | 0x24;
+    pub const VNA_IOC_SET_USEPAIRS: i32 = VNA_IOC | 0x25;
+    pub const VNA_IOC_INTR_POLL_MQ: i32 = VNA_IOC | 0x26;
+    pub const VNA_IOC_SET_PROMISC: i32 = VNA_IOC | 0x27;
+    pub const VNA_IOC_SET_MAC_FILTER: i32 = VNA_IOC | 0x28;
+    pub const VNA_IOC_SET_VLAN_FILTER: i32 = VNA_IOC | 0x29;
 }
 
 pub const VIONA_VQ_MAX: u16 = 2;
+pub const VIONA_MAX_QPAIRS: u16 = 0x100;
+pub const VIONA_MAC_FILTER_MAX: u16 = 64;
+pub const VIONA_VLAN_MAX: u16 = 4096;
+
+pub const VIONA_PROMISC_NONE: u32 = 0;
+pub const VIONA_PROMISC_MULTI: u32 = 1;
+pub const VIONA_PROMISC_ALL: u32 = 2;
 
 mod structs {
     #![allow(non_camel_case_types)]
 
-    use super::VIONA_VQ_MAX;
+    use super::{
+        VIONA_MAC_FILTER_MAX, VIONA_MAX_QPAIRS, VIONA_VLAN_MAX, VIONA_VQ_MAX,
+    };
 
     #[repr(C)]
     pub struct vioc_create {
@@ -58,6 +73,26 @@ mod structs {
         pub vip_status: [u32; VIONA_VQ_MAX as usize],
     }
 
+    #[repr(C)]
+    pub struct vioc_intr_poll_mq {
+        pub vipm_nrings: u16,
+        pub _pad: [u16; 3],
+        pub vipm_status: [u32; (VIONA_MAX_QPAIRS as usize * 2) / 32],
+    }
+
+    #[repr(C)]
+    pub struct vioc_mac_filter {
+        pub vmf_nunicast: u16,
+        pub vmf_nmulticast: u16,
+        pub _pad: [u16; 2],
+        pub vmf_addrs: [[u8; 6]; VIONA_MAC_FILTER_MAX as usize],
+    }
+
+    #[repr(C)]
+    pub struct vioc_vlan_filter {
+        pub vvf_bitmap: [u32; VIONA_VLAN_MAX as usize / 32],
+    }
+
     #[repr(C)]
     #[derive(Default)]
     pub struct vioc_ring_state {
@@ -72,7 +107,7 @@ mod structs {
 /// This is the viona interface version which viona_api expects to operate
 /// against.  All constants and structs defined by the crate are done so in
 /// terms of that specific version.
-pub const VIONA_CURRENT_INTERFACE_VERSION: u32 = 2;
+pub const VIONA_CURRENT_INTERFACE_VERSION: u32 = 4;
 
 pub use ioctls::*;
 pub use structs::*;
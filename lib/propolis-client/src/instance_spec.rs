@@ -161,6 +161,16 @@ impl SpecBuilderV0 {
     pub fn add_serial_port(
         &mut self,
         port: SerialPortNumber,
+    ) -> Result<&Self, SpecBuilderError> {
+        self.add_serial_port_with_history_size(port, None)
+    }
+
+    /// Adds a serial port that retains up to `history_size` bytes of output
+    /// history (or the server's default amount if `None`).
+    pub fn add_serial_port_with_history_size(
+        &mut self,
+        port: SerialPortNumber,
+        history_size: Option<u64>,
     ) -> Result<&Self, SpecBuilderError> {
         if self
             .spec
@@ -174,7 +184,7 @@ impl SpecBuilderV0 {
                     SerialPortNumber::Com4 => "com4",
                 }
                 .to_string(),
-                SerialPort { num: port },
+                SerialPort { num: port, history_size },
             )
             .is_some()
         {
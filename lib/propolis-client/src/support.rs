@@ -19,9 +19,11 @@ use tokio_tungstenite::tungstenite::{Error as WSError, Message as WSMessage};
 pub use tokio_tungstenite::{tungstenite, WebSocketStream};
 
 use crate::types::{
-    Chipset, I440Fx, NetworkDeviceV0, PciPath, StorageDeviceV0,
+    Chipset, I440Fx, InstanceSerialConsoleHistoryResponse, NetworkDeviceV0,
+    PciPath, SerialPortNumber, StorageDeviceV0,
 };
 use crate::Client as PropolisClient;
+use uuid::Uuid;
 
 const PCI_DEV_PER_BUS: u8 = 32;
 const PCI_FUNC_PER_DEV: u8 = 8;
@@ -52,6 +54,8 @@ impl NetworkDeviceV0 {
     pub fn pci_path(&self) -> PciPath {
         match self {
             NetworkDeviceV0::VirtioNic(dev) => dev.pci_path,
+            NetworkDeviceV0::E1000Nic(dev) => dev.pci_path,
+            NetworkDeviceV0::PassthroughNic(dev) => dev.pci_path,
         }
     }
 }
@@ -60,6 +64,9 @@ impl StorageDeviceV0 {
         match self {
             StorageDeviceV0::VirtioDisk(dev) => dev.pci_path,
             StorageDeviceV0::NvmeDisk(dev) => dev.pci_path,
+            StorageDeviceV0::SataDisk(dev) => dev.pci_path,
+            StorageDeviceV0::SataCdrom(dev) => dev.pci_path,
+            StorageDeviceV0::IdeDisk(dev) => dev.pci_path,
         }
     }
 }
@@ -72,7 +79,50 @@ impl StorageDeviceV0 {
 /// manually duplicated for use.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum InstanceSerialConsoleControlMessage {
-    Migrating { destination: SocketAddr, from_start: u64 },
+    Migrating {
+        destination: SocketAddr,
+        from_start: u64,
+    },
+    /// The size of the read-write client's terminal, sent to other clients
+    /// when it changes and to new clients when they attach.
+    WindowSize {
+        rows: u16,
+        cols: u16,
+    },
+    /// Precedes the output history replayed in response to a
+    /// [`InstanceSerialConsoleClientMessage::History`] request, which follows
+    /// in [`WSMessage::Binary`] messages totalling `len` bytes.
+    History {
+        from_start: u64,
+        len: u64,
+    },
+    /// A control request from this client could not be carried out.
+    Error {
+        message: String,
+    },
+}
+
+/// Clone of `InstanceSerialConsoleClientMessage` type defined in
+/// `propolis_api_types`, with which this must be kept in sync.
+///
+/// These requests are sent to the server with
+/// [`InstanceSerialConsoleHelper::send_control`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum InstanceSerialConsoleClientMessage {
+    /// The client's terminal has been resized.  Only honored from the
+    /// read-write client.
+    WindowSize { rows: u16, cols: u16 },
+    /// Sends a break to the serial port, optionally followed by a magic SysRq
+    /// key.  Only honored from the read-write client.
+    Break { sysrq: Option<char> },
+    /// Replays a range of the port's output history to this client, starting
+    /// either `from_start` bytes since instance start or `most_recent` bytes
+    /// before the latest output (exactly one of which must be given).
+    History {
+        from_start: Option<u64>,
+        most_recent: Option<u64>,
+        max_bytes: Option<u64>,
+    },
 }
 
 /// A trait representing a console stream.
@@ -82,22 +132,53 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SerialConsoleStream for T {}
 /// Represents a way to build a serial console stream.
 #[async_trait::async_trait]
 pub(crate) trait SerialConsoleStreamBuilder: Send {
+    /// Connects to the serial console of the server at `address`, or of the
+    /// server the builder was created for if `address` is `None`.
     async fn build(
         &mut self,
-        address: SocketAddr,
+        address: Option<SocketAddr>,
         offset: WSClientOffset,
     ) -> Result<Box<dyn SerialConsoleStream>, WSError>;
 }
 
+/// Returns the scheme of the URLs through which `client` reaches its server.
+fn url_scheme(client: &PropolisClient) -> &str {
+    client.baseurl().split_once("://").map_or("http", |(scheme, _)| scheme)
+}
+
+fn no_address_error() -> WSError {
+    WSError::Http(http::Response::new(Some(
+        b"no server address to connect to".to_vec(),
+    )))
+}
+
 /// A serial console builder that uses a Propolis client to build the
 /// socket.
 #[derive(Debug)]
-struct PropolisSerialBuilder {}
+struct PropolisSerialBuilder {
+    port: SerialPortNumber,
+    read_only: bool,
+
+    /// The client through which the console was first reached, if any.
+    /// Connections to the destinations of the instance's migrations are made
+    /// with its HTTP client, and so with its TLS configuration and default
+    /// headers (e.g. those carrying a bearer token).
+    client: Option<PropolisClient>,
+}
 
 impl PropolisSerialBuilder {
     /// Creates a new `PropolisSerialBuilder`.
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(port: SerialPortNumber, read_only: bool) -> Self {
+        Self { port, read_only, client: None }
+    }
+
+    /// Creates a new `PropolisSerialBuilder` which connects through `client`.
+    pub fn with_client(
+        client: PropolisClient,
+        port: SerialPortNumber,
+        read_only: bool,
+    ) -> Self {
+        Self { port, read_only, client: Some(client) }
     }
 }
 
@@ -105,24 +186,46 @@ impl PropolisSerialBuilder {
 impl SerialConsoleStreamBuilder for PropolisSerialBuilder {
     async fn build(
         &mut self,
-        address: SocketAddr,
+        address: Option<SocketAddr>,
         offset: WSClientOffset,
     ) -> Result<Box<dyn SerialConsoleStream>, WSError> {
-        let client = PropolisClient::new(&format!("http://{}", address));
-        let mut req = client.instance_serial();
+        let client = match (address, &self.client) {
+            (None, Some(client)) => client.clone(),
+            (Some(address), Some(client)) => PropolisClient::new_with_client(
+                &format!("{}://{address}", url_scheme(client)),
+                client.client().clone(),
+            ),
+            (Some(address), None) => {
+                PropolisClient::new(&format!("http://{}", address))
+            }
+            (None, None) => return Err(no_address_error()),
+        };
 
-        match offset {
-            WSClientOffset::FromStart(offset) => {
-                req = req.from_start(offset);
+        // COM1 is reached through its original endpoint so that connecting
+        // to it works with servers that predate per-port access.
+        let res = if self.port == SerialPortNumber::Com1 {
+            let mut req = client.instance_serial();
+            if self.read_only {
+                req = req.read_only(true);
             }
-            WSClientOffset::MostRecent(offset) => {
-                req = req.most_recent(offset);
+            req = match offset {
+                WSClientOffset::FromStart(offset) => req.from_start(offset),
+                WSClientOffset::MostRecent(offset) => req.most_recent(offset),
+            };
+            req.send().await
+        } else {
+            let mut req = client.instance_serial_port().port(self.port);
+            if self.read_only {
+                req = req.read_only(true);
             }
-        }
+            req = match offset {
+                WSClientOffset::FromStart(offset) => req.from_start(offset),
+                WSClientOffset::MostRecent(offset) => req.most_recent(offset),
+            };
+            req.send().await
+        };
 
-        let upgraded = req
-            .send()
-            .await
+        let upgraded = res
             .map_err(|e| {
                 WSError::Http(http::Response::new(Some(
                     e.to_string().into_bytes(),
@@ -165,11 +268,12 @@ impl<St: SerialConsoleStream + 'static> SerialConsoleStreamBuilder
 {
     async fn build(
         &mut self,
-        address: SocketAddr,
+        address: Option<SocketAddr>,
         // offset is currently unused by this builder. Worth testing in
         // the future.
         _offset: WSClientOffset,
     ) -> Result<Box<dyn SerialConsoleStream>, WSError> {
+        let address = address.ok_or_else(no_address_error)?;
         if let Some((delay, stream)) =
             self.client_conns_and_delays.remove(&address)
         {
@@ -184,6 +288,7 @@ impl<St: SerialConsoleStream + 'static> SerialConsoleStreamBuilder
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum WSClientOffset {
     FromStart(u64),
     MostRecent(u64),
@@ -204,6 +309,18 @@ pub struct InstanceSerialConsoleHelper {
     stream_builder: Box<dyn SerialConsoleStreamBuilder>,
     ws_stream: WebSocketStream<Box<dyn SerialConsoleStream>>,
     log: Option<Logger>,
+
+    /// The address of the server to which the helper is connected, or `None`
+    /// if it's the server for which the helper's builder was created.
+    address: Option<SocketAddr>,
+
+    /// The offset, counted in bytes output since instance start, of the next
+    /// byte of live output, if known.
+    next_offset: Option<u64>,
+
+    /// The number of bytes of replayed history still to be received in
+    /// answer to a [`InstanceSerialConsoleClientMessage::History`] request.
+    replay_remaining: u64,
 }
 
 impl InstanceSerialConsoleHelper {
@@ -216,8 +333,53 @@ impl InstanceSerialConsoleHelper {
         offset: WSClientOffset,
         log: Option<Logger>,
     ) -> Result<Self, WSError> {
-        let stream_builder = PropolisSerialBuilder::new();
-        Self::new_with_builder(stream_builder, address, offset, log).await
+        Self::new_for_port(address, SerialPortNumber::Com1, offset, false, log)
+            .await
+    }
+
+    /// Like [`Self::new`], but attaches to the console in read-only mode:
+    /// output is received as usual, but any input sent is discarded by the
+    /// server. Unlike read-write clients, any number of read-only clients
+    /// may be attached to an instance's console at once.
+    pub async fn new_read_only(
+        address: SocketAddr,
+        offset: WSClientOffset,
+        log: Option<Logger>,
+    ) -> Result<Self, WSError> {
+        Self::new_for_port(address, SerialPortNumber::Com1, offset, true, log)
+            .await
+    }
+
+    /// Creates a new helper connected to the given serial port of the
+    /// instance, optionally in read-only mode (see [`Self::new_read_only`]).
+    pub async fn new_for_port(
+        address: SocketAddr,
+        port: SerialPortNumber,
+        offset: WSClientOffset,
+        read_only: bool,
+        log: Option<Logger>,
+    ) -> Result<Self, WSError> {
+        let stream_builder = PropolisSerialBuilder::new(port, read_only);
+        Self::new_with_builder(stream_builder, Some(address), offset, log).await
+    }
+
+    /// Creates a new helper connected to the given serial port of the
+    /// instance served by `client`, optionally in read-only mode (see
+    /// [`Self::new_read_only`]).
+    ///
+    /// Connections are made with `client`'s HTTP client, and so with its TLS
+    /// configuration and default headers (e.g. those carrying a bearer token),
+    /// including those made to the destinations of the instance's migrations.
+    pub async fn new_with_client(
+        client: &PropolisClient,
+        port: SerialPortNumber,
+        offset: WSClientOffset,
+        read_only: bool,
+        log: Option<Logger>,
+    ) -> Result<Self, WSError> {
+        let stream_builder =
+            PropolisSerialBuilder::with_client(client.clone(), port, read_only);
+        Self::new_with_builder(stream_builder, None, offset, log).await
     }
 
     /// Creates a new serial console helper for testing.
@@ -236,7 +398,7 @@ impl InstanceSerialConsoleHelper {
                 .into_iter()
                 .map(|(addr, stream)| (addr, Duration::ZERO, stream)),
         );
-        Self::new_with_builder(stream_builder, address, offset, log).await
+        Self::new_with_builder(stream_builder, Some(address), offset, log).await
     }
 
     /// Creates a new serial console helper for testing, with delays before
@@ -253,20 +415,71 @@ impl InstanceSerialConsoleHelper {
         log: Option<Logger>,
     ) -> Result<Self, WSError> {
         let stream_builder = TestSerialBuilder::new(connections);
-        Self::new_with_builder(stream_builder, address, offset, log).await
+        Self::new_with_builder(stream_builder, Some(address), offset, log).await
     }
 
     // Currently used for testing, and not exposed to clients.
     pub(crate) async fn new_with_builder(
         mut stream_builder: impl SerialConsoleStreamBuilder + 'static,
-        address: SocketAddr,
+        address: Option<SocketAddr>,
         offset: WSClientOffset,
         log: Option<Logger>,
     ) -> Result<Self, WSError> {
         let stream = stream_builder.build(address, offset).await?;
         let ws_stream =
             WebSocketStream::from_raw_socket(stream, Role::Client, None).await;
-        Ok(Self { stream_builder: Box::new(stream_builder), ws_stream, log })
+        let next_offset = match offset {
+            WSClientOffset::FromStart(offset) => Some(offset),
+            WSClientOffset::MostRecent(_) => None,
+        };
+        Ok(Self {
+            stream_builder: Box::new(stream_builder),
+            ws_stream,
+            log,
+            address,
+            next_offset,
+            replay_remaining: 0,
+        })
+    }
+
+    /// Returns the offset, counted in bytes output since instance start, of
+    /// the next byte of live output to be received, if known: that is, if the
+    /// helper was connected with [`WSClientOffset::FromStart`], or has since
+    /// followed the instance through a migration.
+    pub fn next_byte_offset(&self) -> Option<u64> {
+        self.next_offset
+    }
+
+    /// Connects again to the server to which the helper is connected (which
+    /// is the destination of the instance's latest migration, if it has been
+    /// migrated), e.g. after the connection was lost.
+    ///
+    /// Output resumes with the byte following the last one received if
+    /// [`Self::next_byte_offset`] is known, and with only new output if not.
+    pub async fn reconnect(&mut self) -> Result<(), WSError> {
+        let offset = match self.next_offset {
+            Some(offset) => WSClientOffset::FromStart(offset),
+            None => WSClientOffset::MostRecent(0),
+        };
+        self.connect(self.address, offset).await
+    }
+
+    /// Replaces the helper's connection with a new one to the server at
+    /// `address`, starting from `offset`.
+    async fn connect(
+        &mut self,
+        address: Option<SocketAddr>,
+        offset: WSClientOffset,
+    ) -> Result<(), WSError> {
+        let stream = self.stream_builder.build(address, offset).await?;
+        self.ws_stream =
+            WebSocketStream::from_raw_socket(stream, Role::Client, None).await;
+        self.address = address;
+        self.replay_remaining = 0;
+        if let WSClientOffset::FromStart(offset) = offset {
+            self.next_offset = Some(offset);
+        }
+        Ok(())
     }
 
     /// Receives the next [WSMessage] from the server, holding it in
@@ -300,6 +513,21 @@ impl InstanceSerialConsoleHelper {
             Err(error) => Some(Err(error)),
         }
     }
+
+    /// Sends a control request, such as a break or a change in the size of
+    /// the client's terminal, to the server.
+    ///
+    /// Any response (e.g. [`InstanceSerialConsoleControlMessage::Error`])
+    /// arrives as a [`WSMessage::Text`] from [`Self::recv`].
+    pub async fn send_control(
+        &mut self,
+        msg: &InstanceSerialConsoleClientMessage,
+    ) -> Result<(), WSError> {
+        let json = serde_json::to_string(msg).map_err(|e| {
+            WSError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })?;
+        self.ws_stream.send(WSMessage::Text(json)).await
+    }
 }
 
 impl Sink<WSMessage> for InstanceSerialConsoleHelper {
@@ -401,27 +629,34 @@ impl<'a> InstanceSerialConsoleMessage<'a> {
     /// - Buffer messages received during migration on the server rather
     ///   than the client.
     pub async fn process(self) -> Result<WSMessage, WSError> {
+        if let WSMessage::Binary(data) = &self.message {
+            let len = data.len() as u64;
+            let replayed = len.min(self.helper.replay_remaining);
+            self.helper.replay_remaining -= replayed;
+            if let Some(offset) = &mut self.helper.next_offset {
+                *offset += len - replayed;
+            }
+        }
         if let WSMessage::Text(json) = &self.message {
             match serde_json::from_str(json) {
                 Ok(InstanceSerialConsoleControlMessage::Migrating {
                     destination,
                     from_start,
                 }) => {
-                    let stream = self
-                        .helper
-                        .stream_builder
-                        .build(
-                            destination,
+                    self.helper
+                        .connect(
+                            Some(destination),
                             WSClientOffset::FromStart(from_start),
                         )
                         .await?;
-                    self.helper.ws_stream = WebSocketStream::from_raw_socket(
-                        stream,
-                        Role::Client,
-                        None,
-                    )
-                    .await;
                 }
+                Ok(InstanceSerialConsoleControlMessage::History {
+                    len,
+                    ..
+                }) => {
+                    self.helper.replay_remaining = len;
+                }
+                Ok(_) => {}
                 Err(e) => {
                     if let Some(log) = &self.helper.log {
                         slog::warn!(
@@ -440,6 +675,129 @@ impl<'a> InstanceSerialConsoleMessage<'a> {
     }
 }
 
+impl PropolisClient {
+    /// Connects to the given serial port of the instance, optionally in
+    /// read-only mode, through this client (see
+    /// [`InstanceSerialConsoleHelper::new_with_client`]).
+    pub async fn serial_console(
+        &self,
+        port: SerialPortNumber,
+        offset: WSClientOffset,
+        read_only: bool,
+        log: Option<Logger>,
+    ) -> Result<InstanceSerialConsoleHelper, WSError> {
+        InstanceSerialConsoleHelper::new_with_client(
+            self, port, offset, read_only, log,
+        )
+        .await
+    }
+
+    /// Fetches up to `max_bytes` (or, if `None`, all) of the output retained
+    /// in the given serial port's history, starting from `offset`.
+    pub async fn serial_history(
+        &self,
+        port: SerialPortNumber,
+        offset: WSClientOffset,
+        max_bytes: Option<u64>,
+    ) -> Result<
+        InstanceSerialConsoleHistoryResponse,
+        crate::Error<crate::types::Error>,
+    > {
+        let (from_start, most_recent) = match offset {
+            WSClientOffset::FromStart(offset) => (Some(offset), None),
+            WSClientOffset::MostRecent(offset) => (None, Some(offset)),
+        };
+
+        // As with the serial console, COM1 is reached through its original
+        // endpoint so that this works with servers that predate per-port
+        // access.
+        let res = if port == SerialPortNumber::Com1 {
+            let mut req = self.instance_serial_history_get();
+            if let Some(offset) = from_start {
+                req = req.from_start(offset);
+            }
+            if let Some(offset) = most_recent {
+                req = req.most_recent(offset);
+            }
+            if let Some(max_bytes) = max_bytes {
+                req = req.max_bytes(max_bytes);
+            }
+            req.send().await
+        } else {
+            let mut req = self.instance_serial_port_history_get().port(port);
+            if let Some(offset) = from_start {
+                req = req.from_start(offset);
+            }
+            if let Some(offset) = most_recent {
+                req = req.most_recent(offset);
+            }
+            if let Some(max_bytes) = max_bytes {
+                req = req.max_bytes(max_bytes);
+            }
+            req.send().await
+        };
+        Ok(res?.into_inner())
+    }
+
+    /// Opens the websocket over which the destination of the migration
+    /// `migration_id` out of the instance negotiates and carries out the
+    /// migration with it.
+    ///
+    /// The migration endpoints are left out of the server's OpenAPI document,
+    /// since only other Propolis servers are expected to use them.  This and
+    /// [`Self::migration_ram_stream`] are for tools and tests which stand in
+    /// for a migration's destination.
+    pub async fn migration_start(
+        &self,
+        migration_id: Uuid,
+    ) -> Result<WebSocketStream<reqwest::Upgraded>, WSError> {
+        self.websocket(&format!("/instance/migrate/{migration_id}/start")).await
+    }
+
+    /// Opens an additional websocket over which guest memory is sent during
+    /// the migration `migration_id` out of the instance, once the migration
+    /// has agreed to use more than one.
+    pub async fn migration_ram_stream(
+        &self,
+        migration_id: Uuid,
+    ) -> Result<WebSocketStream<reqwest::Upgraded>, WSError> {
+        self.websocket(&format!("/instance/migrate/{migration_id}/ram-stream"))
+            .await
+    }
+
+    /// Upgrades a request to `path` on the client's server to a websocket.
+    async fn websocket(
+        &self,
+        path: &str,
+    ) -> Result<WebSocketStream<reqwest::Upgraded>, WSError> {
+        let io_error =
+            |e| WSError::Io(std::io::Error::new(std::io::ErrorKind::Other, e));
+        let key = base64::Engine::encode(
+            &base64::engine::general_purpose::STANDARD,
+            rand::random::<[u8; 16]>(),
+        );
+        let res = self
+            .client()
+            .get(format!("{}{path}", self.baseurl()))
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", key)
+            .send()
+            .await
+            .map_err(io_error)?;
+        if res.status().as_u16() != 101 {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(WSError::Http(http::Response::new(Some(
+                format!("{status}: {body}").into_bytes(),
+            ))));
+        }
+        let upgraded = res.upgrade().await.map_err(io_error)?;
+        Ok(WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await)
+    }
+}
+
 #[allow(dead_code)]
 fn assert_send<T: Send>() {}
 
@@ -450,6 +808,7 @@ fn _assert_impls() {
 
 #[cfg(test)]
 mod test {
+    use super::InstanceSerialConsoleClientMessage;
     use super::InstanceSerialConsoleControlMessage;
     use super::InstanceSerialConsoleHelper;
     use super::Role;
@@ -507,6 +866,99 @@ mod test {
         assert!(matches!(received, WSError::Http(_)));
     }
 
+    #[tokio::test]
+    async fn test_send_control() {
+        let address = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 12000);
+        let (client_conn, server_conn) = tokio::io::duplex(1024);
+
+        let mut client = InstanceSerialConsoleHelper::new_test(
+            [(address, client_conn)],
+            address,
+            WSClientOffset::FromStart(0),
+            None,
+        )
+        .await
+        .unwrap();
+        let mut server = make_ws_server(server_conn).await;
+
+        client
+            .send_control(&InstanceSerialConsoleClientMessage::Break {
+                sysrq: Some('b'),
+            })
+            .await
+            .unwrap();
+        let received = server.next().await.unwrap().unwrap();
+        assert_eq!(
+            received,
+            WSMessage::Text(r#"{"Break":{"sysrq":"b"}}"#.into())
+        );
+
+        // Control messages the client does not act on are passed through.
+        let sent = WSMessage::Text(
+            serde_json::to_string(
+                &InstanceSerialConsoleControlMessage::Error {
+                    message: "nope".to_string(),
+                },
+            )
+            .unwrap(),
+        );
+        server.send(sent.clone()).await.unwrap();
+        let received =
+            client.recv().await.unwrap().unwrap().process().await.unwrap();
+        assert_eq!(sent, received);
+    }
+
+    #[tokio::test]
+    async fn test_output_offset_tracking() {
+        let address_1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 12000);
+        let address_2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 14000);
+        let (client_conn_1, server_conn_1) = tokio::io::duplex(1024);
+        let (client_conn_2, server_conn_2) = tokio::io::duplex(1024);
+
+        let mut client = InstanceSerialConsoleHelper::new_test(
+            [(address_1, client_conn_1), (address_2, client_conn_2)],
+            address_1,
+            WSClientOffset::FromStart(10),
+            None,
+        )
+        .await
+        .unwrap();
+        let mut server_1 = make_ws_server(server_conn_1).await;
+        let _server_2 = make_ws_server(server_conn_2).await;
+        assert_eq!(client.next_byte_offset(), Some(10));
+
+        server_1.send(WSMessage::Binary(vec![0; 4])).await.unwrap();
+        client.recv().await.unwrap().unwrap().process().await.unwrap();
+        assert_eq!(client.next_byte_offset(), Some(14));
+
+        // Replayed history isn't live output.
+        let history = serde_json::to_string(
+            &InstanceSerialConsoleControlMessage::History {
+                from_start: 0,
+                len: 3,
+            },
+        )
+        .unwrap();
+        server_1.send(WSMessage::Text(history)).await.unwrap();
+        server_1.send(WSMessage::Binary(vec![0; 3])).await.unwrap();
+        server_1.send(WSMessage::Binary(vec![0; 2])).await.unwrap();
+        for _ in 0..3 {
+            client.recv().await.unwrap().unwrap().process().await.unwrap();
+        }
+        assert_eq!(client.next_byte_offset(), Some(16));
+
+        let migrating = serde_json::to_string(
+            &InstanceSerialConsoleControlMessage::Migrating {
+                destination: address_2,
+                from_start: 20,
+            },
+        )
+        .unwrap();
+        server_1.send(WSMessage::Text(migrating)).await.unwrap();
+        client.recv().await.unwrap().unwrap().process().await.unwrap();
+        assert_eq!(client.next_byte_offset(), Some(20));
+    }
+
     // start_paused = true means that the durations passed in are used to
     // just provide a total ordering for awaits -- we don't actually wait
     // that long.
@@ -4,12 +4,19 @@
 
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use super::{Backend, CacheMode, Device, DeviceInfo, Request};
+use super::retry::RetryState;
+use super::{
+    Backend, CacheMode, Device, DeviceInfo, ErrorNotifier, ErrorPolicy,
+    Operation, Request,
+};
 use crate::accessors::MemAccessor;
 use crate::attachment;
+use crate::util::latency::{LatencyHistogram, LATENCY_BUCKETS};
 
 use pin_project_lite::pin_project;
 use tokio::sync::futures::Notified;
@@ -18,16 +25,19 @@ struct BlockData {
     device: Arc<dyn Device>,
     backend: Arc<dyn Backend>,
     acc_mem: MemAccessor,
+    retry: Arc<RetryState>,
     lock: Mutex<()>,
     cv: Condvar,
 }
 impl BlockData {
     fn new(device: Arc<dyn Device>, backend: Arc<dyn Backend>) -> Arc<Self> {
         let acc_mem = device.accessor_mem();
+        let retry = device.attachment().retry.clone();
         Arc::new(Self {
             device,
             backend,
             acc_mem,
+            retry,
             lock: Mutex::new(()),
             cv: Condvar::new(),
         })
@@ -46,8 +56,17 @@ impl BlockData {
         att_state: &attachment::AttachState,
     ) -> Result<Request, ReqError> {
         check_state(att_state)?;
+        // Failed requests which are due to be reissued take precedence over
+        // new ones from the device.
+        if let Some(req) = self.retry.take_ready() {
+            req.dispatched();
+            return Ok(req);
+        }
         match self.device.next() {
-            Some(req) => Ok(req),
+            Some(req) => {
+                req.dispatched();
+                Ok(req)
+            }
             None => {
                 check_state(att_state)?;
                 Err(ReqError::NonePending)
@@ -97,29 +116,82 @@ fn check_state(state: &attachment::AttachState) -> Result<(), ReqError> {
     }
 }
 
-pub struct DeviceAttachment(attachment::FrontAttachment<Arc<BlockData>>);
+pub struct DeviceAttachment {
+    att: attachment::FrontAttachment<Arc<BlockData>>,
+    stats: DeviceStats,
+    retry: Arc<RetryState>,
+    name: OnceLock<String>,
+}
 impl DeviceAttachment {
     pub fn new() -> Self {
-        Self(attachment::FrontAttachment::new())
+        Self {
+            att: attachment::FrontAttachment::new(),
+            stats: DeviceStats::new(),
+            retry: RetryState::new(),
+            name: OnceLock::new(),
+        }
+    }
+
+    /// Set the name by which the device is identified in the block USDT
+    /// probes.  The name can only be set once: subsequent calls are ignored.
+    pub fn set_name(&self, name: impl Into<String>) {
+        let _ = self.name.set(name.into());
+    }
+
+    /// The name of the device (empty if one has not been set)
+    pub fn name(&self) -> &str {
+        self.name.get().map(String::as_str).unwrap_or("")
+    }
+
+    /// Access the I/O statistics (latency and queue depth) accumulated for
+    /// requests issued by this device.
+    pub fn stats(&self) -> &DeviceStats {
+        &self.stats
+    }
+
+    /// Set the [ErrorPolicy] governing how requests which fail in the backend
+    /// are handled.  The `notifier`, if provided, is informed of stalls in
+    /// I/O under [`ErrorPolicy::Pause`], which it is told are to the device
+    /// with the name [set](Self::set_name) beforehand.
+    pub fn set_error_policy(
+        &self,
+        policy: ErrorPolicy,
+        notifier: Option<Arc<dyn ErrorNotifier>>,
+    ) {
+        self.retry.set_policy(policy, notifier, self.name());
+    }
+
+    /// Process the result of a request, holding it to be reissued to the
+    /// backend if the [ErrorPolicy] calls for it.  Returns the request if it
+    /// should be completed to the device instead.
+    pub(super) fn process_result(
+        &self,
+        dev: &Arc<dyn Device>,
+        req: Request,
+        res: super::Result,
+    ) -> Option<Request> {
+        self.retry.process(req, res, Arc::downgrade(dev))
     }
 
     /// Query [DeviceInfo] from associated backend (if attached)
     pub fn info(&self) -> Option<DeviceInfo> {
-        self.0.access(|data, _state| data.backend.info())
+        self.att.access(|data, _state| data.backend.info())
     }
 
-    /// Set cache mode on associated backend
-    ///
-    /// # Warning
-    ///
-    /// This is currently unimplemented!
-    pub fn set_cache_mode(&self, _mode: CacheMode) {
-        todo!("wire up cache mode toggling")
+    /// Set cache mode on associated backend (if attached)
+    pub fn set_cache_mode(&self, mode: CacheMode) {
+        // The backend may need to block (issuing ioctls or flushes) while
+        // changing modes, so do so outside the attachment lock.
+        if let Some(backend) =
+            self.att.access(|data, _state| data.backend.clone())
+        {
+            backend.set_cache_mode(mode);
+        }
     }
 
     /// Notify attached backend of (new) pending requests
     pub fn notify(&self) {
-        self.0.access(|data, state| {
+        self.att.access(|data, state| {
             if !state.is_paused() {
                 state.notify();
                 data.notify();
@@ -132,20 +204,28 @@ impl DeviceAttachment {
     /// Backend (if attached) will not be able to retrieving any requests from
     /// this device while paused.  The completions for any requests in flight,
     /// however, will be able to flow through.
+    ///
+    /// Failed requests held for retry (per the [ErrorPolicy]) are completed to
+    /// the device as failures, and no more will be held until the device is
+    /// resumed, so that pausing is not held up by an unavailable backend.
     pub fn pause(&self) {
-        self.0.access_fallback(
+        self.att.access_fallback(
             |data, state| {
                 state.pause();
                 data.notify();
             },
             |state| state.pause(),
         );
+        for req in self.retry.set_paused(true) {
+            req.complete(super::Result::Failure);
+        }
     }
 
     /// Clear the paused state on this device, allowing the backend (if
     /// attached) to retrieve requests once again.
     pub fn resume(&self) {
-        self.0.access_fallback(
+        let _ = self.retry.set_paused(false);
+        self.att.access_fallback(
             |data, state| {
                 state.resume();
                 data.notify();
@@ -157,7 +237,7 @@ impl DeviceAttachment {
     /// Attempt to detach device from backend
     pub fn detach(&self) -> Result<(), attachment::DetachError> {
         if let Some((dev, backend)) = self
-            .0
+            .att
             .access(|data, _state| (data.device.clone(), data.backend.clone()))
         {
             detach(dev, backend)
@@ -167,6 +247,107 @@ impl DeviceAttachment {
     }
 }
 
+/// Point-in-time copy of the values held in [DeviceStats].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceStatsSnapshot {
+    pub read: LatencyHistogram,
+    /// Latencies of requests which mutate the device (writes, as well as
+    /// write-zeroes and discards)
+    pub write: LatencyHistogram,
+    pub flush: LatencyHistogram,
+    /// Number of requests issued to the backend but not yet completed
+    pub queue_depth: u64,
+    /// Bytes read by successfully completed requests
+    pub read_bytes: u64,
+    /// Bytes written by successfully completed writes
+    pub write_bytes: u64,
+    /// Number of requests which the backend reported as having failed
+    pub errors: u64,
+}
+
+/// I/O statistics for a block [Device].
+///
+/// These are updated by [`Tracking`](super::tracking::Tracking) as requests
+/// are issued and completed, so devices which do not make use of it will not
+/// have any statistics recorded.
+pub struct DeviceStats {
+    read: [AtomicU64; LATENCY_BUCKETS],
+    write: [AtomicU64; LATENCY_BUCKETS],
+    flush: [AtomicU64; LATENCY_BUCKETS],
+    queue_depth: AtomicU64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    errors: AtomicU64,
+}
+impl DeviceStats {
+    fn new() -> Self {
+        Self {
+            read: std::array::from_fn(|_| AtomicU64::new(0)),
+            write: std::array::from_fn(|_| AtomicU64::new(0)),
+            flush: std::array::from_fn(|_| AtomicU64::new(0)),
+            queue_depth: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that a request has been issued to the backend
+    pub(super) fn request_issued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the completion of a request with result `res`, which took
+    /// `latency` to process
+    pub(super) fn request_completed(
+        &self,
+        op: Operation,
+        res: super::Result,
+        latency: Duration,
+    ) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+
+        match (res, op) {
+            (super::Result::Success, Operation::Read(_off, len)) => {
+                self.read_bytes.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            (super::Result::Success, Operation::Write(_off, len)) => {
+                self.write_bytes.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            (super::Result::Failure, _) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+
+        let buckets = match op {
+            Operation::Read(..) => &self.read,
+            Operation::Write(..)
+            | Operation::WriteZeroes(..)
+            | Operation::Discard(..) => &self.write,
+            Operation::Flush => &self.flush,
+        };
+        buckets[LatencyHistogram::bucket_for(latency)]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the current statistics
+    pub fn snapshot(&self) -> DeviceStatsSnapshot {
+        let load = |buckets: &[AtomicU64; LATENCY_BUCKETS]| LatencyHistogram {
+            counts: std::array::from_fn(|i| buckets[i].load(Ordering::Relaxed)),
+        };
+        DeviceStatsSnapshot {
+            read: load(&self.read),
+            write: load(&self.write),
+            flush: load(&self.flush),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            read_bytes: self.read_bytes.load(Ordering::Relaxed),
+            write_bytes: self.write_bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Reason why next request is unavailable from associated device
 pub enum ReqError {
     /// No request is pending from the device
@@ -236,18 +417,28 @@ impl BackendAttachment {
     }
 
     /// Assert stopped state on this Attachment
+    ///
+    /// Any failed requests held for retry by the attached device are completed
+    /// to it as failures.
     pub fn stop(&self) {
-        self.0.access_fallback(
+        let retry = self.0.access_fallback(
             |data, state| {
                 state.stop();
                 data.notify();
+                data.retry.clone()
             },
             |state| state.stop(),
         );
+        for req in retry.into_iter().flat_map(|r| r.set_stopped(true)) {
+            req.complete(super::Result::Failure);
+        }
     }
 
     /// Clear stopped state from this Attachment
     pub fn start(&self) {
+        if let Some(retry) = self.0.access(|data, _state| data.retry.clone()) {
+            let _ = retry.set_stopped(false);
+        }
         self.0.access_fallback(
             |data, state| {
                 state.start();
@@ -326,7 +517,7 @@ pub fn attach(
 ) -> Result<(), attachment::AttachError> {
     attachment::attach(
         BlockData::new(device.clone(), backend.clone()),
-        &device.attachment().0,
+        &device.attachment().att,
         &backend.attachment().0,
         |data| {
             data.device.on_attach(data.backend.info());
@@ -339,7 +530,7 @@ fn detach(
     backend: Arc<dyn Backend>,
 ) -> Result<(), attachment::DetachError> {
     attachment::detach(
-        &device.attachment().0,
+        &device.attachment().att,
         &backend.attachment().0,
         |data, state| {
             if !state.is_stopped() {
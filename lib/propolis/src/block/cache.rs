@@ -0,0 +1,205 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! In-memory cache of data read from read-only backends.
+//!
+//! The cache holds fixed-size chunks of the device, evicting the least
+//! recently used chunk when it is full.  Since the contents of the backend
+//! cannot change underneath it, no invalidation is required.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+/// Size (in bytes) of the chunks in which data is cached
+pub(super) const CHUNK_SIZE: usize = 64 * 1024;
+
+struct Entry {
+    data: Arc<[u8]>,
+    /// Stamp of the last access to this chunk, its key in `CacheInner::lru`
+    stamp: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    chunks: HashMap<usize, Entry>,
+    /// Cached chunk indices, ordered from least to most recently used
+    lru: BTreeMap<u64, usize>,
+    next_stamp: u64,
+}
+impl CacheInner {
+    fn get(&mut self, idx: usize) -> Option<Arc<[u8]>> {
+        let stamp = self.next_stamp;
+        let entry = self.chunks.get_mut(&idx)?;
+        self.lru.remove(&entry.stamp);
+        self.lru.insert(stamp, idx);
+        entry.stamp = stamp;
+        self.next_stamp += 1;
+        Some(entry.data.clone())
+    }
+
+    fn insert(&mut self, idx: usize, data: Arc<[u8]>, capacity: usize) {
+        if self.chunks.contains_key(&idx) {
+            // Another reader raced us to filling this chunk
+            return;
+        }
+        while self.chunks.len() >= capacity {
+            let Some((_stamp, victim)) = self.lru.pop_first() else {
+                break;
+            };
+            self.chunks.remove(&victim);
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.lru.insert(stamp, idx);
+        self.chunks.insert(idx, Entry { data, stamp });
+    }
+}
+
+/// Least-recently-used cache of the contents of a read-only backend.
+pub(super) struct ReadCache {
+    inner: Mutex<CacheInner>,
+    /// Maximum number of chunks held in the cache
+    capacity: usize,
+    /// Size (in bytes) of the backing device
+    total_bytes: usize,
+}
+impl ReadCache {
+    /// Create a cache of (at most) `size` bytes for a device of `total_bytes`.
+    /// Returns `None` if `size` is too small to hold a single chunk.
+    pub(super) fn new(size: usize, total_bytes: usize) -> Option<Self> {
+        let capacity = size / CHUNK_SIZE;
+        if capacity == 0 {
+            return None;
+        }
+        Some(Self {
+            inner: Mutex::new(CacheInner::default()),
+            capacity,
+            total_bytes,
+        })
+    }
+
+    /// Fill `buf` with the device contents at byte offset `off`.
+    ///
+    /// Chunks absent from the cache are read from the backend using `fill`,
+    /// which is called with the offset of the chunk and a buffer of the chunk
+    /// size (or smaller, for a chunk at the end of the device).
+    pub(super) fn read(
+        &self,
+        off: usize,
+        buf: &mut [u8],
+        mut fill: impl FnMut(usize, &mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = off + done;
+            let idx = pos / CHUNK_SIZE;
+            let chunk_off = idx * CHUNK_SIZE;
+
+            let cached = self.inner.lock().unwrap().get(idx);
+            let chunk = match cached {
+                Some(chunk) => chunk,
+                None => {
+                    let len = CHUNK_SIZE
+                        .min(self.total_bytes.saturating_sub(chunk_off));
+                    let mut data = vec![0u8; len];
+                    fill(chunk_off, &mut data)?;
+                    let data: Arc<[u8]> = data.into();
+                    self.inner.lock().unwrap().insert(
+                        idx,
+                        data.clone(),
+                        self.capacity,
+                    );
+                    data
+                }
+            };
+
+            let start = pos - chunk_off;
+            let copy_len =
+                (chunk.len().saturating_sub(start)).min(buf.len() - done);
+            if copy_len == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "read beyond end of device",
+                ));
+            }
+            buf[done..done + copy_len]
+                .copy_from_slice(&chunk[start..start + copy_len]);
+            done += copy_len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn reads_match_backing() {
+        let backing = contents(CHUNK_SIZE * 3 + 512);
+        let cache = ReadCache::new(CHUNK_SIZE * 2, backing.len()).unwrap();
+        let fill = |off: usize, buf: &mut [u8]| {
+            buf.copy_from_slice(&backing[off..off + buf.len()]);
+            Ok(())
+        };
+
+        // Spanning chunks, including the short one at the end of the device
+        for (off, len) in [
+            (0, 512),
+            (CHUNK_SIZE - 512, 1024),
+            (CHUNK_SIZE * 2, CHUNK_SIZE + 512),
+        ] {
+            let mut buf = vec![0u8; len];
+            cache.read(off, &mut buf, fill).unwrap();
+            assert_eq!(buf, &backing[off..off + len]);
+        }
+    }
+
+    #[test]
+    fn hits_avoid_fill() {
+        let backing = contents(CHUNK_SIZE * 4);
+        let cache = ReadCache::new(CHUNK_SIZE * 2, backing.len()).unwrap();
+        let mut fills = 0;
+        let mut fill = |off: usize, buf: &mut [u8]| {
+            fills += 1;
+            buf.copy_from_slice(&backing[off..off + buf.len()]);
+            Ok(())
+        };
+
+        let mut buf = vec![0u8; 512];
+        cache.read(0, &mut buf, &mut fill).unwrap();
+        cache.read(1024, &mut buf, &mut fill).unwrap();
+        assert_eq!(fills, 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let backing = contents(CHUNK_SIZE * 4);
+        let cache = ReadCache::new(CHUNK_SIZE * 2, backing.len()).unwrap();
+        let mut filled = Vec::new();
+        let mut fill = |off: usize, buf: &mut [u8]| {
+            filled.push(off / CHUNK_SIZE);
+            buf.copy_from_slice(&backing[off..off + buf.len()]);
+            Ok(())
+        };
+
+        let mut buf = vec![0u8; 512];
+        for chunk in [0, 1, 0, 2, 0, 1] {
+            cache.read(chunk * CHUNK_SIZE, &mut buf, &mut fill).unwrap();
+        }
+        // Chunk 1 is evicted to make room for chunk 2, while the more recently
+        // used chunk 0 remains cached throughout.
+        assert_eq!(filled, vec![0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn too_small() {
+        assert!(ReadCache::new(CHUNK_SIZE - 1, CHUNK_SIZE * 4).is_none());
+    }
+}
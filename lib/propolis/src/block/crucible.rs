@@ -5,7 +5,9 @@
 //! Implement a virtual block device backed by Crucible
 
 use std::io;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::accessors::MemAccessor;
 use crate::block::{self, DeviceInfo};
@@ -13,27 +15,150 @@ use crate::tasks::TaskGroup;
 use crate::vmm::MemCtx;
 
 use crucible::{
-    BlockIO, Buffer, CrucibleError, ReplaceResult, SnapshotDetails, Volume,
+    BlockIO, Buffer, CrucibleError, DsState, ReplaceResult, SnapshotDetails,
+    Volume,
 };
 use crucible_client_types::VolumeConstructionRequest;
 use oximeter::types::ProducerRegistry;
-use slog::{error, info};
+use slog::{error, info, warn};
 use thiserror::Error;
 use uuid::Uuid;
 
 pub use nexus_client::Client as NexusClient;
 
+/// Delay before a failed volume activation is first retried
+const ACTIVATE_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+/// Upper bound on the delay between attempts at activating the volume
+const ACTIVATE_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Progress of a [CrucibleBackend] in activating its volume
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ActivationState {
+    /// The backend has not been started (or was stopped before activation
+    /// succeeded).
+    Inactive,
+
+    /// The volume is being activated, after `attempts` failed attempts (the
+    /// most recent of which failed with `last_error`).  Requests to the
+    /// backend are held until activation succeeds.
+    Activating { attempts: u32, last_error: Option<String> },
+
+    /// The volume is active, and requests to the backend are being processed.
+    Active,
+
+    /// Activation of the volume failed after `attempts` attempts, the last
+    /// with an `error` which retrying would not fix, and was abandoned.
+    /// Requests to the backend fail.
+    Failed { attempts: u32, error: String },
+}
+
+/// Health of one of the downstairs replicas of a [CrucibleBackend]'s volume
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplicaHealth {
+    /// The upstairs is connecting to (or reconciling with) the downstairs.
+    Connecting,
+
+    /// The downstairs is servicing I/O.
+    Active,
+
+    /// The downstairs is being brought back in sync with the other replicas.
+    /// It receives writes, but does not service reads until repair completes.
+    LiveRepair,
+
+    /// The downstairs is unreachable.  I/O destined for it is held, for a
+    /// time, in the hope it returns.
+    Offline,
+
+    /// The downstairs has been faulted (or is being replaced), and is not
+    /// participating in I/O.
+    Faulted,
+}
+impl From<&DsState> for ReplicaHealth {
+    fn from(value: &DsState) -> Self {
+        match value {
+            DsState::New
+            | DsState::WaitActive
+            | DsState::WaitQuorum
+            | DsState::Reconcile => Self::Connecting,
+            DsState::Active => Self::Active,
+            DsState::LiveRepairReady | DsState::LiveRepair => Self::LiveRepair,
+            DsState::Offline => Self::Offline,
+            _ => Self::Faulted,
+        }
+    }
+}
+
 pub struct CrucibleBackend {
     state: Arc<WorkerState>,
-    workers: TaskGroup,
+    workers: Arc<TaskGroup>,
+    /// Start without waiting for the volume to activate?
+    lazy_attach: bool,
+    /// Task activating the volume in the background, if it is started lazily
+    activation: Mutex<Option<tokio::task::JoinHandle<()>>>,
 }
 struct WorkerState {
     attachment: block::BackendAttachment,
     volume: Volume,
     info: block::DeviceInfo,
     skip_flush: bool,
+    /// Must each write be followed by a flush, as the device expects writes
+    /// to be durable on completion?
+    sync_writes: AtomicBool,
+    activation: Mutex<ActivationState>,
+    log: slog::Logger,
 }
 impl WorkerState {
+    fn new(
+        volume: Volume,
+        info: block::DeviceInfo,
+        skip_flush: bool,
+        log: slog::Logger,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            attachment: block::BackendAttachment::new(),
+            volume,
+            info,
+            skip_flush,
+            sync_writes: AtomicBool::new(false),
+            activation: Mutex::new(ActivationState::Inactive),
+            log,
+        })
+    }
+
+    /// Make an attempt at activating the volume, recording the outcome.
+    async fn try_activate(&self) -> Result<(), CrucibleError> {
+        let res = self.volume.activate().await;
+        let mut state = self.activation.lock().unwrap();
+        match &res {
+            Ok(()) => *state = ActivationState::Active,
+            Err(e) => {
+                let attempts = match &*state {
+                    ActivationState::Activating { attempts, .. } => *attempts,
+                    _ => 0,
+                }
+                .saturating_add(1);
+                *state = if activation_error_is_permanent(e) {
+                    ActivationState::Failed { attempts, error: e.to_string() }
+                } else {
+                    ActivationState::Activating {
+                        attempts,
+                        last_error: Some(e.to_string()),
+                    }
+                };
+            }
+        }
+        res
+    }
+
+    /// Activate the volume, retrying with exponential backoff between
+    /// attempts until it succeeds or fails permanently.
+    async fn activate_with_retry(&self) -> Result<(), CrucibleError> {
+        retry_activation(&self.log, ACTIVATE_BACKOFF_INITIAL, || {
+            self.try_activate()
+        })
+        .await
+    }
+
     async fn process_loop(&self, acc_mem: MemAccessor) {
         let waiter = match self.attachment.waiter() {
             None => {
@@ -58,6 +183,7 @@ impl WorkerState {
                     &self.volume,
                     &self.info,
                     self.skip_flush,
+                    self.sync_writes.load(Ordering::Relaxed),
                     &req,
                     &mut readbuf,
                     &memctx,
@@ -112,6 +238,8 @@ impl CrucibleBackend {
         let volume =
             Volume::construct(request, producer_registry, log.clone()).await?;
 
+        let state_log = log.clone();
+
         // Decide if we need to scrub this volume or not.
         if volume.has_read_only_parent() {
             let vclone = volume.clone();
@@ -160,17 +288,19 @@ impl CrucibleBackend {
         let sectors = total_size / block_size;
 
         Ok(Arc::new(Self {
-            state: Arc::new(WorkerState {
-                attachment: block::BackendAttachment::new(),
+            state: WorkerState::new(
                 volume,
-                info: block::DeviceInfo {
+                block::DeviceInfo {
                     block_size: block_size as u32,
                     total_size: sectors,
                     read_only: opts.read_only.unwrap_or(false),
                 },
-                skip_flush: opts.skip_flush.unwrap_or(false),
-            }),
-            workers: TaskGroup::new(),
+                opts.skip_flush.unwrap_or(false),
+                state_log,
+            ),
+            workers: Arc::new(TaskGroup::new()),
+            lazy_attach: opts.lazy_attach.unwrap_or(false),
+            activation: Mutex::new(None),
         }))
     }
 
@@ -194,21 +324,23 @@ impl CrucibleBackend {
                 block_size,
                 size as usize,
             ));
-            let mut volume = Volume::new(block_size, log);
+            let mut volume = Volume::new(block_size, log.clone());
             volume.add_subvolume(mem_disk).await?;
 
             Ok(Arc::new(CrucibleBackend {
-                state: Arc::new(WorkerState {
-                    attachment: block::BackendAttachment::new(),
+                state: WorkerState::new(
                     volume,
-                    info: block::DeviceInfo {
+                    block::DeviceInfo {
                         block_size: block_size as u32,
                         total_size: size / block_size,
                         read_only: opts.read_only.unwrap_or(false),
                     },
-                    skip_flush: opts.skip_flush.unwrap_or(false),
-                }),
-                workers: TaskGroup::new(),
+                    opts.skip_flush.unwrap_or(false),
+                    log,
+                ),
+                workers: Arc::new(TaskGroup::new()),
+                lazy_attach: opts.lazy_attach.unwrap_or(false),
+                activation: Mutex::new(None),
             }))
         })
         .map_err(CrucibleError::into)
@@ -274,27 +406,23 @@ impl CrucibleBackend {
             .map_err(CrucibleError::into)
     }
 
-    fn spawn_workers(&self) {
-        // TODO: make this tunable?
-        let worker_count = 8;
-        self.workers.extend((0..worker_count).map(|n| {
-            let worker_state = self.state.clone();
-            let worker_acc = self
-                .state
-                .attachment
-                .accessor_mem(|acc_mem| {
-                    acc_mem.child(Some(format!("crucible worker {n}")))
-                })
-                .expect("backend is attached");
-            tokio::spawn(
-                async move { worker_state.process_loop(worker_acc).await },
-            )
-        }))
+    /// Progress of the backend in activating its volume.
+    pub fn activation_state(&self) -> ActivationState {
+        self.state.activation.lock().unwrap().clone()
     }
 
     pub async fn volume_is_active(&self) -> Result<bool, CrucibleError> {
         self.state.volume.query_is_active().await
     }
+
+    /// Health of each of the downstairs replicas of the volume, in the order
+    /// of the targets in its construction request.
+    pub async fn replica_health(
+        &self,
+    ) -> Result<Vec<ReplicaHealth>, CrucibleError> {
+        let states = self.state.volume.query_ds_state().await?;
+        Ok(states.iter().map(ReplicaHealth::from).collect())
+    }
 }
 
 impl block::Backend for CrucibleBackend {
@@ -306,16 +434,138 @@ impl block::Backend for CrucibleBackend {
     }
     fn start(&self) -> anyhow::Result<()> {
         let rt = tokio::runtime::Handle::current();
-        rt.block_on(async move { self.state.volume.activate().await })?;
+        if !self.lazy_attach {
+            rt.block_on(self.state.try_activate())?;
 
+            self.state.attachment.start();
+            spawn_workers(&self.state, &self.workers);
+            return Ok(());
+        }
+
+        // Rather than holding up the start of the instance until the volume
+        // activates, activate it in the background, retrying for as long as a
+        // downstairs may only be briefly unreachable.  Requests queue up in the
+        // meantime.  Should activation fail permanently, the workers are
+        // spawned anyway, so that those requests fail rather than hang.
+        info!(self.state.log, "activating volume in the background");
         self.state.attachment.start();
-        self.spawn_workers();
+        *self.state.activation.lock().unwrap() =
+            ActivationState::Activating { attempts: 0, last_error: None };
+        let state = self.state.clone();
+        let workers = self.workers.clone();
+        let hdl = rt.spawn(async move {
+            let _ = state.activate_with_retry().await;
+            spawn_workers(&state, &workers);
+        });
+        *self.activation.lock().unwrap() = Some(hdl);
         Ok(())
     }
     fn stop(&self) {
+        if let Some(hdl) = self.activation.lock().unwrap().take() {
+            hdl.abort();
+            let rt = tokio::runtime::Handle::current();
+            let _ = rt.block_on(hdl);
+
+            let mut state = self.state.activation.lock().unwrap();
+            if matches!(*state, ActivationState::Activating { .. }) {
+                *state = ActivationState::Inactive;
+            }
+        }
         self.state.attachment.stop();
         self.workers.block_until_joined();
     }
+    fn set_cache_mode(&self, mode: block::CacheMode) {
+        // Writes are made durable in crucible by flushing them, so that is
+        // done after every write when the device expects write-through
+        // behavior.  When flushes are being skipped, there is no expectation
+        // of durability.
+        self.state.sync_writes.store(
+            mode == block::CacheMode::Synchronous && !self.state.skip_flush,
+            Ordering::Relaxed,
+        );
+    }
+    fn read_direct(
+        &self,
+        off: block::ByteOffset,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let block_size = self.state.info.block_size as usize;
+        let (off_blocks, len_blocks) =
+            block_offset_count(off, buf.len(), block_size)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut readbuf = Buffer::new(len_blocks, block_size);
+        let rt = tokio::runtime::Handle::current();
+        rt.block_on(async {
+            self.state.volume.read(off_blocks, &mut readbuf).await
+        })
+        .map_err(CrucibleError::into)?;
+        buf.copy_from_slice(&readbuf[..]);
+        Ok(())
+    }
+}
+
+/// Is `err`, from an attempt at activating a volume, one which retrying would
+/// not fix?  These stem from the volume's construction request or the state of
+/// its regions (a stale generation number, the wrong key), rather than from a
+/// downstairs being unreachable.
+fn activation_error_is_permanent(err: &CrucibleError) -> bool {
+    matches!(
+        err,
+        CrucibleError::GenerationNumberTooLow(_)
+            | CrucibleError::UuidMismatch
+            | CrucibleError::BlockSizeMismatch
+            | CrucibleError::RegionIncompatible(_)
+            | CrucibleError::DecryptionError
+    )
+}
+
+/// Make attempts at activating a volume with `attempt`, doubling the delay
+/// between them from `delay` (up to [ACTIVATE_BACKOFF_MAX]), until one
+/// succeeds or fails permanently.
+async fn retry_activation<F, Fut>(
+    log: &slog::Logger,
+    mut delay: Duration,
+    mut attempt: F,
+) -> Result<(), CrucibleError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<(), CrucibleError>>,
+{
+    loop {
+        match attempt().await {
+            Ok(()) => {
+                info!(log, "volume activation succeeded");
+                return Ok(());
+            }
+            Err(e) if activation_error_is_permanent(&e) => {
+                error!(log, "volume activation failed permanently";
+                       "error" => %e);
+                return Err(e);
+            }
+            Err(e) => {
+                warn!(log, "volume activation failed";
+                      "error" => %e, "retry_in" => ?delay);
+            }
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(ACTIVATE_BACKOFF_MAX);
+    }
+}
+
+fn spawn_workers(state: &Arc<WorkerState>, workers: &TaskGroup) {
+    // TODO: make this tunable?
+    let worker_count = 8;
+    workers.extend((0..worker_count).map(|n| {
+        let worker_state = state.clone();
+        let worker_acc = state
+            .attachment
+            .accessor_mem(|acc_mem| {
+                acc_mem.child(Some(format!("crucible worker {n}")))
+            })
+            .expect("backend is attached");
+        tokio::spawn(async move { worker_state.process_loop(worker_acc).await })
+    }))
 }
 
 #[derive(Debug, Error)]
@@ -369,6 +619,7 @@ async fn process_request(
     block: &(dyn BlockIO + Send + Sync),
     info: &block::DeviceInfo,
     skip_flush: bool,
+    sync_writes: bool,
     req: &block::Request,
     readbuf: &mut Buffer,
     mem: &MemCtx,
@@ -429,6 +680,9 @@ async fn process_request(
             }
 
             let _ = block.write(off_blocks, data).await?;
+            if sync_writes {
+                let _ = block.flush(None).await?;
+            }
         }
         block::Operation::Flush => {
             if !skip_flush {
@@ -436,13 +690,38 @@ async fn process_request(
                 let _ = block.flush(None).await?;
             }
         }
+        block::Operation::WriteZeroes(off, len) => {
+            if info.read_only {
+                return Err(Error::ReadOnly);
+            }
+
+            let (off_blocks, _len_blocks) =
+                block_offset_count(off, len, block_size)?;
+
+            // Crucible has no notion of a zeroing write, so send it the zeroes
+            let mut data = crucible::BytesMut::with_capacity(len);
+            data.resize(len, 0);
+            let _ = block.write(off_blocks, data).await?;
+            if sync_writes {
+                let _ = block.flush(None).await?;
+            }
+        }
+        block::Operation::Discard(off, len) => {
+            if info.read_only {
+                return Err(Error::ReadOnly);
+            }
+
+            // Crucible cannot (yet) deallocate blocks in a region, and discards
+            // are advisory, so just check that the request is well-formed.
+            let _ = block_offset_count(off, len, block_size)?;
+        }
     }
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use super::block_offset_count;
+    use super::*;
 
     #[test]
     fn err_on_bad_offset() {
@@ -474,4 +753,49 @@ mod test {
         assert_eq!(block.block_size_in_bytes(), bs as u32);
         assert_eq!(block.bytes(), off);
     }
+
+    fn test_log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[tokio::test]
+    async fn activation_retried_until_success() {
+        let mut attempts = 0;
+        let res =
+            retry_activation(&test_log(), Duration::from_millis(1), || {
+                attempts += 1;
+                let res = if attempts < 3 {
+                    Err(CrucibleError::GenericError("unreachable".into()))
+                } else {
+                    Ok(())
+                };
+                async move { res }
+            })
+            .await;
+        assert!(res.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn activation_not_retried_after_permanent_error() {
+        let mut attempts = 0;
+        let res =
+            retry_activation(&test_log(), Duration::from_millis(1), || {
+                attempts += 1;
+                async { Err(CrucibleError::UuidMismatch) }
+            })
+            .await;
+        assert!(matches!(res, Err(CrucibleError::UuidMismatch)));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn transient_activation_errors() {
+        assert!(!activation_error_is_permanent(&CrucibleError::GenericError(
+            "connection refused".into()
+        )));
+        assert!(activation_error_is_permanent(
+            &CrucibleError::GenerationNumberTooLow("stale".into())
+        ));
+    }
 }
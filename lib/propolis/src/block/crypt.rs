@@ -0,0 +1,166 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! At-rest encryption of block data using AES-256-XTS.
+//!
+//! Each block of the device is encrypted as an XTS data unit, using its block
+//! number (as a little-endian 128-bit value) as the tweak.  This means the
+//! ciphertext for a given block depends on its location, but that blocks can be
+//! encrypted and decrypted independently of one another.
+
+use std::io::{Error, ErrorKind, Result};
+
+use aes::cipher::KeyInit;
+use aes::Aes256;
+use xts_mode::{get_tweak_default, Xts128};
+
+/// Length, in bytes, of an AES-256-XTS key: two 256-bit AES keys, one for the
+/// data and one for the tweak.
+pub const XTS_KEY_LEN: usize = 64;
+
+/// Key material for an AES-256-XTS encrypted backend.
+///
+/// The contents are zeroed when the key is dropped, and are not exposed
+/// through its `Debug` implementation.
+pub struct XtsKey([u8; XTS_KEY_LEN]);
+impl XtsKey {
+    pub fn new(bytes: [u8; XTS_KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+impl TryFrom<&[u8]> for XtsKey {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let bytes = value.try_into().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "AES-256-XTS key must be {} bytes (got {})",
+                    XTS_KEY_LEN,
+                    value.len()
+                ),
+            )
+        })?;
+        Ok(Self(bytes))
+    }
+}
+impl Drop for XtsKey {
+    fn drop(&mut self) {
+        // Volatile writes keep the compiler from eliding the zeroing of memory
+        // which is about to be freed.
+        for b in self.0.iter_mut() {
+            unsafe { std::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+impl std::fmt::Debug for XtsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("XtsKey(<redacted>)")
+    }
+}
+
+/// Cipher state for encrypting and decrypting the blocks of a device.
+pub(super) struct BlockCipher {
+    xts: Xts128<Aes256>,
+    block_size: usize,
+}
+impl BlockCipher {
+    pub(super) fn new(key: &XtsKey, block_size: u32) -> Result<Self> {
+        let (data_key, tweak_key) = key.0.split_at(XTS_KEY_LEN / 2);
+        if data_key == tweak_key {
+            // The two halves of an XTS key must differ, lest it degrade into
+            // something considerably weaker.
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "AES-256-XTS key halves must not be identical",
+            ));
+        }
+        let xts = Xts128::new(
+            Aes256::new_from_slice(data_key).expect("key half is 256 bits"),
+            Aes256::new_from_slice(tweak_key).expect("key half is 256 bits"),
+        );
+        Ok(Self { xts, block_size: block_size as usize })
+    }
+
+    fn check(&self, off: usize, len: usize) -> Result<u128> {
+        if off % self.block_size != 0 || len % self.block_size != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "encrypted I/O (off {}, len {}) not aligned to block \
+                    size {}",
+                    off, len, self.block_size
+                ),
+            ));
+        }
+        Ok((off / self.block_size) as u128)
+    }
+
+    /// Encrypt `buf`, which holds the plaintext of the blocks starting at byte
+    /// offset `off`, in place.
+    pub(super) fn encrypt(&self, off: usize, buf: &mut [u8]) -> Result<()> {
+        let first = self.check(off, buf.len())?;
+        self.xts.encrypt_area(buf, self.block_size, first, get_tweak_default);
+        Ok(())
+    }
+
+    /// Decrypt `buf`, which holds the ciphertext of the blocks starting at byte
+    /// offset `off`, in place.
+    pub(super) fn decrypt(&self, off: usize, buf: &mut [u8]) -> Result<()> {
+        let first = self.check(off, buf.len())?;
+        self.xts.decrypt_area(buf, self.block_size, first, get_tweak_default);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_key() -> XtsKey {
+        let mut bytes = [0u8; XTS_KEY_LEN];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        XtsKey::new(bytes)
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = BlockCipher::new(&test_key(), 512).unwrap();
+        let plain: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+
+        let mut buf = plain.clone();
+        cipher.encrypt(1024, &mut buf).unwrap();
+        assert_ne!(buf, plain);
+        cipher.decrypt(1024, &mut buf).unwrap();
+        assert_eq!(buf, plain);
+    }
+
+    #[test]
+    fn tweak_depends_on_location() {
+        let cipher = BlockCipher::new(&test_key(), 512).unwrap();
+        let mut a = vec![0u8; 512];
+        let mut b = vec![0u8; 512];
+        cipher.encrypt(0, &mut a).unwrap();
+        cipher.encrypt(512, &mut b).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn unaligned_rejected() {
+        let cipher = BlockCipher::new(&test_key(), 512).unwrap();
+        let mut buf = vec![0u8; 512];
+        assert!(cipher.encrypt(100, &mut buf).is_err());
+        assert!(cipher.decrypt(0, &mut buf[..100]).is_err());
+    }
+
+    #[test]
+    fn identical_key_halves_rejected() {
+        assert!(
+            BlockCipher::new(&XtsKey::new([7u8; XTS_KEY_LEN]), 512).is_err()
+        );
+    }
+}
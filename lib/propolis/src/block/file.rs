@@ -2,26 +2,37 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::VecDeque;
 use std::fs::{metadata, File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
 use std::num::NonZeroUsize;
 use std::os::raw::c_int;
-use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::{FileExt, FileTypeExt};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
 use crate::accessors::MemAccessor;
+use crate::block::cache::ReadCache;
+use crate::block::crypt::{BlockCipher, XtsKey};
+use crate::block::integrity::Integrity;
 use crate::block::{self, DeviceInfo};
 use crate::tasks::ThreadGroup;
 use crate::util::ioctl;
-use crate::vmm::{MappingExt, MemCtx};
+use crate::vmm::{MappingExt, MemCtx, SubMapping};
 
 use anyhow::Context;
 
 // XXX: completely arb for now
 const MAX_WORKERS: usize = 32;
 
+/// Upper bound on the size of a [FileWorkerPool] shared among backends
+const MAX_POOL_WORKERS: usize = 256;
+
+/// Size of the zeroed buffer used to service write-zeroes requests
+const ZERO_BUF_SZ: usize = 64 * 1024;
+
 const DKIOC: i32 = 0x04 << 8;
 const DKIOCGETWCE: i32 = DKIOC | 36;
 const DKIOCSETWCE: i32 = DKIOC | 37;
@@ -29,8 +40,12 @@ const DKIOCSETWCE: i32 = DKIOC | 37;
 pub struct FileBackend {
     state: Arc<WorkerState>,
 
-    worker_count: NonZeroUsize,
-    workers: ThreadGroup,
+    pool: Arc<FileWorkerPool>,
+    /// Limit on requests queued to (or being processed by) the pool
+    max_in_flight: NonZeroUsize,
+    /// Pool membership, while the backend is running
+    member: Mutex<Option<MemberId>>,
+    dispatcher: ThreadGroup,
 }
 struct WorkerState {
     attachment: block::BackendAttachment,
@@ -39,15 +54,39 @@ struct WorkerState {
     /// Write-Cache-Enable state (if supported) of the underlying device
     wce_state: Mutex<Option<WceState>>,
 
+    /// Must writes be synced individually, as the device expects them to be
+    /// durable on completion, and the write cache could not be disabled?
+    sync_writes: AtomicBool,
+
+    /// Number of times written data has been synced
+    #[cfg(test)]
+    syncs: std::sync::atomic::AtomicUsize,
+
     info: block::DeviceInfo,
     skip_flush: bool,
+
+    /// Cipher for data encrypted at rest in the file (if any)
+    cipher: Option<BlockCipher>,
+
+    /// Cache of data read from the file (if read-only)
+    cache: Option<ReadCache>,
+
+    /// Checksums against which data read from the file is verified (if any)
+    integrity: Option<Integrity>,
 }
 struct WceState {
     initial: bool,
     current: bool,
 }
 impl WorkerState {
-    fn new(fp: File, info: block::DeviceInfo, skip_flush: bool) -> Arc<Self> {
+    fn new(
+        fp: File,
+        info: block::DeviceInfo,
+        skip_flush: bool,
+        cipher: Option<BlockCipher>,
+        cache: Option<ReadCache>,
+        integrity: Option<Integrity>,
+    ) -> Arc<Self> {
         let wce_state = match info.read_only {
             true => None,
             false => get_wce(&fp)
@@ -58,8 +97,14 @@ impl WorkerState {
             attachment: block::BackendAttachment::new(),
             fp,
             wce_state: Mutex::new(wce_state),
+            sync_writes: AtomicBool::new(false),
+            #[cfg(test)]
+            syncs: Default::default(),
             skip_flush,
             info,
+            cipher,
+            cache,
+            integrity,
         };
 
         // Attempt to enable write caching if underlying resource supports it
@@ -68,25 +113,36 @@ impl WorkerState {
         Arc::new(state)
     }
 
-    fn processing_loop(&self, acc_mem: MemAccessor) {
-        while let Some(req) = self.attachment.block_for_req() {
-            if self.info.read_only && req.oper().is_write() {
-                req.complete(block::Result::ReadOnly);
-                continue;
+    fn process(&self, req: block::Request, acc_mem: &MemAccessor) {
+        if self.info.read_only && req.oper().is_mutating() {
+            req.complete(block::Result::ReadOnly);
+            return;
+        }
+
+        let mem = match acc_mem.access() {
+            Some(m) => m,
+            None => {
+                req.complete(block::Result::Failure);
+                return;
             }
+        };
+        let res = match self.process_request(&req, &mem) {
+            Ok(_) => block::Result::Success,
+            Err(_) => block::Result::Failure,
+        };
+        req.complete(res);
+    }
 
-            let mem = match acc_mem.access() {
-                Some(m) => m,
-                None => {
-                    req.complete(block::Result::Failure);
-                    continue;
-                }
-            };
-            let res = match self.process_request(&req, &mem) {
-                Ok(_) => block::Result::Success,
-                Err(_) => block::Result::Failure,
-            };
-            req.complete(res);
+    /// Read (decrypting and verifying, if necessary) the file contents at
+    /// `off`
+    fn read_at(&self, off: usize, buf: &mut [u8]) -> Result<()> {
+        self.fp.read_exact_at(buf, off as u64)?;
+        if let Some(cipher) = self.cipher.as_ref() {
+            cipher.decrypt(off, buf)?;
+        }
+        match self.integrity.as_ref() {
+            Some(integrity) => integrity.verify(off, buf),
+            None => Ok(()),
         }
     }
 
@@ -99,6 +155,19 @@ impl WorkerState {
             block::Operation::Read(off, len) => {
                 let maps = req.mappings(mem).ok_or("mapping unavailable")?;
 
+                if let Some(cache) = self.cache.as_ref() {
+                    let mut buf = vec![0u8; len];
+                    cache
+                        .read(off, &mut buf, |off, buf| self.read_at(off, buf))
+                        .map_err(|_| "io error")?;
+                    return copy_to_mappings(&buf, &maps);
+                }
+                if self.cipher.is_some() || self.integrity.is_some() {
+                    let mut buf = vec![0u8; len];
+                    self.read_at(off, &mut buf).map_err(|_| "io error")?;
+                    return copy_to_mappings(&buf, &maps);
+                }
+
                 let nbytes = maps
                     .preadv(self.fp.as_raw_fd(), off as i64)
                     .map_err(|_| "io error")?;
@@ -109,22 +178,118 @@ impl WorkerState {
             block::Operation::Write(off, len) => {
                 let maps = req.mappings(mem).ok_or("bad guest region")?;
 
-                let nbytes = maps
-                    .pwritev(self.fp.as_raw_fd(), off as i64)
-                    .map_err(|_| "io error")?;
-                if nbytes != len {
-                    return Err("bad write length");
+                if self.cipher.is_some() || self.integrity.is_some() {
+                    let mut buf = vec![0u8; len];
+                    copy_from_mappings(&maps, &mut buf)?;
+                    // Checksums are of the plaintext, so that they can be
+                    // verified against the data as read back by the guest,
+                    // but are only recorded once the data has been written,
+                    // lest a failed write leave them describing data which
+                    // never reached the file.
+                    let sums = self
+                        .integrity
+                        .as_ref()
+                        .map(|integrity| integrity.sums(off, &buf))
+                        .transpose()
+                        .map_err(|_| "bad alignment")?;
+                    if let Some(cipher) = self.cipher.as_ref() {
+                        cipher
+                            .encrypt(off, &mut buf)
+                            .map_err(|_| "bad alignment")?;
+                    }
+                    self.fp
+                        .write_all_at(&buf, off as u64)
+                        .map_err(|_| "io error")?;
+                    if let (Some(integrity), Some(sums)) =
+                        (self.integrity.as_ref(), sums)
+                    {
+                        integrity.record_sums(sums).map_err(|_| "io error")?;
+                    }
+                } else {
+                    let nbytes = maps
+                        .pwritev(self.fp.as_raw_fd(), off as i64)
+                        .map_err(|_| "io error")?;
+                    if nbytes != len {
+                        return Err("bad write length");
+                    }
                 }
             }
             block::Operation::Flush => {
                 if !self.skip_flush {
-                    self.fp.sync_data().map_err(|_| "io error")?;
+                    self.sync().map_err(|_| "io error")?;
+                }
+            }
+            block::Operation::WriteZeroes(off, len) => {
+                let mut zeroes = vec![0u8; ZERO_BUF_SZ];
+                let mut done = 0;
+                while done < len {
+                    let chunk = (len - done).min(ZERO_BUF_SZ);
+                    let buf = &mut zeroes[..chunk];
+                    if let Some(cipher) = self.cipher.as_ref() {
+                        // The ciphertext of zeroed blocks differs by location,
+                        // so the buffer must be re-zeroed for each chunk.
+                        buf.fill(0);
+                        cipher
+                            .encrypt(off + done, buf)
+                            .map_err(|_| "bad alignment")?;
+                    }
+                    self.fp
+                        .write_all_at(buf, (off + done) as u64)
+                        .map_err(|_| "io error")?;
+                    done += chunk;
                 }
+                if let Some(integrity) = self.integrity.as_ref() {
+                    integrity
+                        .record_zeroes(off, len)
+                        .map_err(|_| "io error")?;
+                }
+            }
+            block::Operation::Discard(..) => {
+                // Discards are advisory, and there is not yet a portable means
+                // of punching holes in the backing file or device, so simply
+                // leave the existing data in place.
             }
         }
+        if matches!(
+            req.oper(),
+            block::Operation::Write(..) | block::Operation::WriteZeroes(..)
+        ) && self.sync_writes.load(Ordering::Relaxed)
+        {
+            self.sync().map_err(|_| "io error")?;
+        }
         Ok(())
     }
 
+    /// Sync written data (and checksums thereof) to stable storage
+    fn sync(&self) -> Result<()> {
+        #[cfg(test)]
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.fp.sync_data()?;
+        if let Some(integrity) = self.integrity.as_ref() {
+            integrity.sync()?;
+        }
+        Ok(())
+    }
+
+    fn set_cache_mode(&self, mode: block::CacheMode) {
+        let write_back = mode == block::CacheMode::WriteBack;
+        self.set_wce(write_back);
+
+        // Regular files, and devices whose write cache cannot be disabled,
+        // need writes synced as they are made in order to be durable.  When
+        // flushes are being skipped, there is no expectation of durability.
+        let wce_off = self
+            .wce_state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|state| !state.current);
+        self.sync_writes.store(
+            !write_back && !wce_off && !self.skip_flush,
+            Ordering::Relaxed,
+        );
+    }
+
     fn set_wce(&self, enabled: bool) {
         if let Some(state) = self.wce_state.lock().unwrap().as_mut() {
             if state.current != enabled {
@@ -149,19 +314,91 @@ impl Drop for WorkerState {
 
 impl FileBackend {
     /// Creates a new block device from a device at `path`.
+    ///
+    /// Requests are processed by a pool of `worker_count` threads dedicated to
+    /// this backend.
     pub fn create(
         path: impl AsRef<Path>,
         opts: block::BackendOpts,
         worker_count: NonZeroUsize,
     ) -> Result<Arc<Self>> {
+        let pool = Self::dedicated_pool(worker_count)?;
+        Self::create_inner(path.as_ref(), opts, pool, worker_count, None, None)
+    }
+
+    /// Creates a new block device from a device at `path`, the contents of
+    /// which are encrypted with AES-256-XTS using `key`.
+    ///
+    /// Each block is encrypted independently, so the block size (as set in
+    /// `opts`, or the default) must remain the same for the life of the data.
+    pub fn create_encrypted(
+        path: impl AsRef<Path>,
+        opts: block::BackendOpts,
+        worker_count: NonZeroUsize,
+        key: &XtsKey,
+    ) -> Result<Arc<Self>> {
+        let pool = Self::dedicated_pool(worker_count)?;
+        Self::create_inner(
+            path.as_ref(),
+            opts,
+            pool,
+            worker_count,
+            Some(key),
+            None,
+        )
+    }
+
+    /// Creates a new block device from a device at `path`, with requests
+    /// processed by the workers of `pool` (which may be shared with other
+    /// backends).
+    ///
+    /// No more than `max_in_flight` requests from this backend will be queued
+    /// to (or processed by) the pool at any one time.  If `key` is provided,
+    /// the contents of the device are encrypted, as in
+    /// [`FileBackend::create_encrypted()`].
+    ///
+    /// If `integrity_sidecar` is provided, a checksum of each block written to
+    /// the device is recorded in that file (which is created if necessary),
+    /// and blocks read from the device are verified against it, failing the
+    /// read on a mismatch.
+    pub fn create_pooled(
+        path: impl AsRef<Path>,
+        opts: block::BackendOpts,
+        pool: Arc<FileWorkerPool>,
+        max_in_flight: NonZeroUsize,
+        key: Option<&XtsKey>,
+        integrity_sidecar: Option<&Path>,
+    ) -> Result<Arc<Self>> {
+        Self::create_inner(
+            path.as_ref(),
+            opts,
+            pool,
+            max_in_flight,
+            key,
+            integrity_sidecar,
+        )
+    }
+
+    fn dedicated_pool(
+        worker_count: NonZeroUsize,
+    ) -> Result<Arc<FileWorkerPool>> {
         if worker_count.get() > MAX_WORKERS {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
                 "too many workers",
             ));
         }
-        let p: &Path = path.as_ref();
+        FileWorkerPool::new(worker_count)
+    }
 
+    fn create_inner(
+        p: &Path,
+        opts: block::BackendOpts,
+        pool: Arc<FileWorkerPool>,
+        max_in_flight: NonZeroUsize,
+        key: Option<&XtsKey>,
+        integrity_sidecar: Option<&Path>,
+    ) -> Result<Arc<Self>> {
         let meta = metadata(p)?;
         let read_only = match (opts.read_only, meta.permissions().readonly()) {
             (Some(false), true) => Err(Error::new(
@@ -183,31 +420,56 @@ impl FileBackend {
             read_only,
         };
         let skip_flush = opts.skip_flush.unwrap_or(false);
+        let cipher =
+            key.map(|key| BlockCipher::new(key, block_size)).transpose()?;
+        let cache = match opts.read_cache_size {
+            Some(_) if !read_only => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "read cache requires a read-only backend",
+                ))
+            }
+            Some(size) => ReadCache::new(size, len as usize),
+            None => None,
+        };
+        let integrity = integrity_sidecar
+            .map(|sidecar| {
+                Integrity::open(sidecar, block_size, info.total_size)
+            })
+            .transpose()?;
         Ok(Arc::new(Self {
-            state: WorkerState::new(fp, info, skip_flush),
-            worker_count,
-            workers: ThreadGroup::new(),
+            state: WorkerState::new(
+                fp, info, skip_flush, cipher, cache, integrity,
+            ),
+            pool,
+            max_in_flight,
+            member: Mutex::new(None),
+            dispatcher: ThreadGroup::new(),
         }))
     }
-    fn spawn_workers(&self) -> std::io::Result<()> {
-        let spawn_results = (0..self.worker_count.get())
-            .map(|n| {
-                let worker_state = self.state.clone();
-                let worker_acc = self
-                    .state
-                    .attachment
-                    .accessor_mem(|mem| mem.child(Some(format!("worker {n}"))))
-                    .expect("backend is attached");
-
-                std::thread::Builder::new()
-                    .name(format!("file worker {n}"))
-                    .spawn(move || {
-                        worker_state.processing_loop(worker_acc);
-                    })
-            })
-            .collect::<Vec<_>>();
+    fn join_pool(&self) -> std::io::Result<()> {
+        let acc_mem = self
+            .state
+            .attachment
+            .accessor_mem(|mem| mem.child(Some("file workers".to_string())))
+            .expect("backend is attached");
+        let id = self.pool.inner.add_member(self.state.clone(), acc_mem);
+        *self.member.lock().unwrap() = Some(id);
+
+        let pool = self.pool.inner.clone();
+        let state = self.state.clone();
+        let max_in_flight = self.max_in_flight.get();
+        let spawned = std::thread::Builder::new()
+            .name("file dispatch".to_string())
+            .spawn(move || pool.dispatch_loop(id, &state, max_in_flight));
+        self.dispatcher.extend(std::iter::once(spawned))
+    }
 
-        self.workers.extend(spawn_results.into_iter())
+    fn leave_pool(&self) {
+        self.dispatcher.block_until_joined();
+        if let Some(id) = self.member.lock().unwrap().take() {
+            self.pool.inner.remove_member(id);
+        }
     }
 }
 
@@ -221,20 +483,271 @@ impl block::Backend for FileBackend {
     }
     fn start(&self) -> anyhow::Result<()> {
         self.state.attachment.start();
-        if let Err(e) = self.spawn_workers() {
+        if let Err(e) = self.join_pool() {
             self.state.attachment.stop();
-            self.workers.block_until_joined();
-            Err(e).context("failure while spawning workers")
+            self.leave_pool();
+            Err(e).context("failure while spawning dispatcher")
         } else {
             Ok(())
         }
     }
     fn stop(&self) {
         self.state.attachment.stop();
+        self.leave_pool();
+    }
+    fn set_cache_mode(&self, mode: block::CacheMode) {
+        self.state.set_cache_mode(mode);
+    }
+    fn read_direct(
+        &self,
+        off: block::ByteOffset,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        self.state.read_at(off, buf)
+    }
+}
+
+/// A pool of worker threads which process requests on behalf of one or more
+/// [FileBackend]s.
+///
+/// Each backend queues its requests to the pool separately, and the workers
+/// service those queues in round-robin order, so that a busy backend is not
+/// able to starve the others of workers.
+pub struct FileWorkerPool {
+    inner: Arc<PoolInner>,
+    workers: ThreadGroup,
+}
+impl FileWorkerPool {
+    /// Create a pool of `worker_count` threads
+    pub fn new(worker_count: NonZeroUsize) -> Result<Arc<Self>> {
+        if worker_count.get() > MAX_POOL_WORKERS {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "too many workers",
+            ));
+        }
+        let pool = Self {
+            inner: Arc::new(PoolInner {
+                state: Mutex::new(PoolState::default()),
+                work_cv: Condvar::new(),
+                done_cv: Condvar::new(),
+            }),
+            workers: ThreadGroup::new(),
+        };
+
+        let spawn_results = (0..worker_count.get()).map(|n| {
+            let inner = pool.inner.clone();
+            std::thread::Builder::new()
+                .name(format!("file worker {n}"))
+                .spawn(move || inner.worker_loop())
+        });
+        // Should spawning fail, dropping the pool will clean up any workers
+        // which were started.
+        pool.workers.extend(spawn_results)?;
+
+        Ok(Arc::new(pool))
+    }
+}
+impl Drop for FileWorkerPool {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().shutdown = true;
+        self.inner.work_cv.notify_all();
         self.workers.block_until_joined();
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct MemberId(usize);
+
+/// Per-backend context needed by pool workers to process its requests
+struct MemberCtx {
+    state: Arc<WorkerState>,
+    acc_mem: MemAccessor,
+}
+struct PoolMember {
+    id: MemberId,
+    ctx: Arc<MemberCtx>,
+    /// Requests waiting for a worker
+    queued: VecDeque<block::Request>,
+    /// Requests being processed by workers
+    in_flight: usize,
+}
+impl PoolMember {
+    fn outstanding(&self) -> usize {
+        self.queued.len() + self.in_flight
+    }
+}
+
+#[derive(Default)]
+struct PoolState {
+    members: Vec<PoolMember>,
+    next_id: usize,
+    /// Index of the member to be offered the next free worker
+    cursor: usize,
+    shutdown: bool,
+}
+impl PoolState {
+    fn member_mut(&mut self, id: MemberId) -> Option<&mut PoolMember> {
+        self.members.iter_mut().find(|m| m.id == id)
+    }
+
+    /// Take the next queued request, visiting members in round-robin order
+    fn next_request(
+        &mut self,
+    ) -> Option<(MemberId, Arc<MemberCtx>, block::Request)> {
+        let count = self.members.len();
+        for n in 0..count {
+            let idx = (self.cursor + n) % count;
+            let member = &mut self.members[idx];
+            if let Some(req) = member.queued.pop_front() {
+                member.in_flight += 1;
+                self.cursor = (idx + 1) % count;
+                return Some((member.id, member.ctx.clone(), req));
+            }
+        }
+        None
+    }
+}
+
+struct PoolInner {
+    state: Mutex<PoolState>,
+    /// Signalled when requests are queued, or the pool is shutting down
+    work_cv: Condvar,
+    /// Signalled when workers finish processing requests
+    done_cv: Condvar,
+}
+impl PoolInner {
+    fn add_member(
+        &self,
+        state: Arc<WorkerState>,
+        acc_mem: MemAccessor,
+    ) -> MemberId {
+        let mut guard = self.state.lock().unwrap();
+        let id = MemberId(guard.next_id);
+        guard.next_id += 1;
+        guard.members.push(PoolMember {
+            id,
+            ctx: Arc::new(MemberCtx { state, acc_mem }),
+            queued: VecDeque::new(),
+            in_flight: 0,
+        });
+        id
+    }
+
+    /// Remove a member from the pool, once all of its queued requests have
+    /// been processed.
+    fn remove_member(&self, id: MemberId) {
+        let guard = self.state.lock().unwrap();
+        let mut guard = self
+            .done_cv
+            .wait_while(guard, |s| {
+                s.member_mut(id).map(|m| m.outstanding() != 0).unwrap_or(false)
+            })
+            .unwrap();
+        guard.members.retain(|m| m.id != id);
+        guard.cursor = 0;
+    }
+
+    /// Pull requests from a backend, queueing them to the pool for as long as
+    /// the backend is running.
+    fn dispatch_loop(
+        &self,
+        id: MemberId,
+        state: &WorkerState,
+        max_in_flight: usize,
+    ) {
+        loop {
+            {
+                let guard = self.state.lock().unwrap();
+                let _guard = self
+                    .done_cv
+                    .wait_while(guard, |s| {
+                        s.member_mut(id)
+                            .map(|m| m.outstanding() >= max_in_flight)
+                            .unwrap_or(false)
+                    })
+                    .unwrap();
+            }
+
+            let Some(req) = state.attachment.block_for_req() else {
+                return;
+            };
+
+            let mut guard = self.state.lock().unwrap();
+            match guard.member_mut(id) {
+                Some(member) => {
+                    member.queued.push_back(req);
+                    self.work_cv.notify_one();
+                }
+                None => {
+                    drop(guard);
+                    req.complete(block::Result::Failure);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn worker_loop(&self) {
+        let mut guard = self.state.lock().unwrap();
+        loop {
+            if guard.shutdown {
+                return;
+            }
+            let Some((id, ctx, req)) = guard.next_request() else {
+                guard = self.work_cv.wait(guard).unwrap();
+                continue;
+            };
+            drop(guard);
+
+            ctx.state.process(req, &ctx.acc_mem);
+            drop(ctx);
+
+            guard = self.state.lock().unwrap();
+            if let Some(member) = guard.member_mut(id) {
+                member.in_flight -= 1;
+            }
+            self.done_cv.notify_all();
+        }
+    }
+}
+
+/// Copy the contents of `buf` out to guest memory
+fn copy_to_mappings(
+    buf: &[u8],
+    maps: &[SubMapping],
+) -> std::result::Result<(), &'static str> {
+    let mut done = 0;
+    for map in maps {
+        let end = done + map.len();
+        let chunk = buf.get(done..end).ok_or("bad read length")?;
+        map.write_bytes(chunk).map_err(|_| "bad guest region")?;
+        done = end;
+    }
+    if done != buf.len() {
+        return Err("bad read length");
+    }
+    Ok(())
+}
+
+/// Fill `buf` with data from guest memory
+fn copy_from_mappings(
+    maps: &[SubMapping],
+    buf: &mut [u8],
+) -> std::result::Result<(), &'static str> {
+    let mut done = 0;
+    for map in maps {
+        let end = done + map.len();
+        let chunk = buf.get_mut(done..end).ok_or("bad write length")?;
+        map.read_bytes(chunk).map_err(|_| "bad guest region")?;
+        done = end;
+    }
+    if done != buf.len() {
+        return Err("bad write length");
+    }
+    Ok(())
+}
+
 /// Attempt to query the Write-Cache-Enable state for a given open device
 ///
 /// Block devices (including "real" disks and zvols) will regard all writes as
@@ -262,3 +775,242 @@ fn set_wce(fp: &File, enabled: bool) -> Result<bool> {
             .map(|_| enabled)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::{
+        BackendOpts, CacheMode, Device, DeviceAttachment, ReqId, XTS_KEY_LEN,
+    };
+    use crate::common::{GuestAddr, GuestRegion};
+    use crate::vmm::Machine;
+    use std::time::{Duration, Instant};
+
+    const BLOCK_SIZE: usize = 512;
+    /// Start of the test machine's RAM
+    const BUF_BASE: u64 = 0x10_0000;
+
+    /// Create a zeroed disk of `blocks` blocks in `dir`
+    fn disk(dir: &tempfile::TempDir, blocks: usize) -> std::path::PathBuf {
+        let path = dir.path().join("disk");
+        File::create(&path)
+            .unwrap()
+            .set_len((blocks * BLOCK_SIZE) as u64)
+            .unwrap();
+        path
+    }
+
+    fn opts() -> BackendOpts {
+        BackendOpts {
+            block_size: Some(BLOCK_SIZE as u32),
+            ..Default::default()
+        }
+    }
+
+    fn test_key() -> XtsKey {
+        XtsKey::new([0x5a; XTS_KEY_LEN])
+    }
+
+    /// A write of `len` bytes at `off` from the start of guest RAM
+    fn write_req(off: usize, len: usize) -> block::Request {
+        let region = GuestRegion(GuestAddr(BUF_BASE), len);
+        block::Request::new_write(off, len, vec![region])
+    }
+
+    /// A pool without workers, leaving requests queued until a test takes them
+    fn idle_pool() -> Arc<PoolInner> {
+        Arc::new(PoolInner {
+            state: Mutex::new(PoolState::default()),
+            work_cv: Condvar::new(),
+            done_cv: Condvar::new(),
+        })
+    }
+
+    fn wait_until(cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Complete a request taken from the pool, as a worker would
+    fn finish_one(pool: &PoolInner) {
+        let mut guard = pool.state.lock().unwrap();
+        let (id, _ctx, _req) = guard.next_request().unwrap();
+        guard.member_mut(id).unwrap().in_flight -= 1;
+        pool.done_cv.notify_all();
+    }
+
+    /// A device with `remaining` flush requests for its backend
+    struct TestDevice {
+        att: DeviceAttachment,
+        remaining: Mutex<usize>,
+    }
+    impl Device for TestDevice {
+        fn attachment(&self) -> &DeviceAttachment {
+            &self.att
+        }
+        fn next(&self) -> Option<block::Request> {
+            let mut remaining = self.remaining.lock().unwrap();
+            *remaining = remaining.checked_sub(1)?;
+            Some(block::Request::new_flush())
+        }
+        fn complete(&self, _res: block::Result, _id: ReqId) {}
+        fn accessor_mem(&self) -> MemAccessor {
+            MemAccessor::new_orphan()
+        }
+    }
+
+    #[test]
+    fn pool_round_robin() {
+        let dir = tempfile::tempdir().unwrap();
+        let one = NonZeroUsize::new(1).unwrap();
+        let backend = FileBackend::create(disk(&dir, 8), opts(), one).unwrap();
+        let pool = idle_pool();
+        let a =
+            pool.add_member(backend.state.clone(), MemAccessor::new_orphan());
+        let b =
+            pool.add_member(backend.state.clone(), MemAccessor::new_orphan());
+
+        let mut guard = pool.state.lock().unwrap();
+        let queued = &mut guard.member_mut(a).unwrap().queued;
+        queued.extend((0..3).map(|_| block::Request::new_flush()));
+        let queued = &mut guard.member_mut(b).unwrap().queued;
+        queued.push_back(block::Request::new_flush());
+
+        // The busy member gets no more than its turn while the other waits.
+        let order: Vec<usize> =
+            std::iter::from_fn(|| guard.next_request().map(|(id, ..)| id.0))
+                .collect();
+        assert_eq!(order, [a.0, b.0, a.0, a.0]);
+        assert_eq!(guard.member_mut(a).unwrap().in_flight, 3);
+        assert_eq!(guard.member_mut(b).unwrap().in_flight, 1);
+    }
+
+    #[test]
+    fn pool_dispatch_limited_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let one = NonZeroUsize::new(1).unwrap();
+        let backend = FileBackend::create(disk(&dir, 8), opts(), one).unwrap();
+        let dev = Arc::new(TestDevice {
+            att: DeviceAttachment::new(),
+            remaining: Mutex::new(8),
+        });
+        block::attach(dev.clone(), backend.clone()).unwrap();
+        backend.state.attachment.start();
+
+        let pool = idle_pool();
+        let id =
+            pool.add_member(backend.state.clone(), MemAccessor::new_orphan());
+        let dispatcher = {
+            let (pool, state) = (pool.clone(), backend.state.clone());
+            std::thread::spawn(move || pool.dispatch_loop(id, &state, 2))
+        };
+        let outstanding =
+            || pool.state.lock().unwrap().member_mut(id).unwrap().outstanding();
+        let remaining = || *dev.remaining.lock().unwrap();
+
+        wait_until(|| outstanding() == 2);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(outstanding(), 2);
+        assert_eq!(remaining(), 6);
+
+        // Each request finished lets one more in.
+        finish_one(&pool);
+        wait_until(|| remaining() == 5 && outstanding() == 2);
+
+        backend.state.attachment.stop();
+        pool.state.lock().unwrap().member_mut(id).unwrap().queued.clear();
+        pool.done_cv.notify_all();
+        dispatcher.join().unwrap();
+        block::Backend::detach(&*backend).unwrap();
+    }
+
+    #[test]
+    fn pool_member_removed_once_drained() {
+        let dir = tempfile::tempdir().unwrap();
+        let one = NonZeroUsize::new(1).unwrap();
+        let backend = FileBackend::create(disk(&dir, 8), opts(), one).unwrap();
+        let pool = idle_pool();
+        let id =
+            pool.add_member(backend.state.clone(), MemAccessor::new_orphan());
+        pool.state.lock().unwrap().members[0]
+            .queued
+            .extend((0..2).map(|_| block::Request::new_flush()));
+
+        let remover = {
+            let pool = pool.clone();
+            std::thread::spawn(move || pool.remove_member(id))
+        };
+        let present = || pool.state.lock().unwrap().member_mut(id).is_some();
+
+        finish_one(&pool);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(present(), "removed with a request outstanding");
+
+        finish_one(&pool);
+        remover.join().unwrap();
+        assert!(!present());
+    }
+
+    #[test]
+    fn encrypted_writes_synced_without_write_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FileBackend::create_encrypted(
+            disk(&dir, 8),
+            opts(),
+            NonZeroUsize::new(1).unwrap(),
+            &test_key(),
+        )
+        .unwrap();
+        let machine = Machine::new_test().unwrap();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        let state = &backend.state;
+
+        state.set_cache_mode(CacheMode::WriteBack);
+        state.process_request(&write_req(0, BLOCK_SIZE), &mem).unwrap();
+        assert_eq!(state.syncs.load(Ordering::Relaxed), 0);
+
+        state.set_cache_mode(CacheMode::Synchronous);
+        state
+            .process_request(&write_req(BLOCK_SIZE, BLOCK_SIZE), &mem)
+            .unwrap();
+        assert_eq!(state.syncs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn checksummed_writes_verified_on_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = FileWorkerPool::new(NonZeroUsize::new(1).unwrap()).unwrap();
+        let backend = FileBackend::create_pooled(
+            disk(&dir, 8),
+            opts(),
+            pool,
+            NonZeroUsize::new(1).unwrap(),
+            Some(&test_key()),
+            Some(&dir.path().join("sums")),
+        )
+        .unwrap();
+        let machine = Machine::new_test().unwrap();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        mem.write_from(GuestAddr(BUF_BASE), &data, data.len());
+        backend
+            .state
+            .process_request(&write_req(BLOCK_SIZE, data.len()), &mem)
+            .unwrap();
+
+        let mut buf = vec![0u8; data.len()];
+        block::Backend::read_direct(&*backend, BLOCK_SIZE, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // What reached the file is encrypted, not the data itself.
+        let mut raw = vec![0u8; data.len()];
+        backend.state.fp.read_exact_at(&mut raw, BLOCK_SIZE as u64).unwrap();
+        assert_ne!(raw, data);
+    }
+}
@@ -2,8 +2,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::io::{Error, ErrorKind, Result};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Write};
 use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use crate::accessors::MemAccessor;
@@ -12,6 +15,7 @@ use crate::tasks::ThreadGroup;
 use crate::vmm::{MemCtx, SubMapping};
 
 use anyhow::Context;
+use slog::{error, info};
 
 pub struct InMemoryBackend {
     state: Arc<WorkingState>,
@@ -23,11 +27,21 @@ struct WorkingState {
     attachment: block::BackendAttachment,
     bytes: Mutex<Vec<u8>>,
     info: block::DeviceInfo,
+
+    /// File to which the contents are written back when the backend is
+    /// stopped (if any)
+    persist: Option<Persist>,
+}
+struct Persist {
+    path: PathBuf,
+    /// Have the contents been modified since they were loaded or persisted?
+    dirty: AtomicBool,
+    log: slog::Logger,
 }
 impl WorkingState {
     fn processing_loop(&self, acc_mem: MemAccessor) {
         while let Some(req) = self.attachment.block_for_req() {
-            if self.info.read_only && req.oper().is_write() {
+            if self.info.read_only && req.oper().is_mutating() {
                 req.complete(block::Result::ReadOnly);
                 continue;
             }
@@ -40,13 +54,53 @@ impl WorkingState {
                 }
             };
             let res = match self.process_request(&req, &mem) {
-                Ok(_) => block::Result::Success,
+                Ok(_) => {
+                    if req.oper().is_mutating() {
+                        self.mark_dirty();
+                    }
+                    block::Result::Success
+                }
                 Err(_) => block::Result::Failure,
             };
             req.complete(res);
         }
     }
 
+    fn mark_dirty(&self) {
+        if let Some(persist) = self.persist.as_ref() {
+            persist.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Write the contents back to the persistence file, if they have been
+    /// modified.  The contents are written to a temporary file which then
+    /// replaces the original, so a failure part-way through does not leave
+    /// the persisted contents torn.  Returns whether anything was written.
+    fn persist(&self) -> Result<bool> {
+        let Some(persist) = self.persist.as_ref() else {
+            return Ok(false);
+        };
+        if !persist.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(false);
+        }
+
+        let mut tmp_name = persist.path.clone().into_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let res = (|| {
+            let bytes = self.bytes.lock().unwrap();
+            let mut fp = File::create(&tmp_path)?;
+            fp.write_all(&bytes)?;
+            fp.sync_all()?;
+            std::fs::rename(&tmp_path, &persist.path)
+        })();
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+            persist.dirty.store(true, Ordering::Release);
+        }
+        res.map(|_| true)
+    }
+
     fn process_request(
         &self,
         req: &block::Request,
@@ -79,6 +133,20 @@ impl WorkingState {
             block::Operation::Flush => {
                 // nothing to do
             }
+            block::Operation::WriteZeroes(off, len)
+            | block::Operation::Discard(off, len) => {
+                if self.info.read_only {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "backend is read-only",
+                    ));
+                }
+
+                // There is no allocation to give back for discarded regions,
+                // so zero them too, rather than leaving stale data around.
+                let mut bytes = self.bytes.lock().unwrap();
+                process_zero_request(&mut bytes, off as u64, len)?;
+            }
         }
 
         Ok(())
@@ -90,6 +158,40 @@ impl InMemoryBackend {
         bytes: Vec<u8>,
         opts: block::BackendOpts,
         worker_count: NonZeroUsize,
+    ) -> Result<Arc<Self>> {
+        Self::create_inner(bytes, opts, worker_count, None)
+    }
+
+    /// Creates a backend with contents loaded from the file at `path`.
+    ///
+    /// If the backend is writable, its contents are written back to `path`
+    /// when it is stopped (such as when the instance is cleanly shut down), so
+    /// they persist across uses of the backend.  Failure to do so is logged to
+    /// `log`.
+    pub fn create_persistent(
+        path: impl AsRef<Path>,
+        opts: block::BackendOpts,
+        worker_count: NonZeroUsize,
+        log: slog::Logger,
+    ) -> Result<Arc<Self>> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        let persist = match opts.read_only.unwrap_or(false) {
+            true => None,
+            false => Some(Persist {
+                path: path.to_path_buf(),
+                dirty: AtomicBool::new(false),
+                log,
+            }),
+        };
+        Self::create_inner(bytes, opts, worker_count, persist)
+    }
+
+    fn create_inner(
+        bytes: Vec<u8>,
+        opts: block::BackendOpts,
+        worker_count: NonZeroUsize,
+        persist: Option<Persist>,
     ) -> Result<Arc<Self>> {
         let block_size = opts.block_size.unwrap_or(block::DEFAULT_BLOCK_SIZE);
 
@@ -115,6 +217,7 @@ impl InMemoryBackend {
                     total_size: len as u64 / u64::from(block_size),
                     read_only: opts.read_only.unwrap_or(false),
                 },
+                persist,
             }),
             worker_count,
             workers: ThreadGroup::new(),
@@ -160,6 +263,47 @@ impl block::Backend for InMemoryBackend {
     fn stop(&self) {
         self.state.attachment.stop();
         self.workers.block_until_joined();
+
+        if let Some(persist) = self.state.persist.as_ref() {
+            match self.state.persist() {
+                Ok(false) => {}
+                Ok(true) => {
+                    info!(persist.log, "persisted in-memory disk contents";
+                          "path" => %persist.path.display());
+                }
+                Err(e) => {
+                    error!(
+                        persist.log,
+                        "failed to persist in-memory disk contents";
+                        "path" => %persist.path.display(),
+                        "error" => %e
+                    );
+                }
+            }
+        }
+    }
+    fn read_direct(
+        &self,
+        off: block::ByteOffset,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let bytes = self.state.bytes.lock().unwrap();
+        let src = off
+            .checked_add(buf.len())
+            .and_then(|end| bytes.get(off..end))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "invalid offset {} and len {} when bytes len is {}",
+                        off,
+                        buf.len(),
+                        bytes.len(),
+                    ),
+                )
+            })?;
+        buf.copy_from_slice(src);
+        Ok(())
     }
 }
 
@@ -196,6 +340,31 @@ fn process_read_request(
     Ok(())
 }
 
+/// Zero a region of bytes
+fn process_zero_request(
+    bytes: &mut [u8],
+    offset: u64,
+    len: usize,
+) -> Result<()> {
+    let start = offset as usize;
+    let end = offset as usize + len;
+
+    if start >= bytes.len() || end > bytes.len() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "invalid offset {} and len {} when bytes len is {}",
+                offset,
+                len,
+                bytes.len(),
+            ),
+        ));
+    }
+
+    bytes[start..end].fill(0);
+    Ok(())
+}
+
 /// Write from guest memory into bytes
 fn process_write_request(
     bytes: &mut [u8],
@@ -228,3 +397,157 @@ fn process_write_request(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::tracking::Tracking;
+    use crate::block::{Device, DeviceAttachment, ReqId};
+    use crate::vmm::Machine;
+    use std::collections::VecDeque;
+    use std::sync::{Condvar, Weak};
+    use std::time::Duration;
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// A device which submits the requests queued in it, recording their
+    /// results as the backend completes them
+    struct TestDevice {
+        att: DeviceAttachment,
+        tracking: Tracking<()>,
+        acc_mem: MemAccessor,
+        queued: Mutex<VecDeque<block::Request>>,
+        results: Mutex<Vec<block::Result>>,
+        done: Condvar,
+    }
+    impl TestDevice {
+        fn new(acc_mem: MemAccessor) -> Arc<Self> {
+            Arc::new_cyclic(|me: &Weak<Self>| Self {
+                att: DeviceAttachment::new(),
+                tracking: Tracking::new(me.clone()),
+                acc_mem,
+                queued: Mutex::new(VecDeque::new()),
+                results: Mutex::new(Vec::new()),
+                done: Condvar::new(),
+            })
+        }
+
+        /// Submit `reqs` to the backend, and wait for all of their results
+        fn submit(&self, reqs: Vec<block::Request>) -> Vec<block::Result> {
+            let count = reqs.len();
+            self.queued.lock().unwrap().extend(reqs);
+            self.att.notify();
+
+            let results = self.results.lock().unwrap();
+            let (mut results, timeout) = self
+                .done
+                .wait_timeout_while(results, Duration::from_secs(5), |r| {
+                    r.len() < count
+                })
+                .unwrap();
+            assert!(!timeout.timed_out(), "requests not completed");
+            std::mem::take(&mut *results)
+        }
+    }
+    impl Device for TestDevice {
+        fn attachment(&self) -> &DeviceAttachment {
+            &self.att
+        }
+        fn next(&self) -> Option<block::Request> {
+            let req = self.queued.lock().unwrap().pop_front()?;
+            Some(self.tracking.track(req, ()))
+        }
+        fn complete(&self, res: block::Result, id: ReqId) {
+            self.tracking.complete(id, res);
+            self.results.lock().unwrap().push(res);
+            self.done.notify_all();
+        }
+        fn accessor_mem(&self) -> MemAccessor {
+            self.acc_mem.child(None)
+        }
+    }
+
+    /// Attach an in-memory backend of `blocks` blocks, all bytes of which are
+    /// 0xff, to a device
+    fn attached(
+        machine: &Machine,
+        blocks: usize,
+        read_only: bool,
+    ) -> (Arc<TestDevice>, Arc<InMemoryBackend>) {
+        let backend = InMemoryBackend::create(
+            vec![0xff; blocks * BLOCK_SIZE],
+            block::BackendOpts {
+                block_size: Some(BLOCK_SIZE as u32),
+                read_only: Some(read_only),
+                ..Default::default()
+            },
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap();
+        let dev = TestDevice::new(machine.acc_mem.child(None));
+        block::attach(dev.clone(), backend.clone()).unwrap();
+        block::Backend::start(&*backend).unwrap();
+        (dev, backend)
+    }
+
+    fn teardown(backend: &InMemoryBackend) {
+        block::Backend::stop(backend);
+        block::Backend::detach(backend).unwrap();
+    }
+
+    fn contents(backend: &InMemoryBackend, blocks: usize) -> Vec<u8> {
+        let mut buf = vec![0; blocks * BLOCK_SIZE];
+        block::Backend::read_direct(backend, 0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn write_zeroes_and_discard_zero_range() {
+        let machine = Machine::new_test().unwrap();
+        let (dev, backend) = attached(&machine, 4, false);
+
+        let results = dev.submit(vec![
+            block::Request::new_write_zeroes(BLOCK_SIZE, BLOCK_SIZE),
+            block::Request::new_discard(3 * BLOCK_SIZE, BLOCK_SIZE),
+        ]);
+        assert!(results.iter().all(|res| !res.is_err()));
+
+        // Only the blocks named are zeroed.
+        let buf = contents(&backend, 4);
+        for (n, block) in buf.chunks(BLOCK_SIZE).enumerate() {
+            let fill = if n == 1 || n == 3 { 0 } else { 0xff };
+            assert!(block.iter().all(|&b| b == fill), "block {n}");
+        }
+        teardown(&backend);
+    }
+
+    #[test]
+    fn write_zeroes_and_discard_refused_when_read_only() {
+        let machine = Machine::new_test().unwrap();
+        let (dev, backend) = attached(&machine, 2, true);
+
+        let results = dev.submit(vec![
+            block::Request::new_write_zeroes(0, BLOCK_SIZE),
+            block::Request::new_discard(BLOCK_SIZE, BLOCK_SIZE),
+        ]);
+        assert!(results
+            .iter()
+            .all(|res| matches!(res, block::Result::ReadOnly)));
+        assert!(contents(&backend, 2).iter().all(|&b| b == 0xff));
+        teardown(&backend);
+    }
+
+    #[test]
+    fn write_zeroes_past_end_fails() {
+        let machine = Machine::new_test().unwrap();
+        let (dev, backend) = attached(&machine, 2, false);
+
+        let results = dev.submit(vec![block::Request::new_write_zeroes(
+            BLOCK_SIZE,
+            2 * BLOCK_SIZE,
+        )]);
+        assert!(matches!(results[..], [block::Result::Failure]));
+        assert!(contents(&backend, 2).iter().all(|&b| b == 0xff));
+        teardown(&backend);
+    }
+}
@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Verification of block data against checksums kept in a sidecar file.
+//!
+//! The sidecar holds a CRC32 (little-endian) for each block of the device,
+//! recorded as blocks are written and checked as they are read back.  A
+//! checksum of zero means the contents of the block are unknown (as is the
+//! case for every block when the sidecar is first created), and are not
+//! verified.  This is meant for catching data corruption introduced by device
+//! emulation during testing, rather than as any protection against tampering.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Size (in bytes) of the checksum stored for each block
+const SUM_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Per-block checksums of a device, stored in a sidecar file.
+/// Checksums of blocks about to be written, and their position in the sidecar
+pub(super) struct Sums {
+    pos: u64,
+    bytes: Vec<u8>,
+}
+
+pub(super) struct Integrity {
+    sidecar: File,
+    block_size: usize,
+    /// Checksum of a block filled with zeroes
+    zero_sum: u32,
+}
+impl Integrity {
+    /// Open (creating, if necessary) the sidecar at `path` for a device of
+    /// `total_blocks` blocks of `block_size` bytes.
+    pub(super) fn open(
+        path: &Path,
+        block_size: u32,
+        total_blocks: u64,
+    ) -> Result<Self> {
+        let sidecar = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = total_blocks * SUM_SIZE as u64;
+        if sidecar.metadata()?.len() != len {
+            // Blocks beyond the end of a short sidecar are left as unknown.
+            sidecar.set_len(len)?;
+        }
+        let block_size = block_size as usize;
+        Ok(Self {
+            sidecar,
+            block_size,
+            zero_sum: checksum(&vec![0u8; block_size]),
+        })
+    }
+
+    fn check(&self, off: usize, len: usize) -> Result<u64> {
+        if off % self.block_size != 0 || len % self.block_size != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "verified I/O (off {}, len {}) not aligned to block \
+                    size {}",
+                    off, len, self.block_size
+                ),
+            ));
+        }
+        Ok(((off / self.block_size) * SUM_SIZE) as u64)
+    }
+
+    /// Record the checksums of `data`, which holds the blocks being written at
+    /// byte offset `off`.
+    pub(super) fn record(&self, off: usize, data: &[u8]) -> Result<()> {
+        self.record_sums(self.sums(off, data)?)
+    }
+
+    /// Compute the checksums of `data`, which holds the blocks to be written
+    /// at byte offset `off`, to be recorded with [Integrity::record_sums] once
+    /// they have been.
+    pub(super) fn sums(&self, off: usize, data: &[u8]) -> Result<Sums> {
+        let pos = self.check(off, data.len())?;
+        let bytes = data
+            .chunks(self.block_size)
+            .flat_map(|block| checksum(block).to_le_bytes())
+            .collect();
+        Ok(Sums { pos, bytes })
+    }
+
+    /// Record checksums computed by [Integrity::sums].
+    pub(super) fn record_sums(&self, sums: Sums) -> Result<()> {
+        self.sidecar.write_all_at(&sums.bytes, sums.pos)
+    }
+
+    /// Record the checksums of `len` bytes of zeroed blocks at byte offset
+    /// `off`.
+    pub(super) fn record_zeroes(&self, off: usize, len: usize) -> Result<()> {
+        let pos = self.check(off, len)?;
+        let sums: Vec<u8> = std::iter::repeat(self.zero_sum.to_le_bytes())
+            .take(len / self.block_size)
+            .flatten()
+            .collect();
+        self.sidecar.write_all_at(&sums, pos)
+    }
+
+    /// Verify `data`, read from the blocks at byte offset `off`, against their
+    /// recorded checksums.
+    pub(super) fn verify(&self, off: usize, data: &[u8]) -> Result<()> {
+        let pos = self.check(off, data.len())?;
+        let mut sums = vec![0u8; (data.len() / self.block_size) * SUM_SIZE];
+        self.sidecar.read_exact_at(&mut sums, pos)?;
+
+        let first = off / self.block_size;
+        for (i, (block, sum)) in data
+            .chunks(self.block_size)
+            .zip(sums.chunks_exact(SUM_SIZE))
+            .enumerate()
+        {
+            let expected = u32::from_le_bytes(sum.try_into().unwrap());
+            if expected == 0 {
+                continue;
+            }
+            let actual = checksum(block);
+            if actual != expected {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "checksum mismatch in block {} \
+                        (expected {:#010x}, got {:#010x})",
+                        first + i,
+                        expected,
+                        actual
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush recorded checksums to stable storage.
+    pub(super) fn sync(&self) -> Result<()> {
+        self.sidecar.sync_data()
+    }
+}
+
+/// Checksum a block, reserving zero to mean "unknown"
+fn checksum(block: &[u8]) -> u32 {
+    match crc32fast::hash(block) {
+        0 => 1,
+        sum => sum,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(dir: &tempfile::TempDir) -> Integrity {
+        Integrity::open(&dir.path().join("sums"), 512, 8).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = open(&dir);
+        let data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+
+        integrity.record(512, &data).unwrap();
+        integrity.verify(512, &data).unwrap();
+        integrity.verify(1024, &data[512..]).unwrap();
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = open(&dir);
+        let mut data = [0xa5u8; 1024];
+
+        integrity.record(0, &data).unwrap();
+        data[700] ^= 1;
+        let err = integrity.verify(0, &data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_blocks_unverified() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = open(&dir);
+        integrity.verify(0, &[0x5au8; 4096]).unwrap();
+    }
+
+    #[test]
+    fn zeroes() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = open(&dir);
+
+        integrity.record(0, &[0xffu8; 2048]).unwrap();
+        integrity.record_zeroes(512, 1024).unwrap();
+        integrity.verify(512, &[0u8; 1024]).unwrap();
+        assert!(integrity.verify(0, &[0u8; 512]).is_err());
+    }
+
+    #[test]
+    fn unaligned_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = open(&dir);
+        assert!(integrity.record(100, &[0u8; 512]).is_err());
+        assert!(integrity.verify(0, &[0u8; 100]).is_err());
+    }
+}
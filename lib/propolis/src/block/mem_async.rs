@@ -38,7 +38,7 @@ impl WorkingState {
             Some(w) => w,
         };
         while let Some(req) = waiter.for_req().await {
-            if self.info.read_only && req.oper().is_write() {
+            if self.info.read_only && req.oper().is_mutating() {
                 req.complete(block::Result::ReadOnly);
                 continue;
             }
@@ -97,6 +97,12 @@ impl WorkingState {
             block::Operation::Flush => {
                 // nothing to do
             }
+            block::Operation::WriteZeroes(off, len)
+            | block::Operation::Discard(off, len) => {
+                if !seg.zero(off, len) {
+                    return Err("failed mem zero");
+                }
+            }
         }
 
         Ok(())
@@ -185,6 +191,15 @@ impl MmapSeg {
         self.0.as_ptr().add(off).copy_from_nonoverlapping(data, sz);
         true
     }
+    fn zero(&self, off: usize, sz: usize) -> bool {
+        if (off + sz) > self.1 {
+            return false;
+        }
+
+        // Safety: the range was bounds-checked against the segment above
+        unsafe { self.0.as_ptr().add(off).write_bytes(0, sz) };
+        true
+    }
     unsafe fn read(&self, off: usize, data: *mut u8, sz: usize) -> bool {
         if (off + sz) > self.1 {
             return false;
@@ -222,4 +237,19 @@ impl block::Backend for MemAsyncBackend {
         self.work_state.attachment.stop();
         self.workers.block_until_joined();
     }
+
+    fn read_direct(
+        &self,
+        off: block::ByteOffset,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        // Safety: `buf` is a valid, exclusively-borrowed destination for its
+        // full length, and the segment bounds are checked by `read()`.
+        if unsafe { self.work_state.seg.read(off, buf.as_mut_ptr(), buf.len()) }
+        {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidInput, "read beyond end of disk"))
+        }
+    }
 }
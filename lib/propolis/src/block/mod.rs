@@ -11,13 +11,18 @@ use crate::attachment::DetachError;
 use crate::common::*;
 use crate::vmm::{MemCtx, SubMapping};
 
+mod cache;
+mod crypt;
+pub use crypt::{XtsKey, XTS_KEY_LEN};
+mod integrity;
+
 mod file;
-pub use file::FileBackend;
+pub use file::{FileBackend, FileWorkerPool};
 
 #[cfg(feature = "crucible")]
 mod crucible;
 #[cfg(feature = "crucible")]
-pub use self::crucible::CrucibleBackend;
+pub use self::crucible::{ActivationState, CrucibleBackend, ReplicaHealth};
 
 mod in_memory;
 pub use in_memory::InMemoryBackend;
@@ -30,6 +35,9 @@ pub mod tracking;
 
 pub use attachment::{attach, BackendAttachment, DeviceAttachment};
 
+mod retry;
+pub use retry::{ErrorNotifier, ErrorPolicy};
+
 pub type ByteOffset = usize;
 pub type ByteLen = usize;
 
@@ -37,11 +45,63 @@ pub type ByteLen = usize;
 /// is not choosing a block size, a default of 512B is used.
 pub const DEFAULT_BLOCK_SIZE: u32 = 512;
 
+/// USDT probes for the block layer.
+///
+/// Requests are identified by the pair of `dev_id` (unique to each device in
+/// the process) and `req_id` (unique to each request to that device).  The name
+/// given to the device by its consumer (see [`DeviceAttachment::set_name()`]) is
+/// provided as the last argument of each probe, empty if the device is unnamed.
+///
+/// A request is "begun" when submitted by the device, "dispatched" each time
+/// the backend takes it to be processed (more than once, if it is retried per
+/// the device's [ErrorPolicy]), and "completed" when its result is returned to
+/// the device.  The `queue_ns` reported at completion is the time between the
+/// request being submitted and its first dispatch, while `proc_ns` is the time
+/// from then until completion.
 #[usdt::provider(provider = "propolis")]
 mod probes {
-    fn block_begin_read(dev_id: u64, req_id: u64, offset: u64, len: u64) {}
-    fn block_begin_write(dev_id: u64, req_id: u64, offset: u64, len: u64) {}
-    fn block_begin_flush(dev_id: u64, req_id: u64) {}
+    fn block_begin_read(
+        dev_id: u64,
+        req_id: u64,
+        offset: u64,
+        len: u64,
+        dev_name: &str,
+    ) {
+    }
+    fn block_begin_write(
+        dev_id: u64,
+        req_id: u64,
+        offset: u64,
+        len: u64,
+        dev_name: &str,
+    ) {
+    }
+    fn block_begin_flush(dev_id: u64, req_id: u64, dev_name: &str) {}
+    fn block_begin_write_zeroes(
+        dev_id: u64,
+        req_id: u64,
+        offset: u64,
+        len: u64,
+        dev_name: &str,
+    ) {
+    }
+    fn block_begin_discard(
+        dev_id: u64,
+        req_id: u64,
+        offset: u64,
+        len: u64,
+        dev_name: &str,
+    ) {
+    }
+
+    fn block_dispatch(
+        dev_id: u64,
+        req_id: u64,
+        attempt: u32,
+        queue_ns: u64,
+        dev_name: &str,
+    ) {
+    }
 
     fn block_complete_read(
         dev_id: u64,
@@ -49,6 +109,7 @@ mod probes {
         result: u8,
         proc_ns: u64,
         queue_ns: u64,
+        dev_name: &str,
     ) {
     }
     fn block_complete_write(
@@ -57,6 +118,7 @@ mod probes {
         result: u8,
         proc_ns: u64,
         queue_ns: u64,
+        dev_name: &str,
     ) {
     }
     fn block_complete_flush(
@@ -65,6 +127,25 @@ mod probes {
         result: u8,
         proc_ns: u64,
         queue_ns: u64,
+        dev_name: &str,
+    ) {
+    }
+    fn block_complete_write_zeroes(
+        dev_id: u64,
+        req_id: u64,
+        result: u8,
+        proc_ns: u64,
+        queue_ns: u64,
+        dev_name: &str,
+    ) {
+    }
+    fn block_complete_discard(
+        dev_id: u64,
+        req_id: u64,
+        result: u8,
+        proc_ns: u64,
+        queue_ns: u64,
+        dev_name: &str,
     ) {
     }
 }
@@ -78,6 +159,13 @@ pub enum Operation {
     Write(ByteOffset, ByteLen),
     /// Flush buffer(s)
     Flush,
+    /// Write zeroes to `offset` for `len`
+    WriteZeroes(ByteOffset, ByteLen),
+    /// Discard (deallocate) the data at `offset` for `len`.
+    ///
+    /// This is advisory: subsequent reads of the region may return either
+    /// zeroes or the data which was there prior to the discard.
+    Discard(ByteOffset, ByteLen),
 }
 impl Operation {
     pub const fn is_read(&self) -> bool {
@@ -89,6 +177,22 @@ impl Operation {
     pub const fn is_flush(&self) -> bool {
         matches!(self, Operation::Flush)
     }
+    pub const fn is_write_zeroes(&self) -> bool {
+        matches!(self, Operation::WriteZeroes(..))
+    }
+    pub const fn is_discard(&self) -> bool {
+        matches!(self, Operation::Discard(..))
+    }
+    /// Does this operation (potentially) alter the contents of the device,
+    /// making it unsuitable for a read-only backend?
+    pub const fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Operation::Write(..)
+                | Operation::WriteZeroes(..)
+                | Operation::Discard(..)
+        )
+    }
 }
 
 /// Result of a block [`Request`]
@@ -123,6 +227,10 @@ pub struct Request {
     /// the result of the block request is communicated back to the device
     /// emulation for processing.
     marker: Option<tracking::TrackingMarker>,
+
+    /// Number of times this request has been reissued to the backend after
+    /// failing, as directed by the device's [ErrorPolicy].
+    attempts: u32,
 }
 impl Request {
     pub fn new_read(
@@ -130,7 +238,12 @@ impl Request {
         len: ByteLen,
         regions: Vec<GuestRegion>,
     ) -> Self {
-        Self { op: Operation::Read(off, len), regions, marker: None }
+        Self {
+            op: Operation::Read(off, len),
+            regions,
+            marker: None,
+            attempts: 0,
+        }
     }
 
     pub fn new_write(
@@ -138,12 +251,27 @@ impl Request {
         len: ByteLen,
         regions: Vec<GuestRegion>,
     ) -> Self {
-        Self { op: Operation::Write(off, len), regions, marker: None }
+        Self {
+            op: Operation::Write(off, len),
+            regions,
+            marker: None,
+            attempts: 0,
+        }
     }
 
     pub fn new_flush() -> Self {
         let op = Operation::Flush;
-        Self { op, regions: Vec::new(), marker: None }
+        Self { op, regions: Vec::new(), marker: None, attempts: 0 }
+    }
+
+    pub fn new_write_zeroes(off: ByteOffset, len: ByteLen) -> Self {
+        let op = Operation::WriteZeroes(off, len);
+        Self { op, regions: Vec::new(), marker: None, attempts: 0 }
+    }
+
+    pub fn new_discard(off: ByteOffset, len: ByteLen) -> Self {
+        let op = Operation::Discard(off, len);
+        Self { op, regions: Vec::new(), marker: None, attempts: 0 }
     }
 
     /// Type of operation being issued.
@@ -164,14 +292,34 @@ impl Request {
             Operation::Write(..) => {
                 self.regions.iter().map(|r| mem.readable_region(r)).collect()
             }
-            Operation::Flush => None,
+            Operation::Flush
+            | Operation::WriteZeroes(..)
+            | Operation::Discard(..) => None,
         }
     }
 
     /// Indicate disposition of completed request
-    pub fn complete(mut self, res: Result) {
-        if let Some(marker) = self.marker.take() {
-            marker.complete(res);
+    ///
+    /// Failures may not be passed on to the device immediately: depending on
+    /// the [ErrorPolicy] of the device, the request may instead be held to be
+    /// reissued to the backend at a later time.
+    pub fn complete(self, res: Result) {
+        let dev = match self.marker.as_ref() {
+            Some(marker) => marker.device(),
+            None => return,
+        };
+        if let Some(mut req) = dev.attachment().process_result(&dev, self, res)
+        {
+            if let Some(marker) = req.marker.take() {
+                marker.complete(res);
+            }
+        }
+    }
+
+    /// Record that the request is being taken by the backend to be processed
+    fn dispatched(&self) {
+        if let Some(marker) = self.marker.as_ref() {
+            marker.dispatched(self.attempts);
         }
     }
 }
@@ -210,6 +358,17 @@ pub struct BackendOpts {
 
     /// Force flush requests to be skipped (turned into no-op)
     pub skip_flush: Option<bool>,
+
+    /// Size (in bytes) of an in-memory cache of data read from the backend.
+    /// Only read-only backends may be cached.
+    pub read_cache_size: Option<usize>,
+
+    /// Start without waiting for the backend to attach to its storage, instead
+    /// attaching in the background (retrying failed attempts) and holding
+    /// requests until it has.  Otherwise, the backend attempts to attach once
+    /// as it starts, and fails to start if that fails.  Only Crucible backends
+    /// attach to anything.
+    pub lazy_attach: Option<bool>,
 }
 
 /// Top-level trait for block devices (frontends) to translate guest block IO
@@ -255,6 +414,31 @@ pub trait Backend: Send + Sync + 'static {
     /// brought to rest as part of this call.
     fn stop(&self);
 
+    /// Set the [CacheMode] expected by the attached [Device]
+    ///
+    /// Backends start out in [CacheMode::WriteBack].  Those which keep no
+    /// volatile state for written data, or which cannot make it durable
+    /// anyways, need not do anything here.
+    fn set_cache_mode(&self, _mode: CacheMode) {}
+
+    /// Read `buf.len()` bytes, starting at `off`, directly from the underlying
+    /// storage on behalf of the host (rather than the attached [Device]).
+    ///
+    /// This is meant for copying disk contents out of a running instance.  It
+    /// makes no attempt to order itself against requests being processed from
+    /// the device: callers wanting a consistent view of the data should pause
+    /// the device and wait for its in-flight requests to drain first.
+    fn read_direct(
+        &self,
+        _off: ByteOffset,
+        _buf: &mut [u8],
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "backend does not support direct reads",
+        ))
+    }
+
     /// Attempt to detach from associated [Device]
     ///
     /// Any attached backend should be [stopped](Backend::stop()) and detached
@@ -266,8 +450,12 @@ pub trait Backend: Send + Sync + 'static {
     }
 }
 
+/// Caching behavior a [Device] expects of writes issued to its [Backend]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum CacheMode {
+    /// Writes are durable once they complete
     Synchronous,
+    /// Writes may be held in a volatile cache until the next flush
     WriteBack,
 }
 
@@ -0,0 +1,422 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Handling of failed block requests, per the [ErrorPolicy] of a device.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::{Device, Request, Result};
+
+/// Delay before a failed request is first reissued to the backend
+const BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+/// Upper bound on the delay between attempts at reissuing a failed request
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Policy governing how a block device handles requests which the backend
+/// reports as having failed.
+///
+/// Only [`Result::Failure`] is subject to the policy.  Other errors (such as
+/// writes to a read-only backend) are not expected to be transient, and are
+/// always reported to the guest.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Report failures to the guest as they occur.
+    #[default]
+    Report,
+
+    /// Reissue failed requests to the backend (with exponential backoff
+    /// between attempts) up to `max_attempts` times before reporting the
+    /// failure to the guest.
+    Retry { max_attempts: u32 },
+
+    /// Hold failed requests, reissuing them (with exponential backoff between
+    /// attempts) until they succeed.  The device's [ErrorNotifier] is informed
+    /// when I/O stalls in this way, and again once it recovers, so the
+    /// consumer can pause the instance in the meantime.
+    Pause,
+}
+
+/// Receives notifications when I/O to a device stalls (and recovers) under
+/// [`ErrorPolicy::Pause`].
+///
+/// One notifier may be shared by several devices, which are identified by
+/// name.  Each device's stalls and recoveries alternate, but those of
+/// different devices may overlap.
+pub trait ErrorNotifier: Send + Sync + 'static {
+    /// A request to the device named `device` has failed and is being held
+    /// for retry.
+    fn io_stalled(&self, device: &str);
+
+    /// All of the requests held for the device named `device` have been
+    /// retired, either by succeeding or because the device was paused or its
+    /// backend stopped.
+    fn io_resumed(&self, device: &str);
+}
+
+struct Held {
+    req: Request,
+    ready_at: Instant,
+}
+
+struct RetryInner {
+    policy: ErrorPolicy,
+    notifier: Option<Arc<dyn ErrorNotifier>>,
+    /// Name of the device, as given to the notifier
+    name: String,
+
+    /// Failed requests waiting to be reissued to the backend
+    held: VecDeque<Held>,
+
+    /// Is I/O considered stalled (under [`ErrorPolicy::Pause`])?
+    stalled: bool,
+    /// Device is paused: failed requests may not be held
+    paused: bool,
+    /// Backend is stopped: failed requests may not be held
+    stopped: bool,
+
+    /// Is a thread running to wake the backend when held requests are ready?
+    timer_running: bool,
+}
+
+/// Record-keeping for failed requests held (per the [ErrorPolicy]) to be
+/// reissued to the backend.
+pub(super) struct RetryState {
+    inner: Mutex<RetryInner>,
+    cv: Condvar,
+}
+impl RetryState {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(RetryInner {
+                policy: ErrorPolicy::default(),
+                notifier: None,
+                name: String::new(),
+                held: VecDeque::new(),
+                stalled: false,
+                paused: false,
+                stopped: false,
+                timer_running: false,
+            }),
+            cv: Condvar::new(),
+        })
+    }
+
+    pub(super) fn set_policy(
+        &self,
+        policy: ErrorPolicy,
+        notifier: Option<Arc<dyn ErrorNotifier>>,
+        name: &str,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.policy = policy;
+        inner.notifier = notifier;
+        inner.name = name.to_string();
+    }
+
+    /// Process the result of a completed request, holding it for retry if the
+    /// policy calls for it.  Returns the request if it should instead be
+    /// completed to the device.
+    pub(super) fn process(
+        self: &Arc<Self>,
+        mut req: Request,
+        res: Result,
+        dev: Weak<dyn Device>,
+    ) -> Option<Request> {
+        let mut inner = self.inner.lock().unwrap();
+        if !matches!(res, Result::Failure) {
+            if inner.stalled && inner.held.is_empty() {
+                inner.stalled = false;
+                let notifier = inner.notifier.clone();
+                let name = inner.name.clone();
+                drop(inner);
+                if let Some(notifier) = notifier {
+                    notifier.io_resumed(&name);
+                }
+            }
+            return Some(req);
+        }
+
+        if inner.paused || inner.stopped {
+            return Some(req);
+        }
+        let newly_stalled = match inner.policy {
+            ErrorPolicy::Report => return Some(req),
+            ErrorPolicy::Retry { max_attempts } => {
+                if req.attempts >= max_attempts {
+                    return Some(req);
+                }
+                false
+            }
+            ErrorPolicy::Pause => !std::mem::replace(&mut inner.stalled, true),
+        };
+
+        let ready_at = Instant::now() + backoff(req.attempts);
+        req.attempts = req.attempts.saturating_add(1);
+        inner.held.push_back(Held { req, ready_at });
+
+        if inner.timer_running {
+            // Let the timer thread know about the (possibly earlier) deadline
+            self.cv.notify_all();
+        } else {
+            inner.timer_running = true;
+            let state = self.clone();
+            let spawned = std::thread::Builder::new()
+                .name("block retry".to_string())
+                .spawn(move || state.timer_loop(dev));
+            if spawned.is_err() {
+                inner.timer_running = false;
+            }
+        }
+
+        let notifier = inner.notifier.clone().filter(|_| newly_stalled);
+        let name = inner.name.clone();
+        drop(inner);
+        if let Some(notifier) = notifier {
+            notifier.io_stalled(&name);
+        }
+        None
+    }
+
+    /// Take a held request which is ready to be reissued (if any)
+    pub(super) fn take_ready(&self) -> Option<Request> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let idx = inner.held.iter().position(|h| h.ready_at <= now)?;
+        inner.held.remove(idx).map(|h| h.req)
+    }
+
+    /// Update the paused state of the device.  When paused, any held requests
+    /// are returned so that they can be completed (as failed) to the device.
+    pub(super) fn set_paused(&self, paused: bool) -> Vec<Request> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.paused = paused;
+        if paused {
+            self.drain(inner)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Update the stopped state of the backend.  When stopped, any held
+    /// requests are returned so that they can be completed (as failed) to the
+    /// device.
+    pub(super) fn set_stopped(&self, stopped: bool) -> Vec<Request> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stopped = stopped;
+        if stopped {
+            self.drain(inner)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn drain(
+        &self,
+        mut inner: std::sync::MutexGuard<'_, RetryInner>,
+    ) -> Vec<Request> {
+        let reqs = inner.held.drain(..).map(|h| h.req).collect();
+        self.cv.notify_all();
+        if std::mem::replace(&mut inner.stalled, false) {
+            let notifier = inner.notifier.clone();
+            let name = inner.name.clone();
+            drop(inner);
+            if let Some(notifier) = notifier {
+                notifier.io_resumed(&name);
+            }
+        }
+        reqs
+    }
+
+    /// Wake the backend (through the device attachment) as held requests
+    /// become ready to be reissued, for as long as any are held.
+    fn timer_loop(&self, dev: Weak<dyn Device>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut last_wake = Instant::now();
+        loop {
+            let next = inner
+                .held
+                .iter()
+                .map(|h| h.ready_at)
+                .filter(|t| *t > last_wake)
+                .min();
+            let Some(next) = next else {
+                // Anything still held is already ready, and will be picked up
+                // by the backend as it processes requests.
+                inner.timer_running = false;
+                return;
+            };
+
+            let now = Instant::now();
+            if next > now {
+                inner = self.cv.wait_timeout(inner, next - now).unwrap().0;
+                continue;
+            }
+
+            last_wake = now;
+            drop(inner);
+            match dev.upgrade() {
+                Some(dev) => dev.attachment().notify(),
+                None => {
+                    self.inner.lock().unwrap().timer_running = false;
+                    return;
+                }
+            }
+            inner = self.inner.lock().unwrap();
+        }
+    }
+}
+
+/// Delay before reissuing a request which has already been retried `attempts`
+/// times.
+fn backoff(attempts: u32) -> Duration {
+    BACKOFF_INITIAL
+        .checked_mul(1u32.checked_shl(attempts).unwrap_or(u32::MAX))
+        .unwrap_or(BACKOFF_MAX)
+        .min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accessors::MemAccessor;
+    use crate::block::{DeviceAttachment, ReqId};
+
+    struct TestDevice(DeviceAttachment);
+    impl Device for TestDevice {
+        fn attachment(&self) -> &DeviceAttachment {
+            &self.0
+        }
+        fn next(&self) -> Option<Request> {
+            None
+        }
+        fn complete(&self, _res: Result, _id: ReqId) {}
+        fn accessor_mem(&self) -> MemAccessor {
+            MemAccessor::new_orphan()
+        }
+    }
+
+    #[derive(Default)]
+    struct TestNotifier(Mutex<Vec<(&'static str, String)>>);
+    impl ErrorNotifier for TestNotifier {
+        fn io_stalled(&self, device: &str) {
+            self.0.lock().unwrap().push(("stalled", device.to_string()));
+        }
+        fn io_resumed(&self, device: &str) {
+            self.0.lock().unwrap().push(("resumed", device.to_string()));
+        }
+    }
+    impl TestNotifier {
+        fn events(&self) -> Vec<(&'static str, String)> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    struct Fixture {
+        state: Arc<RetryState>,
+        notifier: Arc<TestNotifier>,
+        dev: Arc<TestDevice>,
+    }
+    impl Fixture {
+        fn new(policy: ErrorPolicy) -> Self {
+            let state = RetryState::new();
+            let notifier = Arc::new(TestNotifier::default());
+            state.set_policy(policy, Some(notifier.clone()), "disk0");
+            let dev = Arc::new(TestDevice(DeviceAttachment::new()));
+            Self { state, notifier, dev }
+        }
+
+        fn process(&self, req: Request, res: Result) -> Option<Request> {
+            let dev: Arc<dyn Device> = self.dev.clone();
+            self.state.process(req, res, Arc::downgrade(&dev))
+        }
+
+        fn held(&self) -> usize {
+            self.state.inner.lock().unwrap().held.len()
+        }
+    }
+
+    fn flush_after(attempts: u32) -> Request {
+        let mut req = Request::new_flush();
+        req.attempts = attempts;
+        req
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), BACKOFF_INITIAL);
+        assert_eq!(backoff(1), BACKOFF_INITIAL * 2);
+        assert_eq!(backoff(5), BACKOFF_INITIAL * 32);
+        assert_eq!(backoff(6), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn report_policy_completes_failures() {
+        let fix = Fixture::new(ErrorPolicy::Report);
+        assert!(fix.process(flush_after(0), Result::Failure).is_some());
+        assert_eq!(fix.held(), 0);
+        assert!(fix.notifier.events().is_empty());
+    }
+
+    #[test]
+    fn retry_policy_holds_until_attempts_exhausted() {
+        let fix = Fixture::new(ErrorPolicy::Retry { max_attempts: 2 });
+        assert!(fix.process(flush_after(1), Result::Failure).is_none());
+        assert_eq!(fix.held(), 1);
+        assert!(fix.process(flush_after(2), Result::Failure).is_some());
+        assert_eq!(fix.held(), 1);
+
+        // Other errors are never retried.
+        assert!(fix.process(flush_after(0), Result::ReadOnly).is_some());
+        assert!(fix.notifier.events().is_empty());
+
+        let drained = fix.state.set_stopped(true);
+        assert_eq!(drained.len(), 1);
+        assert_eq!(drained[0].attempts, 2);
+    }
+
+    #[test]
+    fn pause_policy_reports_stall_once() {
+        let fix = Fixture::new(ErrorPolicy::Pause);
+        assert!(fix.process(flush_after(0), Result::Failure).is_none());
+        assert!(fix.process(flush_after(7), Result::Failure).is_none());
+        assert_eq!(fix.held(), 2);
+        assert_eq!(fix.notifier.events(), [("stalled", "disk0".to_string())]);
+
+        // A success while requests are still held is not a recovery.
+        assert!(fix.process(flush_after(0), Result::Success).is_some());
+        assert_eq!(fix.notifier.events().len(), 1);
+
+        fix.state.inner.lock().unwrap().held.clear();
+        assert!(fix.process(flush_after(0), Result::Success).is_some());
+        assert_eq!(
+            fix.notifier.events(),
+            [
+                ("stalled", "disk0".to_string()),
+                ("resumed", "disk0".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn pausing_device_ends_stall() {
+        let fix = Fixture::new(ErrorPolicy::Pause);
+        assert!(fix.process(flush_after(0), Result::Failure).is_none());
+        assert_eq!(fix.state.set_paused(true).len(), 1);
+        assert_eq!(
+            fix.notifier.events(),
+            [
+                ("stalled", "disk0".to_string()),
+                ("resumed", "disk0".to_string())
+            ]
+        );
+
+        // Failures aren't held while the device is paused.
+        assert!(fix.process(flush_after(0), Result::Failure).is_some());
+        assert_eq!(fix.held(), 0);
+        assert_eq!(fix.notifier.events().len(), 2);
+    }
+}
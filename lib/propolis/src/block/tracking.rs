@@ -41,6 +41,9 @@ struct TrackingEntry<T> {
     payload: T,
     /// When this request was submitted to the backend to be processed
     time_submitted: Instant,
+    /// Time (in ns) between submission and first dispatch to the backend,
+    /// recorded by the [TrackingMarker] (zero if not yet dispatched)
+    queued_ns: Arc<AtomicU64>,
 }
 
 /// Track device-specific data for outstanding block [Request]s.
@@ -69,13 +72,23 @@ impl<T> Tracking<T> {
         let began_empty = guard.outstanding.is_empty();
         let id = guard.next_id;
         guard.next_id.advance();
+        let queued_ns = Arc::new(AtomicU64::new(0));
+        let dev = guard.dev.upgrade().expect("device still exists");
         let marker = TrackingMarker {
             id,
-            dev: guard.dev.upgrade().expect("device still exists"),
+            device_id: guard.device_id,
+            dev: dev.clone(),
+            time_submitted: now,
+            queued_ns: queued_ns.clone(),
         };
         guard.outstanding.insert(
             marker.id,
-            TrackingEntry { op: req.op, payload, time_submitted: now },
+            TrackingEntry {
+                op: req.op,
+                payload,
+                time_submitted: now,
+                queued_ns,
+            },
         );
 
         let old = req.marker.replace(marker);
@@ -84,20 +97,32 @@ impl<T> Tracking<T> {
         if began_empty {
             self.wait.lock().unwrap().clear_empty()
         }
+        dev.attachment().stats().request_issued();
         let devid = guard.device_id;
+        let name = || dev.attachment().name();
         match req.op {
             Operation::Read(off, len) => {
                 probes::block_begin_read!(|| {
-                    (devid, id, off as u64, len as u64)
+                    (devid, id, off as u64, len as u64, name())
                 });
             }
             Operation::Write(off, len) => {
                 probes::block_begin_write!(|| {
-                    (devid, id, off as u64, len as u64)
+                    (devid, id, off as u64, len as u64, name())
                 });
             }
             Operation::Flush => {
-                probes::block_begin_flush!(|| { (devid, id) });
+                probes::block_begin_flush!(|| { (devid, id, name()) });
+            }
+            Operation::WriteZeroes(off, len) => {
+                probes::block_begin_write_zeroes!(|| {
+                    (devid, id, off as u64, len as u64, name())
+                });
+            }
+            Operation::Discard(off, len) => {
+                probes::block_begin_discard!(|| {
+                    (devid, id, off as u64, len as u64, name())
+                });
             }
         }
 
@@ -116,25 +141,39 @@ impl<T> Tracking<T> {
             .expect("tracked request should be present");
 
         let devid = guard.device_id;
-        let proc_ns =
-            now.duration_since(entry.time_submitted).as_nanos() as u64;
-        // TODO: calculate queued time
-        let queue_ns = 0;
+        let total = now.duration_since(entry.time_submitted);
+        let dev = guard.dev.upgrade();
+        if let Some(dev) = dev.as_ref() {
+            dev.attachment().stats().request_completed(entry.op, res, total);
+        }
+        let queue_ns = entry.queued_ns.load(Ordering::Relaxed);
+        let proc_ns = (total.as_nanos() as u64).saturating_sub(queue_ns);
         let rescode = res as u8;
+        let name = || dev.as_ref().map(|d| d.attachment().name()).unwrap_or("");
         match entry.op {
             Operation::Read(..) => {
                 probes::block_complete_read!(|| {
-                    (devid, id, rescode, proc_ns, queue_ns)
+                    (devid, id, rescode, proc_ns, queue_ns, name())
                 });
             }
             Operation::Write(..) => {
                 probes::block_complete_write!(|| {
-                    (devid, id, rescode, proc_ns, queue_ns)
+                    (devid, id, rescode, proc_ns, queue_ns, name())
                 });
             }
             Operation::Flush => {
                 probes::block_complete_flush!(|| {
-                    (devid, id, rescode, proc_ns, queue_ns)
+                    (devid, id, rescode, proc_ns, queue_ns, name())
+                });
+            }
+            Operation::WriteZeroes(..) => {
+                probes::block_complete_write_zeroes!(|| {
+                    (devid, id, rescode, proc_ns, queue_ns, name())
+                });
+            }
+            Operation::Discard(..) => {
+                probes::block_complete_discard!(|| {
+                    (devid, id, rescode, proc_ns, queue_ns, name())
                 });
             }
         }
@@ -227,9 +266,38 @@ impl Future for NoneOutstanding {
 
 pub(super) struct TrackingMarker {
     id: ReqId,
+    device_id: u64,
     dev: Arc<dyn Device>,
+    time_submitted: Instant,
+    queued_ns: Arc<AtomicU64>,
 }
 impl TrackingMarker {
+    pub(super) fn device(&self) -> Arc<dyn Device> {
+        self.dev.clone()
+    }
+    /// Record dispatch of the request to the backend for processing.  The
+    /// time spent queued is measured up to the first dispatch, rather than any
+    /// made when reissuing the request after a failure.
+    pub(super) fn dispatched(&self, attempt: u32) {
+        let elapsed = self.time_submitted.elapsed().as_nanos() as u64;
+        // Zero marks the request as not-yet-dispatched
+        let _ = self.queued_ns.compare_exchange(
+            0,
+            elapsed.max(1),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        let queue_ns = self.queued_ns.load(Ordering::Relaxed);
+        probes::block_dispatch!(|| {
+            (
+                self.device_id,
+                self.id,
+                attempt,
+                queue_ns,
+                self.dev.attachment().name(),
+            )
+        });
+    }
     pub(super) fn complete(self, res: block::Result) {
         self.dev.complete(res, self.id);
     }
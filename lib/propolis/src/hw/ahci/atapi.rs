@@ -0,0 +1,820 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ATAPI CD-ROM drive
+//!
+//! SCSI Multi-Media Commands (MMC) are delivered to the drive by way of the
+//! ATA PACKET command.  Only what is needed for guests to find and read data
+//! discs is implemented: there is no support for audio, recording, or
+//! anything beyond a single data track.
+//!
+//! The media in the drive is provided by the block backend attached to the
+//! device.  It is inserted and ejected at the behest of the host (see
+//! [PciAhci::insert_media] and [PciAhci::eject_media]), or ejected by the
+//! guest itself, and changes are reported to the guest through the Unit
+//! Attention condition and media events.
+
+use super::bits::*;
+use super::{
+    ata_string, set_integrity_word, AhciState, CmdResult, CompletionPayload,
+    PciAhci, CDROM_SECTOR_SIZE,
+};
+use crate::block;
+use crate::common::*;
+use crate::vmm::MemCtx;
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn ahci_atapi_cmd(slot: u8, opcode: u8) {}
+    fn ahci_atapi_read_enqueue(slot: u8, off: u64, sz: u64) {}
+}
+
+/// Model number reported by IDENTIFY PACKET DEVICE
+const MODEL_NUMBER: &str = "Propolis CD-ROM";
+
+/// Firmware revision reported by IDENTIFY PACKET DEVICE and INQUIRY
+const FIRMWARE_REV: &str = "1.0";
+
+/// Largest media (in sectors) for which the CD-ROM profile is reported, that
+/// of an 80-minute CD.  Anything larger must be a DVD.
+const CD_MAX_SECTORS: u64 = 80 * 60 * 75;
+
+// SCSI/MMC operation codes
+// See MMC-6 Section 6 and SPC-4 Section 6
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_START_STOP_UNIT: u8 = 0x1b;
+const SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_SEEK_10: u8 = 0x2b;
+const SCSI_VERIFY_10: u8 = 0x2f;
+const SCSI_READ_TOC: u8 = 0x43;
+const SCSI_GET_CONFIGURATION: u8 = 0x46;
+const SCSI_GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
+const SCSI_MODE_SELECT_10: u8 = 0x55;
+const SCSI_MODE_SENSE_10: u8 = 0x5a;
+const SCSI_READ_12: u8 = 0xa8;
+const SCSI_SET_CD_SPEED: u8 = 0xbb;
+const SCSI_MECHANISM_STATUS: u8 = 0xbd;
+
+// Sense Keys
+// See SPC-4 Section 4.5.6 Sense key and sense code definitions
+
+const SENSE_NOT_READY: u8 = 0x2;
+const SENSE_MEDIUM_ERROR: u8 = 0x3;
+const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+const SENSE_UNIT_ATTENTION: u8 = 0x6;
+
+// Additional Sense Codes
+
+const ASC_UNRECOVERED_READ_ERROR: u8 = 0x11;
+const ASC_INVALID_COMMAND_OPCODE: u8 = 0x20;
+const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+const ASC_INVALID_FIELD_IN_CDB: u8 = 0x24;
+const ASC_MEDIUM_MAY_HAVE_CHANGED: u8 = 0x28;
+const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3a;
+/// With ASCQ 02h: Medium Removal Prevented
+const ASC_MEDIUM_REMOVAL_PREVENTED: u8 = 0x53;
+
+// Media Event codes
+// See MMC-6 Section 6.6.2.5 Media Event Status Class Events
+
+const MEDIA_EVENT_NONE: u8 = 0x0;
+const MEDIA_EVENT_NEW_MEDIA: u8 = 0x2;
+const MEDIA_EVENT_MEDIA_REMOVAL: u8 = 0x3;
+
+// MMC Profiles
+// See MMC-6 Section 5.3.1 Profile List
+
+const PROFILE_NONE: u16 = 0x0000;
+const PROFILE_CD_ROM: u16 = 0x0008;
+const PROFILE_DVD_ROM: u16 = 0x0010;
+
+/// Sense data, describing the error of the last command
+#[derive(Copy, Clone, Default)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+/// State of the drive and its media
+#[derive(Default)]
+pub(super) struct AtapiState {
+    /// Is there media in the drive?
+    loaded: bool,
+    /// Has the guest prevented removal of the media?
+    locked: bool,
+    sense: Sense,
+    /// Is a Unit Attention condition pending, as the media has changed?
+    unit_attention: bool,
+    /// Media event yet to be reported by GET EVENT STATUS NOTIFICATION
+    media_event: u8,
+}
+impl AtapiState {
+    pub(super) fn new() -> Self {
+        Self { loaded: true, ..Default::default() }
+    }
+
+    /// Reset the drive, which leaves any media in place
+    pub(super) fn reset(&mut self) {
+        self.locked = false;
+        self.sense = Sense::default();
+    }
+
+    fn insert(&mut self) {
+        self.loaded = true;
+        self.unit_attention = true;
+        self.media_event = MEDIA_EVENT_NEW_MEDIA;
+    }
+
+    fn eject(&mut self) {
+        self.loaded = false;
+        self.locked = false;
+        self.unit_attention = false;
+        self.media_event = MEDIA_EVENT_MEDIA_REMOVAL;
+    }
+
+    /// Complete a command successfully, without any data transfer
+    fn good(&mut self) -> CmdResult {
+        self.sense = Sense::default();
+        CmdResult {
+            count: ATAPI_INTR_IO | ATAPI_INTR_CD,
+            ..CmdResult::success()
+        }
+    }
+
+    /// Fail a command, recording sense data describing why
+    fn check_condition(&mut self, key: u8, asc: u8, ascq: u8) -> CmdResult {
+        self.sense = Sense { key, asc, ascq };
+        CmdResult {
+            count: ATAPI_INTR_IO | ATAPI_INTR_CD,
+            // The sense key is reported in the upper bits of the Error
+            // register.
+            ..CmdResult::error((key << 4) | ATA_ERR_ABRT)
+        }
+    }
+
+    fn invalid_field(&mut self) -> CmdResult {
+        self.check_condition(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB, 0)
+    }
+
+    /// Complete a command by transferring `data` (truncated to the
+    /// allocation length of the command) to the guest
+    fn data_in(
+        &mut self,
+        hdr: &CommandHeader,
+        data: &[u8],
+        alloc_len: usize,
+        pio: bool,
+        mem: &MemCtx,
+    ) -> CmdResult {
+        let data = &data[..data.len().min(alloc_len)];
+        let written = PciAhci::prdt_regions(hdr, data.len(), mem)
+            .and_then(|regions| PciAhci::write_regions(&regions, data, mem));
+        if written.is_none() {
+            return self.invalid_field();
+        }
+        CmdResult { bytes: data.len() as u32, pio_in: pio, ..self.good() }
+    }
+
+    /// Determine the outcome of a read of the media by the backend
+    pub(super) fn complete_read(
+        &mut self,
+        res: block::Result,
+        bytes: u32,
+        pio_in: bool,
+    ) -> CmdResult {
+        match res {
+            block::Result::Success => {
+                CmdResult { bytes, pio_in, ..self.good() }
+            }
+            _ => self.check_condition(
+                SENSE_MEDIUM_ERROR,
+                ASC_UNRECOVERED_READ_ERROR,
+                0,
+            ),
+        }
+    }
+
+    /// Fixed format sense data, as returned by REQUEST SENSE
+    ///
+    /// See SPC-4 Section 4.5.3 Fixed format sense data
+    fn request_sense(&mut self) -> [u8; 18] {
+        let mut sense = std::mem::take(&mut self.sense);
+        if sense.key == 0 && std::mem::take(&mut self.unit_attention) {
+            sense = Sense {
+                key: SENSE_UNIT_ATTENTION,
+                asc: ASC_MEDIUM_MAY_HAVE_CHANGED,
+                ascq: 0,
+            };
+        }
+        let mut data = [0u8; 18];
+        // Current error, fixed format
+        data[0] = 0x70;
+        data[2] = sense.key;
+        // Additional sense length
+        data[7] = 10;
+        data[12] = sense.asc;
+        data[13] = sense.ascq;
+        data
+    }
+
+    /// Media event status, as returned by GET EVENT STATUS NOTIFICATION
+    ///
+    /// See MMC-6 Section 6.6 GET EVENT STATUS NOTIFICATION Command
+    fn event_status(&mut self, class_mask: u8) -> Vec<u8> {
+        // Only the Media class (bit 4) is supported
+        const MEDIA_CLASS: u8 = 1 << 4;
+        if class_mask & MEDIA_CLASS == 0 {
+            // No Event Available (NEA)
+            return vec![0, 2, 0x80, MEDIA_CLASS];
+        }
+        let event = std::mem::replace(&mut self.media_event, MEDIA_EVENT_NONE);
+        // Media Present in bit 1, Door or Tray Open in bit 0
+        let status = if self.loaded { 1 << 1 } else { 1 << 0 };
+        vec![0, 6, 0x4, MEDIA_CLASS, event, status, 0, 0]
+    }
+
+    fn export(&self) -> migrate::AtapiStateV1 {
+        migrate::AtapiStateV1 {
+            loaded: self.loaded,
+            locked: self.locked,
+            sense_key: self.sense.key,
+            asc: self.sense.asc,
+            ascq: self.sense.ascq,
+            unit_attention: self.unit_attention,
+            media_event: self.media_event,
+        }
+    }
+
+    fn import(&mut self, saved: migrate::AtapiStateV1) {
+        *self = Self {
+            loaded: saved.loaded,
+            locked: saved.locked,
+            sense: Sense {
+                key: saved.sense_key,
+                asc: saved.asc,
+                ascq: saved.ascq,
+            },
+            unit_attention: saved.unit_attention,
+            media_event: saved.media_event,
+        };
+    }
+}
+
+impl PciAhci {
+    /// Insert media into the drive, to be called once the backend providing
+    /// its contents has been attached.  Has no effect on disks.
+    pub fn insert_media(&self) {
+        if let Some(atapi) = self.state.lock().unwrap().atapi.as_mut() {
+            atapi.insert();
+        }
+    }
+
+    /// Eject the media (if any) from the drive, regardless of whether the
+    /// guest has prevented its removal.  Has no effect on disks.
+    pub fn eject_media(&self) {
+        if let Some(atapi) = self.state.lock().unwrap().atapi.as_mut() {
+            atapi.eject();
+        }
+    }
+
+    /// Is this a CD-ROM drive (rather than a disk)?
+    pub fn is_cdrom(&self) -> bool {
+        self.state.lock().unwrap().atapi.is_some()
+    }
+
+    /// Begin processing an ATA command issued to the ATAPI device
+    pub(super) fn issue_atapi_cmd(
+        &self,
+        state: &mut AhciState,
+        slot: u8,
+        hdr: &CommandHeader,
+        fis: &RegH2DFis,
+        mem: &MemCtx,
+    ) -> Result<block::Request, CmdResult> {
+        match fis.command {
+            ATA_CMD_PACKET => {
+                if !hdr.is_atapi() {
+                    return Err(CmdResult::error(ATA_ERR_ABRT));
+                }
+                let cdb: [u8; 16] = mem
+                    .read(GuestAddr(hdr.ctba + CMD_TABLE_ACMD_OFF))
+                    .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+                let dma = fis.features & ATAPI_FEAT_DMA != 0;
+                let gen = state.gen;
+                let atapi = state.atapi.as_mut().expect("device is ATAPI");
+                self.packet_cmd(atapi, gen, slot, hdr, &cdb, !dma, mem)
+            }
+            ATA_CMD_IDENTIFY_PACKET_DEVICE => {
+                let ident = self.identify_packet();
+                // Safety: a [u16] may be freely viewed as bytes
+                let data = unsafe {
+                    std::slice::from_raw_parts(
+                        ident.as_ptr() as *const u8,
+                        std::mem::size_of_val(&ident),
+                    )
+                };
+                let regions = Self::prdt_regions(hdr, data.len(), mem)
+                    .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+                Self::write_regions(&regions, data, mem)
+                    .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+                Err(CmdResult {
+                    bytes: data.len() as u32,
+                    pio_in: true,
+                    ..CmdResult::success()
+                })
+            }
+            ATA_CMD_DEVICE_RESET => {
+                if let Some(atapi) = state.atapi.as_mut() {
+                    atapi.reset();
+                }
+                Self::post_signature(state, mem);
+                Err(CmdResult::success())
+            }
+            ATA_CMD_CHECK_POWER_MODE => {
+                Err(CmdResult { count: 0xff, ..CmdResult::success() })
+            }
+            ATA_CMD_SET_FEATURES
+            | ATA_CMD_STANDBY_IMMEDIATE
+            | ATA_CMD_IDLE_IMMEDIATE
+            | ATA_CMD_STANDBY
+            | ATA_CMD_IDLE => Err(CmdResult::success()),
+
+            // Notably, IDENTIFY DEVICE is aborted, which is how guests tell
+            // that they should issue IDENTIFY PACKET DEVICE instead.
+            _ => Err(CmdResult::error(ATA_ERR_ABRT)),
+        }
+    }
+
+    /// Process the SCSI command (`cdb`) of a PACKET command
+    #[allow(clippy::too_many_arguments)]
+    fn packet_cmd(
+        &self,
+        atapi: &mut AtapiState,
+        gen: u64,
+        slot: u8,
+        hdr: &CommandHeader,
+        cdb: &[u8; 16],
+        pio: bool,
+        mem: &MemCtx,
+    ) -> Result<block::Request, CmdResult> {
+        let op = cdb[0];
+        probes::ahci_atapi_cmd!(|| (slot, op));
+        if atapi.unit_attention
+            && !matches!(
+                op,
+                SCSI_INQUIRY
+                    | SCSI_REQUEST_SENSE
+                    | SCSI_GET_EVENT_STATUS_NOTIFICATION
+            )
+        {
+            atapi.unit_attention = false;
+            return Err(atapi.check_condition(
+                SENSE_UNIT_ATTENTION,
+                ASC_MEDIUM_MAY_HAVE_CHANGED,
+                0,
+            ));
+        }
+
+        // Capacity of the media (in sectors), if there is any in the drive
+        let capacity =
+            self.block_attach.info().filter(|_| atapi.loaded).map(|info| {
+                info.total_size * u64::from(info.block_size)
+                    / CDROM_SECTOR_SIZE as u64
+            });
+        let need_media = |atapi: &mut AtapiState| {
+            capacity.ok_or_else(|| {
+                atapi.check_condition(
+                    SENSE_NOT_READY,
+                    ASC_MEDIUM_NOT_PRESENT,
+                    0,
+                )
+            })
+        };
+        let be16 = |off: usize| u16::from_be_bytes([cdb[off], cdb[off + 1]]);
+        let be32 = |off: usize| {
+            u32::from_be_bytes([
+                cdb[off],
+                cdb[off + 1],
+                cdb[off + 2],
+                cdb[off + 3],
+            ])
+        };
+
+        match op {
+            SCSI_TEST_UNIT_READY | SCSI_SEEK_10 | SCSI_VERIFY_10 => {
+                need_media(atapi)?;
+                Err(atapi.good())
+            }
+            SCSI_REQUEST_SENSE => {
+                let data = atapi.request_sense();
+                Err(atapi.data_in(hdr, &data, cdb[4].into(), pio, mem))
+            }
+            SCSI_INQUIRY => {
+                if cdb[1] & 1 != 0 {
+                    // Vital product data pages are not supported
+                    return Err(atapi.invalid_field());
+                }
+                Err(atapi.data_in(hdr, &inquiry(), be16(3).into(), pio, mem))
+            }
+            SCSI_START_STOP_UNIT => {
+                let (load_eject, start) =
+                    (cdb[4] & 0x2 != 0, cdb[4] & 0x1 != 0);
+                if load_eject && !start {
+                    if atapi.locked {
+                        return Err(atapi.check_condition(
+                            SENSE_ILLEGAL_REQUEST,
+                            ASC_MEDIUM_REMOVAL_PREVENTED,
+                            0x2,
+                        ));
+                    }
+                    if atapi.loaded {
+                        slog::info!(self.log, "guest ejected media");
+                        atapi.eject();
+                    }
+                }
+                // Closing the tray brings nothing new into the drive: media
+                // is only ever inserted by the host.
+                Err(atapi.good())
+            }
+            SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                atapi.locked = cdb[4] & 0x1 != 0;
+                Err(atapi.good())
+            }
+            SCSI_READ_CAPACITY => {
+                let sectors = need_media(atapi)?;
+                let mut data = [0u8; 8];
+                let last = sectors.saturating_sub(1).min(u64::from(u32::MAX));
+                data[..4].copy_from_slice(&(last as u32).to_be_bytes());
+                data[4..]
+                    .copy_from_slice(&(CDROM_SECTOR_SIZE as u32).to_be_bytes());
+                Err(atapi.data_in(hdr, &data, data.len(), pio, mem))
+            }
+            SCSI_READ_10 | SCSI_READ_12 => {
+                let sectors = need_media(atapi)?;
+                let lba = u64::from(be32(2));
+                let count = match op {
+                    SCSI_READ_10 => u64::from(be16(7)),
+                    _ => u64::from(be32(6)),
+                };
+                if count == 0 {
+                    return Err(atapi.good());
+                }
+                if lba + count > sectors {
+                    return Err(atapi.check_condition(
+                        SENSE_ILLEGAL_REQUEST,
+                        ASC_LBA_OUT_OF_RANGE,
+                        0,
+                    ));
+                }
+                let off = lba as usize * CDROM_SECTOR_SIZE;
+                let sz = count as usize * CDROM_SECTOR_SIZE;
+                let Some(regions) = Self::prdt_regions(hdr, sz, mem) else {
+                    return Err(atapi.invalid_field());
+                };
+                probes::ahci_atapi_read_enqueue!(|| (
+                    slot, off as u64, sz as u64
+                ));
+                Ok(self.block_tracking.track(
+                    block::Request::new_read(off, sz, regions),
+                    CompletionPayload {
+                        slot,
+                        gen,
+                        bytes: sz as u32,
+                        pio_in: pio,
+                    },
+                ))
+            }
+            SCSI_READ_TOC => {
+                let sectors = need_media(atapi)?;
+                let msf = cdb[1] & 0x2 != 0;
+                let Some(data) = read_toc(cdb[2] & 0xf, msf, cdb[6], sectors)
+                else {
+                    return Err(atapi.invalid_field());
+                };
+                Err(atapi.data_in(hdr, &data, be16(7).into(), pio, mem))
+            }
+            SCSI_GET_CONFIGURATION => {
+                let profile = match capacity {
+                    None => PROFILE_NONE,
+                    Some(sectors) if sectors > CD_MAX_SECTORS => {
+                        PROFILE_DVD_ROM
+                    }
+                    Some(_) => PROFILE_CD_ROM,
+                };
+                let data = get_configuration(cdb[1] & 0x3, be16(2), profile);
+                Err(atapi.data_in(hdr, &data, be16(7).into(), pio, mem))
+            }
+            SCSI_GET_EVENT_STATUS_NOTIFICATION => {
+                if cdb[1] & 0x1 == 0 {
+                    // Only polled operation is supported
+                    return Err(atapi.invalid_field());
+                }
+                let data = atapi.event_status(cdb[4]);
+                Err(atapi.data_in(hdr, &data, be16(7).into(), pio, mem))
+            }
+            SCSI_MODE_SENSE_10 => {
+                let Some(data) = mode_sense(cdb[2] & 0x3f, atapi.locked) else {
+                    return Err(atapi.invalid_field());
+                };
+                Err(atapi.data_in(hdr, &data, be16(7).into(), pio, mem))
+            }
+            SCSI_MECHANISM_STATUS => {
+                let mut data = [0u8; 8];
+                if !atapi.loaded {
+                    // Door open
+                    data[1] = 1 << 4;
+                }
+                Err(atapi.data_in(hdr, &data, be16(8).into(), pio, mem))
+            }
+            SCSI_MODE_SELECT_10 | SCSI_SET_CD_SPEED => {
+                // None of the parameters are changeable, and the speed of the
+                // drive is whatever the backend can manage.
+                Err(atapi.good())
+            }
+            _ => {
+                slog::debug!(self.log, "unsupported ATAPI command";
+                    "opcode" => op,
+                );
+                Err(atapi.check_condition(
+                    SENSE_ILLEGAL_REQUEST,
+                    ASC_INVALID_COMMAND_OPCODE,
+                    0,
+                ))
+            }
+        }
+    }
+
+    /// Assemble the data returned by IDENTIFY PACKET DEVICE
+    ///
+    /// See ACS-3 Section 7.13.6 IDENTIFY PACKET DEVICE data
+    fn identify_packet(&self) -> [u16; 256] {
+        let mut id = [0u16; 256];
+        // ATAPI device, of the CD-ROM type, with removable media, which
+        // accepts 12-byte command packets
+        id[0] = 0x8000 | (0x05 << 8) | (1 << 7) | (1 << 6);
+        ata_string(&mut id[10..20], &self.serial_number);
+        ata_string(&mut id[23..27], FIRMWARE_REV);
+        ata_string(&mut id[27..47], MODEL_NUMBER);
+        // LBA and DMA supported
+        id[49] = (1 << 9) | (1 << 8);
+        // Words 64-70 and 88 are valid
+        id[53] = (1 << 2) | (1 << 1);
+        id[63] = 0x0007;
+        id[64] = 0x0003;
+        id[65] = 120;
+        id[66] = 120;
+        id[67] = 120;
+        id[68] = 120;
+        id[76] = (1 << 3) | (1 << 2) | (1 << 1);
+        id[80] = 0x03f0;
+        // PACKET feature set supported and enabled
+        id[82] = 1 << 4;
+        id[85] = 1 << 4;
+        id[83] = 0x4000;
+        id[84] = 0x4000;
+        id[87] = 0x4000;
+        id[88] = (1 << 14) | 0x007f;
+        set_integrity_word(&mut id);
+        id
+    }
+
+    pub(super) fn export_atapi(&self) -> Option<migrate::AtapiStateV1> {
+        self.state.lock().unwrap().atapi.as_ref().map(AtapiState::export)
+    }
+
+    pub(super) fn import_atapi(&self, saved: migrate::AtapiStateV1) {
+        if let Some(atapi) = self.state.lock().unwrap().atapi.as_mut() {
+            atapi.import(saved);
+        }
+    }
+}
+
+/// Standard INQUIRY data
+///
+/// See SPC-4 Section 6.4.2 Standard INQUIRY data
+fn inquiry() -> [u8; 36] {
+    let mut data = [0u8; 36];
+    // CD/DVD device, with removable media
+    data[0] = 0x05;
+    data[1] = 1 << 7;
+    // ATAPI devices report a version of 0, and response data format 2
+    data[3] = 0x2;
+    data[4] = (data.len() - 5) as u8;
+    let pad = |dst: &mut [u8], s: &str| {
+        let bytes = s.bytes().chain(std::iter::repeat(b' '));
+        for (d, b) in dst.iter_mut().zip(bytes) {
+            *d = b;
+        }
+    };
+    pad(&mut data[8..16], "OXIDE");
+    pad(&mut data[16..32], MODEL_NUMBER);
+    pad(&mut data[32..36], FIRMWARE_REV);
+    data
+}
+
+/// Convert an LBA into the Minute/Second/Frame form used by CD media, in
+/// which the first sector is 2 seconds in.
+fn lba_to_msf(lba: u64) -> [u8; 4] {
+    let frames = lba + 150;
+    [
+        0,
+        (frames / (60 * 75)).min(0xff) as u8,
+        ((frames / 75) % 60) as u8,
+        (frames % 75) as u8,
+    ]
+}
+
+/// Table of contents of the media: a single data track
+///
+/// See MMC-6 Section 6.40 READ TOC/PMA/ATIP Command
+fn read_toc(format: u8, msf: bool, start: u8, sectors: u64) -> Option<Vec<u8>> {
+    let addr = |lba: u64| match msf {
+        true => lba_to_msf(lba),
+        false => (lba as u32).to_be_bytes(),
+    };
+    // Data track, recorded uninterrupted
+    const ADR_CONTROL: u8 = 0x14;
+    const LEAD_OUT: u8 = 0xaa;
+
+    let mut data = match format {
+        // Formatted TOC
+        0x0 => {
+            if start > 1 && start != LEAD_OUT {
+                return None;
+            }
+            // First and last track numbers
+            let mut data = vec![0, 0, 1, 1];
+            if start <= 1 {
+                data.extend([0, ADR_CONTROL, 1, 0]);
+                data.extend(addr(0));
+            }
+            data.extend([0, ADR_CONTROL, LEAD_OUT, 0]);
+            data.extend(addr(sectors));
+            data
+        }
+        // Multi-session information: the first track of the only session
+        0x1 => {
+            let mut data = vec![0, 0, 1, 1, 0, ADR_CONTROL, 1, 0];
+            data.extend(addr(0));
+            data
+        }
+        _ => return None,
+    };
+    let len = (data.len() - 2) as u16;
+    data[..2].copy_from_slice(&len.to_be_bytes());
+    Some(data)
+}
+
+/// Features of the drive, as reported by GET CONFIGURATION
+///
+/// See MMC-6 Section 6.6 GET CONFIGURATION Command
+fn get_configuration(rt: u8, start: u16, profile: u16) -> Vec<u8> {
+    let current = |p: u16| u8::from(p == profile);
+    let features: [(u16, Vec<u8>); 2] = [
+        // Profile List
+        (
+            0x0000,
+            [PROFILE_DVD_ROM, PROFILE_CD_ROM]
+                .into_iter()
+                .flat_map(|p| {
+                    let [hi, lo] = p.to_be_bytes();
+                    [hi, lo, current(p), 0]
+                })
+                .collect(),
+        ),
+        // Core: ATAPI physical interface
+        (0x0001, vec![0, 0, 0, 2, 0, 0, 0, 0]),
+    ];
+
+    // Current profile in the header
+    let mut data = vec![0u8; 8];
+    data[6..8].copy_from_slice(&profile.to_be_bytes());
+    for (code, body) in features {
+        // All features are current, so only the starting feature matters
+        // (when only it is requested, for an RT of 2).
+        if code < start || (rt == 0x2 && code != start) {
+            continue;
+        }
+        data.extend(code.to_be_bytes());
+        // Version 0 (or 1, for Core), persistent, and current
+        let version = u8::from(code == 0x0001);
+        data.push((version << 2) | 0x3);
+        data.push(body.len() as u8);
+        data.extend(body);
+    }
+    let len = (data.len() - 4) as u32;
+    data[..4].copy_from_slice(&len.to_be_bytes());
+    data
+}
+
+/// Mode parameters of the drive, as reported by MODE SENSE(10)
+///
+/// See MMC-6 Section 7 Mode Parameters
+fn mode_sense(page: u8, locked: bool) -> Option<Vec<u8>> {
+    // Read/Write Error Recovery
+    let recovery = vec![0x01, 0x0a, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0];
+    // CD/DVD Capabilities and Mechanical Status (legacy)
+    let mut capabilities = vec![0u8; 20];
+    capabilities[0] = 0x2a;
+    capabilities[1] = (capabilities.len() - 2) as u8;
+    // DVD-ROM read supported
+    capabilities[2] = 1 << 3;
+    // Lock supported, current lock state, eject supported, tray loading
+    capabilities[6] = 0x1 | (u8::from(locked) << 1) | (1 << 3) | (1 << 5);
+    // Maximum and current read speeds, in kB/s (of 8x)
+    capabilities[8..10].copy_from_slice(&1411u16.to_be_bytes());
+    capabilities[14..16].copy_from_slice(&1411u16.to_be_bytes());
+
+    let pages = match page {
+        0x01 => vec![recovery],
+        0x2a => vec![capabilities],
+        // All pages
+        0x3f => vec![recovery, capabilities],
+        _ => return None,
+    };
+    // Header, without any block descriptors
+    let mut data = vec![0u8; 8];
+    data.extend(pages.into_iter().flatten());
+    let len = (data.len() - 2) as u16;
+    data[..2].copy_from_slice(&len.to_be_bytes());
+    Some(data)
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct AtapiStateV1 {
+        pub loaded: bool,
+        pub locked: bool,
+        pub sense_key: u8,
+        pub asc: u8,
+        pub ascq: u8,
+        pub unit_attention: bool,
+        pub media_event: u8,
+    }
+    impl Schema<'_> for AtapiStateV1 {
+        fn id() -> SchemaId {
+            ("ahci-atapi", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn msf() {
+        assert_eq!(lba_to_msf(0), [0, 0, 2, 0]);
+        assert_eq!(lba_to_msf(16), [0, 0, 2, 16]);
+        assert_eq!(lba_to_msf(60 * 75), [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn toc() {
+        let data = read_toc(0, false, 0, 1000).unwrap();
+        assert_eq!(data.len(), 20);
+        assert_eq!(u16::from_be_bytes([data[0], data[1]]), 18);
+        // Lead-out at the end of the media
+        assert_eq!(data[14], 0xaa);
+        assert_eq!(&data[16..20], &1000u32.to_be_bytes());
+
+        // Only the lead-out follows the last track
+        assert_eq!(read_toc(0, false, 0xaa, 1000).unwrap().len(), 12);
+        assert!(read_toc(0, false, 2, 1000).is_none());
+        assert!(read_toc(5, false, 0, 1000).is_none());
+    }
+
+    #[test]
+    fn unit_attention_sense() {
+        let mut atapi = AtapiState::new();
+        atapi.eject();
+        atapi.insert();
+        let sense = atapi.request_sense();
+        assert_eq!(sense[2], SENSE_UNIT_ATTENTION);
+        assert_eq!(sense[12], ASC_MEDIUM_MAY_HAVE_CHANGED);
+        assert!(!atapi.unit_attention);
+        assert_eq!(atapi.request_sense()[2], 0);
+    }
+
+    #[test]
+    fn media_events() {
+        let mut atapi = AtapiState::new();
+        atapi.eject();
+        let data = atapi.event_status(1 << 4);
+        assert_eq!(data[4], MEDIA_EVENT_MEDIA_REMOVAL);
+        assert_eq!(data[5], 1 << 0);
+        let data = atapi.event_status(1 << 4);
+        assert_eq!(data[4], MEDIA_EVENT_NONE);
+    }
+}
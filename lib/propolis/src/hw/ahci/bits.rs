@@ -0,0 +1,358 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Register, FIS, and command definitions for AHCI and the ATA commands it
+//! carries.
+
+// HBA Capabilities (CAP) bits
+// See AHCI 1.3.1 Section 3.1.1 Offset 00h: CAP - HBA Capabilities
+
+/// Number of Command Slots (NCS), a 0's based value
+pub const CAP_NCS_SHIFT: u32 = 8;
+/// Supports AHCI mode only (SAM)
+pub const CAP_SAM: u32 = 1 << 18;
+/// Interface Speed Support (ISS)
+pub const CAP_ISS_SHIFT: u32 = 20;
+/// Supports Command List Override (SCLO)
+pub const CAP_SCLO: u32 = 1 << 24;
+/// Supports 64-bit Addressing (S64A)
+pub const CAP_S64A: u32 = 1 << 31;
+
+// Global HBA Control (GHC) bits
+// See AHCI 1.3.1 Section 3.1.2 Offset 04h: GHC - Global HBA Control
+
+/// HBA Reset (HR)
+pub const GHC_HR: u32 = 1 << 0;
+/// Interrupt Enable (IE)
+pub const GHC_IE: u32 = 1 << 1;
+/// AHCI Enable (AE)
+pub const GHC_AE: u32 = 1 << 31;
+
+/// AHCI Version 1.3.1
+///
+/// See AHCI 1.3.1 Section 3.1.5 Offset 10h: VS - AHCI Version
+pub const AHCI_VER_1_3_1: u32 = 0x0001_0301;
+
+// Port x Interrupt Status/Enable (PxIS/PxIE) bits
+// See AHCI 1.3.1 Section 3.3.5 Offset 10h: PxIS - Port x Interrupt Status
+
+/// Device to Host Register FIS Interrupt (DHRS)
+pub const PXIS_DHRS: u32 = 1 << 0;
+/// PIO Setup FIS Interrupt (PSS)
+pub const PXIS_PSS: u32 = 1 << 1;
+/// Task File Error Status (TFES)
+pub const PXIS_TFES: u32 = 1 << 30;
+
+// Port x Command and Status (PxCMD) bits
+// See AHCI 1.3.1 Section 3.3.7 Offset 18h: PxCMD - Port x Command and Status
+
+/// Start (ST)
+pub const PXCMD_ST: u32 = 1 << 0;
+/// Spin-Up Device (SUD)
+pub const PXCMD_SUD: u32 = 1 << 1;
+/// Power On Device (POD)
+pub const PXCMD_POD: u32 = 1 << 2;
+/// Command List Override (CLO)
+pub const PXCMD_CLO: u32 = 1 << 3;
+/// FIS Receive Enable (FRE)
+pub const PXCMD_FRE: u32 = 1 << 4;
+/// Current Command Slot (CCS)
+pub const PXCMD_CCS_SHIFT: u32 = 8;
+/// FIS Receive Running (FR)
+pub const PXCMD_FR: u32 = 1 << 14;
+/// Command List Running (CR)
+pub const PXCMD_CR: u32 = 1 << 15;
+
+/// Port x Serial ATA Status (PxSSTS) for a device which is present, with
+/// communication established at Gen 3 speed, in the active power state.
+///
+/// See AHCI 1.3.1 Section 3.3.10 Offset 28h: PxSSTS - Port x Serial ATA Status
+pub const PXSSTS_ACTIVE: u32 = 0x133;
+
+/// Device Detection Initialization (DET) field of Port x Serial ATA Control
+/// (PxSCTL): when set to 1, a COMRESET is issued on the interface.
+///
+/// See AHCI 1.3.1 Section 3.3.11 Offset 2Ch: PxSCTL - Port x Serial ATA Control
+pub const PXSCTL_DET_MASK: u32 = 0xf;
+pub const PXSCTL_DET_COMRESET: u32 = 0x1;
+
+/// Signature (PxSIG) reported for an ATA device
+///
+/// See AHCI 1.3.1 Section 3.3.9 Offset 24h: PxSIG - Port x Signature
+pub const SIG_ATA: u32 = 0x0000_0101;
+/// Signature (PxSIG) reported for an ATAPI device
+pub const SIG_ATAPI: u32 = 0xeb14_0101;
+
+// ATA Status register bits
+
+/// Error (ERR)
+pub const ATA_STS_ERR: u8 = 1 << 0;
+/// Data Request (DRQ)
+pub const ATA_STS_DRQ: u8 = 1 << 3;
+/// Device Ready (DRDY), along with the obsolete Device Seek Complete bit
+/// which some guests still expect to be set.
+pub const ATA_STS_READY: u8 = 0x50;
+/// Busy (BSY)
+pub const ATA_STS_BSY: u8 = 1 << 7;
+
+// ATA Error register bits
+
+/// Command Aborted (ABRT)
+pub const ATA_ERR_ABRT: u8 = 1 << 2;
+/// ID Not Found (IDNF): the requested address is out of range
+pub const ATA_ERR_IDNF: u8 = 1 << 4;
+/// Uncorrectable Error (UNC)
+pub const ATA_ERR_UNC: u8 = 1 << 6;
+
+// ATA Device Control register bits
+
+/// Interrupt Disable (nIEN)
+pub const ATA_CTL_NIEN: u8 = 1 << 1;
+/// Software Reset (SRST)
+pub const ATA_CTL_SRST: u8 = 1 << 2;
+/// High Order Byte (HOB): read back the previous contents of the registers
+/// written by 48-bit commands
+pub const ATA_CTL_HOB: u8 = 1 << 7;
+
+// ATAPI Interrupt Reason bits, reported in the Sector Count register
+// See ACS-3 Section 7.18.5 PACKET Normal Outputs
+
+/// Command/Data (C/D): the transfer is of a command (or of its status)
+pub const ATAPI_INTR_CD: u16 = 1 << 0;
+/// Input/Output (I/O): the transfer is to the host
+pub const ATAPI_INTR_IO: u16 = 1 << 1;
+
+/// DMA bit in the Features register of the PACKET command
+pub const ATAPI_FEAT_DMA: u8 = 1 << 0;
+
+// FIS Types
+// See SATA 3.2 Section 10.5.1, Table 106 FIS Type values
+
+/// Register FIS - Host to Device
+pub const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Register FIS - Device to Host
+pub const FIS_TYPE_REG_D2H: u8 = 0x34;
+/// PIO Setup FIS - Device to Host
+pub const FIS_TYPE_PIO_SETUP: u8 = 0x5f;
+
+/// Offset of the PIO Setup FIS in the received FIS area
+pub const RFIS_PIO_SETUP_OFF: u64 = 0x20;
+/// Offset of the D2H Register FIS in the received FIS area
+pub const RFIS_D2H_OFF: u64 = 0x40;
+
+/// Size of a command header in the command list
+pub const CMD_HEADER_SIZE: u64 = 32;
+/// Offset of the ATAPI Command (ACMD) within a command table
+pub const CMD_TABLE_ACMD_OFF: u64 = 0x40;
+/// Offset of the Physical Region Descriptor Table within a command table
+pub const CMD_TABLE_PRDT_OFF: u64 = 0x80;
+
+// ATA Commands
+// See ACS-3 Section 7
+
+pub const ATA_CMD_DEVICE_RESET: u8 = 0x08;
+pub const ATA_CMD_READ_SECTORS: u8 = 0x20;
+pub const ATA_CMD_READ_SECTORS_EXT: u8 = 0x24;
+pub const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_CMD_READ_MULTIPLE_EXT: u8 = 0x29;
+pub const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
+pub const ATA_CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+pub const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_CMD_WRITE_MULTIPLE_EXT: u8 = 0x39;
+pub const ATA_CMD_VERIFY_SECTORS: u8 = 0x40;
+pub const ATA_CMD_VERIFY_SECTORS_EXT: u8 = 0x42;
+pub const ATA_CMD_EXECUTE_DEVICE_DIAGNOSTIC: u8 = 0x90;
+pub const ATA_CMD_INIT_DEV_PARAMS: u8 = 0x91;
+pub const ATA_CMD_PACKET: u8 = 0xa0;
+pub const ATA_CMD_IDENTIFY_PACKET_DEVICE: u8 = 0xa1;
+pub const ATA_CMD_READ_MULTIPLE: u8 = 0xc4;
+pub const ATA_CMD_WRITE_MULTIPLE: u8 = 0xc5;
+pub const ATA_CMD_SET_MULTIPLE_MODE: u8 = 0xc6;
+pub const ATA_CMD_READ_DMA: u8 = 0xc8;
+pub const ATA_CMD_WRITE_DMA: u8 = 0xca;
+pub const ATA_CMD_STANDBY_IMMEDIATE: u8 = 0xe0;
+pub const ATA_CMD_IDLE_IMMEDIATE: u8 = 0xe1;
+pub const ATA_CMD_STANDBY: u8 = 0xe2;
+pub const ATA_CMD_IDLE: u8 = 0xe3;
+pub const ATA_CMD_CHECK_POWER_MODE: u8 = 0xe5;
+pub const ATA_CMD_FLUSH_CACHE: u8 = 0xe7;
+pub const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+pub const ATA_CMD_IDENTIFY_DEVICE: u8 = 0xec;
+pub const ATA_CMD_SET_FEATURES: u8 = 0xef;
+
+/// Command Header, as found in the command list of a port
+///
+/// See AHCI 1.3.1 Section 4.2.2 Command List Structure
+#[derive(Copy, Clone, Default, Debug)]
+#[repr(C, packed(1))]
+pub struct CommandHeader {
+    /// Description Information: Command FIS Length (CFL) in bits 4:0, Write
+    /// (W) in bit 6, and Physical Region Descriptor Table Length (PRDTL) in
+    /// bits 31:16, among others.
+    pub flags: u32,
+    /// Physical Region Descriptor Byte Count (PRDBC)
+    pub prdbc: u32,
+    /// Command Table Descriptor Base Address (CTBA/CTBAU)
+    pub ctba: u64,
+    pub _resv: [u32; 4],
+}
+impl CommandHeader {
+    /// Number of entries in the Physical Region Descriptor Table
+    pub fn prdtl(&self) -> u16 {
+        (self.flags >> 16) as u16
+    }
+    /// Does the command table hold an ATAPI command (A)?
+    pub fn is_atapi(&self) -> bool {
+        self.flags & (1 << 5) != 0
+    }
+}
+
+/// Physical Region Descriptor Table entry
+///
+/// See AHCI 1.3.1 Section 4.2.3.3 Physical Region Descriptor Table (PRDT)
+#[derive(Copy, Clone, Default, Debug)]
+#[repr(C, packed(1))]
+pub struct PrdtEntry {
+    /// Data Base Address (DBA/DBAU)
+    pub dba: u64,
+    pub _resv: u32,
+    /// Data Byte Count (DBC) in bits 21:0, as a 0's based value, and
+    /// Interrupt on Completion (I) in bit 31
+    pub dbc: u32,
+}
+impl PrdtEntry {
+    pub fn len(&self) -> usize {
+        (self.dbc & 0x3f_ffff) as usize + 1
+    }
+}
+
+/// Register FIS - Host to Device
+///
+/// See SATA 3.2 Section 10.5.5 Register - Host to Device FIS
+#[derive(Copy, Clone, Default, Debug)]
+#[repr(C, packed(1))]
+pub struct RegH2DFis {
+    pub fis_type: u8,
+    /// Command (C) in bit 7, set if the FIS updates the Command register
+    /// rather than the Device Control register.
+    pub flags: u8,
+    pub command: u8,
+    pub features: u8,
+    pub lba_low: [u8; 3],
+    pub device: u8,
+    pub lba_high: [u8; 3],
+    pub features_exp: u8,
+    pub count: u16,
+    pub icc: u8,
+    pub control: u8,
+    pub _resv: [u8; 4],
+}
+impl RegH2DFis {
+    pub fn is_command(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    /// Logical block address for a 48-bit (EXT) command
+    pub fn lba48(&self) -> u64 {
+        let [l0, l1, l2] = self.lba_low;
+        let [h0, h1, h2] = self.lba_high;
+        u64::from_le_bytes([l0, l1, l2, h0, h1, h2, 0, 0])
+    }
+
+    /// Logical block address for a 28-bit command, the top nibble of which is
+    /// held in the Device register.
+    pub fn lba28(&self) -> u64 {
+        let [l0, l1, l2] = self.lba_low;
+        u64::from_le_bytes([l0, l1, l2, self.device & 0xf, 0, 0, 0, 0])
+    }
+
+    /// Sector count for a 48-bit (EXT) command, where 0 means 65536
+    pub fn count48(&self) -> u32 {
+        match self.count {
+            0 => 0x10000,
+            n => u32::from(n),
+        }
+    }
+
+    /// Sector count for a 28-bit command, where 0 means 256
+    pub fn count28(&self) -> u32 {
+        match self.count & 0xff {
+            0 => 0x100,
+            n => u32::from(n),
+        }
+    }
+}
+
+/// Register FIS - Device to Host
+///
+/// See SATA 3.2 Section 10.5.6 Register - Device to Host FIS
+#[derive(Copy, Clone, Default, Debug)]
+#[repr(C, packed(1))]
+pub struct RegD2HFis {
+    pub fis_type: u8,
+    /// Interrupt (I) in bit 6
+    pub flags: u8,
+    pub status: u8,
+    pub error: u8,
+    pub lba_low: [u8; 3],
+    pub device: u8,
+    pub lba_high: [u8; 3],
+    pub _resv1: u8,
+    pub count: u16,
+    pub _resv2: [u8; 6],
+}
+
+/// PIO Setup FIS - Device to Host
+///
+/// See SATA 3.2 Section 10.5.11 PIO Setup - Device to Host FIS
+#[derive(Copy, Clone, Default, Debug)]
+#[repr(C, packed(1))]
+pub struct PioSetupFis {
+    pub fis_type: u8,
+    /// Data transfer direction (D) in bit 5, set for device-to-host, and
+    /// Interrupt (I) in bit 6
+    pub flags: u8,
+    pub status: u8,
+    pub error: u8,
+    pub lba_low: [u8; 3],
+    pub device: u8,
+    pub lba_high: [u8; 3],
+    pub _resv1: u8,
+    pub count: u16,
+    pub _resv2: u8,
+    /// Status register value at the end of the data transfer
+    pub e_status: u8,
+    pub transfer_count: u16,
+    pub _resv3: [u8; 2],
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn struct_sizes() {
+        assert_eq!(size_of::<CommandHeader>(), CMD_HEADER_SIZE as usize);
+        assert_eq!(size_of::<PrdtEntry>(), 16);
+        assert_eq!(size_of::<RegH2DFis>(), 20);
+        assert_eq!(size_of::<RegD2HFis>(), 20);
+        assert_eq!(size_of::<PioSetupFis>(), 20);
+    }
+
+    #[test]
+    fn h2d_lba_and_count() {
+        let fis = RegH2DFis {
+            lba_low: [0x01, 0x02, 0x03],
+            device: 0xe4,
+            lba_high: [0x05, 0x06, 0x07],
+            count: 0,
+            ..Default::default()
+        };
+        assert_eq!(fis.lba48(), 0x0706_0503_0201);
+        assert_eq!(fis.lba28(), 0x0403_0201);
+        assert_eq!(fis.count48(), 0x10000);
+        assert_eq!(fis.count28(), 0x100);
+    }
+}
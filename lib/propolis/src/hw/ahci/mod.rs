@@ -0,0 +1,1192 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! AHCI (Serial ATA) host bus adapter
+//!
+//! The HBA exposes a single port, to which either an ATA disk or an ATAPI
+//! CD-ROM drive (backed by a block backend) is attached.  Native Command
+//! Queuing is not supported, so commands issued through the command list are
+//! processed one at a time, in slot order.
+//! Interrupts are delivered through the legacy (pin-based) mechanism, which
+//! is what the older guests this is meant for expect to use.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::accessors::MemAccessor;
+use crate::block;
+use crate::common::*;
+use crate::hw::ids::pci::{PROPOLIS_AHCI_DEV_ID, VENDOR_OXIDE};
+use crate::hw::pci;
+use crate::intr_pins::IntrPin;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+
+mod atapi;
+pub(crate) mod bits;
+
+use atapi::AtapiState;
+use bits::*;
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn ahci_cmd(slot: u8, command: u8) {}
+    fn ahci_read_enqueue(slot: u8, off: u64, sz: u64) {}
+    fn ahci_write_enqueue(slot: u8, off: u64, sz: u64) {}
+    fn ahci_flush_enqueue(slot: u8) {}
+    fn ahci_complete(slot: u8, error: u8) {}
+}
+
+/// Size of the register space behind the AHCI Base Address (ABAR)
+const ABAR_SIZE: usize = 0x1000;
+
+/// Number of command slots in the command list of the port
+const NUM_SLOTS: u32 = 32;
+
+/// Max number of sectors per block for READ/WRITE MULTIPLE commands
+const MAX_MULTIPLE: u16 = 16;
+
+/// Size (in bytes) of a sector of CD-ROM media, of which the block size of a
+/// CD-ROM drive's backend must be a divisor
+pub const CDROM_SECTOR_SIZE: usize = 2048;
+
+/// Model number reported by IDENTIFY DEVICE
+const MODEL_NUMBER: &str = "Propolis SATA Disk";
+
+/// Firmware revision reported by IDENTIFY DEVICE
+const FIRMWARE_REV: &str = "1.0";
+
+/// State of the (single) port of the HBA
+#[derive(Default)]
+struct PortState {
+    /// Command List Base Address (PxCLB/PxCLBU)
+    clb: u64,
+    /// FIS Base Address (PxFB/PxFBU)
+    fb: u64,
+    /// Interrupt Status (PxIS)
+    is: u32,
+    /// Interrupt Enable (PxIE)
+    ie: u32,
+    /// Guest-controlled bits (ST and FRE) of Command and Status (PxCMD)
+    cmd: u32,
+    /// Task File Data (PxTFD): ATA Status in bits 7:0, Error in bits 15:8
+    tfd: u32,
+    /// Serial ATA Control (PxSCTL)
+    sctl: u32,
+    /// Serial ATA Active (PxSACT)
+    sact: u32,
+    /// Command Issue (PxCI)
+    ci: u32,
+
+    /// Slot of the command being processed by the backend, if any
+    active: Option<u8>,
+}
+impl PortState {
+    fn new() -> Self {
+        // The attached device has already sent its signature FIS, and is
+        // ready to receive commands.
+        Self { tfd: u32::from(ATA_STS_READY), ..Default::default() }
+    }
+}
+
+struct AhciState {
+    /// Global HBA Control (GHC), excluding the read-only AE bit
+    ghc: u32,
+    /// Interrupt Status (IS): bit 0 is set when the port has raised an
+    /// interrupt
+    is: u32,
+    port: PortState,
+
+    /// Incremented whenever the port is stopped or reset, so that the
+    /// completion of any command the backend was processing at the time can
+    /// be discarded.
+    gen: u64,
+
+    /// State of the CD-ROM drive, if that (rather than a disk) is attached
+    atapi: Option<AtapiState>,
+
+    /// Legacy interrupt pin, and whether it is currently asserted
+    pin: Option<Arc<dyn IntrPin>>,
+    pin_asserted: bool,
+    /// Has delivery of interrupts through the pin been enabled in the PCI
+    /// command register?
+    pin_enabled: bool,
+}
+impl AhciState {
+    /// Update the state of the interrupt pin to reflect pending interrupts
+    fn sync_intr(&mut self) {
+        if self.port.is & self.port.ie != 0 {
+            self.is |= 1;
+        }
+        let level = self.pin_enabled && self.ghc & GHC_IE != 0 && self.is != 0;
+        if level != self.pin_asserted {
+            if let Some(pin) = self.pin.as_ref() {
+                pin.set_state(level);
+            }
+            self.pin_asserted = level;
+        }
+    }
+
+    /// Abandon any commands issued to the port, as it is stopped or reset.
+    fn clear_cmds(&mut self) {
+        self.port.ci = 0;
+        self.port.sact = 0;
+        self.port.active = None;
+        self.gen += 1;
+    }
+
+    fn reset(&mut self) {
+        self.clear_cmds();
+        self.ghc = 0;
+        self.is = 0;
+        self.port = PortState::new();
+        if let Some(atapi) = self.atapi.as_mut() {
+            atapi.reset();
+        }
+        self.sync_intr();
+    }
+
+    /// Signature of the attached device, identifying its type
+    fn signature(&self) -> u32 {
+        match self.atapi {
+            Some(_) => SIG_ATAPI,
+            None => SIG_ATA,
+        }
+    }
+}
+
+/// Details of a command being processed by the backend
+struct CompletionPayload {
+    /// Command slot from which the command was issued
+    slot: u8,
+    /// Generation of the port (see [AhciState::gen]) when it was issued
+    gen: u64,
+    /// Size (in bytes) of the data transfer
+    bytes: u32,
+    /// Was the command a PIO data-in command?
+    pio_in: bool,
+}
+
+/// Outcome of an ATA command, to be reported to the guest
+struct CmdResult {
+    /// Bytes of data transferred to or from guest memory
+    bytes: u32,
+    /// Was data read using PIO, requiring a PIO Setup FIS (rather than a D2H
+    /// Register FIS) to report the result?
+    pio_in: bool,
+    /// Value of the ATA Error register, non-zero if the command failed
+    error: u8,
+    /// Value of the ATA Sector Count register
+    count: u16,
+}
+impl CmdResult {
+    fn success() -> Self {
+        Self { bytes: 0, pio_in: false, error: 0, count: 0 }
+    }
+    fn error(error: u8) -> Self {
+        Self { error, ..Self::success() }
+    }
+}
+
+/// Data transfer requested by an ATA read or write command
+struct Transfer {
+    lba: u64,
+    count: u32,
+    write: bool,
+    pio: bool,
+}
+
+/// AHCI HBA with a single SATA disk or CD-ROM drive attached
+pub struct PciAhci {
+    state: Mutex<AhciState>,
+    pci_state: pci::DeviceState,
+
+    block_attach: block::DeviceAttachment,
+    block_tracking: block::tracking::Tracking<CompletionPayload>,
+
+    /// Serial number reported by IDENTIFY (PACKET) DEVICE
+    serial_number: String,
+
+    /// Logger resource
+    log: slog::Logger,
+}
+
+impl PciAhci {
+    /// Create a new AHCI HBA, with a disk reporting the given serial number
+    pub fn create(serial_number: String, log: slog::Logger) -> Arc<Self> {
+        Self::new(serial_number, None, log)
+    }
+
+    /// Create a new AHCI HBA, with a CD-ROM drive reporting the given serial
+    /// number.  The drive starts out with media (provided by the attached
+    /// backend) loaded.
+    pub fn create_cdrom(serial_number: String, log: slog::Logger) -> Arc<Self> {
+        Self::new(serial_number, Some(AtapiState::new()), log)
+    }
+
+    fn new(
+        serial_number: String,
+        atapi: Option<AtapiState>,
+        log: slog::Logger,
+    ) -> Arc<Self> {
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_OXIDE,
+            device_id: PROPOLIS_AHCI_DEV_ID,
+            sub_vendor_id: VENDOR_OXIDE,
+            sub_device_id: PROPOLIS_AHCI_DEV_ID,
+            class: pci::bits::CLASS_STORAGE,
+            subclass: pci::bits::SUBCLASS_STORAGE_SATA,
+            prog_if: pci::bits::PROGIF_AHCI,
+            ..Default::default()
+        })
+        // The AHCI Base Address (ABAR) is always found in BAR5
+        .add_bar_mmio(pci::BarN::BAR5, ABAR_SIZE as u32)
+        .add_lintr()
+        .finish();
+
+        let state = AhciState {
+            ghc: 0,
+            is: 0,
+            port: PortState::new(),
+            gen: 0,
+            atapi,
+            pin: None,
+            pin_asserted: false,
+            pin_enabled: false,
+        };
+
+        Arc::new_cyclic(|weak| PciAhci {
+            state: Mutex::new(state),
+            pci_state,
+            block_attach: block::DeviceAttachment::new(),
+            block_tracking: block::tracking::Tracking::new(
+                weak.clone() as Weak<dyn block::Device>
+            ),
+            serial_number,
+            log,
+        })
+    }
+
+    fn reg_read(&self, id: &AhciReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        let port = &state.port;
+        match id {
+            AhciReg::Cap => {
+                // A single port (CAP.NP = 0) supporting Gen 3 speeds
+                ro.write_u32(
+                    CAP_S64A
+                        | CAP_SCLO
+                        | (3 << CAP_ISS_SHIFT)
+                        | CAP_SAM
+                        | ((NUM_SLOTS - 1) << CAP_NCS_SHIFT),
+                );
+            }
+            AhciReg::Ghc => ro.write_u32(state.ghc | GHC_AE),
+            AhciReg::Is => ro.write_u32(state.is),
+            AhciReg::Pi => ro.write_u32(1),
+            AhciReg::Vs => ro.write_u32(AHCI_VER_1_3_1),
+
+            AhciReg::PxClb => ro.write_u64(port.clb),
+            AhciReg::PxFb => ro.write_u64(port.fb),
+            AhciReg::PxIs => ro.write_u32(port.is),
+            AhciReg::PxIe => ro.write_u32(port.ie),
+            AhciReg::PxCmd => {
+                let mut val = port.cmd | PXCMD_SUD | PXCMD_POD;
+                if port.cmd & PXCMD_FRE != 0 {
+                    val |= PXCMD_FR;
+                }
+                if port.cmd & PXCMD_ST != 0 {
+                    val |= PXCMD_CR;
+                }
+                if let Some(slot) = port.active {
+                    val |= u32::from(slot) << PXCMD_CCS_SHIFT;
+                }
+                ro.write_u32(val);
+            }
+            AhciReg::PxTfd => ro.write_u32(port.tfd),
+            AhciReg::PxSig => ro.write_u32(state.signature()),
+            AhciReg::PxSsts => {
+                if port.sctl & PXSCTL_DET_MASK == PXSCTL_DET_COMRESET {
+                    // No communication while COMRESET is asserted
+                    ro.write_u32(0);
+                } else {
+                    ro.write_u32(PXSSTS_ACTIVE);
+                }
+            }
+            AhciReg::PxSctl => ro.write_u32(port.sctl),
+            AhciReg::PxSact => ro.write_u32(port.sact),
+            AhciReg::PxCi => ro.write_u32(port.ci),
+
+            // Command completion coalescing, enclosure management, BIOS/OS
+            // handoff, port errors, notifications, and FIS-based switching
+            // are not supported.
+            AhciReg::CccCtl
+            | AhciReg::CccPorts
+            | AhciReg::EmLoc
+            | AhciReg::EmCtl
+            | AhciReg::Cap2
+            | AhciReg::Bohc
+            | AhciReg::PxSerr
+            | AhciReg::PxSntf
+            | AhciReg::PxFbs
+            | AhciReg::Reserved => ro.fill(0),
+        }
+    }
+
+    fn reg_write(&self, id: &AhciReg, wo: &mut WriteOp) {
+        let mut state = self.state.lock().unwrap();
+        let mut notify = false;
+        match id {
+            AhciReg::Ghc => {
+                let val = wo.read_u32();
+                if val & GHC_HR != 0 {
+                    // The reset completes immediately, clearing HR
+                    slog::info!(self.log, "AHCI HBA reset");
+                    state.reset();
+                } else {
+                    state.ghc = val & GHC_IE;
+                    state.sync_intr();
+                }
+            }
+            AhciReg::Is => {
+                state.is &= !wo.read_u32();
+                state.sync_intr();
+            }
+
+            AhciReg::PxClb => {
+                // The command list is 1K-aligned
+                state.port.clb = wo.read_u64() & !0x3ff;
+            }
+            AhciReg::PxFb => {
+                // The received FIS area is 256-byte aligned
+                state.port.fb = wo.read_u64() & !0xff;
+            }
+            AhciReg::PxIs => {
+                state.port.is &= !wo.read_u32();
+                state.sync_intr();
+            }
+            AhciReg::PxIe => {
+                state.port.ie = wo.read_u32();
+                state.sync_intr();
+            }
+            AhciReg::PxCmd => {
+                let val = wo.read_u32();
+                let was_started = state.port.cmd & PXCMD_ST != 0;
+                if val & PXCMD_CLO != 0 {
+                    // Command List Override clears BSY and DRQ, so that
+                    // commands may be issued to a wedged device.
+                    state.port.tfd &= !u32::from(ATA_STS_BSY | ATA_STS_DRQ);
+                }
+                state.port.cmd = val & (PXCMD_ST | PXCMD_FRE);
+                match (was_started, val & PXCMD_ST != 0) {
+                    (true, false) => state.clear_cmds(),
+                    (false, true) => notify = true,
+                    _ => {}
+                }
+            }
+            AhciReg::PxSctl => {
+                let val = wo.read_u32();
+                let was_reset =
+                    state.port.sctl & PXSCTL_DET_MASK == PXSCTL_DET_COMRESET;
+                let is_reset = val & PXSCTL_DET_MASK == PXSCTL_DET_COMRESET;
+                state.port.sctl = val;
+                if !was_reset && is_reset {
+                    state.clear_cmds();
+                    state.port.tfd = u32::from(ATA_STS_BSY);
+                } else if was_reset && !is_reset {
+                    // With COMRESET released, the device sends its signature
+                    state.port.tfd = u32::from(ATA_STS_READY);
+                    if let Some(mem) = self.pci_state.acc_mem.access() {
+                        Self::post_signature(&state, &mem);
+                    }
+                }
+            }
+            AhciReg::PxSact => {
+                state.port.sact |= wo.read_u32();
+            }
+            AhciReg::PxCi => {
+                let val = wo.read_u32();
+                if state.port.cmd & PXCMD_ST != 0 {
+                    state.port.ci |= val;
+                    notify = true;
+                }
+            }
+
+            AhciReg::Cap
+            | AhciReg::Pi
+            | AhciReg::Vs
+            | AhciReg::CccCtl
+            | AhciReg::CccPorts
+            | AhciReg::EmLoc
+            | AhciReg::EmCtl
+            | AhciReg::Cap2
+            | AhciReg::Bohc
+            | AhciReg::PxTfd
+            | AhciReg::PxSig
+            | AhciReg::PxSsts
+            | AhciReg::PxSerr
+            | AhciReg::PxSntf
+            | AhciReg::PxFbs
+            | AhciReg::Reserved => {}
+        }
+        drop(state);
+
+        if notify {
+            self.block_attach.notify();
+        }
+    }
+
+    /// Post the D2H Register FIS a device sends (with its signature) when it
+    /// has been reset.
+    fn post_signature(state: &AhciState, mem: &MemCtx) {
+        let port = &state.port;
+        if port.cmd & PXCMD_FRE == 0 {
+            return;
+        }
+        let sig = state.signature();
+        let fis = RegD2HFis {
+            fis_type: FIS_TYPE_REG_D2H,
+            status: ATA_STS_READY,
+            lba_low: [(sig >> 8) as u8, (sig >> 16) as u8, (sig >> 24) as u8],
+            count: (sig & 0xff) as u16,
+            ..Default::default()
+        };
+        mem.write(GuestAddr(port.fb + RFIS_D2H_OFF), &fis);
+    }
+
+    /// Take the next command issued to the port which requires processing by
+    /// the backend.  Any other commands found along the way are completed
+    /// immediately.
+    fn next_req(&self) -> Option<block::Request> {
+        let mem = self.pci_state.acc_mem.access()?;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            let port = &state.port;
+            if port.cmd & PXCMD_ST == 0 || port.active.is_some() || port.ci == 0
+            {
+                return None;
+            }
+            let slot = port.ci.trailing_zeros() as u8;
+            match self.issue_cmd(&mut state, slot, &mem) {
+                Ok(req) => {
+                    state.port.active = Some(slot);
+                    return Some(req);
+                }
+                Err(res) => Self::finish_cmd(&mut state, slot, res, Some(&mem)),
+            }
+        }
+    }
+
+    /// Begin processing the command in `slot`, returning the block request
+    /// it requires, or the result of the command if it could be completed
+    /// without the backend.
+    fn issue_cmd(
+        &self,
+        state: &mut AhciState,
+        slot: u8,
+        mem: &MemCtx,
+    ) -> Result<block::Request, CmdResult> {
+        let hdr_addr = state.port.clb + u64::from(slot) * CMD_HEADER_SIZE;
+        let hdr: CommandHeader = mem
+            .read(GuestAddr(hdr_addr))
+            .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+        let fis: RegH2DFis = mem
+            .read(GuestAddr(hdr.ctba))
+            .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+        if fis.fis_type != FIS_TYPE_REG_H2D {
+            return Err(CmdResult::error(ATA_ERR_ABRT));
+        }
+
+        if !fis.is_command() {
+            // An update of the Device Control register, as used (with SRST)
+            // to perform a software reset of the device.
+            if fis.control & ATA_CTL_SRST == 0 {
+                Self::post_signature(state, mem);
+            }
+            return Err(CmdResult::success());
+        }
+
+        let command = fis.command;
+        probes::ahci_cmd!(|| (slot, command));
+        if state.atapi.is_some() {
+            return self.issue_atapi_cmd(state, slot, &hdr, &fis, mem);
+        }
+        let info = self.block_attach.info().unwrap_or_default();
+        let xfer = match command {
+            ATA_CMD_IDENTIFY_DEVICE => {
+                let ident = self.identify(&info);
+                // Safety: a [u16] may be freely viewed as bytes
+                let data = unsafe {
+                    std::slice::from_raw_parts(
+                        ident.as_ptr() as *const u8,
+                        std::mem::size_of_val(&ident),
+                    )
+                };
+                let regions = Self::prdt_regions(&hdr, data.len(), mem)
+                    .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+                Self::write_regions(&regions, data, mem)
+                    .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+                return Err(CmdResult {
+                    bytes: data.len() as u32,
+                    pio_in: true,
+                    ..CmdResult::success()
+                });
+            }
+
+            ATA_CMD_READ_DMA | ATA_CMD_WRITE_DMA => Transfer {
+                lba: fis.lba28(),
+                count: fis.count28(),
+                write: command == ATA_CMD_WRITE_DMA,
+                pio: false,
+            },
+            ATA_CMD_READ_DMA_EXT | ATA_CMD_WRITE_DMA_EXT => Transfer {
+                lba: fis.lba48(),
+                count: fis.count48(),
+                write: command == ATA_CMD_WRITE_DMA_EXT,
+                pio: false,
+            },
+            ATA_CMD_READ_SECTORS
+            | ATA_CMD_READ_MULTIPLE
+            | ATA_CMD_WRITE_SECTORS
+            | ATA_CMD_WRITE_MULTIPLE => Transfer {
+                lba: fis.lba28(),
+                count: fis.count28(),
+                write: matches!(
+                    command,
+                    ATA_CMD_WRITE_SECTORS | ATA_CMD_WRITE_MULTIPLE
+                ),
+                pio: true,
+            },
+            ATA_CMD_READ_SECTORS_EXT
+            | ATA_CMD_READ_MULTIPLE_EXT
+            | ATA_CMD_WRITE_SECTORS_EXT
+            | ATA_CMD_WRITE_MULTIPLE_EXT => Transfer {
+                lba: fis.lba48(),
+                count: fis.count48(),
+                write: matches!(
+                    command,
+                    ATA_CMD_WRITE_SECTORS_EXT | ATA_CMD_WRITE_MULTIPLE_EXT
+                ),
+                pio: true,
+            },
+
+            ATA_CMD_FLUSH_CACHE | ATA_CMD_FLUSH_CACHE_EXT => {
+                probes::ahci_flush_enqueue!(|| slot);
+                return Ok(self.block_tracking.track(
+                    block::Request::new_flush(),
+                    CompletionPayload {
+                        slot,
+                        gen: state.gen,
+                        bytes: 0,
+                        pio_in: false,
+                    },
+                ));
+            }
+
+            ATA_CMD_SET_MULTIPLE_MODE => {
+                let count = fis.count & 0xff;
+                if count > MAX_MULTIPLE || !(count as u8).is_power_of_two() {
+                    return Err(CmdResult::error(ATA_ERR_ABRT));
+                }
+                return Err(CmdResult::success());
+            }
+            ATA_CMD_CHECK_POWER_MODE => {
+                // Always in the active (or idle) power mode
+                return Err(CmdResult { count: 0xff, ..CmdResult::success() });
+            }
+            ATA_CMD_VERIFY_SECTORS
+            | ATA_CMD_VERIFY_SECTORS_EXT
+            | ATA_CMD_INIT_DEV_PARAMS
+            | ATA_CMD_SET_FEATURES
+            | ATA_CMD_STANDBY_IMMEDIATE
+            | ATA_CMD_IDLE_IMMEDIATE
+            | ATA_CMD_STANDBY
+            | ATA_CMD_IDLE => {
+                // Nothing to verify, transfer modes and power management
+                // have no bearing on an emulated device, and the write cache
+                // is left enabled.
+                return Err(CmdResult::success());
+            }
+            _ => {
+                slog::debug!(self.log, "unsupported ATA command";
+                    "command" => command,
+                );
+                return Err(CmdResult::error(ATA_ERR_ABRT));
+            }
+        };
+
+        if xfer.lba.saturating_add(u64::from(xfer.count)) > info.total_size {
+            return Err(CmdResult::error(ATA_ERR_IDNF | ATA_ERR_ABRT));
+        }
+        if xfer.write && info.read_only {
+            return Err(CmdResult::error(ATA_ERR_ABRT));
+        }
+        let block_size = info.block_size as usize;
+        let off = xfer.lba as usize * block_size;
+        let sz = xfer.count as usize * block_size;
+        let regions = Self::prdt_regions(&hdr, sz, mem)
+            .ok_or(CmdResult::error(ATA_ERR_ABRT))?;
+        let payload = CompletionPayload {
+            slot,
+            gen: state.gen,
+            bytes: sz as u32,
+            pio_in: xfer.pio && !xfer.write,
+        };
+
+        let req = if xfer.write {
+            probes::ahci_write_enqueue!(|| (slot, off as u64, sz as u64));
+            block::Request::new_write(off, sz, regions)
+        } else {
+            probes::ahci_read_enqueue!(|| (slot, off as u64, sz as u64));
+            block::Request::new_read(off, sz, regions)
+        };
+        Ok(self.block_tracking.track(req, payload))
+    }
+
+    /// Report the result of the command in `slot` to the guest, clearing it
+    /// from the command list.
+    fn finish_cmd(
+        state: &mut AhciState,
+        slot: u8,
+        res: CmdResult,
+        mem: Option<&MemCtx>,
+    ) {
+        probes::ahci_complete!(|| (slot, res.error));
+        let port = &mut state.port;
+        let status = match res.error {
+            0 => ATA_STS_READY,
+            _ => ATA_STS_READY | ATA_STS_ERR,
+        };
+
+        if let Some(mem) = mem {
+            let hdr_addr = port.clb + u64::from(slot) * CMD_HEADER_SIZE;
+            mem.write(GuestAddr(hdr_addr + 4), &res.bytes);
+
+            if port.cmd & PXCMD_FRE != 0 {
+                if res.pio_in && res.error == 0 {
+                    let fis = PioSetupFis {
+                        fis_type: FIS_TYPE_PIO_SETUP,
+                        // Device-to-host transfer, with interrupt
+                        flags: (1 << 5) | (1 << 6),
+                        status: ATA_STS_READY | ATA_STS_DRQ,
+                        e_status: status,
+                        transfer_count: res.bytes.min(0xffff) as u16,
+                        ..Default::default()
+                    };
+                    mem.write(GuestAddr(port.fb + RFIS_PIO_SETUP_OFF), &fis);
+                } else {
+                    let fis = RegD2HFis {
+                        fis_type: FIS_TYPE_REG_D2H,
+                        // Interrupt
+                        flags: 1 << 6,
+                        status,
+                        error: res.error,
+                        count: res.count,
+                        ..Default::default()
+                    };
+                    mem.write(GuestAddr(port.fb + RFIS_D2H_OFF), &fis);
+                }
+            }
+        }
+
+        port.is |=
+            if res.pio_in && res.error == 0 { PXIS_PSS } else { PXIS_DHRS };
+        if res.error != 0 {
+            port.is |= PXIS_TFES;
+        }
+        port.tfd = (u32::from(res.error) << 8) | u32::from(status);
+        port.ci &= !(1 << slot);
+        state.sync_intr();
+    }
+
+    /// Gather the guest memory regions, as described by the Physical Region
+    /// Descriptor Table of a command, for a transfer of `len` bytes.
+    fn prdt_regions(
+        hdr: &CommandHeader,
+        len: usize,
+        mem: &MemCtx,
+    ) -> Option<Vec<GuestRegion>> {
+        let prdt = hdr.ctba + CMD_TABLE_PRDT_OFF;
+        let mut regions = Vec::new();
+        let mut remain = len;
+        for i in 0..u64::from(hdr.prdtl()) {
+            if remain == 0 {
+                break;
+            }
+            let ent: PrdtEntry = mem.read(GuestAddr(prdt + i * 16))?;
+            let sz = ent.len().min(remain);
+            regions.push(GuestRegion(GuestAddr(ent.dba), sz));
+            remain -= sz;
+        }
+        // The guest must provide enough buffer space for the whole transfer
+        (remain == 0).then_some(regions)
+    }
+
+    fn write_regions(
+        regions: &[GuestRegion],
+        mut data: &[u8],
+        mem: &MemCtx,
+    ) -> Option<()> {
+        for region in regions {
+            let chunk;
+            (chunk, data) = data.split_at(region.1.min(data.len()));
+            mem.writable_region(region)?.write_bytes(chunk).ok()?;
+        }
+        Some(())
+    }
+
+    /// Assemble the data returned by IDENTIFY DEVICE
+    ///
+    /// See ACS-3 Section 7.12.7 IDENTIFY DEVICE data
+    fn identify(&self, info: &block::DeviceInfo) -> [u16; 256] {
+        let mut id = [0u16; 256];
+        let sectors = info.total_size;
+
+        // Fixed (non-removable) ATA device
+        id[0] = 0x0040;
+        // Obsolete CHS geometry, which some older guests still consult
+        let cylinders = (sectors / (16 * 63)).min(16383) as u16;
+        id[1] = cylinders;
+        id[3] = 16;
+        id[6] = 63;
+        ata_string(&mut id[10..20], &self.serial_number);
+        ata_string(&mut id[23..27], FIRMWARE_REV);
+        ata_string(&mut id[27..47], MODEL_NUMBER);
+        id[47] = 0x8000 | MAX_MULTIPLE;
+        // LBA and DMA supported
+        id[49] = (1 << 9) | (1 << 8);
+        id[50] = 0x4000;
+        // Words 64-70 and 88 are valid
+        id[53] = (1 << 2) | (1 << 1);
+        // Current CHS geometry and capacity
+        id[54] = cylinders;
+        id[55] = 16;
+        id[56] = 63;
+        let chs_sectors = u32::from(cylinders) * 16 * 63;
+        id[57] = chs_sectors as u16;
+        id[58] = (chs_sectors >> 16) as u16;
+        id[59] = 0x0100 | MAX_MULTIPLE;
+        // Sectors addressable by 28-bit commands
+        let lba28 = sectors.min(0x0fff_ffff) as u32;
+        id[60] = lba28 as u16;
+        id[61] = (lba28 >> 16) as u16;
+        // Multiword DMA modes 0-2 supported, and PIO modes 3-4
+        id[63] = 0x0007;
+        id[64] = 0x0003;
+        id[65] = 120;
+        id[66] = 120;
+        id[67] = 120;
+        id[68] = 120;
+        // SATA Gen 1-3 speeds supported
+        id[76] = (1 << 3) | (1 << 2) | (1 << 1);
+        // ATA/ATAPI-4 through ACS-3
+        id[80] = 0x03f0;
+        // Write cache supported and enabled
+        id[82] = 1 << 5;
+        id[85] = 1 << 5;
+        // 48-bit addressing, FLUSH CACHE, and FLUSH CACHE EXT supported and
+        // enabled
+        id[83] = 0x4000 | (1 << 13) | (1 << 12) | (1 << 10);
+        id[86] = (1 << 13) | (1 << 12) | (1 << 10);
+        id[84] = 0x4000;
+        id[87] = 0x4000;
+        // Ultra DMA modes 0-6 supported, with mode 6 selected
+        id[88] = (1 << 14) | 0x007f;
+        // Sectors addressable by 48-bit commands
+        id[100] = sectors as u16;
+        id[101] = (sectors >> 16) as u16;
+        id[102] = (sectors >> 32) as u16;
+        id[103] = (sectors >> 48) as u16;
+        id[106] = 0x4000;
+        if info.block_size > 512 {
+            // Logical sectors larger than 512 bytes, with their size given
+            // (in words) by words 117-118
+            id[106] |= 1 << 12;
+            let words = info.block_size / 2;
+            id[117] = words as u16;
+            id[118] = (words >> 16) as u16;
+        }
+        // Non-rotating media
+        id[217] = 1;
+
+        set_integrity_word(&mut id);
+
+        id
+    }
+
+    fn export(&self) -> migrate::AhciStateV1 {
+        let state = self.state.lock().unwrap();
+        let port = &state.port;
+        migrate::AhciStateV1 {
+            ghc: state.ghc,
+            is: state.is,
+            port_clb: port.clb,
+            port_fb: port.fb,
+            port_is: port.is,
+            port_ie: port.ie,
+            port_cmd: port.cmd,
+            port_tfd: port.tfd,
+            port_sctl: port.sctl,
+            port_sact: port.sact,
+            port_ci: port.ci,
+        }
+    }
+
+    fn import(&self, saved: migrate::AhciStateV1) {
+        let mut state = self.state.lock().unwrap();
+        state.ghc = saved.ghc & GHC_IE;
+        state.is = saved.is;
+        state.port = PortState {
+            clb: saved.port_clb,
+            fb: saved.port_fb,
+            is: saved.port_is,
+            ie: saved.port_ie,
+            cmd: saved.port_cmd & (PXCMD_ST | PXCMD_FRE),
+            tfd: saved.port_tfd,
+            sctl: saved.port_sctl,
+            sact: saved.port_sact,
+            ci: saved.port_ci,
+            active: None,
+        };
+
+        // The interrupt pin state is imported separately, so just update the
+        // accounting of it to match.
+        if state.port.is & state.port.ie != 0 {
+            state.is |= 1;
+        }
+        let level =
+            state.pin_enabled && state.ghc & GHC_IE != 0 && state.is != 0;
+        if let Some(pin) = state.pin.as_ref() {
+            pin.import_state(level);
+        }
+        state.pin_asserted = level;
+    }
+}
+
+/// Set the integrity word of IDENTIFY data: a signature, and a checksum making
+/// the sum of all bytes of the data 0.
+pub(crate) fn set_integrity_word(id: &mut [u16; 256]) {
+    id[255] = 0x00a5;
+    let sum = id
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .fold(0u8, |acc, b| acc.wrapping_add(b));
+    id[255] |= u16::from(sum.wrapping_neg()) << 8;
+}
+
+/// Format `s` as an ATA string: padded with spaces, with two characters per
+/// word, the first of which is in the upper byte.
+pub(crate) fn ata_string(dst: &mut [u16], s: &str) {
+    let mut bytes = s.bytes().chain(std::iter::repeat(b' '));
+    for word in dst.iter_mut() {
+        let hi = bytes.next().unwrap();
+        let lo = bytes.next().unwrap();
+        *word = u16::from_be_bytes([hi, lo]);
+    }
+}
+
+impl pci::Device for PciAhci {
+    fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp) {
+        assert_eq!(bar, pci::BarN::BAR5);
+        AHCI_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.reg_read(id, ro),
+            RWOp::Write(wo) => self.reg_write(id, wo),
+        });
+    }
+
+    fn attach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pin = self.pci_state.lintr_pin();
+    }
+
+    fn interrupt_mode_change(&self, mode: pci::IntrMode) {
+        let mut state = self.state.lock().unwrap();
+        state.pin_enabled = mode == pci::IntrMode::INTxPin;
+        state.sync_intr();
+    }
+
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl block::Device for PciAhci {
+    fn attachment(&self) -> &block::DeviceAttachment {
+        &self.block_attach
+    }
+
+    fn next(&self) -> Option<block::Request> {
+        self.next_req()
+    }
+
+    fn complete(&self, res: block::Result, id: block::ReqId) {
+        let (op, payload) = self.block_tracking.complete(id, res);
+        let CompletionPayload { slot, gen, bytes, pio_in } = payload;
+
+        let mut state = self.state.lock().unwrap();
+        if gen != state.gen {
+            // The port was stopped or reset while the backend was processing
+            // the command, so there is no longer anyone to tell of its result.
+            return;
+        }
+        state.port.active = None;
+
+        let res = match state.atapi.as_mut() {
+            Some(atapi) => atapi.complete_read(res, bytes, pio_in),
+            None => {
+                let error = match res {
+                    block::Result::Success => 0,
+                    block::Result::Failure if op.is_read() => ATA_ERR_UNC,
+                    block::Result::Failure
+                    | block::Result::ReadOnly
+                    | block::Result::Unsupported => ATA_ERR_ABRT,
+                };
+                CmdResult {
+                    bytes: if error == 0 { bytes } else { 0 },
+                    pio_in,
+                    error,
+                    count: 0,
+                }
+            }
+        };
+        let mem = self.pci_state.acc_mem.access();
+        Self::finish_cmd(&mut state, slot, res, mem.as_deref());
+        drop(state);
+
+        // Any further commands in the command list can now be processed
+        self.block_attach.notify();
+    }
+
+    fn accessor_mem(&self) -> MemAccessor {
+        self.pci_state.acc_mem.child(Some("block backend".to_string()))
+    }
+}
+
+impl Lifecycle for PciAhci {
+    fn type_name(&self) -> &'static str {
+        "pci-ahci"
+    }
+
+    fn reset(&self) {
+        self.state.lock().unwrap().reset();
+        self.pci_state.reset(self);
+    }
+
+    fn pause(&self) {
+        self.block_attach.pause();
+    }
+
+    fn resume(&self) {
+        self.block_attach.resume();
+    }
+
+    fn paused(&self) -> BoxFuture<'static, ()> {
+        Box::pin(self.block_tracking.none_outstanding())
+    }
+
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl MigrateMulti for PciAhci {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        output.push(self.export().into())?;
+        if let Some(atapi) = self.export_atapi() {
+            output.push(atapi.into())?;
+        }
+        MigrateMulti::export(&self.pci_state, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::AhciStateV1 = offer.take()?;
+        self.import(input);
+        if self.is_cdrom() {
+            let input: atapi::migrate::AtapiStateV1 = offer.take()?;
+            self.import_atapi(input);
+        }
+        MigrateMulti::import(&self.pci_state, offer, ctx)
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::AhciStateV1>();
+        if self.is_cdrom() {
+            schemas.add::<atapi::migrate::AtapiStateV1>();
+        }
+        MigrateMulti::schemas(&self.pci_state, schemas)
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct AhciStateV1 {
+        pub ghc: u32,
+        pub is: u32,
+
+        pub port_clb: u64,
+        pub port_fb: u64,
+        pub port_is: u32,
+        pub port_ie: u32,
+        pub port_cmd: u32,
+        pub port_tfd: u32,
+        pub port_sctl: u32,
+        pub port_sact: u32,
+        pub port_ci: u32,
+    }
+    impl Schema<'_> for AhciStateV1 {
+        fn id() -> SchemaId {
+            ("ahci", 1)
+        }
+    }
+}
+
+/// AHCI HBA registers: the generic host control registers, followed by those
+/// of the single port.
+///
+/// See AHCI 1.3.1 Section 3 HBA Memory Registers
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum AhciReg {
+    /// HBA Capabilities (CAP)
+    Cap,
+    /// Global HBA Control (GHC)
+    Ghc,
+    /// Interrupt Status (IS)
+    Is,
+    /// Ports Implemented (PI)
+    Pi,
+    /// Version (VS)
+    Vs,
+    /// Command Completion Coalescing Control (CCC_CTL)
+    CccCtl,
+    /// Command Completion Coalescing Ports (CCC_PORTS)
+    CccPorts,
+    /// Enclosure Management Location (EM_LOC)
+    EmLoc,
+    /// Enclosure Management Control (EM_CTL)
+    EmCtl,
+    /// HBA Capabilities Extended (CAP2)
+    Cap2,
+    /// BIOS/OS Handoff Control and Status (BOHC)
+    Bohc,
+
+    /// Port x Command List Base Address (PxCLB/PxCLBU)
+    PxClb,
+    /// Port x FIS Base Address (PxFB/PxFBU)
+    PxFb,
+    /// Port x Interrupt Status (PxIS)
+    PxIs,
+    /// Port x Interrupt Enable (PxIE)
+    PxIe,
+    /// Port x Command and Status (PxCMD)
+    PxCmd,
+    /// Port x Task File Data (PxTFD)
+    PxTfd,
+    /// Port x Signature (PxSIG)
+    PxSig,
+    /// Port x Serial ATA Status (PxSSTS)
+    PxSsts,
+    /// Port x Serial ATA Control (PxSCTL)
+    PxSctl,
+    /// Port x Serial ATA Error (PxSERR)
+    PxSerr,
+    /// Port x Serial ATA Active (PxSACT)
+    PxSact,
+    /// Port x Command Issue (PxCI)
+    PxCi,
+    /// Port x Serial ATA Notification (PxSNTF)
+    PxSntf,
+    /// Port x FIS-based Switching Control (PxFBS)
+    PxFbs,
+
+    Reserved,
+}
+
+lazy_static! {
+    static ref AHCI_REGS: RegMap<AhciReg> = {
+        let layout = [
+            // Generic Host Control
+            (AhciReg::Cap, 4),
+            (AhciReg::Ghc, 4),
+            (AhciReg::Is, 4),
+            (AhciReg::Pi, 4),
+            (AhciReg::Vs, 4),
+            (AhciReg::CccCtl, 4),
+            (AhciReg::CccPorts, 4),
+            (AhciReg::EmLoc, 4),
+            (AhciReg::EmCtl, 4),
+            (AhciReg::Cap2, 4),
+            (AhciReg::Bohc, 4),
+            (AhciReg::Reserved, 0xd4),
+            // Port 0
+            (AhciReg::PxClb, 8),
+            (AhciReg::PxFb, 8),
+            (AhciReg::PxIs, 4),
+            (AhciReg::PxIe, 4),
+            (AhciReg::PxCmd, 4),
+            (AhciReg::Reserved, 4),
+            (AhciReg::PxTfd, 4),
+            (AhciReg::PxSig, 4),
+            (AhciReg::PxSsts, 4),
+            (AhciReg::PxSctl, 4),
+            (AhciReg::PxSerr, 4),
+            (AhciReg::PxSact, 4),
+            (AhciReg::PxCi, 4),
+            (AhciReg::PxSntf, 4),
+            (AhciReg::PxFbs, 4),
+            (AhciReg::Reserved, 0x3c),
+            // Ports 1-31 are not implemented
+            (AhciReg::Reserved, ABAR_SIZE - 0x180),
+        ];
+        RegMap::create_packed(ABAR_SIZE, &layout, Some(AhciReg::Reserved))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ata_strings() {
+        let mut words = [0u16; 3];
+        ata_string(&mut words, "ABC");
+        assert_eq!(words, [0x4142, 0x4320, 0x2020]);
+    }
+
+    #[test]
+    fn identify_checksum() {
+        let ahci = PciAhci::create(
+            "serial".to_string(),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        );
+        let info = block::DeviceInfo {
+            block_size: 4096,
+            total_size: 1 << 20,
+            read_only: false,
+        };
+        let id = ahci.identify(&info);
+        let sum = id
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .fold(0u8, |acc, b| acc.wrapping_add(b));
+        assert_eq!(sum, 0);
+        assert_eq!(id[255] & 0xff, 0xa5);
+        assert_eq!(u32::from(id[117]) | (u32::from(id[118]) << 16), 2048);
+    }
+}
@@ -43,6 +43,10 @@ impl MigrateMulti for BhyveAtPic {
         offer.take::<migrate::AtPicV1>()?.write(&self.hdl)?;
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::AtPicV1>();
+    }
 }
 
 pub mod migrate {
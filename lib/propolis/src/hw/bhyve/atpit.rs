@@ -43,6 +43,10 @@ impl MigrateMulti for BhyveAtPit {
         offer.take::<migrate::AtPitV1>()?.write(&self.hdl)?;
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::AtPitV1>();
+    }
 }
 
 pub mod migrate {
@@ -42,6 +42,10 @@ impl MigrateSingle for BhyveHpet {
         offer.parse::<migrate::HpetV1>()?.write(&self.hdl)?;
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::HpetV1>();
+    }
 }
 
 pub mod migrate {
@@ -43,6 +43,10 @@ impl MigrateMulti for BhyveIoApic {
         offer.take::<migrate::IoApicV1>()?.write(&self.hdl)?;
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::IoApicV1>();
+    }
 }
 
 pub mod migrate {
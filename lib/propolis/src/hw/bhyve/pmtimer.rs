@@ -90,6 +90,10 @@ impl MigrateMulti for BhyvePmTimer {
         data.write(&self.hdl)?;
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::PmTimerV1>();
+    }
 }
 
 pub mod migrate {
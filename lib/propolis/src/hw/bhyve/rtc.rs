@@ -130,6 +130,10 @@ impl MigrateMulti for BhyveRtc {
         offer.take::<migrate::BhyveRtcV2>()?.write(&self.hdl)?;
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::BhyveRtcV2>();
+    }
 }
 
 pub mod migrate {
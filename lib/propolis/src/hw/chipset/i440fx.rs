@@ -326,6 +326,11 @@ impl MigrateMulti for I440FxHostBridge {
         self.pci_cfg.set_addr(data.pci_cfg_addr);
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        MigrateMulti::schemas(&self.pci_state, schemas);
+        schemas.add::<migrate::I440FxHostBridgeV1>();
+    }
 }
 
 pub struct Piix3Lpc {
@@ -532,6 +537,15 @@ impl MigrateMulti for Piix3Lpc {
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::Piix3LpcV1>();
+        MigrateMulti::schemas(&self.pci_state, schemas);
+        MigrateMulti::schemas(self.pic.as_ref(), schemas);
+        MigrateMulti::schemas(self.pit.as_ref(), schemas);
+        MigrateMulti::schemas(self.ioapic.as_ref(), schemas);
+        MigrateMulti::schemas(self.rtc.as_ref(), schemas)
+    }
 }
 
 const PMCFG_OFFSET: usize = 0x40;
@@ -737,6 +751,12 @@ impl PMRegs {
     fn pmtimer_port(&self) -> u16 {
         self.pm_base.checked_add(PM_TMR_OFFSET).unwrap()
     }
+    /// Whether an SCI should be raised for the enabled PM1 events which are
+    /// pending.
+    fn sci_pending(&self) -> bool {
+        self.pm_ctrl.contains(PmCntrl::SCI_EN)
+            && self.pm_status.bits() & self.pm_ena.bits() != 0
+    }
 }
 
 impl From<PMRegs> for migrate::Piix3PmV1 {
@@ -832,6 +852,21 @@ impl Piix3PM {
         pio.register(PMBASE_DEFAULT, PMBASE_LEN, piofn).unwrap();
     }
 
+    /// Presses the ACPI power button, raising an SCI if the guest has enabled
+    /// the power button event.  A guest with ACPI support will normally
+    /// respond by shutting itself down.
+    pub fn press_power_button(&self) {
+        let mut regs = self.regs.lock().unwrap();
+        regs.pm_status.insert(PmSts::PWRBTN_STS);
+        self.sync_sci(&regs);
+    }
+
+    fn sync_sci(&self, regs: &PMRegs) {
+        if let Some(pin) = self.pci_state.lintr_pin() {
+            pin.set_state(regs.sci_pending());
+        }
+    }
+
     fn pio_rw(&self, _port: u16, mut rwo: RWOp) {
         PM_REGS.process(&mut rwo, |id, rwo| match rwo {
             RWOp::Read(ro) => self.pmreg_read(id, ro),
@@ -938,12 +973,15 @@ impl Piix3PM {
                 let val = PmSts::from_bits_truncate(wo.read_u16());
                 // status bits are W1C
                 regs.pm_status.remove(val);
+                self.sync_sci(&regs);
             }
             PmReg::PmEn => {
                 regs.pm_ena = PmEn::from_bits_truncate(wo.read_u16());
+                self.sync_sci(&regs);
             }
             PmReg::PmCntrl => {
                 regs.pm_ctrl = PmCntrl::from_bits_truncate(wo.read_u16());
+                self.sync_sci(&regs);
                 if regs.pm_ctrl.contains(PmCntrl::SUS_EN) {
                     // SUS_EN is write-only and should always read 0
                     regs.pm_ctrl.remove(PmCntrl::SUS_EN);
@@ -1044,6 +1082,12 @@ impl MigrateMulti for Piix3PM {
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::Piix3PmV1>();
+        MigrateMulti::schemas(&self.pci_state, schemas);
+        MigrateMulti::schemas(self.pmtimer.as_ref(), schemas)
+    }
 }
 
 mod migrate {
@@ -1188,4 +1232,20 @@ mod test {
 
         cfg_write(pm.as_ref() as &dyn Endpoint);
     }
+
+    #[test]
+    fn pm_power_button_raises_sci_when_enabled() {
+        let mut regs = PMRegs::default();
+        regs.pm_status.insert(PmSts::PWRBTN_STS);
+        assert!(!regs.sci_pending());
+
+        regs.pm_ena.insert(PmEn::PWRBTN_EN);
+        assert!(!regs.sci_pending());
+
+        regs.pm_ctrl.insert(PmCntrl::SCI_EN);
+        assert!(regs.sci_pending());
+
+        regs.pm_status.remove(PmSts::PWRBTN_STS);
+        assert!(!regs.sci_pending());
+    }
 }
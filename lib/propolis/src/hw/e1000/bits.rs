@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Register offsets and bit definitions for the 8254x family of controllers.
+//!
+//! See the PCI/PCI-X Family of Gigabit Ethernet Controllers Software
+//! Developer's Manual (Intel document 317453).
+
+#![allow(unused)]
+
+/// Size of the register space behind BAR0
+pub const MMIO_SIZE: usize = 0x20000;
+/// Size of the IOADDR/IODATA window behind BAR1
+pub const IO_SIZE: u16 = 0x40;
+
+pub const IOADDR: usize = 0x0;
+pub const IODATA: usize = 0x4;
+
+// General registers
+pub const REG_CTRL: u32 = 0x0000;
+pub const REG_STATUS: u32 = 0x0008;
+pub const REG_EECD: u32 = 0x0010;
+pub const REG_EERD: u32 = 0x0014;
+pub const REG_CTRL_EXT: u32 = 0x0018;
+pub const REG_MDIC: u32 = 0x0020;
+pub const REG_FCAL: u32 = 0x0028;
+pub const REG_FCAH: u32 = 0x002c;
+pub const REG_FCT: u32 = 0x0030;
+pub const REG_VET: u32 = 0x0038;
+pub const REG_FCTTV: u32 = 0x0170;
+pub const REG_TXCW: u32 = 0x0178;
+pub const REG_RXCW: u32 = 0x0180;
+pub const REG_LEDCTL: u32 = 0x0e00;
+pub const REG_PBA: u32 = 0x1000;
+
+// Interrupt registers
+pub const REG_ICR: u32 = 0x00c0;
+pub const REG_ITR: u32 = 0x00c4;
+pub const REG_ICS: u32 = 0x00c8;
+pub const REG_IMS: u32 = 0x00d0;
+pub const REG_IMC: u32 = 0x00d8;
+
+// Receive registers
+pub const REG_RCTL: u32 = 0x0100;
+pub const REG_FCRTL: u32 = 0x2160;
+pub const REG_FCRTH: u32 = 0x2168;
+pub const REG_RDBAL: u32 = 0x2800;
+pub const REG_RDBAH: u32 = 0x2804;
+pub const REG_RDLEN: u32 = 0x2808;
+pub const REG_RDH: u32 = 0x2810;
+pub const REG_RDT: u32 = 0x2818;
+pub const REG_RDTR: u32 = 0x2820;
+pub const REG_RXDCTL: u32 = 0x2828;
+pub const REG_RADV: u32 = 0x282c;
+pub const REG_RSRPD: u32 = 0x2c00;
+pub const REG_RXCSUM: u32 = 0x5000;
+pub const REG_MTA: u32 = 0x5200;
+pub const REG_RA: u32 = 0x5400;
+pub const REG_VFTA: u32 = 0x5600;
+
+// Transmit registers
+pub const REG_TCTL: u32 = 0x0400;
+pub const REG_TIPG: u32 = 0x0410;
+pub const REG_TXDMAC: u32 = 0x3000;
+pub const REG_TDBAL: u32 = 0x3800;
+pub const REG_TDBAH: u32 = 0x3804;
+pub const REG_TDLEN: u32 = 0x3808;
+pub const REG_TDH: u32 = 0x3810;
+pub const REG_TDT: u32 = 0x3818;
+pub const REG_TIDV: u32 = 0x3820;
+pub const REG_TXDCTL: u32 = 0x3828;
+pub const REG_TADV: u32 = 0x382c;
+pub const REG_TSPMT: u32 = 0x3830;
+
+// Wakeup and manageability registers
+pub const REG_WUC: u32 = 0x5800;
+pub const REG_WUFC: u32 = 0x5808;
+pub const REG_WUS: u32 = 0x5810;
+pub const REG_MANC: u32 = 0x5820;
+
+/// Statistics registers, all of which are cleared when read
+pub const REG_STATS_START: u32 = 0x4000;
+pub const REG_STATS_END: u32 = 0x4100;
+pub const STAT_MPC: u32 = 0x4010;
+pub const STAT_GPRC: u32 = 0x4074;
+pub const STAT_BPRC: u32 = 0x4078;
+pub const STAT_MPRC: u32 = 0x407c;
+pub const STAT_GPTC: u32 = 0x4080;
+pub const STAT_GORCL: u32 = 0x4088;
+pub const STAT_GOTCL: u32 = 0x4090;
+pub const STAT_TORL: u32 = 0x40c0;
+pub const STAT_TOTL: u32 = 0x40c8;
+pub const STAT_TPR: u32 = 0x40d0;
+pub const STAT_TPT: u32 = 0x40d4;
+pub const STAT_MPTC: u32 = 0x40f0;
+pub const STAT_BPTC: u32 = 0x40f4;
+
+/// Number of entries in the Multicast Table Array
+pub const MTA_LEN: usize = 128;
+/// Number of Receive Address registers (each a RAL/RAH pair)
+pub const RA_LEN: usize = 16;
+/// Number of entries in the VLAN Filter Table Array
+pub const VFTA_LEN: usize = 128;
+
+// CTRL
+pub const CTRL_FD: u32 = 1 << 0;
+pub const CTRL_SLU: u32 = 1 << 6;
+pub const CTRL_SPD_1000: u32 = 1 << 9;
+pub const CTRL_SWDPIN0: u32 = 1 << 18;
+pub const CTRL_SWDPIN2: u32 = 1 << 20;
+pub const CTRL_RST: u32 = 1 << 26;
+pub const CTRL_VME: u32 = 1 << 30;
+pub const CTRL_PHY_RST: u32 = 1 << 31;
+
+// STATUS
+pub const STATUS_FD: u32 = 1 << 0;
+pub const STATUS_LU: u32 = 1 << 1;
+pub const STATUS_SPEED_1000: u32 = 1 << 7;
+pub const STATUS_ASDV_1000: u32 = 1 << 9;
+
+// EECD
+pub const EECD_SK: u32 = 1 << 0;
+pub const EECD_CS: u32 = 1 << 1;
+pub const EECD_DI: u32 = 1 << 2;
+pub const EECD_DO: u32 = 1 << 3;
+pub const EECD_FWE_MASK: u32 = 0b11 << 4;
+pub const EECD_REQ: u32 = 1 << 6;
+pub const EECD_GNT: u32 = 1 << 7;
+pub const EECD_PRES: u32 = 1 << 8;
+
+/// Opcode for a read from a Microwire EEPROM
+pub const EEPROM_READ_OPCODE: u32 = 0b110;
+
+// EERD
+pub const EERD_START: u32 = 1 << 0;
+pub const EERD_DONE: u32 = 1 << 4;
+pub const EERD_ADDR_SHIFT: u32 = 8;
+pub const EERD_DATA_SHIFT: u32 = 16;
+
+/// Number of 16-bit words in the EEPROM
+pub const EEPROM_WORDS: usize = 64;
+/// Value to which the EEPROM words (including the checksum word) must sum
+pub const EEPROM_SUM: u16 = 0xbaba;
+
+// MDIC
+pub const MDIC_DATA_MASK: u32 = 0xffff;
+pub const MDIC_REG_SHIFT: u32 = 16;
+pub const MDIC_PHY_SHIFT: u32 = 21;
+pub const MDIC_OP_WRITE: u32 = 1 << 26;
+pub const MDIC_OP_READ: u32 = 1 << 27;
+pub const MDIC_READY: u32 = 1 << 28;
+pub const MDIC_INT_EN: u32 = 1 << 29;
+pub const MDIC_ERROR: u32 = 1 << 30;
+
+/// Address of the (only) PHY on the MDIO bus
+pub const PHY_ADDR: u32 = 1;
+
+// PHY registers
+pub const PHY_CTRL: usize = 0x00;
+pub const PHY_STATUS: usize = 0x01;
+pub const PHY_ID1: usize = 0x02;
+pub const PHY_ID2: usize = 0x03;
+pub const PHY_AUTONEG_ADV: usize = 0x04;
+pub const PHY_LP_ABILITY: usize = 0x05;
+pub const PHY_AUTONEG_EXP: usize = 0x06;
+pub const PHY_1000T_CTRL: usize = 0x09;
+pub const PHY_1000T_STATUS: usize = 0x0a;
+pub const M88_PHY_SPEC_CTRL: usize = 0x10;
+pub const M88_PHY_SPEC_STATUS: usize = 0x11;
+pub const M88_EXT_PHY_SPEC_CTRL: usize = 0x14;
+pub const PHY_REGS: usize = 0x20;
+
+pub const PHY_CTRL_RESTART_AUTONEG: u16 = 1 << 9;
+pub const PHY_CTRL_RESET: u16 = 1 << 15;
+
+// ICR/ICS/IMS/IMC
+pub const INTR_TXDW: u32 = 1 << 0;
+pub const INTR_TXQE: u32 = 1 << 1;
+pub const INTR_LSC: u32 = 1 << 2;
+pub const INTR_RXSEQ: u32 = 1 << 3;
+pub const INTR_RXDMT0: u32 = 1 << 4;
+pub const INTR_RXO: u32 = 1 << 6;
+pub const INTR_RXT0: u32 = 1 << 7;
+pub const INTR_MDAC: u32 = 1 << 9;
+/// All interrupt causes implemented by the 82540
+pub const INTR_MASK: u32 = 0x1_f6df;
+
+// RCTL
+pub const RCTL_EN: u32 = 1 << 1;
+pub const RCTL_UPE: u32 = 1 << 3;
+pub const RCTL_MPE: u32 = 1 << 4;
+pub const RCTL_RDMTS_SHIFT: u32 = 8;
+pub const RCTL_MO_SHIFT: u32 = 12;
+pub const RCTL_BAM: u32 = 1 << 15;
+pub const RCTL_BSIZE_SHIFT: u32 = 16;
+pub const RCTL_VFE: u32 = 1 << 18;
+pub const RCTL_BSEX: u32 = 1 << 25;
+pub const RCTL_SECRC: u32 = 1 << 26;
+
+// TCTL
+pub const TCTL_EN: u32 = 1 << 1;
+
+/// Address Valid bit of RAH
+pub const RAH_AV: u32 = 1 << 31;
+
+// Receive descriptor status
+pub const RXD_STAT_DD: u8 = 1 << 0;
+pub const RXD_STAT_EOP: u8 = 1 << 1;
+pub const RXD_STAT_IXSM: u8 = 1 << 2;
+pub const RXD_STAT_VP: u8 = 1 << 3;
+
+// Transmit descriptor command and type, in the lower dword of a descriptor
+pub const TXD_CMD_EOP: u32 = 1 << 24;
+pub const TXD_CMD_IFCS: u32 = 1 << 25;
+pub const TXD_CMD_IC: u32 = 1 << 26;
+pub const TXD_CMD_RS: u32 = 1 << 27;
+pub const TXD_CMD_RPS: u32 = 1 << 28;
+pub const TXD_CMD_DEXT: u32 = 1 << 29;
+pub const TXD_CMD_VLE: u32 = 1 << 30;
+pub const TXD_CMD_IDE: u32 = 1 << 31;
+/// In a data descriptor, the TCP Segmentation Enable bit
+pub const TXD_CMD_TSE: u32 = 1 << 26;
+/// In a context descriptor, indicates a TCP (rather than UDP) packet
+pub const TXD_CMD_TCP: u32 = 1 << 24;
+/// In a context descriptor, indicates an IPv4 (rather than IPv6) packet
+pub const TXD_CMD_IP: u32 = 1 << 25;
+pub const TXD_DTYP_MASK: u32 = 0xf << 20;
+pub const TXD_DTYP_D: u32 = 1 << 20;
+pub const TXD_DTYP_C: u32 = 0;
+
+// Transmit descriptor status and (data descriptor) packet options, in the
+// upper dword of a descriptor
+pub const TXD_STAT_DD: u32 = 1 << 0;
+pub const TXD_POPTS_IXSM: u8 = 1 << 0;
+pub const TXD_POPTS_TXSM: u8 = 1 << 1;
+
+/// EEPROM contents, save for the MAC address (words 0-2), PCI IDs, and
+/// checksum, which are filled in when the device is created.
+pub const EEPROM_TEMPLATE: [u16; EEPROM_WORDS] = [
+    0x0000, 0x0000, 0x0000, 0x0000, 0xffff, 0x0000, 0x0000, 0x0000, //
+    0x3000, 0x1000, 0x6403, 0x0000, 0x0000, 0x0000, 0x0000, 0x3040, //
+    0x0008, 0x2000, 0x7e14, 0x0048, 0x1000, 0x00d8, 0x0000, 0x2700, //
+    0x6cc9, 0x3150, 0x0722, 0x040b, 0x0984, 0x0000, 0xc000, 0x0706, //
+    0x1008, 0x0000, 0x0f04, 0x7fff, 0x4d01, 0xffff, 0xffff, 0xffff, //
+    0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, //
+    0x0100, 0x4000, 0x121c, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, //
+    0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0x0000, //
+];
+pub const EEPROM_SUB_DEV_ID: usize = 0x0b;
+pub const EEPROM_SUB_VENDOR_ID: usize = 0x0c;
+pub const EEPROM_DEV_ID: usize = 0x0d;
+pub const EEPROM_VENDOR_ID: usize = 0x0e;
+pub const EEPROM_CHECKSUM: usize = 0x3f;
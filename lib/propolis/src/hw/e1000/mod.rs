@@ -0,0 +1,1306 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Intel 82540EM (e1000) Gigabit Ethernet controller
+//!
+//! Drivers for this NIC ship with practically every operating system, so it
+//! serves guests (such as older installers and firmware appliances) which have
+//! no support for virtio.  Its datapath is implemented entirely in userspace,
+//! exchanging frames with a [net::Backend].
+//!
+//! Interrupt moderation (ITR, RDTR, and the absolute timers) is not emulated:
+//! interrupts are raised as soon as their cause arises.  Neither are the flow
+//! control, wakeup, or manageability features, whose registers simply hold
+//! whatever the guest writes to them.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::common::*;
+use crate::hw::ids::pci::{
+    E1000_DEV_ID, E1000_SUB_DEV_ID, VENDOR_INTEL, VENDOR_OXIDE,
+};
+use crate::hw::pci;
+use crate::intr_pins::IntrPin;
+use crate::migrate::*;
+use crate::net;
+
+mod bits;
+mod tx;
+
+use bits::*;
+use tx::{TxDesc, TxState};
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn e1000_tx(len: u64) {}
+    fn e1000_tx_drop(len: u64) {}
+    fn e1000_rx(len: u64) {}
+    fn e1000_rx_drop(len: u64) {}
+}
+
+const ETHERADDRL: usize = 6;
+
+/// Shortest frame (excluding the FCS) which may be placed on the wire
+const MIN_FRAME_LEN: usize = 60;
+
+/// Size of a receive or transmit descriptor
+const DESC_SIZE: u32 = 16;
+
+/// Number of (32-bit) statistics registers
+const STATS_LEN: usize = ((REG_STATS_END - REG_STATS_START) / 4) as usize;
+
+/// Registers which have no bearing on the emulation, but which hold whatever
+/// the guest writes to them, along with their values at reset.
+const PLAIN_REGS: &[(u32, u32)] = &[
+    (REG_CTRL_EXT, 0),
+    (REG_FCAL, 0),
+    (REG_FCAH, 0),
+    (REG_FCT, 0),
+    (REG_VET, 0x8100),
+    (REG_FCTTV, 0),
+    (REG_TXCW, 0),
+    (REG_RXCW, 0),
+    (REG_LEDCTL, 0x0602),
+    (REG_PBA, 0x0010_0030),
+    (REG_ITR, 0),
+    (REG_FCRTL, 0),
+    (REG_FCRTH, 0),
+    (REG_RDTR, 0),
+    (REG_RXDCTL, 0),
+    (REG_RADV, 0),
+    (REG_RSRPD, 0),
+    (REG_RXCSUM, 0),
+    (REG_TIPG, 0),
+    (REG_TXDMAC, 0),
+    (REG_TIDV, 0),
+    (REG_TXDCTL, 0),
+    (REG_TADV, 0),
+    (REG_MANC, 0),
+];
+
+/// PHY register contents at reset, as befits a link which has completed
+/// autonegotiation to 1000BASE-T full duplex.
+const PHY_DEFAULTS: &[(usize, u16)] = &[
+    (PHY_CTRL, 0x1140),
+    (PHY_STATUS, 0x796d),
+    (PHY_ID1, 0x0141),
+    (PHY_ID2, 0x0c20),
+    (PHY_AUTONEG_ADV, 0x0de1),
+    (PHY_LP_ABILITY, 0x41e1),
+    (PHY_AUTONEG_EXP, 0x0001),
+    (PHY_1000T_CTRL, 0x0e00),
+    (PHY_1000T_STATUS, 0x3c00),
+    (M88_PHY_SPEC_CTRL, 0x0360),
+    (M88_PHY_SPEC_STATUS, 0xac00),
+    (M88_EXT_PHY_SPEC_CTRL, 0x0d60),
+];
+
+/// A receive or transmit descriptor ring
+#[derive(Copy, Clone, Default)]
+struct Ring {
+    /// Descriptor Base Address (xDBAL/xDBAH)
+    base: u64,
+    /// Descriptor Length (xDLEN), in bytes
+    len: u32,
+    /// Descriptor Head (xDH)
+    head: u32,
+    /// Descriptor Tail (xDT)
+    tail: u32,
+}
+impl Ring {
+    /// Number of descriptors in the ring
+    fn count(&self) -> u32 {
+        self.len / DESC_SIZE
+    }
+
+    /// Are the head and tail within the bounds of the ring?
+    fn valid(&self) -> bool {
+        let count = self.count();
+        count != 0 && self.head < count && self.tail < count
+    }
+
+    /// Number of descriptors (from the head onward) owned by the device.
+    /// The ring must be [valid](Self::valid).
+    fn pending(&self) -> u32 {
+        (self.tail + self.count() - self.head) % self.count()
+    }
+
+    fn desc_addr(&self, idx: u32) -> GuestAddr {
+        GuestAddr(self.base + u64::from(idx) * u64::from(DESC_SIZE))
+    }
+
+    fn advance(&mut self) {
+        self.head = (self.head + 1) % self.count();
+    }
+}
+
+/// Receive descriptor
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct RxDesc {
+    addr: u64,
+    length: u16,
+    csum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+/// State of the Microwire interface to the EEPROM, when bit-banged through
+/// EECD
+#[derive(Copy, Clone, Default)]
+struct Microwire {
+    /// Bits of EECD last written by the guest
+    eecd: u32,
+    /// Bits shifted in (opcode and address) since CS was asserted
+    val_in: u16,
+    bitnum_in: u16,
+    /// Position of the next bit to be shifted out
+    bitnum_out: u16,
+    /// Has a read opcode been shifted in?
+    reading: bool,
+}
+
+struct E1000State {
+    /// Device Control (CTRL), excluding the self-clearing reset bits
+    ctrl: u32,
+    /// Interrupt Cause Read (ICR)
+    icr: u32,
+    /// Interrupt Mask (IMS)
+    ims: u32,
+    rctl: u32,
+    tctl: u32,
+    mdic: u32,
+    eerd: u32,
+    eecd: Microwire,
+    /// Register offset selected through the I/O BAR (IOADDR)
+    ioaddr: u32,
+
+    rx: Ring,
+    tx: Ring,
+    tx_state: TxState,
+
+    mta: [u32; MTA_LEN],
+    /// Receive Addresses, as RAL/RAH pairs
+    ra: [u32; RA_LEN * 2],
+    vfta: [u32; VFTA_LEN],
+    phy: [u16; PHY_REGS],
+    plain: BTreeMap<u32, u32>,
+    stats: [u32; STATS_LEN],
+
+    /// Legacy interrupt pin, and whether it is currently asserted
+    pin: Option<Arc<dyn IntrPin>>,
+    pin_asserted: bool,
+    /// Has delivery of interrupts through the pin been enabled in the PCI
+    /// command register?
+    pin_enabled: bool,
+}
+impl E1000State {
+    fn new(mac: &[u8; ETHERADDRL]) -> Self {
+        let mut state = Self {
+            ctrl: 0,
+            icr: 0,
+            ims: 0,
+            rctl: 0,
+            tctl: 0,
+            mdic: 0,
+            eerd: 0,
+            eecd: Microwire::default(),
+            ioaddr: 0,
+            rx: Ring::default(),
+            tx: Ring::default(),
+            tx_state: TxState::default(),
+            mta: [0; MTA_LEN],
+            ra: [0; RA_LEN * 2],
+            vfta: [0; VFTA_LEN],
+            phy: [0; PHY_REGS],
+            plain: BTreeMap::new(),
+            stats: [0; STATS_LEN],
+            pin: None,
+            pin_asserted: false,
+            pin_enabled: false,
+        };
+        state.reset(mac);
+        state
+    }
+
+    /// Reset all device state, other than that of the interrupt pin.
+    fn reset(&mut self, mac: &[u8; ETHERADDRL]) {
+        self.ctrl = CTRL_FD | CTRL_SLU | CTRL_SPD_1000 | CTRL_SWDPIN2;
+        self.icr = 0;
+        self.ims = 0;
+        self.rctl = 0;
+        self.tctl = 0;
+        self.mdic = 0;
+        self.eerd = 0;
+        self.eecd = Microwire::default();
+        self.ioaddr = 0;
+        self.rx = Ring::default();
+        self.tx = Ring::default();
+        self.tx_state.reset();
+        self.mta = [0; MTA_LEN];
+        self.vfta = [0; VFTA_LEN];
+        self.stats = [0; STATS_LEN];
+
+        // The first receive address is loaded from the EEPROM
+        self.ra = [0; RA_LEN * 2];
+        self.ra[0] = u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]);
+        self.ra[1] = u32::from_le_bytes([mac[4], mac[5], 0, 0]) | RAH_AV;
+
+        self.phy = [0; PHY_REGS];
+        for (reg, val) in PHY_DEFAULTS {
+            self.phy[*reg] = *val;
+        }
+        self.plain = PLAIN_REGS.iter().copied().collect();
+
+        self.sync_intr();
+    }
+
+    /// Update the state of the interrupt pin to reflect pending interrupts
+    fn sync_intr(&mut self) {
+        let level = self.pin_enabled && self.icr & self.ims != 0;
+        if level != self.pin_asserted {
+            if let Some(pin) = self.pin.as_ref() {
+                pin.set_state(level);
+            }
+            self.pin_asserted = level;
+        }
+    }
+
+    fn raise(&mut self, cause: u32) {
+        self.icr |= cause & INTR_MASK;
+        self.sync_intr();
+    }
+
+    fn vet(&self) -> u16 {
+        self.plain[&REG_VET] as u16
+    }
+
+    fn stat_add(&mut self, reg: u32, val: u32) {
+        let stat = &mut self.stats[((reg - REG_STATS_START) / 4) as usize];
+        *stat = stat.saturating_add(val);
+    }
+
+    /// Add to a 64-bit counter, split across a low and high register pair
+    fn stat_add64(&mut self, reg: u32, val: u64) {
+        let idx = ((reg - REG_STATS_START) / 4) as usize;
+        let cur =
+            u64::from(self.stats[idx]) | (u64::from(self.stats[idx + 1]) << 32);
+        let new = cur.saturating_add(val);
+        self.stats[idx] = new as u32;
+        self.stats[idx + 1] = (new >> 32) as u32;
+    }
+
+    /// Should a frame be accepted, according to the receive filters?
+    fn rx_accept(&self, frame: &[u8]) -> bool {
+        if frame.len() < 14 {
+            return false;
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        if self.rctl & RCTL_VFE != 0
+            && ethertype == self.vet()
+            && frame.len() >= 16
+        {
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & 0xfff;
+            let entry = self.vfta[usize::from(vid >> 5)];
+            if entry & (1 << (vid & 0x1f)) == 0 {
+                return false;
+            }
+        }
+
+        let dst = &frame[..ETHERADDRL];
+        let multicast = dst[0] & 1 != 0;
+        if dst == [0xff; ETHERADDRL] && self.rctl & RCTL_BAM != 0 {
+            return true;
+        }
+        if multicast && self.rctl & RCTL_MPE != 0 {
+            return true;
+        }
+        if !multicast && self.rctl & RCTL_UPE != 0 {
+            return true;
+        }
+
+        let exact = self.ra.chunks(2).any(|pair| {
+            let (ral, rah) = (pair[0], pair[1]);
+            if rah & RAH_AV == 0 {
+                return false;
+            }
+            let mut addr = [0u8; 8];
+            addr[..4].copy_from_slice(&ral.to_le_bytes());
+            addr[4..].copy_from_slice(&rah.to_le_bytes());
+            addr[..ETHERADDRL] == *dst
+        });
+        if exact {
+            return true;
+        }
+
+        // The Multicast Offset selects which 12 bits of the address index the
+        // hash table.
+        let shift = [4, 3, 2, 0][((self.rctl >> RCTL_MO_SHIFT) & 3) as usize];
+        let hash =
+            ((u16::from(dst[5]) << 8 | u16::from(dst[4])) >> shift) & 0xfff;
+        self.mta[usize::from(hash >> 5)] & (1 << (hash & 0x1f)) != 0
+    }
+
+    /// Size of the receive buffers, per RCTL
+    fn rx_buf_size(&self) -> usize {
+        let bsize = (self.rctl >> RCTL_BSIZE_SHIFT) & 0b11;
+        match (self.rctl & RCTL_BSEX != 0, bsize) {
+            (true, 1) => 16384,
+            (true, 2) => 8192,
+            (true, 3) => 4096,
+            (_, bsize) => 2048 >> bsize,
+        }
+    }
+
+    fn eecd_write(&mut self, val: u32) {
+        let wire = &mut self.eecd;
+        let old = wire.eecd;
+        wire.eecd =
+            val & (EECD_SK | EECD_CS | EECD_DI | EECD_FWE_MASK | EECD_REQ);
+        if val & EECD_CS == 0 {
+            return;
+        }
+        if (val ^ old) & EECD_CS != 0 {
+            // CS has been asserted, beginning a new command
+            wire.val_in = 0;
+            wire.bitnum_in = 0;
+            wire.bitnum_out = 0;
+            wire.reading = false;
+        }
+        if (val ^ old) & EECD_SK == 0 {
+            return;
+        }
+        if val & EECD_SK == 0 {
+            // Data is shifted out on the falling edge of the clock...
+            wire.bitnum_out = wire.bitnum_out.wrapping_add(1);
+            return;
+        }
+        // ... and shifted in on the rising edge.
+        wire.val_in <<= 1;
+        if val & EECD_DI != 0 {
+            wire.val_in |= 1;
+        }
+        wire.bitnum_in = wire.bitnum_in.wrapping_add(1);
+        if wire.bitnum_in == 9 && !wire.reading {
+            // A 3-bit opcode, followed by a 6-bit word address
+            wire.bitnum_out = ((wire.val_in & 0x3f) << 4).wrapping_sub(1);
+            wire.reading =
+                u32::from((wire.val_in >> 6) & 0b111) == EEPROM_READ_OPCODE;
+        }
+    }
+
+    fn eecd_read(&self, eeprom: &[u16; EEPROM_WORDS]) -> u32 {
+        let wire = &self.eecd;
+        let mut val = EECD_PRES | EECD_GNT | wire.eecd;
+        let word = eeprom[usize::from(wire.bitnum_out >> 4) & 0x3f];
+        let bit = (word >> ((wire.bitnum_out & 0xf) ^ 0xf)) & 1;
+        if !wire.reading || bit != 0 {
+            val |= EECD_DO;
+        }
+        val
+    }
+
+    fn eerd_read(&self, eeprom: &[u16; EEPROM_WORDS]) -> u32 {
+        if self.eerd & EERD_START == 0 {
+            return self.eerd;
+        }
+        let val = self.eerd & !EERD_START;
+        let addr = (val >> EERD_ADDR_SHIFT) as usize;
+        match eeprom.get(addr) {
+            Some(word) => {
+                (u32::from(*word) << EERD_DATA_SHIFT) | EERD_DONE | val
+            }
+            None => EERD_DONE | val,
+        }
+    }
+
+    fn mdic_write(&mut self, val: u32) {
+        let phy = (val >> MDIC_PHY_SHIFT) & 0x1f;
+        let reg = ((val >> MDIC_REG_SHIFT) & 0x1f) as usize;
+        let data = (val & MDIC_DATA_MASK) as u16;
+
+        let mut mdic = val;
+        if phy != PHY_ADDR {
+            mdic = self.mdic | MDIC_ERROR;
+        } else if val & MDIC_OP_READ != 0 {
+            mdic = (val & !MDIC_DATA_MASK) | u32::from(self.phy[reg]);
+        } else if val & MDIC_OP_WRITE != 0 {
+            match reg {
+                PHY_CTRL => {
+                    // Reset and autonegotiation complete immediately
+                    self.phy[reg] =
+                        data & !(PHY_CTRL_RESET | PHY_CTRL_RESTART_AUTONEG);
+                }
+                PHY_STATUS | PHY_ID1 | PHY_ID2 | PHY_LP_ABILITY
+                | PHY_AUTONEG_EXP | PHY_1000T_STATUS | M88_PHY_SPEC_STATUS => {
+                    // Read-only
+                }
+                _ => self.phy[reg] = data,
+            }
+        }
+        self.mdic = mdic | MDIC_READY;
+        if mdic & MDIC_INT_EN != 0 {
+            self.raise(INTR_MDAC);
+        }
+    }
+
+    fn export(&self) -> migrate::E1000StateV1 {
+        migrate::E1000StateV1 {
+            ctrl: self.ctrl,
+            icr: self.icr,
+            ims: self.ims,
+            rctl: self.rctl,
+            tctl: self.tctl,
+            mdic: self.mdic,
+            eerd: self.eerd,
+            eecd: self.eecd.eecd,
+            eecd_val_in: self.eecd.val_in,
+            eecd_bitnum_in: self.eecd.bitnum_in,
+            eecd_bitnum_out: self.eecd.bitnum_out,
+            eecd_reading: self.eecd.reading,
+            ioaddr: self.ioaddr,
+            rx_base: self.rx.base,
+            rx_len: self.rx.len,
+            rx_head: self.rx.head,
+            rx_tail: self.rx.tail,
+            tx_base: self.tx.base,
+            tx_len: self.tx.len,
+            tx_head: self.tx.head,
+            tx_tail: self.tx.tail,
+            tx_state: self.tx_state.export(),
+            mta: self.mta.to_vec(),
+            ra: self.ra.to_vec(),
+            vfta: self.vfta.to_vec(),
+            phy: self.phy.to_vec(),
+            regs: self.plain.clone(),
+            stats: self.stats.to_vec(),
+        }
+    }
+
+    fn import(
+        &mut self,
+        input: migrate::E1000StateV1,
+    ) -> Result<(), MigrateStateError> {
+        fn table<const N: usize, T>(
+            name: &str,
+            vals: Vec<T>,
+        ) -> Result<[T; N], MigrateStateError> {
+            vals.try_into().map_err(|v: Vec<T>| {
+                MigrateStateError::ImportFailed(format!(
+                    "e1000: {name} has {} entries, rather than {N}",
+                    v.len()
+                ))
+            })
+        }
+
+        self.ctrl = input.ctrl;
+        self.icr = input.icr;
+        self.ims = input.ims;
+        self.rctl = input.rctl;
+        self.tctl = input.tctl;
+        self.mdic = input.mdic;
+        self.eerd = input.eerd;
+        self.eecd = Microwire {
+            eecd: input.eecd,
+            val_in: input.eecd_val_in,
+            bitnum_in: input.eecd_bitnum_in,
+            bitnum_out: input.eecd_bitnum_out,
+            reading: input.eecd_reading,
+        };
+        self.ioaddr = input.ioaddr;
+        self.rx = Ring {
+            base: input.rx_base,
+            len: input.rx_len,
+            head: input.rx_head,
+            tail: input.rx_tail,
+        };
+        self.tx = Ring {
+            base: input.tx_base,
+            len: input.tx_len,
+            head: input.tx_head,
+            tail: input.tx_tail,
+        };
+        self.tx_state.import(input.tx_state);
+        self.mta = table("MTA", input.mta)?;
+        self.ra = table("RA", input.ra)?;
+        self.vfta = table("VFTA", input.vfta)?;
+        self.phy = table("PHY registers", input.phy)?;
+        self.stats = table("statistics", input.stats)?;
+        for (reg, _) in PLAIN_REGS {
+            let val = input.regs.get(reg).copied().ok_or_else(|| {
+                MigrateStateError::ImportFailed(format!(
+                    "e1000: register {reg:#x} missing"
+                ))
+            })?;
+            self.plain.insert(*reg, val);
+        }
+        self.sync_intr();
+        Ok(())
+    }
+}
+
+pub struct PciE1000 {
+    state: Mutex<E1000State>,
+    pci_state: pci::DeviceState,
+
+    mac_addr: [u8; ETHERADDRL],
+    eeprom: [u16; EEPROM_WORDS],
+    backend: Arc<dyn net::Backend>,
+
+    /// Receives frames from the backend while the device is running
+    receiver: Mutex<Option<net::Receiver>>,
+    this: Weak<Self>,
+}
+
+impl PciE1000 {
+    /// Create an e1000 NIC which presents `mac_addr` to the guest and carries
+    /// its traffic over `backend`.
+    pub fn new(
+        mac_addr: [u8; ETHERADDRL],
+        backend: Arc<dyn net::Backend>,
+    ) -> Arc<Self> {
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_INTEL,
+            device_id: E1000_DEV_ID,
+            sub_vendor_id: VENDOR_OXIDE,
+            sub_device_id: E1000_SUB_DEV_ID,
+            class: pci::bits::CLASS_NETWORK,
+            // Ethernet controller
+            subclass: 0,
+            ..Default::default()
+        })
+        .add_bar_mmio(pci::BarN::BAR0, MMIO_SIZE as u32)
+        .add_bar_io(pci::BarN::BAR1, IO_SIZE)
+        .add_lintr()
+        .finish();
+
+        let mut eeprom = EEPROM_TEMPLATE;
+        for (i, pair) in mac_addr.chunks(2).enumerate() {
+            eeprom[i] = u16::from_le_bytes([pair[0], pair[1]]);
+        }
+        eeprom[EEPROM_SUB_DEV_ID] = E1000_SUB_DEV_ID;
+        eeprom[EEPROM_SUB_VENDOR_ID] = VENDOR_OXIDE;
+        eeprom[EEPROM_DEV_ID] = E1000_DEV_ID;
+        eeprom[EEPROM_VENDOR_ID] = VENDOR_INTEL;
+        let sum = eeprom[..EEPROM_CHECKSUM]
+            .iter()
+            .fold(0u16, |acc, w| acc.wrapping_add(*w));
+        eeprom[EEPROM_CHECKSUM] = EEPROM_SUM.wrapping_sub(sum);
+
+        Arc::new_cyclic(|this| Self {
+            state: Mutex::new(E1000State::new(&mac_addr)),
+            pci_state,
+            mac_addr,
+            eeprom,
+            backend,
+            receiver: Mutex::new(None),
+            this: this.clone(),
+        })
+    }
+
+    fn reg_read(&self, reg: u32) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        match reg {
+            REG_CTRL => state.ctrl,
+            REG_STATUS => {
+                STATUS_FD | STATUS_LU | STATUS_SPEED_1000 | STATUS_ASDV_1000
+            }
+            REG_EECD => state.eecd_read(&self.eeprom),
+            REG_EERD => state.eerd_read(&self.eeprom),
+            REG_MDIC => state.mdic,
+            REG_ICR => {
+                // Reading ICR acknowledges all pending interrupts
+                let icr = state.icr;
+                state.icr = 0;
+                state.sync_intr();
+                icr
+            }
+            REG_IMS => state.ims,
+            REG_RCTL => state.rctl,
+            REG_TCTL => state.tctl,
+            REG_RDBAL => state.rx.base as u32,
+            REG_RDBAH => (state.rx.base >> 32) as u32,
+            REG_RDLEN => state.rx.len,
+            REG_RDH => state.rx.head,
+            REG_RDT => state.rx.tail,
+            REG_TDBAL => state.tx.base as u32,
+            REG_TDBAH => (state.tx.base >> 32) as u32,
+            REG_TDLEN => state.tx.len,
+            REG_TDH => state.tx.head,
+            REG_TDT => state.tx.tail,
+            _ if is_stat(reg) => {
+                // Statistics are cleared when read
+                let idx = ((reg - REG_STATS_START) / 4) as usize;
+                std::mem::take(&mut state.stats[idx])
+            }
+            _ => {
+                if let Some(val) = table_entry(&mut state, reg) {
+                    *val
+                } else {
+                    state.plain.get(&reg).copied().unwrap_or(0)
+                }
+            }
+        }
+    }
+
+    fn reg_write(&self, reg: u32, val: u32) {
+        let mut state = self.state.lock().unwrap();
+        match reg {
+            REG_CTRL => {
+                if val & CTRL_RST != 0 {
+                    state.reset(&self.mac_addr);
+                    return;
+                }
+                let old = state.ctrl;
+                state.ctrl = val & !(CTRL_RST | CTRL_PHY_RST);
+                if (state.ctrl & !old) & CTRL_SLU != 0 {
+                    state.raise(INTR_LSC);
+                }
+            }
+            REG_EECD => state.eecd_write(val),
+            REG_EERD => state.eerd = val,
+            REG_MDIC => state.mdic_write(val),
+            REG_ICR => {
+                state.icr &= !val;
+                state.sync_intr();
+            }
+            REG_ICS => state.raise(val),
+            REG_IMS => {
+                state.ims |= val & INTR_MASK;
+                state.sync_intr();
+            }
+            REG_IMC => {
+                state.ims &= !val;
+                state.sync_intr();
+            }
+            REG_RCTL => state.rctl = val,
+            REG_TCTL => {
+                state.tctl = val;
+                self.process_tx(&mut state);
+            }
+            REG_RDBAL => {
+                state.rx.base =
+                    (state.rx.base & !0xffff_ffff) | u64::from(val & !0xf);
+            }
+            REG_RDBAH => {
+                state.rx.base =
+                    (state.rx.base & 0xffff_ffff) | (u64::from(val) << 32);
+            }
+            REG_RDLEN => state.rx.len = val & 0xf_ff80,
+            REG_RDH => state.rx.head = val & 0xffff,
+            REG_RDT => state.rx.tail = val & 0xffff,
+            REG_TDBAL => {
+                state.tx.base =
+                    (state.tx.base & !0xffff_ffff) | u64::from(val & !0xf);
+            }
+            REG_TDBAH => {
+                state.tx.base =
+                    (state.tx.base & 0xffff_ffff) | (u64::from(val) << 32);
+            }
+            REG_TDLEN => state.tx.len = val & 0xf_ff80,
+            REG_TDH => state.tx.head = val & 0xffff,
+            REG_TDT => {
+                state.tx.tail = val & 0xffff;
+                self.process_tx(&mut state);
+            }
+            _ if is_stat(reg) => {
+                // Read-only
+            }
+            _ => {
+                if let Some(entry) = table_entry(&mut state, reg) {
+                    *entry = val;
+                } else if let Some(entry) = state.plain.get_mut(&reg) {
+                    *entry = val;
+                }
+            }
+        }
+    }
+
+    /// Process the descriptors made available in the transmit ring.
+    fn process_tx(&self, state: &mut E1000State) {
+        if state.tctl & TCTL_EN == 0 || !state.tx.valid() {
+            return;
+        }
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+
+        let vlan_enabled = state.ctrl & CTRL_VME != 0;
+        let vet = state.vet();
+        let mut cause = INTR_TXQE;
+        while state.tx.head != state.tx.tail {
+            let addr = state.tx.desc_addr(state.tx.head);
+            let Some(desc) = mem.read::<TxDesc>(addr) else {
+                break;
+            };
+
+            let (mut frames, mut bytes) = (0, 0);
+            state.tx_state.process(
+                &desc,
+                &mem,
+                vlan_enabled,
+                vet,
+                &mut |frame: &[u8]| match self.backend.send(frame) {
+                    Ok(()) => {
+                        probes::e1000_tx!(|| frame.len() as u64);
+                        frames += 1;
+                        // The FCS is counted, as if it had been appended
+                        bytes += frame.len() as u64 + 4;
+                    }
+                    Err(_) => {
+                        probes::e1000_tx_drop!(|| frame.len() as u64);
+                    }
+                },
+            );
+            state.stat_add(STAT_GPTC, frames);
+            state.stat_add(STAT_TPT, frames);
+            state.stat_add64(STAT_GOTCL, bytes);
+            state.stat_add64(STAT_TOTL, bytes);
+
+            if desc.lower & (TXD_CMD_RS | TXD_CMD_RPS) != 0 {
+                // Write back only the status, in the upper dword
+                let upper = desc.upper | TXD_STAT_DD;
+                mem.write(GuestAddr(addr.0 + 12), &upper);
+                cause |= INTR_TXDW;
+            }
+            state.tx.advance();
+        }
+        state.raise(cause);
+    }
+
+    fn process_rx(&self, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if state.rctl & RCTL_EN == 0
+            || !state.rx.valid()
+            || !state.rx_accept(frame)
+        {
+            probes::e1000_rx_drop!(|| frame.len() as u64);
+            return;
+        }
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+
+        let mut data = Vec::with_capacity(frame.len() + 4);
+        let mut vlan = None;
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        if state.ctrl & CTRL_VME != 0
+            && ethertype == state.vet()
+            && frame.len() >= 18
+        {
+            vlan = Some(u16::from_be_bytes([frame[14], frame[15]]));
+            data.extend_from_slice(&frame[..12]);
+            data.extend_from_slice(&frame[16..]);
+        } else {
+            data.extend_from_slice(frame);
+        }
+        if data.len() < MIN_FRAME_LEN {
+            data.resize(MIN_FRAME_LEN, 0);
+        }
+        if state.rctl & RCTL_SECRC == 0 {
+            // Drivers strip the FCS without checking it, so its value is of
+            // no consequence.
+            data.extend_from_slice(&[0; 4]);
+        }
+
+        let buf_size = state.rx_buf_size();
+        let needed = data.len().div_ceil(buf_size);
+        if needed > state.rx.pending() as usize {
+            probes::e1000_rx_drop!(|| frame.len() as u64);
+            state.stat_add(STAT_MPC, 1);
+            state.raise(INTR_RXO);
+            return;
+        }
+
+        let nchunks = needed;
+        for (idx, chunk) in data.chunks(buf_size).enumerate() {
+            let addr = state.rx.desc_addr(state.rx.head);
+            let Some(buf) = mem.read::<u64>(addr) else {
+                break;
+            };
+            mem.write_from(GuestAddr(buf), chunk, chunk.len());
+
+            let mut status = RXD_STAT_DD | RXD_STAT_IXSM;
+            if idx + 1 == nchunks {
+                status |= RXD_STAT_EOP;
+            }
+            if vlan.is_some() {
+                status |= RXD_STAT_VP;
+            }
+            let desc = RxDesc {
+                addr: buf,
+                length: chunk.len() as u16,
+                csum: 0,
+                status,
+                errors: 0,
+                special: vlan.unwrap_or(0),
+            };
+            mem.write(addr, &desc);
+            state.rx.advance();
+        }
+        probes::e1000_rx!(|| frame.len() as u64);
+
+        let len = data.len() as u64;
+        state.stat_add(STAT_GPRC, 1);
+        state.stat_add(STAT_TPR, 1);
+        state.stat_add64(STAT_GORCL, len);
+        state.stat_add64(STAT_TORL, len);
+        if frame[..ETHERADDRL] == [0xff; ETHERADDRL] {
+            state.stat_add(STAT_BPRC, 1);
+        } else if frame[0] & 1 != 0 {
+            state.stat_add(STAT_MPRC, 1);
+        }
+
+        let mut cause = INTR_RXT0;
+        let rdmts = (state.rctl >> RCTL_RDMTS_SHIFT) & 0b11;
+        if state.rx.pending() < state.rx.count() >> (rdmts + 1) {
+            cause |= INTR_RXDMT0;
+        }
+        state.raise(cause);
+    }
+
+    fn rx_start(&self) {
+        let mut receiver = self.receiver.lock().unwrap();
+        if receiver.is_none() {
+            *receiver = Some(net::Receiver::spawn(
+                "e1000 rx",
+                self.backend.clone(),
+                self.this.clone() as Weak<dyn net::Receive>,
+            ));
+        }
+    }
+
+    fn rx_stop(&self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.stop();
+        }
+    }
+
+    fn mmio_rw(&self, rwo: RWOp) {
+        let off = rwo.offset();
+        let reg = (off & !0b11) as u32;
+        match rwo {
+            RWOp::Read(ro) => {
+                let val = self.reg_read(reg).to_le_bytes();
+                let start = off & 0b11;
+                let end = usize::min(start + ro.len(), val.len());
+                ro.write_bytes(&val[start..end]);
+                ro.fill(0);
+            }
+            RWOp::Write(wo) => {
+                // Drivers access the registers only as whole dwords
+                if off & 0b11 == 0 && wo.len() == 4 {
+                    self.reg_write(reg, wo.read_u32());
+                }
+            }
+        }
+    }
+
+    fn io_rw(&self, rwo: RWOp) {
+        match (rwo.offset(), rwo) {
+            (IOADDR, RWOp::Read(ro)) => {
+                ro.write_u32(self.state.lock().unwrap().ioaddr);
+            }
+            (IOADDR, RWOp::Write(wo)) => {
+                self.state.lock().unwrap().ioaddr = wo.read_u32();
+            }
+            (IODATA, RWOp::Read(ro)) => {
+                let reg = self.state.lock().unwrap().ioaddr;
+                ro.write_u32(self.reg_read(reg & !0b11));
+            }
+            (IODATA, RWOp::Write(wo)) => {
+                let reg = self.state.lock().unwrap().ioaddr;
+                self.reg_write(reg & !0b11, wo.read_u32());
+            }
+            (_, RWOp::Read(ro)) => ro.fill(0),
+            (_, RWOp::Write(_)) => {}
+        }
+    }
+}
+
+fn is_stat(reg: u32) -> bool {
+    (REG_STATS_START..REG_STATS_END).contains(&reg)
+}
+
+/// Locate the entry for `reg` in one of the register tables (MTA, RA, VFTA)
+fn table_entry(state: &mut E1000State, reg: u32) -> Option<&mut u32> {
+    let (table, base): (&mut [u32], u32) = match reg {
+        _ if (REG_MTA..REG_RA).contains(&reg) => (&mut state.mta[..], REG_MTA),
+        _ if (REG_RA..REG_VFTA).contains(&reg) => (&mut state.ra[..], REG_RA),
+        _ if reg >= REG_VFTA => (&mut state.vfta[..], REG_VFTA),
+        _ => return None,
+    };
+    table.get_mut(((reg - base) / 4) as usize)
+}
+
+impl net::Receive for PciE1000 {
+    fn receive(&self, frame: &[u8]) {
+        self.process_rx(frame);
+    }
+}
+
+impl pci::Device for PciE1000 {
+    fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp) {
+        match bar {
+            pci::BarN::BAR0 => self.mmio_rw(rwo),
+            pci::BarN::BAR1 => {
+                if rwo.len() == 4 {
+                    self.io_rw(rwo);
+                } else if let RWOp::Read(ro) = &mut rwo {
+                    ro.fill(0);
+                }
+            }
+            _ => panic!("unexpected BAR {bar:?}"),
+        }
+    }
+
+    fn attach(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pin = self.pci_state.lintr_pin();
+    }
+
+    fn interrupt_mode_change(&self, mode: pci::IntrMode) {
+        let mut state = self.state.lock().unwrap();
+        state.pin_enabled = mode == pci::IntrMode::INTxPin;
+        state.sync_intr();
+    }
+
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl Lifecycle for PciE1000 {
+    fn type_name(&self) -> &'static str {
+        "pci-e1000"
+    }
+    fn reset(&self) {
+        self.state.lock().unwrap().reset(&self.mac_addr);
+        self.pci_state.reset(self);
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.rx_start();
+        Ok(())
+    }
+    fn pause(&self) {
+        // Nothing may be written into guest memory while paused
+        self.rx_stop();
+    }
+    fn resume(&self) {
+        self.rx_start();
+    }
+    fn halt(&self) {
+        self.rx_stop();
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl MigrateMulti for PciE1000 {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        output.push(self.state.lock().unwrap().export().into())?;
+        MigrateMulti::export(&self.pci_state, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::E1000StateV1 = offer.take()?;
+        self.state.lock().unwrap().import(input)?;
+        MigrateMulti::import(&self.pci_state, offer, ctx)
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::E1000StateV1>();
+        MigrateMulti::schemas(&self.pci_state, schemas)
+    }
+}
+
+pub mod migrate {
+    use std::collections::BTreeMap;
+
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct E1000StateV1 {
+        pub ctrl: u32,
+        pub icr: u32,
+        pub ims: u32,
+        pub rctl: u32,
+        pub tctl: u32,
+        pub mdic: u32,
+        pub eerd: u32,
+
+        pub eecd: u32,
+        pub eecd_val_in: u16,
+        pub eecd_bitnum_in: u16,
+        pub eecd_bitnum_out: u16,
+        pub eecd_reading: bool,
+
+        pub ioaddr: u32,
+
+        pub rx_base: u64,
+        pub rx_len: u32,
+        pub rx_head: u32,
+        pub rx_tail: u32,
+        pub tx_base: u64,
+        pub tx_len: u32,
+        pub tx_head: u32,
+        pub tx_tail: u32,
+        pub tx_state: TxStateV1,
+
+        pub mta: Vec<u32>,
+        pub ra: Vec<u32>,
+        pub vfta: Vec<u32>,
+        pub phy: Vec<u16>,
+        /// Registers with no bearing on emulation, keyed by offset
+        pub regs: BTreeMap<u32, u32>,
+        pub stats: Vec<u32>,
+    }
+    impl Schema<'_> for E1000StateV1 {
+        fn id() -> SchemaId {
+            ("e1000", 1)
+        }
+    }
+
+    /// The offload context, along with any packet whose assembly from
+    /// multiple descriptors is incomplete
+    #[derive(Default, Deserialize, Serialize)]
+    pub struct TxStateV1 {
+        pub ipcss: u8,
+        pub ipcso: u8,
+        pub ipcse: u16,
+        pub tucss: u8,
+        pub tucso: u8,
+        pub tucse: u16,
+        pub tcp: bool,
+        pub ipv4: bool,
+        pub tse: bool,
+        pub hdr_len: u8,
+        pub mss: u16,
+
+        pub pkt_data: Vec<u8>,
+        pub pkt_popts: u8,
+        pub pkt_tse: bool,
+        pub pkt_legacy_sum: Option<(u8, u8)>,
+        pub pkt_vlan: Option<u16>,
+        pub pkt_started: bool,
+        pub pkt_invalid: bool,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::net::DgramBackend;
+    use crate::vmm::Machine;
+    use std::os::unix::net::UnixDatagram;
+
+    const MAC: [u8; ETHERADDRL] = [0x02, 0x08, 0x20, 0, 3, 0];
+
+    /// Rings for RX and TX, each of 8 descriptors
+    const RX_RING: u64 = 0x10_0000;
+    const TX_RING: u64 = 0x10_1000;
+    const RING_LEN: u32 = 8 * DESC_SIZE;
+    const BUF_BASE: u64 = 0x10_8000;
+
+    fn setup() -> (Machine, Arc<PciE1000>, UnixDatagram) {
+        let machine = Machine::new_test().unwrap();
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        let dev = PciE1000::new(MAC, Arc::new(DgramBackend::from_socket(ours)));
+        machine.acc_mem.adopt(&dev.pci_state.acc_mem, None);
+        (machine, dev, theirs)
+    }
+
+    /// Read a word from the EEPROM by bit-banging EECD, as older drivers do
+    fn eeprom_read_microwire(dev: &PciE1000, addr: u8) -> u16 {
+        let cmd = (EEPROM_READ_OPCODE << 6) | u32::from(addr);
+        dev.reg_write(REG_EECD, EECD_CS);
+        for i in (0..9).rev() {
+            let di = if (cmd >> i) & 1 != 0 { EECD_DI } else { 0 };
+            dev.reg_write(REG_EECD, EECD_CS | di);
+            dev.reg_write(REG_EECD, EECD_CS | di | EECD_SK);
+            dev.reg_write(REG_EECD, EECD_CS | di);
+        }
+        let mut val = 0;
+        for _ in 0..16 {
+            dev.reg_write(REG_EECD, EECD_CS | EECD_SK);
+            let bit = dev.reg_read(REG_EECD) & EECD_DO != 0;
+            val = (val << 1) | u16::from(bit);
+            dev.reg_write(REG_EECD, EECD_CS);
+        }
+        dev.reg_write(REG_EECD, 0);
+        val
+    }
+
+    #[test]
+    fn eeprom() {
+        let (_machine, dev, _peer) = setup();
+
+        let words: Vec<u16> = (0..EEPROM_WORDS)
+            .map(|addr| {
+                let addr = addr as u32;
+                dev.reg_write(REG_EERD, (addr << EERD_ADDR_SHIFT) | EERD_START);
+                let eerd = dev.reg_read(REG_EERD);
+                assert_ne!(eerd & EERD_DONE, 0);
+                (eerd >> EERD_DATA_SHIFT) as u16
+            })
+            .collect();
+        let sum = words.iter().fold(0u16, |acc, w| acc.wrapping_add(*w));
+        assert_eq!(sum, EEPROM_SUM);
+        assert_eq!(&words[..3], &[0x0802, 0x0020, 0x0003]);
+
+        for addr in [0, 1, 2, EEPROM_DEV_ID, EEPROM_CHECKSUM] {
+            assert_eq!(eeprom_read_microwire(&dev, addr as u8), words[addr]);
+        }
+    }
+
+    #[test]
+    fn phy_access() {
+        let (_machine, dev, _peer) = setup();
+
+        let read = |phy: u32, reg: usize| {
+            dev.reg_write(
+                REG_MDIC,
+                MDIC_OP_READ
+                    | (phy << MDIC_PHY_SHIFT)
+                    | ((reg as u32) << MDIC_REG_SHIFT),
+            );
+            dev.reg_read(REG_MDIC)
+        };
+
+        let mdic = read(PHY_ADDR, PHY_ID1);
+        assert_ne!(mdic & MDIC_READY, 0);
+        assert_eq!(mdic & MDIC_ERROR, 0);
+        assert_eq!(mdic & MDIC_DATA_MASK, 0x0141);
+
+        // There is nothing else on the bus
+        assert_ne!(read(2, PHY_ID1) & MDIC_ERROR, 0);
+
+        // A requested reset completes immediately
+        dev.reg_write(
+            REG_MDIC,
+            MDIC_OP_WRITE
+                | (PHY_ADDR << MDIC_PHY_SHIFT)
+                | ((PHY_CTRL as u32) << MDIC_REG_SHIFT)
+                | u32::from(PHY_CTRL_RESET | 0x1140),
+        );
+        assert_eq!(read(PHY_ADDR, PHY_CTRL) & MDIC_DATA_MASK, 0x1140);
+    }
+
+    #[test]
+    fn transmit() {
+        let (machine, dev, peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let frame: Vec<u8> = (0..64).collect();
+        mem.write_from(GuestAddr(BUF_BASE), &frame, frame.len());
+        let desc = TxDesc {
+            addr: BUF_BASE,
+            lower: TXD_CMD_EOP | TXD_CMD_RS | frame.len() as u32,
+            upper: 0,
+        };
+        mem.write(GuestAddr(TX_RING), &desc);
+
+        dev.reg_write(REG_IMS, INTR_TXDW);
+        dev.reg_write(REG_TDBAL, TX_RING as u32);
+        dev.reg_write(REG_TDLEN, RING_LEN);
+        dev.reg_write(REG_TDT, 1);
+        // Nothing is sent until transmit is enabled
+        assert_eq!(dev.reg_read(REG_TDH), 0);
+        dev.reg_write(REG_TCTL, TCTL_EN);
+        assert_eq!(dev.reg_read(REG_TDH), 1);
+
+        let mut buf = [0u8; 128];
+        assert_eq!(peer.recv(&mut buf).unwrap(), frame.len());
+        assert_eq!(&buf[..frame.len()], &frame[..]);
+
+        let desc: TxDesc = mem.read(GuestAddr(TX_RING)).unwrap();
+        assert_ne!(desc.upper & TXD_STAT_DD, 0);
+        assert_ne!(dev.reg_read(REG_ICR) & INTR_TXDW, 0);
+        assert_eq!(dev.reg_read(REG_ICR), 0);
+        assert_eq!(dev.reg_read(STAT_GPTC), 1);
+        assert_eq!(dev.reg_read(STAT_GPTC), 0);
+    }
+
+    #[test]
+    fn receive() {
+        let (machine, dev, _peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        for i in 0..8 {
+            let desc =
+                RxDesc { addr: BUF_BASE + i * 2048, ..Default::default() };
+            mem.write(GuestAddr(RX_RING + i * u64::from(DESC_SIZE)), &desc);
+        }
+        dev.reg_write(REG_RDBAL, RX_RING as u32);
+        dev.reg_write(REG_RDLEN, RING_LEN);
+        dev.reg_write(REG_RDT, 7);
+
+        let mut frame = vec![0u8; 100];
+        frame[..ETHERADDRL].copy_from_slice(&MAC);
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+
+        // Frames are dropped until receive is enabled
+        dev.process_rx(&frame);
+        assert_eq!(dev.reg_read(REG_RDH), 0);
+        dev.reg_write(REG_RCTL, RCTL_EN | RCTL_SECRC);
+
+        // Frames for another (unicast) address are filtered out
+        let mut other = frame.clone();
+        other[5] = 0xff;
+        dev.process_rx(&other);
+        assert_eq!(dev.reg_read(REG_RDH), 0);
+
+        dev.process_rx(&frame);
+        assert_eq!(dev.reg_read(REG_RDH), 1);
+        let desc: RxDesc = mem.read(GuestAddr(RX_RING)).unwrap();
+        assert_eq!(desc.length, 100);
+        assert_eq!(
+            desc.status & (RXD_STAT_DD | RXD_STAT_EOP),
+            RXD_STAT_DD | RXD_STAT_EOP
+        );
+        let mut buf = vec![0u8; 100];
+        mem.read_into(GuestAddr(BUF_BASE), &mut buf, buf.len());
+        assert_eq!(buf, frame);
+        assert_ne!(dev.reg_read(REG_ICR) & INTR_RXT0, 0);
+
+        // Broadcast frames are accepted only when BAM is set, and padded out
+        // to the minimum length
+        let mut bcast = vec![0xffu8; ETHERADDRL];
+        bcast.extend_from_slice(&[0u8; 8]);
+        dev.process_rx(&bcast);
+        assert_eq!(dev.reg_read(REG_RDH), 1);
+        dev.reg_write(REG_RCTL, RCTL_EN | RCTL_SECRC | RCTL_BAM);
+        dev.process_rx(&bcast);
+        assert_eq!(dev.reg_read(REG_RDH), 2);
+        let desc: RxDesc =
+            mem.read(GuestAddr(RX_RING + u64::from(DESC_SIZE))).unwrap();
+        assert_eq!(usize::from(desc.length), MIN_FRAME_LEN);
+
+        // With the ring full, frames are counted as missed
+        for _ in 0..5 {
+            dev.process_rx(&frame);
+        }
+        assert_eq!(dev.reg_read(REG_RDH), 7);
+        dev.process_rx(&frame);
+        assert_eq!(dev.reg_read(REG_RDH), 7);
+        assert_eq!(dev.reg_read(STAT_MPC), 1);
+        assert_ne!(dev.reg_read(REG_ICR) & INTR_RXO, 0);
+    }
+}
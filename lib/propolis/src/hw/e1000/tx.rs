@@ -0,0 +1,586 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Assembly of frames from transmit descriptors, including the checksum and
+//! TCP segmentation offloads which guest drivers expect of the hardware.
+
+use crate::common::GuestAddr;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::migrate;
+
+/// Largest packet (prior to any segmentation) which will be assembled from
+/// transmit descriptors.  Anything larger is dropped.
+const MAX_TX_PACKET: usize = 256 * 1024;
+
+// Flags in byte 13 of a TCP header
+const TCP_FLAG_FIN: u8 = 1 << 0;
+const TCP_FLAG_PSH: u8 = 1 << 3;
+
+/// A transmit descriptor, in any of its legacy, context, and data forms
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub(super) struct TxDesc {
+    /// Buffer address, or for a context descriptor, the checksum offsets
+    pub addr: u64,
+    /// Length, type, and command bits
+    pub lower: u32,
+    /// Status, along with checksum or segmentation options
+    pub upper: u32,
+}
+impl TxDesc {
+    fn is_context(&self) -> bool {
+        self.lower & (TXD_CMD_DEXT | TXD_DTYP_MASK) == TXD_CMD_DEXT | TXD_DTYP_C
+    }
+    fn is_data(&self) -> bool {
+        self.lower & (TXD_CMD_DEXT | TXD_DTYP_MASK) == TXD_CMD_DEXT | TXD_DTYP_D
+    }
+    fn is_legacy(&self) -> bool {
+        self.lower & TXD_CMD_DEXT == 0
+    }
+}
+
+/// Offload parameters, as loaded from the most recent context descriptor
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct TxContext {
+    pub ipcss: u8,
+    pub ipcso: u8,
+    pub ipcse: u16,
+    pub tucss: u8,
+    pub tucso: u8,
+    pub tucse: u16,
+    /// Is the packet TCP (rather than UDP)?
+    pub tcp: bool,
+    /// Is the packet IPv4 (rather than IPv6)?
+    pub ipv4: bool,
+    /// Is TCP segmentation enabled?
+    pub tse: bool,
+    pub hdr_len: u8,
+    pub mss: u16,
+}
+impl TxContext {
+    fn from_desc(desc: &TxDesc) -> Self {
+        let offs = desc.addr.to_le_bytes();
+        let opts = desc.upper.to_le_bytes();
+        Self {
+            ipcss: offs[0],
+            ipcso: offs[1],
+            ipcse: u16::from_le_bytes([offs[2], offs[3]]),
+            tucss: offs[4],
+            tucso: offs[5],
+            tucse: u16::from_le_bytes([offs[6], offs[7]]),
+            tcp: desc.lower & TXD_CMD_TCP != 0,
+            ipv4: desc.lower & TXD_CMD_IP != 0,
+            tse: desc.lower & TXD_CMD_TSE != 0,
+            hdr_len: opts[1],
+            mss: u16::from_le_bytes([opts[2], opts[3]]),
+        }
+    }
+}
+
+/// A packet being assembled from the buffers of one or more descriptors
+#[derive(Default)]
+struct TxPacket {
+    data: Vec<u8>,
+    /// Checksums to be inserted (`TXD_POPTS_*`), per the first data descriptor
+    popts: u8,
+    /// Is the packet to be split into segments per the context?
+    tse: bool,
+    /// Checksum start and offset of a legacy descriptor requesting one
+    legacy_sum: Option<(u8, u8)>,
+    /// VLAN tag to be inserted
+    vlan: Option<u16>,
+    /// Has a descriptor for this packet already been processed?
+    started: bool,
+    /// Was the packet too large, or its buffers inaccessible?
+    invalid: bool,
+}
+
+#[derive(Default)]
+pub(super) struct TxState {
+    pub ctx: TxContext,
+    pkt: TxPacket,
+}
+impl TxState {
+    /// Process a single descriptor, reading any buffer it refers to through
+    /// `mem`.  Frames completed by the descriptor are passed to `send`.
+    ///
+    /// VLAN tags (of ethertype `vet`) are inserted where requested only if
+    /// `vlan_enabled`, per CTRL.VME.
+    pub fn process(
+        &mut self,
+        desc: &TxDesc,
+        mem: &MemCtx,
+        vlan_enabled: bool,
+        vet: u16,
+        send: &mut dyn FnMut(&[u8]),
+    ) {
+        if desc.is_context() {
+            self.ctx = TxContext::from_desc(desc);
+            return;
+        }
+
+        let pkt = &mut self.pkt;
+        let len = if desc.is_data() {
+            if !pkt.started {
+                pkt.popts = desc.upper.to_le_bytes()[1];
+                pkt.tse = desc.lower & TXD_CMD_TSE != 0;
+            }
+            (desc.lower & 0xf_ffff) as usize
+        } else if desc.is_legacy() {
+            if !pkt.started && desc.lower & TXD_CMD_IC != 0 {
+                let [_status, css, ..] = desc.upper.to_le_bytes();
+                let cso = desc.lower.to_le_bytes()[2];
+                pkt.legacy_sum = Some((css, cso));
+            }
+            (desc.lower & 0xffff) as usize
+        } else {
+            // Descriptors of a reserved type are skipped
+            return;
+        };
+        if !pkt.started && vlan_enabled && desc.lower & TXD_CMD_VLE != 0 {
+            pkt.vlan = Some((desc.upper >> 16) as u16);
+        }
+        pkt.started = true;
+
+        let start = pkt.data.len();
+        if pkt.invalid || start + len > MAX_TX_PACKET {
+            pkt.invalid = true;
+        } else if len != 0 {
+            pkt.data.resize(start + len, 0);
+            let copied = mem.read_into(
+                GuestAddr(desc.addr),
+                &mut pkt.data[start..],
+                len,
+            );
+            if copied != Some(len) {
+                pkt.invalid = true;
+            }
+        }
+
+        if desc.lower & TXD_CMD_EOP != 0 {
+            let pkt = std::mem::take(&mut self.pkt);
+            if !pkt.invalid {
+                self.emit(pkt, vet, send);
+            }
+        }
+    }
+
+    /// Send the frame(s) making up a fully-assembled packet.
+    fn emit(&self, pkt: TxPacket, vet: u16, send: &mut dyn FnMut(&[u8])) {
+        let mut send_frame = |mut frame: Vec<u8>| {
+            if let Some((css, cso)) = pkt.legacy_sum {
+                put_checksum(&mut frame, cso.into(), css.into(), 0);
+            }
+            self.insert_checksums(&mut frame, pkt.popts);
+            if let Some(tag) = pkt.vlan {
+                insert_vlan_tag(&mut frame, vet, tag);
+            }
+            send(&frame);
+        };
+
+        let ctx = &self.ctx;
+        if !(pkt.tse && ctx.tse) {
+            send_frame(pkt.data);
+            return;
+        }
+
+        let hdr_len = usize::from(ctx.hdr_len);
+        let mss = usize::from(ctx.mss);
+        if hdr_len == 0 || mss == 0 || pkt.data.len() <= hdr_len {
+            // There is nothing sensible to be done with such a request
+            return;
+        }
+        let (hdr, payload) = pkt.data.split_at(hdr_len);
+        let nsegs = payload.len().div_ceil(mss);
+        for (idx, chunk) in payload.chunks(mss).enumerate() {
+            let mut frame = Vec::with_capacity(hdr_len + chunk.len());
+            frame.extend_from_slice(hdr);
+            frame.extend_from_slice(chunk);
+            self.fixup_segment(&mut frame, idx, idx + 1 == nsegs, pkt.popts);
+            send_frame(frame);
+        }
+    }
+
+    /// Adjust the headers (copied from the original packet) of segment `idx`
+    /// to reflect its length and position in the stream.
+    fn fixup_segment(
+        &self,
+        frame: &mut [u8],
+        idx: usize,
+        last: bool,
+        popts: u8,
+    ) {
+        let ctx = &self.ctx;
+        let len = frame.len();
+
+        let ipcss = usize::from(ctx.ipcss);
+        if ctx.ipv4 {
+            put_be16(frame, ipcss + 2, len.saturating_sub(ipcss) as u16);
+            if let Some(id) = get_be16(frame, ipcss + 4) {
+                put_be16(frame, ipcss + 4, id.wrapping_add(idx as u16));
+            }
+        } else {
+            // The IPv6 payload length excludes its 40-byte fixed header
+            let plen = len.saturating_sub(ipcss + 40);
+            put_be16(frame, ipcss + 4, plen as u16);
+        }
+
+        let tucss = usize::from(ctx.tucss);
+        let tlen = len.saturating_sub(tucss);
+        if ctx.tcp {
+            let offset = idx * usize::from(ctx.mss);
+            if let Some(seq) = get_be32(frame, tucss + 4) {
+                put_be32(frame, tucss + 4, seq.wrapping_add(offset as u32));
+            }
+            if !last {
+                if let Some(flags) = frame.get_mut(tucss + 13) {
+                    *flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+                }
+            }
+        } else {
+            put_be16(frame, tucss + 4, tlen as u16);
+        }
+
+        // The driver seeds the checksum field with a pseudo-header sum which
+        // excludes the length, since that varies from segment to segment.
+        if popts & TXD_POPTS_TXSM != 0 {
+            let sloc = usize::from(ctx.tucso);
+            if let Some(phsum) = get_be16(frame, sloc) {
+                let sum = u32::from(phsum) + tlen as u32;
+                put_be16(frame, sloc, ((sum >> 16) + (sum & 0xffff)) as u16);
+            }
+        }
+    }
+
+    fn insert_checksums(&self, frame: &mut [u8], popts: u8) {
+        let ctx = &self.ctx;
+        if popts & TXD_POPTS_TXSM != 0 {
+            put_checksum(
+                frame,
+                ctx.tucso.into(),
+                ctx.tucss.into(),
+                ctx.tucse.into(),
+            );
+        }
+        if popts & TXD_POPTS_IXSM != 0 {
+            put_checksum(
+                frame,
+                ctx.ipcso.into(),
+                ctx.ipcss.into(),
+                ctx.ipcse.into(),
+            );
+        }
+    }
+
+    /// Discard any partially-assembled packet, as well as the offload context.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn export(&self) -> migrate::TxStateV1 {
+        let ctx = &self.ctx;
+        let pkt = &self.pkt;
+        migrate::TxStateV1 {
+            ipcss: ctx.ipcss,
+            ipcso: ctx.ipcso,
+            ipcse: ctx.ipcse,
+            tucss: ctx.tucss,
+            tucso: ctx.tucso,
+            tucse: ctx.tucse,
+            tcp: ctx.tcp,
+            ipv4: ctx.ipv4,
+            tse: ctx.tse,
+            hdr_len: ctx.hdr_len,
+            mss: ctx.mss,
+            pkt_data: pkt.data.clone(),
+            pkt_popts: pkt.popts,
+            pkt_tse: pkt.tse,
+            pkt_legacy_sum: pkt.legacy_sum,
+            pkt_vlan: pkt.vlan,
+            pkt_started: pkt.started,
+            pkt_invalid: pkt.invalid,
+        }
+    }
+
+    pub fn import(&mut self, input: migrate::TxStateV1) {
+        self.ctx = TxContext {
+            ipcss: input.ipcss,
+            ipcso: input.ipcso,
+            ipcse: input.ipcse,
+            tucss: input.tucss,
+            tucso: input.tucso,
+            tucse: input.tucse,
+            tcp: input.tcp,
+            ipv4: input.ipv4,
+            tse: input.tse,
+            hdr_len: input.hdr_len,
+            mss: input.mss,
+        };
+        self.pkt = TxPacket {
+            // Don't trust the source to have observed the size limit
+            invalid: input.pkt_invalid || input.pkt_data.len() > MAX_TX_PACKET,
+            data: input.pkt_data,
+            popts: input.pkt_popts,
+            tse: input.pkt_tse,
+            legacy_sum: input.pkt_legacy_sum,
+            vlan: input.pkt_vlan,
+            started: input.pkt_started,
+        };
+    }
+}
+
+/// Store, at offset `sloc` of `frame`, the internet checksum of the bytes
+/// from `css` through `cse` inclusive (or through the end of the frame, if
+/// `cse` is zero).
+pub(super) fn put_checksum(
+    frame: &mut [u8],
+    sloc: usize,
+    css: usize,
+    cse: usize,
+) {
+    let end = match cse {
+        0 => frame.len(),
+        cse => usize::min(cse + 1, frame.len()),
+    };
+    if css >= end || sloc + 2 > end {
+        return;
+    }
+    let mut sum: u32 = frame[css..end]
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+    // A checksum of zero would be mistaken for its absence in UDP
+    let csum = match !(sum as u16) {
+        0 => 0xffff,
+        csum => csum,
+    };
+    put_be16(frame, sloc, csum);
+}
+
+/// Insert an 802.1Q tag, following the source and destination addresses.
+fn insert_vlan_tag(frame: &mut Vec<u8>, vet: u16, tag: u16) {
+    if frame.len() < 12 {
+        return;
+    }
+    let mut hdr = [0u8; 4];
+    hdr[..2].copy_from_slice(&vet.to_be_bytes());
+    hdr[2..].copy_from_slice(&tag.to_be_bytes());
+    frame.splice(12..12, hdr);
+}
+
+fn get_be16(buf: &[u8], off: usize) -> Option<u16> {
+    let bytes = buf.get(off..off + 2)?;
+    Some(u16::from_be_bytes(bytes.try_into().unwrap()))
+}
+fn put_be16(buf: &mut [u8], off: usize, val: u16) {
+    if let Some(bytes) = buf.get_mut(off..off + 2) {
+        bytes.copy_from_slice(&val.to_be_bytes());
+    }
+}
+fn get_be32(buf: &[u8], off: usize) -> Option<u32> {
+    let bytes = buf.get(off..off + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+fn put_be32(buf: &mut [u8], off: usize, val: u32) {
+    if let Some(bytes) = buf.get_mut(off..off + 4) {
+        bytes.copy_from_slice(&val.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::vmm::Machine;
+
+    /// Offset of the IPv4 header in an untagged frame
+    const IPCSS: usize = 14;
+    /// Offset of the TCP header, following a 20-byte IPv4 header
+    const TUCSS: usize = 34;
+    const HDR_LEN: usize = 54;
+    /// Sum of the addresses (10.0.0.1 and 10.0.0.2) and protocol in the TCP
+    /// pseudo-header
+    const PSEUDO_HDR_SUM: u32 = 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 6;
+
+    const BUF_ADDR: u64 = 0x10_0000;
+
+    /// Build a TCP/IPv4 frame with `payload`, in the form handed to the
+    /// hardware by a driver using the offloads: with the IPv4 checksum zeroed,
+    /// and the TCP checksum seeded with the (length-less) pseudo-header sum.
+    fn tcp_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; HDR_LEN];
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        frame[IPCSS] = 0x45;
+        frame[IPCSS + 4..IPCSS + 6].copy_from_slice(&0x1234u16.to_be_bytes());
+        frame[IPCSS + 8] = 64;
+        frame[IPCSS + 9] = 6;
+        frame[IPCSS + 12..IPCSS + 16].copy_from_slice(&[10, 0, 0, 1]);
+        frame[IPCSS + 16..IPCSS + 20].copy_from_slice(&[10, 0, 0, 2]);
+        frame[TUCSS + 4..TUCSS + 8].copy_from_slice(&1000u32.to_be_bytes());
+        frame[TUCSS + 12] = 5 << 4;
+        frame[TUCSS + 13] = TCP_FLAG_PSH | TCP_FLAG_FIN | 0x10;
+        frame[TUCSS + 16..TUCSS + 18]
+            .copy_from_slice(&(PSEUDO_HDR_SUM as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Verify the internet checksum over `data`, which includes the checksum
+    /// itself (and any pseudo-header words in `extra`).
+    fn checksum_ok(data: &[u8], extra: u32) -> bool {
+        let mut sum: u32 = data
+            .chunks(2)
+            .map(|c| {
+                u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]))
+            })
+            .sum::<u32>()
+            + extra;
+        while sum > 0xffff {
+            sum = (sum >> 16) + (sum & 0xffff);
+        }
+        sum == 0xffff
+    }
+
+    fn ctx_desc(mss: u16, tse: bool) -> TxDesc {
+        let addr = u64::from_le_bytes([
+            IPCSS as u8,
+            IPCSS as u8 + 10,
+            (TUCSS - 1) as u8,
+            0,
+            TUCSS as u8,
+            TUCSS as u8 + 16,
+            0,
+            0,
+        ]);
+        let mut lower = TXD_CMD_DEXT | TXD_DTYP_C | TXD_CMD_TCP | TXD_CMD_IP;
+        if tse {
+            lower |= TXD_CMD_TSE;
+        }
+        let upper = u32::from_le_bytes([
+            0,
+            HDR_LEN as u8,
+            mss.to_le_bytes()[0],
+            mss.to_le_bytes()[1],
+        ]);
+        TxDesc { addr, lower, upper }
+    }
+
+    fn data_desc(addr: u64, len: usize, eop: bool, tse: bool) -> TxDesc {
+        let mut lower = TXD_CMD_DEXT | TXD_DTYP_D | len as u32;
+        if eop {
+            lower |= TXD_CMD_EOP;
+        }
+        if tse {
+            lower |= TXD_CMD_TSE;
+        }
+        let popts = TXD_POPTS_IXSM | TXD_POPTS_TXSM;
+        TxDesc { addr, lower, upper: u32::from(popts) << 8 }
+    }
+
+    #[test]
+    fn checksum_offload() {
+        let machine = Machine::new_test().unwrap();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let payload: Vec<u8> = (0..101).collect();
+        let frame = tcp_frame(&payload);
+        mem.write_from(GuestAddr(BUF_ADDR), &frame, frame.len());
+
+        // Split the packet across a pair of descriptors
+        let mut tx = TxState::default();
+        let mut sent = Vec::new();
+        let mut send = |f: &[u8]| sent.push(f.to_vec());
+        tx.process(&ctx_desc(0, false), &mem, false, 0x8100, &mut send);
+        tx.process(
+            &data_desc(BUF_ADDR, 20, false, false),
+            &mem,
+            false,
+            0x8100,
+            &mut send,
+        );
+        tx.process(
+            &data_desc(BUF_ADDR + 20, frame.len() - 20, true, false),
+            &mem,
+            false,
+            0x8100,
+            &mut send,
+        );
+
+        assert_eq!(sent.len(), 1);
+        let out = &sent[0];
+        assert_eq!(out.len(), frame.len());
+        assert!(checksum_ok(&out[IPCSS..TUCSS], 0));
+        assert!(checksum_ok(&out[TUCSS..], PSEUDO_HDR_SUM));
+        assert_eq!(&out[HDR_LEN..], &payload[..]);
+    }
+
+    #[test]
+    fn segmentation() {
+        let machine = Machine::new_test().unwrap();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let payload: Vec<u8> = (0..250u16).map(|v| v as u8).collect();
+        let frame = tcp_frame(&payload);
+        mem.write_from(GuestAddr(BUF_ADDR), &frame, frame.len());
+
+        let mut tx = TxState::default();
+        let mut sent = Vec::new();
+        let mut send = |f: &[u8]| sent.push(f.to_vec());
+        tx.process(&ctx_desc(100, true), &mem, true, 0x8100, &mut send);
+        let mut desc = data_desc(BUF_ADDR, frame.len(), true, true);
+        desc.lower |= TXD_CMD_VLE;
+        desc.upper |= 5 << 16;
+        tx.process(&desc, &mem, true, 0x8100, &mut send);
+
+        assert_eq!(sent.len(), 3);
+        let mut seq = 1000;
+        for (idx, out) in sent.iter().enumerate() {
+            // Each segment is tagged, so strip the tag to check the rest
+            assert_eq!(&out[12..16], &[0x81, 0x00, 0x00, 0x05]);
+            let mut out = out.clone();
+            out.drain(12..16);
+
+            let seg_len = usize::min(100, 250 - idx * 100);
+            assert_eq!(out.len(), HDR_LEN + seg_len);
+            assert_eq!(
+                get_be16(&out, IPCSS + 2),
+                Some((out.len() - IPCSS) as u16)
+            );
+            assert_eq!(get_be16(&out, IPCSS + 4), Some(0x1234 + idx as u16));
+            assert!(checksum_ok(&out[IPCSS..TUCSS], 0));
+
+            assert_eq!(get_be32(&out, TUCSS + 4), Some(seq));
+            let flags = out[TUCSS + 13];
+            let last = idx == 2;
+            assert_eq!(flags & (TCP_FLAG_FIN | TCP_FLAG_PSH) != 0, last);
+            // The seeded pseudo-header sum lacked the TCP length, which the
+            // device is responsible for including.
+            let tcp_len = (out.len() - TUCSS) as u32;
+            assert!(checksum_ok(&out[TUCSS..], PSEUDO_HDR_SUM + tcp_len));
+            assert_eq!(&out[HDR_LEN..], &payload[idx * 100..][..seg_len]);
+            seq += seg_len as u32;
+        }
+    }
+
+    #[test]
+    fn checksum_bounds() {
+        // Offsets beyond the frame must be ignored, rather than panicking
+        let mut frame = vec![0u8; 20];
+        put_checksum(&mut frame, 19, 0, 0);
+        put_checksum(&mut frame, 30, 0, 0);
+        put_checksum(&mut frame, 0, 40, 0);
+        put_checksum(&mut frame, 4, 2, 3);
+        assert_eq!(frame, vec![0u8; 20]);
+
+        put_checksum(&mut frame, 0, 0, 0);
+        assert!(checksum_ok(&frame, 0));
+    }
+}
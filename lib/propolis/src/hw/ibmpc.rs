@@ -24,3 +24,7 @@ pub const PORT_PS2_CMD_STATUS: u16 = 0x64;
 
 pub const IRQ_PS2_PRI: u8 = 1;
 pub const IRQ_PS2_AUX: u8 = 12;
+
+pub const PORT_IDE_PRI_CMD: u16 = 0x1f0;
+pub const PORT_IDE_PRI_CTL: u16 = 0x3f6;
+pub const IRQ_IDE_PRI: u8 = 14;
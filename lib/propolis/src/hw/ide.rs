@@ -0,0 +1,1051 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! PIIX3-compatible IDE controller
+//!
+//! Only the primary channel is implemented, in compatibility mode: its
+//! registers are found at the legacy I/O ports, and it interrupts through ISA
+//! IRQ 14.  A single ATA disk is attached to it as the master device.
+//!
+//! Sector data is moved by the Bus Master IDE engine, as block backends can
+//! only transfer data to and from guest memory.  PIO is limited to returning
+//! IDENTIFY DEVICE data, and PIO data commands are aborted, leaving guests to
+//! fall back to DMA.  With every register access trapping out of the guest
+//! and a single command outstanding at a time, the controller is slow by
+//! design: it is meant for guests too old to drive AHCI, NVMe, or virtio
+//! devices, and nothing else.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::accessors::MemAccessor;
+use crate::block;
+use crate::common::*;
+use crate::hw::ahci::bits::*;
+use crate::hw::ahci::{ata_string, set_integrity_word};
+use crate::hw::ibmpc;
+use crate::hw::ids::pci::{
+    PIIX3_IDE_DEV_ID, PIIX3_IDE_SUB_DEV_ID, VENDOR_INTEL, VENDOR_OXIDE,
+};
+use crate::hw::pci;
+use crate::intr_pins::IntrPin;
+use crate::migrate::*;
+use crate::pio::{PioBus, PioFn};
+use crate::vmm::MemCtx;
+
+use futures::future::BoxFuture;
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn ide_cmd(command: u8) {}
+    fn ide_read_enqueue(off: u64, sz: u64) {}
+    fn ide_write_enqueue(off: u64, sz: u64) {}
+    fn ide_flush_enqueue() {}
+    fn ide_complete(error: u8) {}
+}
+
+/// Length of the command block registers of a channel
+const CMD_BLOCK_LEN: u16 = 8;
+
+/// Size of the I/O BAR (BAR4) holding the Bus Master IDE registers of both
+/// channels
+const BMIDE_BAR_SIZE: u16 = 16;
+
+/// Offset of the IDE Timing (IDETIM) registers of both channels in PCI config
+/// space
+const IDETIM_OFFSET: u8 = 0x40;
+const IDETIM_LEN: usize = 4;
+
+/// IDE Decode Enable (IDE) bit of the upper byte of an IDETIM register
+const IDETIM_HI_DECODE: u8 = 1 << 7;
+
+/// Model number reported by IDENTIFY DEVICE
+const MODEL_NUMBER: &str = "Propolis IDE Disk";
+
+/// Firmware revision reported by IDENTIFY DEVICE
+const FIRMWARE_REV: &str = "1.0";
+
+/// Device (DEV) bit of the Device register, selecting the slave device
+const ATA_DEV_SLAVE: u8 = 1 << 4;
+
+/// Error register value reporting that device 0 passed its diagnostics, and
+/// that there is no device 1
+const ATA_DIAG_PASSED: u8 = 0x01;
+
+// Bus Master IDE Command register bits
+
+/// Start/Stop Bus Master (SSBM)
+const BM_CMD_START: u8 = 1 << 0;
+/// Read/Write Control (RWCON): set when the bus master writes to memory
+const BM_CMD_WRITE_MEM: u8 = 1 << 3;
+
+// Bus Master IDE Status register bits
+
+/// Bus Master IDE Active (BMIDEA)
+const BM_STS_ACTIVE: u8 = 1 << 0;
+/// IDE DMA Error
+const BM_STS_ERR: u8 = 1 << 1;
+/// IDE Interrupt Status
+const BM_STS_INTR: u8 = 1 << 2;
+/// Drive 0 and Drive 1 DMA Capable bits, which are simply retained
+const BM_STS_DMA_CAP: u8 = (1 << 5) | (1 << 6);
+
+/// Physical Region Descriptor, as found in the table walked by the bus master
+#[derive(Copy, Clone, Default, Debug)]
+#[repr(C, packed(1))]
+struct PrdEntry {
+    /// Memory Region Physical Base Address
+    addr: u32,
+    /// Byte Count, where 0 means 64K
+    count: u16,
+    /// End of Table (EOT) in bit 15
+    flags: u16,
+}
+impl PrdEntry {
+    fn len(&self) -> usize {
+        match self.count & !1 {
+            0 => 0x10000,
+            n => n as usize,
+        }
+    }
+    fn is_last(&self) -> bool {
+        self.flags & (1 << 15) != 0
+    }
+}
+
+/// Max number of entries in a PRD table, which may not cross a 64K boundary
+const PRD_TABLE_MAX: u64 = 0x10000 / 8;
+
+/// ATA command awaiting (or undergoing) processing by the backend
+#[derive(Copy, Clone, Debug)]
+enum PendingCmd {
+    Read { lba: u64, count: u32 },
+    Write { lba: u64, count: u32 },
+    Flush,
+}
+
+/// ATA task file registers written by the guest.  Each holds its most
+/// recently written value in the low byte, and the one before that (as read
+/// back with HOB set) in the high byte.
+#[derive(Copy, Clone, Default)]
+struct TaskFile {
+    features: u16,
+    count: u16,
+    lba_low: u16,
+    lba_mid: u16,
+    lba_high: u16,
+    device: u8,
+}
+impl TaskFile {
+    /// Shift a newly written `val` into `reg`
+    fn push(reg: &mut u16, val: u8) {
+        *reg = (*reg << 8) | u16::from(val);
+    }
+
+    /// Logical block address for a 28-bit command, the top nibble of which is
+    /// held in the Device register.
+    fn lba28(&self) -> u64 {
+        u64::from_le_bytes([
+            self.lba_low as u8,
+            self.lba_mid as u8,
+            self.lba_high as u8,
+            self.device & 0xf,
+            0,
+            0,
+            0,
+            0,
+        ])
+    }
+
+    /// Logical block address for a 48-bit (EXT) command
+    fn lba48(&self) -> u64 {
+        let [l0, l1] = self.lba_low.to_le_bytes();
+        let [m0, m1] = self.lba_mid.to_le_bytes();
+        let [h0, h1] = self.lba_high.to_le_bytes();
+        u64::from_le_bytes([l0, m0, h0, l1, m1, h1, 0, 0])
+    }
+
+    /// Sector count for a 28-bit command, where 0 means 256
+    fn count28(&self) -> u32 {
+        match self.count & 0xff {
+            0 => 0x100,
+            n => u32::from(n),
+        }
+    }
+
+    /// Sector count for a 48-bit (EXT) command, where 0 means 65536
+    fn count48(&self) -> u32 {
+        match self.count {
+            0 => 0x10000,
+            n => u32::from(n),
+        }
+    }
+
+    /// Load the signature a device reports after it is reset, identifying it
+    /// as an ATA (rather than ATAPI) device.
+    fn set_signature(&mut self) {
+        self.count = 1;
+        self.lba_low = 1;
+        self.lba_mid = 0;
+        self.lba_high = 0;
+        self.device = 0;
+    }
+}
+
+struct IdeState {
+    tf: TaskFile,
+    /// ATA Status register
+    status: u8,
+    /// ATA Error register
+    error: u8,
+    /// ATA Device Control register
+    control: u8,
+    /// Is the device asserting its interrupt (INTRQ)?
+    intrq: bool,
+
+    /// Data being transferred to the guest by PIO, and how much of it has
+    /// been read so far
+    pio_data: Vec<u8>,
+    pio_pos: usize,
+
+    /// Command waiting to be submitted to the backend
+    pending: Option<PendingCmd>,
+    /// Is a command being processed by the backend?
+    active: bool,
+    /// Incremented whenever the device is reset or the bus master is stopped,
+    /// so that the completion of any command the backend was processing at
+    /// the time can be discarded.
+    gen: u64,
+
+    /// Bus Master IDE Command register
+    bm_cmd: u8,
+    /// Bus Master IDE Status register
+    bm_status: u8,
+    /// Descriptor Table Pointer register
+    bm_prdt: u32,
+
+    /// IDE Timing registers, as found in config space
+    idetim: [u8; IDETIM_LEN],
+
+    irq_pin: Box<dyn IntrPin>,
+}
+impl IdeState {
+    fn new(irq_pin: Box<dyn IntrPin>) -> Self {
+        let mut tf = TaskFile::default();
+        tf.set_signature();
+        Self {
+            tf,
+            status: ATA_STS_READY,
+            error: ATA_DIAG_PASSED,
+            control: 0,
+            intrq: false,
+            pio_data: Vec::new(),
+            pio_pos: 0,
+            pending: None,
+            active: false,
+            gen: 0,
+            bm_cmd: 0,
+            bm_status: 0,
+            bm_prdt: 0,
+            idetim: Self::IDETIM_DEFAULT,
+            irq_pin,
+        }
+    }
+
+    /// Decoding of the primary channel is enabled, and of the (absent)
+    /// secondary channel disabled.
+    const IDETIM_DEFAULT: [u8; IDETIM_LEN] = [0, IDETIM_HI_DECODE, 0, 0];
+
+    fn sync_intr(&self) {
+        let level = self.intrq && self.control & ATA_CTL_NIEN == 0;
+        self.irq_pin.set_state(level);
+    }
+
+    fn raise_intr(&mut self) {
+        self.intrq = true;
+        self.bm_status |= BM_STS_INTR;
+        self.sync_intr();
+    }
+
+    fn slave_selected(&self) -> bool {
+        self.tf.device & ATA_DEV_SLAVE != 0
+    }
+
+    /// Abandon any command in progress, as the device is being reset.
+    fn clear_cmd(&mut self) {
+        self.pending = None;
+        self.active = false;
+        self.pio_data.clear();
+        self.pio_pos = 0;
+        self.gen += 1;
+    }
+
+    /// Reset the device, leaving it ready with its signature loaded.
+    fn reset_device(&mut self) {
+        self.clear_cmd();
+        self.tf.set_signature();
+        self.status = ATA_STS_READY;
+        self.error = ATA_DIAG_PASSED;
+        self.intrq = false;
+        self.sync_intr();
+    }
+
+    fn reset(&mut self) {
+        self.control = 0;
+        self.reset_device();
+        self.bm_cmd = 0;
+        self.bm_status = 0;
+        self.bm_prdt = 0;
+        self.idetim = Self::IDETIM_DEFAULT;
+    }
+
+    /// Complete the current command, interrupting the guest.
+    fn finish_cmd(&mut self, error: u8) {
+        probes::ide_complete!(|| error);
+        self.status = match error {
+            0 => ATA_STS_READY,
+            _ => ATA_STS_READY | ATA_STS_ERR,
+        };
+        self.error = error;
+        self.raise_intr();
+    }
+}
+
+/// PIIX3-compatible IDE controller with a single ATA disk attached
+pub struct PiixIde {
+    state: Mutex<IdeState>,
+    pci_state: pci::DeviceState,
+
+    block_attach: block::DeviceAttachment,
+    block_tracking: block::tracking::Tracking<u64>,
+
+    /// Serial number reported by IDENTIFY DEVICE
+    serial_number: String,
+
+    /// Logger resource
+    log: slog::Logger,
+}
+
+impl PiixIde {
+    /// Create a new IDE controller, with a disk reporting the given serial
+    /// number.  The controller interrupts through `irq_pin`, which is expected
+    /// to be that of ISA IRQ 14.
+    pub fn create(
+        serial_number: String,
+        irq_pin: Box<dyn IntrPin>,
+        log: slog::Logger,
+    ) -> Arc<Self> {
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_INTEL,
+            device_id: PIIX3_IDE_DEV_ID,
+            sub_vendor_id: VENDOR_OXIDE,
+            sub_device_id: PIIX3_IDE_SUB_DEV_ID,
+            class: pci::bits::CLASS_STORAGE,
+            subclass: pci::bits::SUBCLASS_STORAGE_IDE,
+            prog_if: pci::bits::PROGIF_IDE_COMPAT_BM,
+            ..Default::default()
+        })
+        .add_bar_io(pci::BarN::BAR4, BMIDE_BAR_SIZE)
+        .add_custom_cfg(IDETIM_OFFSET, IDETIM_LEN as u8)
+        .finish();
+
+        Arc::new_cyclic(|weak| PiixIde {
+            state: Mutex::new(IdeState::new(irq_pin)),
+            pci_state,
+            block_attach: block::DeviceAttachment::new(),
+            block_tracking: block::tracking::Tracking::new(
+                weak.clone() as Weak<dyn block::Device>
+            ),
+            serial_number,
+            log,
+        })
+    }
+
+    /// Register the command and control block registers of the primary
+    /// channel at their legacy I/O ports.
+    pub fn attach_legacy(self: &Arc<Self>, bus: &PioBus) {
+        let this = self.clone();
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.cmd_rw(rwo))
+            as Arc<PioFn>;
+        bus.register(ibmpc::PORT_IDE_PRI_CMD, CMD_BLOCK_LEN, piofn).unwrap();
+
+        let this = self.clone();
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.ctl_rw(rwo))
+            as Arc<PioFn>;
+        bus.register(ibmpc::PORT_IDE_PRI_CTL, 1, piofn).unwrap();
+    }
+
+    /// Access the command block registers
+    fn cmd_rw(&self, rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) if ro.offset() == 0 => {
+                Self::pio_read(&mut state, ro);
+            }
+            RWOp::Read(ro) => {
+                let hob = state.control & ATA_CTL_HOB != 0;
+                let byte =
+                    |reg: u16| if hob { (reg >> 8) as u8 } else { reg as u8 };
+                let tf = &state.tf;
+                let val = match ro.offset() {
+                    1 => state.error,
+                    2 => byte(tf.count),
+                    3 => byte(tf.lba_low),
+                    4 => byte(tf.lba_mid),
+                    5 => byte(tf.lba_high),
+                    6 => tf.device | 0xa0,
+                    _ => {
+                        // Reading the Status register acknowledges the
+                        // interrupt.
+                        let status = Self::status(&state);
+                        if !state.slave_selected() {
+                            state.intrq = false;
+                            state.sync_intr();
+                        }
+                        status
+                    }
+                };
+                ro.write_u8(val);
+            }
+            RWOp::Write(wo) if wo.offset() == 0 => {
+                // PIO data-out commands are not supported, so there is
+                // nothing to write to.
+            }
+            RWOp::Write(wo) => {
+                let val = wo.read_u8();
+                // Writes to the command block clear HOB
+                state.control &= !ATA_CTL_HOB;
+                let tf = &mut state.tf;
+                match wo.offset() {
+                    1 => TaskFile::push(&mut tf.features, val),
+                    2 => TaskFile::push(&mut tf.count, val),
+                    3 => TaskFile::push(&mut tf.lba_low, val),
+                    4 => TaskFile::push(&mut tf.lba_mid, val),
+                    5 => TaskFile::push(&mut tf.lba_high, val),
+                    6 => tf.device = val,
+                    _ => {
+                        if self.command(&mut state, val) {
+                            drop(state);
+                            self.block_attach.notify();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Access the Alternate Status (read) and Device Control (write)
+    /// registers, neither of which have any effect on pending interrupts.
+    fn ctl_rw(&self, rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) => ro.write_u8(Self::status(&state)),
+            RWOp::Write(wo) => {
+                let val = wo.read_u8();
+                let was_reset = state.control & ATA_CTL_SRST != 0;
+                let is_reset = val & ATA_CTL_SRST != 0;
+                state.control = val;
+                match (was_reset, is_reset) {
+                    (false, true) => {
+                        slog::debug!(self.log, "IDE software reset");
+                        state.clear_cmd();
+                        state.status = ATA_STS_BSY;
+                    }
+                    (true, false) => state.reset_device(),
+                    _ => state.sync_intr(),
+                }
+            }
+        }
+    }
+
+    /// Value of the Status register, as seen by the guest.  There is no slave
+    /// device, and so no status for it.
+    fn status(state: &IdeState) -> u8 {
+        if state.slave_selected() {
+            0
+        } else {
+            state.status
+        }
+    }
+
+    /// Read from the Data register, as the guest transfers PIO data-in.
+    fn pio_read(state: &mut IdeState, ro: &mut ReadOp) {
+        let remain = &state.pio_data[state.pio_pos..];
+        let len = ro.len().min(remain.len());
+        ro.write_bytes(&remain[..len]);
+        state.pio_pos += len;
+        if len != 0 && state.pio_pos == state.pio_data.len() {
+            state.pio_data.clear();
+            state.pio_pos = 0;
+            state.status = ATA_STS_READY;
+        }
+    }
+
+    /// Begin processing an ATA command written to the Command register,
+    /// returning true if the backend must be notified of a new request.
+    fn command(&self, state: &mut IdeState, command: u8) -> bool {
+        probes::ide_cmd!(|| command);
+
+        if command == ATA_CMD_EXECUTE_DEVICE_DIAGNOSTIC {
+            state.reset_device();
+            state.raise_intr();
+            return false;
+        }
+        if state.slave_selected() || state.status & ATA_STS_BSY != 0 {
+            // Commands for the absent slave are ignored, as are any issued
+            // while the master is busy.
+            return false;
+        }
+        state.intrq = false;
+        state.sync_intr();
+
+        let tf = state.tf;
+        let pending = match command {
+            ATA_CMD_IDENTIFY_DEVICE => {
+                let info = self.block_attach.info().unwrap_or_default();
+                let ident = self.identify(&info);
+                state.pio_data =
+                    ident.iter().flat_map(|w| w.to_le_bytes()).collect();
+                state.pio_pos = 0;
+                state.status = ATA_STS_READY | ATA_STS_DRQ;
+                state.error = 0;
+                state.raise_intr();
+                return false;
+            }
+
+            ATA_CMD_READ_DMA => {
+                PendingCmd::Read { lba: tf.lba28(), count: tf.count28() }
+            }
+            ATA_CMD_WRITE_DMA => {
+                PendingCmd::Write { lba: tf.lba28(), count: tf.count28() }
+            }
+            ATA_CMD_READ_DMA_EXT => {
+                PendingCmd::Read { lba: tf.lba48(), count: tf.count48() }
+            }
+            ATA_CMD_WRITE_DMA_EXT => {
+                PendingCmd::Write { lba: tf.lba48(), count: tf.count48() }
+            }
+            ATA_CMD_FLUSH_CACHE | ATA_CMD_FLUSH_CACHE_EXT => PendingCmd::Flush,
+
+            ATA_CMD_CHECK_POWER_MODE => {
+                // Always in the active (or idle) power mode
+                TaskFile::push(&mut state.tf.count, 0xff);
+                state.finish_cmd(0);
+                return false;
+            }
+            ATA_CMD_VERIFY_SECTORS
+            | ATA_CMD_VERIFY_SECTORS_EXT
+            | ATA_CMD_INIT_DEV_PARAMS
+            | ATA_CMD_SET_FEATURES
+            | ATA_CMD_STANDBY_IMMEDIATE
+            | ATA_CMD_IDLE_IMMEDIATE
+            | ATA_CMD_STANDBY
+            | ATA_CMD_IDLE => {
+                // Nothing to verify, and transfer modes and power management
+                // have no bearing on an emulated device.
+                state.finish_cmd(0);
+                return false;
+            }
+            _ => {
+                // This includes the PIO data commands, whose data the
+                // backend has no means of transferring.
+                slog::debug!(self.log, "unsupported ATA command";
+                    "command" => command,
+                );
+                state.finish_cmd(ATA_ERR_ABRT);
+                return false;
+            }
+        };
+
+        if let PendingCmd::Read { lba, count }
+        | PendingCmd::Write { lba, count } = pending
+        {
+            let info = self.block_attach.info().unwrap_or_default();
+            if lba.saturating_add(u64::from(count)) > info.total_size {
+                state.finish_cmd(ATA_ERR_IDNF | ATA_ERR_ABRT);
+                return false;
+            }
+            if matches!(pending, PendingCmd::Write { .. }) && info.read_only {
+                state.finish_cmd(ATA_ERR_ABRT);
+                return false;
+            }
+        }
+        state.status = ATA_STS_READY | ATA_STS_BSY;
+        state.error = 0;
+        state.pending = Some(pending);
+        true
+    }
+
+    /// Access the Bus Master IDE registers
+    fn bmide_rw(&self, rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) => {
+                let mut regs = [0u8; BMIDE_BAR_SIZE as usize];
+                regs[0] = state.bm_cmd;
+                regs[2] = state.bm_status;
+                regs[4..8].copy_from_slice(&state.bm_prdt.to_le_bytes());
+                let off = ro.offset();
+                ro.write_bytes(&regs[off..(off + ro.len())]);
+            }
+            RWOp::Write(wo) => {
+                let mut notify = false;
+                for off in wo.offset()..(wo.offset() + wo.len()) {
+                    let val = wo.read_u8();
+                    match off {
+                        0 => notify |= Self::bm_cmd_write(&mut state, val),
+                        2 => {
+                            // ERR and INTR are write-1-to-clear
+                            let clear = val & (BM_STS_ERR | BM_STS_INTR);
+                            state.bm_status &= !(clear | BM_STS_DMA_CAP);
+                            state.bm_status |= val & BM_STS_DMA_CAP;
+                        }
+                        4..=7 => {
+                            let mut prdt = state.bm_prdt.to_le_bytes();
+                            prdt[off - 4] = val;
+                            // The table is dword-aligned
+                            state.bm_prdt = u32::from_le_bytes(prdt) & !0x3;
+                        }
+                        // The secondary channel is absent
+                        _ => {}
+                    }
+                }
+                drop(state);
+                if notify {
+                    self.block_attach.notify();
+                }
+            }
+        }
+    }
+
+    /// Update the Bus Master IDE Command register, returning true if the
+    /// backend must be notified that a transfer may begin.
+    fn bm_cmd_write(state: &mut IdeState, val: u8) -> bool {
+        let was_started = state.bm_cmd & BM_CMD_START != 0;
+        let is_started = val & BM_CMD_START != 0;
+        state.bm_cmd = val & (BM_CMD_START | BM_CMD_WRITE_MEM);
+        match (was_started, is_started) {
+            (false, true) => {
+                state.bm_status |= BM_STS_ACTIVE;
+                true
+            }
+            (true, false) => {
+                state.bm_status &= !BM_STS_ACTIVE;
+                if state.active {
+                    // Stopping the bus master mid-transfer aborts it, and the
+                    // guest is expected to reset the device to recover.
+                    state.active = false;
+                    state.gen += 1;
+                    state.status = ATA_STS_READY | ATA_STS_ERR;
+                    state.error = ATA_ERR_ABRT;
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Take the pending command, if it can be submitted to the backend.
+    fn next_req(&self) -> Option<block::Request> {
+        let mem = self.pci_state.acc_mem.access()?;
+        let mut state = self.state.lock().unwrap();
+        if state.active {
+            return None;
+        }
+        let pending = state.pending?;
+        let (lba, count, write) = match pending {
+            PendingCmd::Flush => {
+                state.pending = None;
+                state.active = true;
+                probes::ide_flush_enqueue!(|| ());
+                return Some(
+                    self.block_tracking
+                        .track(block::Request::new_flush(), state.gen),
+                );
+            }
+            PendingCmd::Read { lba, count } => (lba, count, false),
+            PendingCmd::Write { lba, count } => (lba, count, true),
+        };
+        if state.bm_cmd & BM_CMD_START == 0 {
+            // Wait for the guest to start the bus master
+            return None;
+        }
+        state.pending = None;
+
+        let info = self.block_attach.info().unwrap_or_default();
+        let block_size = info.block_size as usize;
+        let off = lba as usize * block_size;
+        let sz = count as usize * block_size;
+        let Some(regions) = Self::prd_regions(state.bm_prdt, sz, &mem) else {
+            // The guest provided too little buffer space for the transfer
+            state.bm_status &= !BM_STS_ACTIVE;
+            state.bm_status |= BM_STS_ERR;
+            state.finish_cmd(ATA_ERR_ABRT);
+            return None;
+        };
+        state.active = true;
+
+        let req = if write {
+            probes::ide_write_enqueue!(|| (off as u64, sz as u64));
+            block::Request::new_write(off, sz, regions)
+        } else {
+            probes::ide_read_enqueue!(|| (off as u64, sz as u64));
+            block::Request::new_read(off, sz, regions)
+        };
+        Some(self.block_tracking.track(req, state.gen))
+    }
+
+    /// Gather the guest memory regions, as described by the PRD table at
+    /// `prdt`, for a transfer of `len` bytes.
+    fn prd_regions(
+        prdt: u32,
+        len: usize,
+        mem: &MemCtx,
+    ) -> Option<Vec<GuestRegion>> {
+        let mut regions = Vec::new();
+        let mut remain = len;
+        for i in 0..PRD_TABLE_MAX {
+            if remain == 0 {
+                break;
+            }
+            let ent: PrdEntry = mem.read(GuestAddr(u64::from(prdt) + i * 8))?;
+            let sz = ent.len().min(remain);
+            regions.push(GuestRegion(GuestAddr(u64::from(ent.addr)), sz));
+            remain -= sz;
+            if ent.is_last() {
+                break;
+            }
+        }
+        (remain == 0).then_some(regions)
+    }
+
+    /// Assemble the data returned by IDENTIFY DEVICE
+    ///
+    /// See ACS-3 Section 7.12.7 IDENTIFY DEVICE data
+    fn identify(&self, info: &block::DeviceInfo) -> [u16; 256] {
+        let mut id = [0u16; 256];
+        let sectors = info.total_size;
+
+        // Fixed (non-removable) ATA device
+        id[0] = 0x0040;
+        // CHS geometry, which the guests this is meant for still consult
+        let cylinders = (sectors / (16 * 63)).min(16383) as u16;
+        id[1] = cylinders;
+        id[3] = 16;
+        id[6] = 63;
+        ata_string(&mut id[10..20], &self.serial_number);
+        ata_string(&mut id[23..27], FIRMWARE_REV);
+        ata_string(&mut id[27..47], MODEL_NUMBER);
+        // READ/WRITE MULTIPLE are not supported
+        id[47] = 0x8000;
+        // LBA and DMA supported
+        id[49] = (1 << 9) | (1 << 8);
+        id[50] = 0x4000;
+        // Words 54-58, 64-70, and 88 are valid
+        id[53] = (1 << 2) | (1 << 1) | (1 << 0);
+        id[54] = cylinders;
+        id[55] = 16;
+        id[56] = 63;
+        let chs_sectors = u32::from(cylinders) * 16 * 63;
+        id[57] = chs_sectors as u16;
+        id[58] = (chs_sectors >> 16) as u16;
+        // Sectors addressable by 28-bit commands
+        let lba28 = sectors.min(0x0fff_ffff) as u32;
+        id[60] = lba28 as u16;
+        id[61] = (lba28 >> 16) as u16;
+        // Multiword DMA modes 0-2 supported, and PIO modes 3-4
+        id[63] = 0x0007;
+        id[64] = 0x0003;
+        id[65] = 120;
+        id[66] = 120;
+        id[67] = 120;
+        id[68] = 120;
+        // ATA/ATAPI-4 through ATA/ATAPI-7
+        id[80] = 0x00f0;
+        // 48-bit addressing, FLUSH CACHE, and FLUSH CACHE EXT supported and
+        // enabled
+        id[83] = 0x4000 | (1 << 13) | (1 << 12) | (1 << 10);
+        id[86] = (1 << 13) | (1 << 12) | (1 << 10);
+        id[84] = 0x4000;
+        id[87] = 0x4000;
+        // Ultra DMA modes 0-2 supported (as befits a 40-conductor cable),
+        // with mode 2 selected
+        id[88] = (1 << 10) | 0x0007;
+        // Sectors addressable by 48-bit commands
+        id[100] = sectors as u16;
+        id[101] = (sectors >> 16) as u16;
+        id[102] = (sectors >> 32) as u16;
+        id[103] = (sectors >> 48) as u16;
+
+        set_integrity_word(&mut id);
+
+        id
+    }
+
+    fn export(&self) -> migrate::IdeStateV1 {
+        let state = self.state.lock().unwrap();
+        let tf = &state.tf;
+        migrate::IdeStateV1 {
+            features: tf.features,
+            count: tf.count,
+            lba_low: tf.lba_low,
+            lba_mid: tf.lba_mid,
+            lba_high: tf.lba_high,
+            device: tf.device,
+            status: state.status,
+            error: state.error,
+            control: state.control,
+            intrq: state.intrq,
+            pio_data: state.pio_data[state.pio_pos..].to_vec(),
+            pending: state.pending.map(Into::into),
+            bm_cmd: state.bm_cmd,
+            bm_status: state.bm_status,
+            bm_prdt: state.bm_prdt,
+            idetim: state.idetim,
+        }
+    }
+
+    fn import(&self, saved: migrate::IdeStateV1) {
+        let mut state = self.state.lock().unwrap();
+        state.tf = TaskFile {
+            features: saved.features,
+            count: saved.count,
+            lba_low: saved.lba_low,
+            lba_mid: saved.lba_mid,
+            lba_high: saved.lba_high,
+            device: saved.device,
+        };
+        state.status = saved.status;
+        state.error = saved.error;
+        state.control = saved.control;
+        state.intrq = saved.intrq;
+        state.pio_data = saved.pio_data;
+        state.pio_pos = 0;
+        state.pending = saved.pending.map(Into::into);
+        state.active = false;
+        state.bm_cmd = saved.bm_cmd & (BM_CMD_START | BM_CMD_WRITE_MEM);
+        state.bm_status = saved.bm_status;
+        state.bm_prdt = saved.bm_prdt;
+        state.idetim = saved.idetim;
+
+        let level = state.intrq && state.control & ATA_CTL_NIEN == 0;
+        state.irq_pin.import_state(level);
+    }
+}
+
+impl pci::Device for PiixIde {
+    fn bar_rw(&self, bar: pci::BarN, rwo: RWOp) {
+        assert_eq!(bar, pci::BarN::BAR4);
+        self.bmide_rw(rwo);
+    }
+
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        assert_eq!(region, IDETIM_OFFSET);
+        let mut state = self.state.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) => {
+                let off = ro.offset();
+                ro.write_bytes(&state.idetim[off..(off + ro.len())]);
+            }
+            RWOp::Write(wo) => {
+                let off = wo.offset();
+                wo.read_bytes(&mut state.idetim[off..(off + wo.len())]);
+                // Whether a channel is decoded is fixed by which of them are
+                // implemented, whatever the guest might wish.
+                state.idetim[1] |= IDETIM_HI_DECODE;
+                state.idetim[3] &= !IDETIM_HI_DECODE;
+            }
+        }
+    }
+
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl block::Device for PiixIde {
+    fn attachment(&self) -> &block::DeviceAttachment {
+        &self.block_attach
+    }
+
+    fn next(&self) -> Option<block::Request> {
+        self.next_req()
+    }
+
+    fn complete(&self, res: block::Result, id: block::ReqId) {
+        let (op, gen) = self.block_tracking.complete(id, res);
+
+        let mut state = self.state.lock().unwrap();
+        if gen != state.gen {
+            // The device was reset (or the bus master stopped) while the
+            // backend was processing the command.
+            return;
+        }
+        state.active = false;
+        if !op.is_flush() {
+            state.bm_status &= !BM_STS_ACTIVE;
+        }
+        let error = match res {
+            block::Result::Success => 0,
+            block::Result::Failure if op.is_read() => ATA_ERR_UNC,
+            block::Result::Failure
+            | block::Result::ReadOnly
+            | block::Result::Unsupported => ATA_ERR_ABRT,
+        };
+        state.finish_cmd(error);
+    }
+
+    fn accessor_mem(&self) -> MemAccessor {
+        self.pci_state.acc_mem.child(Some("block backend".to_string()))
+    }
+}
+
+impl Lifecycle for PiixIde {
+    fn type_name(&self) -> &'static str {
+        "pci-piix3-ide"
+    }
+
+    fn reset(&self) {
+        self.state.lock().unwrap().reset();
+        self.pci_state.reset(self);
+    }
+
+    fn pause(&self) {
+        self.block_attach.pause();
+    }
+
+    fn resume(&self) {
+        self.block_attach.resume();
+    }
+
+    fn paused(&self) -> BoxFuture<'static, ()> {
+        Box::pin(self.block_tracking.none_outstanding())
+    }
+
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl MigrateMulti for PiixIde {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        output.push(self.export().into())?;
+        MigrateMulti::export(&self.pci_state, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::IdeStateV1 = offer.take()?;
+        self.import(input);
+        MigrateMulti::import(&self.pci_state, offer, ctx)
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::IdeStateV1>();
+        MigrateMulti::schemas(&self.pci_state, schemas)
+    }
+}
+
+impl From<PendingCmd> for migrate::IdeCmdV1 {
+    fn from(cmd: PendingCmd) -> Self {
+        match cmd {
+            PendingCmd::Read { lba, count } => Self::Read { lba, count },
+            PendingCmd::Write { lba, count } => Self::Write { lba, count },
+            PendingCmd::Flush => Self::Flush,
+        }
+    }
+}
+impl From<migrate::IdeCmdV1> for PendingCmd {
+    fn from(cmd: migrate::IdeCmdV1) -> Self {
+        match cmd {
+            migrate::IdeCmdV1::Read { lba, count } => Self::Read { lba, count },
+            migrate::IdeCmdV1::Write { lba, count } => {
+                Self::Write { lba, count }
+            }
+            migrate::IdeCmdV1::Flush => Self::Flush,
+        }
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Copy, Clone, Deserialize, Serialize)]
+    pub enum IdeCmdV1 {
+        Read { lba: u64, count: u32 },
+        Write { lba: u64, count: u32 },
+        Flush,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct IdeStateV1 {
+        pub features: u16,
+        pub count: u16,
+        pub lba_low: u16,
+        pub lba_mid: u16,
+        pub lba_high: u16,
+        pub device: u8,
+        pub status: u8,
+        pub error: u8,
+        pub control: u8,
+        pub intrq: bool,
+
+        pub pio_data: Vec<u8>,
+        pub pending: Option<IdeCmdV1>,
+
+        pub bm_cmd: u8,
+        pub bm_status: u8,
+        pub bm_prdt: u32,
+
+        pub idetim: [u8; 4],
+    }
+    impl Schema<'_> for IdeStateV1 {
+        fn id() -> SchemaId {
+            ("piix3-ide", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn task_file_hob() {
+        let mut tf = TaskFile::default();
+        for (reg, vals) in [
+            (&mut tf.count, [0x01, 0x02]),
+            (&mut tf.lba_low, [0x04, 0x01]),
+            (&mut tf.lba_mid, [0x05, 0x02]),
+            (&mut tf.lba_high, [0x06, 0x03]),
+        ] {
+            for val in vals {
+                TaskFile::push(reg, val);
+            }
+        }
+        tf.device = 0xe7;
+        assert_eq!(tf.lba48(), 0x0605_0403_0201);
+        assert_eq!(tf.lba28(), 0x0703_0201);
+        assert_eq!(tf.count48(), 0x0102);
+        assert_eq!(tf.count28(), 0x02);
+    }
+
+    #[test]
+    fn prd_entry_len() {
+        let ent = PrdEntry { addr: 0, count: 0, flags: 0x8000 };
+        assert_eq!(ent.len(), 0x10000);
+        assert!(ent.is_last());
+        let ent = PrdEntry { addr: 0, count: 0x200, flags: 0 };
+        assert_eq!(ent.len(), 0x200);
+        assert!(!ent.is_last());
+    }
+}
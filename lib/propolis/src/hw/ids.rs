@@ -37,6 +37,12 @@ pub mod pci {
     /// PCI Device ID for the PIIX4 ACPI PM Controller.
     pub const PIIX4_PM_DEV_ID: u16 = 0x7113;
 
+    /// PCI Device ID for the PIIX3 IDE Controller.
+    pub const PIIX3_IDE_DEV_ID: u16 = 0x7010;
+
+    /// PCI Device ID for the 82540EM Gigabit Ethernet Controller.
+    pub const E1000_DEV_ID: u16 = 0x100e;
+
     // Subsystem Device IDs (for devices emulated by propolis)
 
     /// PCI Subsystem Device ID for the PIIX4 Host Bridge as emulated by propolis.
@@ -54,6 +60,12 @@ pub mod pci {
     /// PCI Subsystem Device ID for the Propolis Virtio Block device.
     pub const VIRTIO_BLOCK_SUB_DEV_ID: u16 = 0xfffa;
 
+    /// PCI Subsystem Device ID for the PIIX3 IDE Controller as emulated by propolis.
+    pub const PIIX3_IDE_SUB_DEV_ID: u16 = 0xfff9;
+
+    /// PCI Subsystem Device ID for the 82540EM NIC as emulated by propolis.
+    pub const E1000_SUB_DEV_ID: u16 = 0xfff8;
+
     // Propolis-specific Device IDs
 
     /// PCI Device ID for the Propolis NVMe controller.
@@ -64,4 +76,7 @@ pub mod pci {
 
     /// PCI Device ID for the Propolis PCI-PCI bridge.
     pub const PROPOLIS_BRIDGE_DEV_ID: u16 = 0x2;
+
+    /// PCI Device ID for the Propolis AHCI controller.
+    pub const PROPOLIS_AHCI_DEV_ID: u16 = 0x3;
 }
@@ -2,12 +2,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod ahci;
 pub mod bhyve;
 pub mod chipset;
+pub mod e1000;
 pub mod ibmpc;
+pub mod ide;
 pub mod ids;
 pub mod nvme;
 pub mod pci;
+pub mod ppt;
 pub mod ps2;
 pub mod qemu;
 pub mod testdev;
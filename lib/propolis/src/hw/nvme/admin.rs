@@ -3,33 +3,60 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::mem::size_of;
+use std::sync::Mutex;
 
+use crate::block::attachment::DeviceStatsSnapshot;
 use crate::common::{GuestAddr, GuestRegion, PAGE_SIZE};
 use crate::vmm::MemCtx;
 
 use super::bits::*;
-use super::queue::{QueueId, ADMIN_QUEUE_ID};
-use super::{cmds, NvmeCtrl, NvmeError, MAX_NUM_IO_QUEUES, MAX_NUM_QUEUES};
+use super::queue::{Permit, QueueId, ADMIN_QUEUE_ID};
+use super::requests::InFlight;
+use super::{
+    cmds, NvmeCtrl, NvmeError, MAX_OUTSTANDING_AERS, SMART_TEMPERATURE,
+};
 
 #[usdt::provider(provider = "propolis")]
 mod probes {
     fn nvme_abort(cid: u16, sqid: u16) {}
+    fn nvme_abort_in_flight(cid: u16, sqid: u16, elapsed_ns: u64) {}
 }
 
 impl NvmeCtrl {
     /// Abort command.
     ///
+    /// Only I/O commands which have been submitted to the backend (and not
+    /// yet completed) can be aborted, and see [InFlight] for what that
+    /// entails.  Admin commands are completed as they are processed, save for
+    /// Asynchronous Event Requests, which are not subject to abort.
+    ///
     /// See NVMe 1.0e Section 5.1 Abort command
-    pub(super) fn acmd_abort(&self, cmd: &cmds::AbortCmd) -> cmds::Completion {
+    pub(super) fn acmd_abort(
+        &self,
+        cmd: &cmds::AbortCmd,
+        in_flight: &Mutex<InFlight>,
+    ) -> cmds::Completion {
         probes::nvme_abort!(|| (cmd.cid, cmd.sqid));
 
         // Verify the SQ in question currently exists
         let sqid = cmd.sqid as usize;
-        if sqid >= MAX_NUM_QUEUES || self.sqs[sqid].is_none() {
+        if sqid >= self.sqs.len() || self.sqs[sqid].is_none() {
             return cmds::Completion::generic_err(STS_INVAL_FIELD).dnr();
         }
 
-        // TODO: Support aborting in-flight commands.
+        if cmd.sqid != ADMIN_QUEUE_ID {
+            let elapsed =
+                in_flight.lock().unwrap().request_abort(cmd.sqid, cmd.cid);
+            if let Some(elapsed) = elapsed {
+                probes::nvme_abort_in_flight!(|| (
+                    cmd.cid,
+                    cmd.sqid,
+                    elapsed.as_nanos() as u64
+                ));
+                // Bit 0 cleared to indicate the command was aborted
+                return cmds::Completion::success_val(0);
+            }
+        }
 
         // The NVMe spec does not make any guarantees about being able to
         // successfully abort commands and allows indicating a failure to
@@ -38,6 +65,31 @@ impl NvmeCtrl {
         cmds::Completion::success_val(1)
     }
 
+    /// Service Asynchronous Event Request command.
+    ///
+    /// Rather than being completed immediately, the request is held until
+    /// there is an event to report.
+    ///
+    /// See NVMe 1.0e Section 5.2 Asynchronous Event Request command
+    pub(super) fn acmd_async_event_req(
+        &mut self,
+        permit: Permit,
+        mem: &MemCtx,
+    ) {
+        if self.aers.len() >= usize::from(MAX_OUTSTANDING_AERS) {
+            permit.complete(
+                cmds::Completion::specific_err(
+                    StatusCodeType::CmdSpecific,
+                    STS_ASYNC_EVENT_LIMIT_EXCEEDED,
+                ),
+                Some(mem),
+            );
+            return;
+        }
+        self.aers.push_back(permit);
+        self.deliver_events(mem);
+    }
+
     /// Service Create I/O Completion Queue command.
     ///
     /// See NVMe 1.0e Section 5.3 Create I/O Completion Queue command
@@ -210,22 +262,74 @@ impl NvmeCtrl {
     ///
     /// See NVMe 1.0e Section 5.10 Get Log Page command
     pub(super) fn acmd_get_log_page(
-        &self,
+        &mut self,
         cmd: &cmds::GetLogPageCmd,
         mem: &MemCtx,
+        stats: &DeviceStatsSnapshot,
     ) -> cmds::Completion {
-        if let Some(regions) = cmd
-            .data(mem)
-            .map(|r| mem.writable_region(&r))
-            .collect::<Option<Vec<_>>>()
-        {
-            // TODO: Keep a log to write back instead of 0s
-            for region in regions {
-                let _ = region.write_byte(0, region.len());
+        let res = match cmd.log_page_ident {
+            cmds::LogPageIdent::Smart => {
+                Self::write_log_page(cmd.data(mem), &self.smart_log(stats), mem)
             }
-            cmds::Completion::success()
-        } else {
-            cmds::Completion::generic_err(STS_DATA_XFER_ERR)
+            cmds::LogPageIdent::Error => {
+                // No errors are logged, so the contents of this page are left
+                // as all 0s.
+                Self::write_log_page(cmd.data(mem), &[0u8; 0], mem)
+            }
+            cmds::LogPageIdent::Firmware => {
+                // The firmware revision (if any) in Identify Controller is
+                // reported as that in the active slot, slot 1.
+                let mut page = [0u8; 16];
+                if self.ctrl_ident.fr != [0u8; 8] {
+                    page[0] = 1;
+                    page[8..].copy_from_slice(&self.ctrl_ident.fr);
+                }
+                Self::write_log_page(cmd.data(mem), &page, mem)
+            }
+            cmds::LogPageIdent::ChangedNamespaceList => {
+                // Reading the log page clears it, and unmasks further
+                // Namespace Attribute Changed events.  The one namespace is
+                // the only possible entry in the list.
+                if std::mem::replace(&mut self.ns_changed, false) {
+                    Self::write_log_page(cmd.data(mem), &1u32, mem)
+                } else {
+                    Self::write_log_page(cmd.data(mem), &[0u8; 0], mem)
+                }
+            }
+            _ => {
+                return cmds::Completion::specific_err(
+                    StatusCodeType::CmdSpecific,
+                    STS_GET_LOG_PAGE_INVAL_LOG_PAGE,
+                );
+            }
+        };
+        match res {
+            Some(()) => cmds::Completion::success(),
+            None => cmds::Completion::generic_err(STS_DATA_XFER_ERR),
+        }
+    }
+
+    /// Assemble the SMART / Health Information log page from the statistics
+    /// of the attached block device.
+    ///
+    /// See NVMe 1.0e Section 5.10.1.2 SMART / Health Information
+    fn smart_log(&self, stats: &DeviceStatsSnapshot) -> SmartLog {
+        // Data units are reported in thousands of 512 byte units, rounded up
+        let data_units = |bytes: u64| u128::from(bytes.div_ceil(512 * 1000));
+        let power_on_hours = self.powered_on.elapsed().as_secs() / 3600;
+
+        SmartLog {
+            temperature: SMART_TEMPERATURE,
+            avail_spare: 100,
+            avail_spare_thresh: 10,
+            data_units_read: data_units(stats.read_bytes),
+            data_units_written: data_units(stats.write_bytes),
+            host_read_cmds: u128::from(stats.read.total()),
+            host_write_cmds: u128::from(stats.write.total()),
+            power_cycles: u128::from(self.power_cycles),
+            power_on_hours: u128::from(power_on_hours),
+            media_errors: u128::from(stats.errors),
+            ..Default::default()
         }
     }
 
@@ -341,8 +445,8 @@ impl NvmeCtrl {
                 // maximums supported
                 cmds::Completion::success_val(
                     cmds::FeatNumberQueues {
-                        ncq: MAX_NUM_IO_QUEUES as u16,
-                        nsq: MAX_NUM_IO_QUEUES as u16,
+                        ncq: self.num_io_queues(),
+                        nsq: self.num_io_queues(),
                     }
                     .into(),
                 )
@@ -365,18 +469,14 @@ impl NvmeCtrl {
                 cmds::Completion::success_val(0)
             }
             cmds::FeatureIdent::AsynchronousEventConfiguration => {
-                // None of the defined events result in AEN transmission
-                cmds::Completion::success_val(0)
+                cmds::Completion::success_val(self.aen_config)
             }
 
             // Optional features
             cmds::FeatureIdent::VolatileWriteCache => {
-                // TODO: wire into actual write cache state
-                //
-                // Until that is done, indicate an enabled write cache to ensure
-                // IO flushes for the backends which require it for consistency.
                 cmds::Completion::success_val(
-                    cmds::FeatVolatileWriteCache { wce: true }.into(),
+                    cmds::FeatVolatileWriteCache { wce: self.write_cache }
+                        .into(),
                 )
             }
 
@@ -393,7 +493,7 @@ impl NvmeCtrl {
     ///
     /// See NVMe 1.0e Section 5.12 Set Features command
     pub(super) fn acmd_set_features(
-        &self,
+        &mut self,
         cmd: &cmds::SetFeaturesCmd,
     ) -> cmds::Completion {
         match cmd.fid {
@@ -409,8 +509,8 @@ impl NvmeCtrl {
 
                 // If they ask for too many queues, just return our max possible
                 let clamped = cmds::FeatNumberQueues {
-                    ncq: nq.ncq.min(MAX_NUM_IO_QUEUES as u16),
-                    nsq: nq.nsq.min(MAX_NUM_IO_QUEUES as u16),
+                    ncq: nq.ncq.min(self.num_io_queues()),
+                    nsq: nq.nsq.min(self.num_io_queues()),
                 };
 
                 cmds::Completion::success_val(clamped.into())
@@ -421,6 +521,18 @@ impl NvmeCtrl {
                 // the host may ... control whether it is enabled with Set
                 // Features specifying the Volatile Write Cache feature
                 // identifier."
+                //
+                // The backend is switched to write-through behavior (once the
+                // controller state is unlocked) when the cache is disabled.
+                let vwc: cmds::FeatVolatileWriteCache = cmd.cdw11.into();
+                self.write_cache = vwc.wce;
+                cmds::Completion::success()
+            }
+            cmds::FeatureIdent::AsynchronousEventConfiguration => {
+                // Of the optional notices, only those for namespace attribute
+                // changes are supported.  The SMART / Health critical warning
+                // bits are accepted, but we never raise any such warnings.
+                self.aen_config = cmd.cdw11 & (AEN_CFG_NS_ATTR | 0xff);
                 cmds::Completion::success()
             }
             cmds::FeatureIdent::Reserved
@@ -432,7 +544,6 @@ impl NvmeCtrl {
             | cmds::FeatureIdent::InterruptCoalescing
             | cmds::FeatureIdent::InterruptVectorConfiguration
             | cmds::FeatureIdent::WriteAtomicity
-            | cmds::FeatureIdent::AsynchronousEventConfiguration
             | cmds::FeatureIdent::SoftwareProgressMarker
             | cmds::FeatureIdent::Vendor(_) => {
                 cmds::Completion::generic_err(STS_INVAL_FIELD).dnr()
@@ -493,4 +604,151 @@ impl NvmeCtrl {
             Some(())
         }
     }
+
+    /// Write the contents of a log page to the memory specified by the given
+    /// [`cmds::PrpIter`].
+    ///
+    /// Unlike [`Self::write_admin_result`], the host may request less (or more)
+    /// of the page than the size of `data`: it is truncated to fit the buffer,
+    /// and any space remaining beyond its end is filled with 0s.
+    ///
+    /// The `data` type must be `repr(packed(1))`
+    ///
+    /// Returns `Some(())` if successful, else None
+    fn write_log_page<T: Copy>(
+        prp: cmds::PrpIter,
+        data: &T,
+        mem: &MemCtx,
+    ) -> Option<()> {
+        let regions =
+            prp.map(|r| mem.writable_region(&r)).collect::<Option<Vec<_>>>()?;
+
+        // Safety:
+        //
+        // As with `write_admin_result`, the data written through this function
+        // is expected to be packed, so there is no padding to risk UB through
+        // the [u8] slice creation.
+        let mut raw = unsafe {
+            std::slice::from_raw_parts(
+                data as *const T as *const u8,
+                size_of::<T>(),
+            )
+        };
+        for region in regions {
+            region.write_byte(0, region.len()).ok()?;
+            let write_len = usize::min(region.len(), raw.len());
+
+            let to_copy;
+            (to_copy, raw) = raw.split_at(write_len);
+            region.write_bytes(to_copy).ok()?;
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+
+    use crate::block::Result as BlockResult;
+    use crate::common::GuestAddr;
+    use crate::hw::nvme::bits::*;
+    use crate::hw::nvme::testutil::*;
+
+    /// A Get Log Page command for page `lid`, of `len` bytes
+    fn get_log_page(lid: u8, len: u32) -> SubmissionQueueEntry {
+        SubmissionQueueEntry {
+            prp1: DATA_BASE,
+            // Number of Dwords (NUMD) is a 0's based value
+            cdw10: u32::from(lid) | (len / 4 - 1) << 16,
+            ..cmd(ADMIN_OPC_GET_LOG_PAGE, 20)
+        }
+    }
+
+    fn smart_log(ctrl: &mut TestCtrl) -> SmartLog {
+        let page = get_log_page(0x02, size_of::<SmartLog>() as u32);
+        assert_eq!(status(&ctrl.admin(page)), STS_SUCCESS);
+        ctrl.mem().read(GuestAddr(DATA_BASE)).unwrap()
+    }
+
+    #[test]
+    fn smart_log_counts_io() {
+        let mut ctrl = TestCtrl::new(64);
+        let log = smart_log(&mut ctrl);
+        assert_eq!({ log.host_read_cmds }, 0);
+        assert_eq!({ log.host_write_cmds }, 0);
+        assert_eq!({ log.data_units_read }, 0);
+        assert_eq!({ log.data_units_written }, 0);
+        assert_eq!({ log.media_errors }, 0);
+
+        let io = [
+            (io_cmd(NVM_OPC_WRITE, 1, 0, 8), BlockResult::Success),
+            (io_cmd(NVM_OPC_READ, 2, 0, 4), BlockResult::Success),
+            (io_cmd(NVM_OPC_READ, 3, 4, 4), BlockResult::Success),
+            (io_cmd(NVM_OPC_READ, 4, 8, 1), BlockResult::Failure),
+            (io_cmd(NVM_OPC_WRITE_ZEROES, 5, 8, 1), BlockResult::Success),
+        ];
+        for (cmd, res) in io {
+            ctrl.submit(IO_QID, cmd);
+            ctrl.backend.take().unwrap().complete(res);
+            assert!(ctrl.completion(IO_QID).is_some());
+        }
+
+        // Failed commands are counted, as are those which transfer no data,
+        // but only data actually transferred counts towards the data units
+        // (of 1000 512-byte units, rounded up).
+        let log = smart_log(&mut ctrl);
+        assert_eq!({ log.host_read_cmds }, 3);
+        assert_eq!({ log.host_write_cmds }, 2);
+        assert_eq!({ log.data_units_read }, 1);
+        assert_eq!({ log.data_units_written }, 1);
+        assert_eq!({ log.media_errors }, 1);
+    }
+
+    #[test]
+    fn ns_change_completes_aer() {
+        let mut ctrl = TestCtrl::new(64);
+        let aen_cfg = SubmissionQueueEntry {
+            cdw10: u32::from(FEAT_ID_ASYNC_EVENT_CFG),
+            cdw11: AEN_CFG_NS_ATTR,
+            ..cmd(ADMIN_OPC_SET_FEATURES, 10)
+        };
+        assert_eq!(status(&ctrl.admin(aen_cfg)), STS_SUCCESS);
+        ctrl.submit(ADMIN_QID, cmd(ADMIN_OPC_ASYNC_EVENT_REQ, 11));
+        assert!(ctrl.completion(ADMIN_QID).is_none());
+
+        // Resizing the namespace completes the outstanding request, once.
+        ctrl.replace_backend(128);
+        let comp = ctrl.completion(ADMIN_QID).unwrap();
+        assert_eq!({ comp.cid }, 11);
+        assert_eq!(status(&comp), STS_SUCCESS);
+        let event = u32::from(AER_TYPE_NOTICE)
+            | u32::from(AER_NOTICE_NS_ATTR_CHANGED) << 8
+            | u32::from(LOG_PAGE_CHANGED_NS_LIST) << 16;
+        assert_eq!({ comp.dw0 }, event);
+        assert!(ctrl.completion(ADMIN_QID).is_none());
+
+        // Further changes are masked until the host reads the log page.
+        ctrl.submit(ADMIN_QID, cmd(ADMIN_OPC_ASYNC_EVENT_REQ, 12));
+        ctrl.replace_backend(256);
+        assert!(ctrl.completion(ADMIN_QID).is_none());
+
+        // Reading it reports the namespace, and clears the list.
+        let page = get_log_page(LOG_PAGE_CHANGED_NS_LIST, 4096);
+        assert_eq!(status(&ctrl.admin(page)), STS_SUCCESS);
+        let ns: u32 = ctrl.mem().read(GuestAddr(DATA_BASE)).unwrap();
+        assert_eq!(ns, 1);
+        assert!(!ctrl.dev.state.lock().unwrap().ns_changed);
+        let page = get_log_page(LOG_PAGE_CHANGED_NS_LIST, 4096);
+        assert_eq!(status(&ctrl.admin(page)), STS_SUCCESS);
+        let ns: u32 = ctrl.mem().read(GuestAddr(DATA_BASE)).unwrap();
+        assert_eq!(ns, 0);
+
+        // With the log page read, the next change is reported.
+        ctrl.replace_backend(64);
+        let comp = ctrl.completion(ADMIN_QID).unwrap();
+        assert_eq!({ comp.cid }, 12);
+        assert_eq!({ comp.dw0 }, event);
+        assert!(ctrl.completion(ADMIN_QID).is_none());
+    }
 }
@@ -536,6 +536,14 @@ pub const NVM_OPC_FLUSH: u8 = 0x00;
 pub const NVM_OPC_WRITE: u8 = 0x01;
 /// Read Command Opcode
 pub const NVM_OPC_READ: u8 = 0x02;
+/// Write Zeroes Command Opcode
+///
+/// See NVMe 1.1 Section 6.16 Write Zeroes command
+pub const NVM_OPC_WRITE_ZEROES: u8 = 0x08;
+/// Dataset Management Command Opcode
+///
+/// See NVMe 1.0e Section 6.6 Dataset Management command
+pub const NVM_OPC_DATASET_MGMT: u8 = 0x09;
 
 // Generic Command Status values
 // See NVMe 1.0e Section 4.5.1.2.1, Figure 17 Status Code - Generic Command Status Values
@@ -605,6 +613,14 @@ pub const STS_INVALID_NS: u8 = 0xB;
 /// The command was aborted due to a protocol violation in a multi-command sequence.
 pub const STS_COMMAND_SEQ_ERR: u8 = 0xC;
 
+// Generic Command Status values specific to the NVM Command Set
+// See NVMe 1.0e Section 4.5.1.2.1, Figure 17 Status Code - Generic Command Status Values
+
+/// LBA Out of Range
+///
+/// The command references an LBA that exceeds the size of the namespace.
+pub const STS_LBA_RANGE: u8 = 0x80;
+
 // Command Specific Status values
 // See NVMe 1.0e Section 4.5.1.2.2, Figure 19 Status Code - Command Specific Status Values
 
@@ -626,6 +642,12 @@ pub const STS_DELETE_IO_Q_INVAL_QID: u8 = 0x1;
 /// Invalid Queue Deletion
 pub const STS_DELETE_IO_Q_INVAL_Q_DELETION: u8 = 0xC;
 
+/// Asynchronous Event Request Limit Exceeded
+pub const STS_ASYNC_EVENT_LIMIT_EXCEEDED: u8 = 0x5;
+
+/// Invalid Log Page
+pub const STS_GET_LOG_PAGE_INVAL_LOG_PAGE: u8 = 0x9;
+
 // NVM Command Specific Status values
 // See NVMe 1.0e Section 4.5.1.2.2, Figure 20 Status Code - Command Specific Status Values, NVM Command Set
 
@@ -696,6 +718,36 @@ pub const FEAT_ID_WRITE_ATOMIC: u8 = 0x0A;
 /// See NVMe 1.0e Section 5.12.1.11 Asynchronous Event Configuration (Feature Identifier 0Bh)
 pub const FEAT_ID_ASYNC_EVENT_CFG: u8 = 0x0B;
 
+// Asynchronous Event Configuration bits
+
+/// Send an asynchronous event notice when the attributes of a namespace (such
+/// as its size) change.
+///
+/// This is also used to report support for such notices in the Optional
+/// Asynchronous Events Supported (OAES) field of the Identify Controller data
+/// structure.
+///
+/// See NVMe 1.2 Section 5.14.1.11 Asynchronous Event Configuration (Feature Identifier 0Bh)
+pub const AEN_CFG_NS_ATTR: u32 = 1 << 8;
+
+// Asynchronous Event Request completion values
+// See NVMe 1.2 Section 5.2, Figure 45 Asynchronous Event Request - Completion Queue Entry Dword 0
+
+/// Asynchronous Event Type: Notice
+pub const AER_TYPE_NOTICE: u8 = 0x2;
+
+/// Notice Asynchronous Event Information: Namespace Attribute Changed
+///
+/// Reported with the Changed Namespace List log page.
+pub const AER_NOTICE_NS_ATTR_CHANGED: u8 = 0x0;
+
+// Log Page Identifiers
+
+/// Changed Namespace List
+///
+/// See NVMe 1.2 Section 5.10.1.4 Changed Namespace List (Log Identifier 04h)
+pub const LOG_PAGE_CHANGED_NS_LIST: u8 = 0x04;
+
 // Identify CNS values
 
 /// Identify - Namespace Structure
@@ -710,6 +762,18 @@ pub const IDENT_CNS_NAMESPACE: u8 = 0x0;
 /// See NVMe 1.0e Section 5.11
 pub const IDENT_CNS_CONTROLLER: u8 = 0x1;
 
+// Optional NVM Command Support (ONCS) bits
+
+/// The controller supports the Dataset Management command.
+///
+/// See NVMe 1.0e Section 5.11, Figure 66 Identify - Identify Controller Data Structure
+pub const ONCS_DATASET_MGMT: u16 = 1 << 2;
+
+/// The controller supports the Write Zeroes command.
+///
+/// See NVMe 1.1 Section 7.11, Figure 90 Identify - Identify Controller Data Structure
+pub const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
 /// The type of value specified in the Status Field (SF) of a command completion.
 ///
 /// See NVMe 1.0e Section 4.5.1.1 Status Code Type (SCT)
@@ -851,8 +915,16 @@ pub struct IdentifyController {
     /// reported as a power of two (2^n). A value of 0h indicates no restrictions on
     /// transfer size. The restrictions includes interleaved metadata.
     pub mdts: u8,
-    /// Reserved - Bytes 255:78
-    pub _resv1: [u8; 178],
+    /// Reserved - Bytes 91:78
+    pub _resv1: [u8; 14],
+    /// Optional Asynchronous Events Supported (OAES)
+    ///
+    /// Bit 8 indicates support for Namespace Attribute Notices and the
+    /// Changed Namespace List log page.  Other bits are reserved.
+    /// See NVMe 1.2 Section 5.11, Figure 90 Identify - Identify Controller Data Structure
+    pub oaes: u32,
+    /// Reserved - Bytes 255:96
+    pub _resv1b: [u8; 160],
 
     // bytes 256-511 - Admin Command Set Attributes & Optional Controller Capabilities
     /// Optional Admin Command Support (OACS)
@@ -937,7 +1009,8 @@ pub struct IdentifyController {
     pub nn: u32,
     /// Option NVM Command Support (ONCS)
     ///
-    /// Bits 15:3 are reserved.
+    /// Bits 15:4 are reserved.
+    /// Bit 3 indicates Write Zeroes command support (NVMe 1.1).
     /// Bit 2 indicates Dataset Management command support.
     /// Bit 1 indicates Write Uncorrectable command support.
     /// Bit 0 indicates Compare command support.
@@ -1025,7 +1098,9 @@ impl Default for IdentifyController {
             psd: [PowerStateDescriptor::default(); 32],
             vs: [0; 1024],
 
-            _resv1: [0; 178],
+            _resv1: [0; 14],
+            oaes: 0,
+            _resv1b: [0; 160],
             _resv2: [0; 247],
             _resv3: [0; 2],
             _resv4: [0; 173],
@@ -1170,6 +1245,107 @@ impl Default for IdentifyNamespace {
     }
 }
 
+/// SMART / Health Information Log
+///
+/// Health and usage information for the controller, accumulated over its life.
+///
+/// See NVMe 1.0e Section 5.10.1.2, Figure 61 Get Log Page - SMART / Health Information Log
+#[derive(Copy, Clone)]
+#[repr(C, packed(1))]
+pub struct SmartLog {
+    /// Critical Warning
+    ///
+    /// Bits 7:5 are reserved.
+    /// Bit 4 indicates the volatile memory backup device has failed.
+    /// Bit 3 indicates the media has been placed in read only mode.
+    /// Bit 2 indicates device reliability has been degraded.
+    /// Bit 1 indicates the temperature has exceeded a critical threshold.
+    /// Bit 0 indicates the available spare space has fallen below the threshold.
+    pub critical_warning: u8,
+    /// Temperature
+    ///
+    /// Current temperature of the controller and namespaces, in degrees Kelvin.
+    pub temperature: u16,
+    /// Available Spare
+    ///
+    /// Normalized percentage (0 to 100%) of the remaining spare capacity.
+    pub avail_spare: u8,
+    /// Available Spare Threshold
+    ///
+    /// When `avail_spare` falls below this percentage, an asynchronous event
+    /// may be reported.
+    pub avail_spare_thresh: u8,
+    /// Percentage Used
+    ///
+    /// Vendor specific estimate of the percentage of the device life used.
+    pub percent_used: u8,
+    /// Reserved - Bytes 31:6
+    pub _resv1: [u8; 26],
+    /// Data Units Read
+    ///
+    /// Number of 512 byte data units read by the host, reported in thousands
+    /// (i.e. a value of 1 corresponds to 1000 units of 512 bytes), rounded up.
+    pub data_units_read: u128,
+    /// Data Units Written
+    ///
+    /// Number of 512 byte data units written by the host, reported in the same
+    /// manner as `data_units_read`.
+    pub data_units_written: u128,
+    /// Host Read Commands
+    pub host_read_cmds: u128,
+    /// Host Write Commands
+    pub host_write_cmds: u128,
+    /// Controller Busy Time
+    ///
+    /// Amount of time, in minutes, the controller is busy with I/O commands.
+    pub ctrl_busy_time: u128,
+    /// Power Cycles
+    pub power_cycles: u128,
+    /// Power On Hours
+    pub power_on_hours: u128,
+    /// Unsafe Shutdowns
+    ///
+    /// Number of times the controller was powered off without first being
+    /// notified of the shutdown.
+    pub unsafe_shutdowns: u128,
+    /// Media Errors
+    ///
+    /// Number of occurrences where the controller detected an unrecovered data
+    /// integrity error.
+    pub media_errors: u128,
+    /// Number of Error Information Log Entries
+    pub num_err_log_entries: u128,
+    /// Reserved - Bytes 511:192
+    pub _resv2: [u8; 320],
+}
+
+// We can't derive Default since Default isn't impl'd
+// for [T; N] where N > 32 yet (rust #61415)
+impl Default for SmartLog {
+    fn default() -> Self {
+        Self {
+            critical_warning: 0,
+            temperature: 0,
+            avail_spare: 0,
+            avail_spare_thresh: 0,
+            percent_used: 0,
+            data_units_read: 0,
+            data_units_written: 0,
+            host_read_cmds: 0,
+            host_write_cmds: 0,
+            ctrl_busy_time: 0,
+            power_cycles: 0,
+            power_on_hours: 0,
+            unsafe_shutdowns: 0,
+            media_errors: 0,
+            num_err_log_entries: 0,
+
+            _resv1: [0; 26],
+            _resv2: [0; 320],
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1183,5 +1359,6 @@ mod test {
         assert_eq!(size_of::<IdentifyController>(), 4096);
         assert_eq!(size_of::<LbaFormat>(), 4);
         assert_eq!(size_of::<IdentifyNamespace>(), 4096);
+        assert_eq!(size_of::<SmartLog>(), 512);
     }
 }
@@ -85,7 +85,7 @@ impl AdminCmd {
                 AdminCmd::GetLogPage(GetLogPageCmd {
                     nsid: raw.nsid,
                     // Convert from 0's based dword
-                    len: (((raw.cdw10 & 0xFFF) >> 16) + 1) * 4,
+                    len: (((raw.cdw10 >> 16) & 0xFFF) + 1) * 4,
                     log_page_ident: LogPageIdent::from(raw.cdw10 as u8),
                     prp1: raw.prp1,
                     prp2: raw.prp2,
@@ -288,6 +288,8 @@ pub enum LogPageIdent {
     Smart,
     /// Firmware Slot Information Log PAge
     Firmware,
+    /// Changed Namespace List Log Page
+    ChangedNamespaceList,
     /// I/O Command Set Specific Log Page
     IOSpecifc(u8),
     /// Vendor Specific Log Page
@@ -301,7 +303,10 @@ impl From<u8> for LogPageIdent {
             1 => LogPageIdent::Error,
             2 => LogPageIdent::Smart,
             3 => LogPageIdent::Firmware,
-            0x04..=0x7F => LogPageIdent::Reserved,
+            bits::LOG_PAGE_CHANGED_NS_LIST => {
+                LogPageIdent::ChangedNamespaceList
+            }
+            0x05..=0x7F => LogPageIdent::Reserved,
             0x80..=0xBF => LogPageIdent::IOSpecifc(ident),
             0xC0..=0xFF => LogPageIdent::Vendor(ident),
         }
@@ -639,6 +644,10 @@ pub enum NvmCmd {
     Write(WriteCmd),
     /// Read data and metadata
     Read(ReadCmd),
+    /// Set a range of logical blocks to zero
+    WriteZeroes(WriteZeroesCmd),
+    /// Indicate attributes for ranges of logical blocks
+    DatasetMgmt(DatasetMgmtCmd),
     /// An unknown NVM command
     Unknown(SubmissionQueueEntry),
 }
@@ -668,6 +677,22 @@ impl NvmCmd {
                 prp1: raw.prp1,
                 prp2: raw.prp2,
             }),
+            bits::NVM_OPC_WRITE_ZEROES => {
+                NvmCmd::WriteZeroes(WriteZeroesCmd {
+                    slba: u64::from(raw.cdw11) << 32 | u64::from(raw.cdw10),
+                    // Convert from 0's based value
+                    nlb: raw.cdw12 as u16 + 1,
+                })
+            }
+            bits::NVM_OPC_DATASET_MGMT => {
+                NvmCmd::DatasetMgmt(DatasetMgmtCmd {
+                    // Convert from 0's based value
+                    nr: (raw.cdw10 & 0xFF) as u16 + 1,
+                    deallocate: raw.cdw11 & (1 << 2) != 0,
+                    prp1: raw.prp1,
+                    prp2: raw.prp2,
+                })
+            }
             _ => NvmCmd::Unknown(raw),
         };
         Ok(cmd)
@@ -738,6 +763,92 @@ impl ReadCmd {
     }
 }
 
+/// Write Zeroes Command Parameters
+#[derive(Debug)]
+pub struct WriteZeroesCmd {
+    /// Starting LBA (SLBA)
+    ///
+    /// 64-bit base address of the first logical block to be zeroed.
+    pub slba: u64,
+
+    /// Number of Logical Blocks (NLB)
+    ///
+    /// The number of logical blocks to be zeroed.
+    pub nlb: u16,
+}
+
+/// Dataset Management Command Parameters
+#[derive(Debug)]
+pub struct DatasetMgmtCmd {
+    /// Number of Ranges (NR)
+    ///
+    /// The number of 16 byte range entries specified in the data buffer.
+    pub nr: u16,
+
+    /// Attribute - Deallocate (AD)
+    ///
+    /// The host has no further use for the data in the specified ranges, so
+    /// the controller may deallocate them.  The other attributes (Integral
+    /// Dataset for Read/Write) are merely hints about access patterns.
+    pub deallocate: bool,
+
+    /// PRP Entry 1 (PRP1)
+    ///
+    /// The first PRP entry specifying the start of the range list.
+    prp1: u64,
+
+    /// PRP Entry 2 (PRP2)
+    ///
+    /// If PRP1 specifies enough space, then PRP2 is reserved. Otherwise
+    /// PRP2 specifies the second page holding the remainder of the list.
+    prp2: u64,
+}
+
+impl DatasetMgmtCmd {
+    /// Size (in bytes) of each range entry in the data buffer
+    const RANGE_SIZE: usize = 16;
+
+    /// Read the ranges specified by this command out of guest memory.
+    ///
+    /// Returns `None` if the data buffer is not accessible.
+    pub fn ranges(&self, mem: &MemCtx) -> Option<Vec<DsmRange>> {
+        let len = usize::from(self.nr) * Self::RANGE_SIZE;
+        let mut raw = vec![0u8; len];
+        let mut copied = 0;
+        for region in PrpIter::new(len as u64, self.prp1, self.prp2, mem) {
+            let mapping = mem.readable_region(&region)?;
+            copied += mapping.read_bytes(&mut raw[copied..]).ok()?;
+        }
+        if copied != len {
+            return None;
+        }
+
+        Some(
+            raw.chunks_exact(Self::RANGE_SIZE)
+                .map(|ent| DsmRange {
+                    // Bytes 3:0 are Context Attributes, which we ignore
+                    nlb: u32::from_le_bytes(ent[4..8].try_into().unwrap()),
+                    slba: u64::from_le_bytes(ent[8..16].try_into().unwrap()),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A range of logical blocks specified in a Dataset Management command
+///
+/// See NVMe 1.0e Section 6.6, Figure 112 Dataset Management - Range Definition
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DsmRange {
+    /// Starting LBA (SLBA)
+    pub slba: u64,
+
+    /// Length in Logical Blocks
+    ///
+    /// Unlike most other block counts, this is not a 0's based value.
+    pub nlb: u32,
+}
+
 /// Indicates the possible states of a [`PrpIter`].
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum PrpNext {
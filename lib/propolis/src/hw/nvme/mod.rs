@@ -2,9 +2,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::mem::size_of;
 use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Instant;
 
 use crate::accessors::Guard;
 use crate::block;
@@ -25,6 +27,8 @@ mod bits;
 mod cmds;
 mod queue;
 mod requests;
+#[cfg(test)]
+mod testutil;
 
 use bits::*;
 use queue::{CompQueue, Permit, QueueId, SubQueue};
@@ -139,13 +143,63 @@ struct CtrlState {
     admin_cq_base: u64,
 }
 
-/// The max number of completion or submission queues we support.
-/// Note: This includes the admin completion/submission queues.
-const MAX_NUM_QUEUES: usize = 16;
+/// The default number of I/O completion/submission queue pairs a device
+/// supports, if not otherwise configured.
+pub const DEFAULT_NUM_IO_QUEUES: u16 = 15;
+
+/// The max number of I/O completion/submission queue pairs a device may be
+/// configured to support.  This leaves enough MSI-X vectors for each I/O
+/// completion queue (as well as the admin completion queue) to have its own.
+pub const MAX_NUM_IO_QUEUES: u16 = NVME_MSIX_COUNT - 1;
+
+/// The default (and max) number of entries in an I/O queue.
+pub const MAX_IO_QUEUE_SIZE: u32 = queue::MAX_QUEUE_SIZE;
+
+/// The min number of entries which may be configured as the max size of an
+/// I/O queue.
+pub const MIN_IO_QUEUE_SIZE: u32 = queue::MIN_QUEUE_SIZE;
+
+/// The max length (in ASCII characters) of the model number a controller may
+/// be configured to report
+pub const MAX_MODEL_NUMBER_LEN: usize = 40;
+
+/// The max length (in ASCII characters) of the firmware revision a controller
+/// may be configured to report
+pub const MAX_FIRMWARE_REV_LEN: usize = 8;
+
+/// Details (beyond its serial number) with which a controller identifies
+/// itself to the guest in its Identify Controller data
+#[derive(Clone, Debug, Default)]
+pub struct CtrlIdentity {
+    /// Model Number (MN), left blank if not set.  Truncated to
+    /// [MAX_MODEL_NUMBER_LEN] characters.
+    pub model_number: Option<String>,
+    /// Firmware Revision (FR), left blank if not set.  Truncated to
+    /// [MAX_FIRMWARE_REV_LEN] characters.
+    pub firmware_revision: Option<String>,
+    /// IEEE OUI Identifier (IEEE), which defaults to [OXIDE_OUI]
+    pub ieee_oui: Option<[u8; 3]>,
+}
 
-/// The max number of I/O completion or submission queues we support.
-/// Always 1 less than the total number w/ the admin queues.
-const MAX_NUM_IO_QUEUES: usize = MAX_NUM_QUEUES - 1;
+/// Format `s` as an Identify Controller string field: ASCII, padded with
+/// spaces, and truncated to fit.  An unset string is left as all 0s.
+fn ident_string<const N: usize>(s: Option<&str>) -> [u8; N] {
+    let mut field = [0u8; N];
+    if let Some(s) = s {
+        let bytes = s.bytes().chain(std::iter::repeat(b' '));
+        for (f, b) in field.iter_mut().zip(bytes) {
+            *f = b;
+        }
+    }
+    field
+}
+
+/// The max number of Asynchronous Event Request commands which may be
+/// outstanding at once
+const MAX_OUTSTANDING_AERS: u8 = 4;
+
+/// Temperature (in degrees Kelvin) reported in the SMART / Health log page
+const SMART_TEMPERATURE: u16 = 311;
 
 /// NVMe Controller
 struct NvmeCtrl {
@@ -156,16 +210,48 @@ struct NvmeCtrl {
     msix_hdl: Option<pci::MsixHdl>,
 
     /// The list of Completion Queues handled by the controller
-    cqs: [Option<Arc<CompQueue>>; MAX_NUM_QUEUES],
+    ///
+    /// Sized to hold the admin queue and each of the I/O queues supported.
+    cqs: Vec<Option<Arc<CompQueue>>>,
 
     /// The list of Submission Queues handled by the controller
-    sqs: [Option<Arc<SubQueue>>; MAX_NUM_QUEUES],
+    ///
+    /// Sized to hold the admin queue and each of the I/O queues supported.
+    sqs: Vec<Option<Arc<SubQueue>>>,
 
     /// The Identify structure returned for Identify controller commands
     ctrl_ident: IdentifyController,
 
     /// The Identify structure returned for Identify namespace commands
     ns_ident: IdentifyNamespace,
+
+    /// Number of times the device has been powered on (reported in the SMART
+    /// / Health log page).  Resets of the controller through CC.EN do not
+    /// count towards this, only those of the device as a whole.
+    power_cycles: u64,
+
+    /// When the device was created, from which its power-on hours are
+    /// reported in the SMART / Health log page
+    powered_on: Instant,
+
+    /// Asynchronous Event Request commands held until there is an event to
+    /// report through them
+    aers: VecDeque<Permit>,
+
+    /// Asynchronous events awaiting an Asynchronous Event Request command, in
+    /// the form of the Dword 0 of the completion which will report them
+    pending_events: VecDeque<u32>,
+
+    /// Asynchronous Event Configuration, as set by the host
+    aen_config: u32,
+
+    /// Has the namespace changed since the Changed Namespace List log page
+    /// was last read?  Further Namespace Attribute Changed events are masked
+    /// until it is.
+    ns_changed: bool,
+
+    /// Volatile Write Cache Enable (WCE), as set by the host
+    write_cache: bool,
 }
 
 impl NvmeCtrl {
@@ -204,12 +290,15 @@ impl NvmeCtrl {
         size: u32,
         mem: &MemCtx,
     ) -> Result<Arc<CompQueue>, NvmeError> {
-        if (cqid as usize) >= MAX_NUM_QUEUES {
+        if (cqid as usize) >= self.cqs.len() {
             return Err(NvmeError::InvalidCompQueue(cqid));
         }
         if self.cqs[cqid as usize].is_some() {
             return Err(NvmeError::CompQueueAlreadyExists(cqid));
         }
+        if cqid != queue::ADMIN_QUEUE_ID && size > self.max_io_queue_size() {
+            return Err(queue::QueueCreateErr::InvalidSize.into());
+        }
         let msix_hdl = self
             .msix_hdl
             .as_ref()
@@ -232,12 +321,15 @@ impl NvmeCtrl {
         size: u32,
         mem: &MemCtx,
     ) -> Result<Arc<SubQueue>, NvmeError> {
-        if (sqid as usize) >= MAX_NUM_QUEUES {
+        if (sqid as usize) >= self.sqs.len() {
             return Err(NvmeError::InvalidSubQueue(sqid));
         }
         if self.sqs[sqid as usize].is_some() {
             return Err(NvmeError::SubQueueAlreadyExists(sqid));
         }
+        if sqid != queue::ADMIN_QUEUE_ID && size > self.max_io_queue_size() {
+            return Err(queue::QueueCreateErr::InvalidSize.into());
+        }
         let cq = self.get_cq(cqid)?;
         let sq = SubQueue::new(sqid, cq, size, base, mem)?;
         self.sqs[sqid as usize] = Some(sq.clone());
@@ -246,7 +338,7 @@ impl NvmeCtrl {
 
     /// Removes the [`CompQueue`] which corresponds to the given completion queue id (`cqid`).
     fn delete_cq(&mut self, cqid: QueueId) -> Result<(), NvmeError> {
-        if (cqid as usize) >= MAX_NUM_QUEUES
+        if (cqid as usize) >= self.cqs.len()
             || self.cqs[cqid as usize].is_none()
         {
             return Err(NvmeError::InvalidCompQueue(cqid));
@@ -269,7 +361,7 @@ impl NvmeCtrl {
     ///           in-flight IO requests for this SQ. But after this call, we'll no longer
     ///           answer any new doorbell requests for this SQ.
     fn delete_sq(&mut self, sqid: QueueId) -> Result<(), NvmeError> {
-        if (sqid as usize) >= MAX_NUM_QUEUES
+        if (sqid as usize) >= self.sqs.len()
             || self.sqs[sqid as usize].is_none()
         {
             return Err(NvmeError::InvalidSubQueue(sqid));
@@ -282,7 +374,7 @@ impl NvmeCtrl {
 
     /// Returns a reference to the [`CompQueue`] which corresponds to the given completion queue id (`cqid`).
     fn get_cq(&self, cqid: QueueId) -> Result<Arc<CompQueue>, NvmeError> {
-        if (cqid as usize) >= MAX_NUM_QUEUES {
+        if (cqid as usize) >= self.cqs.len() {
             return Err(NvmeError::InvalidCompQueue(cqid));
         }
         self.cqs[cqid as usize].clone().ok_or(NvmeError::InvalidCompQueue(cqid))
@@ -290,12 +382,24 @@ impl NvmeCtrl {
 
     /// Returns a reference to the [`SubQueue`] which corresponds to the given submission queue id (`cqid`).
     fn get_sq(&self, sqid: QueueId) -> Result<Arc<SubQueue>, NvmeError> {
-        if (sqid as usize) >= MAX_NUM_QUEUES {
+        if (sqid as usize) >= self.sqs.len() {
             return Err(NvmeError::InvalidSubQueue(sqid));
         }
         self.sqs[sqid as usize].clone().ok_or(NvmeError::InvalidSubQueue(sqid))
     }
 
+    /// Number of I/O queue pairs supported by the controller
+    fn num_io_queues(&self) -> u16 {
+        // The admin queue accounts for the remaining entry
+        (self.sqs.len() - 1) as u16
+    }
+
+    /// Max number of entries in an I/O queue, as advertised in CAP.MQES
+    fn max_io_queue_size(&self) -> u32 {
+        // Convert from 0's based
+        u32::from(self.ctrl.cap.mqes()) + 1
+    }
+
     /// Returns a reference to the Admin [`CompQueue`].
     fn get_admin_cq(&self) -> Result<Arc<CompQueue>, NvmeError> {
         self.get_cq(queue::ADMIN_QUEUE_ID)
@@ -396,6 +500,14 @@ impl NvmeCtrl {
         self.ctrl.cc = Configuration(0);
         self.ctrl.csts = Status(0);
 
+        // Outstanding async event requests went away with the admin queues,
+        // and the async event configuration reverts to its default.
+        self.abort_aers();
+        self.pending_events.clear();
+        self.aen_config = 0;
+        self.ns_changed = false;
+        self.write_cache = true;
+
         // The other registers (e.g. CAP/VS) we never modify
         // and thus don't need to do anything on reset
     }
@@ -421,6 +533,9 @@ impl NvmeCtrl {
 
     fn update_block_info(&mut self, info: block::DeviceInfo) {
         let nsze = info.total_size;
+        let lbads = info.block_size.trailing_zeros() as u8;
+        let (old_nsze, old_lbads) =
+            (self.ns_ident.nsze, self.ns_ident.lbaf[0].lbads);
         self.ns_ident = bits::IdentifyNamespace {
             // No thin provisioning so nsze == ncap == nuse
             nsze,
@@ -428,13 +543,56 @@ impl NvmeCtrl {
             nuse: nsze,
             ..self.ns_ident
         };
-        self.ns_ident.lbaf[0].lbads = info.block_size.trailing_zeros() as u8;
+        self.ns_ident.lbaf[0].lbads = lbads;
+
+        // A backend attached in place of another (or the first one, resized)
+        // may change the namespace out from under a running guest.
+        if self.ctrl.cc.enabled() && (nsze != old_nsze || lbads != old_lbads) {
+            self.ns_attr_changed();
+        }
+    }
+
+    /// Note that the attributes of the namespace have changed, queuing a
+    /// Namespace Attribute Changed event for the host if it has enabled them.
+    fn ns_attr_changed(&mut self) {
+        let masked = std::mem::replace(&mut self.ns_changed, true);
+        if masked || self.aen_config & AEN_CFG_NS_ATTR == 0 {
+            return;
+        }
+        self.pending_events.push_back(
+            u32::from(AER_TYPE_NOTICE)
+                | u32::from(AER_NOTICE_NS_ATTR_CHANGED) << 8
+                | u32::from(LOG_PAGE_CHANGED_NS_LIST) << 16,
+        );
+    }
+
+    /// Report any pending asynchronous events through outstanding
+    /// Asynchronous Event Request commands.
+    fn deliver_events(&mut self, mem: &MemCtx) {
+        while !self.aers.is_empty() {
+            let Some(event) = self.pending_events.pop_front() else {
+                break;
+            };
+            let permit = self.aers.pop_front().unwrap();
+            permit.complete(cmds::Completion::success_val(event), Some(mem));
+        }
     }
 
-    fn export(&self) -> migrate::NvmeCtrlV1 {
+    /// Complete any outstanding Asynchronous Event Request commands without
+    /// posting them to the (defunct) admin completion queue.
+    fn abort_aers(&mut self) {
+        for permit in self.aers.drain(..) {
+            permit.complete(
+                cmds::Completion::generic_err(STS_ABORT_SQ_DEL),
+                None,
+            );
+        }
+    }
+
+    fn export(&self) -> migrate::NvmeCtrlV3 {
         let cqs = self.cqs.iter().flatten().map(|cq| cq.export()).collect();
         let sqs = self.sqs.iter().flatten().map(|sq| sq.export()).collect();
-        migrate::NvmeCtrlV1 {
+        migrate::NvmeCtrlV3 {
             cap: self.ctrl.cap.0,
             cc: self.ctrl.cc.0,
             csts: self.ctrl.csts.0,
@@ -443,12 +601,17 @@ impl NvmeCtrl {
             asq_base: self.ctrl.admin_sq_base,
             cqs,
             sqs,
+            aer_cids: self.aers.iter().map(Permit::cid).collect(),
+            pending_events: self.pending_events.iter().copied().collect(),
+            aen_config: self.aen_config,
+            ns_changed: self.ns_changed,
+            write_cache: self.write_cache,
         }
     }
 
     fn import(
         &mut self,
-        state: migrate::NvmeCtrlV1,
+        state: migrate::NvmeCtrlV3,
         mem: &MemCtx,
     ) -> Result<(), MigrateStateError> {
         // TODO: bitstruct doesn't have a validation routine?
@@ -482,6 +645,24 @@ impl NvmeCtrl {
                 .import(sq)?;
         }
 
+        if !state.aer_cids.is_empty() {
+            let sq = self.get_admin_sq().map_err(|e| {
+                MigrateStateError::ImportFailed(format!(
+                    "NVMe: async event requests without admin SQ: {}",
+                    e
+                ))
+            })?;
+            self.aers = state
+                .aer_cids
+                .into_iter()
+                .map(|cid| sq.restore_permit(cid))
+                .collect();
+        }
+        self.pending_events = state.pending_events.into();
+        self.aen_config = state.aen_config;
+        self.ns_changed = state.ns_changed;
+        self.write_cache = state.write_cache;
+
         Ok(())
     }
 }
@@ -496,7 +677,13 @@ pub struct PciNvme {
 
     block_attach: block::DeviceAttachment,
 
-    block_tracking: block::tracking::Tracking<Permit>,
+    block_tracking: block::tracking::Tracking<requests::IoCmd>,
+
+    /// Dataset Management commands with ranges yet to be issued as discards
+    dsm_pending: Mutex<VecDeque<requests::IoCmd>>,
+
+    /// I/O commands being processed by the backend, which may be aborted
+    in_flight: Mutex<requests::InFlight>,
 
     /// Logger resource
     log: slog::Logger,
@@ -504,9 +691,17 @@ pub struct PciNvme {
 
 impl PciNvme {
     /// Create a new pci-nvme device with the given values
+    ///
+    /// The number of I/O queue pairs defaults to [DEFAULT_NUM_IO_QUEUES] and is
+    /// clamped to [MAX_NUM_IO_QUEUES].  The max number of entries per I/O queue
+    /// defaults to [MAX_IO_QUEUE_SIZE], and is clamped to the range starting at
+    /// [MIN_IO_QUEUE_SIZE].
     pub fn create(
         serial_number: String,
+        identity: CtrlIdentity,
         mdts: Option<u8>,
+        num_io_queues: Option<u16>,
+        io_queue_size: Option<u32>,
         log: slog::Logger,
     ) -> Arc<Self> {
         let builder = pci::Builder::new(pci::Ident {
@@ -537,7 +732,9 @@ impl PciNvme {
             vid: VENDOR_OXIDE,
             ssvid: VENDOR_OXIDE,
             sn,
-            ieee: OXIDE_OUI,
+            mn: ident_string(identity.model_number.as_deref()),
+            fr: ident_string(identity.firmware_revision.as_deref()),
+            ieee: identity.ieee_oui.unwrap_or(OXIDE_OUI),
             mdts: mdts.unwrap_or(0),
             // We use standard Completion/Submission Queue Entry structures with no extra
             // data, so required (minimum) == maximum
@@ -548,6 +745,14 @@ impl PciNvme {
             nn: 1,
             // bit 0 indicates volatile write cache is present
             vwc: 1,
+            oncs: bits::ONCS_WRITE_ZEROES | bits::ONCS_DATASET_MGMT,
+            // 0's based value; Abort commands are completed as they are
+            // processed, so the recommended limit of 4 is easily met.
+            acl: 3,
+            // 0's based value
+            aerl: MAX_OUTSTANDING_AERS - 1,
+            // Changes to the namespace (e.g. its size) can be reported
+            oaes: bits::AEN_CFG_NS_ATTR,
             ..Default::default()
         };
 
@@ -566,10 +771,16 @@ impl PciNvme {
         //  DSTRD   = 0 => 2^(2+0) byte stride for doorbell registers
         //  MPSMIN  = 0 => 2^(12+0) bytes, 4K
         //  MPSMAX  = 0 => 2^(12+0) bytes, 4K
+        let num_io_queues = num_io_queues
+            .unwrap_or(DEFAULT_NUM_IO_QUEUES)
+            .clamp(1, MAX_NUM_IO_QUEUES);
+        let io_queue_size = io_queue_size
+            .unwrap_or(MAX_IO_QUEUE_SIZE)
+            .clamp(MIN_IO_QUEUE_SIZE, MAX_IO_QUEUE_SIZE);
         let cap = Capabilities(0)
-            // Allow up to the spec max supported queue size
+            // Allow up to the configured max queue size
             // converted to 0's based
-            .with_mqes((queue::MAX_QUEUE_SIZE - 1) as u16)
+            .with_mqes((io_queue_size - 1) as u16)
             // I/O Queues must be physically contiguous
             .with_cqr(true)
             // We support the NVM command set
@@ -595,10 +806,17 @@ impl PciNvme {
         let state = NvmeCtrl {
             ctrl: CtrlState { cap, cc, csts, ..Default::default() },
             msix_hdl: None,
-            cqs: Default::default(),
-            sqs: Default::default(),
+            cqs: vec![None; usize::from(num_io_queues) + 1],
+            sqs: vec![None; usize::from(num_io_queues) + 1],
             ctrl_ident,
             ns_ident,
+            power_cycles: 1,
+            powered_on: Instant::now(),
+            aers: VecDeque::new(),
+            pending_events: VecDeque::new(),
+            aen_config: 0,
+            ns_changed: false,
+            write_cache: true,
         };
 
         let pci_state = builder
@@ -616,6 +834,8 @@ impl PciNvme {
             block_tracking: block::tracking::Tracking::new(
                 weak.clone() as Weak<dyn block::Device>
             ),
+            dsm_pending: Mutex::new(VecDeque::new()),
+            in_flight: Mutex::new(requests::InFlight::default()),
             log,
         })
     }
@@ -623,6 +843,7 @@ impl PciNvme {
     /// Service a write to the NVMe Controller Configuration from the VM
     fn ctrlr_cfg_write(&self, new: Configuration) -> Result<(), NvmeError> {
         let mut state = self.state.lock().unwrap();
+        let write_cache = state.write_cache;
 
         // Propogate any CC changes first
         if state.ctrl.cc != new {
@@ -664,6 +885,13 @@ impl PciNvme {
             state.ctrl.csts.set_shst(ShutdownStatus::Normal);
         }
 
+        // A controller reset reverts the write cache to being enabled
+        if state.write_cache != write_cache {
+            let write_cache = state.write_cache;
+            drop(state);
+            self.update_cache_mode(write_cache);
+        }
+
         Ok(())
     }
 
@@ -896,6 +1124,10 @@ impl PciNvme {
         }
         let mem = mem.unwrap();
 
+        // Write cache state set by the host, to be passed on to the backend
+        // once the controller state is unlocked
+        let mut cache_change = None;
+
         while let Some((sub, permit, _idx)) = sq.pop(&mem) {
             use cmds::AdminCmd;
 
@@ -910,32 +1142,35 @@ impl PciNvme {
                 AdminCmd::Unknown(sub)
             });
             let comp = match cmd {
-                AdminCmd::Abort(cmd) => state.acmd_abort(&cmd),
+                AdminCmd::Abort(cmd) => state.acmd_abort(&cmd, &self.in_flight),
                 AdminCmd::CreateIOCompQ(cmd) => {
                     state.acmd_create_io_cq(&cmd, &mem)
                 }
                 AdminCmd::CreateIOSubQ(cmd) => {
                     state.acmd_create_io_sq(&cmd, &mem)
                 }
-                AdminCmd::GetLogPage(cmd) => {
-                    state.acmd_get_log_page(&cmd, &mem)
-                }
+                AdminCmd::GetLogPage(cmd) => state.acmd_get_log_page(
+                    &cmd,
+                    &mem,
+                    &self.block_attach.stats().snapshot(),
+                ),
                 AdminCmd::Identify(cmd) => state.acmd_identify(&cmd, &mem),
                 AdminCmd::GetFeatures(cmd) => state.acmd_get_features(&cmd),
-                AdminCmd::SetFeatures(cmd) => state.acmd_set_features(&cmd),
+                AdminCmd::SetFeatures(cmd) => {
+                    let comp = state.acmd_set_features(&cmd);
+                    if matches!(cmd.fid, cmds::FeatureIdent::VolatileWriteCache)
+                    {
+                        cache_change = Some(state.write_cache);
+                    }
+                    comp
+                }
                 AdminCmd::DeleteIOCompQ(cqid) => state.acmd_delete_io_cq(cqid),
                 AdminCmd::DeleteIOSubQ(sqid) => state.acmd_delete_io_sq(sqid),
                 AdminCmd::AsyncEventReq => {
-                    // async event requests do not appear to be an optional
-                    // feature but are not yet supported. The only
-                    // command-specific error we could return is "async event
-                    // limit exceeded".
-                    //
-                    // qemu's emulated NVMe also does not support async events
-                    // but returns invalid opcode with the do-not-retry flag
-                    // set. Do the same so that guest drivers that check for
-                    // this can detect it and stop posting async events.
-                    cmds::Completion::generic_err(bits::STS_INVAL_OPC).dnr()
+                    // The request is held (rather than completed here) until
+                    // there is an event for it to report.
+                    state.acmd_async_event_req(permit, &mem);
+                    continue;
                 }
                 AdminCmd::Unknown(_) => {
                     cmds::Completion::generic_err(bits::STS_INTERNAL_ERR)
@@ -948,12 +1183,26 @@ impl PciNvme {
         // Notify for any newly added completions
         cq.fire_interrupt();
 
+        drop(state);
+        if let Some(write_cache) = cache_change {
+            self.update_cache_mode(write_cache);
+        }
+
         Ok(())
     }
 
     fn mem_access(&self) -> Option<Guard<MemCtx>> {
         self.pci_state.acc_mem.access()
     }
+
+    /// Have the backend honor the Volatile Write Cache state set by the host:
+    /// with the cache disabled, writes must be durable when they complete.
+    fn update_cache_mode(&self, write_cache: bool) {
+        self.block_attach.set_cache_mode(match write_cache {
+            true => block::CacheMode::WriteBack,
+            false => block::CacheMode::Synchronous,
+        });
+    }
 }
 
 impl pci::Device for PciNvme {
@@ -1016,16 +1265,23 @@ impl MigrateMulti for PciNvme {
         offer: &mut PayloadOffers,
         ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError> {
-        let input: migrate::NvmeCtrlV1 = offer.take()?;
+        let input: migrate::NvmeCtrlV3 = offer.take()?;
 
         let mut ctrl = self.state.lock().unwrap();
         ctrl.import(input, ctx.mem)?;
+        let write_cache = ctrl.write_cache;
         drop(ctrl);
+        self.update_cache_mode(write_cache);
 
         MigrateMulti::import(&self.pci_state, offer, ctx)?;
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::NvmeCtrlV3>();
+        MigrateMulti::schemas(&self.pci_state, schemas)
+    }
 }
 
 impl Lifecycle for PciNvme {
@@ -1035,18 +1291,29 @@ impl Lifecycle for PciNvme {
 
     fn reset(&self) {
         let mut ctrl = self.state.lock().unwrap();
+        let write_cache = ctrl.write_cache;
         ctrl.reset();
+        ctrl.power_cycles += 1;
+        drop(ctrl);
+        if !write_cache {
+            self.update_cache_mode(true);
+        }
         self.pci_state.reset(self);
     }
 
     fn pause(&self) {
         self.block_attach.pause();
+        self.complete_pending_dsm();
     }
 
     fn resume(&self) {
         self.block_attach.resume();
     }
 
+    fn halt(&self) {
+        self.state.lock().unwrap().abort_aers();
+    }
+
     fn paused(&self) -> BoxFuture<'static, ()> {
         Box::pin(self.block_tracking.none_outstanding())
     }
@@ -1064,7 +1331,7 @@ pub mod migrate {
     use super::queue::migrate::{NvmeCompQueueV1, NvmeSubQueueV1};
 
     #[derive(Deserialize, Serialize)]
-    pub struct NvmeCtrlV1 {
+    pub struct NvmeCtrlV3 {
         pub cap: u64,
         pub cc: u32,
         pub csts: u32,
@@ -1075,10 +1342,17 @@ pub mod migrate {
 
         pub cqs: Vec<NvmeCompQueueV1>,
         pub sqs: Vec<NvmeSubQueueV1>,
+
+        /// Command IDs of outstanding Asynchronous Event Requests
+        pub aer_cids: Vec<u16>,
+        pub pending_events: Vec<u32>,
+        pub aen_config: u32,
+        pub ns_changed: bool,
+        pub write_cache: bool,
     }
-    impl Schema<'_> for NvmeCtrlV1 {
+    impl Schema<'_> for NvmeCtrlV3 {
         fn id() -> SchemaId {
-            ("nvme-ctrl", 1)
+            ("nvme-ctrl", 3)
         }
     }
 }
@@ -1172,7 +1446,7 @@ lazy_static! {
             // CAP.DSTRD = 0 hence 0 stride and doorbells are 4 bytes apart
             (CtrlrReg::DoorBellAdminSQ, 4),
             (CtrlrReg::DoorBellAdminCQ, 4),
-            (CtrlrReg::IOQueueDoorBells, 8 * MAX_NUM_IO_QUEUES),
+            (CtrlrReg::IOQueueDoorBells, 8 * MAX_NUM_IO_QUEUES as usize),
             // Left as 0 and adjusted below
             (CtrlrReg::Reserved, 0),
         ];
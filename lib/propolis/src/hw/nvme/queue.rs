@@ -31,7 +31,7 @@ pub type QueueId = u16;
 /// Note: One entry will always be unavailable for use due to the Head and Tail
 ///       entry pointer definitions.
 /// See NVMe 1.0e Section 4.1.3 Queue Size
-const MIN_QUEUE_SIZE: u32 = 2;
+pub const MIN_QUEUE_SIZE: u32 = 2;
 
 /// The maximum number of entries in either a Completion or Submission Queue.
 ///
@@ -448,6 +448,16 @@ impl SubQueue {
         }
     }
 
+    /// Recreate the [Permit] for a command which was popped from this queue,
+    /// and still outstanding, when the device state was exported.
+    ///
+    /// The capacity reserved for the command in the Completion Queue is
+    /// expected to already be accounted for by the imported state of that
+    /// queue.
+    pub(super) fn restore_permit(self: &Arc<SubQueue>, cid: u16) -> Permit {
+        ProtoPermit::new(&self.cq, self).promote(cid)
+    }
+
     /// Returns the ID of this Submission Queue.
     pub(super) fn id(&self) -> QueueId {
         self.id
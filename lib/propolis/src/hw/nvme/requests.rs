@@ -2,6 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
 use crate::{
     accessors::MemAccessor,
     block::{self, Operation, Request, Result as BlockResult},
@@ -11,6 +15,86 @@ use crate::{
 
 use super::{cmds::NvmCmd, queue::Permit, PciNvme};
 
+/// Byte ranges of a Dataset Management command which remain to be discarded.
+///
+/// The block layer only accommodates a single range per request, so the
+/// ranges of a command are issued to the backend one after another, with the
+/// command completed once the last of them is done.
+pub(super) type DsmRanges = VecDeque<(block::ByteOffset, block::ByteLen)>;
+
+/// An I/O command being processed by the backend
+pub(super) struct IoCmd {
+    permit: Permit,
+    /// Ranges of a Dataset Management command yet to be issued
+    ranges: DsmRanges,
+    /// Identifies the command among those which are [InFlight]
+    token: u64,
+}
+
+/// I/O commands submitted to the backend and not yet completed, so that they
+/// can be found by the Abort command.
+///
+/// A request cannot be withdrawn from the backend once submitted, so aborting
+/// a command does not hasten its completion.  It is instead marked such that,
+/// once the backend is done with it, the command completes with a status of
+/// Command Abort Requested (rather than the result from the backend), letting
+/// the guest know that the outcome of the command is indeterminate.
+#[derive(Default)]
+pub(super) struct InFlight {
+    cmds: HashMap<(u16, u16), InFlightCmd>,
+    next_token: u64,
+}
+struct InFlightCmd {
+    token: u64,
+    /// When the command was submitted to the backend
+    issued: Instant,
+    abort_requested: bool,
+}
+impl InFlight {
+    fn insert(&mut self, sqid: u16, cid: u16) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        // Should the guest reuse the ID of a command still in flight (as it
+        // may, having reset the controller in the meantime), only the newer
+        // command can be aborted.
+        self.cmds.insert(
+            (sqid, cid),
+            InFlightCmd {
+                token,
+                issued: Instant::now(),
+                abort_requested: false,
+            },
+        );
+        token
+    }
+
+    fn remove(
+        &mut self,
+        sqid: u16,
+        cid: u16,
+        token: u64,
+    ) -> Option<InFlightCmd> {
+        match self.cmds.entry((sqid, cid)) {
+            Entry::Occupied(ent) if ent.get().token == token => {
+                Some(ent.remove())
+            }
+            _ => None,
+        }
+    }
+
+    /// Mark command `cid` from Submission Queue `sqid` as aborted, returning
+    /// how long it has been in flight, or `None` if no such command is.
+    pub(super) fn request_abort(
+        &mut self,
+        sqid: u16,
+        cid: u16,
+    ) -> Option<Duration> {
+        let cmd = self.cmds.get_mut(&(sqid, cid))?;
+        cmd.abort_requested = true;
+        Some(cmd.issued.elapsed())
+    }
+}
+
 #[usdt::provider(provider = "propolis")]
 mod probes {
     fn nvme_read_enqueue(qid: u16, idx: u16, cid: u16, off: u64, sz: u64) {}
@@ -22,6 +106,21 @@ mod probes {
     fn nvme_flush_enqueue(qid: u16, idx: u16, cid: u16) {}
     fn nvme_flush_complete(qid: u16, cid: u16, res: u8) {}
 
+    fn nvme_write_zeroes_enqueue(
+        qid: u16,
+        idx: u16,
+        cid: u16,
+        off: u64,
+        sz: u64,
+    ) {
+    }
+    fn nvme_write_zeroes_complete(qid: u16, cid: u16, res: u8) {}
+
+    fn nvme_dsm_enqueue(qid: u16, idx: u16, cid: u16, nr: u16) {}
+    fn nvme_dsm_complete(qid: u16, cid: u16, res: u8) {}
+
+    fn nvme_aborted_complete(qid: u16, cid: u16, elapsed_ns: u64) {}
+
     fn nvme_raw_cmd(
         qid: u16,
         cdw0nsid: u64,
@@ -38,17 +137,42 @@ impl block::Device for PciNvme {
     }
 
     fn on_attach(&self, info: block::DeviceInfo) {
-        self.state.lock().unwrap().update_block_info(info);
+        let mut state = self.state.lock().unwrap();
+        state.update_block_info(info);
+        if let Some(mem) = self.mem_access() {
+            state.deliver_events(&mem);
+        }
     }
 
     fn next(&self) -> Option<Request> {
-        let (req, permit) = self.next_req()?;
-        Some(self.block_tracking.track(req, permit))
+        let (req, cmd) = self.next_dsm_range().or_else(|| {
+            let (req, permit, ranges) = self.next_req()?;
+            let token = self
+                .in_flight
+                .lock()
+                .unwrap()
+                .insert(permit.sqid(), permit.cid());
+            Some((req, IoCmd { permit, ranges, token }))
+        })?;
+        Some(self.block_tracking.track(req, cmd))
     }
 
     fn complete(&self, res: BlockResult, id: block::ReqId) {
-        let (op, permit) = self.block_tracking.complete(id, res);
-        self.complete_req(op, res, permit);
+        let (op, cmd) = self.block_tracking.complete(id, res);
+        let res = match (op, res) {
+            // Deallocation is advisory, so a backend which cannot discard
+            // data has not failed the command.
+            (Operation::Discard(..), BlockResult::Unsupported) => {
+                BlockResult::Success
+            }
+            (_, res) => res,
+        };
+        if !res.is_err() && !cmd.ranges.is_empty() {
+            self.dsm_pending.lock().unwrap().push_back(cmd);
+            self.block_attach.notify();
+            return;
+        }
+        self.complete_req(op, res, cmd);
     }
 
     fn accessor_mem(&self) -> MemAccessor {
@@ -57,9 +181,64 @@ impl block::Device for PciNvme {
 }
 
 impl PciNvme {
+    /// Issue the next range of a partially-completed Dataset Management
+    /// command (if any) to the underlying Block Device.
+    fn next_dsm_range(&self) -> Option<(Request, IoCmd)> {
+        let mut cmd = self.dsm_pending.lock().unwrap().pop_front()?;
+        let (off, len) =
+            cmd.ranges.pop_front().expect("pending DSM has ranges remaining");
+        Some((Request::new_discard(off, len), cmd))
+    }
+
+    /// Complete any Dataset Management commands which have ranges yet to be
+    /// issued, leaving those ranges in place.  This is permissible since
+    /// deallocation is advisory, and spares us from holding commands across
+    /// a pause or reset of the device.
+    pub(super) fn complete_pending_dsm(&self) {
+        let pending = std::mem::take(&mut *self.dsm_pending.lock().unwrap());
+        let guard = self.mem_access();
+        for cmd in pending {
+            let qid = cmd.permit.sqid();
+            let cid = cmd.permit.cid();
+            probes::nvme_dsm_complete!(|| (
+                qid,
+                cid,
+                BlockResult::Success as u8
+            ));
+            self.finish_cmd(cmd, Completion::success(), guard.as_deref());
+        }
+    }
+
+    /// Complete an I/O command with `comp`, unless the guest has asked for
+    /// the command to be aborted in the meantime.
+    fn finish_cmd(&self, cmd: IoCmd, comp: Completion, mem: Option<&MemCtx>) {
+        let IoCmd { permit, token, .. } = cmd;
+        let qid = permit.sqid();
+        let cid = permit.cid();
+        let entry = self.in_flight.lock().unwrap().remove(qid, cid, token);
+        let comp = match entry {
+            Some(entry) if entry.abort_requested => {
+                let elapsed = entry.issued.elapsed();
+                probes::nvme_aborted_complete!(|| (
+                    qid,
+                    cid,
+                    elapsed.as_nanos() as u64
+                ));
+                slog::info!(self.log, "aborted command completed";
+                    "sqid" => qid,
+                    "cid" => cid,
+                    "elapsed" => ?elapsed,
+                );
+                Completion::generic_err(bits::STS_ABORT_REQ)
+            }
+            _ => comp,
+        };
+        permit.complete(comp, mem);
+    }
+
     /// Pop an available I/O request off of a Submission Queue to begin
     /// processing by the underlying Block Device.
-    fn next_req(&self) -> Option<(Request, Permit)> {
+    fn next_req(&self) -> Option<(Request, Permit, DsmRanges)> {
         let state = self.state.lock().unwrap();
 
         let mem = self.mem_access()?;
@@ -108,7 +287,7 @@ impl PciNvme {
                             size as usize,
                             bufs,
                         );
-                        return Some((req, permit));
+                        return Some((req, permit, DsmRanges::new()));
                     }
                     Ok(NvmCmd::Read(cmd)) => {
                         let off = state.nlb_to_size(cmd.slba as usize) as u64;
@@ -129,12 +308,81 @@ impl PciNvme {
                             size as usize,
                             bufs,
                         );
-                        return Some((req, permit));
+                        return Some((req, permit, DsmRanges::new()));
                     }
                     Ok(NvmCmd::Flush) => {
                         probes::nvme_flush_enqueue!(|| (qid, idx, cid));
                         let req = Request::new_flush();
-                        return Some((req, permit));
+                        return Some((req, permit, DsmRanges::new()));
+                    }
+                    Ok(NvmCmd::WriteZeroes(cmd)) => {
+                        // No data is transferred for Write Zeroes, so it is
+                        // not subject to the MDTS limit.
+                        let off = state.nlb_to_size(cmd.slba as usize) as u64;
+                        let size = state.nlb_to_size(cmd.nlb as usize) as u64;
+
+                        probes::nvme_write_zeroes_enqueue!(|| (
+                            qid, idx, cid, off, size
+                        ));
+
+                        let req = Request::new_write_zeroes(
+                            off as usize,
+                            size as usize,
+                        );
+                        return Some((req, permit, DsmRanges::new()));
+                    }
+                    Ok(NvmCmd::DatasetMgmt(cmd)) => {
+                        probes::nvme_dsm_enqueue!(|| (qid, idx, cid, cmd.nr));
+
+                        if !cmd.deallocate {
+                            // The remaining attributes are only hints about
+                            // how the ranges will be accessed, and can be
+                            // safely ignored.
+                            permit.complete(Completion::success(), Some(&mem));
+                            continue;
+                        }
+                        let Some(ranges) = cmd.ranges(&mem) else {
+                            permit.complete(
+                                Completion::generic_err(
+                                    bits::STS_DATA_XFER_ERR,
+                                ),
+                                Some(&mem),
+                            );
+                            continue;
+                        };
+
+                        let ranges: Vec<_> =
+                            ranges.into_iter().filter(|r| r.nlb != 0).collect();
+                        let nsze = state.ns_ident.nsze;
+                        let out_of_range = ranges.iter().any(|r| {
+                            r.slba
+                                .checked_add(u64::from(r.nlb))
+                                .map_or(true, |end| end > nsze)
+                        });
+                        if out_of_range {
+                            permit.complete(
+                                Completion::generic_err(bits::STS_LBA_RANGE)
+                                    .dnr(),
+                                Some(&mem),
+                            );
+                            continue;
+                        }
+
+                        let mut ranges: DsmRanges = ranges
+                            .into_iter()
+                            .map(|r| {
+                                (
+                                    state.nlb_to_size(r.slba as usize),
+                                    state.nlb_to_size(r.nlb as usize),
+                                )
+                            })
+                            .collect();
+                        let Some((off, len)) = ranges.pop_front() else {
+                            permit.complete(Completion::success(), Some(&mem));
+                            continue;
+                        };
+                        let req = Request::new_discard(off, len);
+                        return Some((req, permit, ranges));
                     }
                     Ok(NvmCmd::Unknown(_)) | Err(_) => {
                         // For any other unrecognized or malformed command,
@@ -152,9 +400,9 @@ impl PciNvme {
 
     /// Place the operation result (success or failure) onto the corresponding
     /// Completion Queue.
-    fn complete_req(&self, op: Operation, res: BlockResult, permit: Permit) {
-        let qid = permit.sqid();
-        let cid = permit.cid();
+    fn complete_req(&self, op: Operation, res: BlockResult, cmd: IoCmd) {
+        let qid = cmd.permit.sqid();
+        let cid = cmd.permit.cid();
         let resnum = res as u8;
         match op {
             Operation::Read(..) => {
@@ -166,9 +414,189 @@ impl PciNvme {
             Operation::Flush => {
                 probes::nvme_flush_complete!(|| (qid, cid, resnum));
             }
+            Operation::WriteZeroes(..) => {
+                probes::nvme_write_zeroes_complete!(|| (qid, cid, resnum));
+            }
+            Operation::Discard(..) => {
+                probes::nvme_dsm_complete!(|| (qid, cid, resnum));
+            }
         }
 
         let guard = self.mem_access();
-        permit.complete(Completion::from(res), guard.as_deref());
+        self.finish_cmd(cmd, Completion::from(res), guard.as_deref());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block::{Operation, Result as BlockResult};
+    use crate::common::GuestAddr;
+    use crate::hw::nvme::bits::*;
+    use crate::hw::nvme::testutil::*;
+
+    /// An Abort command for command `cid` from Submission Queue `sqid`
+    fn abort_cmd(sqid: u16, cid: u16) -> SubmissionQueueEntry {
+        SubmissionQueueEntry {
+            cdw10: u32::from(cid) << 16 | u32::from(sqid),
+            ..cmd(ADMIN_OPC_ABORT, 100)
+        }
+    }
+
+    #[test]
+    fn abort_in_flight() {
+        let mut ctrl = TestCtrl::new(64);
+        ctrl.submit(IO_QID, io_cmd(NVM_OPC_WRITE, 7, 0, 1));
+        let req = ctrl.backend.take().unwrap();
+
+        // Bit 0 of Dword 0 is cleared when the command is to be aborted.
+        let comp = ctrl.admin(abort_cmd(IO_QID, 7));
+        assert_eq!(status(&comp), STS_SUCCESS);
+        assert_eq!({ comp.dw0 } & 1, 0);
+
+        // The command still completes only once the backend is done with it.
+        assert!(ctrl.completion(IO_QID).is_none());
+        req.complete(BlockResult::Success);
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!({ comp.cid }, 7);
+        assert_eq!(status(&comp), STS_ABORT_REQ);
+    }
+
+    #[test]
+    fn abort_unknown_command() {
+        let mut ctrl = TestCtrl::new(64);
+
+        // Nothing is in flight, so nothing is aborted.
+        let comp = ctrl.admin(abort_cmd(IO_QID, 7));
+        assert_eq!(status(&comp), STS_SUCCESS);
+        assert_eq!({ comp.dw0 } & 1, 1);
+
+        // Nor is one in flight under another ID.
+        ctrl.submit(IO_QID, io_cmd(NVM_OPC_READ, 8, 0, 1));
+        let req = ctrl.backend.take().unwrap();
+        let comp = ctrl.admin(abort_cmd(IO_QID, 7));
+        assert_eq!({ comp.dw0 } & 1, 1);
+        req.complete(BlockResult::Success);
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!(status(&comp), STS_SUCCESS);
+
+        // An Abort naming a queue which doesn't exist is itself invalid.
+        let comp = ctrl.admin(abort_cmd(IO_QID + 1, 7));
+        assert_eq!(status(&comp), STS_INVAL_FIELD);
+    }
+
+    #[test]
+    fn abort_reused_cid() {
+        let mut ctrl = TestCtrl::new(64);
+
+        // With the same ID in flight twice, only the newer command is
+        // aborted.
+        ctrl.submit(IO_QID, io_cmd(NVM_OPC_WRITE, 3, 0, 1));
+        ctrl.submit(IO_QID, io_cmd(NVM_OPC_WRITE, 3, 1, 1));
+        let older = ctrl.backend.take().unwrap();
+        let newer = ctrl.backend.take().unwrap();
+        assert_eq!({ ctrl.admin(abort_cmd(IO_QID, 3)).dw0 } & 1, 0);
+        older.complete(BlockResult::Success);
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!(status(&comp), STS_SUCCESS);
+        newer.complete(BlockResult::Success);
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!(status(&comp), STS_ABORT_REQ);
+
+        // Nor is a later command given the ID of an aborted one.
+        ctrl.submit(IO_QID, io_cmd(NVM_OPC_WRITE, 3, 2, 1));
+        ctrl.backend.take().unwrap().complete(BlockResult::Success);
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!(status(&comp), STS_SUCCESS);
+    }
+
+    /// A Dataset Management command to deallocate `ranges` (of starting LBA
+    /// and length), which are placed in the data buffer
+    fn dsm_cmd(
+        ctrl: &TestCtrl,
+        cid: u16,
+        ranges: &[(u64, u32)],
+    ) -> SubmissionQueueEntry {
+        let ents: Vec<[u8; 16]> = ranges
+            .iter()
+            .map(|(slba, nlb)| {
+                let mut ent = [0u8; 16];
+                ent[4..8].copy_from_slice(&nlb.to_le_bytes());
+                ent[8..16].copy_from_slice(&slba.to_le_bytes());
+                ent
+            })
+            .collect();
+        assert!(ctrl.mem().write_many(GuestAddr(DATA_BASE), &ents));
+        SubmissionQueueEntry {
+            prp1: DATA_BASE,
+            cdw10: ranges.len() as u32 - 1,
+            // Attribute - Deallocate (AD)
+            cdw11: 1 << 2,
+            ..cmd(NVM_OPC_DATASET_MGMT, cid)
+        }
+    }
+
+    #[test]
+    fn dsm_multiple_ranges() {
+        let mut ctrl = TestCtrl::new(64);
+        let cmd = dsm_cmd(&ctrl, 5, &[(0, 4), (8, 2), (63, 1)]);
+        ctrl.submit(IO_QID, cmd);
+
+        // Each range is discarded in turn, with the command completing only
+        // once the last of them has been.
+        let bs = BLOCK_SIZE as usize;
+        for (off, len) in [(0, 4 * bs), (8 * bs, 2 * bs), (63 * bs, bs)] {
+            assert!(ctrl.completion(IO_QID).is_none());
+            let req = ctrl.backend.take().unwrap();
+            assert_eq!(req.oper(), Operation::Discard(off, len));
+            assert!(ctrl.backend.take().is_none());
+            req.complete(BlockResult::Success);
+        }
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!({ comp.cid }, 5);
+        assert_eq!(status(&comp), STS_SUCCESS);
+        assert!(ctrl.completion(IO_QID).is_none());
+    }
+
+    #[test]
+    fn dsm_zero_length_range() {
+        let mut ctrl = TestCtrl::new(64);
+
+        // Ranges of no blocks are skipped.
+        let cmd = dsm_cmd(&ctrl, 5, &[(0, 0), (4, 1), (8, 0)]);
+        ctrl.submit(IO_QID, cmd);
+        let req = ctrl.backend.take().unwrap();
+        let bs = BLOCK_SIZE as usize;
+        assert_eq!(req.oper(), Operation::Discard(4 * bs, bs));
+        req.complete(BlockResult::Success);
+        assert_eq!(status(&ctrl.completion(IO_QID).unwrap()), STS_SUCCESS);
+
+        // As such, a command with nothing but those has nothing to discard.
+        let cmd = dsm_cmd(&ctrl, 6, &[(0, 0)]);
+        ctrl.submit(IO_QID, cmd);
+        assert!(ctrl.backend.take().is_none());
+        let comp = ctrl.completion(IO_QID).unwrap();
+        assert_eq!({ comp.cid }, 6);
+        assert_eq!(status(&comp), STS_SUCCESS);
+    }
+
+    #[test]
+    fn dsm_range_past_end() {
+        let mut ctrl = TestCtrl::new(64);
+
+        // Nothing is discarded when any range extends past the namespace,
+        // including by overflowing.
+        for ranges in [&[(0, 4), (60, 5)][..], &[(64, 1)], &[(u64::MAX, 2)]] {
+            let cmd = dsm_cmd(&ctrl, 5, ranges);
+            ctrl.submit(IO_QID, cmd);
+            assert!(ctrl.backend.take().is_none());
+            let comp = ctrl.completion(IO_QID).unwrap();
+            assert_eq!(status(&comp), STS_LBA_RANGE);
+        }
+
+        // While one which ends at the end of the namespace is fine.
+        let cmd = dsm_cmd(&ctrl, 6, &[(60, 4)]);
+        ctrl.submit(IO_QID, cmd);
+        ctrl.backend.take().unwrap().complete(BlockResult::Success);
+        assert_eq!(status(&ctrl.completion(IO_QID).unwrap()), STS_SUCCESS);
     }
 }
@@ -0,0 +1,238 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for device tests which act as the guest, driving the admin queue
+//! and a single I/O queue pair of a controller, each of [`QUEUE_SIZE`]
+//! entries.  Requests are handed by the controller to a [`TestBackend`],
+//! which leaves them for the test to complete.
+
+use std::sync::Arc;
+
+use crate::accessors::Guard;
+use crate::block;
+use crate::common::{GuestAddr, RWOp, WriteOp};
+use crate::hw::pci;
+use crate::vmm::{Machine, MemCtx};
+
+use super::bits::*;
+use super::{CtrlIdentity, PciNvme};
+
+const QUEUE_SIZE: u16 = 16;
+
+const ASQ_BASE: u64 = 0x10_0000;
+const ACQ_BASE: u64 = 0x10_1000;
+const IOSQ_BASE: u64 = 0x10_2000;
+const IOCQ_BASE: u64 = 0x10_3000;
+
+/// Start of the (page-aligned) buffer used for the data of commands
+pub(super) const DATA_BASE: u64 = 0x10_4000;
+
+pub(super) const ADMIN_QID: u16 = 0;
+pub(super) const IO_QID: u16 = 1;
+
+pub(super) const BLOCK_SIZE: u32 = 512;
+
+/// A backend which leaves requests with the device until the test takes them
+pub(super) struct TestBackend {
+    att: block::BackendAttachment,
+    info: block::DeviceInfo,
+}
+impl TestBackend {
+    pub(super) fn new(blocks: u64) -> Arc<Self> {
+        Arc::new(Self {
+            att: block::BackendAttachment::new(),
+            info: block::DeviceInfo {
+                block_size: BLOCK_SIZE,
+                total_size: blocks,
+                read_only: false,
+            },
+        })
+    }
+
+    /// Take the next request the device has for the backend (if any)
+    pub(super) fn take(&self) -> Option<block::Request> {
+        self.att.next_req().ok()
+    }
+}
+impl block::Backend for TestBackend {
+    fn attachment(&self) -> &block::BackendAttachment {
+        &self.att
+    }
+    fn info(&self) -> block::DeviceInfo {
+        self.info
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.att.start();
+        Ok(())
+    }
+    fn stop(&self) {
+        self.att.stop();
+    }
+}
+
+/// A controller, enabled and with an I/O queue pair created, as a guest
+/// would leave it
+pub(super) struct TestCtrl {
+    machine: Machine,
+    pub(super) dev: Arc<PciNvme>,
+    pub(super) backend: Arc<TestBackend>,
+    sq_tail: [u16; 2],
+    cq_head: [u16; 2],
+    phase: [bool; 2],
+}
+impl TestCtrl {
+    /// Set up a controller with a namespace of `blocks` blocks
+    pub(super) fn new(blocks: u64) -> Self {
+        let machine = Machine::new_test().unwrap();
+        let dev = PciNvme::create(
+            "test".to_string(),
+            CtrlIdentity::default(),
+            None,
+            Some(1),
+            None,
+            slog::Logger::root(slog::Discard, slog::o!()),
+        );
+        machine.acc_mem.adopt(&dev.pci_state.acc_mem, None);
+        dev.state.lock().unwrap().msix_hdl = Some(pci::MsixHdl::new_test());
+        let backend = TestBackend::new(blocks);
+        block::attach(dev.clone(), backend.clone()).unwrap();
+        block::Backend::start(&*backend).unwrap();
+
+        let mut ctrl = Self {
+            machine,
+            dev,
+            backend,
+            sq_tail: [0; 2],
+            cq_head: [0; 2],
+            phase: [true; 2],
+        };
+
+        let aqa = AdminQueueAttrs(0)
+            .with_asqs(QUEUE_SIZE - 1)
+            .with_acqs(QUEUE_SIZE - 1);
+        ctrl.reg_write(0x24, &aqa.0.to_le_bytes());
+        ctrl.reg_write(0x28, &ASQ_BASE.to_le_bytes());
+        ctrl.reg_write(0x30, &ACQ_BASE.to_le_bytes());
+        let cc =
+            Configuration(0).with_enabled(true).with_iosqes(6).with_iocqes(4);
+        ctrl.reg_write(0x14, &cc.0.to_le_bytes());
+        assert!(ctrl.dev.state.lock().unwrap().ctrl.csts.ready());
+
+        let size = u32::from(QUEUE_SIZE - 1) << 16;
+        let qid = u32::from(IO_QID);
+        let create_cq = SubmissionQueueEntry {
+            prp1: IOCQ_BASE,
+            cdw10: size | qid,
+            // Physically contiguous, with interrupts enabled
+            cdw11: 0b11,
+            ..cmd(ADMIN_OPC_CREATE_IO_CQ, 0)
+        };
+        assert_eq!(status(&ctrl.admin(create_cq)), STS_SUCCESS);
+        let create_sq = SubmissionQueueEntry {
+            prp1: IOSQ_BASE,
+            cdw10: size | qid,
+            cdw11: qid << 16 | 0b1,
+            ..cmd(ADMIN_OPC_CREATE_IO_SQ, 1)
+        };
+        assert_eq!(status(&ctrl.admin(create_sq)), STS_SUCCESS);
+
+        ctrl
+    }
+
+    pub(super) fn mem(&self) -> Guard<'_, MemCtx> {
+        self.machine.acc_mem.access().unwrap()
+    }
+
+    fn reg_write(&self, off: usize, val: &[u8]) {
+        let mut wo = WriteOp::from_buf(off, val);
+        pci::Device::bar_rw(&*self.dev, pci::BarN::BAR0, RWOp::Write(&mut wo));
+    }
+
+    /// Place `cmd` in the next slot of Submission Queue `qid`, and ring its
+    /// doorbell
+    pub(super) fn submit(&mut self, qid: u16, cmd: SubmissionQueueEntry) {
+        let q = usize::from(qid);
+        let base = [ASQ_BASE, IOSQ_BASE][q];
+        let slot = u64::from(self.sq_tail[q]);
+        assert!(self.mem().write(GuestAddr(base + 64 * slot), &cmd));
+
+        self.sq_tail[q] = (self.sq_tail[q] + 1) % QUEUE_SIZE;
+        let tail = u32::from(self.sq_tail[q]);
+        self.reg_write(0x1000 + 8 * q, &tail.to_le_bytes());
+    }
+
+    /// Take the next entry posted to Completion Queue `qid` (if any), and
+    /// ring its doorbell
+    pub(super) fn completion(
+        &mut self,
+        qid: u16,
+    ) -> Option<CompletionQueueEntry> {
+        let q = usize::from(qid);
+        let base = [ACQ_BASE, IOCQ_BASE][q];
+        let slot = u64::from(self.cq_head[q]);
+        let entry: CompletionQueueEntry =
+            self.mem().read(GuestAddr(base + 16 * slot)).unwrap();
+        if (entry.status_phase & 1 == 1) != self.phase[q] {
+            return None;
+        }
+
+        self.cq_head[q] = (self.cq_head[q] + 1) % QUEUE_SIZE;
+        if self.cq_head[q] == 0 {
+            self.phase[q] = !self.phase[q];
+        }
+        let head = u32::from(self.cq_head[q]);
+        self.reg_write(0x1000 + 8 * q + 4, &head.to_le_bytes());
+        Some(entry)
+    }
+
+    /// Submit an admin command, which is expected to complete immediately
+    pub(super) fn admin(
+        &mut self,
+        cmd: SubmissionQueueEntry,
+    ) -> CompletionQueueEntry {
+        self.submit(ADMIN_QID, cmd);
+        self.completion(ADMIN_QID).expect("admin command completed")
+    }
+
+    /// Replace the backend with one of `blocks` blocks, as when a disk is
+    /// resized or swapped out from under the guest
+    pub(super) fn replace_backend(&mut self, blocks: u64) {
+        block::Backend::stop(&*self.backend);
+        block::Backend::detach(&*self.backend).unwrap();
+        self.backend = TestBackend::new(blocks);
+        block::attach(self.dev.clone(), self.backend.clone()).unwrap();
+        block::Backend::start(&*self.backend).unwrap();
+    }
+}
+
+/// A command with opcode `opc` and identifier `cid`, for the namespace
+pub(super) fn cmd(opc: u8, cid: u16) -> SubmissionQueueEntry {
+    SubmissionQueueEntry {
+        cdw0: u32::from(opc) | u32::from(cid) << 16,
+        nsid: 1,
+        ..Default::default()
+    }
+}
+
+/// An I/O command for `nlb` blocks starting at `slba`, with any data in the
+/// buffer at [`DATA_BASE`]
+pub(super) fn io_cmd(
+    opc: u8,
+    cid: u16,
+    slba: u64,
+    nlb: u16,
+) -> SubmissionQueueEntry {
+    SubmissionQueueEntry {
+        prp1: DATA_BASE,
+        cdw10: slba as u32,
+        cdw11: (slba >> 32) as u32,
+        cdw12: u32::from(nlb - 1),
+        ..cmd(opc, cid)
+    }
+}
+
+/// The Status Code (SC) of a completion
+pub(super) fn status(comp: &CompletionQueueEntry) -> u8 {
+    (comp.status_phase >> 1) as u8
+}
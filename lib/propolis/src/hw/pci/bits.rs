@@ -44,6 +44,7 @@ pub const BAR_TYPE_MEM64: u32 = 0b100;
 
 pub const CAP_ID_MSI: u8 = 0x05;
 pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_PCIE: u8 = 0x10;
 pub const CAP_ID_MSIX: u8 = 0x11;
 
 pub const CLASS_UNCLASSIFIED: u8 = 0;
@@ -53,8 +54,12 @@ pub const CLASS_DISPLAY: u8 = 3;
 pub const CLASS_MULTIMEDIA: u8 = 4;
 pub const CLASS_MEMORY: u8 = 5;
 pub const CLASS_BRIDGE: u8 = 6;
+pub const CLASS_COMMUNICATION: u8 = 7;
+pub const CLASS_INPUT: u8 = 9;
 
 // Sub-classes under CLASS_STORAGE
+pub const SUBCLASS_STORAGE_IDE: u8 = 1;
+pub const SUBCLASS_STORAGE_SATA: u8 = 6;
 pub const SUBCLASS_STORAGE_NVM: u8 = 8;
 
 // Sub-classes under CLASS_BRIDGE
@@ -69,6 +74,13 @@ pub const HEADER_TYPE_MULTIFUNC: u8 = 0b1000_0000;
 // Programming Interfaces for SUBCLASS_STORAGE_NVM
 pub const PROGIF_ENTERPRISE_NVME: u8 = 2;
 
+// Programming Interfaces for SUBCLASS_STORAGE_SATA
+pub const PROGIF_AHCI: u8 = 1;
+
+// Programming Interfaces for SUBCLASS_STORAGE_IDE
+/// Both channels in compatibility mode, with Bus Master IDE supported
+pub const PROGIF_IDE_COMPAT_BM: u8 = 0x80;
+
 pub(super) const MASK_FUNC: u8 = 0x07;
 pub(super) const MASK_DEV: u8 = 0x1f;
 pub(super) const MASK_BUS: u8 = 0xff;
@@ -6,9 +6,12 @@
 
 use std::num::NonZeroU8;
 use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
 use super::bus::Attachment;
 use super::cfgspace::{CfgBuilder, CfgReg};
+use super::device::Cap;
+use super::hotplug::{self, HotplugError, HotplugSlot};
 use super::topology::{LogicalBusId, RoutedBusId, Topology};
 use super::{bits::*, Endpoint, Ident};
 use super::{BarN, BusNum, StdCfgReg};
@@ -96,6 +99,8 @@ pub struct Bridge {
     // single config transaction is expected to access both common state and
     // bridge state).
     cfg_map: RegMap<CfgReg>,
+    caps: Vec<Cap>,
+    hotplug: Option<HotplugSlot>,
     inner: Mutex<Inner>,
 }
 
@@ -109,7 +114,41 @@ impl Bridge {
         topology: &Arc<Topology>,
         downstream_bus_id: LogicalBusId,
     ) -> Arc<Self> {
-        let cfg_builder = CfgBuilder::new();
+        Self::new_common(vendor, device, topology, downstream_bus_id, None)
+    }
+
+    /// Construct a new PCI bridge which presents itself as a PCI Express root
+    /// port with a hotplug slot, whose physical slot number is supplied.  See
+    /// [`Bridge::new`] for the other arguments.
+    pub fn new_hotplug(
+        vendor: u16,
+        device: u16,
+        topology: &Arc<Topology>,
+        downstream_bus_id: LogicalBusId,
+        physical_slot: u16,
+    ) -> Arc<Self> {
+        Self::new_common(
+            vendor,
+            device,
+            topology,
+            downstream_bus_id,
+            Some(HotplugSlot::new(physical_slot)),
+        )
+    }
+
+    fn new_common(
+        vendor: u16,
+        device: u16,
+        topology: &Arc<Topology>,
+        downstream_bus_id: LogicalBusId,
+        hotplug: Option<HotplugSlot>,
+    ) -> Arc<Self> {
+        let mut cfg_builder = CfgBuilder::new();
+        if hotplug.is_some() {
+            cfg_builder.add_capability(CAP_ID_PCIE, hotplug::PCIE_CAP_LEN);
+            cfg_builder.add_capability(CAP_ID_MSI, hotplug::MSI_CAP_LEN);
+        }
+        let (cfg_map, caps) = cfg_builder.finish();
         Arc::new(Self {
             ident: Ident {
                 vendor_id: vendor,
@@ -121,7 +160,9 @@ impl Bridge {
                 prog_if: BRIDGE_PROG_IF,
                 ..Default::default()
             },
-            cfg_map: cfg_builder.finish().0,
+            cfg_map,
+            caps,
+            hotplug,
             inner: Mutex::new(Inner::new(topology, downstream_bus_id)),
         })
     }
@@ -159,12 +200,16 @@ impl Bridge {
                     ro.write_u16(guard.reg_command.bits());
                 }
 
-                // The bridge never generates its own interrupts and currently
-                // has no capabilities, so set both of those bits to 0.
-                StdCfgReg::Status => ro.write_u16(0),
+                // The bridge never asserts its (nonexistent) interrupt pin,
+                // but it does have capabilities if it has a hotplug slot.
+                StdCfgReg::Status => {
+                    let mut status = RegStatus::empty();
+                    status.set(RegStatus::CAP_LIST, !self.caps.is_empty());
+                    ro.write_u16(status.bits());
+                }
 
-                // Disable interrupts from the bridge device itself (SS3.2.5.16
-                // and 17).
+                // Disable pin-based interrupts from the bridge device itself
+                // (SS3.2.5.16 and 17).  Hotplug events are signaled with MSI.
                 StdCfgReg::IntrLine => ro.write_u8(0xFF),
                 StdCfgReg::IntrPin => ro.write_u8(0),
 
@@ -176,8 +221,9 @@ impl Bridge {
                 // Expansion ROMs are not supported.
                 StdCfgReg::ExpansionRomAddr => ro.write_u32(0),
 
-                // No capabilities for now.
-                StdCfgReg::CapPtr => ro.write_u8(0),
+                StdCfgReg::CapPtr => {
+                    ro.write_u8(self.caps.first().map_or(0, Cap::offset))
+                }
 
                 // Other registers defined to be optional in SS3.2.4.
                 StdCfgReg::CacheLineSize => ro.write_u8(0),
@@ -309,10 +355,118 @@ impl Bridge {
             BridgeReg::BridgeControl => {}
         }
     }
+
+    fn cfg_cap_rw(&self, id: &CfgReg, rwo: RWOp) {
+        match id {
+            CfgReg::CapId(i) => {
+                if let RWOp::Read(ro) = rwo {
+                    ro.write_u8(self.caps[*i as usize].id())
+                }
+            }
+            CfgReg::CapNext(i) => {
+                if let RWOp::Read(ro) = rwo {
+                    let next = self.caps.get(*i as usize + 1);
+                    ro.write_u8(next.map_or(0, Cap::offset))
+                }
+            }
+            CfgReg::CapBody(i) => {
+                // Capabilities are only present with a hotplug slot
+                let slot = self.hotplug.as_ref().unwrap();
+                match self.caps[*i as usize].id() {
+                    CAP_ID_PCIE => slot.pcie_cap_rw(rwo),
+                    CAP_ID_MSI => slot.msi_cap_rw(rwo),
+                    id => panic!("Unexpected bridge capability {:#x}", id),
+                }
+            }
+            _ => panic!("Unexpected capability register {:?}", id),
+        }
+    }
+
+    /// Returns the logical ID of the bus downstream of this bridge.
+    pub fn downstream_bus(&self) -> LogicalBusId {
+        self.inner.lock().unwrap().downstream_bus_id
+    }
+
+    /// Returns the physical slot number of this bridge's hotplug slot, if it
+    /// has one.
+    pub fn hotplug_slot(&self) -> Option<u16> {
+        self.hotplug.as_ref().map(HotplugSlot::physical_slot)
+    }
+
+    fn hotplug_slot_and_topology(
+        &self,
+    ) -> Result<(&HotplugSlot, Arc<Topology>, LogicalBusId), HotplugError> {
+        let slot =
+            self.hotplug.as_ref().ok_or(HotplugError::NotHotplugCapable)?;
+        let inner = self.inner.lock().unwrap();
+        let topology =
+            inner.topology.upgrade().ok_or(HotplugError::TopologyGone)?;
+        Ok((slot, topology, inner.downstream_bus_id))
+    }
+
+    /// Inserts `dev` into this bridge's hotplug slot, attaching it to the
+    /// downstream bus and notifying the guest of its presence.
+    pub fn hotplug_insert(
+        &self,
+        dev: Arc<dyn Endpoint>,
+    ) -> Result<(), HotplugError> {
+        let (slot, topology, bus) = self.hotplug_slot_and_topology()?;
+        slot.insert(|| {
+            topology.pci_attach(bus, hotplug::SLOT_LOCATION, dev, None)?;
+            Ok(())
+        })
+    }
+
+    /// Presses the attention button of this bridge's hotplug slot, requesting
+    /// that the guest release the device in the slot and power it off.
+    pub fn hotplug_request_removal(&self) -> Result<(), HotplugError> {
+        self.hotplug
+            .as_ref()
+            .ok_or(HotplugError::NotHotplugCapable)?
+            .press_attention_button()
+    }
+
+    /// Withdraws a press of the attention button of this bridge's hotplug slot,
+    /// if the guest has yet to acknowledge it, returning whether it did so.
+    /// A guest which has acknowledged the press may still power off the slot.
+    pub fn hotplug_cancel_removal(&self) -> Result<bool, HotplugError> {
+        Ok(self
+            .hotplug
+            .as_ref()
+            .ok_or(HotplugError::NotHotplugCapable)?
+            .cancel_attention())
+    }
+
+    /// Waits for up to `timeout` for the guest to power off this bridge's
+    /// hotplug slot, returning `true` if it did so.
+    pub fn hotplug_wait_power_off(
+        &self,
+        timeout: Duration,
+    ) -> Result<bool, HotplugError> {
+        Ok(self
+            .hotplug
+            .as_ref()
+            .ok_or(HotplugError::NotHotplugCapable)?
+            .wait_power_off(timeout))
+    }
+
+    /// Removes the device in this bridge's hotplug slot, detaching it from
+    /// the downstream bus, notifying the guest of its absence, and returning
+    /// the removed device.
+    ///
+    /// The caller is responsible for ensuring that the guest is no longer
+    /// using the device, e.g. by waiting for the slot to be powered off.
+    pub fn hotplug_remove(&self) -> Result<Arc<dyn Endpoint>, HotplugError> {
+        let (slot, topology, bus) = self.hotplug_slot_and_topology()?;
+        slot.remove(|| Ok(topology.pci_detach(bus, hotplug::SLOT_LOCATION)?))
+    }
 }
 
 impl Endpoint for Bridge {
     fn attach(&self, attachment: Attachment) {
+        if let Some(slot) = self.hotplug.as_ref() {
+            attachment.acc_msi.adopt(slot.acc_msi(), None);
+        }
         let mut inner = self.inner.lock().unwrap();
         let _old = inner.attachment.replace(attachment);
         assert!(_old.is_none());
@@ -323,6 +477,9 @@ impl Endpoint for Bridge {
             CfgReg::Std => {
                 self.cfg_header_rw(rwo);
             }
+            CfgReg::CapId(_) | CfgReg::CapNext(_) | CfgReg::CapBody(_) => {
+                self.cfg_cap_rw(id, rwo);
+            }
             _ => {
                 panic!(
                     "Unexpected read of bridge config space with ID {:?}",
@@ -346,6 +503,9 @@ impl Lifecycle for Bridge {
     }
     fn reset(&self) {
         self.inner.lock().unwrap().reset();
+        if let Some(slot) = self.hotplug.as_ref() {
+            slot.reset();
+        }
     }
     fn migrate(&self) -> Migrator {
         // TODO Should be migratable in theory: copy all the register state,
@@ -426,12 +586,15 @@ mod test {
 
     const OFFSET_VENDOR_ID: usize = 0x00;
     const OFFSET_DEVICE_ID: usize = 0x02;
+    const OFFSET_STATUS: usize = 0x06;
     const OFFSET_HEADER_TYPE: usize = 0x0E;
     const OFFSET_SECONDARY_BUS: usize = 0x19;
+    const OFFSET_CAP_PTR: usize = 0x34;
 
     struct Env {
         _machine: Machine,
         topology: Arc<Topology>,
+        bridges: Vec<(Bdf, Arc<Bridge>)>,
     }
 
     impl Env {
@@ -444,9 +607,9 @@ mod test {
             }
 
             let machine = Machine::new_test().unwrap();
-            let FinishedTopology { topology, bridges: _bridges } =
+            let FinishedTopology { topology, bridges } =
                 builder.finish(&machine).unwrap();
-            Self { _machine: machine, topology }
+            Self { _machine: machine, topology, bridges }
         }
 
         fn make_bridge(&self) -> Arc<Bridge> {
@@ -562,4 +725,52 @@ mod test {
         env.write_secondary_bus(Bdf::new(0, 1, 0).unwrap(), 0);
         assert_eq!(env.read_secondary_bus(Bdf::new(82, 1, 0).unwrap()), 0);
     }
+
+    #[test]
+    fn hotplug_bridge() {
+        let bdf = Bdf::new(0, 1, 0).unwrap();
+        let env =
+            Env::new(Some(vec![BridgeDescription::new(LogicalBusId(1), bdf)
+                .with_hotplug_slot(1)]));
+        let bridge = env.bridges[0].1.clone();
+        assert_eq!(bridge.hotplug_slot(), Some(1));
+
+        // The PCIe and MSI capabilities should be discoverable
+        let status = env.read_header_byte(bdf, OFFSET_STATUS);
+        assert_ne!(status & RegStatus::CAP_LIST.bits() as u8, 0);
+        let pcie = env.read_header_byte(bdf, OFFSET_CAP_PTR) as usize;
+        assert_eq!(env.read_header_byte(bdf, pcie), CAP_ID_PCIE);
+        let msi = env.read_header_byte(bdf, pcie + 1) as usize;
+        assert_eq!(env.read_header_byte(bdf, msi), CAP_ID_MSI);
+        assert_eq!(env.read_header_byte(bdf, msi + 1), 0);
+
+        // Devices inserted into the slot appear at device 0 of the downstream
+        // bus, and disappear once removed.
+        env.write_secondary_bus(bdf, 5);
+        let child = Bdf::new(5, 0, 0).unwrap();
+        bridge.hotplug_insert(env.make_bridge()).unwrap();
+        assert_eq!(env.read_header_type(child), HEADER_TYPE_BRIDGE);
+        assert!(matches!(
+            bridge.hotplug_insert(env.make_bridge()),
+            Err(HotplugError::SlotOccupied)
+        ));
+
+        bridge.hotplug_remove().unwrap();
+        assert_eq!(env.read_header_type(child), 0);
+        assert!(matches!(
+            bridge.hotplug_remove(),
+            Err(HotplugError::SlotEmpty)
+        ));
+    }
+
+    #[test]
+    fn hotplug_unsupported() {
+        let env = Env::new(None);
+        let bridge = env.make_bridge();
+        assert_eq!(bridge.hotplug_slot(), None);
+        assert!(matches!(
+            bridge.hotplug_insert(env.make_bridge()),
+            Err(HotplugError::NotHotplugCapable)
+        ));
+    }
 }
@@ -52,6 +52,13 @@ impl Bus {
         dev.attach(attached);
     }
 
+    /// Detach the device (if any) at `location`, removing any BARs it had
+    /// registered.
+    pub fn detach(&self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.detach(location)
+    }
+
     pub fn device_at(
         &self,
         location: BusLocation,
@@ -125,6 +132,9 @@ impl Slot {
         }
         self.state.clone()
     }
+    fn detach(&mut self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        self.funcs[location.func.get() as usize].take()
+    }
 }
 
 struct BarState {
@@ -184,6 +194,18 @@ impl Inner {
             self.acc_mem.child(Some(acc_name)),
         )
     }
+    fn detach(&mut self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        let dev = self.slots[location.dev.get() as usize].detach(location)?;
+        let bars = self
+            .bar_state
+            .range((location, BarN::BAR0)..=(location, BarN::BAR5))
+            .map(|((_, n), _)| *n)
+            .collect::<Vec<_>>();
+        for n in bars {
+            self.bar_unregister(location, n);
+        }
+        Some(dev)
+    }
     fn bar_register(
         &mut self,
         location: BusLocation,
@@ -191,7 +213,10 @@ impl Inner {
         def: BarDefine,
         value: u64,
     ) {
-        let dev = self.device_at(location).unwrap();
+        // The device may have been detached from the bus in the meantime
+        let Some(dev) = self.device_at(location) else {
+            return;
+        };
 
         let live = match def {
             BarDefine::Pio(sz) => {
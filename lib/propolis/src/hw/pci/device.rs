@@ -46,6 +46,13 @@ pub trait Device: Send + Sync + 'static {
     fn interrupt_mode_change(&self, mode: IntrMode) {}
     #[allow(unused_variables)]
     fn msi_update(&self, info: MsiUpdate) {}
+    /// Notification that BAR `bar` has been mapped into its address space at
+    /// `addr`, or unmapped from it (when `None`), by the guest.
+    ///
+    /// This is called with the [DeviceState] locked, so implementations must
+    /// not call back into it.
+    #[allow(unused_variables)]
+    fn bar_update(&self, bar: BarN, addr: Option<u64>) {}
     // TODO
     // fn cap_read(&self);
     // fn cap_write(&self);
@@ -192,6 +199,14 @@ impl Cap {
     pub(super) fn new(id: u8, offset: u8) -> Self {
         Self { id, offset }
     }
+
+    pub(super) fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub(super) fn offset(&self) -> u8 {
+        self.offset
+    }
 }
 
 pub struct DeviceState {
@@ -384,6 +399,7 @@ impl DeviceState {
                     if (pio_en && def.is_pio()) || (mmio_en && def.is_mmio()) {
                         attach.bar_unregister(*bar);
                         attach.bar_register(*bar, def, new);
+                        dev.bar_update(*bar, Some(new));
                     }
                 }
             }
@@ -435,15 +451,19 @@ impl DeviceState {
                 if diff.contains(RegCmd::IO_EN) && def.is_pio() {
                     if val.contains(RegCmd::IO_EN) {
                         attach.bar_register(n, def, v);
+                        dev.bar_update(n, Some(v));
                     } else {
                         attach.bar_unregister(n);
+                        dev.bar_update(n, None);
                     }
                 }
                 if diff.contains(RegCmd::MMIO_EN) && def.is_mmio() {
                     if val.contains(RegCmd::MMIO_EN) {
                         attach.bar_register(n, def, v);
+                        dev.bar_update(n, Some(v));
                     } else {
                         attach.bar_unregister(n);
+                        dev.bar_update(n, None);
                     }
                 }
             }
@@ -553,7 +573,7 @@ impl DeviceState {
                 state.bars.set(n, 0);
                 let attach = state.attached();
                 attach.bar_unregister(n);
-                // TODO: notify device of zeroed BARs
+                dev.bar_update(n, None);
             }
         }
     }
@@ -653,6 +673,10 @@ impl MigrateMulti for DeviceState {
     ) -> Result<(), MigrateStateError> {
         self.import(offer.take()?)
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::PciStateV1>();
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
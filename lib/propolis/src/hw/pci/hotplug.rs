@@ -0,0 +1,593 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! PCI Express native hotplug slots.
+//!
+//! A bridge with a hotplug slot presents itself to the guest as a PCIe root
+//! port, whose Slot Capabilities, Slot Control and Slot Status registers (PCIe
+//! base spec rev 5.0 SS7.5.3.9-11) let the guest learn of devices arriving in
+//! (or departing from) the slot, and control its power and indicators.  Slot
+//! events are signaled to the guest with MSI, as the bridge has no interrupt
+//! pin.
+//!
+//! Devices are inserted at the host's behest, with the guest told of their
+//! arrival through a presence detect change.  Removal is cooperative: the
+//! slot's attention button is pressed, after which the guest is expected to
+//! quiesce the device and power off the slot, at which point the device can
+//! be taken out of it.
+
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::accessors::MsiAccessor;
+use crate::common::{RWOp, ReadOp, WriteOp};
+use crate::util::regmap::{Flags, RegMap};
+
+use super::topology::PciTopologyError;
+use super::BusLocation;
+
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+/// Length of the body (beyond the ID and next pointer) of the PCI Express
+/// capability, through the Slot Status 2 register.
+pub(super) const PCIE_CAP_LEN: u8 = 0x3a;
+
+/// Length of the body of a 64-bit MSI capability, without per-vector masking,
+/// padded out to a multiple of 4 bytes.
+pub(super) const MSI_CAP_LEN: u8 = 0xe;
+
+// PCI Express Capabilities register: version 2, root port, slot implemented
+const PCIE_CAP_VERSION: u16 = 0x2;
+const PCIE_CAP_TYPE_ROOT_PORT: u16 = 0x4 << 4;
+const PCIE_CAP_SLOT_IMPL: u16 = 1 << 8;
+
+// Link Capabilities and Status: a single 2.5 GT/s lane, whose data link layer
+// state is reported to the guest
+const LINK_SPEED_2_5GT: u32 = 0x1;
+const LINK_WIDTH_X1: u32 = 0x1 << 4;
+const LINK_CAP_DLL_ACTIVE_REPORTING: u32 = 1 << 20;
+const LINK_STATUS_DLL_ACTIVE: u16 = 1 << 13;
+
+// Slot Capabilities
+const SLOT_CAP_ATTN_BTN: u32 = 1 << 0;
+const SLOT_CAP_PWR_CTL: u32 = 1 << 1;
+const SLOT_CAP_ATTN_IND: u32 = 1 << 3;
+const SLOT_CAP_PWR_IND: u32 = 1 << 4;
+const SLOT_CAP_HOTPLUG: u32 = 1 << 6;
+const SLOT_CAP_NO_CMD_CMPL: u32 = 1 << 18;
+const SLOT_CAP_PHYS_SLOT_SHIFT: u32 = 19;
+
+/// The location, on the bus beneath a hotplug slot, of the device in the slot
+pub const SLOT_LOCATION: BusLocation = BusLocation::new_unchecked(0, 0);
+
+/// Largest physical slot number which can be expressed in Slot Capabilities
+pub const MAX_PHYSICAL_SLOT: u16 = 0x1fff;
+
+// MSI Message Control
+const MSI_CTL_ENABLE: u16 = 1 << 0;
+const MSI_CTL_64BIT: u16 = 1 << 7;
+
+bitflags! {
+    #[derive(Copy, Clone, Default, Debug, PartialEq)]
+    struct SlotCtl: u16 {
+        const ATTN_BTN_EN = 1 << 0;
+        const PWR_FAULT_EN = 1 << 1;
+        const MRL_EN = 1 << 2;
+        const PRES_DET_EN = 1 << 3;
+        const CMD_CMPL_EN = 1 << 4;
+        const HP_INTR_EN = 1 << 5;
+        const ATTN_IND = 0b11 << 6;
+        const PWR_IND = 0b11 << 8;
+        /// Set to turn off power to the slot
+        const PWR_CTL_OFF = 1 << 10;
+        const DLL_STATE_EN = 1 << 12;
+    }
+}
+impl SlotCtl {
+    /// The slot events which the guest has enabled notifications for
+    fn enabled_events(&self) -> SlotStatus {
+        [
+            (SlotCtl::ATTN_BTN_EN, SlotStatus::ATTN_BTN),
+            (SlotCtl::PWR_FAULT_EN, SlotStatus::PWR_FAULT),
+            (SlotCtl::MRL_EN, SlotStatus::MRL_CHANGED),
+            (SlotCtl::PRES_DET_EN, SlotStatus::PRES_DET_CHANGED),
+            (SlotCtl::CMD_CMPL_EN, SlotStatus::CMD_CMPL),
+            (SlotCtl::DLL_STATE_EN, SlotStatus::DLL_STATE_CHANGED),
+        ]
+        .into_iter()
+        .filter(|(en, _)| self.contains(*en))
+        .fold(SlotStatus::empty(), |acc, (_, ev)| acc | ev)
+    }
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Default, Debug, PartialEq)]
+    struct SlotStatus: u16 {
+        const ATTN_BTN = 1 << 0;
+        const PWR_FAULT = 1 << 1;
+        const MRL_CHANGED = 1 << 2;
+        const PRES_DET_CHANGED = 1 << 3;
+        const CMD_CMPL = 1 << 4;
+        const PRES_DET_STATE = 1 << 6;
+        const DLL_STATE_CHANGED = 1 << 8;
+
+        /// Event bits, which are cleared by writing 1 to them
+        const EVENTS = Self::ATTN_BTN.bits()
+            | Self::PWR_FAULT.bits()
+            | Self::MRL_CHANGED.bits()
+            | Self::PRES_DET_CHANGED.bits()
+            | Self::CMD_CMPL.bits()
+            | Self::DLL_STATE_CHANGED.bits();
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PcieReg {
+    PcieCap,
+    DevCap,
+    LinkCap,
+    LinkStatus,
+    SlotCap,
+    SlotCtl,
+    SlotStatus,
+    Reserved,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum MsiReg {
+    MsgCtl,
+    AddrLow,
+    AddrHigh,
+    Data,
+    Reserved,
+}
+
+lazy_static! {
+    static ref PCIE_CAP_MAP: RegMap<PcieReg> = {
+        let layout = [
+            (PcieReg::PcieCap, 2),
+            (PcieReg::DevCap, 4),
+            (PcieReg::Reserved, 4), // Device Control and Status
+            (PcieReg::LinkCap, 4),
+            (PcieReg::Reserved, 2), // Link Control
+            (PcieReg::LinkStatus, 2),
+            (PcieReg::SlotCap, 4),
+            (PcieReg::SlotCtl, 2),
+            (PcieReg::SlotStatus, 2),
+            (PcieReg::Reserved, 32), // Root and "2" registers
+        ];
+        let mut map = RegMap::new(PCIE_CAP_LEN as usize);
+        let mut off = 0;
+        for (id, len) in layout {
+            let flags = match id {
+                PcieReg::Reserved => Flags::PASSTHRU,
+                // The event bits of Slot Status are cleared by writing 1 to
+                // them, so a partial write must not write back the others.
+                PcieReg::SlotStatus => Flags::NO_READ_MOD_WRITE,
+                _ => Flags::DEFAULT,
+            };
+            map.define_with_flags(off, len, id, flags);
+            off += len;
+        }
+        assert_eq!(off, PCIE_CAP_LEN as usize);
+        map
+    };
+    static ref MSI_CAP_MAP: RegMap<MsiReg> = {
+        let layout = [
+            (MsiReg::MsgCtl, 2),
+            (MsiReg::AddrLow, 4),
+            (MsiReg::AddrHigh, 4),
+            (MsiReg::Data, 2),
+            (MsiReg::Reserved, 2),
+        ];
+        RegMap::create_packed(
+            MSI_CAP_LEN as usize,
+            &layout,
+            Some(MsiReg::Reserved),
+        )
+    };
+}
+
+/// Errors returned when manipulating a hotplug slot.
+#[derive(Debug, Error)]
+pub enum HotplugError {
+    #[error("Bridge does not have a hotplug slot")]
+    NotHotplugCapable,
+
+    #[error("Hotplug slot is already occupied")]
+    SlotOccupied,
+
+    #[error("Hotplug slot is empty")]
+    SlotEmpty,
+
+    #[error("PCI topology no longer exists")]
+    TopologyGone,
+
+    #[error(transparent)]
+    Topology(#[from] PciTopologyError),
+}
+
+#[derive(Default)]
+struct MsiState {
+    enabled: bool,
+    addr: u64,
+    data: u16,
+}
+
+#[derive(Default)]
+struct SlotState {
+    ctl: SlotCtl,
+    /// Pending slot events (the RW1C bits of Slot Status)
+    events: SlotStatus,
+    present: bool,
+    /// Whether the hotplug interrupt is asserted.  MSIs are edge-triggered, so
+    /// one is sent only when the interrupt becomes asserted.
+    intr_asserted: bool,
+    msi: MsiState,
+}
+impl SlotState {
+    fn powered(&self) -> bool {
+        !self.ctl.contains(SlotCtl::PWR_CTL_OFF)
+    }
+
+    fn link_active(&self) -> bool {
+        self.present && self.powered()
+    }
+
+    fn slot_status(&self) -> SlotStatus {
+        let mut status = self.events;
+        status.set(SlotStatus::PRES_DET_STATE, self.present);
+        status
+    }
+
+    /// Raise `events`, interrupting the guest if it has asked to be notified
+    /// of them.
+    fn raise(&mut self, events: SlotStatus, acc_msi: &MsiAccessor) {
+        self.events |= events;
+        self.update_intr(acc_msi);
+    }
+
+    fn update_intr(&mut self, acc_msi: &MsiAccessor) {
+        let asserted = self.ctl.contains(SlotCtl::HP_INTR_EN)
+            && self.events.intersects(self.ctl.enabled_events());
+        if asserted && !self.intr_asserted && self.msi.enabled {
+            let _ = acc_msi.send(self.msi.addr, u64::from(self.msi.data));
+        }
+        self.intr_asserted = asserted;
+    }
+}
+
+/// The hotplug slot beneath a PCI Express port.
+pub struct HotplugSlot {
+    physical_slot: u16,
+    acc_msi: MsiAccessor,
+    state: Mutex<SlotState>,
+    /// Signaled when the power state of the slot changes
+    cv: Condvar,
+}
+impl HotplugSlot {
+    /// Create an (empty) hotplug slot with the given physical slot number,
+    /// which must not exceed [MAX_PHYSICAL_SLOT].
+    pub(super) fn new(physical_slot: u16) -> Self {
+        assert!(physical_slot <= MAX_PHYSICAL_SLOT);
+        Self {
+            physical_slot,
+            acc_msi: MsiAccessor::new_orphan(),
+            state: Mutex::new(SlotState::default()),
+            cv: Condvar::new(),
+        }
+    }
+
+    pub(super) fn physical_slot(&self) -> u16 {
+        self.physical_slot
+    }
+
+    pub(super) fn acc_msi(&self) -> &MsiAccessor {
+        &self.acc_msi
+    }
+
+    pub(super) fn pcie_cap_rw(&self, mut rwo: RWOp) {
+        PCIE_CAP_MAP.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.pcie_cap_read(id, ro),
+            RWOp::Write(wo) => self.pcie_cap_write(id, wo),
+        });
+    }
+
+    fn pcie_cap_read(&self, id: &PcieReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        match id {
+            PcieReg::PcieCap => ro.write_u16(
+                PCIE_CAP_VERSION | PCIE_CAP_TYPE_ROOT_PORT | PCIE_CAP_SLOT_IMPL,
+            ),
+            // Only the minimum (128-byte) max payload size is supported
+            PcieReg::DevCap => ro.write_u32(0),
+            PcieReg::LinkCap => ro.write_u32(
+                LINK_SPEED_2_5GT
+                    | LINK_WIDTH_X1
+                    | LINK_CAP_DLL_ACTIVE_REPORTING,
+            ),
+            PcieReg::LinkStatus => {
+                let mut val = (LINK_SPEED_2_5GT | LINK_WIDTH_X1) as u16;
+                if state.link_active() {
+                    val |= LINK_STATUS_DLL_ACTIVE;
+                }
+                ro.write_u16(val);
+            }
+            PcieReg::SlotCap => ro.write_u32(
+                SLOT_CAP_ATTN_BTN
+                    | SLOT_CAP_PWR_CTL
+                    | SLOT_CAP_ATTN_IND
+                    | SLOT_CAP_PWR_IND
+                    | SLOT_CAP_HOTPLUG
+                    // Slot Control writes take effect immediately, so there
+                    // is no need to notify the guest of their completion.
+                    | SLOT_CAP_NO_CMD_CMPL
+                    | u32::from(self.physical_slot) << SLOT_CAP_PHYS_SLOT_SHIFT,
+            ),
+            PcieReg::SlotCtl => ro.write_u16(state.ctl.bits()),
+            PcieReg::SlotStatus => ro.write_u16(state.slot_status().bits()),
+            PcieReg::Reserved => ro.fill(0),
+        }
+    }
+
+    fn pcie_cap_write(&self, id: &PcieReg, wo: &mut WriteOp) {
+        let mut state = self.state.lock().unwrap();
+        match id {
+            PcieReg::SlotCtl => {
+                let was_powered = state.powered();
+                let was_active = state.link_active();
+                state.ctl = SlotCtl::from_bits_truncate(wo.read_u16());
+                if state.powered() != was_powered {
+                    self.cv.notify_all();
+                }
+                if state.link_active() != was_active {
+                    state.events |= SlotStatus::DLL_STATE_CHANGED;
+                }
+                // Enabling notifications may expose already-pending events.
+                state.update_intr(&self.acc_msi);
+            }
+            PcieReg::SlotStatus => {
+                let clear = SlotStatus::from_bits_truncate(wo.read_u16())
+                    & SlotStatus::EVENTS;
+                state.events.remove(clear);
+                state.update_intr(&self.acc_msi);
+            }
+            // Everything else is read-only
+            _ => {}
+        }
+    }
+
+    pub(super) fn msi_cap_rw(&self, mut rwo: RWOp) {
+        MSI_CAP_MAP.process(&mut rwo, |id, rwo| {
+            let mut state = self.state.lock().unwrap();
+            let msi = &mut state.msi;
+            match rwo {
+                RWOp::Read(ro) => match id {
+                    MsiReg::MsgCtl => {
+                        let mut val = MSI_CTL_64BIT;
+                        if msi.enabled {
+                            val |= MSI_CTL_ENABLE;
+                        }
+                        ro.write_u16(val);
+                    }
+                    MsiReg::AddrLow => ro.write_u32(msi.addr as u32),
+                    MsiReg::AddrHigh => ro.write_u32((msi.addr >> 32) as u32),
+                    MsiReg::Data => ro.write_u16(msi.data),
+                    MsiReg::Reserved => ro.fill(0),
+                },
+                RWOp::Write(wo) => match id {
+                    // Only a single vector is offered, so the Multiple
+                    // Message Enable field is ignored.
+                    MsiReg::MsgCtl => {
+                        msi.enabled = wo.read_u16() & MSI_CTL_ENABLE != 0;
+                    }
+                    MsiReg::AddrLow => {
+                        let low = u64::from(wo.read_u32() & !0b11);
+                        msi.addr = (msi.addr & !0xffff_ffff) | low;
+                    }
+                    MsiReg::AddrHigh => {
+                        let high = u64::from(wo.read_u32()) << 32;
+                        msi.addr = (msi.addr & 0xffff_ffff) | high;
+                    }
+                    MsiReg::Data => msi.data = wo.read_u16(),
+                    MsiReg::Reserved => {}
+                },
+            }
+        });
+    }
+
+    /// Place a device into the slot, using `attach` to attach it to the bus
+    /// beneath the slot, and notify the guest of its arrival.
+    pub(super) fn insert(
+        &self,
+        attach: impl FnOnce() -> Result<(), HotplugError>,
+    ) -> Result<(), HotplugError> {
+        let mut state = self.state.lock().unwrap();
+        if state.present {
+            return Err(HotplugError::SlotOccupied);
+        }
+        attach()?;
+        state.present = true;
+
+        let mut events = SlotStatus::PRES_DET_CHANGED;
+        if state.link_active() {
+            events |= SlotStatus::DLL_STATE_CHANGED;
+        }
+        state.raise(events, &self.acc_msi);
+        Ok(())
+    }
+
+    /// Press the attention button of the slot, asking the guest to release
+    /// the device within it.
+    pub(super) fn press_attention_button(&self) -> Result<(), HotplugError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.present {
+            return Err(HotplugError::SlotEmpty);
+        }
+        state.raise(SlotStatus::ATTN_BTN, &self.acc_msi);
+        Ok(())
+    }
+
+    /// Withdraw a press of the attention button which the guest has not yet
+    /// acknowledged, returning whether there was one to withdraw.
+    pub(super) fn cancel_attention(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let pending = state.events.contains(SlotStatus::ATTN_BTN);
+        state.events.remove(SlotStatus::ATTN_BTN);
+        state.update_intr(&self.acc_msi);
+        pending
+    }
+
+    /// Wait (for at most `timeout`) for the guest to power off the slot,
+    /// returning whether it has done so.
+    pub(super) fn wait_power_off(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .cv
+            .wait_timeout_while(state, timeout, |state| state.powered())
+            .unwrap();
+        !state.powered()
+    }
+
+    /// Take the device out of the slot, using `detach` to detach it from the
+    /// bus beneath the slot, and notify the guest of its departure.
+    pub(super) fn remove<T>(
+        &self,
+        detach: impl FnOnce() -> Result<T, HotplugError>,
+    ) -> Result<T, HotplugError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.present {
+            return Err(HotplugError::SlotEmpty);
+        }
+        let dev = detach()?;
+        let was_active = state.link_active();
+        state.present = false;
+
+        let mut events = SlotStatus::PRES_DET_CHANGED;
+        if was_active {
+            events |= SlotStatus::DLL_STATE_CHANGED;
+        }
+        state.raise(events, &self.acc_msi);
+        Ok(dev)
+    }
+
+    /// Reset the slot registers, leaving any device in the slot (and the slot
+    /// powered).
+    pub(super) fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        let present = state.present;
+        *state = SlotState { present, ..Default::default() };
+        self.cv.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OFFSET_SLOT_CTL: usize = 0x16;
+    const OFFSET_SLOT_STATUS: usize = 0x18;
+
+    fn read_u16(slot: &HotplugSlot, offset: usize) -> u16 {
+        let mut buf = [0u8; 2];
+        let mut ro = ReadOp::from_buf(offset, &mut buf);
+        slot.pcie_cap_rw(RWOp::Read(&mut ro));
+        u16::from_le_bytes(buf)
+    }
+
+    fn write_u16(slot: &HotplugSlot, offset: usize, val: u16) {
+        let buf = val.to_le_bytes();
+        let mut wo = WriteOp::from_buf(offset, &buf);
+        slot.pcie_cap_rw(RWOp::Write(&mut wo));
+    }
+
+    fn slot_status(slot: &HotplugSlot) -> SlotStatus {
+        SlotStatus::from_bits_truncate(read_u16(slot, OFFSET_SLOT_STATUS))
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let slot = HotplugSlot::new(5);
+        assert!(!slot_status(&slot).contains(SlotStatus::PRES_DET_STATE));
+
+        slot.insert(|| Ok(())).unwrap();
+        let status = slot_status(&slot);
+        assert!(status.contains(
+            SlotStatus::PRES_DET_STATE
+                | SlotStatus::PRES_DET_CHANGED
+                | SlotStatus::DLL_STATE_CHANGED
+        ));
+        assert!(matches!(
+            slot.insert(|| Ok(())),
+            Err(HotplugError::SlotOccupied)
+        ));
+
+        // Event bits are cleared by writing 1 to them
+        write_u16(&slot, OFFSET_SLOT_STATUS, SlotStatus::EVENTS.bits());
+        assert_eq!(slot_status(&slot), SlotStatus::PRES_DET_STATE);
+
+        slot.remove(|| Ok(())).unwrap();
+        assert_eq!(
+            slot_status(&slot),
+            SlotStatus::PRES_DET_CHANGED | SlotStatus::DLL_STATE_CHANGED
+        );
+        assert!(matches!(slot.remove(|| Ok(())), Err(HotplugError::SlotEmpty)));
+    }
+
+    #[test]
+    fn failed_attach_leaves_slot_empty() {
+        let slot = HotplugSlot::new(5);
+        assert!(slot.insert(|| Err(HotplugError::TopologyGone)).is_err());
+        assert_eq!(slot_status(&slot), SlotStatus::empty());
+    }
+
+    #[test]
+    fn attention_button_and_power_off() {
+        let slot = HotplugSlot::new(5);
+        assert!(matches!(
+            slot.press_attention_button(),
+            Err(HotplugError::SlotEmpty)
+        ));
+
+        slot.insert(|| Ok(())).unwrap();
+        write_u16(&slot, OFFSET_SLOT_STATUS, SlotStatus::EVENTS.bits());
+        slot.press_attention_button().unwrap();
+        assert!(slot_status(&slot).contains(SlotStatus::ATTN_BTN));
+        assert!(!slot.wait_power_off(Duration::from_millis(1)));
+
+        // Powering off the slot takes the link down
+        write_u16(&slot, OFFSET_SLOT_CTL, SlotCtl::PWR_CTL_OFF.bits());
+        assert!(slot.wait_power_off(Duration::from_millis(1)));
+        assert!(slot_status(&slot).contains(SlotStatus::DLL_STATE_CHANGED));
+    }
+
+    #[test]
+    fn attention_button_cancelled() {
+        let slot = HotplugSlot::new(5);
+        slot.insert(|| Ok(())).unwrap();
+        write_u16(&slot, OFFSET_SLOT_STATUS, SlotStatus::EVENTS.bits());
+        assert!(!slot.cancel_attention());
+
+        slot.press_attention_button().unwrap();
+        assert!(slot.cancel_attention());
+        assert!(!slot_status(&slot).contains(SlotStatus::ATTN_BTN));
+
+        // Once the guest has acknowledged the press, it can't be withdrawn.
+        slot.press_attention_button().unwrap();
+        write_u16(&slot, OFFSET_SLOT_STATUS, SlotStatus::ATTN_BTN.bits());
+        assert!(!slot.cancel_attention());
+    }
+
+    #[test]
+    fn reset_keeps_device() {
+        let slot = HotplugSlot::new(5);
+        slot.insert(|| Ok(())).unwrap();
+        write_u16(&slot, OFFSET_SLOT_CTL, SlotCtl::PWR_CTL_OFF.bits());
+
+        slot.reset();
+        assert_eq!(read_u16(&slot, OFFSET_SLOT_CTL), 0);
+        assert_eq!(slot_status(&slot), SlotStatus::PRES_DET_STATE);
+    }
+}
@@ -20,6 +20,7 @@ pub mod bridge;
 pub mod bus;
 mod cfgspace;
 pub(crate) mod device;
+pub mod hotplug;
 pub mod topology;
 
 #[cfg(test)]
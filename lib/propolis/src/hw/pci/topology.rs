@@ -13,7 +13,7 @@ use crate::hw::ids;
 use crate::vmm::Machine;
 
 use super::bridge::Bridge;
-use super::{Bdf, Bus, BusLocation, Endpoint, LintrCfg};
+use super::{Bdf, Bus, BusLocation, BusNum, Endpoint, LintrCfg};
 
 use thiserror::Error;
 
@@ -42,6 +42,9 @@ pub enum PciTopologyError {
 
     #[error("A PCI device was already attached at {0:?}")]
     DeviceAlreadyAttached(Bdf),
+
+    #[error("No PCI device is attached at {0:?}")]
+    NoDeviceAttached(Bdf),
 }
 
 impl From<PciTopologyError> for IoError {
@@ -60,6 +63,10 @@ impl From<PciTopologyError> for IoError {
                 ErrorKind::AlreadyExists,
                 format!("Device at {} already attached", bdf),
             ),
+            NoDeviceAttached(bdf) => IoError::new(
+                ErrorKind::NotFound,
+                format!("No device attached at {}", bdf),
+            ),
         }
     }
 }
@@ -93,6 +100,30 @@ impl Topology {
         }
     }
 
+    /// Detaches the device at the supplied location on a logical bus in this
+    /// topology, returning it to the caller.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the logical bus is not present in the topology or if no
+    /// device is attached at the supplied location.
+    pub fn pci_detach(
+        &self,
+        bus: LogicalBusId,
+        location: BusLocation,
+    ) -> Result<Arc<dyn Endpoint>, PciTopologyError> {
+        let bus_index = self
+            .logical_buses
+            .get(&bus)
+            .ok_or(PciTopologyError::LogicalBusNotFound(bus))?;
+        self.buses[bus_index.0].detach(location).ok_or_else(|| {
+            PciTopologyError::NoDeviceAttached(Bdf {
+                bus: BusNum::new(bus.0),
+                location,
+            })
+        })
+    }
+
     /// Issues a configuration space I/O to a device at the supplied location.
     pub fn pci_cfg_rw(
         &self,
@@ -182,6 +213,7 @@ pub struct BridgeDescription {
     attachment_addr: Bdf,
     vendor_id: u16,
     device_id: u16,
+    hotplug_slot: Option<u16>,
 }
 
 impl BridgeDescription {
@@ -212,7 +244,20 @@ impl BridgeDescription {
         vendor_id: u16,
         device_id: u16,
     ) -> Self {
-        Self { downstream_bus_id, attachment_addr, vendor_id, device_id }
+        Self {
+            downstream_bus_id,
+            attachment_addr,
+            vendor_id,
+            device_id,
+            hotplug_slot: None,
+        }
+    }
+
+    /// Gives the bridge a PCIe hotplug slot with the supplied physical slot
+    /// number, into which a single device (at device 0, function 0 of the
+    /// downstream bus) can be inserted and removed at runtime.
+    pub fn with_hotplug_slot(self, physical_slot: u16) -> Self {
+        Self { hotplug_slot: Some(physical_slot), ..self }
     }
 }
 
@@ -312,12 +357,21 @@ impl Builder {
             .bridges
             .iter()
             .map(|bdesc| {
-                let bridge = Bridge::new(
-                    bdesc.vendor_id,
-                    bdesc.device_id,
-                    &topology,
-                    bdesc.downstream_bus_id,
-                );
+                let bridge = match bdesc.hotplug_slot {
+                    Some(physical_slot) => Bridge::new_hotplug(
+                        bdesc.vendor_id,
+                        bdesc.device_id,
+                        &topology,
+                        bdesc.downstream_bus_id,
+                        physical_slot,
+                    ),
+                    None => Bridge::new(
+                        bdesc.vendor_id,
+                        bdesc.device_id,
+                        &topology,
+                        bdesc.downstream_bus_id,
+                    ),
+                };
                 topology.pci_attach(
                     LogicalBusId(bdesc.attachment_addr.bus.get()),
                     bdesc.attachment_addr.location,
@@ -455,4 +509,35 @@ mod test {
         assert_eq!(bridges.len(), 1);
         assert_eq!(bridges[0].0, Bdf::new(0, 1, 0).unwrap());
     }
+
+    #[test]
+    fn detach() {
+        let machine = Machine::new_test().unwrap();
+
+        let mut builder = Builder::new();
+        assert!(builder
+            .add_bridge(BridgeDescription::new(
+                LogicalBusId(1),
+                Bdf::new(0, 1, 0).unwrap()
+            ))
+            .is_ok());
+        let topology = builder.finish(&machine).unwrap().topology;
+
+        let location = BusLocation::new(1, 0).unwrap();
+        assert!(topology.pci_detach(LogicalBusId(0), location).is_ok());
+        assert!(matches!(
+            topology.pci_detach(LogicalBusId(0), location),
+            Err(PciTopologyError::NoDeviceAttached(_))
+        ));
+        assert!(matches!(
+            topology.pci_detach(LogicalBusId(2), location),
+            Err(PciTopologyError::LogicalBusNotFound(_))
+        ));
+
+        let mut buf = [0u8; 1];
+        let mut ro = ReadOp::from_buf(0, &mut buf);
+        assert!(topology
+            .pci_cfg_rw(RoutedBusId(0), location, RWOp::Read(&mut ro))
+            .is_none());
+    }
 }
@@ -0,0 +1,507 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! PCI passthrough: assignment of a host PCI function (such as an SR-IOV
+//! virtual function) directly to the guest, by way of the ppt driver.
+//!
+//! The guest is presented with an emulated config space, mirroring the
+//! identity and BAR layout of the host device.  Once the guest places a
+//! memory BAR, it is mapped straight through to the host BAR, save for the
+//! pages holding the host MSI-X table and PBA.  Those are instead trapped, as
+//! the guest programs MSI-X through an emulated table (in a BAR of its own),
+//! the entries of which are relayed into the kernel for delivery.
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::hw::pci::{self, bits::*, BarN};
+use crate::migrate::*;
+use crate::vmm::VmmHdl;
+
+use bhyve_api::PptFd;
+
+// Offsets of host config space registers consulted during setup
+const CFG_VENDOR_ID: u64 = 0x00;
+const CFG_DEVICE_ID: u64 = 0x02;
+const CFG_COMMAND: u64 = 0x04;
+const CFG_STATUS: u64 = 0x06;
+const CFG_REVISION_ID: u64 = 0x08;
+const CFG_PROG_IF: u64 = 0x09;
+const CFG_SUBCLASS: u64 = 0x0a;
+const CFG_CLASS: u64 = 0x0b;
+const CFG_SUB_VENDOR_ID: u64 = 0x2c;
+const CFG_SUB_DEVICE_ID: u64 = 0x2e;
+const CFG_CAP_PTR: u64 = 0x34;
+
+const STATUS_CAP_LIST: u32 = 1 << 4;
+
+/// Size of each entry in an MSI-X table
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VEC_MASKED: u32 = 1 << 0;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum HostBarKind {
+    Pio,
+    Mmio,
+    Mmio64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct HostBar {
+    kind: HostBarKind,
+    base: u64,
+    size: u64,
+}
+
+/// Location of the MSI-X structures within the BARs of the host device
+#[derive(Copy, Clone, Debug)]
+struct HostMsix {
+    count: u16,
+    table_bar: BarN,
+    table_off: u64,
+    pba_bar: BarN,
+    pba_off: u64,
+}
+impl HostMsix {
+    /// Page-aligned ranges (as offset and length) of BAR `bar` which hold
+    /// parts of the MSI-X table or PBA, and must not be mapped into the guest.
+    fn holes(&self, bar: BarN) -> Vec<(u64, u64)> {
+        let table_len = u64::from(self.count) * MSIX_ENTRY_SIZE;
+        let pba_len = u64::from(self.count).div_ceil(64) * 8;
+
+        let mut holes = Vec::with_capacity(2);
+        if bar == self.table_bar {
+            holes.push(page_span(self.table_off, table_len));
+        }
+        if bar == self.pba_bar {
+            holes.push(page_span(self.pba_off, pba_len));
+        }
+        holes
+    }
+}
+
+/// Expand the range at `off` of `len` bytes out to page boundaries
+fn page_span(off: u64, len: u64) -> (u64, u64) {
+    let page = PAGE_SIZE as u64;
+    let start = off & !(page - 1);
+    let end = (off + len).next_multiple_of(page);
+    (start, end - start)
+}
+
+/// Split a BAR of `size` bytes into the (offset, length) segments which remain
+/// after excluding the page-aligned `holes`.
+fn map_segments(size: u64, mut holes: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    holes.sort_unstable();
+
+    let mut segs = Vec::new();
+    let mut pos = 0;
+    for (off, len) in holes {
+        if off > pos {
+            segs.push((pos, off - pos));
+        }
+        pos = pos.max(off + len);
+    }
+    if pos < size {
+        segs.push((pos, size - pos));
+    }
+    segs
+}
+
+struct PptState {
+    /// MSI-X is enabled for the device, so unmasked entries should be
+    /// programmed into the kernel
+    msix_enabled: bool,
+
+    /// Segments of each BAR (as guest-physical address and length) which are
+    /// currently mapped through to the host device
+    mapped: [Vec<(u64, u64)>; 6],
+}
+
+pub struct PciPpt {
+    pci_state: pci::DeviceState,
+    ppt: PptFd,
+    hdl: Arc<VmmHdl>,
+
+    bars: [Option<HostBar>; 6],
+    msix: HostMsix,
+    state: Mutex<PptState>,
+}
+
+impl PciPpt {
+    /// Assign the host device at `path` (a `/dev/pptN` device) to the VM.
+    pub fn create(path: &Path, hdl: Arc<VmmHdl>) -> Result<Arc<Self>> {
+        let ppt = PptFd::open(path)?;
+
+        let mut bind = bhyve_api::vm_pptdev { pptfd: ppt.as_raw_fd() };
+        unsafe { hdl.ioctl(bhyve_api::VM_BIND_PPTDEV, &mut bind) }?;
+
+        match Self::init(&ppt, &hdl) {
+            Ok((pci_state, bars, msix)) => Ok(Arc::new(Self {
+                pci_state,
+                ppt,
+                hdl,
+                bars,
+                msix,
+                state: Mutex::new(PptState {
+                    msix_enabled: false,
+                    mapped: Default::default(),
+                }),
+            })),
+            Err(e) => {
+                let _ = unsafe {
+                    hdl.ioctl(bhyve_api::VM_UNBIND_PPTDEV, &mut bind)
+                };
+                Err(e)
+            }
+        }
+    }
+
+    fn init(
+        ppt: &PptFd,
+        hdl: &VmmHdl,
+    ) -> Result<(pci::DeviceState, [Option<HostBar>; 6], HostMsix)> {
+        let ident = pci::Ident {
+            vendor_id: ppt.cfg_read(CFG_VENDOR_ID, 2)? as u16,
+            device_id: ppt.cfg_read(CFG_DEVICE_ID, 2)? as u16,
+            class: ppt.cfg_read(CFG_CLASS, 1)? as u8,
+            subclass: ppt.cfg_read(CFG_SUBCLASS, 1)? as u8,
+            prog_if: ppt.cfg_read(CFG_PROG_IF, 1)? as u8,
+            revision_id: ppt.cfg_read(CFG_REVISION_ID, 1)? as u8,
+            sub_vendor_id: ppt.cfg_read(CFG_SUB_VENDOR_ID, 2)? as u16,
+            sub_device_id: ppt.cfg_read(CFG_SUB_DEVICE_ID, 2)? as u16,
+        };
+
+        let bars = Self::query_bars(ppt)?;
+        let msix = Self::find_msix(ppt, hdl)?;
+        if bars[msix.table_bar as usize].is_none()
+            || bars[msix.pba_bar as usize].is_none()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "MSI-X structures reside in unimplemented BAR",
+            ));
+        }
+
+        let mut builder = pci::Builder::new(ident);
+        let mut used = [false; 6];
+        for (idx, bar) in bars.iter().enumerate() {
+            let Some(bar) = bar else {
+                continue;
+            };
+            let n = BarN::from_repr(idx as u8).unwrap();
+            used[idx] = true;
+            builder = match bar.kind {
+                HostBarKind::Pio => builder.add_bar_io(n, bar.size as u16),
+                HostBarKind::Mmio => builder.add_bar_mmio(n, bar.size as u32),
+                HostBarKind::Mmio64 => {
+                    used[idx + 1] = true;
+                    builder.add_bar_mmio64(n, bar.size)
+                }
+            };
+        }
+
+        // The emulated MSI-X table needs a BAR of its own
+        let spare = used.iter().position(|u| !u).ok_or_else(|| {
+            Error::new(ErrorKind::Unsupported, "no BAR free for MSI-X table")
+        })?;
+        let builder = builder
+            .add_cap_msix(BarN::from_repr(spare as u8).unwrap(), msix.count);
+
+        // The device can only be useful with decoding and DMA enabled on the
+        // host side, whatever the guest chooses to do with its command
+        // register.
+        let cmd = ppt.cfg_read(CFG_COMMAND, 2)?;
+        let ena = RegCmd::IO_EN | RegCmd::MMIO_EN | RegCmd::BUSMSTR_EN;
+        ppt.cfg_write(CFG_COMMAND, 2, cmd | u32::from(ena.bits()))?;
+
+        Ok((builder.finish(), bars, msix))
+    }
+
+    fn query_bars(ppt: &PptFd) -> Result<[Option<HostBar>; 6]> {
+        let mut bars = [None; 6];
+        let mut idx = 0;
+        while idx < 6 {
+            let res = ppt.bar_query(idx as u32)?;
+            let kind = match res.pbq_type {
+                bhyve_api::PCI_ADDR_IO => HostBarKind::Pio,
+                bhyve_api::PCI_ADDR_MEM32 => HostBarKind::Mmio,
+                bhyve_api::PCI_ADDR_MEM64 if idx < 5 => HostBarKind::Mmio64,
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            };
+            let size = res.pbq_size;
+            let valid = match kind {
+                HostBarKind::Pio => size >= 4 && size <= u64::from(u16::MAX),
+                HostBarKind::Mmio => {
+                    size >= PAGE_SIZE as u64 && size <= u64::from(u32::MAX)
+                }
+                // Memory BARs are mapped directly into the guest, so they
+                // must span whole pages.
+                HostBarKind::Mmio64 => size >= PAGE_SIZE as u64,
+            };
+            if !valid || !size.is_power_of_two() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("BAR{idx} of unsupported size {size:#x}"),
+                ));
+            }
+            bars[idx] = Some(HostBar { kind, base: res.pbq_base, size });
+            idx += if kind == HostBarKind::Mmio64 { 2 } else { 1 };
+        }
+        Ok(bars)
+    }
+
+    fn find_msix(ppt: &PptFd, hdl: &VmmHdl) -> Result<HostMsix> {
+        let no_msix =
+            || Error::new(ErrorKind::Unsupported, "device lacks MSI-X support");
+
+        let mut limits = bhyve_api::vm_pptdev_limits {
+            pptfd: ppt.as_raw_fd(),
+            ..Default::default()
+        };
+        unsafe { hdl.ioctl(bhyve_api::VM_GET_PPTDEV_LIMITS, &mut limits) }?;
+        if limits.msix_limit <= 0 {
+            return Err(no_msix());
+        }
+
+        if ppt.cfg_read(CFG_STATUS, 2)? & STATUS_CAP_LIST == 0 {
+            return Err(no_msix());
+        }
+        let mut ptr = ppt.cfg_read(CFG_CAP_PTR, 1)? as u64 & !0b11;
+        // Bound the walk, lest a malformed list send us in circles
+        for _ in 0..48 {
+            if ptr == 0 {
+                break;
+            }
+            let id = ppt.cfg_read(ptr, 1)? as u8;
+            if id == CAP_ID_MSIX {
+                let ctrl = ppt.cfg_read(ptr + 2, 2)?;
+                let table = ppt.cfg_read(ptr + 4, 4)?;
+                let pba = ppt.cfg_read(ptr + 8, 4)?;
+
+                let bar = |val: u32| {
+                    BarN::from_repr((val & 0b111) as u8).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidData, "bad MSI-X BIR")
+                    })
+                };
+                let size = (ctrl & 0x7ff) as u16 + 1;
+                return Ok(HostMsix {
+                    count: size.min(limits.msix_limit as u16),
+                    table_bar: bar(table)?,
+                    table_off: u64::from(table & !0b111),
+                    pba_bar: bar(pba)?,
+                    pba_off: u64::from(pba & !0b111),
+                });
+            }
+            ptr = ppt.cfg_read(ptr + 1, 1)? as u64 & !0b11;
+        }
+        Err(no_msix())
+    }
+
+    fn unmap_bar(&self, state: &mut PptState, bar: BarN) {
+        for (gpa, len) in state.mapped[bar as usize].drain(..) {
+            let mut req = bhyve_api::vm_pptdev_mmio {
+                pptfd: self.ppt.as_raw_fd(),
+                gpa,
+                hpa: 0,
+                len: len as usize,
+            };
+            let _ = unsafe {
+                self.hdl.ioctl(bhyve_api::VM_UNMAP_PPTDEV_MMIO, &mut req)
+            };
+        }
+    }
+
+    fn map_bar(&self, state: &mut PptState, bar: BarN, addr: u64) {
+        let host = self.bars[bar as usize].unwrap();
+        for (off, len) in map_segments(host.size, self.msix.holes(bar)) {
+            let mut req = bhyve_api::vm_pptdev_mmio {
+                pptfd: self.ppt.as_raw_fd(),
+                gpa: addr + off,
+                hpa: host.base + off,
+                len: len as usize,
+            };
+            let res = unsafe {
+                self.hdl.ioctl(bhyve_api::VM_MAP_PPTDEV_MMIO, &mut req)
+            };
+            match res {
+                Ok(()) => state.mapped[bar as usize].push((req.gpa, len)),
+                Err(_) => probes::ppt_map_fail!(|| (bar as u8, req.gpa)),
+            }
+        }
+    }
+
+    /// Relay the guest-programmed contents of MSI-X entry `idx` to the kernel
+    fn program_msix(&self, state: &PptState, idx: u16) {
+        let hdl = self.pci_state.msix_hdl().unwrap();
+        let ent = hdl.read(idx);
+        let masked = !state.msix_enabled || ent.masked;
+        let mut req = bhyve_api::vm_pptdev_msix {
+            vcpu: 0,
+            pptfd: self.ppt.as_raw_fd(),
+            idx: i32::from(idx),
+            msg: u64::from(ent.data),
+            vector_control: if masked { MSIX_VEC_MASKED } else { 0 },
+            addr: ent.addr,
+        };
+        if unsafe { self.hdl.ioctl(bhyve_api::VM_PPTDEV_MSIX, &mut req) }
+            .is_err()
+        {
+            probes::ppt_msix_fail!(|| idx);
+        }
+    }
+
+    fn program_msix_all(&self, state: &PptState) {
+        for idx in 0..self.msix.count {
+            self.program_msix(state, idx);
+        }
+    }
+}
+
+impl pci::Device for PciPpt {
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+
+    fn bar_rw(&self, bar: BarN, rwo: RWOp) {
+        let Some(host) = self.bars[bar as usize] else {
+            return;
+        };
+        match (host.kind, rwo) {
+            (HostBarKind::Pio, RWOp::Read(ro)) => {
+                let (off, width) = (ro.offset() as u32, ro.len() as u32);
+                let val =
+                    self.ppt.bar_read(bar as u32, off, width).unwrap_or(!0);
+                match width {
+                    1 => ro.write_u8(val as u8),
+                    2 => ro.write_u16(val as u16),
+                    4 => ro.write_u32(val),
+                    _ => ro.fill(0xff),
+                }
+            }
+            (HostBarKind::Pio, RWOp::Write(wo)) => {
+                let (off, width) = (wo.offset() as u32, wo.len() as u32);
+                let val = match width {
+                    1 => u32::from(wo.read_u8()),
+                    2 => u32::from(wo.read_u16()),
+                    4 => wo.read_u32(),
+                    _ => return,
+                };
+                let _ = self.ppt.bar_write(bar as u32, off, width, val);
+            }
+            // Accesses to memory BARs only trap for the pages covering the
+            // host MSI-X table and PBA.  The guest uses the emulated MSI-X
+            // table instead, so the host structures read as zero and ignore
+            // writes.  (Any other registers sharing those pages are, for now,
+            // inaccessible.)
+            (_, RWOp::Read(ro)) => ro.fill(0),
+            (_, RWOp::Write(_)) => {}
+        }
+    }
+
+    fn bar_update(&self, bar: BarN, addr: Option<u64>) {
+        let Some(host) = self.bars[bar as usize] else {
+            return;
+        };
+        if host.kind == HostBarKind::Pio {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.unmap_bar(&mut state, bar);
+        if let Some(addr) = addr {
+            self.map_bar(&mut state, bar, addr);
+        }
+    }
+
+    fn interrupt_mode_change(&self, mode: pci::IntrMode) {
+        let mut state = self.state.lock().unwrap();
+        state.msix_enabled = mode == pci::IntrMode::Msix;
+        self.program_msix_all(&state);
+    }
+
+    fn msi_update(&self, info: pci::MsiUpdate) {
+        let state = self.state.lock().unwrap();
+        match info {
+            pci::MsiUpdate::MaskAll | pci::MsiUpdate::UnmaskAll => {
+                self.program_msix_all(&state)
+            }
+            pci::MsiUpdate::Modify(idx) => self.program_msix(&state, idx),
+        }
+    }
+}
+
+impl Lifecycle for PciPpt {
+    fn type_name(&self) -> &'static str {
+        "pci-ppt"
+    }
+
+    fn reset(&self) {
+        self.pci_state.reset(self);
+    }
+
+    fn halt(&self) {
+        let mut state = self.state.lock().unwrap();
+        for n in 0..6 {
+            self.unmap_bar(&mut state, BarN::from_repr(n).unwrap());
+        }
+        drop(state);
+
+        let mut bind = bhyve_api::vm_pptdev { pptfd: self.ppt.as_raw_fd() };
+        let _ =
+            unsafe { self.hdl.ioctl(bhyve_api::VM_UNBIND_PPTDEV, &mut bind) };
+    }
+
+    fn migrate(&self) -> Migrator {
+        // The state of the host device is opaque to us, and it cannot follow
+        // the guest to another machine in any case.
+        Migrator::NonMigratable
+    }
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn ppt_map_fail(bar: u8, gpa: u64) {}
+    fn ppt_msix_fail(idx: u16) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn segments_skip_msix_pages() {
+        let page = PAGE_SIZE as u64;
+        let msix = HostMsix {
+            count: 64,
+            table_bar: BarN::BAR0,
+            table_off: 0x2000,
+            pba_bar: BarN::BAR0,
+            pba_off: 0x3800,
+        };
+
+        // The table and PBA sit in the third and fourth pages of the BAR
+        let holes = msix.holes(BarN::BAR0);
+        assert_eq!(holes, vec![(0x2000, page), (0x3000, page)]);
+        assert_eq!(
+            map_segments(0x8000, holes),
+            vec![(0, 0x2000), (0x4000, 0x4000)]
+        );
+
+        // Other BARs are mapped whole
+        assert_eq!(msix.holes(BarN::BAR2), vec![]);
+        assert_eq!(map_segments(0x4000, vec![]), vec![(0, 0x4000)]);
+    }
+
+    #[test]
+    fn segments_overlapping_holes() {
+        let holes = vec![(0x1000, 0x2000), (0, 0x1000), (0x2000, 0x1000)];
+        assert_eq!(map_segments(0x4000, holes), vec![(0x3000, 0x1000)]);
+        assert_eq!(map_segments(0x1000, vec![(0, 0x1000)]), vec![]);
+    }
+}
@@ -3,7 +3,6 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::collections::VecDeque;
-use std::convert::TryFrom;
 use std::mem::replace;
 use std::sync::{Arc, Mutex};
 
@@ -15,6 +14,7 @@ use crate::pio::{PioBus, PioFn};
 
 use rfb::rfb::KeyEvent;
 
+use super::keyboard::keymap::Keymap;
 use super::keyboard::KeyEventRep;
 
 /// PS/2 Controller (Intel 8042) Emulation
@@ -305,6 +305,10 @@ struct PS2State {
     pri_pin: Option<Box<dyn IntrPin>>,
     aux_pin: Option<Box<dyn IntrPin>>,
     reset_pin: Option<Arc<dyn IntrPin>>,
+
+    /// Translation of keysyms from the VNC client for the guest's layout.
+    /// This is host configuration, so it is neither reset nor migrated.
+    keymap: Keymap,
 }
 
 pub struct PS2Ctrl {
@@ -335,12 +339,18 @@ impl PS2Ctrl {
         state.reset_pin = Some(reset_pin);
     }
 
+    /// Sets the keymap used to translate key events for the guest's keyboard
+    /// layout.
+    pub fn set_keymap(&self, keymap: Keymap) {
+        self.state.lock().unwrap().keymap = keymap;
+    }
+
     pub fn key_event(&self, ke: KeyEvent) {
         let mut state = self.state.lock().unwrap();
         let translate = state.ctrl_cfg.contains(CtrlCfg::PRI_XLATE_EN);
         let key_rep;
 
-        match KeyEventRep::try_from(ke) {
+        match KeyEventRep::from_key_event(ke, &state.keymap) {
             Ok(kr) => {
                 key_rep = kr;
             }
@@ -702,6 +712,10 @@ impl MigrateSingle for PS2Ctrl {
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::PS2CtrlV1>();
+    }
 }
 
 // Keyboard-specific commands
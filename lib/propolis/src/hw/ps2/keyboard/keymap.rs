@@ -0,0 +1,329 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Translation of keysyms to keys according to the guest's keyboard layout.
+//!
+//! A VNC client sends the keysym a key produces on the client's own layout,
+//! not which key was pressed.  Without a keymap, each keysym is translated to
+//! the key which produces it on a US keyboard, so a guest configured with any
+//! other layout would see the wrong keys pressed.  A keymap instead maps
+//! keysyms to the keys which produce them on the guest's layout, leaving any
+//! modifiers (such as Shift or AltGr) to the key events the client sends for
+//! them.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use super::scan_code_1::*;
+use super::scan_code_2::*;
+use super::ScanCodeBase;
+
+/// The Scan Code Set 1 and Set 2 codes of each key which may be the target of
+/// a keymap entry.
+const SC_SET_PAIRS: &[(u8, u8)] = &[
+    (SC1_A, SC2_A),
+    (SC1_B, SC2_B),
+    (SC1_C, SC2_C),
+    (SC1_D, SC2_D),
+    (SC1_E, SC2_E),
+    (SC1_F, SC2_F),
+    (SC1_G, SC2_G),
+    (SC1_H, SC2_H),
+    (SC1_I, SC2_I),
+    (SC1_J, SC2_J),
+    (SC1_K, SC2_K),
+    (SC1_L, SC2_L),
+    (SC1_M, SC2_M),
+    (SC1_N, SC2_N),
+    (SC1_O, SC2_O),
+    (SC1_P, SC2_P),
+    (SC1_Q, SC2_Q),
+    (SC1_R, SC2_R),
+    (SC1_S, SC2_S),
+    (SC1_T, SC2_T),
+    (SC1_U, SC2_U),
+    (SC1_V, SC2_V),
+    (SC1_W, SC2_W),
+    (SC1_X, SC2_X),
+    (SC1_Y, SC2_Y),
+    (SC1_Z, SC2_Z),
+    (SC1_0_RPAREN, SC2_0_RPAREN),
+    (SC1_1_EXCLAMATION, SC2_1_EXCLAMATION),
+    (SC1_2_AT, SC2_2_AT),
+    (SC1_3_HASH, SC2_3_HASH),
+    (SC1_4_DOLLAR, SC2_4_DOLLAR),
+    (SC1_5_PERCENT, SC2_5_PERCENT),
+    (SC1_6_CARET, SC2_6_CARET),
+    (SC1_7_AMPERSAND, SC2_7_AMPERSAND),
+    (SC1_8_ASTERISK, SC2_8_ASTERISK),
+    (SC1_9_LPAREN, SC2_9_LPAREN),
+    (SC1_1_END, SC2_1_END),
+    (SC1_2_DOWN, SC2_2_DOWN),
+    (SC1_3_PGDN, SC2_3_PGDN),
+    (SC1_4_LEFT, SC2_4_LEFT),
+    (SC1_5_CENTER, SC2_5_CENTER),
+    (SC1_6_RIGHT, SC2_6_RIGHT),
+    (SC1_7_HOME, SC2_7_HOME),
+    (SC1_8_UP, SC2_8_UP),
+    (SC1_9_PGUP, SC2_9_PGUP),
+    (SC1_0_INSERT, SC2_0_INSERT),
+    (SC1_QUOTE_DBLQUOTE, SC2_QUOTE_DBLQUOTE),
+    (SC1_COMMA_LESSTHAN, SC2_COMMA_LESSTHAN),
+    (SC1_DASH_UNDERSCORE, SC2_DASH_UNDERSCORE),
+    (SC1_PERIOD_GREATERTHAN, SC2_PERIOD_GREATERTHAN),
+    (SC1_SLASH_QUESTIONMARK, SC2_SLASH_QUESTIONMARK),
+    (SC1_SEMICOLON_COLON, SC2_SEMICOLON_COLON),
+    (SC1_EQUALS_PLUS, SC2_EQUALS_PLUS),
+    (SC1_LBRACKET_LCURLY, SC2_LBRACKET_LCURLY),
+    (SC1_BACKSLASH_PIPE, SC2_BACKSLASH_PIPE),
+    (SC1_RBRACKET_RCURLY, SC2_RBRACKET_RCURLY),
+    (SC1_BACKTICK_TILDE, SC2_BACKTICK_TILDE),
+    (SC1_BACKSPACE, SC2_BACKSPACE),
+    (SC1_TAB, SC2_TAB),
+    (SC1_SPACE, SC2_SPACE),
+    (SC1_DELETE, SC2_DELETE),
+    (SC1_ENTER, SC2_ENTER),
+    (SC1_ESCAPE, SC2_ESCAPE),
+    (SC1_PRINTSCREEN, SC2_PRINTSCREEN),
+    (SC1_F1, SC2_F1),
+    (SC1_F2, SC2_F2),
+    (SC1_F3, SC2_F3),
+    (SC1_F4, SC2_F4),
+    (SC1_F5, SC2_F5),
+    (SC1_F6, SC2_F6),
+    (SC1_F7, SC2_F7),
+    (SC1_F8, SC2_F8),
+    (SC1_F9, SC2_F9),
+    (SC1_F10, SC2_F10),
+    (SC1_F11, SC2_F11),
+    (SC1_F12, SC2_F12),
+    (SC1_KP_MINUS, SC2_KP_MINUS),
+    (SC1_KP_PLUS, SC2_KP_PLUS),
+    (SC1_CTRL_LEFT, SC2_CTRL_LEFT),
+    (SC1_SHIFT_LEFT, SC2_SHIFT_LEFT),
+    (SC1_CAPS_LOCK, SC2_CAPS_LOCK),
+    (SC1_NUM_LOCK, SC2_NUM_LOCK),
+    (SC1_SCROLL_LOCK, SC2_SCROLL_LOCK),
+    (SC1_SHIFT_RIGHT, SC2_SHIFT_RIGHT),
+    (SC1_ALT_LEFT, SC2_ALT_LEFT),
+    (SC1_SUPER_LEFT, SC2_SUPER_LEFT),
+    (SC1_SUPER_RIGHT, SC2_SUPER_RIGHT),
+    (SC1_MENU, SC2_MENU),
+    (SC1_NONUS_BACKSLASH, SC2_NONUS_BACKSLASH),
+];
+
+/// Marks a keymap target as an extended key
+const EXTENDED: u16 = (SC1_EXTENDED_PREFIX_0 as u16) << 8;
+
+// Keysyms which have no ASCII equivalent
+const XK_SECTION: u32 = 0x00a7;
+const XK_STERLING: u32 = 0x00a3;
+const XK_NOTSIGN: u32 = 0x00ac;
+const XK_DEGREE: u32 = 0x00b0;
+const XK_TWOSUPERIOR: u32 = 0x00b2;
+const XK_THREESUPERIOR: u32 = 0x00b3;
+const XK_MU: u32 = 0x00b5;
+const XK_ADIAERESIS_UPPER: u32 = 0x00c4;
+const XK_ODIAERESIS_UPPER: u32 = 0x00d6;
+const XK_UDIAERESIS_UPPER: u32 = 0x00dc;
+const XK_SSHARP: u32 = 0x00df;
+const XK_ADIAERESIS: u32 = 0x00e4;
+const XK_ODIAERESIS: u32 = 0x00f6;
+const XK_UDIAERESIS: u32 = 0x00fc;
+const XK_EURO_SIGN: u32 = 0x20ac;
+const XK_ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+const XK_DEAD_GRAVE: u32 = 0xfe50;
+const XK_DEAD_ACUTE: u32 = 0xfe51;
+const XK_DEAD_CIRCUMFLEX: u32 = 0xfe52;
+const XK_MODE_SWITCH: u32 = 0xff7e;
+
+fn ascii(c: char) -> u32 {
+    c as u32
+}
+
+/// German (QWERTZ) layout, as it differs from the US layout
+fn layout_de() -> Vec<(u32, u16)> {
+    let mut keys = vec![
+        (XK_SSHARP, SC1_DASH_UNDERSCORE as u16),
+        (XK_DEAD_ACUTE, SC1_EQUALS_PLUS as u16),
+        (XK_DEAD_GRAVE, SC1_EQUALS_PLUS as u16),
+        (XK_UDIAERESIS, SC1_LBRACKET_LCURLY as u16),
+        (XK_UDIAERESIS_UPPER, SC1_LBRACKET_LCURLY as u16),
+        (XK_ODIAERESIS, SC1_SEMICOLON_COLON as u16),
+        (XK_ODIAERESIS_UPPER, SC1_SEMICOLON_COLON as u16),
+        (XK_ADIAERESIS, SC1_QUOTE_DBLQUOTE as u16),
+        (XK_ADIAERESIS_UPPER, SC1_QUOTE_DBLQUOTE as u16),
+        (XK_DEAD_CIRCUMFLEX, SC1_BACKTICK_TILDE as u16),
+        (XK_DEGREE, SC1_BACKTICK_TILDE as u16),
+        (XK_SECTION, SC1_3_HASH as u16),
+        (XK_TWOSUPERIOR, SC1_2_AT as u16),
+        (XK_THREESUPERIOR, SC1_3_HASH as u16),
+        (XK_MU, SC1_M as u16),
+        (XK_EURO_SIGN, SC1_E as u16),
+        (XK_ISO_LEVEL3_SHIFT, EXTENDED | SC1_ALT_RIGHT as u16),
+        (XK_MODE_SWITCH, EXTENDED | SC1_ALT_RIGHT as u16),
+    ];
+    for (chars, key) in [
+        ("yY", SC1_Z),
+        ("zZ", SC1_Y),
+        ("\"", SC1_2_AT),
+        ("&", SC1_6_CARET),
+        ("/{", SC1_7_AMPERSAND),
+        ("([", SC1_8_ASTERISK),
+        (")]", SC1_9_LPAREN),
+        ("=}", SC1_0_RPAREN),
+        ("?\\", SC1_DASH_UNDERSCORE),
+        ("+*~", SC1_RBRACKET_RCURLY),
+        ("#'", SC1_BACKSLASH_PIPE),
+        ("^", SC1_BACKTICK_TILDE),
+        ("@", SC1_Q),
+        (";", SC1_COMMA_LESSTHAN),
+        (":", SC1_PERIOD_GREATERTHAN),
+        ("-_", SC1_SLASH_QUESTIONMARK),
+        ("<>|", SC1_NONUS_BACKSLASH),
+    ] {
+        keys.extend(chars.chars().map(|c| (ascii(c), key as u16)));
+    }
+    keys
+}
+
+/// United Kingdom layout, as it differs from the US layout
+fn layout_gb() -> Vec<(u32, u16)> {
+    let mut keys = vec![
+        (XK_STERLING, SC1_3_HASH as u16),
+        (XK_NOTSIGN, SC1_BACKTICK_TILDE as u16),
+        (XK_EURO_SIGN, SC1_4_DOLLAR as u16),
+        (XK_ISO_LEVEL3_SHIFT, EXTENDED | SC1_ALT_RIGHT as u16),
+    ];
+    for (chars, key) in [
+        ("\"", SC1_2_AT),
+        ("@", SC1_QUOTE_DBLQUOTE),
+        ("#~", SC1_BACKSLASH_PIPE),
+        ("\\|", SC1_NONUS_BACKSLASH),
+    ] {
+        keys.extend(chars.chars().map(|c| (ascii(c), key as u16)));
+    }
+    keys
+}
+
+/// A translation of keysyms to the keys which produce them, identified by
+/// their Scan Code Set 1 make codes.  The make codes of extended keys carry
+/// the `0xe0` prefix in their upper byte.
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    keys: HashMap<u32, u16>,
+}
+
+impl Keymap {
+    /// The names of the layouts accepted by [`Keymap::for_layout`]
+    pub const LAYOUTS: &'static [&'static str] = &["us", "de", "gb"];
+
+    /// Creates the keymap for a built-in keyboard layout.
+    pub fn for_layout(layout: &str) -> Result<Self> {
+        let keys = match layout {
+            "us" => Vec::new(),
+            "de" => layout_de(),
+            "gb" => layout_gb(),
+            _ => {
+                return Err(anyhow!(
+                    "unknown keyboard layout {:?} (expected one of {:?})",
+                    layout,
+                    Self::LAYOUTS
+                ))
+            }
+        };
+        Ok(Self { keys: keys.into_iter().collect() })
+    }
+
+    /// Translates `keysym` to the key with Scan Code Set 1 make code
+    /// `scan_code`, replacing any existing translation.
+    pub fn insert(&mut self, keysym: u32, scan_code: u16) -> Result<()> {
+        if Self::scan_codes(scan_code).is_none() {
+            return Err(anyhow!(
+                "scan code 0x{:x} for keysym 0x{:x} is not a known key",
+                scan_code,
+                keysym
+            ));
+        }
+        self.keys.insert(keysym, scan_code);
+        Ok(())
+    }
+
+    /// Gets the scan codes, in sets 1 and 2, of the key to which `keysym` is
+    /// translated, if any.
+    pub(super) fn lookup(
+        &self,
+        keysym: u32,
+    ) -> Option<(ScanCodeBase, ScanCodeBase)> {
+        Self::scan_codes(*self.keys.get(&keysym)?)
+    }
+
+    fn scan_codes(scan_code: u16) -> Option<(ScanCodeBase, ScanCodeBase)> {
+        let [prefix, base] = scan_code.to_be_bytes();
+        let extended = match prefix {
+            0 => false,
+            SC1_EXTENDED_PREFIX_0 => true,
+            _ => return None,
+        };
+        let (base_1, base_2) =
+            SC_SET_PAIRS.iter().find(|(sc1, _)| *sc1 == base)?;
+        let prefix = |p| extended.then(|| vec![p]);
+        Some((
+            ScanCodeBase {
+                base_val: *base_1,
+                prefix: prefix(SC1_EXTENDED_PREFIX_0),
+            },
+            ScanCodeBase {
+                base_val: *base_2,
+                prefix: prefix(SC2_EXTENDED_PREFIX_0),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_pairs_consistent() {
+        // Keys sharing a code in one set must share a code in the other.
+        for (sc1, sc2) in SC_SET_PAIRS {
+            for (other1, other2) in SC_SET_PAIRS {
+                assert_eq!(sc1 == other1, sc2 == other2);
+            }
+        }
+    }
+
+    #[test]
+    fn layouts() {
+        for layout in Keymap::LAYOUTS {
+            let keymap = Keymap::for_layout(layout).unwrap();
+            for keysym in keymap.keys.keys() {
+                assert!(keymap.lookup(*keysym).is_some());
+            }
+        }
+        assert!(Keymap::for_layout("xx").is_err());
+
+        let de = Keymap::for_layout("de").unwrap();
+        let (sc1, sc2) = de.lookup(ascii('z')).unwrap();
+        assert_eq!((sc1.base_val, sc2.base_val), (SC1_Y, SC2_Y));
+        let (sc1, sc2) = de.lookup(XK_ISO_LEVEL3_SHIFT).unwrap();
+        assert_eq!(sc1.prefix, Some(vec![SC1_EXTENDED_PREFIX_0]));
+        assert_eq!(sc2.base_val, SC2_ALT_RIGHT);
+        assert!(de.lookup(ascii('a')).is_none());
+    }
+
+    #[test]
+    fn custom_entries() {
+        let mut keymap = Keymap::for_layout("us").unwrap();
+        keymap.insert(XK_EURO_SIGN, SC1_E as u16).unwrap();
+        assert!(keymap.lookup(XK_EURO_SIGN).is_some());
+
+        assert!(keymap.insert(XK_MU, 0x7f).is_err());
+        assert!(keymap.insert(XK_MU, 0xe1_00 | SC1_M as u16).is_err());
+        assert!(keymap.lookup(XK_MU).is_none());
+    }
+}
@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod keymap;
 mod scan_code_1;
 mod scan_code_2;
 
@@ -16,6 +17,7 @@ use scan_code_1::*;
 use scan_code_2::*;
 
 use super::ctrl::PS2ScanCodeSet;
+use keymap::Keymap;
 
 /// A struct that contains all information necessary to construct a scan code
 /// for multiple scan code sets, as scan codes from all sets have a similar
@@ -50,7 +52,8 @@ use super::ctrl::PS2ScanCodeSet;
 /// - multibyte sequences composed of 2 or more break codes
 ///
 /// Exceptions:
-/// - In Set 2, the pause key does not have a break code.
+/// - The pause key does not have a break code, and its make code is a fixed
+///   sequence in each set.
 ///
 #[derive(Debug)]
 pub struct ScanCodeBase {
@@ -71,8 +74,36 @@ pub struct KeyEventRep {
 }
 
 impl KeyEventRep {
+    /// Translate a key event, preferring the key to which `keymap` maps its
+    /// keysym over the key which produces it on a US keyboard.
+    pub(crate) fn from_key_event(
+        keyevent: KeyEvent,
+        keymap: &Keymap,
+    ) -> Result<Self> {
+        match keymap.lookup(keyevent.keysym_raw()) {
+            Some((scan_code_1, scan_code_2)) => Ok(Self {
+                keysym: keyevent.keysym(),
+                keysym_raw: keyevent.keysym_raw(),
+                is_pressed: keyevent.is_pressed(),
+                scan_code_1,
+                scan_code_2,
+            }),
+            None => Self::try_from(keyevent),
+        }
+    }
+
     /// Convert the given key to its scan code within a given scan code set.
     pub(crate) fn to_scan_code(&self, sc_set: PS2ScanCodeSet) -> Vec<u8> {
+        if matches!(self.keysym, Pause) {
+            if !self.is_pressed {
+                return Vec::new();
+            }
+            return match sc_set {
+                PS2ScanCodeSet::Set1 => SC1_PAUSE.to_vec(),
+                PS2ScanCodeSet::Set2 => SC2_PAUSE.to_vec(),
+            };
+        }
+
         let mut bytes = Vec::new();
         let sc = match sc_set {
             PS2ScanCodeSet::Set1 => &self.scan_code_1,
@@ -238,6 +269,7 @@ impl TryFrom<KeyEvent> for KeyEventRep {
             PageUp => (SC1_PGUP, SC2_PGUP),
             PageDown => (SC1_PGDN, SC2_PGDN),
             Print => (SC1_PRINTSCREEN, SC2_PRINTSCREEN),
+            Menu => (SC1_MENU, SC2_MENU),
             CapsLock => (SC1_CAPS_LOCK, SC2_CAPS_LOCK),
             SuperLeft => (SC1_SUPER_LEFT, SC2_SUPER_LEFT),
             SuperRight => (SC1_SUPER_RIGHT, SC2_SUPER_RIGHT),
@@ -288,8 +320,12 @@ impl TryFrom<KeyEvent> for KeyEventRep {
             Keypad8 | KeypadUp => (SC1_8_UP, SC2_8_UP),
             Keypad9 | KeypadPgUp => (SC1_9_PGUP, SC2_9_PGUP),
 
+            // The pause key is sent as a fixed sequence by to_scan_code(), so
+            // these only mark it as recognized.
+            Pause => (SC1_PAUSE[0], SC2_PAUSE[0]),
+
             // Keys we're choosing to drop explicitly for now
-            FunctionKey(_) | Pause | Menu => (0x0, 0x0),
+            FunctionKey(_) => (0x0, 0x0),
         };
 
         if matches!((base_val_1, base_val_2), (0x0, 0x0)) {
@@ -300,16 +336,20 @@ impl TryFrom<KeyEvent> for KeyEventRep {
         }
 
         let prefix_1 = match keyevent.keysym() {
-            AltRight | ControlRight | Home | Insert | End | PageUp
-            | PageDown | KeypadSlash | KeypadEnter | SuperLeft | SuperRight
-            | Left | Right | Up | Down => Some(vec![SC1_EXTENDED_PREFIX_0]),
+            AltRight | ControlRight | Home | Insert | Delete | End | PageUp
+            | PageDown | Print | KeypadSlash | KeypadEnter | SuperLeft
+            | SuperRight | Menu | Left | Right | Up | Down => {
+                Some(vec![SC1_EXTENDED_PREFIX_0])
+            }
             _ => None,
         };
 
         let prefix_2 = match keyevent.keysym() {
-            AltRight | ControlRight | Home | Insert | End | PageUp
-            | PageDown | KeypadSlash | KeypadEnter | SuperLeft | SuperRight
-            | Left | Right | Up | Down => Some(vec![SC2_EXTENDED_PREFIX_0]),
+            AltRight | ControlRight | Home | Insert | Delete | End | PageUp
+            | PageDown | Print | KeypadSlash | KeypadEnter | SuperLeft
+            | SuperRight | Menu | Left | Right | Up | Down => {
+                Some(vec![SC2_EXTENDED_PREFIX_0])
+            }
             _ => None,
         };
 
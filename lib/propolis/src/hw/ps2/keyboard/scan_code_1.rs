@@ -122,7 +122,12 @@ pub const SC1_SHIFT_RIGHT: u8 = 0x36;
 pub const SC1_ALT_LEFT: u8 = 0x38;
 pub const SC1_ALT_RIGHT: u8 = 0x38;
 
-// TODO(JPH): pause key
+// The pause key has no break code, and its make code is a sequence of its own
+pub const SC1_PAUSE: [u8; 6] = [0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5];
 
 pub const SC1_SUPER_LEFT: u8 = 0x5b;
 pub const SC1_SUPER_RIGHT: u8 = 0x5c;
+pub const SC1_MENU: u8 = 0x5d;
+
+// The additional key beside the left shift key on ISO keyboards
+pub const SC1_NONUS_BACKSLASH: u8 = 0x56;
@@ -122,7 +122,12 @@ pub const SC2_SHIFT_RIGHT: u8 = 0x59;
 pub const SC2_ALT_LEFT: u8 = 0x11;
 pub const SC2_ALT_RIGHT: u8 = 0x11;
 
-// TODO(JPH): pause key
+// The pause key has no break code, and its make code is a sequence of its own
+pub const SC2_PAUSE: [u8; 8] = [0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77];
 
 pub const SC2_SUPER_LEFT: u8 = 0x1f;
 pub const SC2_SUPER_RIGHT: u8 = 0x27;
+pub const SC2_MENU: u8 = 0x2f;
+
+// The additional key beside the left shift key on ISO keyboards
+pub const SC2_NONUS_BACKSLASH: u8 = 0x61;
@@ -4,3 +4,5 @@
 
 pub mod ctrl;
 mod keyboard;
+
+pub use keyboard::keymap::Keymap;
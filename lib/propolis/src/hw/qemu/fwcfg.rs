@@ -355,6 +355,32 @@ impl FwCfg {
         Ok(selector)
     }
 
+    /// Replace the contents of the entry with specified `name`, keeping its
+    /// selector, and returning the prior contents (if the entry exists)
+    pub fn replace_named(&self, name: &str, entry: Entry) -> Option<Entry> {
+        let mut state = self.state.lock().unwrap();
+        let state = state.deref_mut();
+        let selector = state.directory.named_selector(name)?;
+        let old = std::mem::replace(
+            state
+                .directory
+                .entry(selector)
+                .expect("entry is present for translated selector"),
+            entry,
+        );
+        // A guest part-way through reading the entry sees the remainder of the
+        // new contents, rather than a stale cached copy of the old.
+        if let Some(selected) =
+            state.selected.as_mut().filter(|s| s.selector == selector)
+        {
+            selected.cached_value = match state.directory.entry(selector) {
+                Some(Entry::FileDir) => Some(state.directory.render()),
+                _ => None,
+            };
+        }
+        Some(old)
+    }
+
     pub fn remove(&self, selector: u16) -> Option<Entry> {
         let mut state = self.state.lock().unwrap();
         let entry = state.directory.remove(selector)?;
@@ -743,6 +769,10 @@ impl MigrateSingle for FwCfg {
         Self::ensure_valid_selected(&mut state);
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::FwCfgV2>();
+    }
 }
 
 pub mod migrate {
@@ -941,6 +971,27 @@ mod test {
         assert_eq!(version, FW_CFG_VER_BASE | FW_CFG_VER_DMA);
     }
 
+    #[test]
+    fn replace_named_entry() {
+        let dev = FwCfg::new();
+        let selector = dev
+            .insert_named("etc/test", Entry::Bytes(vec![1, 2, 3, 4]))
+            .unwrap();
+
+        pio_write(&dev, FW_CFG_IOP_SELECTOR, selector);
+        assert_eq!(pio_read_data::<[u8; 2]>(&dev), [1, 2]);
+
+        let old = dev.replace_named("etc/test", Entry::Bytes(vec![5, 6, 7, 8]));
+        assert!(matches!(old, Some(Entry::Bytes(buf)) if buf == [1, 2, 3, 4]));
+        // The selection, and its offset, survive the replacement
+        assert_eq!(pio_read_data::<[u8; 2]>(&dev), [7, 8]);
+
+        pio_write(&dev, FW_CFG_IOP_SELECTOR, selector);
+        assert_eq!(pio_read_data::<[u8; 4]>(&dev), [5, 6, 7, 8]);
+
+        assert!(dev.replace_named("etc/missing", Entry::FileDir).is_none());
+    }
+
     fn machine_setup() -> (Machine, Arc<FwCfg>, Accessor<MemCtx>) {
         let machine = Machine::new_test().unwrap();
 
@@ -80,22 +80,125 @@ impl Config {
             width: self.width,
             height: self.height,
             fourcc: self.fourcc,
+            stride: self.stride,
         }
     }
 }
 
+/// A display resolution, in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+impl Resolution {
+    pub const MIN: Self = Self { width: 640, height: 480 };
+    /// Largest resolution whose timings can be described by an EDID detailed
+    /// timing descriptor at 60Hz
+    pub const MAX: Self = Self { width: 3840, height: 2160 };
+    pub const DEFAULT: Self = Self { width: 1024, height: 768 };
+
+    pub fn is_valid(&self) -> bool {
+        (Self::MIN.width..=Self::MAX.width).contains(&self.width)
+            && (Self::MIN.height..=Self::MAX.height).contains(&self.height)
+    }
+}
+
+/// Build a 128-byte EDID (version 1.4) block describing a display whose
+/// preferred (and only detailed) mode is `res` at 60Hz.
+///
+/// The mode's timings follow CVT reduced blanking closely enough for guests to
+/// accept them; nothing actually scans out at this rate.
+pub fn edid(res: Resolution) -> Vec<u8> {
+    assert!(res.is_valid());
+
+    let mut buf = vec![0u8; 128];
+    buf[0..8]
+        .copy_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+    // Manufacturer ID "PRP", as three 5-bit letters
+    let mfg = [b'P', b'R', b'P']
+        .iter()
+        .fold(0u16, |acc, c| (acc << 5) | u16::from(c - b'@'));
+    buf[8..10].copy_from_slice(&mfg.to_be_bytes());
+    buf[10..12].copy_from_slice(&1u16.to_le_bytes());
+    // Model year 2024
+    buf[17] = 2024 - 1990;
+    buf[18] = 1;
+    buf[19] = 4;
+    // Digital input, with an undefined interface and color depth
+    buf[20] = 0x80;
+    // Gamma 2.2
+    buf[23] = 120;
+    // sRGB is the default color space; the detailed mode is preferred
+    buf[24] = 0x06;
+    // sRGB chromaticity coordinates
+    buf[25..35].copy_from_slice(&[
+        0xee, 0x91, 0xa3, 0x54, 0x4c, 0x99, 0x26, 0x0f, 0x50, 0x54,
+    ]);
+    // Established timing: 640x480@60Hz
+    buf[35] = 0x20;
+    // No standard timings
+    for b in buf[38..54].chunks_mut(2) {
+        b.copy_from_slice(&[0x01, 0x01]);
+    }
+
+    // Detailed timing descriptor for the preferred mode
+    let (hactive, vactive) = (res.width, res.height);
+    let (hfront, hsync, hblank) = (48u32, 32u32, 160u32);
+    let (vfront, vsync) = (3u32, 5u32);
+    // At least 460us of vertical blanking
+    let vblank = u32::max((vactive * 276).div_ceil(9724), vfront + vsync + 6);
+    let clock_10khz =
+        ((hactive + hblank) * (vactive + vblank) * 60).div_ceil(10_000);
+    let dtd = &mut buf[54..72];
+    dtd[0..2].copy_from_slice(&(clock_10khz as u16).to_le_bytes());
+    dtd[2] = hactive as u8;
+    dtd[3] = hblank as u8;
+    dtd[4] = (((hactive >> 8) << 4) | (hblank >> 8)) as u8;
+    dtd[5] = vactive as u8;
+    dtd[6] = vblank as u8;
+    dtd[7] = (((vactive >> 8) << 4) | (vblank >> 8)) as u8;
+    dtd[8] = hfront as u8;
+    dtd[9] = hsync as u8;
+    dtd[10] = (((vfront & 0xf) << 4) | (vsync & 0xf)) as u8;
+    dtd[11] = (((hfront >> 8) << 6)
+        | ((hsync >> 8) << 4)
+        | ((vfront >> 4) << 2)
+        | (vsync >> 4)) as u8;
+    // Digital separate sync, positive hsync and negative vsync
+    dtd[17] = 0x1a;
+
+    // Display product name descriptor
+    let name = &mut buf[72..90];
+    name[3] = 0xfc;
+    name[5..].copy_from_slice(b"propolis\n    ");
+    // Dummy descriptors
+    buf[93] = 0x10;
+    buf[111] = 0x10;
+
+    let sum = buf[..127].iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    buf[127] = 0u8.wrapping_sub(sum);
+    buf
+}
+
 #[derive(Clone, Copy)]
 pub struct FramebufferSpec {
     pub addr: u64,
     pub width: u32,
     pub height: u32,
     pub fourcc: u32,
+    /// Distance, in pixels, from the start of one line to the start of the
+    /// next, or 0 if lines are `width` pixels apart
+    pub stride: u32,
 }
 
 type NotifyFn = Box<dyn Fn(&Config, bool) + Send + Sync + 'static>;
 
 pub struct RamFb {
     config: Mutex<Config>,
+    /// Resolution suggested to the guest, both through the dimensions of an
+    /// unconfigured framebuffer and through the EDID provided alongside it
+    preferred: Mutex<Resolution>,
     notify: Mutex<Option<NotifyFn>>,
     acc_mem: MemAccessor,
     log: slog::Logger,
@@ -106,9 +209,13 @@ impl RamFb {
 
     pub const FWCFG_ENTRY_NAME: &'static str = "etc/ramfb";
 
+    /// Name of the `fw_cfg` entry holding the EDID for the framebuffer
+    pub const FWCFG_EDID_NAME: &'static str = "etc/edid";
+
     pub fn create(log: slog::Logger) -> Arc<Self> {
         Arc::new(Self {
             config: Mutex::new(Config::default()),
+            preferred: Mutex::new(Resolution::DEFAULT),
             notify: Mutex::new(None),
             acc_mem: MemAccessor::new_orphan(),
             log,
@@ -120,6 +227,31 @@ impl RamFb {
     pub fn get_framebuffer_spec(&self) -> FramebufferSpec {
         self.config.lock().unwrap().get_framebuffer_spec()
     }
+    /// Get the framebuffer's configuration, if the guest has configured it
+    /// validly: in a known format, and wholly within guest memory.
+    pub fn valid_framebuffer_spec(&self) -> Option<FramebufferSpec> {
+        let mem = self.acc_mem.access()?;
+        let config = self.config.lock().unwrap();
+        config.verify(&mem)?;
+        Some(config.get_framebuffer_spec())
+    }
+    pub fn preferred_resolution(&self) -> Resolution {
+        *self.preferred.lock().unwrap()
+    }
+    /// Set the resolution suggested to the guest.
+    ///
+    /// Firmware reading the `fw_cfg` entry before configuring the framebuffer
+    /// finds these dimensions in it.  The guest only picks up the change when
+    /// it next configures the framebuffer, and the EDID returned by
+    /// [RamFb::edid] must be republished by the caller.
+    pub fn set_preferred_resolution(&self, res: Resolution) {
+        assert!(res.is_valid());
+        *self.preferred.lock().unwrap() = res;
+    }
+    /// EDID describing the preferred resolution
+    pub fn edid(&self) -> Vec<u8> {
+        edid(self.preferred_resolution())
+    }
     pub fn set_notifier(&self, n: NotifyFn) {
         let mut locked = self.notify.lock().unwrap();
         *locked = Some(n);
@@ -128,6 +260,7 @@ impl RamFb {
     pub(crate) fn fwcfg_rw(&self, mut rwo: RWOp) -> Result<(), ()> {
         let mem = self.acc_mem.access().expect("usable mem accessor");
         let mut config = self.config.lock().unwrap();
+        let preferred = self.preferred_resolution();
         let valid_before =
             if rwo.is_write() { config.verify(&mem).is_some() } else { false };
 
@@ -145,6 +278,14 @@ impl RamFb {
                 Reg::Addr => ro.write_u64(config.addr.to_be()),
                 Reg::FourCC => ro.write_u32(config.fourcc.to_be()),
                 Reg::Flags => ro.write_u32(config.flags.to_be()),
+                // Until the guest has placed the framebuffer, it is offered
+                // the preferred dimensions.
+                Reg::Width if config.addr == 0 => {
+                    ro.write_u32(preferred.width.to_be())
+                }
+                Reg::Height if config.addr == 0 => {
+                    ro.write_u32(preferred.height.to_be())
+                }
                 Reg::Width => ro.write_u32(config.width.to_be()),
                 Reg::Height => ro.write_u32(config.height.to_be()),
                 Reg::Stride => ro.write_u32(config.stride.to_be()),
@@ -203,6 +344,10 @@ impl MigrateSingle for RamFb {
             width: state.width,
             height: state.height,
             stride: state.stride,
+            preferred: Some({
+                let res = self.preferred_resolution();
+                (res.width, res.height)
+            }),
         }
         .into())
     }
@@ -221,9 +366,22 @@ impl MigrateSingle for RamFb {
         state.width = data.width;
         state.height = data.height;
         state.stride = data.stride;
+        if let Some((width, height)) = data.preferred {
+            let res = Resolution { width, height };
+            if !res.is_valid() {
+                return Err(MigrateStateError::ImportFailed(format!(
+                    "invalid preferred resolution {width}x{height}"
+                )));
+            }
+            *self.preferred.lock().unwrap() = res;
+        }
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::RamFbV1>();
+    }
 }
 
 pub mod migrate {
@@ -239,6 +397,9 @@ pub mod migrate {
         pub width: u32,
         pub height: u32,
         pub stride: u32,
+        /// Absent from sources predating runtime resolution changes
+        #[serde(default)]
+        pub preferred: Option<(u32, u32)>,
     }
     impl Schema<'_> for RamFbV1 {
         fn id() -> SchemaId {
@@ -255,4 +416,20 @@ mod test {
     fn config_reg_size() {
         assert_eq!(size_of::<Config>(), RamFb::FWCFG_ENTRY_SIZE);
     }
+
+    #[test]
+    fn edid_block() {
+        for res in [Resolution::MIN, Resolution::DEFAULT, Resolution::MAX] {
+            let buf = edid(res);
+            assert_eq!(buf.len(), 128);
+            assert_eq!(&buf[0..8], &[0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0]);
+            assert_eq!(buf.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)), 0);
+
+            let dtd = &buf[54..72];
+            let width = u32::from(dtd[2]) | (u32::from(dtd[4] >> 4) << 8);
+            let height = u32::from(dtd[5]) | (u32::from(dtd[7] >> 4) << 8);
+            assert_eq!(Resolution { width, height }, res);
+            assert_ne!(u16::from_le_bytes([dtd[0], dtd[1]]), 0);
+        }
+    }
 }
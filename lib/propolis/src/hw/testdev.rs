@@ -112,6 +112,10 @@ impl MigrateSingle for MigrationFailureDevice {
         }
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<MigrationFailurePayloadV1>();
+    }
 }
 
 impl Schema<'_> for MigrationFailurePayloadV1 {
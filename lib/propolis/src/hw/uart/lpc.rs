@@ -95,6 +95,19 @@ impl LpcUart {
             self.notify_writable.notify(self as &dyn Sink);
         }
     }
+    /// Signal a break on the line to the guest, returning false if the uart
+    /// cannot yet receive it (see [Uart::data_break]).
+    pub fn send_break(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.paused {
+            return false;
+        }
+
+        let res = state.uart.data_break();
+        state.sync_intr_pin();
+        res
+    }
     fn reset(&self) {
         let mut state = self.state.lock().unwrap();
         state.uart.reset();
@@ -193,4 +206,8 @@ impl MigrateSingle for LpcUart {
         state.irq_pin.import_state(state.uart.intr_state());
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::Uart16550V1>();
+    }
 }
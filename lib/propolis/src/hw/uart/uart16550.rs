@@ -104,7 +104,8 @@ impl Uart {
             Some(UartReg::ModemCtrl) => self.reg_modem_ctrl.bits(),
             Some(UartReg::LineStatus) => {
                 let val = self.reg_line_status;
-                self.reg_line_status.remove(LineStatusReg::OE);
+                self.reg_line_status
+                    .remove(LineStatusReg::OE | LineStatusReg::BI);
                 self.update_isr();
 
                 val.bits()
@@ -222,6 +223,25 @@ impl Uart {
             res
         }
     }
+    /// Signal a break condition on the line to be received by the uart
+    ///
+    /// As with real hardware, this places a zero character in the receive
+    /// buffer and raises the Break Interrupt indicator.  Returns false if there
+    /// is no room for the character, in which case the break should be retried
+    /// once the guest has read the pending data.
+    pub fn data_break(&mut self) -> bool {
+        if self.is_loopback() {
+            // As with data, the serial input pin is disconnected.
+            true
+        } else if self.rx_fifo.write(0) {
+            self.reg_line_status.insert(LineStatusReg::BI);
+            self.update_dr();
+            self.update_isr();
+            true
+        } else {
+            false
+        }
+    }
     pub fn intr_state(&self) -> bool {
         self.intr_pin
     }
@@ -262,9 +282,11 @@ impl Uart {
 
     fn next_intr(&self) -> Option<IntrIdent> {
         if self.reg_intr_enable.contains(IntrEnaReg::ELSI)
-            && self.reg_line_status.contains(LineStatusReg::OE)
+            && self
+                .reg_line_status
+                .intersects(LineStatusReg::OE | LineStatusReg::BI)
         {
-            // This ignores Parity Error, Framing Error, and Break
+            // This ignores Parity Error and Framing Error
             Some(IntrIdent::RLS)
         } else if self.reg_intr_enable.contains(IntrEnaReg::ERBFI)
             && self.reg_line_status.contains(LineStatusReg::DR)
@@ -469,6 +491,8 @@ bitflags! {
         const DR = 1 << 0;
         /// Overrun Error
         const OE = 1 << 1;
+        /// Break Interrupt
+        const BI = 1 << 4;
         /// Transmit Hold Register Empty
         const THRE = 1 << 5;
         /// Transmitter Empty
@@ -681,6 +705,7 @@ mod test {
         // Line Status Register (LSR) bits
         pub const LSR_DR: u8 = 1 << 0; // Data Ready
         pub const LSR_OE: u8 = 1 << 1; // Overrun Error
+        pub const LSR_BI: u8 = 1 << 4; // Break Interrupt
         pub const LSR_THRE: u8 = 1 << 5; // THRE indicator
         pub const LSR_TEMT: u8 = 1 << 6; // Transmitter Empty indicator
         pub const LCR_DLAB: u8 = 0b10000000; // Divisor Latch Access Bit
@@ -815,4 +840,29 @@ mod test {
         assert_eq!(uart.reg_read(REG_ISR), ISRC_NONE);
         assert_eq!(uart.data_read(), Some(tval));
     }
+
+    #[test]
+    fn break_condition() {
+        let mut uart = Uart::new();
+        uart.reg_write(REG_IER, IER_ELSI | IER_ERBFI);
+
+        assert!(uart.data_break());
+        assert!(!uart.is_writable());
+        // A second break cannot be received until the first is read
+        assert!(!uart.data_break());
+
+        // The break is reported through receiver-line-status
+        assert_eq!(uart.reg_read(REG_ISR), ISRC_RLS);
+        assert_eq!(uart.reg_read(REG_LSR) & (LSR_BI | LSR_DR), LSR_BI | LSR_DR);
+        // ... which is cleared by reading LSR, leaving the zero character
+        assert_eq!(uart.reg_read(REG_LSR) & LSR_BI, 0);
+        assert_eq!(uart.reg_read(REG_ISR), ISRC_DR);
+        assert_eq!(uart.reg_read(REG_RHR), 0);
+        assert_eq!(uart.reg_read(REG_ISR), ISRC_NONE);
+
+        // Incoming breaks are ignored in loopback mode
+        uart.reg_write(REG_MCR, MCR_LOOP);
+        assert!(uart.data_break());
+        assert_eq!(uart.reg_read(REG_LSR) & LSR_BI, 0);
+    }
 }
@@ -4,12 +4,21 @@
 
 pub const VIRTIO_DEV_NET: u16 = 0x1000;
 pub const VIRTIO_DEV_BLOCK: u16 = 0x1001;
+pub const VIRTIO_DEV_CONSOLE: u16 = 0x1003;
 pub const VIRTIO_DEV_9P: u16 = 0x1009;
+// virtio-vsock has no transitional device ID, but legacy drivers identify any
+// device in the 0x1000-0x103f range by its sub-device-ID.
+pub const VIRTIO_DEV_SOCKET: u16 = 0x1012;
+// Likewise for virtio-input
+pub const VIRTIO_DEV_INPUT: u16 = 0x1011;
 
 // Legacy virtio-pci devices must present these sub-device-IDs
 pub const VIRTIO_SUB_DEV_NET: u16 = 0x1;
 pub const VIRTIO_SUB_DEV_BLOCK: u16 = 0x2;
+pub const VIRTIO_SUB_DEV_CONSOLE: u16 = 0x3;
 pub const VIRTIO_SUB_DEV_9P_TRANSPORT: u16 = 0x9;
+pub const VIRTIO_SUB_DEV_SOCKET: u16 = 0x13;
+pub const VIRTIO_SUB_DEV_INPUT: u16 = 0x12;
 
 // Legacy interface feature bits
 pub const VIRTIO_F_NOTIFY_ON_EMPTY: usize = 1 << 24;
@@ -39,6 +48,7 @@ pub const VIRTIO_NET_F_STATUS: u32 = 1 << 16;
 pub const VIRTIO_NET_F_CTRL_VQ: u32 = 1 << 17;
 pub const VIRTIO_NET_F_CTRL_RX: u32 = 1 << 18;
 pub const VIRTIO_NET_F_CTRL_VLAN: u32 = 1 << 19;
+pub const VIRTIO_NET_F_MQ: u32 = 1 << 22;
 
 // virtio-block feature bits
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1 << 1;
@@ -49,6 +59,7 @@ pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
 pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
 pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 1 << 10;
 pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
+pub const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
 pub const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
 
@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::num::NonZeroU16;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
 
 use crate::accessors::MemAccessor;
@@ -11,10 +12,11 @@ use crate::common::*;
 use crate::hw::pci;
 use crate::migrate::*;
 use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
 
 use super::bits::*;
 use super::pci::{PciVirtio, PciVirtioState};
-use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::queue::{Chain, IntrModeration, VirtQueue, VirtQueues};
 use super::VirtioDevice;
 use bits::*;
 
@@ -24,9 +26,22 @@ use lazy_static::lazy_static;
 /// Sizing for virtio-block is specified in 512B sectors
 const SECTOR_SZ: usize = 512;
 
+/// Maximum number of request queues a virtio-block device may expose
+pub const VIRTIO_BLK_MAX_QUEUES: u16 = 64;
+
+/// Maximum size (in sectors) of a single discard request
+const MAX_DISCARD_SECTORS: u32 = 1 << 22;
+
+/// Maximum size (in sectors) of a single write-zeroes request.  Some backends
+/// must allocate a buffer of zeroes to fulfill such a request, so this is more
+/// conservative than the limit for discards.
+const MAX_WRITE_ZEROES_SECTORS: u32 = 1 << 16;
+
 struct CompletionPayload {
     /// ID of original request.
     rid: u16,
+    /// Queue from which the request was taken.
+    qid: u16,
     /// VirtIO chain in which we indicate the result.
     chain: Chain,
 }
@@ -37,17 +52,33 @@ pub struct PciVirtioBlock {
 
     block_attach: block::DeviceAttachment,
     block_tracking: block::tracking::Tracking<CompletionPayload>,
+
+    /// Queue to be checked first for the next request, so that requests are
+    /// taken from each of the queues in turn, rather than favoring those with
+    /// lower IDs.
+    next_queue: AtomicUsize,
 }
 impl PciVirtioBlock {
-    pub fn new(queue_size: u16) -> Arc<Self> {
-        let queues = VirtQueues::new(
+    /// Create a virtio-block device with `num_queues` request queues (clamped
+    /// to [VIRTIO_BLK_MAX_QUEUES]) of `queue_size` entries each.  Multiple
+    /// queues are offered to the guest through VIRTIO_BLK_F_MQ.
+    ///
+    /// The interrupts announcing completed requests may be deferred according
+    /// to `moderation`, so that several completions share an interrupt.
+    pub fn new(
+        queue_size: u16,
+        num_queues: u16,
+        moderation: Option<IntrModeration>,
+    ) -> Arc<Self> {
+        let num_queues = num_queues.clamp(1, VIRTIO_BLK_MAX_QUEUES);
+        let queues = VirtQueues::with_moderation(
             NonZeroU16::new(queue_size).unwrap(),
-            NonZeroU16::new(1).unwrap(),
+            NonZeroU16::new(num_queues).unwrap(),
+            moderation,
         );
-        // virtio-block only needs two MSI-X entries for its interrupt needs:
-        // - device config changes
-        // - queue 0 notification
-        let msix_count = Some(2);
+        // virtio-block needs an MSI-X entry for device config changes, as well
+        // as one for notifications from each of its queues.
+        let msix_count = Some(num_queues + 1);
         let (virtio_state, pci_state) = PciVirtioState::create(
             queues,
             msix_count,
@@ -64,6 +95,7 @@ impl PciVirtioBlock {
             block_tracking: block::tracking::Tracking::new(
                 weak.clone() as Weak<dyn block::Device>
             ),
+            next_queue: AtomicUsize::new(0),
         })
     }
 
@@ -80,6 +112,20 @@ impl PciVirtioBlock {
                 ro.write_u32(128 - 2);
             }
             BlockReg::BlockSize => ro.write_u32(info.block_size),
+            BlockReg::NumQueues => {
+                ro.write_u16(self.virtio_state.queues.count().get());
+            }
+            BlockReg::MaxDiscardSectors => ro.write_u32(MAX_DISCARD_SECTORS),
+            BlockReg::DiscardSectorAlign => {
+                ro.write_u32(info.block_size / SECTOR_SZ as u32);
+            }
+            BlockReg::MaxZeroSectors => ro.write_u32(MAX_WRITE_ZEROES_SECTORS),
+            // Only a single range is accepted per discard or write-zeroes
+            // request, sparing us from splitting them into several requests
+            // to the backend.
+            BlockReg::MaxDiscardSeg | BlockReg::MaxZeroSeg => ro.write_u32(1),
+            // Zeroed blocks are always written out, rather than unmapped
+            BlockReg::ZeroMayUnmap => ro.write_u8(0),
             BlockReg::Unused => {
                 ro.fill(0);
             }
@@ -91,18 +137,32 @@ impl PciVirtioBlock {
     }
 
     fn next_req(&self) -> Option<block::Request> {
-        let vq = &self.virtio_state.queues[0];
         let mem = self.pci_state.acc_mem.access()?;
+        let queues = &self.virtio_state.queues;
+        let count = usize::from(queues.count().get());
+        let start = self.next_queue.load(Ordering::Relaxed);
+
+        (0..count).map(|i| (start + i) % count).find_map(|idx| {
+            let req = self.next_queue_req(idx as u16, &mem);
+            if req.is_some() {
+                self.next_queue.store((idx + 1) % count, Ordering::Relaxed);
+            }
+            req
+        })
+    }
+
+    fn next_queue_req(&self, qid: u16, mem: &MemCtx) -> Option<block::Request> {
+        let vq = &self.virtio_state.queues[qid as usize];
 
         let mut chain = Chain::with_capacity(4);
         // Pop a request off the queue if there's one available.
         // For debugging purposes, we'll also use the returned index
         // as a psuedo-id for the request to associate it with its
         // subsequent completion
-        let (rid, _clen) = vq.pop_avail(&mut chain, &mem)?;
+        let (rid, _clen) = vq.pop_avail(&mut chain, mem)?;
 
         let mut breq = VbReq::default();
-        if !chain.read(&mut breq, &mem) {
+        if !chain.read(&mut breq, mem) {
             todo!("error handling");
         }
         let off = breq.sector as usize * SECTOR_SZ;
@@ -119,7 +179,7 @@ impl PciVirtioBlock {
                     ));
                     Ok(self.block_tracking.track(
                         block::Request::new_read(off, sz, regions),
-                        CompletionPayload { rid, chain },
+                        CompletionPayload { rid, qid, chain },
                     ))
                 } else {
                     Err(chain)
@@ -136,7 +196,7 @@ impl PciVirtioBlock {
                     ));
                     Ok(self.block_tracking.track(
                         block::Request::new_write(off, sz, regions),
-                        CompletionPayload { rid, chain },
+                        CompletionPayload { rid, qid, chain },
                     ))
                 } else {
                     Err(chain)
@@ -146,9 +206,55 @@ impl PciVirtioBlock {
                 probes::vioblk_flush_enqueue!(|| (rid));
                 Ok(self.block_tracking.track(
                     block::Request::new_flush(),
-                    CompletionPayload { rid, chain },
+                    CompletionPayload { rid, qid, chain },
                 ))
             }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                // The sector in the request header is unused, with the range
+                // instead described by the (single) segment which follows.
+                let mut seg = VbDiscardWriteZeroes::default();
+                let valid = chain.remain_read_bytes()
+                    == std::mem::size_of::<VbDiscardWriteZeroes>()
+                    && chain.read(&mut seg, mem);
+                let off = seg.sector as usize * SECTOR_SZ;
+                let sz = seg.num_sectors as usize * SECTOR_SZ;
+                match breq.rtype {
+                    _ if !valid => Err(chain),
+                    VIRTIO_BLK_T_DISCARD => {
+                        if seg.num_sectors > MAX_DISCARD_SECTORS
+                            || seg.flags != 0
+                        {
+                            // The unmap flag is not valid for discards
+                            Err(chain)
+                        } else {
+                            probes::vioblk_discard_enqueue!(|| (
+                                rid, off as u64, sz as u64
+                            ));
+                            Ok(self.block_tracking.track(
+                                block::Request::new_discard(off, sz),
+                                CompletionPayload { rid, qid, chain },
+                            ))
+                        }
+                    }
+                    _ => {
+                        if seg.num_sectors > MAX_WRITE_ZEROES_SECTORS
+                            || seg.flags & !VIRTIO_BLK_WZ_F_UNMAP != 0
+                        {
+                            Err(chain)
+                        } else {
+                            // Unmapping is only permitted, not required, so
+                            // the flag can be ignored.
+                            probes::vioblk_write_zeroes_enqueue!(|| (
+                                rid, off as u64, sz as u64
+                            ));
+                            Ok(self.block_tracking.track(
+                                block::Request::new_write_zeroes(off, sz),
+                                CompletionPayload { rid, qid, chain },
+                            ))
+                        }
+                    }
+                }
+            }
             _ => Err(chain),
         };
         match req {
@@ -157,9 +263,9 @@ impl PciVirtioBlock {
                 let remain = chain.remain_write_bytes();
                 if remain >= 1 {
                     chain.write_skip(remain - 1);
-                    chain.write(&VIRTIO_BLK_S_UNSUPP, &mem);
+                    chain.write(&VIRTIO_BLK_S_UNSUPP, mem);
                 }
-                vq.push_used(&mut chain, &mem);
+                vq.push_used(&mut chain, mem);
                 None
             }
             Ok(r) => Some(r),
@@ -169,13 +275,21 @@ impl PciVirtioBlock {
     fn complete_req(
         &self,
         rid: u16,
+        qid: u16,
         op: block::Operation,
         res: block::Result,
         chain: &mut Chain,
     ) {
-        let vq = self.virtio_state.queues.get(0).expect("vq must exist");
+        let vq = self.virtio_state.queues.get(qid).expect("vq must exist");
         if let Some(mem) = vq.acc_mem.access() {
             let resnum = match res {
+                // Discards are advisory, so a backend which cannot discard
+                // data has not failed the request.
+                block::Result::Unsupported
+                    if matches!(op, block::Operation::Discard(..)) =>
+                {
+                    VIRTIO_BLK_S_OK
+                }
                 block::Result::Success => VIRTIO_BLK_S_OK,
                 block::Result::Failure => VIRTIO_BLK_S_IOERR,
                 block::Result::ReadOnly => VIRTIO_BLK_S_IOERR,
@@ -191,6 +305,12 @@ impl PciVirtioBlock {
                 block::Operation::Flush => {
                     probes::vioblk_flush_complete!(|| (rid, resnum));
                 }
+                block::Operation::WriteZeroes(..) => {
+                    probes::vioblk_write_zeroes_complete!(|| (rid, resnum));
+                }
+                block::Operation::Discard(..) => {
+                    probes::vioblk_discard_complete!(|| (rid, resnum));
+                }
             }
             chain.write(&resnum, &mem);
             vq.push_used(chain, &mem);
@@ -211,6 +331,11 @@ impl VirtioDevice for PciVirtioBlock {
         let mut feat = VIRTIO_BLK_F_BLK_SIZE;
         feat |= VIRTIO_BLK_F_SEG_MAX;
         feat |= VIRTIO_BLK_F_FLUSH;
+        feat |= VIRTIO_BLK_F_DISCARD;
+        feat |= VIRTIO_BLK_F_WRITE_ZEROES;
+        if self.virtio_state.queues.count().get() > 1 {
+            feat |= VIRTIO_BLK_F_MQ;
+        }
 
         let info = self.block_attach.info().unwrap_or_else(Default::default);
         if info.read_only {
@@ -246,8 +371,8 @@ impl block::Device for PciVirtioBlock {
 
     fn complete(&self, res: block::Result, id: block::ReqId) {
         let (op, mut payload) = self.block_tracking.complete(id, res);
-        let CompletionPayload { rid, ref mut chain } = payload;
-        self.complete_req(rid, op, res, chain);
+        let CompletionPayload { rid, qid, ref mut chain } = payload;
+        self.complete_req(rid, qid, op, res, chain);
     }
 
     fn accessor_mem(&self) -> MemAccessor {
@@ -290,6 +415,10 @@ impl MigrateMulti for PciVirtioBlock {
     ) -> Result<(), MigrateStateError> {
         <dyn PciVirtio>::import(self, offer, ctx)
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        <dyn PciVirtio>::schemas(self, schemas)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -300,6 +429,15 @@ struct VbReq {
     sector: u64,
 }
 
+/// Range to be operated upon by a discard or write-zeroes request
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VbDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum BlockReg {
     Capacity,
@@ -314,6 +452,7 @@ enum BlockReg {
     TopoMinIoSz,
     TopoOptIoSz,
     Writeback,
+    NumQueues,
     Unused,
     MaxDiscardSectors,
     MaxDiscardSeg,
@@ -337,7 +476,8 @@ lazy_static! {
             (BlockReg::TopoMinIoSz, 2),
             (BlockReg::TopoOptIoSz, 4),
             (BlockReg::Writeback, 1),
-            (BlockReg::Unused, 3),
+            (BlockReg::Unused, 1),
+            (BlockReg::NumQueues, 2),
             (BlockReg::MaxDiscardSectors, 4),
             (BlockReg::MaxDiscardSeg, 4),
             (BlockReg::DiscardSectorAlign, 4),
@@ -363,6 +503,8 @@ mod bits {
     pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
     pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 
+    pub const VIRTIO_BLK_WZ_F_UNMAP: u32 = 1 << 0;
+
     pub const VIRTIO_BLK_S_OK: u8 = 0;
     pub const VIRTIO_BLK_S_IOERR: u8 = 1;
     pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
@@ -380,4 +522,10 @@ mod probes {
 
     fn vioblk_flush_enqueue(id: u16) {}
     fn vioblk_flush_complete(id: u16, res: u8) {}
+
+    fn vioblk_write_zeroes_enqueue(id: u16, off: u64, sz: u64) {}
+    fn vioblk_write_zeroes_complete(id: u16, res: u8) {}
+
+    fn vioblk_discard_enqueue(id: u16, off: u64, sz: u64) {}
+    fn vioblk_discard_complete(id: u16, res: u8) {}
 }
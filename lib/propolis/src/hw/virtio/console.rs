@@ -0,0 +1,1011 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtio-console device presenting any number of named ports, through
+//! which guest agents and log streams can exchange data with the host without
+//! the byte-at-a-time overhead of an emulated UART.
+//!
+//! Each port is exposed on the host as a Unix socket, to which one process
+//! may be connected at a time; a new connection replaces any existing one.
+//! In the guest, a port appears as `/dev/virtio-ports/<name>`.
+//!
+//! Data is moved between a port and its host socket only as fast as both
+//! sides consume it: data from the host is read only once the guest has
+//! buffers to receive it, and the guest's buffers are returned only once
+//! their contents have been written to the host socket.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::num::NonZeroU16;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{read_buf, write_buf, Chain, VirtQueue, VirtQueues};
+use super::{VirtioDevice, VqChange};
+
+use lazy_static::lazy_static;
+
+/// Maximum number of ports a device may present
+pub const MAX_CONSOLE_PORTS: usize = 16;
+
+const CTRL_RX_QUEUE: u16 = 2;
+const CTRL_TX_QUEUE: u16 = 3;
+
+const VIRTIO_CONSOLE_CFG_SIZE: usize = 12;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Largest amount of data read from a host socket at once
+const MAX_READ: usize = 16 * 1024;
+
+/// Time allowed for a host process to accept data written to its socket
+/// before it is disconnected
+const HOST_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Message exchanged on the control queues
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct ControlMsg {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+/// A port presented by a virtio-console device
+#[derive(Clone, Debug)]
+pub struct ConsolePort {
+    /// Name by which the guest identifies the port
+    pub name: String,
+    /// Path of the Unix socket exposing the port on the host
+    pub uds_path: PathBuf,
+}
+
+#[derive(Default)]
+struct PortState {
+    /// The connected host process, if any
+    conn: Option<Arc<UnixStream>>,
+    /// Incremented for each host connection, so that a reader thread can
+    /// tell if its connection has been replaced
+    conn_gen: u64,
+
+    /// The guest driver is prepared to use the port
+    guest_ready: bool,
+    /// A process in the guest has the port open
+    guest_open: bool,
+
+    /// Data read from the host yet to be delivered to the guest, and the
+    /// offset into it of that which remains
+    rx_pending: Option<(Vec<u8>, usize)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    ports: Vec<PortState>,
+    ctrl_pending: VecDeque<Vec<u8>>,
+
+    /// The driver negotiated multiple ports, and with them the control
+    /// queues.  Without it, only port 0 is used.
+    multiport: bool,
+    /// The driver has signalled it is ready to be told of ports
+    driver_ready: bool,
+
+    /// Nothing may be written into guest memory while the device is not
+    /// running.
+    running: bool,
+}
+impl Inner {
+    /// May data from the host be delivered to the guest on `port`?
+    fn accepts_rx(&self, port: usize) -> bool {
+        if self.multiport {
+            self.ports[port].guest_open
+        } else {
+            port == 0
+        }
+    }
+
+    fn queue_ctrl(&mut self, id: usize, event: u16, value: u16, extra: &[u8]) {
+        let msg = ControlMsg { id: id as u32, event, value };
+        let mut buf = Vec::with_capacity(std::mem::size_of_val(&msg));
+        // SAFETY: ControlMsg is a packed struct of plain integers
+        buf.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                &msg as *const ControlMsg as *const u8,
+                std::mem::size_of::<ControlMsg>(),
+            )
+        });
+        buf.extend_from_slice(extra);
+        self.ctrl_pending.push_back(buf);
+    }
+
+    /// Tell the guest whether a host process is connected to `port`, if the
+    /// guest is prepared to hear of it.
+    fn queue_host_open(&mut self, port: usize) {
+        if self.multiport && self.driver_ready && self.ports[port].guest_ready {
+            let open = self.ports[port].conn.is_some();
+            self.queue_ctrl(port, VIRTIO_CONSOLE_PORT_OPEN, open as u16, &[]);
+        }
+    }
+}
+
+/// Thread accepting connections to the socket of a port
+struct Listener {
+    hdl: JoinHandle<()>,
+    shutdown: Arc<AtomicBool>,
+}
+
+pub struct PciVirtioConsole {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+
+    ports: Vec<ConsolePort>,
+    uds: Mutex<Vec<UnixListener>>,
+    listeners: Mutex<Vec<Listener>>,
+
+    inner: Mutex<Inner>,
+    /// Signalled as pending data is delivered to the guest, ports are
+    /// opened, or host connections are replaced
+    rx_cv: Condvar,
+    /// Serializes the processing of TX queues, so data from the guest is
+    /// written to host sockets in order
+    tx_lock: Mutex<()>,
+    this: Weak<Self>,
+}
+
+impl PciVirtioConsole {
+    /// Create a virtio-console device, with queues of `queue_size` entries,
+    /// presenting the given `ports` to the guest.
+    ///
+    /// The socket for each port is bound immediately, replacing any stale
+    /// socket left at its path.
+    pub fn new(
+        queue_size: u16,
+        ports: Vec<ConsolePort>,
+    ) -> std::io::Result<Arc<Self>> {
+        if ports.is_empty() || ports.len() > MAX_CONSOLE_PORTS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "virtio-console must have between 1 and {} ports",
+                    MAX_CONSOLE_PORTS
+                ),
+            ));
+        }
+        for (i, port) in ports.iter().enumerate() {
+            if port.name.is_empty()
+                || ports[..i].iter().any(|p| p.name == port.name)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid virtio-console port name {:?}", port.name),
+                ));
+            }
+        }
+        let mut uds = Vec::with_capacity(ports.len());
+        for port in ports.iter() {
+            if std::fs::symlink_metadata(&port.uds_path)
+                .is_ok_and(|meta| meta.file_type().is_socket())
+            {
+                std::fs::remove_file(&port.uds_path)?;
+            }
+            uds.push(UnixListener::bind(&port.uds_path)?);
+        }
+
+        // Each port has a receive and transmit queue, as does the control
+        // channel, whose pair sits between those of ports 0 and 1.
+        let nqueues = 2 * ports.len() as u16 + 2;
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(nqueues).unwrap(),
+        );
+        // One MSI-X entry for each queue, plus one for device config changes
+        let msix_count = Some(nqueues + 1);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_CONSOLE,
+            VIRTIO_SUB_DEV_CONSOLE,
+            pci::bits::CLASS_COMMUNICATION,
+            VIRTIO_CONSOLE_CFG_SIZE,
+        );
+
+        let inner = Inner {
+            ports: ports.iter().map(|_| PortState::default()).collect(),
+            ..Default::default()
+        };
+        Ok(Arc::new_cyclic(|this| Self {
+            virtio_state,
+            pci_state,
+            ports,
+            uds: Mutex::new(uds),
+            listeners: Mutex::new(Vec::new()),
+            inner: Mutex::new(inner),
+            rx_cv: Condvar::new(),
+            tx_lock: Mutex::new(()),
+            this: this.clone(),
+        }))
+    }
+
+    /// The ports presented by this device
+    pub fn ports(&self) -> &[ConsolePort] {
+        &self.ports
+    }
+
+    fn console_cfg_read(&self, id: &ConsoleReg, ro: &mut ReadOp) {
+        match id {
+            ConsoleReg::Cols | ConsoleReg::Rows => ro.write_u16(0),
+            ConsoleReg::MaxNrPorts => ro.write_u32(self.ports.len() as u32),
+            ConsoleReg::EmergWr => ro.write_u32(0),
+        }
+    }
+
+    /// The receive and transmit queues of `port`
+    fn port_queues(port: usize) -> (u16, u16) {
+        let rx = match port {
+            0 => 0,
+            n => 2 * n as u16 + 2,
+        };
+        (rx, rx + 1)
+    }
+
+    /// The port whose receive or transmit queue is `qid`, if any
+    fn queue_port(&self, qid: u16) -> Option<usize> {
+        let port = match qid {
+            0 | 1 => 0,
+            CTRL_RX_QUEUE | CTRL_TX_QUEUE => return None,
+            n => (n as usize - 2) / 2,
+        };
+        (port < self.ports.len()).then_some(port)
+    }
+
+    /// Deliver as much data pending for `port` as there are buffers for.
+    fn flush_rx(&self, inner: &mut Inner, port: usize) {
+        if !inner.running || !inner.accepts_rx(port) {
+            return;
+        }
+        let Some((data, off)) = inner.ports[port].rx_pending.as_mut() else {
+            return;
+        };
+        let vq = &self.virtio_state.queues[Self::port_queues(port).0 as usize];
+        if !vq.live.load(Ordering::Acquire) {
+            return;
+        }
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(4);
+        while *off < data.len() {
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                return;
+            }
+            let remain = &data[*off..];
+            let len = usize::min(remain.len(), chain.remain_write_bytes());
+            write_buf(&remain[..len], &mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+            *off += len;
+            probes::virtio_console_rx!(|| (port as u32, len as u64));
+        }
+        inner.ports[port].rx_pending = None;
+        self.rx_cv.notify_all();
+    }
+
+    /// Deliver as many pending control messages as there are buffers for.
+    fn flush_ctrl(&self, inner: &mut Inner) {
+        if !inner.running || inner.ctrl_pending.is_empty() {
+            return;
+        }
+        let vq = &self.virtio_state.queues[CTRL_RX_QUEUE as usize];
+        if !vq.live.load(Ordering::Acquire) {
+            return;
+        }
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(2);
+        while let Some(msg) = inner.ctrl_pending.front() {
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            write_buf(msg, &mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+            inner.ctrl_pending.pop_front();
+        }
+    }
+
+    /// Pass the data in all available chains of the transmit queue of `port`
+    /// to its host socket, or discard it if nothing is connected.
+    fn process_tx(&self, vq: &VirtQueue, port: usize) {
+        let _guard = self.tx_lock.lock().unwrap();
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut data = Vec::new();
+        let mut chain = Chain::with_capacity(4);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            data.resize(chain.remain_read_bytes(), 0);
+            let len = read_buf(&mem, &mut chain, &mut data);
+            probes::virtio_console_tx!(|| (port as u32, len as u64));
+
+            // Writes to the host socket may block, and so are made without
+            // holding the lock.
+            let conn = self.inner.lock().unwrap().ports[port].conn.clone();
+            if let Some(conn) = conn {
+                if conn.as_ref().write_all(&data[..len]).is_err() {
+                    // The reader thread for this connection will find the
+                    // socket shut down, and detach it.
+                    let _ = conn.shutdown(std::net::Shutdown::Both);
+                }
+            }
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+
+    /// Act upon all available messages in the control transmit queue.
+    fn process_ctrl(&self, vq: &VirtQueue) {
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let mut chain = Chain::with_capacity(2);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            let mut msg = ControlMsg::default();
+            let valid = chain.read(&mut msg, &mem);
+            vq.push_used(&mut chain, &mem);
+            if valid {
+                self.handle_ctrl(&mut inner, &msg);
+            }
+        }
+        self.flush_ctrl(&mut inner);
+    }
+
+    fn handle_ctrl(&self, inner: &mut Inner, msg: &ControlMsg) {
+        let id = msg.id as usize;
+        match msg.event {
+            VIRTIO_CONSOLE_DEVICE_READY if msg.value == 1 => {
+                inner.driver_ready = true;
+                for port in 0..self.ports.len() {
+                    inner.queue_ctrl(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if id < self.ports.len() => {
+                if msg.value != 1 {
+                    // The driver failed to set up the port
+                    return;
+                }
+                inner.ports[id].guest_ready = true;
+                let name = self.ports[id].name.as_bytes();
+                inner.queue_ctrl(id, VIRTIO_CONSOLE_PORT_NAME, 1, name);
+                if inner.ports[id].conn.is_some() {
+                    inner.queue_host_open(id);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN if id < self.ports.len() => {
+                inner.ports[id].guest_open = msg.value == 1;
+                self.rx_cv.notify_all();
+                self.flush_rx(inner, id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Attach a newly connected host process to `port`, replacing any
+    /// already attached.
+    fn host_attach(&self, port: usize, stream: UnixStream) {
+        if stream.set_write_timeout(Some(HOST_IO_TIMEOUT)).is_err() {
+            return;
+        }
+        let Ok(reader) = stream.try_clone() else {
+            return;
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let state = &mut inner.ports[port];
+        if let Some(old) = state.conn.replace(Arc::new(stream)) {
+            // Wakes its reader thread, if it is blocked on the socket
+            let _ = old.shutdown(std::net::Shutdown::Both);
+        }
+        state.conn_gen += 1;
+        // Data from the replaced connection is not passed on
+        state.rx_pending = None;
+        let gen = state.conn_gen;
+        inner.queue_host_open(port);
+        self.flush_ctrl(&mut inner);
+        self.rx_cv.notify_all();
+        drop(inner);
+
+        let dev = self.this.clone();
+        let _ = std::thread::Builder::new()
+            .name(format!("virtio-console port {}", port))
+            .spawn(move || Self::reader_loop(dev, port, gen, reader));
+    }
+
+    /// The host process attached to `port` as connection `gen` has gone.
+    fn host_detach(&self, port: usize, gen: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let state = &mut inner.ports[port];
+        if state.conn_gen != gen {
+            return;
+        }
+        if let Some(conn) = state.conn.take() {
+            let _ = conn.shutdown(std::net::Shutdown::Both);
+        }
+        state.rx_pending = None;
+        inner.queue_host_open(port);
+        self.flush_ctrl(&mut inner);
+        self.rx_cv.notify_all();
+    }
+
+    /// Wait until data may be read from connection `gen` to `port`,
+    /// returning false if the connection has been replaced or closed.
+    fn wait_rx_ready(&self, port: usize, gen: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let inner = self
+            .rx_cv
+            .wait_while(inner, |inner| {
+                let state = &inner.ports[port];
+                state.conn_gen == gen
+                    && state.conn.is_some()
+                    && (state.rx_pending.is_some() || !inner.accepts_rx(port))
+            })
+            .unwrap();
+        let state = &inner.ports[port];
+        state.conn_gen == gen && state.conn.is_some()
+    }
+
+    /// Pass data read from connection `gen` to `port` on to the guest.
+    fn host_data(&self, port: usize, gen: u64, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.ports[port].conn_gen != gen {
+            return;
+        }
+        inner.ports[port].rx_pending = Some((data.to_vec(), 0));
+        self.flush_rx(&mut inner, port);
+    }
+
+    fn reader_loop(
+        dev: Weak<Self>,
+        port: usize,
+        gen: u64,
+        mut stream: UnixStream,
+    ) {
+        let mut buf = vec![0u8; MAX_READ];
+        loop {
+            if !dev.upgrade().is_some_and(|d| d.wait_rx_ready(port, gen)) {
+                return;
+            }
+            let res = stream.read(&mut buf);
+            let Some(dev) = dev.upgrade() else {
+                return;
+            };
+            match res {
+                Ok(0) | Err(_) => {
+                    dev.host_detach(port, gen);
+                    return;
+                }
+                Ok(n) => dev.host_data(port, gen, &buf[..n]),
+            }
+        }
+    }
+
+    fn listeners_start(&self) -> std::io::Result<()> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if !listeners.is_empty() {
+            return Ok(());
+        }
+        let uds = self.uds.lock().unwrap();
+        for (port, uds) in uds.iter().enumerate() {
+            let uds = uds.try_clone()?;
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let dev = self.this.clone();
+            let thread_shutdown = shutdown.clone();
+            let hdl = std::thread::Builder::new()
+                .name(format!("virtio-console listener {}", port))
+                .spawn(move || {
+                    for stream in uds.incoming() {
+                        if thread_shutdown.load(Ordering::Acquire) {
+                            return;
+                        }
+                        let Ok(stream) = stream else {
+                            continue;
+                        };
+                        let Some(dev) = dev.upgrade() else {
+                            return;
+                        };
+                        dev.host_attach(port, stream);
+                    }
+                })?;
+            listeners.push(Listener { hdl, shutdown });
+        }
+        Ok(())
+    }
+
+    fn listeners_stop(&self) {
+        let listeners = std::mem::take(&mut *self.listeners.lock().unwrap());
+        for (port, listener) in listeners.into_iter().enumerate() {
+            listener.shutdown.store(true, Ordering::Release);
+            // Wake the thread from its wait for a connection
+            let _ = UnixStream::connect(&self.ports[port].uds_path);
+            let _ = listener.hdl.join();
+        }
+    }
+
+    fn set_running(&self, running: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.running = running;
+        self.flush_ctrl(&mut inner);
+        for port in 0..self.ports.len() {
+            self.flush_rx(&mut inner, port);
+        }
+    }
+
+    /// Forget all state established by the guest driver, discarding any data
+    /// and messages yet to reach it.
+    fn reset_driver_state(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.multiport = false;
+        inner.driver_ready = false;
+        inner.ctrl_pending.clear();
+        for state in inner.ports.iter_mut() {
+            state.guest_ready = false;
+            state.guest_open = false;
+            state.rx_pending = None;
+        }
+        self.rx_cv.notify_all();
+    }
+
+    /// Disconnect all host processes.
+    fn detach_all(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        for state in inner.ports.iter_mut() {
+            if let Some(conn) = state.conn.take() {
+                let _ = conn.shutdown(std::net::Shutdown::Both);
+            }
+            state.rx_pending = None;
+        }
+        self.rx_cv.notify_all();
+    }
+}
+
+impl VirtioDevice for PciVirtioConsole {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        CONSOLE_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.console_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                // emergency writes are not offered, so ignore all writes
+            }
+        });
+    }
+    fn get_features(&self) -> u32 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+    fn set_features(&self, feat: u32) -> Result<(), ()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.multiport = feat & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        self.rx_cv.notify_all();
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        match vq.id {
+            CTRL_RX_QUEUE => self.flush_ctrl(&mut self.inner.lock().unwrap()),
+            CTRL_TX_QUEUE => self.process_ctrl(vq),
+            qid => {
+                let Some(port) = self.queue_port(qid) else {
+                    return;
+                };
+                if qid == Self::port_queues(port).0 {
+                    self.flush_rx(&mut self.inner.lock().unwrap(), port);
+                } else {
+                    self.process_tx(vq, port);
+                }
+            }
+        }
+    }
+
+    fn queue_change(
+        &self,
+        vq: &Arc<VirtQueue>,
+        change: VqChange,
+    ) -> Result<(), ()> {
+        if let (0, VqChange::Reset) = (vq.id, change) {
+            self.reset_driver_state();
+        }
+        Ok(())
+    }
+}
+
+impl Lifecycle for PciVirtioConsole {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-console"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.listeners_start()?;
+        self.set_running(true);
+        Ok(())
+    }
+    fn pause(&self) {
+        self.set_running(false);
+    }
+    fn resume(&self) {
+        self.set_running(true);
+    }
+    fn halt(&self) {
+        self.listeners_stop();
+        self.detach_all();
+        self.inner.lock().unwrap().running = false;
+        for (port, _uds) in self.uds.lock().unwrap().drain(..).enumerate() {
+            let _ = std::fs::remove_file(&self.ports[port].uds_path);
+        }
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl PciVirtio for PciVirtioConsole {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl MigrateMulti for PciVirtioConsole {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let inner = self.inner.lock().unwrap();
+        output.push(
+            migrate::VirtioConsoleV1 {
+                multiport: inner.multiport,
+                driver_ready: inner.driver_ready,
+                ports: inner
+                    .ports
+                    .iter()
+                    .map(|state| migrate::VirtioConsolePortV1 {
+                        guest_ready: state.guest_ready,
+                        guest_open: state.guest_open,
+                        host_open: state.conn.is_some(),
+                    })
+                    .collect(),
+            }
+            .into(),
+        )?;
+        drop(inner);
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::VirtioConsoleV1 = offer.take()?;
+        if input.ports.len() != self.ports.len() {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "virtio-console: {} ports, expected {}",
+                input.ports.len(),
+                self.ports.len()
+            )));
+        }
+        <dyn PciVirtio>::import(self, offer, ctx)?;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.multiport = input.multiport;
+        inner.driver_ready = input.driver_ready;
+        for (port, input) in input.ports.iter().enumerate() {
+            let state = &mut inner.ports[port];
+            state.guest_ready = input.guest_ready;
+            state.guest_open = input.guest_open;
+            // Host connections are not carried across a migration, so the
+            // guest is told that those it knew of have closed (or that a new
+            // one has been made in the meantime) once the device is running.
+            if input.host_open != state.conn.is_some() {
+                inner.queue_host_open(port);
+            }
+        }
+        Ok(())
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::VirtioConsoleV1>();
+        <dyn PciVirtio>::schemas(self, schemas)
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct VirtioConsolePortV1 {
+        pub guest_ready: bool,
+        pub guest_open: bool,
+        pub host_open: bool,
+    }
+
+    /// State of the control channel of a virtio-console device
+    #[derive(Deserialize, Serialize)]
+    pub struct VirtioConsoleV1 {
+        pub multiport: bool,
+        pub driver_ready: bool,
+        pub ports: Vec<VirtioConsolePortV1>,
+    }
+    impl Schema<'_> for VirtioConsoleV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-console", 1)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ConsoleReg {
+    Cols,
+    Rows,
+    MaxNrPorts,
+    EmergWr,
+}
+lazy_static! {
+    static ref CONSOLE_DEV_REGS: RegMap<ConsoleReg> = {
+        let layout = [
+            (ConsoleReg::Cols, 2),
+            (ConsoleReg::Rows, 2),
+            (ConsoleReg::MaxNrPorts, 4),
+            (ConsoleReg::EmergWr, 4),
+        ];
+        RegMap::create_packed(VIRTIO_CONSOLE_CFG_SIZE, &layout, None)
+    };
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn virtio_console_tx(port: u32, len: u64) {}
+    fn virtio_console_rx(port: u32, len: u64) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::GuestAddr;
+    use crate::hw::virtio::testutil::{
+        post_buf, used_len, wait_used, QUEUE_SIZE,
+    };
+    use crate::vmm::{Machine, MemCtx};
+
+    /// Rings for each queue, in (4k-aligned) 8k regions from here
+    const RING_BASE: u64 = 0x10_0000;
+    const BUF_BASE: u64 = 0x12_0000;
+
+    fn ring(qid: u16) -> u64 {
+        RING_BASE + 0x2000 * u64::from(qid)
+    }
+
+    fn setup(
+        dir: &tempfile::TempDir,
+        names: &[&str],
+    ) -> (Machine, Arc<PciVirtioConsole>, Vec<PathBuf>) {
+        let machine = Machine::new_test().unwrap();
+        let ports: Vec<_> = names
+            .iter()
+            .map(|name| ConsolePort {
+                name: name.to_string(),
+                uds_path: dir.path().join(format!("{}.sock", name)),
+            })
+            .collect();
+        let paths = ports.iter().map(|p| p.uds_path.clone()).collect();
+        let dev = PciVirtioConsole::new(QUEUE_SIZE, ports).unwrap();
+        machine.acc_mem.adopt(&dev.pci_state.acc_mem, None);
+        for vq in dev.virtio_state.queues.iter() {
+            vq.map_legacy(ring(vq.id));
+            vq.live.store(true, Ordering::Release);
+        }
+        (machine, dev, paths)
+    }
+
+    fn buf_addr(qid: u16, idx: u16) -> u64 {
+        BUF_BASE + 0x1_0000 * u64::from(qid) + 0x1000 * u64::from(idx)
+    }
+
+    fn notify(dev: &PciVirtioConsole, qid: u16) {
+        let vq = dev.virtio_state.queues[qid as usize].clone();
+        dev.queue_notify(&vq);
+    }
+
+    /// Send a control message from the guest, in slot `idx`.
+    fn guest_ctrl(
+        dev: &PciVirtioConsole,
+        mem: &MemCtx,
+        idx: u16,
+        msg: ControlMsg,
+    ) {
+        let addr = buf_addr(CTRL_TX_QUEUE, idx);
+        mem.write(GuestAddr(addr), &msg);
+        let len = std::mem::size_of::<ControlMsg>() as u32;
+        post_buf(mem, ring(CTRL_TX_QUEUE), idx, addr, len, false);
+        notify(dev, CTRL_TX_QUEUE);
+    }
+
+    /// Provide a receive buffer in slot `idx` of queue `qid`.
+    fn guest_post_rx(dev: &PciVirtioConsole, mem: &MemCtx, qid: u16, idx: u16) {
+        post_buf(mem, ring(qid), idx, buf_addr(qid, idx), 0x1000, true);
+        notify(dev, qid);
+    }
+
+    fn ctrl_msg(mem: &MemCtx, idx: u16) -> ControlMsg {
+        mem.read(GuestAddr(buf_addr(CTRL_RX_QUEUE, idx))).unwrap()
+    }
+
+    #[test]
+    fn max_nr_ports_cfg() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_machine, dev, _paths) = setup(&dir, &["a", "b", "c"]);
+
+        let mut buf = [0u8; 4];
+        let mut ro = ReadOp::from_buf(4, &mut buf);
+        dev.cfg_rw(RWOp::Read(&mut ro));
+        assert_eq!(u32::from_le_bytes(buf), 3);
+    }
+
+    #[test]
+    fn invalid_ports() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = |name: &str| ConsolePort {
+            name: name.to_string(),
+            uds_path: dir.path().join(format!("{}.sock", name)),
+        };
+        assert!(PciVirtioConsole::new(QUEUE_SIZE, vec![]).is_err());
+        assert!(PciVirtioConsole::new(QUEUE_SIZE, vec![port("")]).is_err());
+        assert!(PciVirtioConsole::new(QUEUE_SIZE, vec![port("a"), port("a")])
+            .is_err());
+        let many = (0..=MAX_CONSOLE_PORTS).map(|i| port(&i.to_string()));
+        assert!(PciVirtioConsole::new(QUEUE_SIZE, many.collect()).is_err());
+    }
+
+    #[test]
+    fn port_queues() {
+        assert_eq!(PciVirtioConsole::port_queues(0), (0, 1));
+        assert_eq!(PciVirtioConsole::port_queues(1), (4, 5));
+        assert_eq!(PciVirtioConsole::port_queues(2), (6, 7));
+
+        let dir = tempfile::tempdir().unwrap();
+        let (_machine, dev, _paths) = setup(&dir, &["a", "b"]);
+        assert_eq!(dev.queue_port(1), Some(0));
+        assert_eq!(dev.queue_port(CTRL_RX_QUEUE), None);
+        assert_eq!(dev.queue_port(5), Some(1));
+        assert_eq!(dev.queue_port(6), None);
+    }
+
+    #[test]
+    fn port_discovery_and_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let (machine, dev, paths) = setup(&dir, &["agent", "log"]);
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        dev.set_features(VIRTIO_CONSOLE_F_MULTIPORT).unwrap();
+        dev.start().unwrap();
+
+        for idx in 0..8 {
+            guest_post_rx(&dev, &mem, CTRL_RX_QUEUE, idx);
+        }
+        let ready =
+            ControlMsg { id: 0, event: VIRTIO_CONSOLE_DEVICE_READY, value: 1 };
+        guest_ctrl(&dev, &mem, 0, ready);
+
+        // Each port is announced to the driver
+        for idx in 0..2u16 {
+            wait_used(&mem, ring(CTRL_RX_QUEUE), idx);
+            let msg = ctrl_msg(&mem, idx);
+            assert_eq!(({ msg.id }, { msg.event }), (idx as u32, 1));
+        }
+
+        // Once the driver has set up the port, it learns its name
+        let ready =
+            ControlMsg { id: 1, event: VIRTIO_CONSOLE_PORT_READY, value: 1 };
+        guest_ctrl(&dev, &mem, 1, ready);
+        let len = wait_used(&mem, ring(CTRL_RX_QUEUE), 2) as usize;
+        let msg = ctrl_msg(&mem, 2);
+        assert_eq!({ msg.event }, VIRTIO_CONSOLE_PORT_NAME);
+        let hdr_len = std::mem::size_of::<ControlMsg>();
+        assert_eq!(len, hdr_len + 3);
+        let mut name = [0u8; 3];
+        let name_addr = buf_addr(CTRL_RX_QUEUE, 2) + hdr_len as u64;
+        mem.read_into(GuestAddr(name_addr), &mut name, 3);
+        assert_eq!(&name, b"log");
+
+        // The guest is told when a host process connects
+        let mut host = UnixStream::connect(&paths[1]).unwrap();
+        wait_used(&mem, ring(CTRL_RX_QUEUE), 3);
+        let msg = ctrl_msg(&mem, 3);
+        assert_eq!(({ msg.id }, { msg.event }, { msg.value }), (1, 6, 1));
+
+        // Guest output reaches the host socket
+        let (rx, tx) = PciVirtioConsole::port_queues(1);
+        let addr = buf_addr(tx, 0);
+        mem.write_from(GuestAddr(addr), b"booted", 6);
+        post_buf(&mem, ring(tx), 0, addr, 6, false);
+        notify(&dev, tx);
+        let mut buf = [0u8; 6];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"booted");
+
+        // Host input is held until the guest opens the port
+        guest_post_rx(&dev, &mem, rx, 0);
+        host.write_all(b"hello").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(used_len(&mem, ring(rx), 0), None);
+
+        let open =
+            ControlMsg { id: 1, event: VIRTIO_CONSOLE_PORT_OPEN, value: 1 };
+        guest_ctrl(&dev, &mem, 2, open);
+        assert_eq!(wait_used(&mem, ring(rx), 0), 5);
+        let mut buf = [0u8; 5];
+        mem.read_into(GuestAddr(buf_addr(rx, 0)), &mut buf, 5);
+        assert_eq!(&buf, b"hello");
+
+        // ... and the guest is told when it disconnects
+        drop(host);
+        wait_used(&mem, ring(CTRL_RX_QUEUE), 4);
+        let msg = ctrl_msg(&mem, 4);
+        assert_eq!(({ msg.id }, { msg.event }, { msg.value }), (1, 6, 0));
+
+        dev.halt();
+        assert!(!paths[0].exists() && !paths[1].exists());
+    }
+
+    #[test]
+    fn host_input_split_across_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (machine, dev, paths) = setup(&dir, &["console"]);
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        // Without multiport, port 0 is usable as soon as the device runs
+        dev.set_features(0).unwrap();
+        dev.start().unwrap();
+
+        let mut host = UnixStream::connect(&paths[0]).unwrap();
+        let data = [0xa5u8; 0x1800];
+        host.write_all(&data).unwrap();
+
+        // However the host data is read, no buffer is overfilled, and it all
+        // arrives in order.
+        let mut total = 0;
+        let mut idx = 0;
+        while total < data.len() {
+            guest_post_rx(&dev, &mem, 0, idx);
+            let len = wait_used(&mem, ring(0), idx) as usize;
+            assert!(len > 0 && len <= 0x1000);
+            let mut buf = vec![0u8; len];
+            mem.read_into(GuestAddr(buf_addr(0, idx)), &mut buf, len);
+            assert!(buf.iter().all(|b| *b == 0xa5));
+            total += len;
+            idx += 1;
+        }
+        assert_eq!(total, 0x1800);
+        dev.halt();
+    }
+}
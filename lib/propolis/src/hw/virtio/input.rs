@@ -0,0 +1,537 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtio-input device presenting an absolute pointer (a "tablet").
+//!
+//! Unlike the relative motion reported by a PS/2 mouse, the position of an
+//! absolute pointer cannot drift from that of the host's cursor, which makes
+//! it the appropriate device to drive from a remote console such as VNC.
+
+use std::collections::VecDeque;
+use std::num::NonZeroU16;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::{VirtioDevice, VqChange};
+
+use lazy_static::lazy_static;
+
+/// The largest coordinate reported on either axis of the tablet.  Positions
+/// are scaled by the guest to span its display.
+pub const TABLET_ABS_MAX: u32 = 0x7fff;
+
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+const VIRTIO_INPUT_CFG_SIZE: usize = 136;
+const VIRTIO_INPUT_CFG_DATA_SIZE: usize = 128;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// Event types and codes, as defined by Linux's input-event-codes.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// Maximum number of events held for a guest which is not consuming them
+const MAX_PENDING_EVENTS: usize = 1024;
+
+const TABLET_NAME: &str = "Propolis Tablet";
+
+bitflags! {
+    /// Buttons held on a pointing device.
+    ///
+    /// These follow the layout of the RFB button mask, in which the wheel is
+    /// reported as a pair of buttons pressed for each step it is turned.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct PointerButtons: u8 {
+        const LEFT = 1 << 0;
+        const MIDDLE = 1 << 1;
+        const RIGHT = 1 << 2;
+        const WHEEL_UP = 1 << 3;
+        const WHEEL_DOWN = 1 << 4;
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+struct InputEvent {
+    typ: u16,
+    code: u16,
+    value: u32,
+}
+impl InputEvent {
+    fn new(typ: u16, code: u16, value: i32) -> Self {
+        Self { typ, code, value: value as u32 }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The configuration item selected by the driver
+    select: u8,
+    subsel: u8,
+
+    /// The last reported pointer state
+    pos: Option<(u32, u32)>,
+    buttons: PointerButtons,
+
+    pending: VecDeque<InputEvent>,
+
+    /// Nothing may be written into guest memory while the device is not
+    /// running.
+    running: bool,
+}
+
+pub struct PciVirtioTablet {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+
+    inner: Mutex<Inner>,
+}
+
+impl PciVirtioTablet {
+    pub fn new(queue_size: u16) -> Arc<Self> {
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(2).unwrap(),
+        );
+        // One for each queue, plus one for device config changes
+        let msix_count = Some(3);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_INPUT,
+            VIRTIO_SUB_DEV_INPUT,
+            pci::bits::CLASS_INPUT,
+            VIRTIO_INPUT_CFG_SIZE,
+        );
+        Arc::new(Self { virtio_state, pci_state, inner: Default::default() })
+    }
+
+    /// Report the pointer at (`x`, `y`), each scaled to the range
+    /// `0..=TABLET_ABS_MAX`, with `buttons` held.
+    pub fn pointer_event(&self, x: u32, y: u32, buttons: PointerButtons) {
+        let x = x.min(TABLET_ABS_MAX);
+        let y = y.min(TABLET_ABS_MAX);
+
+        let mut inner = self.inner.lock().unwrap();
+        let mut events = Vec::with_capacity(8);
+        if inner.pos != Some((x, y)) {
+            events.push(InputEvent::new(EV_ABS, ABS_X, x as i32));
+            events.push(InputEvent::new(EV_ABS, ABS_Y, y as i32));
+        }
+        let changed = inner.buttons ^ buttons;
+        for (button, code) in [
+            (PointerButtons::LEFT, BTN_LEFT),
+            (PointerButtons::MIDDLE, BTN_MIDDLE),
+            (PointerButtons::RIGHT, BTN_RIGHT),
+        ] {
+            if changed.contains(button) {
+                let value = buttons.contains(button) as i32;
+                events.push(InputEvent::new(EV_KEY, code, value));
+            }
+        }
+        // The wheel turns a step each time one of its buttons is pressed.
+        let pressed = changed & buttons;
+        if pressed.contains(PointerButtons::WHEEL_UP) {
+            events.push(InputEvent::new(EV_REL, REL_WHEEL, 1));
+        }
+        if pressed.contains(PointerButtons::WHEEL_DOWN) {
+            events.push(InputEvent::new(EV_REL, REL_WHEEL, -1));
+        }
+        if events.is_empty() {
+            // Releasing a wheel button reports nothing, but must still be
+            // noted so that the next press turns the wheel.
+            inner.buttons = buttons;
+            return;
+        }
+        if inner.pending.len() + events.len() >= MAX_PENDING_EVENTS {
+            // The guest is not keeping up (or not listening at all), so
+            // discard the whole report rather than leave it incomplete.  The
+            // changes it carried are reported along with those of the next.
+            probes::virtio_input_drop!(|| ());
+            return;
+        }
+
+        inner.pos = Some((x, y));
+        inner.buttons = buttons;
+        events.push(InputEvent::new(EV_SYN, SYN_REPORT, 0));
+        inner.pending.extend(events);
+        self.flush_events(&mut inner);
+    }
+
+    /// Deliver as many pending events as there are buffers for.
+    fn flush_events(&self, inner: &mut Inner) {
+        if !inner.running || inner.pending.is_empty() {
+            return;
+        }
+        let vq = &self.virtio_state.queues[EVENT_QUEUE];
+        if !vq.live.load(Ordering::Acquire) {
+            return;
+        }
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(1);
+        while let Some(event) = inner.pending.front() {
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            chain.write(event, &mem);
+            vq.push_used(&mut chain, &mem);
+            probes::virtio_input_event!(|| (
+                event.typ,
+                event.code,
+                event.value
+            ));
+            inner.pending.pop_front();
+        }
+    }
+
+    /// Return the buffers the guest has sent on the status queue, whose
+    /// contents (such as LED state) have no meaning for a tablet.
+    fn process_status(&self, vq: &VirtQueue) {
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(1);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+
+    /// The contents of the configuration item selected by the driver
+    fn cfg_data(select: u8, subsel: u8) -> Vec<u8> {
+        match select {
+            VIRTIO_INPUT_CFG_ID_NAME if subsel == 0 => {
+                TABLET_NAME.as_bytes().to_vec()
+            }
+            VIRTIO_INPUT_CFG_ID_DEVIDS if subsel == 0 => {
+                // Identified as QEMU's tablet, for which guests may already
+                // carry any quirks required.
+                [BUS_VIRTUAL, 0x0627, 0x0003, 0x0001]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            VIRTIO_INPUT_CFG_EV_BITS => {
+                let codes: &[u16] = match u16::from(subsel) {
+                    EV_KEY => &[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE],
+                    EV_REL => &[REL_WHEEL],
+                    EV_ABS => &[ABS_X, ABS_Y],
+                    _ => &[],
+                };
+                let mut bitmap = Vec::new();
+                for code in codes.iter().map(|c| usize::from(*c)) {
+                    if bitmap.len() <= code / 8 {
+                        bitmap.resize(code / 8 + 1, 0);
+                    }
+                    bitmap[code / 8] |= 1 << (code % 8);
+                }
+                bitmap
+            }
+            VIRTIO_INPUT_CFG_ABS_INFO
+                if matches!(u16::from(subsel), ABS_X | ABS_Y) =>
+            {
+                // min, max, fuzz, flat, and resolution
+                [0, TABLET_ABS_MAX, 0, 0, 0]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn input_cfg_read(&self, id: &InputReg, ro: &mut ReadOp) {
+        let inner = self.inner.lock().unwrap();
+        let data = Self::cfg_data(inner.select, inner.subsel);
+        match id {
+            InputReg::Select => ro.write_u8(inner.select),
+            InputReg::Subsel => ro.write_u8(inner.subsel),
+            InputReg::Size => ro.write_u8(data.len() as u8),
+            InputReg::Reserved => ro.fill(0),
+            InputReg::Data => {
+                ro.write_bytes(&data);
+                ro.fill(0);
+            }
+        }
+    }
+
+    fn input_cfg_write(&self, id: &InputReg, wo: &mut WriteOp) {
+        let mut inner = self.inner.lock().unwrap();
+        match id {
+            InputReg::Select => inner.select = wo.read_u8(),
+            InputReg::Subsel => inner.subsel = wo.read_u8(),
+            _ => {}
+        }
+    }
+
+    fn set_running(&self, running: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.running = running;
+        self.flush_events(&mut inner);
+    }
+}
+
+impl VirtioDevice for PciVirtioTablet {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        INPUT_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.input_cfg_read(id, ro),
+            RWOp::Write(wo) => self.input_cfg_write(id, wo),
+        });
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        match vq.id as usize {
+            EVENT_QUEUE => self.flush_events(&mut self.inner.lock().unwrap()),
+            STATUS_QUEUE => self.process_status(vq),
+            _ => {}
+        }
+    }
+
+    fn queue_change(
+        &self,
+        vq: &Arc<VirtQueue>,
+        change: VqChange,
+    ) -> Result<(), ()> {
+        if let (EVENT_QUEUE, VqChange::Reset) = (vq.id as usize, change) {
+            let mut inner = self.inner.lock().unwrap();
+            let running = inner.running;
+            *inner = Inner { running, ..Default::default() };
+        }
+        Ok(())
+    }
+}
+
+impl Lifecycle for PciVirtioTablet {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-tablet"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.set_running(true);
+        Ok(())
+    }
+    fn pause(&self) {
+        self.set_running(false);
+    }
+    fn resume(&self) {
+        self.set_running(true);
+    }
+    fn halt(&self) {
+        self.inner.lock().unwrap().running = false;
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl PciVirtio for PciVirtioTablet {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl MigrateMulti for PciVirtioTablet {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        // Pending events are not carried across a migration: the next report
+        // from the console re-establishes the pointer's state.
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        <dyn PciVirtio>::schemas(self, schemas)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum InputReg {
+    Select,
+    Subsel,
+    Size,
+    Reserved,
+    Data,
+}
+lazy_static! {
+    static ref INPUT_DEV_REGS: RegMap<InputReg> = {
+        let layout = [
+            (InputReg::Select, 1),
+            (InputReg::Subsel, 1),
+            (InputReg::Size, 1),
+            (InputReg::Reserved, 5),
+            (InputReg::Data, VIRTIO_INPUT_CFG_DATA_SIZE),
+        ];
+        RegMap::create_packed(VIRTIO_INPUT_CFG_SIZE, &layout, None)
+    };
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn virtio_input_event(typ: u16, code: u16, value: u32) {}
+    fn virtio_input_drop() {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::GuestAddr;
+    use crate::hw::virtio::testutil::{post_buf, used_idx, QUEUE_SIZE};
+    use crate::vmm::{Machine, MemCtx};
+
+    const EVENT_RING: u64 = 0x10_0000;
+    const BUF_BASE: u64 = 0x10_8000;
+
+    fn setup() -> (Machine, Arc<PciVirtioTablet>) {
+        let machine = Machine::new_test().unwrap();
+        let dev = PciVirtioTablet::new(QUEUE_SIZE);
+        machine.acc_mem.adopt(&dev.pci_state.acc_mem, None);
+        let vq = &dev.virtio_state.queues[EVENT_QUEUE];
+        vq.map_legacy(EVENT_RING);
+        vq.live.store(true, Ordering::Release);
+        (machine, dev)
+    }
+
+    /// Provide an event buffer in slot `idx` of the event queue.
+    fn post_event_buf(dev: &PciVirtioTablet, mem: &MemCtx, idx: u16) {
+        let addr = BUF_BASE + 0x10 * u64::from(idx);
+        post_buf(mem, EVENT_RING, idx, addr, 8, true);
+        let vq = dev.virtio_state.queues[EVENT_QUEUE].clone();
+        dev.queue_notify(&vq);
+    }
+
+    /// The events delivered into the first `count` buffers
+    fn delivered(mem: &MemCtx, count: u16) -> Vec<InputEvent> {
+        (0..count.min(used_idx(mem, EVENT_RING)))
+            .map(|idx| {
+                let addr = BUF_BASE + 0x10 * u64::from(idx);
+                mem.read(GuestAddr(addr)).unwrap()
+            })
+            .collect()
+    }
+
+    fn cfg_query(dev: &PciVirtioTablet, select: u8, subsel: u8) -> Vec<u8> {
+        let buf = [select, subsel];
+        let mut wo = WriteOp::from_buf(0, &buf);
+        dev.cfg_rw(RWOp::Write(&mut wo));
+
+        let mut size = [0u8];
+        let mut ro = ReadOp::from_buf(2, &mut size);
+        dev.cfg_rw(RWOp::Read(&mut ro));
+        if size[0] == 0 {
+            return Vec::new();
+        }
+        let mut data = vec![0u8; size[0] as usize];
+        let mut ro = ReadOp::from_buf(8, &mut data);
+        dev.cfg_rw(RWOp::Read(&mut ro));
+        data
+    }
+
+    #[test]
+    fn cfg_items() {
+        let (_machine, dev) = setup();
+
+        assert_eq!(
+            cfg_query(&dev, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            TABLET_NAME.as_bytes()
+        );
+        // Buttons 0x110-0x112 fall in the 35th byte of the key bitmap
+        let keys = cfg_query(&dev, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(keys.len(), 35);
+        assert_eq!(keys[34], 0b111);
+        assert_eq!(
+            cfg_query(&dev, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            [0b11]
+        );
+        let abs = cfg_query(&dev, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(abs.len(), 20);
+        assert_eq!(abs[4..8], TABLET_ABS_MAX.to_le_bytes());
+        assert!(cfg_query(&dev, VIRTIO_INPUT_CFG_EV_BITS, 0x05).is_empty());
+    }
+
+    #[test]
+    fn pointer_reports() {
+        let (machine, dev) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        dev.start().unwrap();
+
+        for idx in 0..4 {
+            post_event_buf(&dev, &mem, idx);
+        }
+        dev.pointer_event(100, 200, PointerButtons::LEFT);
+        assert_eq!(
+            delivered(&mem, 4),
+            [
+                InputEvent::new(EV_ABS, ABS_X, 100),
+                InputEvent::new(EV_ABS, ABS_Y, 200),
+                InputEvent::new(EV_KEY, BTN_LEFT, 1),
+                InputEvent::new(EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        // Reports wait for buffers, and unchanged state is not repeated
+        dev.pointer_event(100, 200, PointerButtons::WHEEL_DOWN);
+        dev.pointer_event(100, 200, PointerButtons::empty());
+        dev.pointer_event(100, 200, PointerButtons::WHEEL_DOWN);
+        for idx in 4..8 {
+            post_event_buf(&dev, &mem, idx);
+        }
+        assert_eq!(
+            delivered(&mem, 8)[4..],
+            [
+                InputEvent::new(EV_KEY, BTN_LEFT, 0),
+                InputEvent::new(EV_REL, REL_WHEEL, -1),
+                InputEvent::new(EV_SYN, SYN_REPORT, 0),
+                InputEvent::new(EV_REL, REL_WHEEL, -1),
+            ]
+        );
+    }
+}
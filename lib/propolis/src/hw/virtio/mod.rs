@@ -8,19 +8,30 @@ use std::sync::Arc;
 mod bits;
 
 pub mod block;
+pub mod console;
+pub mod input;
+pub mod net;
 #[cfg(feature = "falcon")]
 pub mod p9fs;
 pub mod pci;
 mod queue;
 #[cfg(feature = "falcon")]
 pub mod softnpu;
+#[cfg(test)]
+mod testutil;
 pub mod viona;
+pub mod vsock;
 
 use crate::common::*;
 use queue::VirtQueue;
 
 pub use block::PciVirtioBlock;
+pub use console::PciVirtioConsole;
+pub use input::PciVirtioTablet;
+pub use net::PciVirtioNet;
+pub use queue::{IntrModeration, VqStatsSnapshot, MAX_INTR_MODERATION_USECS};
 pub use viona::PciVirtioViona;
+pub use vsock::PciVirtioSocket;
 
 pub trait VirtioDevice: Send + Sync + 'static + Lifecycle {
     /// Read/write device-specific virtio configuration space
@@ -51,6 +62,17 @@ pub trait VirtioDevice: Send + Sync + 'static + Lifecycle {
     }
 }
 
+/// A virtio NIC whose link state, as reported to the guest through the
+/// VIRTIO_NET_S_LINK_UP status bit, can be controlled.
+pub trait NetLink: Send + Sync {
+    /// Report the link as up or down, notifying the guest of any change with
+    /// a config-change interrupt.
+    fn set_link_up(&self, up: bool);
+
+    /// Is the link currently reported as up?
+    fn link_up(&self) -> bool;
+}
+
 pub trait VirtioIntr: Send + 'static {
     fn notify(&self);
     fn read(&self) -> VqIntr;
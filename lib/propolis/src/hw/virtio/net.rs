@@ -0,0 +1,562 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtio-net device whose datapath is implemented in userspace, passing
+//! frames to and from a [net::Backend], for hosts lacking viona.
+
+use std::num::NonZeroU16;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::net;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{
+    read_buf, write_buf, Chain, IntrModeration, VirtQueue, VirtQueues,
+};
+use super::viona::bits::{VIRTIO_NET_CFG_SIZE, VIRTIO_NET_S_LINK_UP};
+use super::{NetLink, VirtioDevice};
+
+use lazy_static::lazy_static;
+
+const ETHERADDRL: usize = 6;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+pub struct PciVirtioNet {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+
+    mac_addr: [u8; ETHERADDRL],
+    backend: Arc<dyn net::Backend>,
+
+    /// Receives frames from the backend while the device is running
+    receiver: Mutex<Option<net::Receiver>>,
+    this: Weak<Self>,
+
+    /// Link state reported to the guest.  While the link is down, frames are
+    /// dropped in both directions, as they would be without carrier.
+    link_up: AtomicBool,
+
+    stats: net::NicStats,
+}
+
+impl PciVirtioNet {
+    /// Create a virtio-net device, with queues of `queue_size` entries, which
+    /// presents `mac_addr` to the guest and carries its traffic over
+    /// `backend`.  The interrupts announcing frames sent and received may be
+    /// deferred according to `moderation`.
+    pub fn new(
+        mac_addr: [u8; ETHERADDRL],
+        queue_size: u16,
+        backend: Arc<dyn net::Backend>,
+        moderation: Option<IntrModeration>,
+    ) -> Arc<Self> {
+        let queues = VirtQueues::with_moderation(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(2).unwrap(),
+            moderation,
+        );
+        // One MSI-X entry for each of the RX and TX queues, plus one for
+        // device config changes
+        let msix_count = Some(3);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_NET,
+            VIRTIO_SUB_DEV_NET,
+            pci::bits::CLASS_NETWORK,
+            VIRTIO_NET_CFG_SIZE,
+        );
+
+        Arc::new_cyclic(|this| Self {
+            virtio_state,
+            pci_state,
+            mac_addr,
+            backend,
+            receiver: Mutex::new(None),
+            this: this.clone(),
+            link_up: AtomicBool::new(true),
+            stats: Default::default(),
+        })
+    }
+
+    /// Choose a MAC address for a NIC at `bdf`, for use when one has not been
+    /// otherwise specified.
+    ///
+    /// The address is locally administered, and derived only from the
+    /// device's location, so it remains stable across restarts and migrations
+    /// of an instance.
+    pub fn default_mac(bdf: pci::Bdf) -> [u8; ETHERADDRL] {
+        [
+            0x02,
+            0x08,
+            0x20,
+            bdf.bus.get(),
+            bdf.location.dev.get(),
+            bdf.location.func.get(),
+        ]
+    }
+
+    fn net_cfg_read(&self, id: &NetReg, ro: &mut ReadOp) {
+        match id {
+            NetReg::Mac => ro.write_bytes(&self.mac_addr),
+            NetReg::Status => match self.link_up() {
+                true => ro.write_u16(VIRTIO_NET_S_LINK_UP),
+                false => ro.write_u16(0),
+            },
+            NetReg::Unused => ro.fill(0),
+        }
+    }
+
+    /// Send the frames in all available TX chains out through the backend.
+    fn process_tx(&self, vq: &VirtQueue) {
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut frame = [0u8; net::MAX_FRAME_LEN];
+        let mut chain = Chain::with_capacity(4);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            let mut hdr = NetHdr::default();
+            let len = match chain.read(&mut hdr, &mem) {
+                true => read_buf(&mem, &mut chain, &mut frame),
+                false => 0,
+            };
+            // Frames too large to have been sent with the features we offer
+            // are as malformed as those missing their header.
+            let valid = len != 0 && chain.remain_read_bytes() == 0;
+            if valid && self.link_up() {
+                probes::vionet_tx!(|| len as u64);
+                // Frames are sent on a best-effort basis, as they would be on
+                // a wire, so errors from the backend result only in loss.
+                match self.backend.send(&frame[..len]) {
+                    Ok(()) => self.stats.tx(len),
+                    Err(_) => self.tx_drop(len),
+                }
+            } else {
+                self.tx_drop(len);
+            }
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+
+    /// Deliver a frame from the backend into the next available RX chain.
+    fn process_rx(&self, frame: &[u8]) {
+        let vq = &self.virtio_state.queues[RX_QUEUE];
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        // Until the driver notifies us of the queue, it may yet be in the midst
+        // of configuring it.
+        if !vq.live.load(Ordering::Acquire) || !self.link_up() {
+            self.rx_drop(frame.len());
+            return;
+        }
+        let mut chain = Chain::with_capacity(4);
+        if vq.pop_avail(&mut chain, &mem).is_none() {
+            // Like a physical NIC with a full receive ring, we can only drop
+            // frames for which the guest has not provided buffers.
+            self.rx_drop(frame.len());
+            return;
+        }
+        let hdr_len = std::mem::size_of::<NetHdr>();
+        if chain.remain_write_bytes() >= hdr_len + frame.len() {
+            probes::vionet_rx!(|| frame.len() as u64);
+            chain.write(&NetHdr::default(), &mem);
+            write_buf(frame, &mut chain, &mem);
+            self.stats.rx(frame.len());
+        } else {
+            // Return the buffers empty, rather than holding on to them
+            self.rx_drop(frame.len());
+        }
+        vq.push_used(&mut chain, &mem);
+    }
+
+    fn tx_drop(&self, len: usize) {
+        probes::vionet_tx_drop!(|| len as u64);
+        self.stats.tx_error();
+    }
+
+    fn rx_drop(&self, len: usize) {
+        probes::vionet_rx_drop!(|| len as u64);
+        self.stats.rx_error();
+    }
+
+    /// Counters of the traffic the device has passed
+    pub fn stats(&self) -> net::NicStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    fn rx_start(&self) {
+        let mut receiver = self.receiver.lock().unwrap();
+        if receiver.is_none() {
+            *receiver = Some(net::Receiver::spawn(
+                "virtio-net rx",
+                self.backend.clone(),
+                self.this.clone() as Weak<dyn net::Receive>,
+            ));
+        }
+    }
+
+    fn rx_stop(&self) {
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            receiver.stop();
+        }
+    }
+}
+
+impl net::Receive for PciVirtioNet {
+    fn receive(&self, frame: &[u8]) {
+        self.process_rx(frame);
+    }
+}
+
+impl VirtioDevice for PciVirtioNet {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        NET_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.net_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn get_features(&self) -> u32 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+    fn set_features(&self, _feat: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        // Buffers added to the RX queue are consumed as frames arrive
+        if vq.id as usize == TX_QUEUE {
+            self.process_tx(vq);
+        }
+    }
+}
+
+impl Lifecycle for PciVirtioNet {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-net"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.rx_start();
+        Ok(())
+    }
+    fn pause(&self) {
+        // Nothing may be written into guest memory while paused
+        self.rx_stop();
+    }
+    fn resume(&self) {
+        self.rx_start();
+    }
+    fn halt(&self) {
+        self.rx_stop();
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl NetLink for PciVirtioNet {
+    fn set_link_up(&self, up: bool) {
+        if self.link_up.swap(up, Ordering::SeqCst) != up {
+            self.virtio_state.config_change(&self.pci_state);
+        }
+    }
+    fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::SeqCst)
+    }
+}
+
+impl PciVirtio for PciVirtioNet {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl MigrateMulti for PciVirtioNet {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        <dyn PciVirtio>::schemas(self, schemas)
+    }
+}
+
+/// Header preceding each frame in the TX and RX queues.
+///
+/// This is the legacy layout, without the `num_buffers` field which is present
+/// only when VIRTIO_NET_F_MRG_RXBUF is negotiated.  None of the offload
+/// features it describes are offered, so it is ignored for TX and zeroed for
+/// RX.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct NetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum NetReg {
+    Mac,
+    Status,
+    Unused,
+}
+lazy_static! {
+    static ref NET_DEV_REGS: RegMap<NetReg> = {
+        let layout =
+            [(NetReg::Mac, 6), (NetReg::Status, 2), (NetReg::Unused, 4)];
+        RegMap::create_packed(VIRTIO_NET_CFG_SIZE, &layout, None)
+    };
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn vionet_tx(len: u64) {}
+    fn vionet_tx_drop(len: u64) {}
+    fn vionet_rx(len: u64) {}
+    fn vionet_rx_drop(len: u64) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::GuestAddr;
+    use crate::hw::virtio::testutil::{post_buf, used_len, QUEUE_SIZE};
+    use crate::hw::virtio::{VirtioIntr, VqIntr, MAX_INTR_MODERATION_USECS};
+    use crate::net::DgramBackend;
+    use crate::vmm::{Machine, MemCtx};
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    /// Rings for the RX and TX queues, each in a (4k-aligned) 8k region
+    const RX_RING: u64 = 0x10_0000;
+    const TX_RING: u64 = 0x10_2000;
+    const BUF_BASE: u64 = 0x10_8000;
+
+    fn setup() -> (Machine, Arc<PciVirtioNet>, UnixDatagram) {
+        setup_moderated(None)
+    }
+
+    fn setup_moderated(
+        moderation: Option<IntrModeration>,
+    ) -> (Machine, Arc<PciVirtioNet>, UnixDatagram) {
+        let machine = Machine::new_test().unwrap();
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        let dev = PciVirtioNet::new(
+            [0x02, 0, 0, 0, 0, 1],
+            QUEUE_SIZE,
+            Arc::new(DgramBackend::from_socket(ours)),
+            moderation,
+        );
+        machine.acc_mem.adopt(&dev.pci_state.acc_mem, None);
+        dev.virtio_state.queues[RX_QUEUE].map_legacy(RX_RING);
+        dev.virtio_state.queues[TX_QUEUE].map_legacy(TX_RING);
+        (machine, dev, theirs)
+    }
+
+    #[test]
+    fn transmit() {
+        let (machine, dev, peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let frame: Vec<u8> = (0..64).collect();
+        mem.write_from(GuestAddr(BUF_BASE), &[0u8; 10], 10);
+        mem.write_from(GuestAddr(BUF_BASE + 10), &frame, frame.len());
+        post_buf(&mem, TX_RING, 0, BUF_BASE, 10 + frame.len() as u32, false);
+
+        let vq = dev.virtio_state.queues[TX_QUEUE].clone();
+        dev.queue_notify(&vq);
+
+        let mut buf = [0u8; 128];
+        assert_eq!(peer.recv(&mut buf).unwrap(), frame.len());
+        assert_eq!(&buf[..frame.len()], &frame[..]);
+        assert_eq!(used_len(&mem, TX_RING, 0), Some(0));
+
+        let stats = dev.stats();
+        assert_eq!((stats.tx_packets, stats.tx_bytes), (1, 64));
+        assert_eq!(stats.tx_errors, 0);
+    }
+
+    #[test]
+    fn transmit_headerless() {
+        let (machine, dev, peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        // A chain too short to hold even the header is consumed, but nothing
+        // should be sent.
+        post_buf(&mem, TX_RING, 0, BUF_BASE, 4, false);
+        let vq = dev.virtio_state.queues[TX_QUEUE].clone();
+        dev.queue_notify(&vq);
+
+        assert_eq!(used_len(&mem, TX_RING, 0), Some(0));
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.recv(&mut [0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn receive() {
+        let (machine, dev, _peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let frame: Vec<u8> = (0..64).rev().collect();
+
+        // Frames arriving before the driver has made the queue live, or has
+        // provided buffers, are dropped.
+        dev.process_rx(&frame);
+        dev.virtio_state.queues[RX_QUEUE].live.store(true, Ordering::Release);
+        dev.process_rx(&frame);
+        assert_eq!(used_len(&mem, RX_RING, 0), None);
+
+        post_buf(&mem, RX_RING, 0, BUF_BASE, 2048, true);
+        dev.process_rx(&frame);
+        assert_eq!(used_len(&mem, RX_RING, 0), Some(10 + frame.len() as u32));
+
+        let mut buf = [0xffu8; 74];
+        mem.read_into(GuestAddr(BUF_BASE), &mut buf, buf.len());
+        assert_eq!(&buf[..10], &[0u8; 10]);
+        assert_eq!(&buf[10..], &frame[..]);
+
+        // The two frames dropped before buffers were available count as
+        // errors.
+        let stats = dev.stats();
+        assert_eq!((stats.rx_packets, stats.rx_bytes), (1, 64));
+        assert_eq!(stats.rx_errors, 2);
+    }
+
+    #[test]
+    fn link_down() {
+        let (machine, dev, peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let read_status = |dev: &PciVirtioNet| {
+            let mut buf = [0u8; 2];
+            let mut ro = ReadOp::from_buf(6, &mut buf);
+            dev.cfg_rw(RWOp::Read(&mut ro));
+            u16::from_le_bytes(buf)
+        };
+        assert_eq!(read_status(&dev), VIRTIO_NET_S_LINK_UP);
+        dev.set_link_up(false);
+        assert_eq!(read_status(&dev), 0);
+
+        // Without a link, frames are dropped in both directions, though the
+        // guest's buffers are still returned to it.
+        post_buf(&mem, TX_RING, 0, BUF_BASE, 74, false);
+        let vq = dev.virtio_state.queues[TX_QUEUE].clone();
+        dev.queue_notify(&vq);
+        assert_eq!(used_len(&mem, TX_RING, 0), Some(0));
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.recv(&mut [0u8; 128]).is_err());
+
+        dev.virtio_state.queues[RX_QUEUE].live.store(true, Ordering::Release);
+        post_buf(&mem, RX_RING, 0, BUF_BASE, 2048, true);
+        dev.process_rx(&[0u8; 64]);
+        assert_eq!(used_len(&mem, RX_RING, 0), None);
+
+        dev.set_link_up(true);
+        assert_eq!(read_status(&dev), VIRTIO_NET_S_LINK_UP);
+        dev.process_rx(&[0u8; 64]);
+        assert_eq!(used_len(&mem, RX_RING, 0), Some(74));
+    }
+
+    #[test]
+    fn receive_small_buffer() {
+        let (machine, dev, _peer) = setup();
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        dev.virtio_state.queues[RX_QUEUE].live.store(true, Ordering::Release);
+        post_buf(&mem, RX_RING, 0, BUF_BASE, 32, true);
+        dev.process_rx(&[0u8; 64]);
+
+        // The buffer is returned to the guest with nothing written to it
+        assert_eq!(used_len(&mem, RX_RING, 0), Some(0));
+    }
+
+    /// Counts the interrupts sent for a queue
+    struct CountingIntr(Arc<AtomicUsize>);
+    impl VirtioIntr for CountingIntr {
+        fn notify(&self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+        fn read(&self) -> VqIntr {
+            VqIntr::Pin
+        }
+    }
+
+    #[test]
+    fn intr_moderation() {
+        let moderation = IntrModeration::new(2, 1000).unwrap();
+        let (machine, dev, _peer) = setup_moderated(Some(moderation));
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let vq = dev.virtio_state.queues[TX_QUEUE].clone();
+        vq.set_intr(Box::new(CountingIntr(count.clone())));
+
+        // The first of the frames sent awaits a second to share its interrupt
+        post_buf(&mem, TX_RING, 0, BUF_BASE, 74, false);
+        dev.queue_notify(&vq);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        post_buf(&mem, TX_RING, 1, BUF_BASE, 74, false);
+        dev.queue_notify(&vq);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // A third, left on its own, is announced once its delay expires
+        post_buf(&mem, TX_RING, 2, BUF_BASE, 74, false);
+        dev.queue_notify(&vq);
+        assert_eq!(used_len(&mem, TX_RING, 2), Some(0));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while count.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(vq.stats().interrupts, 2);
+    }
+
+    #[test]
+    fn intr_moderation_limits() {
+        assert!(IntrModeration::new(0, 100).is_err());
+        assert!(IntrModeration::new(8, 0).is_err());
+        assert!(IntrModeration::new(8, MAX_INTR_MODERATION_USECS + 1).is_err());
+        assert!(IntrModeration::new(1, MAX_INTR_MODERATION_USECS).is_ok());
+    }
+}
@@ -8,7 +8,7 @@ use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
 
 use super::bits::*;
 use super::probes;
-use super::queue::VirtQueues;
+use super::queue::{VirtQueues, VqStatsSnapshot};
 use super::{VirtioDevice, VirtioIntr, VqChange, VqIntr};
 use crate::common::*;
 use crate::hw::ids::pci::VENDOR_VIRTIO;
@@ -437,6 +437,7 @@ impl PciVirtioState {
         ));
         if let Some(vq) = self.queues.get(queue) {
             vq.live.store(true, Ordering::Release);
+            vq.kicked();
             dev.queue_notify(vq);
         }
     }
@@ -525,10 +526,42 @@ impl PciVirtioState {
         self.state_cv.notify_all();
     }
 
+    /// Notify the driver that the device-specific configuration has changed,
+    /// via the MSI-X vector it assigned for such changes, or the ISR when
+    /// MSI-X is not in use.
+    pub fn config_change(&self, pci_state: &pci::DeviceState) {
+        let state = self.state.lock().unwrap();
+        match state.intr_mode {
+            IntrMode::Msi => {
+                let vec = state.msix_cfg_vec;
+                drop(state);
+                let hdl = pci_state.msix_hdl().unwrap();
+                // A driver which assigned no vector (VIRTIO_MSI_NO_VECTOR)
+                // is left to notice the change for itself.
+                if vec < hdl.count() {
+                    hdl.fire(vec);
+                }
+            }
+            _ => {
+                drop(state);
+                self.isr_state.raise_cfg();
+            }
+        }
+    }
+
     pub fn negotiated_features(&self) -> u32 {
         let state = self.state.lock().unwrap();
         state.nego_feat
     }
+
+    /// Snapshot the activity counters of each of the device's virtqueues.
+    ///
+    /// For devices whose rings are processed outside of propolis (such as
+    /// viona, which is serviced in-kernel), only the kicks from the driver and
+    /// any interrupts delivered by propolis on their behalf are observed here.
+    pub fn queue_stats(&self) -> Vec<VqStatsSnapshot> {
+        self.queues.iter().map(|vq| vq.stats()).collect()
+    }
 }
 impl MigrateMulti for PciVirtioState {
     fn export(
@@ -583,6 +616,10 @@ impl MigrateMulti for PciVirtioState {
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas.add::<migrate::PciVirtioStateV1>();
+    }
 }
 
 impl MigrateMulti for dyn PciVirtio {
@@ -620,6 +657,11 @@ impl MigrateMulti for dyn PciVirtio {
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        MigrateMulti::schemas(self.virtio_state(), schemas);
+        MigrateMulti::schemas(self.pci_state(), schemas)
+    }
 }
 
 #[derive(Default)]
@@ -647,6 +689,12 @@ impl IsrState {
             inner.intr_queue = true;
         });
     }
+    /// Raise config-change ISR condition
+    fn raise_cfg(&self) {
+        self.sync_pin(|inner| {
+            inner.intr_cfg = true;
+        });
+    }
     /// Read ISR value, then clear it.
     fn read_clear(&self) -> u8 {
         let (mut queue, mut cfg) = (false, false);
@@ -6,8 +6,10 @@ use std::mem;
 use std::num::{NonZeroU16, Wrapping};
 use std::ops::Index;
 use std::slice::SliceIndex;
-use std::sync::atomic::{fence, AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use super::bits::*;
 use super::probes;
@@ -15,6 +17,7 @@ use super::{VirtioIntr, VqIntr};
 use crate::accessors::MemAccessor;
 use crate::common::*;
 use crate::migrate::MigrateStateError;
+use crate::util::latency::{LatencyHistogram, LATENCY_BUCKETS};
 use crate::vmm::MemCtx;
 
 #[repr(C)]
@@ -48,6 +51,10 @@ pub struct VqAvail {
     cur_avail_idx: Wrapping<u16>,
 
     gpa_desc: GuestAddr,
+
+    /// When the driver first notified us of buffers which have yet to be
+    /// consumed from the ring
+    last_kick: Option<Instant>,
 }
 impl VqAvail {
     /// If there's a request ready, pop it off the queue and return the
@@ -58,7 +65,11 @@ impl VqAvail {
         }
         if let Some(idx) = mem.read::<u16>(self.gpa_idx) {
             let ndesc = Wrapping(idx) - self.cur_avail_idx;
-            if ndesc.0 != 0 && ndesc.0 < rsize {
+            if ndesc.0 == 0 {
+                // With the ring drained, any subsequent buffers will be
+                // accompanied by a kick of their own.
+                self.last_kick = None;
+            } else if ndesc.0 < rsize {
                 let avail_idx = self.cur_avail_idx.0 & (rsize - 1);
                 self.cur_avail_idx += Wrapping(1);
 
@@ -88,6 +99,7 @@ impl VqAvail {
         self.gpa_ring = GuestAddr(0);
         self.gpa_desc = GuestAddr(0);
         self.cur_avail_idx = Wrapping(0);
+        self.last_kick = None;
     }
     fn map_split(&mut self, desc_addr: u64, avail_addr: u64) {
         self.gpa_desc = GuestAddr(desc_addr);
@@ -107,6 +119,12 @@ pub struct VqUsed {
     gpa_ring: GuestAddr,
     used_idx: Wrapping<u16>,
     interrupt: Option<Box<dyn VirtioIntr>>,
+
+    /// Entries placed in the ring since the driver was last interrupted,
+    /// while their interrupt is deferred by moderation
+    deferred: u32,
+    /// When the oldest of those entries was placed in the ring
+    deferred_since: Option<Instant>,
 }
 impl VqUsed {
     fn write_used(&mut self, id: u16, len: u32, rsize: u16, mem: &MemCtx) {
@@ -134,6 +152,8 @@ impl VqUsed {
         self.gpa_idx = GuestAddr(0);
         self.gpa_ring = GuestAddr(0);
         self.used_idx = Wrapping(0);
+        self.deferred = 0;
+        self.deferred_since = None;
     }
     fn map_split(&mut self, gpa: u64) {
         // 16-bit flags, followed by 16-bit idx, followed by used desc ring
@@ -143,6 +163,118 @@ impl VqUsed {
     }
 }
 
+/// Activity counters for a [VirtQueue], kept to aid in diagnosing misbehaving
+/// guest drivers.
+///
+/// These accumulate over the life of the queue, and are not reset along with
+/// the device or carried across a migration.
+struct VqStats {
+    kicks: AtomicU64,
+    interrupts: AtomicU64,
+    chains: AtomicU64,
+    descriptors: AtomicU64,
+    bytes: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+impl VqStats {
+    fn new() -> Self {
+        Self {
+            kicks: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+            chains: AtomicU64::new(0),
+            descriptors: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            latency: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+    fn chain_completed(&self, chain: &Chain) {
+        self.chains.fetch_add(1, Ordering::Relaxed);
+        self.descriptors.fetch_add(chain.bufs.len() as u64, Ordering::Relaxed);
+        let bytes =
+            chain.read_stat.bytes as u64 + chain.write_stat.bytes as u64;
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(start) = chain.start {
+            self.latency[LatencyHistogram::bucket_for(start.elapsed())]
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Point-in-time copy of the activity counters for a [VirtQueue].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VqStatsSnapshot {
+    /// ID of the queue within its device
+    pub queue_id: u16,
+    /// Notifications from the driver that buffers are available
+    pub kicks: u64,
+    /// Interrupts sent to the driver for buffers placed in the used ring
+    pub interrupts: u64,
+    /// Descriptor chains consumed and returned to the driver
+    pub chains: u64,
+    /// Descriptors making up those chains
+    pub descriptors: u64,
+    /// Total size, in bytes, of the buffers described by those chains
+    pub bytes: u64,
+    /// Time from the kick which made a chain available to its return to the
+    /// used ring.  Chains are measured from the earliest kick received since
+    /// the ring was last found empty, or from when they were popped if there
+    /// was no such kick (as for devices which poll their rings).
+    pub latency: LatencyHistogram,
+}
+
+/// Longest delay permitted for an interrupt by [IntrModeration]
+pub const MAX_INTR_MODERATION_USECS: u32 = 100_000;
+
+/// Limits on how long the interrupt announcing an entry in the used ring may
+/// be deferred, so that a single interrupt can announce several entries.
+///
+/// The interrupt is delivered once `max_packets` entries are awaiting it, or
+/// once the oldest of them has waited `max_usecs`, whichever comes first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IntrModeration {
+    max_packets: u32,
+    max_usecs: u32,
+}
+impl IntrModeration {
+    pub fn new(max_packets: u32, max_usecs: u32) -> std::io::Result<Self> {
+        use std::io::{Error, ErrorKind};
+        if max_packets == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "packet limit must be non-zero",
+            ));
+        }
+        if max_usecs == 0 || max_usecs > MAX_INTR_MODERATION_USECS {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "delay must be between 1 and \
+                    {MAX_INTR_MODERATION_USECS} microseconds"
+                ),
+            ));
+        }
+        Ok(Self { max_packets, max_usecs })
+    }
+
+    pub fn max_packets(&self) -> u32 {
+        self.max_packets
+    }
+
+    pub fn max_usecs(&self) -> u32 {
+        self.max_usecs
+    }
+
+    fn max_delay(&self) -> Duration {
+        Duration::from_micros(u64::from(self.max_usecs))
+    }
+}
+
+/// Moderation applied to the interrupts of a [VirtQueue]
+struct VqModeration {
+    limits: IntrModeration,
+    timer: Arc<TimerShared>,
+}
+
 pub struct VirtQueue {
     pub id: u16,
     pub size: u16,
@@ -150,6 +282,8 @@ pub struct VirtQueue {
     avail: Mutex<VqAvail>,
     used: Mutex<VqUsed>,
     pub acc_mem: MemAccessor,
+    stats: VqStats,
+    moderation: Option<VqModeration>,
 }
 const LEGACY_QALIGN: u64 = PAGE_SIZE as u64;
 const fn qalign(addr: u64, align: u64) -> u64 {
@@ -160,6 +294,13 @@ const fn qalign(addr: u64, align: u64) -> u64 {
 }
 impl VirtQueue {
     pub fn new(id: u16, size: u16) -> Self {
+        Self::with_moderation(id, size, None)
+    }
+    fn with_moderation(
+        id: u16,
+        size: u16,
+        moderation: Option<VqModeration>,
+    ) -> Self {
         assert!(size.is_power_of_two());
         Self {
             id,
@@ -172,6 +313,7 @@ impl VirtQueue {
                 gpa_ring: GuestAddr(0),
                 cur_avail_idx: Wrapping(0),
                 gpa_desc: GuestAddr(0),
+                last_kick: None,
             }),
             used: Mutex::new(VqUsed {
                 valid: false,
@@ -180,8 +322,12 @@ impl VirtQueue {
                 gpa_ring: GuestAddr(0),
                 used_idx: Wrapping(0),
                 interrupt: None,
+                deferred: 0,
+                deferred_since: None,
             }),
             acc_mem: MemAccessor::new_orphan(),
+            stats: VqStats::new(),
+            moderation,
         }
     }
     pub(super) fn reset(&self) {
@@ -259,6 +405,7 @@ impl VirtQueue {
         let mut count = 0;
         let mut len = 0;
         chain.idx = Some(req.desc_idx);
+        chain.start = Some(avail.last_kick.unwrap_or_else(Instant::now));
         probes::virtio_vq_pop!(|| (
             self as *const VirtQueue as u64,
             req.desc_idx,
@@ -337,14 +484,89 @@ impl VirtQueue {
         let len = chain.write_stat.bytes - chain.write_stat.bytes_remain;
         probes::virtio_vq_push!(|| (self as *const VirtQueue as u64, id, len));
         used.write_used(id, len, self.size, mem);
-        if !used.intr_supressed(mem) {
-            if let Some(intr) = used.interrupt.as_ref() {
-                intr.notify();
-            }
-        }
+        self.stats.chain_completed(chain);
+        self.used_added(&mut used, mem);
         chain.reset();
     }
 
+    /// Interrupt the driver for an entry placed in the used ring, unless
+    /// moderation permits the interrupt to be deferred.
+    fn used_added(&self, used: &mut VqUsed, mem: &MemCtx) {
+        let Some(moderation) = self.moderation.as_ref() else {
+            return self.notify(used, Some(mem));
+        };
+        let now = Instant::now();
+        used.deferred += 1;
+        let since = *used.deferred_since.get_or_insert(now);
+        if used.deferred >= moderation.limits.max_packets
+            || now >= since + moderation.limits.max_delay()
+        {
+            self.notify(used, Some(mem));
+        } else if used.deferred == 1 {
+            // The timer must learn of the new deadline, in case no further
+            // entries arrive to carry the interrupt.
+            moderation.timer.wake();
+        }
+    }
+
+    /// Interrupt the driver, unless it has asked not to be, for any entries in
+    /// the used ring.  Without access to guest memory, the driver's wishes are
+    /// unknown and the interrupt is sent regardless, as a spurious interrupt
+    /// is harmless where a missing one could stall it.
+    fn notify(&self, used: &mut VqUsed, mem: Option<&MemCtx>) {
+        used.deferred = 0;
+        used.deferred_since = None;
+        if mem.is_some_and(|mem| used.intr_supressed(mem)) {
+            return;
+        }
+        if let Some(intr) = used.interrupt.as_ref() {
+            intr.notify();
+            self.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Deliver a deferred interrupt if its delay has expired as of `now`,
+    /// returning the deadline of one which remains deferred.
+    fn flush_deferred(&self, now: Instant) -> Option<Instant> {
+        let moderation = self.moderation.as_ref()?;
+        let mem = self.acc_mem.access();
+        let mut used = self.used.lock().unwrap();
+        let deadline = used.deferred_since? + moderation.limits.max_delay();
+        if now < deadline {
+            return Some(deadline);
+        }
+        self.notify(&mut used, mem.as_deref());
+        None
+    }
+
+    /// Record a notification from the driver that buffers have been made
+    /// available in this queue.
+    pub(super) fn kicked(&self) {
+        self.stats.kicks.fetch_add(1, Ordering::Relaxed);
+        let mut avail = self.avail.lock().unwrap();
+        if avail.last_kick.is_none() {
+            avail.last_kick = Some(Instant::now());
+        }
+    }
+
+    /// Take a snapshot of the activity counters for this queue
+    pub fn stats(&self) -> VqStatsSnapshot {
+        let stats = &self.stats;
+        VqStatsSnapshot {
+            queue_id: self.id,
+            kicks: stats.kicks.load(Ordering::Relaxed),
+            interrupts: stats.interrupts.load(Ordering::Relaxed),
+            chains: stats.chains.load(Ordering::Relaxed),
+            descriptors: stats.descriptors.load(Ordering::Relaxed),
+            bytes: stats.bytes.load(Ordering::Relaxed),
+            latency: LatencyHistogram {
+                counts: std::array::from_fn(|i| {
+                    stats.latency[i].load(Ordering::Relaxed)
+                }),
+            },
+        }
+    }
+
     /// Set the backing interrupt resource for VQ
     pub(super) fn set_intr(&self, intr: Box<dyn VirtioIntr>) {
         let mut used = self.used.lock().unwrap();
@@ -359,17 +581,20 @@ impl VirtQueue {
 
     /// Send an interrupt for VQ
     pub(super) fn send_intr(&self, mem: &MemCtx) {
-        let used = self.used.lock().unwrap();
-        if !used.intr_supressed(mem) {
-            if let Some(intr) = used.interrupt.as_ref() {
-                intr.notify();
-            }
-        }
+        let mut used = self.used.lock().unwrap();
+        self.notify(&mut used, Some(mem));
     }
 
     pub fn export(&self) -> migrate::VirtQueueV1 {
+        let mem = self.acc_mem.access();
         let avail = self.avail.lock().unwrap();
-        let used = self.used.lock().unwrap();
+        let mut used = self.used.lock().unwrap();
+
+        // Deferred interrupts are not part of the migrated state, so any
+        // entries still awaiting one are announced now.
+        if used.deferred != 0 {
+            self.notify(&mut used, mem.as_deref());
+        }
 
         migrate::VirtQueueV1 {
             id: self.id,
@@ -458,6 +683,8 @@ struct ChainStat {
 #[derive(Debug)]
 pub struct Chain {
     idx: Option<u16>,
+    /// When the kick which made this chain available occurred
+    start: Option<Instant>,
     read_stat: ChainStat,
     write_stat: ChainStat,
     bufs: Vec<ChainBuf>,
@@ -467,6 +694,7 @@ impl Chain {
         assert!(size <= u16::MAX as usize);
         Self {
             idx: None,
+            start: None,
             read_stat: Default::default(),
             write_stat: Default::default(),
             bufs: Vec::with_capacity(size),
@@ -484,6 +712,7 @@ impl Chain {
     }
     fn reset(&mut self) {
         self.idx = None;
+        self.start = None;
         self.read_stat = Default::default();
         self.write_stat = Default::default();
         self.bufs.clear();
@@ -699,15 +928,35 @@ pub struct VirtQueues {
     queues: Vec<Arc<VirtQueue>>,
     size: NonZeroU16,
     num: NonZeroU16,
+    _timer: Option<ModerationTimer>,
 }
 impl VirtQueues {
     pub fn new(size: NonZeroU16, num: NonZeroU16) -> Self {
+        Self::with_moderation(size, num, None)
+    }
+    /// Create queues whose interrupts are subject to `moderation`, if any.
+    pub fn with_moderation(
+        size: NonZeroU16,
+        num: NonZeroU16,
+        moderation: Option<IntrModeration>,
+    ) -> Self {
         assert!(size.get().is_power_of_two());
+        let timer = moderation.map(|_| Arc::new(TimerShared::default()));
         let mut queues = Vec::with_capacity(size.get() as usize);
         for id in 0..num.get() {
-            queues.push(Arc::new(VirtQueue::new(id, size.get())));
+            let vq_moderation = moderation.map(|limits| VqModeration {
+                limits,
+                timer: timer.clone().unwrap(),
+            });
+            queues.push(Arc::new(VirtQueue::with_moderation(
+                id,
+                size.get(),
+                vq_moderation,
+            )));
         }
-        Self { queues, size, num }
+        let _timer =
+            timer.map(|shared| ModerationTimer::spawn(shared, &queues));
+        Self { queues, size, num, _timer }
     }
     pub fn queue_size(&self) -> NonZeroU16 {
         self.size
@@ -731,6 +980,85 @@ impl<S: SliceIndex<[Arc<VirtQueue>]>> Index<S> for VirtQueues {
     }
 }
 
+#[derive(Default)]
+struct TimerState {
+    /// A queue has begun deferring an interrupt, whose deadline the timer has
+    /// yet to consider
+    woken: bool,
+    shutdown: bool,
+}
+
+/// State shared between a set of moderated queues and their [ModerationTimer]
+#[derive(Default)]
+struct TimerShared {
+    state: Mutex<TimerState>,
+    cv: Condvar,
+}
+impl TimerShared {
+    fn wake(&self) {
+        self.state.lock().unwrap().woken = true;
+        self.cv.notify_one();
+    }
+}
+
+/// Delivers the deferred interrupts of a set of queues once their delays
+/// expire, for when no further entries in the used ring arrive to carry them.
+struct ModerationTimer {
+    shared: Arc<TimerShared>,
+    hdl: Option<JoinHandle<()>>,
+}
+impl ModerationTimer {
+    fn spawn(shared: Arc<TimerShared>, queues: &[Arc<VirtQueue>]) -> Self {
+        // The queues are held weakly, as they refer to the shared state
+        let queues: Vec<Weak<VirtQueue>> =
+            queues.iter().map(Arc::downgrade).collect();
+        let thread_shared = shared.clone();
+        let hdl = std::thread::Builder::new()
+            .name("virtio intr moderation".to_string())
+            .spawn(move || Self::run(&thread_shared, &queues))
+            .expect("moderation thread should spawn");
+        Self { shared, hdl: Some(hdl) }
+    }
+
+    fn run(shared: &TimerShared, queues: &[Weak<VirtQueue>]) {
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return;
+            }
+            state.woken = false;
+            drop(state);
+
+            let now = Instant::now();
+            let next = queues
+                .iter()
+                .filter_map(|vq| vq.upgrade()?.flush_deferred(now))
+                .min();
+
+            state = shared.state.lock().unwrap();
+            if state.woken || state.shutdown {
+                continue;
+            }
+            state = match next {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    shared.cv.wait_timeout(state, timeout).unwrap().0
+                }
+                None => shared.cv.wait(state).unwrap(),
+            };
+        }
+    }
+}
+impl Drop for ModerationTimer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.cv.notify_one();
+        if let Some(hdl) = self.hdl.take() {
+            let _ = hdl.join();
+        }
+    }
+}
+
 pub mod migrate {
     use serde::{Deserialize, Serialize};
 
@@ -750,7 +1078,26 @@ pub mod migrate {
     }
 }
 
-#[cfg(feature = "falcon")]
+/// Copy from the remaining readable buffers in `chain` into `buf`, returning
+/// the number of bytes copied.
+pub(crate) fn read_buf(
+    mem: &MemCtx,
+    chain: &mut Chain,
+    buf: &mut [u8],
+) -> usize {
+    let mut done = 0;
+    chain.for_remaining_type(true, |addr, len| {
+        let remain = &mut buf[done..];
+        if let Some(copied) = mem.read_into(addr, remain, len) {
+            let need_more = copied != remain.len();
+            done += copied;
+            (copied, need_more)
+        } else {
+            (0, false)
+        }
+    })
+}
+
 pub(crate) fn write_buf(buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
     // more copy pasta from Chain::write b/c like Chain:read a
     // statically sized type is expected.
@@ -768,3 +1115,88 @@ pub(crate) fn write_buf(buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
         }
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmm::Machine;
+
+    const QUEUE_SIZE: u16 = 16;
+
+    /// Rings of two queues (each taking 8k in the legacy layout) and a data
+    /// buffer, all in the test machine's RAM
+    const RING_A: u64 = 0x10_0000;
+    const RING_B: u64 = 0x10_2000;
+    const DATA_BASE: u64 = 0x10_4000;
+
+    /// Counts the interrupts sent for a queue
+    struct TestIntr(Arc<AtomicU64>);
+    impl VirtioIntr for TestIntr {
+        fn notify(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+        fn read(&self) -> VqIntr {
+            VqIntr::Pin
+        }
+    }
+
+    /// Make a buffer of `len` bytes, which the device may write to only if
+    /// it is `writable`, available as the chain in slot `idx` of the ring at
+    /// `ring`
+    fn post(mem: &MemCtx, ring: u64, idx: u16, len: u32, writable: bool) {
+        let flags = match writable {
+            true => DescFlag::WRITE.bits(),
+            false => 0,
+        };
+        let desc = VqdDesc { addr: DATA_BASE, len, flags, next: 0 };
+        mem.write(GuestAddr(ring).offset::<VqdDesc>(idx as usize), &desc);
+
+        let avail =
+            ring + (mem::size_of::<VqdDesc>() * QUEUE_SIZE as usize) as u64;
+        mem.write(GuestAddr(avail + 4).offset::<u16>(idx as usize), &idx);
+        mem.write(GuestAddr(avail + 2), &(idx + 1));
+    }
+
+    #[test]
+    fn stats_kept_per_queue() {
+        let machine = Machine::new_test().unwrap();
+        let mem = machine.acc_mem.access().unwrap();
+        let vq_a = VirtQueue::new(0, QUEUE_SIZE);
+        let vq_b = VirtQueue::new(1, QUEUE_SIZE);
+        vq_a.map_legacy(RING_A);
+        vq_b.map_legacy(RING_B);
+        let intrs = Arc::new(AtomicU64::new(0));
+        vq_a.set_intr(Box::new(TestIntr(intrs.clone())));
+
+        post(&mem, RING_A, 0, 64, false);
+        post(&mem, RING_A, 1, 128, true);
+        vq_a.kicked();
+        let mut chain = Chain::with_capacity(1);
+        while vq_a.pop_avail(&mut chain, &mem).is_some() {
+            vq_a.push_used(&mut chain, &mem);
+        }
+
+        let stats = vq_a.stats();
+        assert_eq!(stats.queue_id, 0);
+        assert_eq!(stats.kicks, 1);
+        assert_eq!(stats.chains, 2);
+        assert_eq!(stats.descriptors, 2);
+        assert_eq!(stats.bytes, 64 + 128);
+        assert_eq!(stats.interrupts, 2);
+        assert_eq!(intrs.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.latency.total(), 2);
+
+        // The counters outlive a reset of the queue.
+        vq_a.reset();
+        assert_eq!(vq_a.stats().chains, 2);
+
+        // Nothing done with one queue is counted against another.
+        let stats = vq_b.stats();
+        assert_eq!(stats.queue_id, 1);
+        assert_eq!(
+            [stats.kicks, stats.interrupts, stats.chains, stats.bytes],
+            [0; 4]
+        );
+        assert_eq!(stats.latency.total(), 0);
+    }
+}
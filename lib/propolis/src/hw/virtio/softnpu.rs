@@ -24,7 +24,7 @@ use crate::{
 use super::{
     bits::*,
     pci::{PciVirtio, PciVirtioState},
-    queue::{write_buf, Chain, VirtQueue, VirtQueues},
+    queue::{read_buf, write_buf, Chain, VirtQueue, VirtQueues},
     viona::bits::VIRTIO_NET_S_LINK_UP,
     VirtioDevice,
 };
@@ -648,21 +648,6 @@ mod bits {
 }
 use bits::*;
 
-// helper functions to read/write a buffer from/to a guest
-fn read_buf(mem: &MemCtx, chain: &mut Chain, buf: &mut [u8]) -> usize {
-    let mut done = 0;
-    chain.for_remaining_type(true, |addr, len| {
-        let remain = &mut buf[done..];
-        if let Some(copied) = mem.read_into(addr, remain, len) {
-            let need_more = copied != remain.len();
-            done += copied;
-            (copied, need_more)
-        } else {
-            (0, false)
-        }
-    })
-}
-
 /// Handle ASIC management messages from the guest using the loaded program.
 fn handle_management_message(
     msg: ManagementRequest,
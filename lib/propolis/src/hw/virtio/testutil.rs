@@ -0,0 +1,71 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for device tests which act as the guest, driving legacy virtqueues
+//! of [`QUEUE_SIZE`] entries, each mapped in a 4k-aligned 8k region.
+
+use std::time::{Duration, Instant};
+
+use crate::common::GuestAddr;
+use crate::vmm::MemCtx;
+
+pub(super) const QUEUE_SIZE: u16 = 16;
+
+const DESC_F_WRITE: u16 = 1 << 1;
+
+/// Place a single-descriptor chain in slot `idx` of the legacy ring at
+/// `ring`, and make it available.  The device may write to the buffer only if
+/// it is `writable`.
+pub(super) fn post_buf(
+    mem: &MemCtx,
+    ring: u64,
+    idx: u16,
+    addr: u64,
+    len: u32,
+    writable: bool,
+) {
+    let desc = ring + 16 * u64::from(idx);
+    mem.write(GuestAddr(desc), &addr);
+    mem.write(GuestAddr(desc + 8), &len);
+    mem.write(GuestAddr(desc + 12), &if writable { DESC_F_WRITE } else { 0 });
+    mem.write(GuestAddr(desc + 14), &0u16);
+
+    let avail = ring + 16 * u64::from(QUEUE_SIZE);
+    mem.write(GuestAddr(avail + 4 + 2 * u64::from(idx)), &idx);
+    mem.write(GuestAddr(avail + 2), &(idx + 1));
+}
+
+/// The address of the used ring of the legacy ring at `ring`.
+fn used_ring(ring: u64) -> u64 {
+    // With 16 entries, the used ring follows at the next 4k boundary
+    ring + 0x1000
+}
+
+/// Get the number of entries the device has placed in the used ring of the
+/// legacy ring at `ring`.
+pub(super) fn used_idx(mem: &MemCtx, ring: u64) -> u16 {
+    mem.read(GuestAddr(used_ring(ring) + 2)).unwrap()
+}
+
+/// Get the length recorded in slot `idx` of the used ring of the legacy ring
+/// at `ring`, if it has been populated.
+pub(super) fn used_len(mem: &MemCtx, ring: u64, idx: u16) -> Option<u32> {
+    if used_idx(mem, ring) <= idx {
+        return None;
+    }
+    mem.read(GuestAddr(used_ring(ring) + 4 + 8 * u64::from(idx) + 4))
+}
+
+/// Wait for slot `idx` of the used ring of the legacy ring at `ring` to be
+/// populated, returning the length recorded in it.
+pub(super) fn wait_used(mem: &MemCtx, ring: u64, idx: u16) -> u32 {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Some(len) = used_len(mem, ring, idx) {
+            return len;
+        }
+        assert!(Instant::now() < deadline, "buffer not used");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -4,22 +4,23 @@
 
 #![cfg_attr(not(target_os = "illumos"), allow(dead_code, unused_imports))]
 
+use std::collections::BTreeSet;
 use std::io::{self, Error, ErrorKind};
 use std::num::NonZeroU16;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, Weak};
 
 use crate::common::*;
 use crate::hw::pci;
 use crate::migrate::*;
 use crate::util::regmap::RegMap;
-use crate::vmm::VmmHdl;
+use crate::vmm::{MemCtx, VmmHdl};
 
 use super::bits::*;
 use super::pci::{PciVirtio, PciVirtioState};
-use super::queue::{self, VirtQueue, VirtQueues};
-use super::{VirtioDevice, VqChange, VqIntr};
+use super::queue::{self, Chain, VirtQueue, VirtQueues};
+use super::{NetLink, VirtioDevice, VqChange, VqIntr};
 
 use lazy_static::lazy_static;
 use tokio::io::unix::AsyncFd;
@@ -70,11 +71,40 @@ enum VRingState {
 
 struct Inner {
     poller: Option<PollerHdl>,
-    vring_state: [VRingState; 2],
+    vring_state: Vec<VRingState>,
+
+    /// Index of the control queue, if the driver negotiated one.  Its position
+    /// depends on whether multi-queue operation was also negotiated.
+    ctrl_vq: Option<u16>,
+
+    /// Number of TX/RX queue pairs the driver has asked to use
+    use_pairs: u16,
+
+    /// Receive filtering configured by the driver
+    rx_filter: RxFilter,
 }
 impl Inner {
-    fn new() -> Self {
-        Self { poller: None, vring_state: [Default::default(); 2] }
+    fn new(queue_pairs: u16) -> Self {
+        Self {
+            poller: None,
+            vring_state: vec![Default::default(); queue_pairs as usize * 2],
+            ctrl_vq: None,
+            use_pairs: 1,
+            rx_filter: RxFilter::new(0),
+        }
+    }
+
+    /// Is the given VirtQueue the control queue (and thus emulated here,
+    /// rather than by viona)?
+    fn is_ctrl(&self, vq: &VirtQueue) -> bool {
+        self.ctrl_vq == Some(vq.id)
+    }
+
+    /// Is the given VirtQueue backed by a viona vring?  The control queue is
+    /// not, nor is the queue which would have been the control queue had the
+    /// driver negotiated it.
+    fn is_vring(&self, vq: &VirtQueue) -> bool {
+        !self.is_ctrl(vq) && (vq.id as usize) < self.vring_state.len()
     }
 
     /// Get the `VRingState` for a given VirtQueue
@@ -86,6 +116,127 @@ impl Inner {
     }
 }
 
+/// Offloads which a [PciVirtioViona] may offer its guest, where viona itself
+/// supports them.  All are enabled by default.
+///
+/// Disabling one also disables those which depend upon it: TSO requires TX
+/// checksum offload, and LRO requires RX checksum offload.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NetOffloads {
+    /// Guest may leave the checksums of the packets it sends to the device
+    pub tx_checksum: bool,
+    /// Guest may send TCP segments larger than the MTU for the device to split
+    pub tso: bool,
+    /// Device may deliver packets with unfinished (but verified) checksums
+    pub rx_checksum: bool,
+    /// Device may deliver coalesced TCP segments larger than the MTU
+    pub lro: bool,
+}
+impl Default for NetOffloads {
+    fn default() -> Self {
+        Self { tx_checksum: true, tso: true, rx_checksum: true, lro: true }
+    }
+}
+impl NetOffloads {
+    /// Remove the features for any disabled offloads from `feat`.
+    fn mask(&self, mut feat: u32) -> u32 {
+        const HOST_TSO: u32 = VIRTIO_NET_F_HOST_TSO4
+            | VIRTIO_NET_F_HOST_TSO6
+            | VIRTIO_NET_F_HOST_ECN
+            | VIRTIO_NET_F_HOST_UFO;
+        const GUEST_TSO: u32 = VIRTIO_NET_F_GUEST_TSO4
+            | VIRTIO_NET_F_GUEST_TSO6
+            | VIRTIO_NET_F_GUEST_ECN
+            | VIRTIO_NET_F_GUEST_UFO;
+
+        if !self.tx_checksum {
+            feat &= !(VIRTIO_NET_F_CSUM | HOST_TSO);
+        }
+        if !self.tso {
+            feat &= !HOST_TSO;
+        }
+        if !self.rx_checksum {
+            feat &= !(VIRTIO_NET_F_GUEST_CSUM
+                | VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+                | GUEST_TSO);
+        }
+        if !self.lro {
+            feat &= !GUEST_TSO;
+        }
+        feat
+    }
+}
+
+/// Receive filtering requested by the driver through the control queue, which
+/// viona applies to the traffic it delivers to the guest.
+///
+/// Viona's MAC filter has room for a limited number of addresses.  Should the
+/// driver ask for more than that, the filter falls back to the promiscuity
+/// which covers them, as the virtio spec permits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RxFilter {
+    promisc: bool,
+    allmulti: bool,
+    unicast: Vec<[u8; ETHERADDRL]>,
+    multicast: Vec<[u8; ETHERADDRL]>,
+    /// VLANs the driver wishes to receive, if it negotiated VLAN filtering
+    vlans: Option<BTreeSet<u16>>,
+}
+impl RxFilter {
+    /// The filter in effect before the driver configures one, given the
+    /// negotiated features `feat`.  This matches viona's own default of
+    /// accepting all multicast traffic.
+    fn new(feat: u32) -> Self {
+        Self {
+            promisc: false,
+            allmulti: true,
+            unicast: Vec::new(),
+            multicast: Vec::new(),
+            vlans: (feat & VIRTIO_NET_F_CTRL_VLAN != 0).then(BTreeSet::new),
+        }
+    }
+
+    fn promisc_mode(&self) -> u32 {
+        let max = viona_api::VIONA_MAC_FILTER_MAX as usize;
+        if self.promisc || self.unicast.len() > max {
+            viona_api::VIONA_PROMISC_ALL
+        } else if self.allmulti
+            || self.unicast.len() + self.multicast.len() > max
+        {
+            viona_api::VIONA_PROMISC_MULTI
+        } else {
+            viona_api::VIONA_PROMISC_NONE
+        }
+    }
+
+    /// The unicast and multicast addresses which fit in viona's MAC filter.
+    /// Any which do not are covered by [RxFilter::promisc_mode] instead.
+    fn mac_tables(&self) -> (&[[u8; ETHERADDRL]], &[[u8; ETHERADDRL]]) {
+        let max = viona_api::VIONA_MAC_FILTER_MAX as usize;
+        if self.unicast.len() > max {
+            (&[], &[])
+        } else if self.unicast.len() + self.multicast.len() > max {
+            (&self.unicast, &[])
+        } else {
+            (&self.unicast, &self.multicast)
+        }
+    }
+
+    /// Bitmap of the VLANs to be received.  All are if VLAN filtering was not
+    /// negotiated.
+    fn vlan_bitmap(&self) -> [u32; VLAN_BITMAP_WORDS] {
+        let Some(vlans) = self.vlans.as_ref() else {
+            return [u32::MAX; VLAN_BITMAP_WORDS];
+        };
+        let mut bitmap = [0; VLAN_BITMAP_WORDS];
+        for vid in vlans.iter().map(|vid| *vid as usize) {
+            bitmap[vid / 32] |= 1 << (vid % 32);
+        }
+        bitmap
+    }
+}
+const VLAN_BITMAP_WORDS: usize = viona_api::VIONA_VLAN_MAX as usize / 32;
+
 /// Represents a connection to the kernel's Viona (VirtIO Network Adapter)
 /// driver.
 pub struct PciVirtioViona {
@@ -95,24 +246,78 @@ pub struct PciVirtioViona {
     dev_features: u32,
     mac_addr: [u8; ETHERADDRL],
     mtu: Option<u16>,
+    queue_pairs: u16,
+    rx_filter: bool,
     hdl: VionaHdl,
     inner: Mutex<Inner>,
+
+    /// Link state reported to the guest.  This is purely advisory: viona
+    /// carries traffic regardless.
+    link_up: AtomicBool,
+
+    /// Name of the VNIC carrying the device's traffic
+    vnic_name: String,
+    /// VLAN-tagged VNIC created for the device, if any, which is deleted when
+    /// the device halts
+    vlan: Mutex<Option<dladm::VlanVnic>>,
 }
 impl PciVirtioViona {
+    /// Create a virtio-net device, backed by viona, atop the vNIC `vnic_name`.
+    ///
+    /// If `vlan_id` is given, the device instead sits atop a VNIC created to
+    /// carry that VLAN, over the same link and with the same MAC address as
+    /// `vnic_name`.  Viona itself is unaware of the tagging.
+    ///
+    /// When `queue_pairs` is greater than one (up to
+    /// [`viona_api::VIONA_MAX_QPAIRS`]), the device offers multi-queue
+    /// operation to the guest, allowing it to spread its network traffic across
+    /// that many TX/RX queue pairs.  This requires a viona new enough to
+    /// support it.
+    ///
+    /// Of the offloads viona supports, only those in `offloads` are offered.
+    ///
+    /// Where viona supports receive filtering, the guest is offered a control
+    /// queue through which to configure it.
     pub fn new(
         vnic_name: &str,
+        vlan_id: Option<u16>,
         queue_size: u16,
+        queue_pairs: u16,
+        offloads: NetOffloads,
         vm: &VmmHdl,
     ) -> io::Result<Arc<PciVirtioViona>> {
         let dlhdl = dladm::Handle::new()?;
-        let info = dlhdl.query_vnic(vnic_name)?;
+        let vlan = vlan_id
+            .map(|vid| dladm::VlanVnic::create(vnic_name, vid))
+            .transpose()?;
+        let vnic_name =
+            vlan.as_ref().map_or(vnic_name, |v| v.name()).to_owned();
+        let info = dlhdl.query_vnic(&vnic_name)?;
         let hdl = VionaHdl::new(info.link_id, vm.fd())?;
 
-        // TX and RX
-        let queue_count = NonZeroU16::new(2).unwrap();
-        // interrupts for TX, RX, and device config
-        let msix_count = Some(3);
-        let dev_features = hdl.get_avail_features()?;
+        let api_version = hdl.api_version()?;
+        let queue_pairs = queue_pairs.clamp(1, viona_api::VIONA_MAX_QPAIRS);
+        if queue_pairs > 1 {
+            if api_version < viona_api::ApiVersion::V3 {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "viona does not support multiple queue pairs",
+                ));
+            }
+            hdl.set_pairs(queue_pairs)?;
+        }
+
+        // TX and RX for each pair, plus a control queue if there is more than
+        // one pair for the guest to choose between, or filtering to configure
+        let rx_filter = api_version >= viona_api::ApiVersion::V4;
+        let queue_count = match (queue_pairs, rx_filter) {
+            (1, false) => 2,
+            (n, _) => n * 2 + 1,
+        };
+        // interrupts for each queue, and device config
+        let msix_count = Some(queue_count + 1);
+        let queue_count = NonZeroU16::new(queue_count).unwrap();
+        let dev_features = offloads.mask(hdl.get_avail_features()?);
 
         let queues =
             VirtQueues::new(NonZeroU16::new(queue_size).unwrap(), queue_count);
@@ -132,8 +337,13 @@ impl PciVirtioViona {
             dev_features,
             mac_addr: [0; ETHERADDRL],
             mtu: info.mtu,
+            queue_pairs,
+            rx_filter,
             hdl,
-            inner: Mutex::new(Inner::new()),
+            inner: Mutex::new(Inner::new(queue_pairs)),
+            link_up: AtomicBool::new(true),
+            vnic_name,
+            vlan: Mutex::new(vlan),
         };
         this.mac_addr.copy_from_slice(&info.mac_addr);
         let this = Arc::new(this);
@@ -147,10 +357,16 @@ impl PciVirtioViona {
         Ok(this)
     }
 
+    /// Name of the VNIC carrying the device's traffic.  This differs from the
+    /// one it was created with if the device was given a VLAN.
+    pub fn vnic_name(&self) -> &str {
+        &self.vnic_name
+    }
+
     fn process_interrupts(&self) {
         if let Some(mem) = self.pci_state.acc_mem.access() {
             self.hdl
-                .intr_poll(|vq_idx| {
+                .intr_poll(self.queue_pairs * 2, |vq_idx| {
                     self.hdl.ring_intr_clear(vq_idx).unwrap();
                     self.virtio_state.queues[vq_idx as usize].send_intr(&mem);
                 })
@@ -162,12 +378,14 @@ impl PciVirtioViona {
         match id {
             NetReg::Mac => ro.write_bytes(&self.mac_addr),
             NetReg::Status => {
-                // Always report link up
-                ro.write_u16(VIRTIO_NET_S_LINK_UP);
+                if self.link_up() {
+                    ro.write_u16(VIRTIO_NET_S_LINK_UP);
+                } else {
+                    ro.write_u16(0);
+                }
             }
             NetReg::MaxVqPairs => {
-                // hard-wired to single vq pair for now
-                ro.write_u16(1);
+                ro.write_u16(self.queue_pairs);
             }
             NetReg::Mtu => {
                 // Guests should not be asking for this value unless
@@ -178,12 +396,125 @@ impl PciVirtioViona {
         }
     }
 
+    /// Index of the control queue, if any, given negotiated features `feat`
+    fn ctrl_vq_index(&self, feat: u32) -> Option<u16> {
+        match (feat & VIRTIO_NET_F_CTRL_VQ != 0, feat & VIRTIO_NET_F_MQ != 0) {
+            (false, _) => None,
+            (true, false) => Some(2),
+            (true, true) => Some(self.queue_pairs * 2),
+        }
+    }
+
+    /// Process any commands the driver has placed in the control queue.
+    fn ctrl_queue_notify(&self, vq: &VirtQueue) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(4);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            let ack = match self.ctrl_cmd(&mut chain, &mem) {
+                Ok(()) => VIRTIO_NET_OK,
+                Err(()) => VIRTIO_NET_ERR,
+            };
+            chain.write(&ack, &mem);
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+
+    fn ctrl_cmd(&self, chain: &mut Chain, mem: &MemCtx) -> Result<(), ()> {
+        let mut hdr = CtrlHdr::default();
+        if !chain.read(&mut hdr, mem) {
+            return Err(());
+        }
+        match (hdr.class, hdr.cmd) {
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                let mut pairs = 0u16;
+                if !chain.read(&mut pairs, mem)
+                    || pairs == 0
+                    || pairs > self.queue_pairs
+                {
+                    return Err(());
+                }
+                let mut inner = self.inner.lock().unwrap();
+                self.hdl.set_usepairs(pairs).map_err(|_| ())?;
+                inner.use_pairs = pairs;
+                Ok(())
+            }
+            (VIRTIO_NET_CTRL_RX, cmd) if self.ctrl_rx_negotiated() => {
+                let mut on = 0u8;
+                if !chain.read(&mut on, mem) {
+                    return Err(());
+                }
+                let mut inner = self.inner.lock().unwrap();
+                let filter = &mut inner.rx_filter;
+                match cmd {
+                    VIRTIO_NET_CTRL_RX_PROMISC => filter.promisc = on != 0,
+                    VIRTIO_NET_CTRL_RX_ALLMULTI => filter.allmulti = on != 0,
+                    _ => return Err(()),
+                }
+                self.hdl.set_promisc(filter.promisc_mode()).map_err(|_| ())
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET)
+                if self.ctrl_rx_negotiated() =>
+            {
+                let unicast = read_mac_table(chain, mem)?;
+                let multicast = read_mac_table(chain, mem)?;
+                let mut inner = self.inner.lock().unwrap();
+                let filter = &mut inner.rx_filter;
+                filter.unicast = unicast;
+                filter.multicast = multicast;
+                self.set_mac_filter(filter).map_err(|_| ())
+            }
+            (VIRTIO_NET_CTRL_VLAN, cmd) => {
+                let mut vid = 0u16;
+                if !chain.read(&mut vid, mem)
+                    || vid >= viona_api::VIONA_VLAN_MAX
+                {
+                    return Err(());
+                }
+                let mut inner = self.inner.lock().unwrap();
+                let Some(vlans) = inner.rx_filter.vlans.as_mut() else {
+                    return Err(());
+                };
+                match cmd {
+                    VIRTIO_NET_CTRL_VLAN_ADD => vlans.insert(vid),
+                    VIRTIO_NET_CTRL_VLAN_DEL => vlans.remove(&vid),
+                    _ => return Err(()),
+                };
+                let bitmap = inner.rx_filter.vlan_bitmap();
+                self.hdl.set_vlan_filter(&bitmap).map_err(|_| ())
+            }
+            // No other classes of command are offered to the guest
+            _ => Err(()),
+        }
+    }
+
+    fn ctrl_rx_negotiated(&self) -> bool {
+        self.virtio_state.negotiated_features() & VIRTIO_NET_F_CTRL_RX != 0
+    }
+
+    /// Push the MAC filter, and the promiscuity which goes with it, to viona.
+    fn set_mac_filter(&self, filter: &RxFilter) -> io::Result<()> {
+        let (unicast, multicast) = filter.mac_tables();
+        self.hdl.set_mac_filter(unicast, multicast)?;
+        self.hdl.set_promisc(filter.promisc_mode())
+    }
+
+    /// Push the entirety of the receive filter to viona.
+    fn set_rx_filter(&self, filter: &RxFilter) -> io::Result<()> {
+        if !self.rx_filter {
+            return Ok(());
+        }
+        self.set_mac_filter(filter)?;
+        self.hdl.set_vlan_filter(&filter.vlan_bitmap())
+    }
+
     /// Pause the associated virtqueues and sync any in-kernel state for them
     /// into the userspace representation.
     fn queues_sync(&self) {
         let mut inner = self.inner.lock().unwrap();
         for vq in self.virtio_state.queues.iter() {
-            if !vq.live.load(Ordering::Acquire) {
+            if !vq.live.load(Ordering::Acquire) || !inner.is_vring(vq) {
                 continue;
             }
 
@@ -225,7 +556,15 @@ impl PciVirtioViona {
     fn queues_restart(&self) -> Result<(), ()> {
         let mut inner = self.inner.lock().unwrap();
         let mut res = Ok(());
+        if self.queue_pairs > 1
+            && self.hdl.set_usepairs(inner.use_pairs).is_err()
+        {
+            return Err(());
+        }
         for vq in self.virtio_state.queues.iter() {
+            if !inner.is_vring(vq) {
+                continue;
+            }
             let rs = inner.for_vq(vq);
 
             // The existing state machine for vrings in Viona does not allow for
@@ -273,6 +612,9 @@ impl PciVirtioViona {
     fn queues_kill(&self) {
         let mut inner = self.inner.lock().unwrap();
         for vq in self.virtio_state.queues.iter() {
+            if !inner.is_vring(vq) {
+                continue;
+            }
             let rs = inner.for_vq(vq);
             match *rs {
                 VRingState::Init => {
@@ -323,7 +665,7 @@ impl VirtioDevice for PciVirtioViona {
         });
     }
     fn get_features(&self) -> u32 {
-        let mut feat = VIRTIO_NET_F_MAC;
+        let mut feat = VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS;
         // We drop the "VIRTIO_NET_F_MTU" flag from feat if we are unable to
         // query it. This can happen when executing within a non-global Zone.
         //
@@ -331,16 +673,51 @@ impl VirtioDevice for PciVirtioViona {
         if self.mtu.is_some() {
             feat |= VIRTIO_NET_F_MTU;
         }
+        if self.queue_pairs > 1 {
+            feat |= VIRTIO_NET_F_CTRL_VQ | VIRTIO_NET_F_MQ;
+        }
+        if self.rx_filter {
+            feat |= VIRTIO_NET_F_CTRL_VQ
+                | VIRTIO_NET_F_CTRL_RX
+                | VIRTIO_NET_F_CTRL_VLAN;
+        }
         feat |= self.dev_features;
 
         feat
     }
     fn set_features(&self, feat: u32) -> Result<(), ()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ctrl_vq = self.ctrl_vq_index(feat);
+        // Until the driver says otherwise, only the first pair is used.
+        inner.use_pairs = 1;
+        if self.queue_pairs > 1 {
+            self.hdl.set_usepairs(1).map_err(|_| ())?;
+        }
+        inner.rx_filter = RxFilter::new(feat);
+        self.set_rx_filter(&inner.rx_filter).map_err(|_| ())?;
+
+        // The control queue is emulated here, so viona is left unaware of it
+        // and of the features configured through it.  The same goes for the
+        // link status.
+        let feat = feat
+            & !(VIRTIO_NET_F_CTRL_VQ
+                | VIRTIO_NET_F_MQ
+                | VIRTIO_NET_F_CTRL_RX
+                | VIRTIO_NET_F_CTRL_VLAN
+                | VIRTIO_NET_F_STATUS);
         self.hdl.set_features(feat).map_err(|_| ())
     }
 
     fn queue_notify(&self, vq: &Arc<VirtQueue>) {
         let mut inner = self.inner.lock().unwrap();
+        if inner.is_ctrl(vq) {
+            drop(inner);
+            self.ctrl_queue_notify(vq);
+            return;
+        }
+        if !inner.is_vring(vq) {
+            return;
+        }
         let rs = inner.for_vq(vq);
         match rs {
             VRingState::Ready | VRingState::Run => {
@@ -359,6 +736,9 @@ impl VirtioDevice for PciVirtioViona {
         change: VqChange,
     ) -> Result<(), ()> {
         let mut inner = self.inner.lock().unwrap();
+        if !inner.is_vring(vq) {
+            return Ok(());
+        }
         let rs = inner.for_vq(vq);
 
         match change {
@@ -452,12 +832,25 @@ impl Lifecycle for PciVirtioViona {
         // destruction.
         self.queues_kill();
         let _ = self.hdl.delete();
+        // With viona released, any VLAN VNIC can go too.
+        drop(self.vlan.lock().unwrap().take());
     }
     fn migrate(&self) -> Migrator {
         Migrator::Multi(self)
     }
 }
 
+impl NetLink for PciVirtioViona {
+    fn set_link_up(&self, up: bool) {
+        if self.link_up.swap(up, Ordering::SeqCst) != up {
+            self.virtio_state.config_change(&self.pci_state);
+        }
+    }
+    fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::SeqCst)
+    }
+}
+
 impl PciVirtio for PciVirtioViona {
     fn virtio_state(&self) -> &PciVirtioState {
         &self.virtio_state
@@ -473,6 +866,29 @@ impl MigrateMulti for PciVirtioViona {
         output: &mut PayloadOutputs,
         ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError> {
+        if self.queue_pairs > 1 {
+            let inner = self.inner.lock().unwrap();
+            output.push(
+                migrate::VionaMqV1 { use_pairs: inner.use_pairs }.into(),
+            )?;
+        }
+        if self.rx_filter {
+            let inner = self.inner.lock().unwrap();
+            let filter = &inner.rx_filter;
+            output.push(
+                migrate::VionaRxFilterV1 {
+                    promisc: filter.promisc,
+                    allmulti: filter.allmulti,
+                    unicast: filter.unicast.clone(),
+                    multicast: filter.multicast.clone(),
+                    vlans: filter
+                        .vlans
+                        .as_ref()
+                        .map(|vlans| vlans.iter().copied().collect()),
+                }
+                .into(),
+            )?;
+        }
         <dyn PciVirtio>::export(self, output, ctx)
     }
 
@@ -481,8 +897,92 @@ impl MigrateMulti for PciVirtioViona {
         offer: &mut PayloadOffers,
         ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError> {
-        <dyn PciVirtio>::import(self, offer, ctx)
+        if self.queue_pairs > 1 {
+            let input: migrate::VionaMqV1 = offer.take()?;
+            if input.use_pairs == 0 || input.use_pairs > self.queue_pairs {
+                return Err(MigrateStateError::ImportFailed(format!(
+                    "viona: {} queue pairs in use, of {} available",
+                    input.use_pairs, self.queue_pairs
+                )));
+            }
+            self.inner.lock().unwrap().use_pairs = input.use_pairs;
+        }
+        let rx_filter = if self.rx_filter {
+            let input: migrate::VionaRxFilterV1 = offer.take()?;
+            if input
+                .vlans
+                .iter()
+                .flatten()
+                .any(|vid| *vid >= viona_api::VIONA_VLAN_MAX)
+            {
+                return Err(MigrateStateError::ImportFailed(
+                    "viona: VLAN filter ID out of range".to_string(),
+                ));
+            }
+            Some(RxFilter {
+                promisc: input.promisc,
+                allmulti: input.allmulti,
+                unicast: input.unicast,
+                multicast: input.multicast,
+                vlans: input.vlans.map(|vlans| vlans.into_iter().collect()),
+            })
+        } else {
+            None
+        };
+        <dyn PciVirtio>::import(self, offer, ctx)?;
+
+        let feat = self.virtio_state.negotiated_features();
+        let mut inner = self.inner.lock().unwrap();
+        inner.ctrl_vq = self.ctrl_vq_index(feat);
+        if let Some(filter) = rx_filter {
+            self.set_rx_filter(&filter).map_err(|e| {
+                MigrateStateError::ImportFailed(format!(
+                    "viona: failed to set receive filter: {e}"
+                ))
+            })?;
+            inner.rx_filter = filter;
+        }
+        Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        if self.queue_pairs > 1 {
+            schemas.add::<migrate::VionaMqV1>();
+        }
+        if self.rx_filter {
+            schemas.add::<migrate::VionaRxFilterV1>();
+        }
+        <dyn PciVirtio>::schemas(self, schemas)
+    }
+}
+
+/// Header of a command in the control queue
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct CtrlHdr {
+    class: u8,
+    cmd: u8,
+}
+
+/// Read one of the MAC address tables of a `VIRTIO_NET_CTRL_MAC_TABLE_SET`
+/// command: a count of entries, followed by the addresses themselves.
+fn read_mac_table(
+    chain: &mut Chain,
+    mem: &MemCtx,
+) -> Result<Vec<[u8; ETHERADDRL]>, ()> {
+    let mut entries = 0u32;
+    if !chain.read(&mut entries, mem) {
+        return Err(());
+    }
+    let mut table = Vec::new();
+    for _ in 0..entries {
+        let mut addr = [0u8; ETHERADDRL];
+        if !chain.read(&mut addr, mem) {
+            return Err(());
+        }
+        table.push(addr);
+    }
+    Ok(table)
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -610,7 +1110,12 @@ impl VionaHdl {
         }
         Ok(())
     }
-    fn intr_poll(&self, mut f: impl FnMut(u16)) -> io::Result<()> {
+    /// Call `f` with the index of each of the first `nrings` rings with an
+    /// interrupt pending.
+    fn intr_poll(&self, nrings: u16, mut f: impl FnMut(u16)) -> io::Result<()> {
+        if nrings > viona_api::VIONA_VQ_MAX {
+            return self.intr_poll_mq(nrings, f);
+        }
         let mut vna_ip = viona_api::vioc_intr_poll {
             vip_status: [0; viona_api::VIONA_VQ_MAX as usize],
         };
@@ -624,10 +1129,96 @@ impl VionaHdl {
         }
         Ok(())
     }
+    fn intr_poll_mq(
+        &self,
+        nrings: u16,
+        mut f: impl FnMut(u16),
+    ) -> io::Result<()> {
+        let mut vna_ip = viona_api::vioc_intr_poll_mq {
+            vipm_nrings: nrings,
+            _pad: [0; 3],
+            vipm_status: [0; (viona_api::VIONA_MAX_QPAIRS as usize * 2) / 32],
+        };
+        unsafe {
+            self.0.ioctl(viona_api::VNA_IOC_INTR_POLL_MQ, &mut vna_ip)?;
+        }
+        for i in 0..nrings {
+            let word = vna_ip.vipm_status[i as usize / 32];
+            if word & (1 << (i % 32)) != 0 {
+                f(i)
+            }
+        }
+        Ok(())
+    }
     fn ring_intr_clear(&self, idx: u16) -> io::Result<()> {
         self.0.ioctl_usize(viona_api::VNA_IOC_RING_INTR_CLR, idx as usize)?;
         Ok(())
     }
+    fn api_version(&self) -> io::Result<u32> {
+        self.0.api_version()
+    }
+    /// Set the number of TX/RX queue pairs the device is to support.
+    fn set_pairs(&self, pairs: u16) -> io::Result<()> {
+        self.0.ioctl_usize(viona_api::VNA_IOC_SET_PAIRS, pairs as usize)?;
+        Ok(())
+    }
+    /// Set the number of TX/RX queue pairs the guest has chosen to use.
+    fn set_usepairs(&self, pairs: u16) -> io::Result<()> {
+        self.0.ioctl_usize(viona_api::VNA_IOC_SET_USEPAIRS, pairs as usize)?;
+        Ok(())
+    }
+    /// Set which traffic is received regardless of the MAC filter.
+    fn set_promisc(&self, mode: u32) -> io::Result<()> {
+        self.0.ioctl_usize(viona_api::VNA_IOC_SET_PROMISC, mode as usize)?;
+        Ok(())
+    }
+    /// Set the addresses, beyond the device's own, from which traffic is
+    /// received.  They must fit within [`viona_api::VIONA_MAC_FILTER_MAX`].
+    fn set_mac_filter(
+        &self,
+        unicast: &[[u8; ETHERADDRL]],
+        multicast: &[[u8; ETHERADDRL]],
+    ) -> io::Result<()> {
+        let mut vna_mac_filter = viona_api::vioc_mac_filter {
+            vmf_nunicast: unicast.len() as u16,
+            vmf_nmulticast: multicast.len() as u16,
+            _pad: [0; 2],
+            vmf_addrs: [[0; ETHERADDRL];
+                viona_api::VIONA_MAC_FILTER_MAX as usize],
+        };
+        assert!(
+            unicast.len() + multicast.len() <= vna_mac_filter.vmf_addrs.len()
+        );
+        for (slot, addr) in vna_mac_filter
+            .vmf_addrs
+            .iter_mut()
+            .zip(unicast.iter().chain(multicast.iter()))
+        {
+            *slot = *addr;
+        }
+        unsafe {
+            self.0.ioctl(
+                viona_api::VNA_IOC_SET_MAC_FILTER,
+                &mut vna_mac_filter,
+            )?;
+        }
+        Ok(())
+    }
+    /// Set the VLANs from which tagged traffic is received.
+    fn set_vlan_filter(
+        &self,
+        bitmap: &[u32; VLAN_BITMAP_WORDS],
+    ) -> io::Result<()> {
+        let mut vna_vlan_filter =
+            viona_api::vioc_vlan_filter { vvf_bitmap: *bitmap };
+        unsafe {
+            self.0.ioctl(
+                viona_api::VNA_IOC_SET_VLAN_FILTER,
+                &mut vna_vlan_filter,
+            )?;
+        }
+        Ok(())
+    }
 }
 impl AsRawFd for VionaHdl {
     fn as_raw_fd(&self) -> RawFd {
@@ -822,15 +1413,65 @@ pub(crate) mod bits {
     pub const VIRTIO_NET_S_ANNOUNCE: u16 = 1 << 1;
 
     pub const VIRTIO_NET_CFG_SIZE: usize = 0xc;
+
+    // Control queue command classes/commands, and their acknowledgements
+    pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+    pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+    pub const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+    pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+    pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+    pub const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+    pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+    pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+    pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
+    pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+    pub const VIRTIO_NET_OK: u8 = 0;
+    pub const VIRTIO_NET_ERR: u8 = 1;
 }
 use bits::*;
 
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    /// State of a viona device offering multiple queue pairs
+    #[derive(Deserialize, Serialize)]
+    pub struct VionaMqV1 {
+        /// Number of queue pairs selected by the driver
+        pub use_pairs: u16,
+    }
+    impl Schema<'_> for VionaMqV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-viona-mq", 1)
+        }
+    }
+
+    /// Receive filtering configured by the driver of a viona device
+    #[derive(Deserialize, Serialize)]
+    pub struct VionaRxFilterV1 {
+        pub promisc: bool,
+        pub allmulti: bool,
+        pub unicast: Vec<[u8; 6]>,
+        pub multicast: Vec<[u8; 6]>,
+        /// VLANs to be received, if VLAN filtering was negotiated
+        pub vlans: Option<Vec<u16>>,
+    }
+    impl Schema<'_> for VionaRxFilterV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-viona-rx-filter", 1)
+        }
+    }
+}
+
 /// Check that available viona API matches expectations of propolis crate
 pub(crate) fn check_api_version() -> Result<(), crate::api_version::Error> {
     let fd = viona_api::VionaFd::open()?;
     let vers = fd.api_version()?;
 
-    // viona only requires the V2 bits for now
+    // viona only requires the V2 bits for now.  Multi-queue support (V3) is
+    // checked for when creating a device which asks for it, and receive
+    // filtering (V4) is offered to guests only where it is present.
     let compare = viona_api::ApiVersion::V2;
 
     if vers < compare {
@@ -839,3 +1480,89 @@ pub(crate) fn check_api_version() -> Result<(), crate::api_version::Error> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offload_dependencies() {
+        let all = VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_CSUM
+            | VIRTIO_NET_F_HOST_TSO4
+            | VIRTIO_NET_F_HOST_TSO6
+            | VIRTIO_NET_F_GUEST_CSUM
+            | VIRTIO_NET_F_GUEST_TSO4
+            | VIRTIO_NET_F_GUEST_TSO6;
+        assert_eq!(NetOffloads::default().mask(all), all);
+
+        // TSO cannot be offered without TX checksum offload
+        let no_tx_csum =
+            NetOffloads { tx_checksum: false, ..Default::default() };
+        assert_eq!(
+            no_tx_csum.mask(all),
+            VIRTIO_NET_F_MAC
+                | VIRTIO_NET_F_GUEST_CSUM
+                | VIRTIO_NET_F_GUEST_TSO4
+                | VIRTIO_NET_F_GUEST_TSO6
+        );
+
+        let no_lro = NetOffloads { lro: false, ..Default::default() };
+        assert_eq!(
+            no_lro.mask(all),
+            all & !(VIRTIO_NET_F_GUEST_TSO4 | VIRTIO_NET_F_GUEST_TSO6)
+        );
+
+        // Nothing is added which viona doesn't offer
+        assert_eq!(
+            NetOffloads::default().mask(VIRTIO_NET_F_MAC),
+            VIRTIO_NET_F_MAC
+        );
+    }
+
+    #[test]
+    fn rx_filter_overflow() {
+        let max = viona_api::VIONA_MAC_FILTER_MAX as usize;
+        let addr = |n: usize| [0x02, 0, 0, 0, (n >> 8) as u8, n as u8];
+        let mcast = |n: usize| [0x01, 0, 0x5e, 0, (n >> 8) as u8, n as u8];
+
+        let mut filter = RxFilter::new(0);
+        assert_eq!(filter.promisc_mode(), viona_api::VIONA_PROMISC_MULTI);
+
+        filter.allmulti = false;
+        filter.unicast = (0..2).map(addr).collect();
+        filter.multicast = (0..max - 2).map(mcast).collect();
+        assert_eq!(filter.promisc_mode(), viona_api::VIONA_PROMISC_NONE);
+        assert_eq!(
+            filter.mac_tables(),
+            (&filter.unicast[..], &filter.multicast[..])
+        );
+
+        // Multicast addresses which do not fit are received through
+        // multicast promiscuity instead...
+        filter.multicast.push(mcast(max));
+        assert_eq!(filter.promisc_mode(), viona_api::VIONA_PROMISC_MULTI);
+        assert_eq!(filter.mac_tables(), (&filter.unicast[..], &[][..]));
+
+        // ...and unicast addresses through full promiscuity.
+        filter.unicast = (0..=max).map(addr).collect();
+        assert_eq!(filter.promisc_mode(), viona_api::VIONA_PROMISC_ALL);
+        assert_eq!(filter.mac_tables(), (&[][..], &[][..]));
+    }
+
+    #[test]
+    fn rx_filter_vlans() {
+        // Without VLAN filtering, all VLANs are received
+        let filter = RxFilter::new(0);
+        assert!(filter.vlan_bitmap().iter().all(|w| *w == u32::MAX));
+
+        let mut filter = RxFilter::new(VIRTIO_NET_F_CTRL_VLAN);
+        assert!(filter.vlan_bitmap().iter().all(|w| *w == 0));
+
+        filter.vlans.as_mut().unwrap().extend([1, 33, 4095]);
+        let bitmap = filter.vlan_bitmap();
+        assert_eq!(bitmap[0], 1 << 1);
+        assert_eq!(bitmap[1], 1 << 1);
+        assert_eq!(bitmap[VLAN_BITMAP_WORDS - 1], 1 << 31);
+    }
+}
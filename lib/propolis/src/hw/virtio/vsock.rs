@@ -0,0 +1,1105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtio-vsock device, through which agents in the guest can reach
+//! processes on the host without any network configuration.
+//!
+//! Stream connections are multiplexed onto Unix domain sockets on the host,
+//! following the convention established by Firecracker:
+//!
+//! - A guest connecting to port `P` of the host CID reaches whatever is
+//!   listening on the Unix socket at `<path>_P`, where `<path>` is that given
+//!   when creating the device.
+//! - A host process connecting to the Unix socket at `<path>` itself writes
+//!   `CONNECT <port>\n` to reach a guest listening on `port`.  Once the guest
+//!   accepts the connection, the device replies `OK <host-port>\n`, and the
+//!   socket then carries the stream.  If the guest refuses it, the socket is
+//!   closed.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::num::NonZeroU16;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{read_buf, write_buf, Chain, VirtQueue, VirtQueues};
+use super::{VirtioDevice, VqChange};
+
+use lazy_static::lazy_static;
+
+/// The CID at which the guest reaches the host
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// CIDs below this are reserved, and cannot be assigned to a guest
+pub const VSOCK_MIN_GUEST_CID: u64 = 3;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const EVENT_QUEUE: usize = 2;
+
+const VIRTIO_VSOCK_CFG_SIZE: usize = 8;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+const VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE: u32 = 1 << 0;
+const VIRTIO_VSOCK_SHUTDOWN_F_SEND: u32 = 1 << 1;
+
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+/// Buffer space advertised to the guest for each connection.  Data from the
+/// guest is written to the host socket as it arrives, so this only bounds how
+/// far the guest may run ahead of the credit updates sent back to it.
+const BUF_ALLOC: u32 = 256 * 1024;
+
+/// Largest payload read from a host socket, or accepted from the guest, in a
+/// single packet
+const MAX_PKT_DATA: usize = 64 * 1024;
+
+/// Host ports for connections made from the host side are allocated upward
+/// from here, well clear of those a guest is likely to listen on.
+const HOST_PORT_BASE: u32 = 1 << 30;
+
+/// Time allowed for a host process to send its `CONNECT` request, or to
+/// accept data written to its socket, before it is disconnected
+const HOST_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Header preceding each packet in the RX and TX queues
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VsockHdr {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    typ: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+/// A connection is identified by its host and guest ports
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct ConnKey {
+    host_port: u32,
+    guest_port: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ConnState {
+    /// A host process has requested the connection, to which the guest has
+    /// yet to respond
+    Connecting,
+    Established,
+}
+
+struct Conn {
+    state: ConnState,
+    stream: Arc<UnixStream>,
+
+    /// Buffer space and consumed byte count last advertised by the guest
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Bytes sent to the guest
+    tx_cnt: u32,
+    /// Bytes received from the guest and written to the host socket
+    fwd_cnt: u32,
+    /// `fwd_cnt` as last reported to the guest
+    fwd_cnt_sent: u32,
+}
+impl Conn {
+    fn new(state: ConnState, stream: UnixStream) -> Self {
+        Self {
+            state,
+            stream: Arc::new(stream),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+        }
+    }
+
+    /// Bytes which may be sent to the guest without overrunning its buffers
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    fn update_peer_credit(&mut self, hdr: &VsockHdr) {
+        self.peer_buf_alloc = hdr.buf_alloc;
+        self.peer_fwd_cnt = hdr.fwd_cnt;
+    }
+
+    fn close(&self) {
+        // Wakes the reader thread, if it is blocked on the socket
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// A packet awaiting delivery into the RX queue
+struct Packet {
+    hdr: VsockHdr,
+    data: Vec<u8>,
+    /// Offset into `data` of that which remains undelivered
+    off: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    conns: HashMap<ConnKey, Conn>,
+    rx_pending: VecDeque<Packet>,
+    next_host_port: u32,
+
+    /// The guest must be told that its connections have been lost, as they
+    /// are after a migration.
+    reset_event: bool,
+
+    /// Nothing may be written into guest memory while the device is not
+    /// running.
+    running: bool,
+}
+
+/// Thread accepting connections to the multiplexer socket
+struct Listener {
+    hdl: JoinHandle<()>,
+    shutdown: Arc<AtomicBool>,
+}
+
+pub struct PciVirtioSocket {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: Mutex<Option<Listener>>,
+    uds: Mutex<Option<UnixListener>>,
+
+    inner: Mutex<Inner>,
+    /// Signalled as the guest extends credit, or connections are closed
+    credit_cv: Condvar,
+    /// Serializes the processing of the TX queue, so data from the guest is
+    /// written to host sockets in order
+    tx_lock: Mutex<()>,
+    this: Weak<Self>,
+}
+
+impl PciVirtioSocket {
+    /// Create a virtio-vsock device, with queues of `queue_size` entries,
+    /// which presents `guest_cid` to the guest, and multiplexes its
+    /// connections over Unix sockets at `uds_path`.
+    ///
+    /// The multiplexer socket is bound immediately, replacing any stale
+    /// socket left at `uds_path`.
+    pub fn new(
+        guest_cid: u64,
+        queue_size: u16,
+        uds_path: impl AsRef<Path>,
+    ) -> std::io::Result<Arc<Self>> {
+        if !(VSOCK_MIN_GUEST_CID..=u64::from(u32::MAX)).contains(&guest_cid) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("guest CID {} is out of range", guest_cid),
+            ));
+        }
+        let uds_path = uds_path.as_ref().to_path_buf();
+        if std::fs::symlink_metadata(&uds_path)
+            .is_ok_and(|meta| meta.file_type().is_socket())
+        {
+            std::fs::remove_file(&uds_path)?;
+        }
+        let uds = UnixListener::bind(&uds_path)?;
+
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(3).unwrap(),
+        );
+        // One MSI-X entry for each queue, plus one for device config changes
+        let msix_count = Some(4);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_SOCKET,
+            VIRTIO_SUB_DEV_SOCKET,
+            pci::bits::CLASS_COMMUNICATION,
+            VIRTIO_VSOCK_CFG_SIZE,
+        );
+
+        Ok(Arc::new_cyclic(|this| Self {
+            virtio_state,
+            pci_state,
+            guest_cid,
+            uds_path,
+            listener: Mutex::new(None),
+            uds: Mutex::new(Some(uds)),
+            inner: Mutex::new(Inner {
+                next_host_port: HOST_PORT_BASE,
+                ..Default::default()
+            }),
+            credit_cv: Condvar::new(),
+            tx_lock: Mutex::new(()),
+            this: this.clone(),
+        }))
+    }
+
+    /// Path of the multiplexer socket to which host processes connect
+    pub fn uds_path(&self) -> &Path {
+        &self.uds_path
+    }
+
+    fn vsock_cfg_read(&self, id: &VsockReg, ro: &mut ReadOp) {
+        match id {
+            VsockReg::GuestCid => ro.write_bytes(&self.guest_cid.to_le_bytes()),
+        }
+    }
+
+    /// Header for a packet from the host to the guest on connection `key`
+    fn hdr_for(&self, key: ConnKey, op: u16) -> VsockHdr {
+        VsockHdr {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.host_port,
+            dst_port: key.guest_port,
+            typ: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: BUF_ALLOC,
+            ..Default::default()
+        }
+    }
+
+    /// Queue a packet for delivery to the guest, carrying the current credit
+    /// for its connection, if it still exists.
+    fn queue_pkt(&self, inner: &mut Inner, mut hdr: VsockHdr, data: Vec<u8>) {
+        let key = ConnKey { host_port: hdr.src_port, guest_port: hdr.dst_port };
+        if let Some(conn) = inner.conns.get_mut(&key) {
+            hdr.fwd_cnt = conn.fwd_cnt;
+            conn.fwd_cnt_sent = conn.fwd_cnt;
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(data.len() as u32);
+        }
+        hdr.len = data.len() as u32;
+        inner.rx_pending.push_back(Packet { hdr, data, off: 0 });
+    }
+
+    /// Reply to `req` with a reset, unless it is itself a reset.
+    fn queue_rst(&self, inner: &mut Inner, req: &VsockHdr) {
+        if req.op == VIRTIO_VSOCK_OP_RST {
+            return;
+        }
+        let hdr = VsockHdr {
+            src_cid: req.dst_cid,
+            dst_cid: req.src_cid,
+            src_port: req.dst_port,
+            dst_port: req.src_port,
+            typ: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RST,
+            ..Default::default()
+        };
+        inner.rx_pending.push_back(Packet { hdr, data: Vec::new(), off: 0 });
+    }
+
+    fn remove_conn(&self, inner: &mut Inner, key: ConnKey) {
+        if let Some(conn) = inner.conns.remove(&key) {
+            conn.close();
+            self.credit_cv.notify_all();
+        }
+    }
+
+    /// Deliver as many pending packets as there are RX buffers for.
+    fn flush_rx(&self, inner: &mut Inner) {
+        if !inner.running || inner.rx_pending.is_empty() {
+            return;
+        }
+        let vq = &self.virtio_state.queues[RX_QUEUE];
+        if !vq.live.load(Ordering::Acquire) {
+            return;
+        }
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let hdr_len = std::mem::size_of::<VsockHdr>();
+        let mut chain = Chain::with_capacity(4);
+        while let Some(pkt) = inner.rx_pending.front_mut() {
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            let space = chain.remain_write_bytes();
+            if space < hdr_len {
+                // Too small to be of use, so return it empty
+                vq.push_used(&mut chain, &mem);
+                continue;
+            }
+            // Data which does not fit in this buffer is left to follow in the
+            // next, in a packet of its own.
+            let remain = &pkt.data[pkt.off..];
+            let len = usize::min(remain.len(), space - hdr_len);
+            let mut hdr = pkt.hdr;
+            hdr.len = len as u32;
+            let op = hdr.op;
+            probes::vsock_rx!(|| (op, len as u64));
+            chain.write(&hdr, &mem);
+            write_buf(&remain[..len], &mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+
+            pkt.off += len;
+            if pkt.off == pkt.data.len() {
+                inner.rx_pending.pop_front();
+            }
+        }
+    }
+
+    /// Tell the guest its connections have been lost, once it has provided a
+    /// buffer in the event queue to do so.
+    fn flush_event(&self, inner: &mut Inner) {
+        if !inner.running || !inner.reset_event {
+            return;
+        }
+        let vq = &self.virtio_state.queues[EVENT_QUEUE];
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(1);
+        if vq.pop_avail(&mut chain, &mem).is_some() {
+            chain.write(&VIRTIO_VSOCK_EVENT_TRANSPORT_RESET, &mem);
+            vq.push_used(&mut chain, &mem);
+            inner.reset_event = false;
+        }
+    }
+
+    /// Process the packets in all available TX chains.
+    fn process_tx(&self, vq: &VirtQueue) {
+        let _guard = self.tx_lock.lock().unwrap();
+        let Some(mem) = self.pci_state.acc_mem.access() else {
+            return;
+        };
+        let mut data = vec![0u8; MAX_PKT_DATA];
+        let mut chain = Chain::with_capacity(4);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            let mut hdr = VsockHdr::default();
+            let valid = chain.read(&mut hdr, &mem);
+            let len = usize::min(hdr.len as usize, MAX_PKT_DATA);
+            let len = match valid {
+                true => read_buf(&mem, &mut chain, &mut data[..len]),
+                false => 0,
+            };
+            vq.push_used(&mut chain, &mem);
+            if valid {
+                let op = hdr.op;
+                probes::vsock_tx!(|| (op, len as u64));
+                self.handle_pkt(&hdr, &data[..len]);
+            }
+        }
+    }
+
+    /// Act upon a packet sent by the guest.
+    fn handle_pkt(&self, hdr: &VsockHdr, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if hdr.src_cid != self.guest_cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.typ != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            self.queue_rst(&mut inner, hdr);
+            self.flush_rx(&mut inner);
+            return;
+        }
+
+        let key = ConnKey { host_port: hdr.dst_port, guest_port: hdr.src_port };
+        match (hdr.op, inner.conns.get_mut(&key)) {
+            (VIRTIO_VSOCK_OP_REQUEST, None) => {
+                let path = self.port_path(hdr.dst_port);
+                let stream = UnixStream::connect(path).and_then(|stream| {
+                    stream.set_write_timeout(Some(HOST_IO_TIMEOUT))?;
+                    Ok(stream)
+                });
+                match stream {
+                    Ok(stream) => {
+                        let mut conn =
+                            Conn::new(ConnState::Established, stream);
+                        conn.update_peer_credit(hdr);
+                        match self.spawn_reader(key, &conn) {
+                            Ok(()) => {
+                                inner.conns.insert(key, conn);
+                                let resp =
+                                    self.hdr_for(key, VIRTIO_VSOCK_OP_RESPONSE);
+                                self.queue_pkt(&mut inner, resp, Vec::new());
+                            }
+                            Err(_) => self.queue_rst(&mut inner, hdr),
+                        }
+                    }
+                    // Nothing is listening on the host for this port
+                    Err(_) => self.queue_rst(&mut inner, hdr),
+                }
+            }
+            (VIRTIO_VSOCK_OP_RESPONSE, Some(conn))
+                if conn.state == ConnState::Connecting =>
+            {
+                conn.update_peer_credit(hdr);
+                let reply = format!("OK {}\n", key.host_port);
+                match conn.stream.as_ref().write_all(reply.as_bytes()) {
+                    Ok(()) => {
+                        conn.state = ConnState::Established;
+                        self.credit_cv.notify_all();
+                    }
+                    Err(_) => {
+                        self.remove_conn(&mut inner, key);
+                        self.queue_rst(&mut inner, hdr);
+                    }
+                }
+            }
+            (VIRTIO_VSOCK_OP_RW, Some(conn))
+                if conn.state == ConnState::Established =>
+            {
+                conn.update_peer_credit(hdr);
+                let stream = conn.stream.clone();
+                self.credit_cv.notify_all();
+
+                // Writes to the host socket may block, and so are made without
+                // holding the lock, which the reader thread for this connection
+                // may need in order to make progress.
+                drop(inner);
+                let res = stream.as_ref().write_all(data);
+                inner = self.inner.lock().unwrap();
+
+                let Some(conn) = inner.conns.get_mut(&key) else {
+                    // The connection was reset in the meantime
+                    return;
+                };
+                if res.is_err() {
+                    self.remove_conn(&mut inner, key);
+                    self.queue_rst(&mut inner, hdr);
+                } else {
+                    conn.fwd_cnt = conn.fwd_cnt.wrapping_add(data.len() as u32);
+                    // Let the guest know of the space it has been freed
+                    // before it runs out, rather than awaiting its request.
+                    let unreported =
+                        conn.fwd_cnt.wrapping_sub(conn.fwd_cnt_sent);
+                    if unreported >= BUF_ALLOC / 2 {
+                        let update =
+                            self.hdr_for(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+                        self.queue_pkt(&mut inner, update, Vec::new());
+                    }
+                }
+            }
+            (VIRTIO_VSOCK_OP_SHUTDOWN, Some(conn)) => {
+                let both = VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE
+                    | VIRTIO_VSOCK_SHUTDOWN_F_SEND;
+                if hdr.flags & both == both {
+                    // The guest expects the host to finish closing the
+                    // connection with a reset.
+                    self.remove_conn(&mut inner, key);
+                    self.queue_rst(&mut inner, hdr);
+                } else if hdr.flags & VIRTIO_VSOCK_SHUTDOWN_F_SEND != 0 {
+                    let _ = conn.stream.shutdown(std::net::Shutdown::Write);
+                }
+            }
+            (VIRTIO_VSOCK_OP_RST, Some(_)) => {
+                self.remove_conn(&mut inner, key);
+            }
+            (VIRTIO_VSOCK_OP_CREDIT_UPDATE, Some(conn)) => {
+                conn.update_peer_credit(hdr);
+                self.credit_cv.notify_all();
+            }
+            (VIRTIO_VSOCK_OP_CREDIT_REQUEST, Some(conn)) => {
+                conn.update_peer_credit(hdr);
+                self.credit_cv.notify_all();
+                let update = self.hdr_for(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+                self.queue_pkt(&mut inner, update, Vec::new());
+            }
+            (_, Some(_)) => {
+                // Any other packet is a protocol violation on the part of the
+                // guest, for which the connection is reset.
+                self.remove_conn(&mut inner, key);
+                self.queue_rst(&mut inner, hdr);
+            }
+            (_, None) => self.queue_rst(&mut inner, hdr),
+        }
+        self.flush_rx(&mut inner);
+    }
+
+    /// Path of the Unix socket reached by guest connections to `port`
+    fn port_path(&self, port: u32) -> PathBuf {
+        let mut path = self.uds_path.clone().into_os_string();
+        path.push(format!("_{}", port));
+        path.into()
+    }
+
+    /// Register a connection, requested by a host process, to `guest_port`.
+    ///
+    /// Connections are refused while the device is not running.
+    fn host_connect(
+        &self,
+        guest_port: u32,
+        stream: UnixStream,
+    ) -> Option<ConnKey> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.running {
+            return None;
+        }
+        let key = loop {
+            let host_port = inner.next_host_port;
+            inner.next_host_port = match host_port.checked_add(1) {
+                Some(next) => next,
+                None => HOST_PORT_BASE,
+            };
+            let key = ConnKey { host_port, guest_port };
+            if !inner.conns.contains_key(&key) {
+                break key;
+            }
+        };
+        inner.conns.insert(key, Conn::new(ConnState::Connecting, stream));
+        let req = self.hdr_for(key, VIRTIO_VSOCK_OP_REQUEST);
+        self.queue_pkt(&mut inner, req, Vec::new());
+        self.flush_rx(&mut inner);
+        Some(key)
+    }
+
+    /// Wait until connection `key` may send data to the guest, returning how
+    /// much, or `None` if the connection has been closed.
+    fn wait_credit(&self, key: ConnKey) -> Option<usize> {
+        let inner = self.inner.lock().unwrap();
+        let inner = self
+            .credit_cv
+            .wait_while(inner, |inner| match inner.conns.get(&key) {
+                Some(conn) => {
+                    conn.state != ConnState::Established
+                        || conn.peer_credit() == 0
+                }
+                None => false,
+            })
+            .unwrap();
+        let credit = inner.conns.get(&key)?.peer_credit();
+        Some(usize::min(credit as usize, MAX_PKT_DATA))
+    }
+
+    /// Pass data read from the host socket of connection `key` to the guest.
+    fn host_data(&self, key: ConnKey, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.conns.contains_key(&key) {
+            return;
+        }
+        let hdr = self.hdr_for(key, VIRTIO_VSOCK_OP_RW);
+        self.queue_pkt(&mut inner, hdr, data.to_vec());
+        self.flush_rx(&mut inner);
+    }
+
+    /// The host side of connection `key` has closed its socket.
+    fn host_closed(&self, key: ConnKey) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(conn) = inner.conns.get(&key) else {
+            return;
+        };
+        let (op, flags) = match conn.state {
+            ConnState::Connecting => (VIRTIO_VSOCK_OP_RST, 0),
+            ConnState::Established => (
+                VIRTIO_VSOCK_OP_SHUTDOWN,
+                VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE | VIRTIO_VSOCK_SHUTDOWN_F_SEND,
+            ),
+        };
+        let mut hdr = self.hdr_for(key, op);
+        hdr.flags = flags;
+        self.queue_pkt(&mut inner, hdr, Vec::new());
+        // Any reset from the guest which follows will find no connection,
+        // and be ignored.
+        self.remove_conn(&mut inner, key);
+        self.flush_rx(&mut inner);
+    }
+
+    /// Spawn a thread to read data from the host socket of `conn`.
+    fn spawn_reader(&self, key: ConnKey, conn: &Conn) -> std::io::Result<()> {
+        let stream = conn.stream.try_clone()?;
+        let dev = self.this.clone();
+        std::thread::Builder::new()
+            .name(format!("vsock {}:{}", key.host_port, key.guest_port))
+            .spawn(move || Self::reader_loop(dev, key, stream))?;
+        Ok(())
+    }
+
+    fn reader_loop(dev: Weak<Self>, key: ConnKey, mut stream: UnixStream) {
+        let mut buf = vec![0u8; MAX_PKT_DATA];
+        loop {
+            let Some(credit) = dev.upgrade().and_then(|d| d.wait_credit(key))
+            else {
+                return;
+            };
+            let res = stream.read(&mut buf[..credit]);
+            let Some(dev) = dev.upgrade() else {
+                return;
+            };
+            match res {
+                Ok(0) | Err(_) => {
+                    dev.host_closed(key);
+                    return;
+                }
+                Ok(n) => dev.host_data(key, &buf[..n]),
+            }
+        }
+    }
+
+    /// Handle a connection to the multiplexer socket, reading the port to
+    /// which it is destined and then passing its data on to the guest.
+    fn host_conn_loop(dev: Weak<Self>, mut stream: UnixStream) {
+        let Some(port) = read_connect(&mut stream) else {
+            return;
+        };
+        let Ok(reader) = stream.try_clone() else {
+            return;
+        };
+        if stream.set_write_timeout(Some(HOST_IO_TIMEOUT)).is_err() {
+            return;
+        }
+        let Some(key) =
+            dev.upgrade().and_then(|d| d.host_connect(port, stream))
+        else {
+            return;
+        };
+        Self::reader_loop(dev, key, reader);
+    }
+
+    fn listener_start(&self) -> std::io::Result<()> {
+        let mut listener = self.listener.lock().unwrap();
+        if listener.is_some() {
+            return Ok(());
+        }
+        let uds = match self.uds.lock().unwrap().as_ref() {
+            Some(uds) => uds.try_clone()?,
+            None => return Ok(()),
+        };
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let dev = self.this.clone();
+        let thread_shutdown = shutdown.clone();
+        let hdl = std::thread::Builder::new()
+            .name("vsock listener".into())
+            .spawn(move || {
+                for stream in uds.incoming() {
+                    if thread_shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let dev = dev.clone();
+                    let _ = std::thread::Builder::new()
+                        .name("vsock host conn".into())
+                        .spawn(move || Self::host_conn_loop(dev, stream));
+                }
+            })?;
+        *listener = Some(Listener { hdl, shutdown });
+        Ok(())
+    }
+
+    fn listener_stop(&self) {
+        if let Some(listener) = self.listener.lock().unwrap().take() {
+            listener.shutdown.store(true, Ordering::Release);
+            // Wake the thread from its wait for a connection
+            let _ = UnixStream::connect(&self.uds_path);
+            let _ = listener.hdl.join();
+        }
+    }
+
+    fn set_running(&self, running: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.running = running;
+        self.flush_event(&mut inner);
+        self.flush_rx(&mut inner);
+    }
+
+    /// Close all connections, discarding any data yet to reach the guest.
+    fn reset_conns(&self) -> MutexGuard<'_, Inner> {
+        let mut inner = self.inner.lock().unwrap();
+        for (_, conn) in inner.conns.drain() {
+            conn.close();
+        }
+        inner.rx_pending.clear();
+        self.credit_cv.notify_all();
+        inner
+    }
+}
+
+/// Read a `CONNECT <port>\n` request from a host process.
+fn read_connect(stream: &mut UnixStream) -> Option<u32> {
+    stream.set_read_timeout(Some(HOST_IO_TIMEOUT)).ok()?;
+    // The request is read a byte at a time, so none of the stream which
+    // follows it is consumed.
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        stream.read_exact(&mut byte).ok()?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= 32 {
+            return None;
+        }
+        line.push(byte[0]);
+    }
+    stream.set_read_timeout(None).ok()?;
+    let line = std::str::from_utf8(&line).ok()?;
+    let port = line.trim_end_matches('\r').strip_prefix("CONNECT ")?;
+    port.trim().parse().ok()
+}
+
+impl VirtioDevice for PciVirtioSocket {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        VSOCK_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.vsock_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        match vq.id as usize {
+            TX_QUEUE => self.process_tx(vq),
+            RX_QUEUE => self.flush_rx(&mut self.inner.lock().unwrap()),
+            EVENT_QUEUE => self.flush_event(&mut self.inner.lock().unwrap()),
+            _ => {}
+        }
+    }
+
+    fn queue_change(
+        &self,
+        vq: &Arc<VirtQueue>,
+        change: VqChange,
+    ) -> Result<(), ()> {
+        // Connections do not survive a reset of the device by its driver
+        if let (RX_QUEUE, VqChange::Reset) = (vq.id as usize, change) {
+            self.reset_conns();
+        }
+        Ok(())
+    }
+}
+
+impl Lifecycle for PciVirtioSocket {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-vsock"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.listener_start()?;
+        self.set_running(true);
+        Ok(())
+    }
+    fn pause(&self) {
+        self.set_running(false);
+    }
+    fn resume(&self) {
+        self.set_running(true);
+    }
+    fn halt(&self) {
+        self.listener_stop();
+        let mut inner = self.reset_conns();
+        inner.running = false;
+        drop(inner);
+        if let Some(_uds) = self.uds.lock().unwrap().take() {
+            let _ = std::fs::remove_file(&self.uds_path);
+        }
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+impl PciVirtio for PciVirtioSocket {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl MigrateMulti for PciVirtioSocket {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)?;
+        // Connections to the host are not carried across a migration, so the
+        // guest is told to drop its own once the device is running.
+        self.reset_conns().reset_event = true;
+        Ok(())
+    }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        <dyn PciVirtio>::schemas(self, schemas)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum VsockReg {
+    GuestCid,
+}
+lazy_static! {
+    static ref VSOCK_DEV_REGS: RegMap<VsockReg> = {
+        let layout = [(VsockReg::GuestCid, 8)];
+        RegMap::create_packed(VIRTIO_VSOCK_CFG_SIZE, &layout, None)
+    };
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn vsock_tx(op: u16, len: u64) {}
+    fn vsock_rx(op: u16, len: u64) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::GuestAddr;
+    use crate::hw::virtio::testutil::{
+        post_buf, used_len, wait_used, QUEUE_SIZE,
+    };
+    use crate::vmm::{Machine, MemCtx};
+
+    const GUEST_CID: u64 = 3;
+    /// Rings for the RX, TX, and event queues, each in a (4k-aligned) 8k
+    /// region
+    const RX_RING: u64 = 0x10_0000;
+    const TX_RING: u64 = 0x10_2000;
+    const EVENT_RING: u64 = 0x10_4000;
+    const BUF_BASE: u64 = 0x10_8000;
+
+    fn setup(
+        dir: &tempfile::TempDir,
+    ) -> (Machine, Arc<PciVirtioSocket>, PathBuf) {
+        let machine = Machine::new_test().unwrap();
+        let path = dir.path().join("vsock.sock");
+        let dev = PciVirtioSocket::new(GUEST_CID, QUEUE_SIZE, &path).unwrap();
+        machine.acc_mem.adopt(&dev.pci_state.acc_mem, None);
+        dev.virtio_state.queues[RX_QUEUE].map_legacy(RX_RING);
+        dev.virtio_state.queues[TX_QUEUE].map_legacy(TX_RING);
+        dev.virtio_state.queues[EVENT_QUEUE].map_legacy(EVENT_RING);
+        dev.virtio_state.queues[RX_QUEUE].live.store(true, Ordering::Release);
+        (machine, dev, path)
+    }
+
+    /// Send a packet from the guest, in TX slot `idx`.
+    fn guest_send(
+        dev: &PciVirtioSocket,
+        mem: &MemCtx,
+        idx: u16,
+        hdr: VsockHdr,
+        data: &[u8],
+    ) {
+        let addr = BUF_BASE + 0x1000 * u64::from(idx);
+        let hdr = VsockHdr { len: data.len() as u32, ..hdr };
+        let hdr_len = std::mem::size_of::<VsockHdr>();
+        mem.write(GuestAddr(addr), &hdr);
+        mem.write_from(GuestAddr(addr + hdr_len as u64), data, data.len());
+        let len = (hdr_len + data.len()) as u32;
+        post_buf(mem, TX_RING, idx, addr, len, false);
+        let vq = dev.virtio_state.queues[TX_QUEUE].clone();
+        dev.queue_notify(&vq);
+    }
+
+    /// Provide an RX buffer to the device in slot `idx`, returning its
+    /// address.
+    fn guest_post_rx(dev: &PciVirtioSocket, mem: &MemCtx, idx: u16) -> u64 {
+        let addr = BUF_BASE + 0x10_000 + 0x1000 * u64::from(idx);
+        post_buf(mem, RX_RING, idx, addr, 0x1000, true);
+        let vq = dev.virtio_state.queues[RX_QUEUE].clone();
+        dev.queue_notify(&vq);
+        addr
+    }
+
+    fn guest_hdr(host_port: u32, guest_port: u32, op: u16) -> VsockHdr {
+        VsockHdr {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: guest_port,
+            dst_port: host_port,
+            typ: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 0x1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn guest_cid_cfg() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_machine, dev, _path) = setup(&dir);
+
+        let mut buf = [0u8; 8];
+        let mut ro = ReadOp::from_buf(0, &mut buf);
+        dev.cfg_rw(RWOp::Read(&mut ro));
+        assert_eq!(u64::from_le_bytes(buf), GUEST_CID);
+    }
+
+    #[test]
+    fn reserved_cid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vsock.sock");
+        assert!(PciVirtioSocket::new(2, QUEUE_SIZE, &path).is_err());
+    }
+
+    #[test]
+    fn guest_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let (machine, dev, path) = setup(&dir);
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        dev.set_running(true);
+
+        let mut port_path = path.into_os_string();
+        port_path.push("_1234");
+        let listener = UnixListener::bind(&port_path).unwrap();
+
+        let rx0 = guest_post_rx(&dev, &mem, 0);
+        guest_send(
+            &dev,
+            &mem,
+            0,
+            guest_hdr(1234, 5000, VIRTIO_VSOCK_OP_REQUEST),
+            &[],
+        );
+        let (mut host, _) = listener.accept().unwrap();
+
+        wait_used(&mem, RX_RING, 0);
+        let resp: VsockHdr = mem.read(GuestAddr(rx0)).unwrap();
+        assert_eq!({ resp.op }, VIRTIO_VSOCK_OP_RESPONSE);
+        assert_eq!(({ resp.src_port }, { resp.dst_port }), (1234, 5000));
+        assert_eq!({ resp.dst_cid }, GUEST_CID);
+
+        // Data flows in both directions
+        let hdr = guest_hdr(1234, 5000, VIRTIO_VSOCK_OP_RW);
+        guest_send(&dev, &mem, 1, hdr, b"ping");
+        let mut buf = [0u8; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let rx1 = guest_post_rx(&dev, &mem, 1);
+        host.write_all(b"pong").unwrap();
+        let len = wait_used(&mem, RX_RING, 1) as usize;
+        let hdr_len = std::mem::size_of::<VsockHdr>();
+        assert_eq!(len, hdr_len + 4);
+        let rw: VsockHdr = mem.read(GuestAddr(rx1)).unwrap();
+        assert_eq!(({ rw.op }, { rw.len }), (VIRTIO_VSOCK_OP_RW, 4));
+        let mut buf = [0u8; 4];
+        mem.read_into(GuestAddr(rx1 + hdr_len as u64), &mut buf, 4);
+        assert_eq!(&buf, b"pong");
+
+        // Closing the host socket shuts the connection down
+        let rx2 = guest_post_rx(&dev, &mem, 2);
+        drop(host);
+        wait_used(&mem, RX_RING, 2);
+        let shut: VsockHdr = mem.read(GuestAddr(rx2)).unwrap();
+        assert_eq!({ shut.op }, VIRTIO_VSOCK_OP_SHUTDOWN);
+        dev.halt();
+    }
+
+    #[test]
+    fn guest_connect_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (machine, dev, _path) = setup(&dir);
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        dev.set_running(true);
+
+        // With nothing listening on the host, the guest is sent a reset
+        let rx0 = guest_post_rx(&dev, &mem, 0);
+        let hdr = guest_hdr(4321, 5000, VIRTIO_VSOCK_OP_REQUEST);
+        guest_send(&dev, &mem, 0, hdr, &[]);
+        wait_used(&mem, RX_RING, 0);
+        let rst: VsockHdr = mem.read(GuestAddr(rx0)).unwrap();
+        assert_eq!({ rst.op }, VIRTIO_VSOCK_OP_RST);
+        assert_eq!(({ rst.src_port }, { rst.dst_port }), (4321, 5000));
+    }
+
+    #[test]
+    fn host_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let (machine, dev, path) = setup(&dir);
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+        dev.start().unwrap();
+
+        let rx0 = guest_post_rx(&dev, &mem, 0);
+        let mut host = UnixStream::connect(&path).unwrap();
+        host.write_all(b"CONNECT 52\n").unwrap();
+
+        wait_used(&mem, RX_RING, 0);
+        let req: VsockHdr = mem.read(GuestAddr(rx0)).unwrap();
+        assert_eq!({ req.op }, VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!({ req.dst_port }, 52);
+        let host_port = req.src_port;
+        assert!(host_port >= HOST_PORT_BASE);
+
+        // Once the guest accepts, the host process learns of its port
+        let hdr = guest_hdr(host_port, 52, VIRTIO_VSOCK_OP_RESPONSE);
+        guest_send(&dev, &mem, 0, hdr, &[]);
+        let expected = format!("OK {}\n", host_port);
+        let mut buf = vec![0u8; expected.len()];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected.as_bytes());
+
+        let rx1 = guest_post_rx(&dev, &mem, 1);
+        host.write_all(b"hello").unwrap();
+        wait_used(&mem, RX_RING, 1);
+        let rw: VsockHdr = mem.read(GuestAddr(rx1)).unwrap();
+        assert_eq!(({ rw.op }, { rw.len }), (VIRTIO_VSOCK_OP_RW, 5));
+
+        dev.halt();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn transport_reset_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let (machine, dev, _path) = setup(&dir);
+        let acc_mem = machine.acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        // As after a migration, the guest is told of the reset once running
+        dev.reset_conns().reset_event = true;
+        post_buf(&mem, EVENT_RING, 0, BUF_BASE, 4, true);
+        let vq = dev.virtio_state.queues[EVENT_QUEUE].clone();
+        dev.queue_notify(&vq);
+        assert_eq!(used_len(&mem, EVENT_RING, 0), None);
+
+        dev.set_running(true);
+        assert_eq!(used_len(&mem, EVENT_RING, 0), Some(4));
+        let id: u32 = mem.read(GuestAddr(BUF_BASE)).unwrap();
+        assert_eq!(id, VIRTIO_VSOCK_EVENT_TRANSPORT_RESET);
+    }
+}
@@ -2,6 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeSet;
+
 use crate::vmm::MemCtx;
 
 use serde::{Deserialize, Serialize};
@@ -65,6 +67,20 @@ pub enum Migrator<'a> {
     /// etc).
     Multi(&'a dyn MigrateMulti),
 }
+impl Migrator<'_> {
+    /// The schemas of the payloads the device exports and imports, or `None`
+    /// if the device is not migratable.
+    pub fn schemas(&self) -> Option<PayloadSchemas> {
+        let mut schemas = PayloadSchemas::default();
+        match self {
+            Migrator::NonMigratable => return None,
+            Migrator::Empty => {}
+            Migrator::Single(mech) => mech.schemas(&mut schemas),
+            Migrator::Multi(mech) => mech.schemas(&mut schemas),
+        }
+        Some(schemas)
+    }
+}
 
 /// A device which can be migrated using a single typed payload to represent its
 /// internal state.
@@ -78,6 +94,9 @@ pub trait MigrateSingle: Send + Sync + 'static {
         offer: PayloadOffer,
         ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError>;
+
+    /// Declares the schemas of the payloads the device exports and imports.
+    fn schemas(&self, schemas: &mut PayloadSchemas);
 }
 
 /// A device which can be migrated using multiple differently-typed payloads to
@@ -93,6 +112,9 @@ pub trait MigrateMulti: Send + Sync {
         offer: &mut PayloadOffers,
         ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError>;
+
+    /// Declares the schemas of the payloads the device exports and imports.
+    fn schemas(&self, schemas: &mut PayloadSchemas);
 }
 
 /// Additional (borrowed) context data used during device import and export.
@@ -236,6 +258,53 @@ impl Iterator for OutputIter {
 /// data schema for device migration state.
 pub type SchemaId = (&'static str, u32);
 
+/// The schemas of the payloads a device exports during a migration, and of
+/// those it is able to import, declared ahead of time so that a migration
+/// whose destination can't accept the source's payloads is refused before any
+/// state is transferred.
+///
+/// A device can import the payloads it exports, and may also accept payloads
+/// of older versions which it no longer exports.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PayloadSchemas {
+    exports: BTreeSet<SchemaId>,
+    imports: BTreeSet<SchemaId>,
+}
+impl PayloadSchemas {
+    /// Declares that the device exports, and so imports, payloads of schema
+    /// `T`.
+    pub fn add<'a, T: Schema<'a>>(&mut self) -> &mut Self {
+        self.exports.insert(T::id());
+        self.imports.insert(T::id());
+        self
+    }
+
+    /// Declares that the device imports payloads of schema `T`, though it
+    /// does not export them.
+    pub fn add_import<'a, T: Schema<'a>>(&mut self) -> &mut Self {
+        self.imports.insert(T::id());
+        self
+    }
+
+    /// The schemas of the payloads the device exports.
+    pub fn exports(&self) -> impl Iterator<Item = SchemaId> + '_ {
+        self.exports.iter().copied()
+    }
+
+    /// The versions of payloads of `kind` the device imports.
+    pub fn import_versions<'a>(
+        &'a self,
+        kind: &'a str,
+    ) -> impl Iterator<Item = u32> + 'a {
+        self.imports.iter().filter(move |id| id.0 == kind).map(|id| id.1)
+    }
+
+    /// Returns `true` if the device imports payloads of `kind` and `version`.
+    pub fn can_import(&self, kind: &str, version: u32) -> bool {
+        self.imports.iter().any(|id| id.0 == kind && id.1 == version)
+    }
+}
+
 /// Define the type (kind) and version for a migration payload data structure.
 pub trait Schema<'de>: Serialize + Deserialize<'de> + Sized + 'static {
     /// The [`SchemaId`] associated with a given device state data type.
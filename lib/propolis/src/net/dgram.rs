@@ -0,0 +1,104 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{ErrorKind, Result};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Network backend which carries each frame as a datagram on a unix domain
+/// socket.
+///
+/// This requires no special privileges or kernel facilities, making it usable
+/// on hosts (or in zones) where neither viona nor DLPI is available.  Whatever
+/// is at the other end of the socket, be it a software switch or a test, is
+/// responsible for getting frames to their destination.
+pub struct DgramBackend {
+    sock: UnixDatagram,
+    /// Path to which `sock` is bound, if any, to be cleaned up on drop
+    bound_path: Option<PathBuf>,
+}
+
+impl DgramBackend {
+    /// Bind a socket at `local`, exchanging frames with the peer bound at
+    /// `peer`.
+    pub fn connect(local: &Path, peer: &Path) -> Result<Self> {
+        let sock = UnixDatagram::bind(local)?;
+        let bound_path = Some(local.to_path_buf());
+        sock.connect(peer)?;
+        Ok(Self { sock, bound_path })
+    }
+
+    /// Exchange frames over an already-connected socket, such as one of those
+    /// created by [UnixDatagram::pair].
+    pub fn from_socket(sock: UnixDatagram) -> Self {
+        Self { sock, bound_path: None }
+    }
+}
+
+impl super::Backend for DgramBackend {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        self.sock.send(frame).map(|_| ())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        // A zero timeout is rejected, rather than treated as non-blocking
+        let timeout = timeout.max(Duration::from_millis(1));
+        self.sock.set_read_timeout(Some(timeout))?;
+        match self.sock.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for DgramBackend {
+    fn drop(&mut self) {
+        if let Some(path) = self.bound_path.as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::Backend;
+
+    #[test]
+    fn exchange_frames() {
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        let be = DgramBackend::from_socket(ours);
+
+        be.send(&[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(theirs.recv(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+
+        theirs.send(&[4, 5]).unwrap();
+        let res = be.recv(&mut buf, Duration::from_secs(5)).unwrap();
+        assert_eq!(res, Some(2));
+        assert_eq!(&buf[..2], &[4, 5]);
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let (ours, _theirs) = UnixDatagram::pair().unwrap();
+        let be = DgramBackend::from_socket(ours);
+
+        let mut buf = [0u8; 8];
+        let res = be.recv(&mut buf, Duration::from_millis(10)).unwrap();
+        assert_eq!(res, None);
+        let res = be.recv(&mut buf, Duration::ZERO).unwrap();
+        assert_eq!(res, None);
+    }
+}
@@ -0,0 +1,63 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{ErrorKind, Result};
+use std::time::Duration;
+
+/// Network backend which sends and receives raw frames on a host datalink
+/// (typically a VNIC) through DLPI.
+pub struct DlpiBackend {
+    hdl: dlpi::DlpiHandle,
+}
+
+impl DlpiBackend {
+    /// Open the datalink named `link`.
+    ///
+    /// The link is placed in promiscuous mode, so that the guest is free to
+    /// use a MAC address other than the one assigned to the link.
+    pub fn open(link: &str) -> Result<Self> {
+        let hdl = dlpi::open(link, dlpi::sys::DLPI_RAW)?;
+        let res: Result<()> = (|| {
+            // Binding to some SAP is required before any frames are received,
+            // but DL_PROMISC_SAP then opens things up to all of them.
+            dlpi::bind(hdl, 0x0800)?;
+            dlpi::promisc_on(hdl, dlpi::sys::DL_PROMISC_SAP)?;
+            dlpi::promisc_on(hdl, dlpi::sys::DL_PROMISC_MULTI)?;
+            dlpi::promisc_on(hdl, dlpi::sys::DL_PROMISC_PHYS)?;
+            // Without this, frames sent by the guest would be looped back to
+            // it as received.
+            dlpi::promisc_on(hdl, dlpi::sys::DL_PROMISC_RX_ONLY)?;
+            Ok(())
+        })();
+        match res {
+            Ok(()) => Ok(Self { hdl }),
+            Err(e) => {
+                let _ = dlpi::close(hdl);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl super::Backend for DlpiBackend {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        dlpi::send(self.hdl, &[], frame, None).map(|_| ())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        let msec = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        let mut src = [0u8; dlpi::sys::DLPI_PHYSADDR_MAX];
+        match dlpi::recv(self.hdl, &mut src, buf, msec, None) {
+            Ok((_, len)) => Ok(Some(len)),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for DlpiBackend {
+    fn drop(&mut self) {
+        let _ = dlpi::close(self.hdl);
+    }
+}
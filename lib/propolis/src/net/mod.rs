@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Backends through which emulated NICs exchange Ethernet frames with the
+//! outside world.
+//!
+//! These serve devices whose datapath is implemented in userspace, such as
+//! [PciVirtioNet](crate::hw::virtio::PciVirtioNet) and
+//! [PciE1000](crate::hw::e1000::PciE1000).  The viona device, with its
+//! datapath in the kernel, has no need of them.
+
+use std::io::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+mod dgram;
+pub use dgram::DgramBackend;
+
+mod ratelimit;
+pub use ratelimit::{RateLimitedBackend, RateLimits, TokenBucket};
+
+mod services;
+pub use services::{ServicesBackend, ServicesConfig, SERVICES_MAC};
+
+mod stats;
+pub use stats::{NicStats, NicStatsSnapshot};
+
+#[cfg(feature = "dlpi")]
+mod dlpi;
+#[cfg(feature = "dlpi")]
+pub use self::dlpi::DlpiBackend;
+
+/// Largest frame (excluding the FCS) which a device is expected to pass to or
+/// accept from a backend: a 1500-byte payload behind an 802.1Q-tagged header.
+pub const MAX_FRAME_LEN: usize = 1518;
+
+pub trait Backend: Send + Sync + 'static {
+    /// Transmit a single Ethernet frame.
+    fn send(&self, frame: &[u8]) -> Result<()>;
+
+    /// Wait up to `timeout` for a frame to arrive, copying it into `buf`.
+    ///
+    /// Returns the length of the frame, or `None` if nothing arrived before
+    /// the timeout expired.  Frames too large for `buf` are truncated.
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>>;
+}
+
+/// A device to which a [Receiver] delivers the frames it receives.
+pub(crate) trait Receive: Send + Sync + 'static {
+    fn receive(&self, frame: &[u8]);
+}
+
+/// How long a [Receiver] waits on its backend before checking whether it has
+/// been asked to stop
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Thread which receives frames from a [Backend] on behalf of a device.
+///
+/// Devices run one of these only while they are running, so that nothing is
+/// written into guest memory while the instance is paused.
+pub(crate) struct Receiver {
+    stop: Arc<AtomicBool>,
+    hdl: JoinHandle<()>,
+}
+
+impl Receiver {
+    /// Spawn a thread named `name`, passing the frames received from `backend`
+    /// to `dev` until stopped (or until `dev` no longer exists).
+    pub(crate) fn spawn(
+        name: &str,
+        backend: Arc<dyn Backend>,
+        dev: Weak<dyn Receive>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let hdl = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || Self::run(backend, dev, thread_stop))
+            .expect("receiver thread should spawn");
+        Self { stop, hdl }
+    }
+
+    /// Stop the thread, waiting for it to exit.
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        let _ = self.hdl.join();
+    }
+
+    fn run(
+        backend: Arc<dyn Backend>,
+        dev: Weak<dyn Receive>,
+        stop: Arc<AtomicBool>,
+    ) {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        while !stop.load(Ordering::Acquire) {
+            if dev.strong_count() == 0 {
+                return;
+            }
+            match backend.recv(&mut buf, RECV_POLL_INTERVAL) {
+                Ok(Some(len)) => {
+                    let Some(dev) = dev.upgrade() else {
+                        return;
+                    };
+                    dev.receive(&buf[..len]);
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    // Avoid spinning on a backend which is persistently
+                    // failing, such as a socket without a peer.
+                    std::thread::sleep(RECV_POLL_INTERVAL);
+                }
+            }
+        }
+    }
+}
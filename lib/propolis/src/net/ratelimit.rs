@@ -0,0 +1,267 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Backend, MAX_FRAME_LEN};
+
+/// Limit on the traffic passing in one direction, enforced with a token
+/// bucket: the bucket fills at `bytes_per_sec`, up to `burst_bytes`, and each
+/// frame passed drains it by the frame's length.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TokenBucket {
+    bytes_per_sec: u64,
+    burst_bytes: u64,
+}
+
+impl TokenBucket {
+    /// Create a limit, checking that it permits traffic to flow at all: the
+    /// rate must be non-zero, and the burst must admit a full-sized frame.
+    pub fn new(bytes_per_sec: u64, burst_bytes: u64) -> Result<Self> {
+        if bytes_per_sec == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "rate limit must be non-zero",
+            ));
+        }
+        if burst_bytes < MAX_FRAME_LEN as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("burst must be at least {MAX_FRAME_LEN} bytes"),
+            ));
+        }
+        Ok(Self { bytes_per_sec, burst_bytes })
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    pub fn burst_bytes(&self) -> u64 {
+        self.burst_bytes
+    }
+}
+
+/// Limits on the traffic a NIC transmits and receives.  Either direction may
+/// be left unlimited.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub tx: Option<TokenBucket>,
+    pub rx: Option<TokenBucket>,
+}
+
+struct Bucket {
+    limit: TokenBucket,
+    tokens: u64,
+    last_fill: Instant,
+}
+
+impl Bucket {
+    /// A bucket starts out full, so a newly limited NIC can burst at once.
+    fn new(limit: TokenBucket, now: Instant) -> Self {
+        Self { limit, tokens: limit.burst_bytes, last_fill: now }
+    }
+
+    fn fill(&mut self, now: Instant) {
+        const NANOS_PER_SEC: u128 = 1_000_000_000;
+        let rate = u128::from(self.limit.bytes_per_sec);
+        let elapsed = now.saturating_duration_since(self.last_fill);
+        let added = elapsed.as_nanos() * rate / NANOS_PER_SEC;
+        let room = self.limit.burst_bytes - self.tokens;
+        if added >= u128::from(room) {
+            self.tokens = self.limit.burst_bytes;
+            self.last_fill = now;
+        } else if added > 0 {
+            // Advance the fill time only by as long as those whole tokens
+            // took to accrue, so that frequent calls carry their remainders
+            // forward rather than each rounding them away.
+            self.tokens += added as u64;
+            let nanos = added * NANOS_PER_SEC / rate;
+            self.last_fill += Duration::from_nanos(nanos as u64);
+        }
+    }
+
+    /// Take tokens for a frame of `len` bytes, if enough are available.
+    fn take(&mut self, len: usize, now: Instant) -> bool {
+        self.fill(now);
+        let len = len as u64;
+        if self.tokens >= len {
+            self.tokens -= len;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Update a bucket to reflect a (possibly) changed limit.  Tokens already
+/// accrued are kept, up to the new burst size.
+fn update_bucket(
+    bucket: &mut Option<Bucket>,
+    limit: Option<TokenBucket>,
+    now: Instant,
+) {
+    *bucket = match (bucket.take(), limit) {
+        (_, None) => None,
+        (Some(mut old), Some(limit)) => {
+            old.fill(now);
+            Some(Bucket {
+                limit,
+                tokens: old.tokens.min(limit.burst_bytes),
+                last_fill: old.last_fill,
+            })
+        }
+        (None, Some(limit)) => Some(Bucket::new(limit, now)),
+    };
+}
+
+/// A [Backend] which polices the traffic of another, dropping frames which
+/// would exceed its [RateLimits].
+///
+/// Frames are dropped, rather than delayed, as they would be by a switch
+/// enforcing the same limits: guest network stacks already cope with such
+/// loss, and holding frames would stall the emulated device instead.
+pub struct RateLimitedBackend {
+    inner: Arc<dyn Backend>,
+    tx: Mutex<Option<Bucket>>,
+    rx: Mutex<Option<Bucket>>,
+}
+
+impl RateLimitedBackend {
+    pub fn new(inner: Arc<dyn Backend>, limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            tx: Mutex::new(limits.tx.map(|l| Bucket::new(l, now))),
+            rx: Mutex::new(limits.rx.map(|l| Bucket::new(l, now))),
+        }
+    }
+
+    /// Replace the limits in effect, which apply from the next frame.
+    pub fn set_limits(&self, limits: RateLimits) {
+        let now = Instant::now();
+        update_bucket(&mut self.tx.lock().unwrap(), limits.tx, now);
+        update_bucket(&mut self.rx.lock().unwrap(), limits.rx, now);
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        RateLimits {
+            tx: self.tx.lock().unwrap().as_ref().map(|b| b.limit),
+            rx: self.rx.lock().unwrap().as_ref().map(|b| b.limit),
+        }
+    }
+
+    fn admit(bucket: &Mutex<Option<Bucket>>, len: usize) -> bool {
+        match bucket.lock().unwrap().as_mut() {
+            Some(bucket) => bucket.take(len, Instant::now()),
+            None => true,
+        }
+    }
+}
+
+impl Backend for RateLimitedBackend {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if !Self::admit(&self.tx, frame.len()) {
+            probes::net_ratelimit_tx_drop!(|| frame.len() as u64);
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "transmit rate limit exceeded",
+            ));
+        }
+        self.inner.send(frame)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(len) = self.inner.recv(buf, remaining)? else {
+                return Ok(None);
+            };
+            if Self::admit(&self.rx, len) {
+                return Ok(Some(len));
+            }
+            probes::net_ratelimit_rx_drop!(|| len as u64);
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn net_ratelimit_tx_drop(len: u64) {}
+    fn net_ratelimit_rx_drop(len: u64) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limit_validation() {
+        assert!(TokenBucket::new(0, 4096).is_err());
+        assert!(TokenBucket::new(1000, 64).is_err());
+        assert!(TokenBucket::new(1000, MAX_FRAME_LEN as u64).is_ok());
+    }
+
+    #[test]
+    fn bucket_refill() {
+        let start = Instant::now();
+        let limit = TokenBucket::new(10_000, 3000).unwrap();
+        let mut bucket = Bucket::new(limit, start);
+
+        // The initial burst is available immediately...
+        assert!(bucket.take(1500, start));
+        assert!(bucket.take(1500, start));
+        assert!(!bucket.take(1, start));
+
+        // ...after which tokens accrue at the configured rate.
+        let later = start + Duration::from_millis(100);
+        assert!(bucket.take(1000, later));
+        assert!(!bucket.take(1, later));
+
+        // Accrual stops at the burst size, however long the bucket sits idle.
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.take(3000, much_later));
+        assert!(!bucket.take(1, much_later));
+    }
+
+    #[test]
+    fn bucket_accrues_fractional_tokens() {
+        let start = Instant::now();
+        let limit = TokenBucket::new(1000, 2000).unwrap();
+        let mut bucket = Bucket::new(limit, start);
+        assert!(bucket.take(2000, start));
+
+        // Each of these calls is too soon after the last for a whole token to
+        // have accrued, but together they span a full second.
+        for i in 1..=1000 {
+            let now = start + Duration::from_micros(999 * i);
+            assert!(!bucket.take(1000, now));
+        }
+        assert!(bucket.take(1000, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn limit_update() {
+        let start = Instant::now();
+        let mut bucket =
+            Some(Bucket::new(TokenBucket::new(1000, 4000).unwrap(), start));
+
+        // Shrinking the burst discards any tokens beyond it
+        let smaller = TokenBucket::new(1000, 2000).unwrap();
+        update_bucket(&mut bucket, Some(smaller), start);
+        let b = bucket.as_mut().unwrap();
+        assert_eq!(b.limit, smaller);
+        assert!(!b.take(2001, start));
+        assert!(b.take(2000, start));
+
+        update_bucket(&mut bucket, None, start);
+        assert!(bucket.is_none());
+    }
+}
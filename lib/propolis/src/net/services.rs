@@ -0,0 +1,848 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Minimal DHCP and DNS services, answered on behalf of a guest from within
+//! its network backend.
+//!
+//! Much like the services of QEMU's user-mode networking, these let a guest
+//! configure its network without anything having been set up for it on the
+//! host.  The guest is leased a single fixed address, and may resolve names
+//! (through the host's resolver) by querying the server address.
+
+use std::collections::VecDeque;
+use std::io::Result;
+use std::net::{Ipv4Addr, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::Backend;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHER_HDR_LEN: usize = 14;
+const IPV4_HDR_LEN: usize = 20;
+const UDP_HDR_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DNS_PORT: u16 = 53;
+
+/// MAC address from which the services answer.  It is locally administered,
+/// so cannot clash with that of any real NIC.
+pub const SERVICES_MAC: [u8; 6] = [0x02, 0x00, 0x0a, 0x00, 0x02, 0x02];
+
+/// How long [ServicesBackend::recv] waits on its inner backend at a time,
+/// before checking for replies of its own to deliver
+const REPLY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Most replies held for delivery to the guest.  Further replies are dropped
+/// until the guest has received some, as a real network would drop them.
+const MAX_PENDING_REPLIES: usize = 64;
+
+/// Most DNS queries waiting for the resolver.  Further queries are dropped,
+/// and the guest is left to retry them.
+const MAX_PENDING_QUERIES: usize = 16;
+
+/// Addressing handed out to the guest by the DHCP service.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ServicesConfig {
+    /// The address leased to the guest
+    pub guest_addr: Ipv4Addr,
+    /// The address of the services themselves, which is also given to the
+    /// guest as its DNS server
+    pub server_addr: Ipv4Addr,
+    /// Length of the network prefix shared by the guest and server addresses
+    pub prefix_len: u8,
+    /// Duration of the lease given to the guest
+    pub lease_secs: u32,
+    /// Whether to answer DNS queries sent to `server_addr`
+    pub dns: bool,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            guest_addr: Ipv4Addr::new(10, 0, 2, 15),
+            server_addr: Ipv4Addr::new(10, 0, 2, 2),
+            prefix_len: 24,
+            lease_secs: 86400,
+            dns: true,
+        }
+    }
+}
+
+impl ServicesConfig {
+    fn netmask(&self) -> Ipv4Addr {
+        let bits = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+        Ipv4Addr::from(bits.unwrap_or(0))
+    }
+
+    /// Check that the guest and server addresses are distinct, and both sit
+    /// within the same network.
+    pub fn validate(&self) -> Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        if self.prefix_len == 0 || self.prefix_len > 30 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "prefix length must be between 1 and 30",
+            ));
+        }
+        let mask = u32::from(self.netmask());
+        let (guest, server) =
+            (u32::from(self.guest_addr), u32::from(self.server_addr));
+        if guest == server || guest & mask != server & mask {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "guest and server addresses must be distinct, within the \
+                same network",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Frames generated by the services, awaiting delivery to the guest
+#[derive(Default)]
+struct Replies {
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    cv: Condvar,
+}
+
+impl Replies {
+    fn push(&self, frame: Vec<u8>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_PENDING_REPLIES {
+            return;
+        }
+        queue.push_back(frame);
+        self.cv.notify_all();
+    }
+
+    fn pop(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let queue = self.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .cv
+            .wait_timeout_while(queue, timeout, |q| q.is_empty())
+            .unwrap();
+        queue.pop_front()
+    }
+}
+
+/// A DNS query awaiting resolution
+struct DnsQuery {
+    guest_mac: [u8; 6],
+    guest_addr: Ipv4Addr,
+    guest_port: u16,
+    msg: Vec<u8>,
+}
+
+/// A [Backend] which answers the DHCP, DNS, and ARP traffic addressed to its
+/// services, and passes everything else on to an inner backend.
+///
+/// Without an inner backend, the guest is isolated: it may configure itself
+/// and resolve names, but its remaining traffic is dropped.
+pub struct ServicesBackend {
+    inner: Option<Arc<dyn Backend>>,
+    config: ServicesConfig,
+    replies: Arc<Replies>,
+    /// Queries for the resolver thread, which exits once this is dropped
+    resolver: Option<mpsc::SyncSender<DnsQuery>>,
+}
+
+impl ServicesBackend {
+    pub fn new(
+        inner: Option<Arc<dyn Backend>>,
+        config: ServicesConfig,
+    ) -> Result<Self> {
+        config.validate()?;
+        let replies = Arc::new(Replies::default());
+        let resolver = if config.dns {
+            let (tx, rx) = mpsc::sync_channel(MAX_PENDING_QUERIES);
+            let thread_replies = replies.clone();
+            std::thread::Builder::new()
+                .name("net-services-dns".to_string())
+                .spawn(move || resolver_thread(config, rx, thread_replies))?;
+            Some(tx)
+        } else {
+            None
+        };
+        Ok(Self { inner, config, replies, resolver })
+    }
+
+    /// Handle a frame from the guest if it is addressed to the services,
+    /// returning whether it was.
+    fn handle(&self, frame: &[u8]) -> bool {
+        let Some(eth) = EthFrame::parse(frame) else {
+            return false;
+        };
+        match eth.ethertype {
+            ETHERTYPE_ARP => match arp_reply(&self.config, eth.payload) {
+                Some(reply) => {
+                    self.replies.push(reply);
+                    true
+                }
+                None => false,
+            },
+            ETHERTYPE_IPV4 => {
+                let Some(udp) = UdpDatagram::parse(eth.payload) else {
+                    return false;
+                };
+                match udp.dst_port {
+                    DHCP_SERVER_PORT => {
+                        if let Some(reply) = dhcp_reply(&self.config, &udp) {
+                            self.replies.push(reply);
+                        }
+                        true
+                    }
+                    DNS_PORT if udp.dst == self.config.server_addr => {
+                        let Some(resolver) = self.resolver.as_ref() else {
+                            return false;
+                        };
+                        let _ = resolver.try_send(DnsQuery {
+                            guest_mac: eth.src,
+                            guest_addr: udp.src,
+                            guest_port: udp.src_port,
+                            msg: udp.payload.to_vec(),
+                        });
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Backend for ServicesBackend {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        if self.handle(frame) {
+            return Ok(());
+        }
+        match self.inner.as_ref() {
+            Some(inner) => inner.send(frame),
+            None => Ok(()),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        let Some(inner) = self.inner.as_ref() else {
+            return Ok(self.replies.pop(timeout).map(|f| copy_frame(buf, &f)));
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(frame) = self.replies.pop(Duration::ZERO) {
+                return Ok(Some(copy_frame(buf, &frame)));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait = remaining.min(REPLY_POLL_INTERVAL);
+            if let Some(len) = inner.recv(buf, wait)? {
+                return Ok(Some(len));
+            }
+            if remaining <= wait {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+fn copy_frame(buf: &mut [u8], frame: &[u8]) -> usize {
+    let len = frame.len().min(buf.len());
+    buf[..len].copy_from_slice(&frame[..len]);
+    len
+}
+
+fn resolver_thread(
+    config: ServicesConfig,
+    queries: mpsc::Receiver<DnsQuery>,
+    replies: Arc<Replies>,
+) {
+    for query in queries {
+        let Some(question) = DnsQuestion::parse(&query.msg) else {
+            continue;
+        };
+        let addrs = match question.qtype {
+            DNS_TYPE_A => resolve(&question.name),
+            _ => Some(Vec::new()),
+        };
+        let msg = dns_response(&query.msg, &question, addrs.as_deref());
+        replies.push(udp_frame(
+            query.guest_mac,
+            config.server_addr,
+            DNS_PORT,
+            query.guest_addr,
+            query.guest_port,
+            &msg,
+        ));
+    }
+}
+
+/// Resolve `name` to its IPv4 addresses with the host's resolver, or `None`
+/// if it does not exist.
+fn resolve(name: &str) -> Option<Vec<Ipv4Addr>> {
+    let addrs = (name, 0).to_socket_addrs().ok()?;
+    let mut v4: Vec<Ipv4Addr> = addrs
+        .filter_map(|a| match a.ip() {
+            std::net::IpAddr::V4(ip) => Some(ip),
+            std::net::IpAddr::V6(_) => None,
+        })
+        .collect();
+    v4.dedup();
+    Some(v4)
+}
+
+struct EthFrame<'a> {
+    src: [u8; 6],
+    ethertype: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> EthFrame<'a> {
+    fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < ETHER_HDR_LEN {
+            return None;
+        }
+        Some(Self {
+            src: frame[6..12].try_into().unwrap(),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+            payload: &frame[ETHER_HDR_LEN..],
+        })
+    }
+}
+
+struct UdpDatagram<'a> {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    /// Parse a UDP datagram from an IPv4 packet.  Fragments are ignored.
+    fn parse(pkt: &'a [u8]) -> Option<Self> {
+        if pkt.len() < IPV4_HDR_LEN || pkt[0] >> 4 != 4 {
+            return None;
+        }
+        let hdr_len = usize::from(pkt[0] & 0xf) * 4;
+        let total_len = usize::from(u16::from_be_bytes([pkt[2], pkt[3]]));
+        let frag = u16::from_be_bytes([pkt[6], pkt[7]]);
+        if pkt[9] != IPPROTO_UDP
+            || hdr_len < IPV4_HDR_LEN
+            || total_len > pkt.len()
+            || total_len < hdr_len + UDP_HDR_LEN
+            || frag & 0x3fff != 0
+        {
+            return None;
+        }
+        let udp = &pkt[hdr_len..total_len];
+        let udp_len = usize::from(u16::from_be_bytes([udp[4], udp[5]]));
+        if udp_len < UDP_HDR_LEN || udp_len > udp.len() {
+            return None;
+        }
+        Some(Self {
+            src: Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]),
+            dst: Ipv4Addr::new(pkt[16], pkt[17], pkt[18], pkt[19]),
+            src_port: u16::from_be_bytes([udp[0], udp[1]]),
+            dst_port: u16::from_be_bytes([udp[2], udp[3]]),
+            payload: &udp[UDP_HDR_LEN..udp_len],
+        })
+    }
+}
+
+/// One's-complement sum of `data`, as used by IP checksums
+fn csum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([c[0], c[1]]));
+    }
+    if let [b] = chunks.remainder() {
+        sum += u32::from(*b) << 8;
+    }
+    sum
+}
+
+fn csum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build an Ethernet frame, from the services to `dst_mac`, carrying a UDP
+/// datagram.
+fn udp_frame(
+    dst_mac: [u8; 6],
+    src: Ipv4Addr,
+    src_port: u16,
+    dst: Ipv4Addr,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = UDP_HDR_LEN + payload.len();
+    let ip_len = IPV4_HDR_LEN + udp_len;
+
+    let mut frame = Vec::with_capacity(ETHER_HDR_LEN + ip_len);
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&SERVICES_MAC);
+    frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+    let mut ip = [0u8; IPV4_HDR_LEN];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&(ip_len as u16).to_be_bytes());
+    ip[8] = 64;
+    ip[9] = IPPROTO_UDP;
+    ip[12..16].copy_from_slice(&src.octets());
+    ip[16..20].copy_from_slice(&dst.octets());
+    let ip_csum = csum_finish(csum_add(0, &ip));
+    ip[10..12].copy_from_slice(&ip_csum.to_be_bytes());
+    frame.extend_from_slice(&ip);
+
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&src_port.to_be_bytes());
+    udp.extend_from_slice(&dst_port.to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    let mut sum = csum_add(0, &ip[12..20]);
+    sum = csum_add(sum, &[0, IPPROTO_UDP]);
+    sum = csum_add(sum, &(udp_len as u16).to_be_bytes());
+    let udp_csum = match csum_finish(csum_add(sum, &udp)) {
+        // A computed checksum of zero is sent as all ones
+        0 => 0xffff,
+        c => c,
+    };
+    udp[6..8].copy_from_slice(&udp_csum.to_be_bytes());
+    frame.extend_from_slice(&udp);
+    frame
+}
+
+/// Answer an ARP request for the server address.
+fn arp_reply(config: &ServicesConfig, arp: &[u8]) -> Option<Vec<u8>> {
+    const ARP_LEN: usize = 28;
+    const ARP_REQUEST: u16 = 1;
+    const ARP_REPLY: u16 = 2;
+
+    if arp.len() < ARP_LEN
+        || arp[0..6] != [0, 1, 0x08, 0x00, 6, 4]
+        || u16::from_be_bytes([arp[6], arp[7]]) != ARP_REQUEST
+        || arp[24..28] != config.server_addr.octets()
+    {
+        return None;
+    }
+    let (sha, spa) = (&arp[8..14], &arp[14..18]);
+
+    let mut frame = Vec::with_capacity(ETHER_HDR_LEN + ARP_LEN);
+    frame.extend_from_slice(sha);
+    frame.extend_from_slice(&SERVICES_MAC);
+    frame.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    frame.extend_from_slice(&arp[0..6]);
+    frame.extend_from_slice(&ARP_REPLY.to_be_bytes());
+    frame.extend_from_slice(&SERVICES_MAC);
+    frame.extend_from_slice(&config.server_addr.octets());
+    frame.extend_from_slice(sha);
+    frame.extend_from_slice(spa);
+    Some(frame)
+}
+
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_FIXED_LEN: usize = 236;
+
+const DHCP_OPT_PAD: u8 = 0;
+const DHCP_OPT_SUBNET_MASK: u8 = 1;
+const DHCP_OPT_DNS: u8 = 6;
+const DHCP_OPT_REQUESTED_ADDR: u8 = 50;
+const DHCP_OPT_LEASE_TIME: u8 = 51;
+const DHCP_OPT_MSG_TYPE: u8 = 53;
+const DHCP_OPT_SERVER_ID: u8 = 54;
+const DHCP_OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Find option `code` among the options of a DHCP message.
+fn dhcp_option(opts: &[u8], code: u8) -> Option<&[u8]> {
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            DHCP_OPT_PAD => i += 1,
+            DHCP_OPT_END => return None,
+            c => {
+                let len = usize::from(*opts.get(i + 1)?);
+                let val = opts.get(i + 2..i + 2 + len)?;
+                if c == code {
+                    return Some(val);
+                }
+                i += 2 + len;
+            }
+        }
+    }
+    None
+}
+
+/// Answer a DHCP request from the guest: offering its address in response to
+/// a discover, and acknowledging a request for it.
+fn dhcp_reply(config: &ServicesConfig, udp: &UdpDatagram) -> Option<Vec<u8>> {
+    let msg = udp.payload;
+    if udp.src_port != DHCP_CLIENT_PORT
+        || msg.len() < DHCP_FIXED_LEN + DHCP_MAGIC.len()
+        || msg[0] != 1
+        || msg[DHCP_FIXED_LEN..DHCP_FIXED_LEN + 4] != DHCP_MAGIC
+    {
+        return None;
+    }
+    let opts = &msg[DHCP_FIXED_LEN + 4..];
+    let reply_type = match dhcp_option(opts, DHCP_OPT_MSG_TYPE)? {
+        [DHCPDISCOVER] => DHCPOFFER,
+        [DHCPREQUEST] => {
+            // The address requested is either in an option (when selecting or
+            // rebooting), or is already in use by the client (when renewing).
+            let requested = match dhcp_option(opts, DHCP_OPT_REQUESTED_ADDR) {
+                Some(addr) => addr,
+                None => &msg[12..16],
+            };
+            if requested == config.guest_addr.octets() {
+                DHCPACK
+            } else {
+                DHCPNAK
+            }
+        }
+        _ => return None,
+    };
+    let mut reply = vec![0u8; DHCP_FIXED_LEN];
+    // op, htype, hlen, hops
+    reply[0..4].copy_from_slice(&[2, 1, 6, 0]);
+    // xid, flags, and the client hardware address are echoed back
+    reply[4..8].copy_from_slice(&msg[4..8]);
+    reply[10..12].copy_from_slice(&msg[10..12]);
+    reply[28..44].copy_from_slice(&msg[28..44]);
+    reply.extend_from_slice(&DHCP_MAGIC);
+
+    let mut opt = |code: u8, val: &[u8]| {
+        reply.push(code);
+        reply.push(val.len() as u8);
+        reply.extend_from_slice(val);
+    };
+    opt(DHCP_OPT_MSG_TYPE, &[reply_type]);
+    opt(DHCP_OPT_SERVER_ID, &config.server_addr.octets());
+    if reply_type != DHCPNAK {
+        opt(DHCP_OPT_LEASE_TIME, &config.lease_secs.to_be_bytes());
+        opt(DHCP_OPT_SUBNET_MASK, &config.netmask().octets());
+        if config.dns {
+            opt(DHCP_OPT_DNS, &config.server_addr.octets());
+        }
+        reply[16..20].copy_from_slice(&config.guest_addr.octets());
+        reply[20..24].copy_from_slice(&config.server_addr.octets());
+    }
+    reply.push(DHCP_OPT_END);
+
+    // The guest may not have an address with which to receive a unicast
+    // reply, so it is broadcast instead.
+    Some(udp_frame(
+        [0xff; 6],
+        config.server_addr,
+        DHCP_SERVER_PORT,
+        Ipv4Addr::BROADCAST,
+        DHCP_CLIENT_PORT,
+        &reply,
+    ))
+}
+
+const DNS_HDR_LEN: usize = 12;
+const DNS_TYPE_A: u16 = 1;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+/// TTL of the records given in DNS responses: they are only as good as the
+/// host's resolver, so are not to be cached for long.
+const DNS_TTL: u32 = 60;
+
+/// The (sole) question of a DNS query
+struct DnsQuestion {
+    name: String,
+    qtype: u16,
+    /// Length of the header and question, which are echoed in the response
+    len: usize,
+}
+
+impl DnsQuestion {
+    fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.len() < DNS_HDR_LEN {
+            return None;
+        }
+        let flags = u16::from_be_bytes([msg[2], msg[3]]);
+        let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+        // Only standard queries (QR clear, opcode 0) with one question
+        if flags & 0xf800 != 0 || qdcount != 1 {
+            return None;
+        }
+        let mut labels = Vec::new();
+        let mut i = DNS_HDR_LEN;
+        loop {
+            let len = usize::from(*msg.get(i)?);
+            i += 1;
+            if len == 0 {
+                break;
+            }
+            // Compression has no place in the question of a query
+            if len > 63 {
+                return None;
+            }
+            let label = msg.get(i..i + len)?;
+            labels.push(std::str::from_utf8(label).ok()?);
+            i += len;
+        }
+        let qtype = u16::from_be_bytes([*msg.get(i)?, *msg.get(i + 1)?]);
+        let qclass = u16::from_be_bytes([*msg.get(i + 2)?, *msg.get(i + 3)?]);
+        if qclass != DNS_CLASS_IN || labels.is_empty() {
+            return None;
+        }
+        Some(Self { name: labels.join("."), qtype, len: i + 4 })
+    }
+}
+
+/// Build the response to a DNS query, answering its question with `addrs`,
+/// or that the name does not exist if `None`.
+fn dns_response(
+    query: &[u8],
+    question: &DnsQuestion,
+    addrs: Option<&[Ipv4Addr]>,
+) -> Vec<u8> {
+    let answers = addrs.unwrap_or_default();
+    let rd = u16::from_be_bytes([query[2], query[3]]) & 0x0100;
+    // QR and RA set, with RD echoed from the query
+    let mut flags = 0x8080 | rd;
+    if addrs.is_none() {
+        flags |= DNS_RCODE_NXDOMAIN;
+    }
+
+    let mut msg = Vec::with_capacity(question.len + answers.len() * 16);
+    msg.extend_from_slice(&query[0..2]);
+    msg.extend_from_slice(&flags.to_be_bytes());
+    for count in [1, answers.len() as u16, 0, 0] {
+        msg.extend_from_slice(&u16::to_be_bytes(count));
+    }
+    msg.extend_from_slice(&query[DNS_HDR_LEN..question.len]);
+    for addr in answers {
+        // Name is a pointer to that in the question
+        msg.extend_from_slice(&[0xc0, DNS_HDR_LEN as u8]);
+        msg.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+        msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&DNS_TTL.to_be_bytes());
+        msg.extend_from_slice(&4u16.to_be_bytes());
+        msg.extend_from_slice(&addr.octets());
+    }
+    msg
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x02, 0x08, 0x20, 0x00, 0x00, 0x01];
+
+    /// A frame from the guest, carrying a UDP datagram
+    fn guest_udp(
+        src: Ipv4Addr,
+        src_port: u16,
+        dst: Ipv4Addr,
+        dst_port: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut frame =
+            udp_frame([0; 6], src, src_port, dst, dst_port, payload);
+        frame[6..12].copy_from_slice(&GUEST_MAC);
+        frame
+    }
+
+    fn dhcp_msg(msg_type: u8, requested: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut msg = vec![0u8; DHCP_FIXED_LEN];
+        msg[0..4].copy_from_slice(&[1, 1, 6, 0]);
+        msg[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        msg[28..34].copy_from_slice(&GUEST_MAC);
+        msg.extend_from_slice(&DHCP_MAGIC);
+        msg.extend_from_slice(&[DHCP_OPT_MSG_TYPE, 1, msg_type]);
+        if let Some(addr) = requested {
+            msg.extend_from_slice(&[DHCP_OPT_REQUESTED_ADDR, 4]);
+            msg.extend_from_slice(&addr.octets());
+        }
+        msg.push(DHCP_OPT_END);
+        msg
+    }
+
+    /// Pull the DHCP message from a reply frame, checking its UDP checksum
+    fn parse_reply(frame: &[u8]) -> Vec<u8> {
+        let eth = EthFrame::parse(frame).unwrap();
+        let udp = UdpDatagram::parse(eth.payload).unwrap();
+        let ip = &eth.payload[..IPV4_HDR_LEN];
+        assert_eq!(csum_finish(csum_add(0, ip)), 0);
+        let mut sum = csum_add(0, &ip[12..20]);
+        sum = csum_add(sum, &[0, IPPROTO_UDP]);
+        sum = csum_add(sum, &eth.payload[IPV4_HDR_LEN + 4..IPV4_HDR_LEN + 6]);
+        assert_eq!(csum_finish(csum_add(sum, &eth.payload[IPV4_HDR_LEN..])), 0);
+        udp.payload.to_vec()
+    }
+
+    fn dhcp_exchange(msg: &[u8]) -> Option<Vec<u8>> {
+        let config = ServicesConfig::default();
+        let frame = guest_udp(
+            Ipv4Addr::UNSPECIFIED,
+            DHCP_CLIENT_PORT,
+            Ipv4Addr::BROADCAST,
+            DHCP_SERVER_PORT,
+            msg,
+        );
+        let eth = EthFrame::parse(&frame).unwrap();
+        let udp = UdpDatagram::parse(eth.payload).unwrap();
+        dhcp_reply(&config, &udp).map(|reply| parse_reply(&reply))
+    }
+
+    #[test]
+    fn dhcp_lease() {
+        let config = ServicesConfig::default();
+
+        let offer = dhcp_exchange(&dhcp_msg(DHCPDISCOVER, None)).unwrap();
+        let opts = &offer[DHCP_FIXED_LEN + 4..];
+        assert_eq!(&offer[4..8], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&offer[16..20], &config.guest_addr.octets());
+        assert_eq!(
+            dhcp_option(opts, DHCP_OPT_MSG_TYPE),
+            Some(&[DHCPOFFER][..])
+        );
+        assert_eq!(
+            dhcp_option(opts, DHCP_OPT_SUBNET_MASK),
+            Some(&[255, 255, 255, 0][..])
+        );
+        assert_eq!(
+            dhcp_option(opts, DHCP_OPT_DNS),
+            Some(&config.server_addr.octets()[..])
+        );
+
+        let ack =
+            dhcp_exchange(&dhcp_msg(DHCPREQUEST, Some(config.guest_addr)))
+                .unwrap();
+        let opts = &ack[DHCP_FIXED_LEN + 4..];
+        assert_eq!(dhcp_option(opts, DHCP_OPT_MSG_TYPE), Some(&[DHCPACK][..]));
+
+        // Any other address is refused
+        let other = Ipv4Addr::new(10, 0, 2, 100);
+        let nak = dhcp_exchange(&dhcp_msg(DHCPREQUEST, Some(other))).unwrap();
+        let opts = &nak[DHCP_FIXED_LEN + 4..];
+        assert_eq!(dhcp_option(opts, DHCP_OPT_MSG_TYPE), Some(&[DHCPNAK][..]));
+        assert_eq!(&nak[16..20], &[0; 4]);
+    }
+
+    #[test]
+    fn arp_for_server() {
+        let config = ServicesConfig::default();
+        let mut arp = vec![0, 1, 0x08, 0x00, 6, 4, 0, 1];
+        arp.extend_from_slice(&GUEST_MAC);
+        arp.extend_from_slice(&config.guest_addr.octets());
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&config.server_addr.octets());
+
+        let reply = arp_reply(&config, &arp).unwrap();
+        assert_eq!(&reply[0..6], &GUEST_MAC);
+        assert_eq!(&reply[22..28], &SERVICES_MAC);
+        assert_eq!(&reply[28..32], &config.server_addr.octets());
+        assert_eq!(&reply[38..42], &config.guest_addr.octets());
+
+        // Requests for other addresses are left for someone else to answer
+        arp[24..28].copy_from_slice(&[10, 0, 2, 1]);
+        assert!(arp_reply(&config, &arp).is_none());
+    }
+
+    #[test]
+    fn dns_answers() {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in ["example", "com"] {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.extend_from_slice(&[0, 0, 1, 0, 1]);
+
+        let question = DnsQuestion::parse(&query).unwrap();
+        assert_eq!(question.name, "example.com");
+        assert_eq!(question.qtype, DNS_TYPE_A);
+        assert_eq!(question.len, query.len());
+
+        let addr = Ipv4Addr::new(192, 0, 2, 1);
+        let resp = dns_response(&query, &question, Some(&[addr]));
+        assert_eq!(&resp[0..4], &[0x12, 0x34, 0x81, 0x80]);
+        assert_eq!(&resp[4..8], &[0, 1, 0, 1]);
+        assert_eq!(&resp[DNS_HDR_LEN..query.len()], &query[DNS_HDR_LEN..]);
+        assert_eq!(&resp[resp.len() - 4..], &addr.octets());
+
+        let resp = dns_response(&query, &question, None);
+        assert_eq!(&resp[2..4], &[0x81, 0x83]);
+        assert_eq!(resp.len(), query.len());
+    }
+
+    #[test]
+    fn isolated_backend() {
+        let config = ServicesConfig { dns: false, ..Default::default() };
+        let be = ServicesBackend::new(None, config).unwrap();
+        let mut buf = [0u8; 1518];
+
+        // Traffic for elsewhere goes nowhere
+        let frame = guest_udp(
+            config.guest_addr,
+            1234,
+            Ipv4Addr::new(192, 0, 2, 1),
+            1234,
+            &[1, 2, 3],
+        );
+        be.send(&frame).unwrap();
+        assert_eq!(be.recv(&mut buf, Duration::from_millis(10)).unwrap(), None);
+
+        let discover = guest_udp(
+            Ipv4Addr::UNSPECIFIED,
+            DHCP_CLIENT_PORT,
+            Ipv4Addr::BROADCAST,
+            DHCP_SERVER_PORT,
+            &dhcp_msg(DHCPDISCOVER, None),
+        );
+        be.send(&discover).unwrap();
+        let len = be.recv(&mut buf, Duration::from_secs(5)).unwrap().unwrap();
+        let offer = parse_reply(&buf[..len]);
+        assert_eq!(&offer[16..20], &config.guest_addr.octets());
+    }
+
+    #[test]
+    fn replies_bounded() {
+        let replies = Replies::default();
+        for i in 0..MAX_PENDING_REPLIES + 8 {
+            replies.push(vec![i as u8]);
+        }
+        for i in 0..MAX_PENDING_REPLIES {
+            assert_eq!(replies.pop(Duration::ZERO), Some(vec![i as u8]));
+        }
+        assert_eq!(replies.pop(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn config_validation() {
+        assert!(ServicesConfig::default().validate().is_ok());
+        let bad = [
+            ServicesConfig { prefix_len: 0, ..Default::default() },
+            ServicesConfig { prefix_len: 31, ..Default::default() },
+            ServicesConfig {
+                server_addr: Ipv4Addr::new(10, 0, 3, 2),
+                ..Default::default()
+            },
+            ServicesConfig {
+                server_addr: ServicesConfig::default().guest_addr,
+                ..Default::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+}
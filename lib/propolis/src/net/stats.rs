@@ -0,0 +1,64 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of the traffic a NIC has passed in each direction.  Receive (RX)
+/// and transmit (TX) are from the perspective of the guest.
+#[derive(Default)]
+pub struct NicStats {
+    rx_bytes: AtomicU64,
+    rx_packets: AtomicU64,
+    rx_errors: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_errors: AtomicU64,
+}
+
+impl NicStats {
+    /// Count a frame of `len` bytes delivered to the guest.
+    pub(crate) fn rx(&self, len: usize) {
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a frame which could not be delivered to the guest.
+    pub(crate) fn rx_error(&self) {
+        self.rx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a frame of `len` bytes sent by the guest.
+    pub(crate) fn tx(&self, len: usize) {
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a frame from the guest which was malformed, or could not be
+    /// sent.
+    pub(crate) fn tx_error(&self) {
+        self.tx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NicStatsSnapshot {
+        NicStatsSnapshot {
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_errors: self.rx_errors.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            tx_errors: self.tx_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of [NicStats].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NicStatsSnapshot {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+}
@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Histograms of request latencies, as kept for block devices and virtqueues.
+
+use std::time::Duration;
+
+/// Number of buckets in a [LatencyHistogram].
+///
+/// The first bucket holds requests which completed in under 1us.  Each bucket
+/// after that covers a power-of-two range of microseconds (`[1us, 2us)`,
+/// `[2us, 4us)`, and so on), with the last bucket holding everything which took
+/// longer than ~1s.
+pub const LATENCY_BUCKETS: usize = 22;
+
+/// Histogram of request latencies, bucketed as described in
+/// [LATENCY_BUCKETS].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub counts: [u64; LATENCY_BUCKETS],
+}
+impl LatencyHistogram {
+    /// Lower bound (inclusive), in nanoseconds, of bucket `idx`.
+    pub const fn bucket_start_ns(idx: usize) -> u64 {
+        match idx {
+            0 => 0,
+            n => 1000 << (n - 1),
+        }
+    }
+
+    /// Index of the bucket in which a latency of `dur` is counted
+    pub fn bucket_for(dur: Duration) -> usize {
+        let us = dur.as_micros().min(u128::from(u64::MAX)) as u64;
+        ((u64::BITS - us.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1)
+    }
+
+    /// Total number of requests counted in this histogram
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_bucket_for_zero() {
+        assert_eq!(LatencyHistogram::bucket_for(Duration::ZERO), 0);
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_nanos(999)), 0);
+    }
+
+    #[test]
+    fn latency_bucket_edges() {
+        for idx in 1..LATENCY_BUCKETS {
+            let start = LatencyHistogram::bucket_start_ns(idx);
+            assert_eq!(
+                LatencyHistogram::bucket_for(Duration::from_nanos(start)),
+                idx,
+                "start of bucket {idx}"
+            );
+            assert_eq!(
+                LatencyHistogram::bucket_for(Duration::from_nanos(start - 1)),
+                idx - 1,
+                "end of bucket {}",
+                idx - 1
+            );
+        }
+    }
+
+    #[test]
+    fn latency_bucket_overflow() {
+        let last = LATENCY_BUCKETS - 1;
+        let start = LatencyHistogram::bucket_start_ns(last);
+        assert_eq!(
+            LatencyHistogram::bucket_for(Duration::from_nanos(start * 4)),
+            last
+        );
+        assert_eq!(LatencyHistogram::bucket_for(Duration::from_secs(60)), last);
+        assert_eq!(LatencyHistogram::bucket_for(Duration::MAX), last);
+    }
+}
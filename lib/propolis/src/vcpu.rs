@@ -493,6 +493,19 @@ impl MigrateMulti for Vcpu {
 
         Ok(())
     }
+
+    fn schemas(&self, schemas: &mut PayloadSchemas) {
+        schemas
+            .add::<migrate::VcpuRunStateV1>()
+            .add::<migrate::VcpuGpRegsV1>()
+            .add::<migrate::VcpuCtrlRegsV1>()
+            .add::<migrate::VcpuDbgRegsV1>()
+            .add::<migrate::VcpuSegRegsV1>()
+            .add::<migrate::VcpuMsrsV1>()
+            .add::<migrate::FpuStateV1>()
+            .add::<migrate::LapicV1>()
+            .add::<migrate::CpuidV1>();
+    }
 }
 
 pub mod migrate {
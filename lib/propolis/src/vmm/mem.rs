@@ -994,6 +994,17 @@ impl MemCtx {
         }
     }
 
+    /// Returns the regions of the space backed by guest DRAM, in ascending
+    /// order of address.
+    pub fn dram_regions(&self) -> Vec<GuestRegion> {
+        let guard = self.map.lock().unwrap();
+        guard
+            .iter()
+            .filter(|(_, _, ent)| matches!(ent.kind, MapKind::Dram(_)))
+            .map(|(addr, len, _)| GuestRegion(GuestAddr(addr as u64), len))
+            .collect()
+    }
+
     /// Returns the [lowest, highest] memory addresses in the space as an
     /// inclusive range.
     pub fn mem_bounds(&self) -> Option<RangeInclusive<GuestAddr>> {
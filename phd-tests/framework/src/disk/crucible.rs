@@ -324,6 +324,8 @@ impl super::DiskConfig for CrucibleDisk {
                 request_json: serde_json::to_string(&vcr)
                     .expect("VolumeConstructionRequest should serialize"),
                 readonly: false,
+                error_policy: None,
+                lazy_attach: None,
             }),
         )
     }
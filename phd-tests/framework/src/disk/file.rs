@@ -85,6 +85,11 @@ pub struct FileBackedDisk {
     /// The backing file for this disk.
     file: BackingFile,
 
+    /// The sidecar file in which the disk's backend keeps checksums of the
+    /// disk's blocks, so that data corrupted on its way to or from the disk is
+    /// caught when read back.
+    integrity_sidecar: Utf8PathBuf,
+
     /// The kind of guest OS image this guest contains, or `None` if the disk
     /// was not initialized from a guest OS artifact.
     guest_os: Option<GuestOsKind>,
@@ -116,7 +121,25 @@ impl FileBackedDisk {
         permissions.set_readonly(false);
         disk_file.set_permissions(permissions)?;
 
-        Ok(Self { backend_name, file: artifact, guest_os })
+        let mut integrity_sidecar = data_dir.as_ref().to_path_buf();
+        integrity_sidecar.push(format!("{}.phd_sums", Uuid::new_v4()));
+
+        Ok(Self { backend_name, file: artifact, integrity_sidecar, guest_os })
+    }
+}
+
+impl Drop for FileBackedDisk {
+    fn drop(&mut self) {
+        // The sidecar is created by the backend, and so will not exist if the
+        // disk was never attached to a running VM.
+        let path = &self.integrity_sidecar;
+        match std::fs::remove_file(path) {
+            Ok(()) => debug!(%path, "deleted disk integrity sidecar"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                error!(?e, %path, "failed to delete disk integrity sidecar")
+            }
+        }
     }
 }
 
@@ -127,6 +150,12 @@ impl super::DiskConfig for FileBackedDisk {
             StorageBackendV0::File(FileStorageBackend {
                 path: self.file.path().to_string(),
                 readonly: false,
+                error_policy: None,
+                encryption_key: None,
+                workers: None,
+                max_in_flight: None,
+                read_cache_size: None,
+                integrity_sidecar: Some(self.integrity_sidecar.to_string()),
             }),
         )
     }
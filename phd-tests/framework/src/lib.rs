@@ -31,7 +31,7 @@ use std::{fmt, ops::Range, sync::Arc};
 
 use anyhow::Context;
 use artifacts::DEFAULT_PROPOLIS_ARTIFACT;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 
 use disk::DiskFactory;
 use futures::{stream::FuturesUnordered, StreamExt};
@@ -298,6 +298,12 @@ impl Framework {
         &self.default_guest_os_artifact
     }
 
+    /// Yields the directory in which this framework instance places the files
+    /// its tests and their Propolis servers create.
+    pub fn tmp_directory(&self) -> &Utf8Path {
+        &self.tmp_directory
+    }
+
     /// Indicates whether the disk factory in this framework supports the
     /// creation of Crucible disks. This can be used to skip tests that require
     /// Crucible support.
@@ -8,7 +8,10 @@ use std::sync::Arc;
 use anyhow::Context;
 use propolis_client::{
     instance_spec::SpecBuilderV0,
-    types::{NvmeDisk, PciPath, SerialPortNumber, StorageDeviceV0, VirtioDisk},
+    types::{
+        NvmeDisk, PciPath, SataDisk, SerialPortNumber, StorageDeviceV0,
+        VirtioDisk,
+    },
 };
 
 use crate::{
@@ -22,6 +25,7 @@ use crate::{
 pub enum DiskInterface {
     Virtio,
     Nvme,
+    Sata,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -206,11 +210,22 @@ impl VmConfig {
                     StorageDeviceV0::VirtioDisk(VirtioDisk {
                         backend_name: backend_name.clone(),
                         pci_path,
+                        num_queues: None,
+                        interrupt_moderation: None,
                     })
                 }
                 DiskInterface::Nvme => StorageDeviceV0::NvmeDisk(NvmeDisk {
                     backend_name: backend_name.clone(),
                     pci_path,
+                    num_queues: None,
+                    queue_size: None,
+                    model_number: None,
+                    firmware_revision: None,
+                    ieee_oui: None,
+                }),
+                DiskInterface::Sata => StorageDeviceV0::SataDisk(SataDisk {
+                    backend_name: backend_name.clone(),
+                    pci_path,
                 }),
             };
 
@@ -5,6 +5,7 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 
 use anyhow::Context;
+use camino::Utf8PathBuf;
 
 use crate::{test_vm::server::ServerProcessParameters, Framework};
 
@@ -16,15 +17,34 @@ pub enum VmLocation {
     // TODO: Support remote VMs.
 }
 
+/// Directs a Propolis server to write the device state it sends in
+/// migrations as canned payloads, or to import canned payloads in place of
+/// the device state it is sent.
+#[derive(Clone, Debug)]
+pub enum MigrationPayloads {
+    /// Write the device state sent in migrations out of the VM to this
+    /// directory.
+    Emit(Utf8PathBuf),
+
+    /// Import the payloads in this directory in place of those sent in
+    /// migrations into the VM.
+    Import(Utf8PathBuf),
+}
+
 #[derive(Clone, Debug)]
 pub struct EnvironmentSpec {
     pub(crate) location: VmLocation,
     pub(crate) propolis_artifact: String,
+    pub(crate) migration_payloads: Option<MigrationPayloads>,
 }
 
 impl EnvironmentSpec {
     pub(crate) fn new(location: VmLocation, propolis_artifact: &str) -> Self {
-        Self { location, propolis_artifact: propolis_artifact.to_owned() }
+        Self {
+            location,
+            propolis_artifact: propolis_artifact.to_owned(),
+            migration_payloads: None,
+        }
     }
 
     pub fn location(&mut self, location: VmLocation) -> &mut Self {
@@ -37,6 +57,14 @@ impl EnvironmentSpec {
         self
     }
 
+    pub fn migration_payloads(
+        &mut self,
+        payloads: Option<MigrationPayloads>,
+    ) -> &mut Self {
+        self.migration_payloads = payloads;
+        self
+    }
+
     pub(crate) async fn build<'a>(
         &self,
         framework: &'a Framework,
@@ -87,6 +115,7 @@ impl<'a> Environment<'a> {
                         vnc_port,
                     ),
                     log_mode: framework.server_log_mode,
+                    migration_payloads: builder.migration_payloads.clone(),
                 };
                 Ok(Self::Local(params))
             }
@@ -23,9 +23,10 @@ use propolis_client::{
     support::{InstanceSerialConsoleHelper, WSClientOffset},
     types::{
         InstanceGetResponse, InstanceMetadata, InstanceMigrateInitiateRequest,
-        InstanceProperties, InstanceSerialConsoleHistoryResponse,
-        InstanceSpecEnsureRequest, InstanceSpecGetResponse, InstanceState,
-        InstanceStateRequested, MigrationState, VersionedInstanceSpec,
+        InstanceMigrateStatusResponse, InstanceProperties,
+        InstanceSerialConsoleHistoryResponse, InstanceSpecEnsureRequest,
+        InstanceSpecGetResponse, InstanceState, InstanceStateRequested,
+        MigrationState, VersionedInstanceSpec,
     },
 };
 use propolis_client::{Client, ResponseValue};
@@ -48,7 +49,7 @@ mod server;
 pub(crate) mod spec;
 
 pub use config::*;
-pub use environment::VmLocation;
+pub use environment::{MigrationPayloads, VmLocation};
 
 use self::environment::EnvironmentSpec;
 
@@ -305,6 +306,9 @@ impl TestVm {
             properties,
             instance_spec: versioned_spec,
             migrate,
+            start_paused: false,
+            boot_to_firmware_setup: false,
+            supervision: None,
         };
 
         // There is a brief period where the Propolis server process has begun
@@ -434,6 +438,20 @@ impl TestVm {
         self.put_instance_state(InstanceStateRequested::Reboot).await
     }
 
+    /// Pauses the VM's vCPUs and devices.
+    #[instrument(skip_all, fields(vm = self.spec.vm_name, vm_id = %self.id))]
+    pub async fn pause(&self) -> PropolisClientResult<()> {
+        info!("Requesting instance pause");
+        self.client.instance_pause().send().await
+    }
+
+    /// Resumes a VM paused by [`TestVm::pause`].
+    #[instrument(skip_all, fields(vm = self.spec.vm_name, vm_id = %self.id))]
+    pub async fn resume(&self) -> PropolisClientResult<()> {
+        info!("Requesting instance resume");
+        self.client.instance_resume().send().await
+    }
+
     #[instrument(skip_all, fields(vm = self.spec.vm_name, vm_id = %self.id))]
     async fn put_instance_state(
         &self,
@@ -514,6 +532,11 @@ impl TestVm {
                             migration_id,
                             src_addr: server_addr.to_string(),
                             src_uuid: Uuid::default(),
+                            auto_converge: None,
+                            max_downtime_ms: None,
+                            tls: None,
+                            retry: None,
+                            dirty_tracking: None,
                         }),
                         InstanceConsoleSource::InheritFrom(source),
                     )
@@ -567,13 +590,22 @@ impl TestVm {
         &self,
         migration_id: Uuid,
     ) -> Result<MigrationState> {
+        Ok(self.get_migration_status(migration_id).await?.state)
+    }
+
+    /// Gets the status of the migration with ID `migration_id`, including
+    /// how it failed if it did.
+    pub async fn get_migration_status(
+        &self,
+        migration_id: Uuid,
+    ) -> Result<InstanceMigrateStatusResponse> {
         Ok(self
             .client
             .instance_migrate_status()
             .migration_id(migration_id)
             .send()
             .await?
-            .state)
+            .into_inner())
     }
 
     pub async fn get_serial_console_history(
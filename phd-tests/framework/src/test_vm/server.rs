@@ -10,7 +10,7 @@ use anyhow::Result;
 use camino::{Utf8Path, Utf8PathBuf};
 use tracing::{debug, info};
 
-use crate::server_log_mode::ServerLogMode;
+use crate::{server_log_mode::ServerLogMode, test_vm::MigrationPayloads};
 
 /// Parameters used to launch and configure the Propolis server process. These
 /// are distinct from the parameters used to configure the VM that that process
@@ -31,6 +31,10 @@ pub struct ServerProcessParameters<'a> {
     pub vnc_addr: SocketAddrV4,
 
     pub log_mode: ServerLogMode,
+
+    /// Directs the server to write or import canned device payloads in
+    /// migrations, if set.
+    pub migration_payloads: Option<MigrationPayloads>,
 }
 
 pub struct PropolisServer {
@@ -50,6 +54,7 @@ impl PropolisServer {
             server_addr,
             vnc_addr,
             log_mode,
+            migration_payloads,
         } = process_params;
 
         info!(
@@ -73,6 +78,15 @@ impl PropolisServer {
             ])
             .stdout(server_stdout)
             .stderr(server_stderr);
+        match &migration_payloads {
+            Some(MigrationPayloads::Emit(dir)) => {
+                server_cmd.args(["--emit-migration-payloads", dir.as_str()]);
+            }
+            Some(MigrationPayloads::Import(dir)) => {
+                server_cmd.args(["--import-migration-payloads", dir.as_str()]);
+            }
+            None => {}
+        }
 
         // Gracefully shutting down a Propolis server requires PHD to send an
         // instance stop request to the server before it is actually terminated.
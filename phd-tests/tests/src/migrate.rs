@@ -3,10 +3,13 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use phd_framework::{
-    artifacts, lifecycle::Action, test_vm::MigrationTimeout, TestVm,
+    artifacts,
+    lifecycle::Action,
+    test_vm::{MigrationPayloads, MigrationTimeout},
+    TestVm,
 };
 use phd_testcase::*;
-use propolis_client::types::MigrationState;
+use propolis_client::types::{MigrationFailureCause, MigrationState};
 use tracing::info;
 use uuid::Uuid;
 
@@ -81,6 +84,58 @@ mod from_base {
         .await?;
     }
 
+    // Tests that the Propolis under test can import the device state emitted
+    // by the "migration base" Propolis, as a release would import the canned
+    // payloads of the one before it.
+    #[phd_testcase]
+    async fn import_base_payloads(ctx: &Framework) {
+        if !ctx.migration_base_enabled() {
+            phd_skip!("No 'migration base' Propolis revision available");
+        }
+
+        let dir = ctx.tmp_directory().join("import_base_payloads");
+        let mut env = ctx.environment_builder();
+        env.propolis(artifacts::BASE_PROPOLIS_ARTIFACT)
+            .migration_payloads(Some(MigrationPayloads::Emit(dir.clone())));
+        let cfg = ctx.vm_config_builder("import_base_payloads_base_source");
+        let mut base_source = ctx.spawn_vm(&cfg, Some(&env)).await?;
+        let mut base_target = ctx
+            .spawn_successor_vm(
+                "import_base_payloads_base_target",
+                &base_source,
+                None,
+            )
+            .await?;
+        base_source.launch().await?;
+        base_source.wait_to_boot().await?;
+        base_target
+            .migrate_from(
+                &base_source,
+                Uuid::new_v4(),
+                MigrationTimeout::default(),
+            )
+            .await?;
+
+        // The payloads of another instance don't match the guest being
+        // migrated, so only check that they can be imported.
+        let mut source =
+            ctx.spawn_default_vm("import_base_payloads_source").await?;
+        let mut env = source.environment_spec();
+        env.migration_payloads(Some(MigrationPayloads::Import(dir)));
+        let mut target = ctx
+            .spawn_successor_vm(
+                "import_base_payloads_target",
+                &source,
+                Some(&env),
+            )
+            .await?;
+        source.launch().await?;
+        source.wait_to_boot().await?;
+        target
+            .migrate_from(&source, Uuid::new_v4(), MigrationTimeout::default())
+            .await?;
+    }
+
     async fn spawn_base_vm(ctx: &Framework, name: &str) -> Result<TestVm> {
         if !ctx.migration_base_enabled() {
             phd_skip!("No 'migration base' Propolis revision available");
@@ -285,6 +340,22 @@ async fn incompatible_vms(ctx: &Framework) {
         let target_migration_state =
             target.get_migration_state(migration_id).await?;
         assert_eq!(target_migration_state, MigrationState::Error);
+
+        // The target refuses the migration when it reads the source's
+        // preamble, and the source learns of this from the target.
+        let failure = target
+            .get_migration_status(migration_id)
+            .await?
+            .failure
+            .expect("failed migration should report its failure");
+        assert_eq!(failure.cause, MigrationFailureCause::OfferRejected);
+        assert_eq!(failure.phase, MigrationState::Sync);
+        let failure = source
+            .get_migration_status(migration_id)
+            .await?
+            .failure
+            .expect("failed migration should report its failure");
+        assert_eq!(failure.cause, MigrationFailureCause::Remote);
     }
 }
 
@@ -89,3 +89,33 @@ async fn instance_reset_requires_running_test(ctx: &Framework) {
     vm.launch().await?;
     vm.wait_for_state(InstanceState::Running, Duration::from_secs(60)).await?;
 }
+
+#[phd_testcase]
+async fn instance_pause_resume_test(ctx: &Framework) {
+    let mut vm = ctx.spawn_default_vm("instance_pause_resume_test").await?;
+
+    assert!(vm.pause().await.is_err());
+    vm.launch().await?;
+    vm.wait_to_boot().await?;
+
+    // Pausing and resuming are idempotent, and a paused instance can't be
+    // reset.
+    vm.pause().await?;
+    vm.pause().await?;
+    vm.wait_for_state(InstanceState::Paused, Duration::from_secs(60)).await?;
+    assert!(vm.reset().await.is_err());
+    vm.resume().await?;
+    vm.resume().await?;
+    vm.wait_for_state(InstanceState::Running, Duration::from_secs(60)).await?;
+
+    // The guest picks up where it left off.
+    let out = vm.run_shell_command("echo hello").await?;
+    assert_eq!(out, "hello");
+
+    // A paused instance can still be stopped.
+    vm.pause().await?;
+    vm.wait_for_state(InstanceState::Paused, Duration::from_secs(60)).await?;
+    vm.stop().await?;
+    vm.wait_for_state(InstanceState::Destroyed, Duration::from_secs(60))
+        .await?;
+}